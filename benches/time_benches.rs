@@ -1,5 +1,5 @@
 use clann::{
-    build, core::{Config, MetricsOutput}, init_with_config, metricdata::{AngularData, MetricData}, puffinn_binds::PuffinnIndex, search, utils::load_hdf5_dataset
+    build, core::{Config, MetricsOutput}, init_with_config, metricdata::{AngularData, MetricData}, puffinn_binds::PuffinnIndex, search, search_batch, search_batch_grouped, utils::load_hdf5_dataset
 };
 use criterion::{
     criterion_group, criterion_main, AxisScale, BenchmarkId, Criterion, PlotConfiguration
@@ -29,7 +29,7 @@ pub fn compare_implementations_time(c: &mut Criterion) {
         .collect();
 
     for (config_idx, config) in configs.iter().enumerate() {
-        let data = AngularData::new(hdf5_dataset.dataset_array.clone());
+        let data = AngularData::from_view(hdf5_dataset.dataset_array.view());
 
         // Initialize base PUFFINN index
         let (base_index, _memory) = PuffinnIndex::new(&data, config.num_tables * data.num_points() * 1024).unwrap();
@@ -42,6 +42,7 @@ pub fn compare_implementations_time(c: &mut Criterion) {
             delta: config.delta,
             dataset_name: config.dataset_name.clone(),
             metrics_output: MetricsOutput::DB,
+            ..Default::default()
         };
         let mut clustered_index = init_with_config(data, clann_config).unwrap();
         build(&mut clustered_index).unwrap();
@@ -92,10 +93,53 @@ pub fn compare_implementations_time(c: &mut Criterion) {
     }
 }
 
+/// Searches the whole query set per iteration, query by query and grouped by cluster
+pub fn compare_batch_strategies(c: &mut Criterion) {
+    let configs = load_configs_from_file("benches/configs.json").unwrap();
+
+    let dataset_path = format!("./datasets/{}.hdf5", configs[0].dataset_name);      // assume the dataset does not change
+    let hdf5_dataset = load_hdf5_dataset(&dataset_path).unwrap();
+    let queries = &hdf5_dataset.dataset_queries;
+
+    for (config_idx, config) in configs.iter().enumerate() {
+        let data = AngularData::from_view(hdf5_dataset.dataset_array.view());
+
+        // no metrics, grouped batches would fall back to one query at a time
+        let clann_config = Config {
+            metrics_output: MetricsOutput::None,
+            ..config.clone()
+        };
+        let mut clustered_index = init_with_config(data, clann_config).unwrap();
+        build(&mut clustered_index).unwrap();
+
+        let mut group = c.benchmark_group(format!(
+            "batch_config_{}_clusters_{}_L_{}_queries_{}",
+            config_idx,
+            config.num_clusters_factor,
+            config.num_tables,
+            queries.nrows()
+        ));
+        group
+            .sample_size(10)
+            .measurement_time(Duration::from_secs(30))
+            .warm_up_time(Duration::from_secs(1));
+
+        group.bench_function("search_batch", |b| {
+            b.iter(|| search_batch(&mut clustered_index, queries).unwrap());
+        });
+        group.bench_function("search_batch_grouped", |b| {
+            b.iter(|| search_batch_grouped(&mut clustered_index, queries).unwrap());
+        });
+
+        group.finish();
+    }
+}
+
 pub fn run_time_benchmarks(c: &mut Criterion) {
     print_benchmark_header("PUFFINN-CLANN Time Comparison");
     let pb = create_progress_bar("Running time comparison".to_string(), 100);
     compare_implementations_time(c);
+    compare_batch_strategies(c);
     pb.finish_with_message("Time Comparison complete");
 }
 
@@ -1,16 +1,8 @@
-use std::{fs::File, io::{self, Read}};
-
-use clann::core::Config;
+use clann::core::{Config, Result};
 use indicatif::{ProgressBar, ProgressStyle};
 
-pub mod db_utils;
-
-pub fn load_configs_from_file(path: &str) -> io::Result<Vec<Config>> {
-    let mut file = File::open(path)?;
-    let mut json = String::new();
-    file.read_to_string(&mut json)?;
-    let configs: Vec<Config> = serde_json::from_str(&json)?;
-    Ok(configs)
+pub fn load_configs_from_file(path: &str) -> Result<Vec<Config>> {
+    Config::list_from_file(path)
 }
 
 
@@ -25,27 +25,69 @@ fn main() {
     let header_file = c_api_dir.join("c_binder.h");
     let cpp_file = c_api_dir.join("c_binder.cpp");
 
-    // First, compile the C++ code using cc-rs
-    let mut build = cc::Build::new();
-    build
-        .cpp(true)
-        .file(cpp_file)
-        .include(puffinn_include_dir)
-        .include(c_api_dir)
-        .flag("-std=c++14")
-        .flag("-march=native")
-        .flag("-Wall")
-        .flag("-Wextra")
-        .flag("-O3")
-        .flag("-fopenmp");
-    for path in &hdf5.include_paths {
-        build.include(path);
+    // First, compile the C++ code using cc-rs, once per kernel variant. The variants are
+    // selected at runtime from the features of the CPU (see src/puffinn_binds/dispatch.rs),
+    // so the binary runs on any machine of the target architecture.
+    let target_arch = std::env::var("CARGO_CFG_TARGET_ARCH").unwrap_or_default();
+    let variants: &[(&str, &[&str])] = if target_arch == "x86_64" {
+        &[
+            ("base", &[]),
+            ("avx2", &["-mavx2", "-mfma", "-mpopcnt"]),
+            (
+                "avx512",
+                &["-mavx512f", "-mavx512bw", "-mavx512vl", "-mavx2", "-mfma", "-mpopcnt"],
+            ),
+        ]
+    } else {
+        &[("base", &[])]
+    };
+
+    let out_dir = std::path::PathBuf::from(std::env::var("OUT_DIR").unwrap());
+    // a single variant has no symbols clashing with another one
+    let objcopy = (variants.len() > 1).then(objcopy);
+    let mut objects = Vec::new();
+    for (variant, flags) in variants {
+        let mut build = cc::Build::new();
+        build
+            .cpp(true)
+            .out_dir(out_dir.join(variant))
+            .file(&cpp_file)
+            .include(puffinn_include_dir)
+            .include(c_api_dir)
+            .define("CPUFFINN_VARIANT", *variant)
+            .flag("-std=c++14")
+            .flag("-Wall")
+            .flag("-Wextra")
+            .flag("-O3")
+            .flag("-fopenmp");
+        for flag in *flags {
+            build.flag(flag);
+        }
+        for path in &hdf5.include_paths {
+            build.include(path);
+        }
+
+        for object in build.compile_intermediates() {
+            // FFHT defines its C functions in its header, so every variant has them: make them
+            // local to the variant. The inline functions shared by the variants (the standard
+            // library) are deduplicated by the linker, which keeps the first definition, the one
+            // of the base variant, which runs everywhere
+            if let Some(objcopy) = &objcopy {
+                let status = Command::new(objcopy)
+                    .args(["--wildcard", "--localize-symbol=fht_*", "--localize-symbol=helper_*", "--localize-symbol=fast_copy*"])
+                    .arg(&object)
+                    .status()
+                    .expect("Failed to run objcopy, set OBJCOPY to its path");
+                assert!(status.success(), "objcopy failed on {}", object.display());
+            }
+            objects.push(object);
+        }
     }
 
     // Attempt to compile
-    println!("cargo:rerun-if-changed=libpuffinn_ffi/c_binder.cpp");
-    println!("cargo:rerun-if-changed=libpuffinn_ffi/c_binder.h");
-    build.compile("libpuffinn");
+    println!("cargo:rerun-if-changed=libpuffinn-ffi/c_binder.cpp");
+    println!("cargo:rerun-if-changed=libpuffinn-ffi/c_binder.h");
+    cc::Build::new().cpp(true).objects(objects).compile("libpuffinn");
 
     // Now generate the Rust bindings
     let bindings = bindgen::Builder::default()
@@ -60,6 +102,7 @@ fn main() {
         .clang_arg("-x")
         .clang_arg("c++")
         .clang_arg("-std=c++14")
+        .clang_arg("-DCPUFFINN_ALL_VARIANTS")
         .clang_args(
             hdf5.include_paths.iter().map(|path| format!("-I{}", path.display())).collect::<Vec<_>>()
         )
@@ -75,6 +118,33 @@ fn main() {
     // Link against OpenMP
     println!("cargo:rustc-link-lib=gomp");
 
+    // Generate the gRPC service
+    #[cfg(feature = "serve")]
+    compile_protos();
+
     // rebuild if there is a commit
     println!("cargo:rerun-if-changed=.git/HEAD");
+}
+
+/// Path of objcopy: `OBJCOPY` if set, otherwise the GNU or the LLVM one, whichever is installed
+fn objcopy() -> String {
+    println!("cargo:rerun-if-env-changed=OBJCOPY");
+    if let Ok(objcopy) = std::env::var("OBJCOPY") {
+        return objcopy;
+    }
+    ["objcopy", "llvm-objcopy"]
+        .into_iter()
+        .find(|tool| Command::new(tool).arg("--version").output().is_ok_and(|output| output.status.success()))
+        .expect("Failed to find objcopy or llvm-objcopy, set OBJCOPY to its path")
+        .to_string()
+}
+
+#[cfg(feature = "serve")]
+fn compile_protos() {
+    // use the vendored protoc so that it doesn't need to be installed
+    let protoc = protoc_bin_vendored::protoc_bin_path().expect("Failed to find protoc");
+    std::env::set_var("PROTOC", protoc);
+
+    println!("cargo:rerun-if-changed=proto/clann.proto");
+    tonic_prost_build::compile_protos("proto/clann.proto").expect("Failed to compile protos");
 }
\ No newline at end of file
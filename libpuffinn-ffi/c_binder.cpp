@@ -1,18 +1,19 @@
 #include "c_binder.h"
 
 extern "C" {
-    CPUFFINN* CPUFFINN_load_from_file(const char* file_name, const char* dataset_name) {
+    // Returns nullptr if the dataset is missing or can't be deserialized
+    CPUFFINN* CPUFFINN_FN(load_from_file)(const char* file_name, const char* dataset_name) {
         // Open HDF5 file
         hid_t file_id = H5Fopen(file_name, H5F_ACC_RDONLY, H5P_DEFAULT);
         if (file_id < 0) {
-            throw std::runtime_error("Failed to open HDF5 file");
+            return nullptr;
         }
 
         // Open dataset
         hid_t dataset_id = H5Dopen(file_id, dataset_name, H5P_DEFAULT);
         if (dataset_id < 0) {
             H5Fclose(file_id);
-            throw std::runtime_error(std::string("Failed to open dataset: ") + dataset_name);
+            return nullptr;
         }
 
         // Read binary data into memory
@@ -32,11 +33,18 @@ extern "C" {
         std::istringstream* input_stream = new std::istringstream(buffer_str);
 
         // Create the PUFFINN index
-        return reinterpret_cast<CPUFFINN*>(new puffinn::Index<puffinn::CosineSimilarity>(*input_stream));
+        try {
+            auto index = new puffinn::Index<puffinn::CosineSimilarity>(*input_stream);
+            delete input_stream;
+            return reinterpret_cast<CPUFFINN*>(index);
+        } catch (...) {
+            delete input_stream;
+            return nullptr;
+        }
     }
 
     // Create a new index
-    CPUFFINN* CPUFFINN_index_create(const char* dataset_type, int dataset_args) {
+    CPUFFINN* CPUFFINN_FN(index_create)(const char* dataset_type, int dataset_args) {
 
         if (strcmp("angular", dataset_type) == 0) {
             return reinterpret_cast<CPUFFINN*>(new puffinn::Index<puffinn::CosineSimilarity>(dataset_args));
@@ -50,7 +58,7 @@ extern "C" {
     }
 
     // Rebuild the index
-    uint64_t CPUFFINN_index_rebuild(CPUFFINN* index, unsigned int num_maps) {
+    uint64_t CPUFFINN_FN(index_rebuild)(CPUFFINN* index, unsigned int num_maps) {
         try{
             auto cpp_index = reinterpret_cast<puffinn::Index<puffinn::CosineSimilarity>*>(index);
             return cpp_index->rebuild(num_maps);
@@ -59,15 +67,22 @@ extern "C" {
         }
     }
 
+    // Set the number of OpenMP threads of the calling thread, instead of OMP_NUM_THREADS
+    void CPUFFINN_FN(set_num_threads)(int num_threads) {
+        if (num_threads > 0) {
+            omp_set_num_threads(num_threads);
+        }
+    }
+
     // Insert a point into the index
-    void CPUFFINN_index_insert_cosine(CPUFFINN* index, float* point, int dimension) {
+    void CPUFFINN_FN(index_insert_cosine)(CPUFFINN* index, float* point, int dimension) {
         auto cpp_index = reinterpret_cast<puffinn::Index<puffinn::CosineSimilarity>*>(index);
         cpp_index->insert(std::vector<float>(point, point + dimension));
     }
 
-    // Search in the index
-    uint32_t* CPUFFINN_search_cosine(CPUFFINN* index, float* query, unsigned int k, float recall, float max_sim, int dimension) {
-        if (!query || dimension <= 0) {
+    // Search in the index, the number of ids in the returned buffer is written to result_len
+    uint32_t* CPUFFINN_FN(search_cosine)(CPUFFINN* index, float* query, unsigned int k, float recall, float max_sim, int dimension, unsigned int* result_len) {
+        if (!query || dimension <= 0 || !result_len) {
             std::cerr << "Error: Query is null or empty.\n";
             return nullptr;
         }
@@ -75,35 +90,27 @@ extern "C" {
         auto cpp_index = reinterpret_cast<puffinn::Index<puffinn::CosineSimilarity>*>(index);
         auto result = cpp_index->search(std::vector<float>(query, query + dimension), k, recall, max_sim);
     
-        if (result.empty()) {
-            uint32_t* empty_result = static_cast<uint32_t*>(malloc(sizeof(uint32_t)));
-            if (!empty_result) {
-                std::cerr << "Memory allocation failed for empty result!\n";
-                return nullptr;
-            }
-            *empty_result = 0xFFFFFFFF;  // Sentinel value for empty result
-            return empty_result;
-        }
-    
-        uint32_t* c_result = static_cast<uint32_t*>(malloc(result.size() * sizeof(uint32_t)));
+        // malloc at least one id so that an empty result still returns a valid pointer
+        uint32_t* c_result = static_cast<uint32_t*>(malloc((result.empty() ? 1 : result.size()) * sizeof(uint32_t)));
         if (!c_result) {
             std::cerr << "Memory allocation failed!\n";
             return nullptr;
         }
     
         std::memcpy(c_result, result.data(), result.size() * sizeof(uint32_t));
+        *result_len = static_cast<unsigned int>(result.size());
         return c_result;
     }    
 
-    unsigned int CPUFFINN_get_distance_computations() {
+    unsigned int CPUFFINN_FN(get_distance_computations)() {
         return puffinn::g_performance_metrics.get_distance_computations();
     }
     
-    void CPUFFINN_clear_distance_computations() {
+    void CPUFFINN_FN(clear_distance_computations)() {
         puffinn::g_performance_metrics.clear();
     }
 
-    void CPUFFINN_save_index(CPUFFINN* index, const char* file_name, int index_id) {
+    void CPUFFINN_FN(save_index)(CPUFFINN* index, const char* file_name, int index_id) {
         auto cpp_index = reinterpret_cast<puffinn::Index<puffinn::CosineSimilarity>*>(index);
         
         // Open the existing HDF5 file in read-write mode
@@ -144,4 +151,49 @@ extern "C" {
         H5Sclose(dataspace_id);
         H5Fclose(file_id);
     }
+
+    uint8_t* CPUFFINN_FN(serialize_to_buffer)(CPUFFINN* index, uint64_t* buffer_len) {
+        if (!index || !buffer_len) {
+            std::cerr << "Error: Index or output length is null.\n";
+            return nullptr;
+        }
+
+        auto cpp_index = reinterpret_cast<puffinn::Index<puffinn::CosineSimilarity>*>(index);
+
+        std::stringstream buffer;
+        try {
+            cpp_index->serialize(buffer, false);
+        } catch (...) {
+            std::cerr << "Error: Failed to serialize index.\n";
+            return nullptr;
+        }
+        std::string data = buffer.str();
+
+        // malloc at least one byte so that an empty index still returns a valid pointer
+        uint8_t* c_buffer = static_cast<uint8_t*>(malloc(data.size() > 0 ? data.size() : 1));
+        if (!c_buffer) {
+            std::cerr << "Memory allocation failed!\n";
+            return nullptr;
+        }
+
+        std::memcpy(c_buffer, data.data(), data.size());
+        *buffer_len = data.size();
+        return c_buffer;
+    }
+
+    CPUFFINN* CPUFFINN_FN(load_from_buffer)(const uint8_t* buffer, uint64_t buffer_len) {
+        if (!buffer || buffer_len == 0) {
+            std::cerr << "Error: Buffer is null or empty.\n";
+            return nullptr;
+        }
+
+        try {
+            std::string buffer_str(reinterpret_cast<const char*>(buffer), buffer_len);
+            std::istringstream input_stream(buffer_str);
+            return reinterpret_cast<CPUFFINN*>(new puffinn::Index<puffinn::CosineSimilarity>(input_stream));
+        } catch (...) {
+            std::cerr << "Error: Failed to deserialize index from buffer.\n";
+            return nullptr;
+        }
+    }
 }
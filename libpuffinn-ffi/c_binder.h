@@ -1,3 +1,16 @@
+// Kernel variants: build.rs compiles c_binder.cpp once per instruction set with
+// -DCPUFFINN_VARIANT=<name>, which suffixes the functions (CPUFFINN_avx2_index_create) and
+// renames the puffinn namespace, so that the variants can be linked in the same binary and
+// one of them selected at runtime. Without CPUFFINN_VARIANT the functions keep their names.
+#define CPUFFINN_CONCAT_(a, b) a##b
+#define CPUFFINN_CONCAT(a, b) CPUFFINN_CONCAT_(a, b)
+#ifdef CPUFFINN_VARIANT
+    #define puffinn CPUFFINN_CONCAT(puffinn_, CPUFFINN_VARIANT)
+    #define CPUFFINN_FN(name) CPUFFINN_CONCAT(CPUFFINN_CONCAT(CPUFFINN_, CPUFFINN_VARIANT), _##name)
+#else
+    #define CPUFFINN_FN(name) CPUFFINN_##name
+#endif
+
 #include "../libpuffinn/include/puffinn.hpp"
 #include <string.h>
 #include <iostream>
@@ -5,23 +18,43 @@
 #include <vector>
 #include <sstream>
 
-#define EMPTY_RESULT_SENTINEL 0xFFFFFFFF
+#define CPUFFINN_DECLARE_API(fn) \
+    CPUFFINN* fn(load_from_file)(const char* file_name, const char* dataset_name); \
+    \
+    CPUFFINN* fn(index_create)(const char* dataset_type, int dataset_args); \
+    uint64_t fn(index_rebuild)(CPUFFINN* index, unsigned int num_maps); \
+    \
+    /* OpenMP threads of the following rebuilds and searches of the calling thread */ \
+    void fn(set_num_threads)(int num_threads); \
+    \
+    /* For float data (angular) */ \
+    void fn(index_insert_cosine)(CPUFFINN* index, float* point, int dimension); \
+    /* Returns a malloc'd buffer of *result_len ids, at most k, owned by the caller */ \
+    uint32_t* fn(search_cosine)(CPUFFINN* index, float* query, unsigned int k, float recall, float max_sim, int dimension, unsigned int* result_len); \
+    \
+    unsigned int fn(get_distance_computations)(); \
+    void fn(clear_distance_computations)(); \
+    \
+    void fn(save_index)(CPUFFINN* index, const char* file_name, int index_number); \
+    \
+    /* In-memory serialization, the returned buffer is malloc'd and owned by the caller */ \
+    uint8_t* fn(serialize_to_buffer)(CPUFFINN* index, uint64_t* buffer_len); \
+    CPUFFINN* fn(load_from_buffer)(const uint8_t* buffer, uint64_t buffer_len);
+
+#define CPUFFINN_BASE_FN(name) CPUFFINN_base_##name
+#define CPUFFINN_AVX2_FN(name) CPUFFINN_avx2_##name
+#define CPUFFINN_AVX512_FN(name) CPUFFINN_avx512_##name
 
 extern "C" {
     struct CPUFFINN;
     typedef struct CPUFFINN CPUFFINN;
 
-    CPUFFINN* CPUFFINN_load_from_file(const char* file_name, const char* dataset_name);
-
-    CPUFFINN* CPUFFINN_index_create(const char* dataset_type, int dataset_args);
-    uint64_t CPUFFINN_index_rebuild(CPUFFINN* index, unsigned int num_maps);
-
-    // For float data (angular)
-    void CPUFFINN_index_insert_cosine(CPUFFINN* index, float* point, int dimension);
-    uint32_t* CPUFFINN_search_cosine(CPUFFINN* index, float* query, unsigned int k, float recall, float max_sim, int dimension);
-
-    unsigned int CPUFFINN_get_distance_computations();
-    void CPUFFINN_clear_distance_computations();
-
-    void CPUFFINN_save_index(CPUFFINN* index, const char* file_name, int index_number);
-}
\ No newline at end of file
+#ifdef CPUFFINN_ALL_VARIANTS
+    // the Rust bindings declare every variant, and dispatch at runtime
+    CPUFFINN_DECLARE_API(CPUFFINN_BASE_FN)
+    CPUFFINN_DECLARE_API(CPUFFINN_AVX2_FN)
+    CPUFFINN_DECLARE_API(CPUFFINN_AVX512_FN)
+#else
+    CPUFFINN_DECLARE_API(CPUFFINN_FN)
+#endif
+}
@@ -101,17 +101,6 @@ namespace puffinn {
         // first rebuild so that we know how many tables are at most used.
         std::unique_ptr<HashSourceArgs<THash>> hash_args;
 
-        // A buffer to hold the hash values of queries, to be reused across
-        // different queries.
-        // NOTE: This is not thread safe: i.e. we assume that no queries
-        // are evaluated in parallel.
-        std::vector<uint64_t> query_hashes;
-        // A buffer to hold the sketch values of queries, to be reused across
-        // different queries.
-        // NOTE: This is not thread safe: i.e. we assume that no queries
-        // are evaluated in parallel.
-        QuerySketches query_sketches;
-
     public:
         /// Construct an empty index.
         ///
@@ -556,13 +545,18 @@ namespace puffinn {
             g_performance_metrics.new_query();
             g_performance_metrics.start_timer(Computation::Total);
 
+            // Hash values and sketches of the query, local so that queries can be evaluated
+            // in parallel on the same index.
+            std::vector<uint64_t> query_hashes;
+            QuerySketches query_sketches;
+
             MaxBuffer maxbuffer(k);
             g_performance_metrics.start_timer(Computation::Hashing);
-            hash_source->hash_repetitions(query, this->query_hashes);
+            hash_source->hash_repetitions(query, query_hashes);
             g_performance_metrics.store_time(Computation::Hashing);
 
             g_performance_metrics.start_timer(Computation::Sketching);
-            filterer.sketch(query, this->query_sketches);
+            filterer.sketch(query, query_sketches);
             g_performance_metrics.store_time(Computation::Sketching);
 
             g_performance_metrics.start_timer(Computation::Search);
@@ -572,16 +566,16 @@ namespace puffinn {
                         query,
                         maxbuffer,
                         recall,
-                        this->query_sketches,
-                        this->query_hashes);
+                        query_sketches,
+                        query_hashes);
                     break;
                 case FilterType::Simple:
                     search_maps_simple_filter(
                         query,
                         maxbuffer,
                         recall,
-                        this->query_sketches,
-                        this->query_hashes);
+                        query_sketches,
+                        query_hashes);
                     break;
                 default:
                     search_maps(
@@ -589,8 +583,8 @@ namespace puffinn {
                         maxbuffer, 
                         recall, 
                         max_sim,
-                        this->query_sketches, 
-                        this->query_hashes
+                        query_sketches,
+                        query_hashes
                     );
             }
             g_performance_metrics.store_time(Computation::Search);
@@ -39,7 +39,8 @@ namespace puffinn {
 
     };
 
-    // A globally accessible structure to store performance metrics in.
+    // A globally accessible structure to store performance metrics in, one per thread so that
+    // concurrent searches each count their own distance computations.
     class PerformanceMetrics {
         std::vector<QueryMetrics> queries;
 
@@ -131,6 +132,6 @@ namespace puffinn {
         }
     };
 
-    PerformanceMetrics g_performance_metrics;
+    thread_local PerformanceMetrics g_performance_metrics;
 }
 
@@ -0,0 +1,358 @@
+//! Adapter for the [ann-benchmarks](https://github.com/erikbern/ann-benchmarks) suite.
+//!
+//! Reads the HDF5 datasets of the suite, maps the `args` and `query_args` of an entry of
+//! its `config.yml` to a [`Config`], and writes the results of a run where the suite looks
+//! for them, `results/<dataset>/<count>/clann/<run name>.hdf5`, with the attributes and the
+//! `times`, `neighbors` and `distances` datasets its plotting and metrics scripts read.
+//!
+//! ```no_run
+//! use clann::annbench::{load_dataset, result_path, run, write_results, RunParams};
+//! use serde_json::json;
+//!
+//! let dataset = load_dataset("./datasets/glove-25-angular.hdf5").unwrap();
+//! let params = RunParams::from_args(10, &[json!(84), json!(0.4)], &[json!(0.9)]).unwrap();
+//!
+//! let result = run(&dataset, "glove-25-angular", &params).unwrap();
+//! let path = result_path("./results", "glove-25-angular", &params);
+//! write_results(&path, "glove-25-angular", &dataset.distance, &params, &result).unwrap();
+//! ```
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use hdf5::types::VarLenUnicode;
+use hdf5::File;
+use log::info;
+use ndarray::{Array1, Array2, Ix2};
+use serde_json::Value;
+
+use crate::core::index::ClusteredIndex;
+use crate::core::{ClusteredIndexError, Config, Result};
+use crate::metricdata::AngularData;
+
+/// Name of the algorithm in the result files and directories
+pub const ALGORITHM: &str = "clann";
+
+/// A dataset of the suite, with its ground truth.
+pub struct AnnDataset {
+    pub train: Array2<f32>,
+    pub test: Array2<f32>,
+
+    /// Ids of the true nearest neighbors of each test point, closest first
+    pub neighbors: Array2<usize>,
+
+    /// Distances of the true nearest neighbors of each test point, closest first
+    pub distances: Array2<f32>,
+
+    /// Distance of the dataset, e.g. "angular" or "euclidean"
+    pub distance: String,
+}
+
+/// Loads a dataset in the ann-benchmarks HDF5 layout.
+///
+/// # Errors
+/// Returns `ClusteredIndexError::DataError` if the file can't be read or a dataset is missing
+pub fn load_dataset(path: &str) -> Result<AnnDataset> {
+    let to_err = |e: hdf5::Error| ClusteredIndexError::DataError(format!("{}: {}", path, e));
+    let file = File::open(path).map_err(to_err)?;
+
+    let read_f32 = |name: &str| {
+        file.dataset(name)
+            .and_then(|d| d.read::<f32, Ix2>())
+            .map_err(|e| ClusteredIndexError::DataError(format!("{}, dataset '{}': {}", path, name, e)))
+    };
+    let train = read_f32("train")?;
+    let test = read_f32("test")?;
+    let distances = read_f32("distances")?;
+    let neighbors = file
+        .dataset("neighbors")
+        .and_then(|d| d.read::<i32, Ix2>())
+        .map_err(|e| ClusteredIndexError::DataError(format!("{}, dataset 'neighbors': {}", path, e)))?
+        .mapv(|id| id as usize);
+    let distance = file
+        .attr("distance")
+        .and_then(|a| a.read_scalar::<VarLenUnicode>())
+        .map_err(to_err)?
+        .as_str()
+        .to_string();
+
+    info!(
+        "Loaded {} with {} points, {} queries and {} distance",
+        path,
+        train.nrows(),
+        test.nrows(),
+        distance
+    );
+
+    Ok(AnnDataset {
+        train,
+        test,
+        neighbors,
+        distances,
+        distance,
+    })
+}
+
+/// Parameters of a single run of the suite.
+#[derive(Debug, Clone)]
+pub struct RunParams {
+    /// Number of neighbors to return, the `--count` of the suite
+    pub count: usize,
+    pub num_tables: usize,
+    pub num_clusters_factor: f32,
+    pub delta: f32,
+}
+
+impl RunParams {
+    /// Maps the arguments of a `config.yml` entry of the suite to run parameters.
+    ///
+    /// The entry is expected to look like:
+    /// ```yaml
+    /// clann:
+    ///   args: [[84, 128], [0.4, 1.0]]   # num_tables, num_clusters_factor
+    ///   query_args: [[0.9, 0.95]]       # delta
+    /// ```
+    /// and `args`, `query_args` are one combination of it, e.g. `[84, 0.4]` and `[0.9]`.
+    ///
+    /// # Errors
+    /// Returns `ClusteredIndexError::ConfigError` if an argument is missing or is not a number
+    pub fn from_args(count: usize, args: &[Value], query_args: &[Value]) -> Result<Self> {
+        let number = |values: &[Value], i: usize, name: &str| {
+            values.get(i).and_then(Value::as_f64).ok_or_else(|| {
+                ClusteredIndexError::ConfigError(format!("missing or invalid argument {}", name))
+            })
+        };
+
+        Ok(Self {
+            count,
+            num_tables: number(args, 0, "num_tables")? as usize,
+            num_clusters_factor: number(args, 1, "num_clusters_factor")? as f32,
+            delta: number(query_args, 0, "delta")? as f32,
+        })
+    }
+
+    pub fn to_config(&self, dataset_name: &str) -> Config {
+        Config {
+            num_tables: self.num_tables,
+            num_clusters_factor: self.num_clusters_factor,
+            k: self.count,
+            delta: self.delta,
+            dataset_name: dataset_name.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Name of the run, unique for each combination of parameters
+    pub fn name(&self) -> String {
+        format!(
+            "CLANN(L={}, factor={}, delta={})",
+            self.num_tables, self.num_clusters_factor, self.delta
+        )
+    }
+}
+
+/// Outcome of a run, as recorded by the suite.
+#[derive(Debug, Clone)]
+pub struct RunResult {
+    /// Seconds to build the index
+    pub build_time: f64,
+
+    /// Memory of the cluster indices, in kB
+    pub index_size: f64,
+
+    /// Seconds of each query
+    pub times: Vec<f64>,
+
+    /// Distance computations of each query, centers included
+    pub distance_computations: Vec<usize>,
+
+    /// (distance, id) pairs of each query, closest first
+    pub neighbors: Vec<Vec<(f32, usize)>>,
+}
+
+/// Builds an index over the training points with `params` and searches every test point.
+///
+/// # Errors
+/// - `ClusteredIndexError::ConfigError` if the distance of the dataset is not angular, the
+///   only one CLANN indexes
+/// - Any error returned while building or searching the index
+pub fn run(dataset: &AnnDataset, dataset_name: &str, params: &RunParams) -> Result<RunResult> {
+    if dataset.distance != "angular" {
+        return Err(ClusteredIndexError::ConfigError(format!(
+            "unsupported distance {}",
+            dataset.distance
+        )));
+    }
+
+    info!("Running {} on {}", params.name(), dataset_name);
+    let data = AngularData::new(dataset.train.view());
+    let mut index: ClusteredIndex<_> = ClusteredIndex::new(params.to_config(dataset_name), data)?;
+
+    let start = Instant::now();
+    index.build()?;
+    let build_time = start.elapsed().as_secs_f64();
+    let index_size = index
+        .build_report()
+        .map_or(0.0, |report| report.memory_used as f64 / 1024.0);
+
+    let mut times = Vec::with_capacity(dataset.test.nrows());
+    let mut neighbors = Vec::with_capacity(dataset.test.nrows());
+    let mut distance_computations = Vec::with_capacity(dataset.test.nrows());
+    for query in dataset.test.rows() {
+        let query = query.to_vec();
+        let start = Instant::now();
+        let result = index.search(&query)?;
+        times.push(start.elapsed().as_secs_f64());
+        neighbors.push(result);
+        distance_computations.push(index.last_distance_computations());
+    }
+
+    Ok(RunResult {
+        build_time,
+        index_size,
+        times,
+        neighbors,
+        distance_computations,
+    })
+}
+
+/// Path of the result file of a run, the suite expects `<results>/<dataset>/<count>/<algorithm>/<name>.hdf5`.
+pub fn result_path(results_dir: &str, dataset_name: &str, params: &RunParams) -> PathBuf {
+    // the run name is used as a file name, keep it portable
+    let file_name: String = params
+        .name()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '.' || c == '=' { c } else { '_' })
+        .collect();
+
+    Path::new(results_dir)
+        .join(dataset_name)
+        .join(params.count.to_string())
+        .join(ALGORITHM)
+        .join(format!("{}.hdf5", file_name))
+}
+
+/// Pads each result to `count` neighbors, with id -1 and infinite distance, as the suite does.
+fn pad_results(neighbors: &[Vec<(f32, usize)>], count: usize) -> (Array2<i32>, Array2<f32>) {
+    let mut ids = Array2::from_elem((neighbors.len(), count), -1i32);
+    let mut distances = Array2::from_elem((neighbors.len(), count), f32::INFINITY);
+    for (i, result) in neighbors.iter().enumerate() {
+        for (j, &(distance, id)) in result.iter().take(count).enumerate() {
+            ids[[i, j]] = id as i32;
+            distances[[i, j]] = distance;
+        }
+    }
+    (ids, distances)
+}
+
+/// Writes the result of a run to `path` in the layout of the suite, creating the missing directories.
+///
+/// # Errors
+/// Returns `ClusteredIndexError::SerializeError` if the directories or the file can't be written
+pub fn write_results(
+    path: &Path,
+    dataset_name: &str,
+    distance: &str,
+    params: &RunParams,
+    result: &RunResult,
+) -> Result<()> {
+    let to_err = |e: hdf5::Error| ClusteredIndexError::SerializeError(e.to_string());
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .map_err(|e| ClusteredIndexError::SerializeError(e.to_string()))?;
+    }
+    let file = File::create(path).map_err(to_err)?;
+
+    let num_queries = result.times.len().max(1) as f64;
+    let mean_time = result.times.iter().sum::<f64>() / num_queries;
+    // the suite reads the candidates as the distance computations per query
+    let candidates = result.distance_computations.iter().sum::<usize>() as f64 / num_queries;
+
+    let text = |s: &str| {
+        s.parse::<VarLenUnicode>()
+            .map_err(|e| ClusteredIndexError::SerializeError(e.to_string()))
+    };
+    for (name, value) in [
+        ("algo", text(ALGORITHM)?),
+        ("dataset", text(dataset_name)?),
+        ("distance", text(distance)?),
+        ("name", text(&params.name())?),
+    ] {
+        file.new_attr::<VarLenUnicode>()
+            .create(name)
+            .and_then(|a| a.write_scalar(&value))
+            .map_err(to_err)?;
+    }
+    for (name, value) in [
+        ("build_time", result.build_time),
+        ("index_size", result.index_size),
+        ("best_search_time", mean_time),
+        ("candidates", candidates),
+    ] {
+        file.new_attr::<f64>()
+            .create(name)
+            .and_then(|a| a.write_scalar(&value))
+            .map_err(to_err)?;
+    }
+    for (name, value) in [("count", params.count as i64), ("run_count", 1)] {
+        file.new_attr::<i64>()
+            .create(name)
+            .and_then(|a| a.write_scalar(&value))
+            .map_err(to_err)?;
+    }
+    for name in ["batch_mode", "expect_extra"] {
+        file.new_attr::<bool>()
+            .create(name)
+            .and_then(|a| a.write_scalar(&false))
+            .map_err(to_err)?;
+    }
+
+    let times: Array1<f32> = result.times.iter().map(|&t| t as f32).collect();
+    let (neighbors, distances) = pad_results(&result.neighbors, params.count);
+    file.new_dataset_builder()
+        .with_data(&times)
+        .create("times")
+        .map_err(to_err)?;
+    file.new_dataset_builder()
+        .with_data(&neighbors)
+        .create("neighbors")
+        .map_err(to_err)?;
+    file.new_dataset_builder()
+        .with_data(&distances)
+        .create("distances")
+        .map_err(to_err)?;
+
+    info!("Wrote results of {} to {}", params.name(), path.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{pad_results, result_path, RunParams};
+
+    #[test]
+    fn test_params_from_args() {
+        let params = RunParams::from_args(10, &[json!(84), json!(0.4)], &[json!(0.9)]).unwrap();
+        assert_eq!(params.num_tables, 84);
+        assert_eq!(params.to_config("glove").k, 10);
+        assert!(RunParams::from_args(10, &[json!(84)], &[json!(0.9)]).is_err());
+
+        let path = result_path("results", "glove-25-angular", &params);
+        assert_eq!(
+            path.to_str().unwrap(),
+            "results/glove-25-angular/10/clann/CLANN_L=84__factor=0.4__delta=0.9_.hdf5"
+        );
+    }
+
+    #[test]
+    fn test_pad_results() {
+        let (ids, distances) = pad_results(&[vec![(0.1, 3)], vec![(0.2, 5), (0.3, 7)]], 2);
+        assert_eq!(ids.row(0).to_vec(), vec![3, -1]);
+        assert_eq!(ids.row(1).to_vec(), vec![5, 7]);
+        assert!(distances[[0, 1]].is_infinite());
+    }
+}
@@ -0,0 +1,504 @@
+//! Benchmark harness comparing CLANN with a single PUFFINN index over the whole dataset.
+//!
+//! Given a list of configurations, [`run_configs`] loads the dataset of each one from
+//! `<dataset_dir>/<dataset_name>.hdf5`, reuses the index cached in `index_dir` if one was
+//! built with the same parameters (building and caching it otherwise), searches the test set
+//! and saves the metrics to the SQLite database of [`MetricsOutput::DB`]. The PUFFINN baseline
+//! goes to the `puffinn_results` tables of the same database. Configurations already in the
+//! database for the current commit are skipped, unless [`BenchOptions::rerun`] is set.
+//!
+//! ```no_run
+//! use clann::bench::{load_configs, run_configs, BenchOptions, RunStatus};
+//!
+//! let configs = load_configs("benches/configs.json").unwrap();
+//! let outcomes = run_configs(&configs, &BenchOptions::default()).unwrap();
+//! for outcome in outcomes.iter().filter(|o| matches!(o.clann, RunStatus::Failed(_))) {
+//!     eprintln!("{} failed: {:?}", outcome.config.dataset_name, outcome.clann);
+//! }
+//! ```
+
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+use ndarray::{OwnedRepr, ViewRepr};
+use rusqlite::{params, Connection};
+
+use crate::core::index::{index_file_path, ClusteredIndex};
+use crate::core::{ClusteredIndexError, Config, MetricsGranularity, MetricsOutput, Result};
+use crate::metricdata::{AngularData, MetricData};
+use crate::puffinn_binds::{set_num_threads, PuffinnIndex};
+use crate::utils::metrics::schema::sqlite_migrate;
+use crate::utils::{load_hdf5_dataset, Hdf5Dataset};
+use crate::{build, init_from_file, init_with_config, save_metrics, search, serialize};
+
+type BenchIndex<'a> = ClusteredIndex<AngularData<ViewRepr<&'a f32>>>;
+
+/// Where a benchmark reads its datasets and writes its indexes and results.
+#[derive(Debug, Clone)]
+pub struct BenchOptions {
+    /// Directory of the cached indexes, created if it doesn't exist
+    pub index_dir: String,
+
+    /// SQLite database of the results, created with the metrics tables if it doesn't exist
+    pub db_path: String,
+
+    /// Directory of the HDF5 datasets, named after [`Config::dataset_name`]
+    pub dataset_dir: String,
+
+    /// Detail of the CLANN metrics saved
+    pub granularity: MetricsGranularity,
+
+    /// Whether to run configurations already in the database
+    pub rerun: bool,
+
+    /// Whether to run PUFFINN on the whole dataset next to every configuration
+    pub puffinn_baseline: bool,
+}
+
+impl Default for BenchOptions {
+    fn default() -> Self {
+        Self {
+            index_dir: "./__index_cache__".to_string(),
+            db_path: "./results_v2.sqlite3".to_string(),
+            dataset_dir: "./datasets".to_string(),
+            granularity: MetricsGranularity::Query,
+            rerun: false,
+            puffinn_baseline: true,
+        }
+    }
+}
+
+/// What became of one side of a configuration.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RunStatus {
+    /// Searched and saved to the database
+    Ran,
+
+    /// Already in the database
+    Skipped,
+
+    /// Failed with the given error, the next configurations are still run
+    Failed(String),
+}
+
+/// Outcome of a configuration, see [`run_config`].
+#[derive(Debug, Clone)]
+pub struct BenchOutcome {
+    pub config: Config,
+    pub clann: RunStatus,
+
+    /// `None` if [`BenchOptions::puffinn_baseline`] is not set
+    pub puffinn: Option<RunStatus>,
+}
+
+/// Results of PUFFINN over a whole dataset, as saved by [`save_puffinn_run`].
+#[derive(Debug, Clone)]
+pub struct PuffinnRun {
+    pub dataset_len: usize,
+    pub memory_bytes: usize,
+    pub search_time: Duration,
+
+    /// Search time of each query
+    pub query_times: Vec<Duration>,
+
+    /// Distance computations of each query
+    pub distance_computations: Vec<usize>,
+}
+
+/// Loads the list of configurations of a benchmark, see [`Config::list_from_file`].
+pub fn load_configs(path: &str) -> Result<Vec<Config>> {
+    Config::list_from_file(path)
+}
+
+/// Path of the cached index of `config`, the file [`crate::serialize`] writes in [`BenchOptions::index_dir`].
+pub fn index_path(config: &Config, options: &BenchOptions) -> String {
+    index_file_path(&options.index_dir, config)
+}
+
+/// Path of the dataset of `config` in [`BenchOptions::dataset_dir`].
+pub fn dataset_path(config: &Config, options: &BenchOptions) -> String {
+    format!("{}/{}.hdf5", options.dataset_dir, config.dataset_name)
+}
+
+/// Loads the dataset of `config`.
+///
+/// # Errors
+/// Returns `ClusteredIndexError::DataError` if the file can't be read
+pub fn load_dataset(config: &Config, options: &BenchOptions) -> Result<Hdf5Dataset> {
+    let path = dataset_path(config, options);
+    info!("Loading dataset {}", path);
+    load_hdf5_dataset(&path).map_err(ClusteredIndexError::DataError)
+}
+
+/// Opens the results database, creating or migrating the metrics tables.
+///
+/// # Errors
+/// Returns `ClusteredIndexError::ResultDBError` if the database can't be opened or migrated
+pub fn open_results(path: &str) -> Result<Connection> {
+    let mut conn = Connection::open(path).map_err(db_error)?;
+    sqlite_migrate(&mut conn).map_err(db_error)?;
+    Ok(conn)
+}
+
+fn db_error(e: rusqlite::Error) -> ClusteredIndexError {
+    ClusteredIndexError::ResultDBError(e.to_string())
+}
+
+/// Whether the database has search metrics of `config` for the current commit.
+///
+/// # Errors
+/// Returns `ClusteredIndexError::ResultDBError` if the query fails
+pub fn clann_run_exists(conn: &Connection, config: &Config) -> Result<bool> {
+    conn.query_row(
+        "SELECT EXISTS (
+            SELECT 1 FROM search_metrics
+            WHERE num_clusters BETWEEN ?1 - 1e-6 AND ?1 + 1e-6
+            AND num_tables = ?2
+            AND k = ?3
+            AND delta BETWEEN ?4 - 1e-6 AND ?4 + 1e-6
+            AND dataset = ?5
+            AND git_commit_hash = ?6
+        )",
+        params![
+            config.num_clusters_factor,
+            config.num_tables,
+            config.k,
+            config.delta,
+            config.dataset_name,
+            option_env!("GIT_COMMIT_HASH").unwrap_or("NO_COMMIT"),
+        ],
+        |row| row.get(0),
+    )
+    .map_err(db_error)
+}
+
+/// Whether the database has PUFFINN results for the parameters of `config`.
+///
+/// # Errors
+/// Returns `ClusteredIndexError::ResultDBError` if the query fails
+pub fn puffinn_run_exists(conn: &Connection, config: &Config) -> Result<bool> {
+    conn.query_row(
+        "SELECT EXISTS (
+            SELECT 1 FROM puffinn_results
+            WHERE num_tables = ?1
+            AND k = ?2
+            AND delta BETWEEN ?3 - 1e-6 AND ?3 + 1e-6
+            AND dataset = ?4
+        )",
+        params![config.num_tables, config.k, config.delta, config.dataset_name],
+        |row| row.get(0),
+    )
+    .map_err(db_error)
+}
+
+/// Loads the cached index of `config`, or builds it and writes it to the cache.
+///
+/// A cached index built with other parameters, e.g. another clustering, is rebuilt and
+/// overwritten. The search parameters of `config` apply to the returned index whether it was
+/// cached or not, and its metrics are written to the results database.
+///
+/// # Errors
+/// Returns an error if the index can't be built, or `ClusteredIndexError::SerializeError` if
+/// it can't be written to the cache
+pub fn load_or_build<'a>(
+    config: &Config,
+    data: AngularData<ViewRepr<&'a f32>>,
+    options: &BenchOptions,
+) -> Result<BenchIndex<'a>> {
+    let config = Config {
+        metrics_output: MetricsOutput::DB,
+        ..config.clone()
+    };
+
+    let path = index_path(&config, options);
+    if Path::new(&path).exists() {
+        info!("Loading index from {}", path);
+        match init_from_file(data.clone(), &path).and_then(|mut index| {
+            index.adopt_config(config.clone())?;
+            Ok(index)
+        }) {
+            Ok(index) => return Ok(index),
+            Err(e) => warn!("Rebuilding the cached index {}: {}", path, e),
+        }
+    }
+
+    let mut index = init_with_config(data, config)?;
+    build(&mut index)?;
+    fs::create_dir_all(&options.index_dir)
+        .map_err(|e| ClusteredIndexError::SerializeError(format!("{}: {}", options.index_dir, e)))?;
+    serialize(&index, &options.index_dir)?;
+    info!("Index written to {}", path);
+
+    Ok(index)
+}
+
+/// Searches the test set of `dataset` with CLANN and saves the metrics, with the recall, to
+/// the results database.
+///
+/// # Errors
+/// Returns an error if the index can't be loaded or built, if a search fails or if the
+/// metrics can't be saved
+pub fn run_clann(config: &Config, dataset: &Hdf5Dataset, options: &BenchOptions) -> Result<()> {
+    let data = AngularData::from_view(dataset.dataset_array.view());
+    let mut index = load_or_build(config, data, options)?;
+
+    let queries = dataset.dataset_queries.as_standard_layout();
+    let mut distances = Vec::with_capacity(queries.nrows());
+    let start = Instant::now();
+    for query in queries.rows() {
+        let query = query.as_slice().expect("rows of a standard layout array are contiguous");
+        let result = search(&mut index, query)?;
+        distances.push(result.iter().map(|&(distance, _)| distance).collect());
+    }
+    let search_time = start.elapsed();
+    info!(
+        "CLANN searched {} queries in {:.2?}",
+        queries.nrows(),
+        search_time
+    );
+
+    save_metrics(
+        &mut index,
+        &options.db_path,
+        options.granularity,
+        &dataset.ground_truth_distances,
+        &distances,
+        &search_time,
+    )
+}
+
+/// Builds a single PUFFINN index over the whole dataset with the tables of `config` and
+/// searches its test set.
+///
+/// # Errors
+/// Returns `ClusteredIndexError::PuffinnCreationError` or `PuffinnSearchError` if PUFFINN fails
+pub fn run_puffinn(config: &Config, dataset: &Hdf5Dataset) -> Result<PuffinnRun> {
+    let data = AngularData::from_view(dataset.dataset_array.view());
+    set_num_threads(config.threads());
+    let (index, memory_bytes) = PuffinnIndex::new(&data, config.num_tables)
+        .map_err(ClusteredIndexError::PuffinnCreationError)?;
+    info!("PUFFINN index created with {} bytes", memory_bytes);
+
+    let queries = dataset.dataset_queries.as_standard_layout();
+    let mut query_times = Vec::with_capacity(queries.nrows());
+    let mut distance_computations = Vec::with_capacity(queries.nrows());
+    let start = Instant::now();
+    for query in queries.rows() {
+        let query = query.as_slice().expect("rows of a standard layout array are contiguous");
+        let query_start = Instant::now();
+        let (_, computations) = index
+            .search_counted::<AngularData<OwnedRepr<f32>>>(query, config.k, f32::INFINITY, config.delta)
+            .map_err(ClusteredIndexError::PuffinnSearchError)?;
+        query_times.push(query_start.elapsed());
+        distance_computations.push(computations);
+    }
+    let search_time = start.elapsed();
+    info!(
+        "PUFFINN searched {} queries in {:.2?}",
+        queries.nrows(),
+        search_time
+    );
+
+    Ok(PuffinnRun {
+        dataset_len: data.num_points(),
+        memory_bytes,
+        search_time,
+        query_times,
+        distance_computations,
+    })
+}
+
+/// Saves the results of PUFFINN for the parameters of `config`, replacing earlier ones.
+///
+/// # Errors
+/// Returns `ClusteredIndexError::ResultDBError` if the results can't be written
+pub fn save_puffinn_run(conn: &mut Connection, config: &Config, run: &PuffinnRun) -> Result<()> {
+    let tx = conn.transaction().map_err(db_error)?;
+    tx.execute(
+        "INSERT OR REPLACE INTO puffinn_results
+        (num_tables, k, delta, dataset, dataset_len, memory_used_bytes,
+         total_time_ms, queries_per_second)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+        params![
+            config.num_tables,
+            config.k,
+            config.delta,
+            config.dataset_name,
+            run.dataset_len,
+            run.memory_bytes,
+            run.search_time.as_millis() as i64,
+            run.query_times.len() as f64 / run.search_time.as_secs_f64(),
+        ],
+    )
+    .map_err(db_error)?;
+
+    {
+        let mut stmt = tx
+            .prepare(
+                "INSERT OR REPLACE INTO puffinn_results_query
+                (num_tables, k, delta, dataset, query_idx, query_time_ms, distance_computations)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            )
+            .map_err(db_error)?;
+        for (idx, (time, count)) in run.query_times.iter().zip(&run.distance_computations).enumerate() {
+            stmt.execute(params![
+                config.num_tables,
+                config.k,
+                config.delta,
+                config.dataset_name,
+                idx,
+                time.as_millis() as i64,
+                count,
+            ])
+            .map_err(db_error)?;
+        }
+    }
+
+    tx.commit().map_err(db_error)
+}
+
+/// Runs CLANN, and PUFFINN if [`BenchOptions::puffinn_baseline`] is set, on a configuration
+/// whose dataset is loaded, skipping the sides already in the database.
+///
+/// # Errors
+/// Returns `ClusteredIndexError::ResultDBError` if the database can't be queried. Errors
+/// of the runs themselves are reported in the [`BenchOutcome`]
+pub fn run_config(
+    conn: &mut Connection,
+    config: &Config,
+    dataset: &Hdf5Dataset,
+    options: &BenchOptions,
+) -> Result<BenchOutcome> {
+    let clann = if !options.rerun && clann_run_exists(conn, config)? {
+        info!("Skipping CLANN on {}, already in the database", config.dataset_name);
+        RunStatus::Skipped
+    } else {
+        status(run_clann(config, dataset, options))
+    };
+
+    let puffinn = if !options.puffinn_baseline {
+        None
+    } else if !options.rerun && puffinn_run_exists(conn, config)? {
+        info!("Skipping PUFFINN on {}, already in the database", config.dataset_name);
+        Some(RunStatus::Skipped)
+    } else {
+        Some(status(
+            run_puffinn(config, dataset).and_then(|run| save_puffinn_run(conn, config, &run)),
+        ))
+    };
+
+    Ok(BenchOutcome {
+        config: config.clone(),
+        clann,
+        puffinn,
+    })
+}
+
+fn status(result: Result<()>) -> RunStatus {
+    match result {
+        Ok(()) => RunStatus::Ran,
+        Err(e) => {
+            warn!("Run failed: {}", e);
+            RunStatus::Failed(e.to_string())
+        }
+    }
+}
+
+/// Runs every configuration in order, see [`run_config`]. The dataset is loaded once for
+/// consecutive configurations on the same one.
+///
+/// # Errors
+/// Returns `ClusteredIndexError::ResultDBError` if the database can't be opened or queried.
+/// A configuration whose dataset can't be loaded is reported as failed
+pub fn run_configs(configs: &[Config], options: &BenchOptions) -> Result<Vec<BenchOutcome>> {
+    let mut conn = open_results(&options.db_path)?;
+
+    let mut loaded: Option<(String, Hdf5Dataset)> = None;
+    let mut outcomes = Vec::with_capacity(configs.len());
+    for (i, config) in configs.iter().enumerate() {
+        info!("Configuration {} of {}: {:?}", i + 1, configs.len(), config);
+
+        if !matches!(&loaded, Some((name, _)) if *name == config.dataset_name) {
+            loaded = None;
+            match load_dataset(config, options) {
+                Ok(dataset) => loaded = Some((config.dataset_name.clone(), dataset)),
+                Err(e) => {
+                    let failed = RunStatus::Failed(e.to_string());
+                    warn!("Configuration {} failed: {}", i + 1, e);
+                    outcomes.push(BenchOutcome {
+                        config: config.clone(),
+                        clann: failed.clone(),
+                        puffinn: options.puffinn_baseline.then_some(failed),
+                    });
+                    continue;
+                }
+            }
+        }
+        let (_, dataset) = loaded.as_ref().expect("the dataset was just loaded");
+
+        outcomes.push(run_config(&mut conn, config, dataset, options)?);
+    }
+
+    Ok(outcomes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_paths_and_existing_runs() {
+        let options = BenchOptions {
+            index_dir: "cache".to_string(),
+            dataset_dir: "data".to_string(),
+            ..Default::default()
+        };
+        let config = Config {
+            dataset_name: "glove-25-angular".to_string(),
+            num_clusters_factor: 0.5,
+            num_tables: 20,
+            k: 10,
+            delta: 0.9,
+            ..Default::default()
+        };
+        assert_eq!(index_path(&config, &options), "cache/index_glove-25-angular_k0.50_L20.h5");
+        assert_eq!(dataset_path(&config, &options), "data/glove-25-angular.hdf5");
+
+        let mut conn = open_results(":memory:").unwrap();
+        assert!(!clann_run_exists(&conn, &config).unwrap());
+        assert!(!puffinn_run_exists(&conn, &config).unwrap());
+
+        let run = PuffinnRun {
+            dataset_len: 100,
+            memory_bytes: 1024,
+            search_time: Duration::from_millis(20),
+            query_times: vec![Duration::from_millis(10); 2],
+            distance_computations: vec![40, 50],
+        };
+        save_puffinn_run(&mut conn, &config, &run).unwrap();
+        // saving again replaces the results
+        save_puffinn_run(&mut conn, &config, &run).unwrap();
+        assert!(puffinn_run_exists(&conn, &config).unwrap());
+        assert!(!puffinn_run_exists(&conn, &Config { k: 5, ..config.clone() }).unwrap());
+        let queries: usize = conn
+            .query_row("SELECT COUNT(*) FROM puffinn_results_query", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(queries, 2);
+
+        conn.execute(
+            "INSERT INTO runs (run_id) VALUES ('test')",
+            [],
+        )
+        .unwrap();
+        conn.execute(
+            "INSERT INTO search_metrics (run_id, num_clusters, num_tables, k, delta, dataset, git_commit_hash)
+            VALUES ('test', ?1, 20, 10, ?2, 'glove-25-angular', ?3)",
+            params![0.5f32, 0.9f32, option_env!("GIT_COMMIT_HASH").unwrap_or("NO_COMMIT")],
+        )
+        .unwrap();
+        assert!(clann_run_exists(&conn, &config).unwrap());
+        assert!(!clann_run_exists(&conn, &Config { delta: 0.8, ..config }).unwrap());
+    }
+}
@@ -0,0 +1,483 @@
+//! Minimal HTTP vector search service over a CLANN index, built with the `server` feature.
+//!
+//! ```text
+//! cargo run --release --features server --bin clann-server -- \
+//!     <dataset.hdf5> <index.bin> [--addr 127.0.0.1:8080] [--threads N] [--timeout-ms 1000] \
+//!     [--max-pending N] [--metric angular|euclidean]
+//! ```
+//!
+//! The dataset is the `train` split of an ann-benchmarks HDF5 file, the index a file written
+//! by `clann::serialize_binary` for that dataset with the distance of `--metric`, angular by
+//! default. The searches run on a pool of `--threads` threads; a search that times out keeps
+//! running on it, so new searches are answered with 503 while `--max-pending` searches are
+//! queued or running. Endpoints:
+//! - `POST /search` with `{"vector": [...], "k": 10, "filters": {"exclude": [3, 7], "max_distance": 0.5}}`,
+//!   `k` and `filters` are optional
+//! - `GET /stats`
+
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use clann::core::{BlockingPool, ClusteredIndexError, Config, IndexHandle};
+use clann::metricdata::{AngularData, EuclideanData};
+use clann::utils::load_hdf5_dataset;
+use clap::builder::RangedU64ValueParser;
+use clap::{value_parser, Arg, Command};
+use log::{error, info};
+use ndarray::{Array2, OwnedRepr};
+use serde::{Deserialize, Serialize};
+
+/// Distance of the served index
+#[derive(Debug, Clone, Copy)]
+enum Metric {
+    Angular,
+    Euclidean,
+}
+
+/// Served index, with the distance of `--metric`
+enum Index {
+    Angular(IndexHandle<AngularData<OwnedRepr<f32>>>),
+    Euclidean(IndexHandle<EuclideanData<OwnedRepr<f32>>>),
+}
+
+/// Evaluates `$body` with `$handle` bound to the handle of `$index`, whatever its distance
+macro_rules! with_handle {
+    ($index:expr, $handle:ident => $body:expr) => {
+        match $index {
+            Index::Angular($handle) => $body,
+            Index::Euclidean($handle) => $body,
+        }
+    };
+}
+
+impl Index {
+    /// Loads the index at `path` over `points` with the distance `metric`, searched on a pool
+    /// of `threads` threads
+    fn load(metric: Metric, points: Array2<f32>, path: &str, threads: usize) -> clann::core::Result<Self> {
+        let pool = BlockingPool::new(threads);
+        Ok(match metric {
+            Metric::Angular => {
+                let index = clann::init_from_mmap(AngularData::new(points), path)?;
+                Index::Angular(IndexHandle::with_pool(index, pool))
+            }
+            Metric::Euclidean => {
+                let index = clann::init_from_mmap(EuclideanData::new(points), path)?;
+                Index::Euclidean(IndexHandle::with_pool(index, pool))
+            }
+        })
+    }
+}
+
+struct Args {
+    dataset_path: String,
+    index_path: String,
+    addr: SocketAddr,
+    threads: usize,
+    timeout: Duration,
+    max_pending: usize,
+    metric: Metric,
+}
+
+impl Args {
+    fn parse() -> Self {
+        let args = cli().get_matches();
+        let metric = match args.get_one::<String>("metric").unwrap().as_str() {
+            "euclidean" => Metric::Euclidean,
+            _ => Metric::Angular,
+        };
+
+        let threads = args
+            .get_one::<usize>("threads")
+            .copied()
+            .unwrap_or_else(|| std::thread::available_parallelism().map_or(4, |n| n.get()));
+
+        Self {
+            dataset_path: args.get_one::<String>("dataset").unwrap().clone(),
+            index_path: args.get_one::<String>("index").unwrap().clone(),
+            addr: *args.get_one::<SocketAddr>("addr").unwrap(),
+            threads,
+            timeout: Duration::from_millis(*args.get_one::<u64>("timeout-ms").unwrap()),
+            max_pending: args.get_one::<usize>("max-pending").copied().unwrap_or(4 * threads),
+            metric,
+        }
+    }
+}
+
+fn cli() -> Command {
+    Command::new("clann-server")
+        .about("HTTP vector search service over a CLANN index")
+        .arg(
+            Arg::new("dataset")
+                .value_name("DATASET")
+                .required(true)
+                .help("HDF5 dataset in the ann-benchmarks format, whose train split the index was built on"),
+        )
+        .arg(
+            Arg::new("index")
+                .value_name("INDEX")
+                .required(true)
+                .help("Index file written by `clann::serialize_binary` for the dataset"),
+        )
+        .arg(
+            Arg::new("addr")
+                .long("addr")
+                .value_name("ADDR")
+                .value_parser(value_parser!(SocketAddr))
+                .default_value("127.0.0.1:8080")
+                .help("Address to listen on"),
+        )
+        .arg(
+            Arg::new("threads")
+                .long("threads")
+                .value_name("N")
+                .value_parser(RangedU64ValueParser::<usize>::new().range(1..))
+                .help("Threads of the search pool and of the runtime [default: number of CPUs]"),
+        )
+        .arg(
+            Arg::new("timeout-ms")
+                .long("timeout-ms")
+                .value_name("MS")
+                .value_parser(value_parser!(u64))
+                .default_value("1000")
+                .help("Time after which a search is answered with 408"),
+        )
+        .arg(
+            Arg::new("max-pending")
+                .long("max-pending")
+                .value_name("N")
+                .value_parser(RangedU64ValueParser::<usize>::new().range(1..))
+                .help("Searches queued or running on the pool past which new ones are answered with 503 [default: 4 per thread]"),
+        )
+        .arg(
+            Arg::new("metric")
+                .long("metric")
+                .value_name("METRIC")
+                .value_parser(["angular", "euclidean"])
+                .default_value("angular")
+                .help("Distance the index was built with"),
+        )
+}
+
+struct AppState {
+    // searches run concurrently on the pool of the handle under its read lock, each with its own k
+    index: Index,
+    default_k: usize,
+    timeout: Duration,
+    // searches queued or running on the pool, timed out ones included, past which new ones are refused
+    max_pending: usize,
+    queries_served: AtomicU64,
+}
+
+#[derive(Deserialize)]
+struct SearchRequest {
+    vector: Vec<f32>,
+    k: Option<usize>,
+    #[serde(default)]
+    filters: Filters,
+}
+
+/// Filters applied to the neighbors of a query
+#[derive(Deserialize, Default)]
+struct Filters {
+    /// Ids that must not be returned
+    #[serde(default)]
+    exclude: Vec<usize>,
+
+    /// Neighbors farther than this are not returned, and the clusters beyond it not searched
+    max_distance: Option<f32>,
+}
+
+#[derive(Serialize)]
+struct Neighbor {
+    id: usize,
+    distance: f32,
+}
+
+#[derive(Serialize)]
+struct SearchResponse {
+    neighbors: Vec<Neighbor>,
+    took_ms: f64,
+}
+
+#[derive(Serialize)]
+struct StatsResponse {
+    num_points: usize,
+    dimensions: usize,
+    num_clusters: usize,
+    queries_served: u64,
+    config: Config,
+}
+
+struct ApiError(StatusCode, String);
+
+impl From<ClusteredIndexError> for ApiError {
+    fn from(e: ClusteredIndexError) -> Self {
+        let status = match e {
+            ClusteredIndexError::ConfigError(_)
+            | ClusteredIndexError::DataError(_)
+            | ClusteredIndexError::DimensionMismatch { .. } => StatusCode::BAD_REQUEST,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        ApiError(status, e.to_string())
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.0, Json(serde_json::json!({ "error": self.1 }))).into_response()
+    }
+}
+
+async fn search(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<SearchRequest>,
+) -> Result<Json<SearchResponse>, ApiError> {
+    let start = Instant::now();
+    let k = request.k.unwrap_or(state.default_k);
+    if k == 0 {
+        return Err(ApiError(StatusCode::BAD_REQUEST, "k must be greater than zero".to_string()));
+    }
+    // the top-k is allocated upfront, a huge k would abort the server
+    let num_points = with_handle!(&state.index, index => index.num_points()?);
+    if k > num_points {
+        return Err(ApiError(
+            StatusCode::BAD_REQUEST,
+            format!("k = {} is larger than the {} points of the index", k, num_points),
+        ));
+    }
+
+    // the search is not interrupted on timeout, it runs to completion on the pool, whose queue
+    // would grow without bound under a load it can't keep up with
+    let pending = with_handle!(&state.index, index => index.pool().pending());
+    if pending >= state.max_pending {
+        return Err(ApiError(
+            StatusCode::SERVICE_UNAVAILABLE,
+            format!("{} searches are already pending", pending),
+        ));
+    }
+
+    let SearchRequest { vector, filters, .. } = request;
+    let task = with_handle!(&state.index, index => {
+        index.search_with_async(vector, filters.exclude, Some(k), filters.max_distance)
+    });
+    let result = tokio::time::timeout(state.timeout, task)
+        .await
+        .map_err(|_| ApiError(StatusCode::REQUEST_TIMEOUT, "search timed out".to_string()))??;
+    let neighbors = result
+        .into_iter()
+        .map(|(distance, id)| Neighbor { id, distance })
+        .collect();
+
+    state.queries_served.fetch_add(1, Ordering::Relaxed);
+    Ok(Json(SearchResponse {
+        neighbors,
+        took_ms: start.elapsed().as_secs_f64() * 1000.0,
+    }))
+}
+
+async fn stats(State(state): State<Arc<AppState>>) -> Result<Json<StatsResponse>, ApiError> {
+    let queries_served = state.queries_served.load(Ordering::Relaxed);
+    // short reads under the read lock, which no writer ever holds in the server
+    let response = with_handle!(&state.index, index => StatsResponse {
+        num_points: index.num_points()?,
+        dimensions: index.dimensions()?,
+        num_clusters: index.num_clusters()?,
+        queries_served,
+        config: index.config()?,
+    });
+
+    Ok(Json(response))
+}
+
+fn app(state: Arc<AppState>) -> Router {
+    Router::new()
+        .route("/search", post(search))
+        .route("/stats", get(stats))
+        .with_state(state)
+}
+
+fn main() {
+    env_logger::Builder::from_default_env()
+        .format_timestamp_millis()
+        .init();
+
+    let args = Args::parse();
+
+    info!("Loading dataset {}", args.dataset_path);
+    let dataset = load_hdf5_dataset(&args.dataset_path).unwrap_or_else(|e| {
+        error!("{}", e);
+        std::process::exit(1);
+    });
+
+    info!("Loading {:?} index {}", args.metric, args.index_path);
+    let index = Index::load(args.metric, dataset.dataset_array, &args.index_path, args.threads).unwrap_or_else(|e| {
+        error!("{}", e);
+        std::process::exit(1);
+    });
+    let default_k = with_handle!(&index, index => index.config())
+        .unwrap_or_else(|e| {
+            error!("{}", e);
+            std::process::exit(1);
+        })
+        .k;
+
+    let state = Arc::new(AppState {
+        default_k,
+        index,
+        timeout: args.timeout,
+        max_pending: args.max_pending,
+        queries_served: AtomicU64::new(0),
+    });
+
+    // the workers only serve the connections, the searches run on the pool of the index
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(args.threads)
+        .enable_all()
+        .build()
+        .expect("Failed to start the runtime");
+
+    runtime.block_on(async {
+        let listener = tokio::net::TcpListener::bind(args.addr)
+            .await
+            .unwrap_or_else(|e| {
+                error!("Failed to bind {}: {}", args.addr, e);
+                std::process::exit(1);
+            });
+        info!(
+            "Serving on {} with {} search threads, timeout {:?}",
+            args.addr, args.threads, args.timeout
+        );
+        if let Err(e) = axum::serve(listener, app(state)).await {
+            error!("Server error: {}", e);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+
+    use clann::core::IndexMode;
+    use clann::utils::datagen::unit_vectors;
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    /// Serves a flat index over 200 points, refusing searches past `max_pending`, on the
+    /// returned runtime
+    fn serve(max_pending: usize) -> (tokio::runtime::Runtime, SocketAddr) {
+        let config = Config {
+            index_mode: IndexMode::Flat,
+            ..Default::default()
+        };
+        let mut index = clann::init_with_config(AngularData::new(unit_vectors(200, 8, 1)), config).unwrap();
+        clann::build(&mut index).unwrap();
+        let state = Arc::new(AppState {
+            index: Index::Angular(IndexHandle::with_pool(index, BlockingPool::new(2))),
+            default_k: 10,
+            timeout: Duration::from_secs(10),
+            max_pending,
+            queries_served: AtomicU64::new(0),
+        });
+
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()
+            .unwrap();
+        let listener = runtime.block_on(tokio::net::TcpListener::bind("127.0.0.1:0")).unwrap();
+        let addr = listener.local_addr().unwrap();
+        runtime.spawn(async move { axum::serve(listener, app(state)).await.unwrap() });
+        (runtime, addr)
+    }
+
+    /// Status and JSON body of the response to `method path` with the JSON `body`
+    fn request(addr: SocketAddr, method: &str, path: &str, body: &Value) -> (u16, Value) {
+        let body = body.to_string();
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(
+            stream,
+            "{} {} HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            method,
+            path,
+            body.len(),
+            body
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let status = response[9..12].parse().unwrap();
+        let (_, body) = response.split_once("\r\n\r\n").unwrap();
+        (status, serde_json::from_str(body).unwrap_or(Value::Null))
+    }
+
+    #[test]
+    fn test_search() {
+        let (_runtime, addr) = serve(8);
+        let point = unit_vectors(200, 8, 1).row(3).to_vec();
+
+        let (status, found) = request(addr, "POST", "/search", &json!({ "vector": point }));
+        assert_eq!(status, 200);
+        let neighbors = found["neighbors"].as_array().unwrap();
+        assert_eq!(neighbors.len(), 10);
+        assert_eq!(neighbors[0]["id"], 3);
+
+        let request_body = json!({ "vector": point, "k": 3, "filters": { "exclude": [3] } });
+        let (status, found) = request(addr, "POST", "/search", &request_body);
+        assert_eq!(status, 200);
+        let ids: Vec<u64> = found["neighbors"].as_array().unwrap().iter().map(|n| n["id"].as_u64().unwrap()).collect();
+        assert_eq!(ids.len(), 3);
+        assert!(!ids.contains(&3));
+
+        // the distance bounds the search, not the neighbors it returned
+        let max_distance = neighbors[4]["distance"].as_f64().unwrap();
+        let request_body = json!({ "vector": point, "filters": { "max_distance": max_distance } });
+        let (status, found) = request(addr, "POST", "/search", &request_body);
+        assert_eq!(status, 200);
+        let within = neighbors.iter().filter(|n| n["distance"].as_f64().unwrap() <= max_distance).count();
+        assert_eq!(found["neighbors"].as_array().unwrap().len(), within);
+
+        for invalid in [
+            json!({ "vector": point, "k": 0 }),
+            json!({ "vector": point, "k": 1000 }),
+            json!({ "vector": [0.5, 0.5] }),
+            json!({ "vector": point, "filters": { "max_distance": -1.0 } }),
+        ] {
+            let (status, error) = request(addr, "POST", "/search", &invalid);
+            assert_eq!(status, 400);
+            assert!(error["error"].is_string());
+        }
+    }
+
+    #[test]
+    fn test_search_saturated() {
+        let (_runtime, addr) = serve(0);
+        let point = unit_vectors(1, 8, 2).row(0).to_vec();
+        let (status, error) = request(addr, "POST", "/search", &json!({ "vector": point }));
+        assert_eq!(status, 503);
+        assert!(error["error"].is_string());
+    }
+
+    #[test]
+    fn test_stats() {
+        let (_runtime, addr) = serve(8);
+        let point = unit_vectors(1, 8, 2).row(0).to_vec();
+        assert_eq!(request(addr, "POST", "/search", &json!({ "vector": point })).0, 200);
+
+        let (status, stats) = request(addr, "GET", "/stats", &Value::Null);
+        assert_eq!(status, 200);
+        assert_eq!(stats["num_points"], 200);
+        assert_eq!(stats["dimensions"], 8);
+        assert!(stats["num_clusters"].as_u64().unwrap() > 0);
+        assert_eq!(stats["queries_served"], 1);
+        assert_eq!(stats["config"]["k"], 10);
+    }
+}
@@ -0,0 +1,85 @@
+use crate::core::MappedBytes;
+use crate::metricdata::{MetricData, SubsetView};
+use crate::puffinn_binds::{set_num_threads, IndexableSimilarity, PuffinnIndex};
+
+/// Approximate nearest neighbor index built over the points of a single cluster.
+///
+/// The clustered search only needs each cluster to return candidate points, so the
+/// LSH implementation is pluggable. [`PuffinnIndex`] (through the C++ FFI) is the default;
+/// with the `rust-lsh` feature, [`crate::lsh::CrossPolytopeIndex`] is a pure-Rust alternative.
+pub trait ClusterBackend<M: MetricData>: Sized {
+    /// Builds an index over the points of `data` listed in `indices`.
+    ///
+    /// Returns the index together with the memory it uses, in bytes.
+    fn build(data: &M, indices: &[usize], num_tables: usize) -> Result<(Self, usize), String>;
+
+    /// Number of threads the following [`build`](Self::build)s on the calling thread may use,
+    /// for backends that build in parallel. Does nothing by default.
+    fn set_num_threads(_num_threads: usize) {}
+
+    /// Searches the `k` nearest neighbors of `query` within distance `max_dist`, with target recall `recall`.
+    ///
+    /// Searches may run concurrently on the same index. Returned ids are local to the cluster,
+    /// i.e. positions in the `indices` passed to [`build`](Self::build), together with the
+    /// number of distance computations of this search.
+    fn search(
+        &self,
+        query: &[M::DataType],
+        k: usize,
+        max_dist: f32,
+        recall: f32,
+    ) -> Result<(Vec<u32>, usize), String>;
+
+    fn to_bytes(&self) -> Result<Vec<u8>, String>;
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, String>;
+
+    /// Restores an index from the bytes of [`to_bytes`](Self::to_bytes) in a memory mapped
+    /// file, see [`crate::init_from_mmap`].
+    ///
+    /// Backends that can read their arrays in place keep `bytes`, so that every process
+    /// mapping the file shares one physical copy of them. By default the bytes are decoded
+    /// with [`from_bytes`](Self::from_bytes) into memory of the process.
+    fn from_mapped(bytes: MappedBytes) -> Result<Self, String> {
+        Self::from_bytes(&bytes)
+    }
+}
+
+impl<M> ClusterBackend<M> for PuffinnIndex
+where
+    M: MetricData + IndexableSimilarity<M>,
+{
+    /// Inserts the points of the cluster straight from `data`, without copying the cluster.
+    fn build(data: &M, indices: &[usize], num_tables: usize) -> Result<(Self, usize), String> {
+        PuffinnIndex::new(&SubsetView::new(data, indices), num_tables)
+    }
+
+    /// The OpenMP threads of the rebuilds, which otherwise follow `OMP_NUM_THREADS`
+    fn set_num_threads(num_threads: usize) {
+        set_num_threads(num_threads)
+    }
+
+    fn search(
+        &self,
+        query: &[M::DataType],
+        k: usize,
+        max_dist: f32,
+        recall: f32,
+    ) -> Result<(Vec<u32>, usize), String> {
+        PuffinnIndex::search_counted::<M>(self, query, k, max_dist, recall)
+    }
+
+    fn to_bytes(&self) -> Result<Vec<u8>, String> {
+        PuffinnIndex::to_bytes(self)
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        PuffinnIndex::from_bytes(bytes)
+    }
+
+    /// PUFFINN only loads an index into tables it allocates, so the hash tables are decoded
+    /// into memory of the process: a mapped file shares the cluster members but not the tables.
+    fn from_mapped(bytes: MappedBytes) -> Result<Self, String> {
+        PuffinnIndex::from_bytes(&bytes)
+    }
+}
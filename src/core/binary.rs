@@ -0,0 +1,496 @@
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::ops::Range;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::core::index::{ClusterCenter, Hierarchy};
+use crate::core::mapped::Members;
+use crate::core::Config;
+
+/// Magic bytes at the start of every binary index file
+const MAGIC: &[u8; 8] = b"CLANNBIN";
+
+/// Version of the binary layout, bumped on incompatible changes
+pub(crate) const FORMAT_VERSION: u32 = 2;
+
+/// Size of the fixed preamble: magic, version (u32) and header length (u64)
+const PREAMBLE_LEN: usize = MAGIC.len() + 4 + 8;
+
+/// Magic bytes at the start of every collection file
+const COLLECTION_MAGIC: &[u8; 8] = b"CLANNCOL";
+
+/// Version of the collection layout, bumped on incompatible changes
+const COLLECTION_VERSION: u32 = 1;
+
+/// Alignment of the blobs in the files, relative to the start of the file, so that backends
+/// restored from a mapping can read their arrays in place
+const BLOB_ALIGN: u64 = 8;
+
+/// Bytes to add after `len` bytes to reach the next multiple of [`BLOB_ALIGN`]
+fn padding(len: u64) -> u64 {
+    (BLOB_ALIGN - len % BLOB_ALIGN) % BLOB_ALIGN
+}
+
+/// Metadata stored at the start of a binary index file.
+///
+/// File layout (integers are little-endian):
+/// ```text
+/// | magic (8) | version (u32) | header_len (u64) | header (JSON) | member section | blob section |
+/// ```
+/// The member section holds the dataset ids of the points of every cluster, as `u64`s one
+/// cluster after the other, `members` of them per cluster. The clusters of the header have
+/// no members, they are filled from the section when the file is read. The blob section
+/// holds the serialized PUFFINN index of each non brute-force cluster, located through
+/// `blobs`. The header is padded with spaces and the blobs with zeros, so that the member
+/// section and every blob start at a multiple of 8 bytes from the start of the file.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct BinaryHeader {
+    pub(crate) config: Config,
+    pub(crate) clusters: Vec<ClusterCenter>,
+    /// Number of members of each cluster in the member section
+    pub(crate) members: Vec<u64>,
+    /// Coarse cells of a two-level index, missing from the files of one-level indexes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) hierarchy: Option<Hierarchy>,
+    /// (offset, length) of each cluster's PUFFINN index, relative to the start of the blob section
+    pub(crate) blobs: Vec<Option<(u64, u64)>>,
+}
+
+/// Writes the header, with the hierarchy of a two-level index, and the PUFFINN blobs of each
+/// cluster (`None` for brute force clusters).
+///
+/// The file is written under a temporary name and renamed, an index mapped from the previous
+/// file keeps reading its members and blobs from it.
+pub(crate) fn write_binary(
+    file_path: &str,
+    config: &Config,
+    clusters: &[ClusterCenter],
+    hierarchy: Option<&Hierarchy>,
+    blobs: &[Option<Vec<u8>>],
+) -> Result<(), String> {
+    let temp_path = format!("{}.tmp", file_path);
+    let file = File::create(&temp_path).map_err(|e| e.to_string())?;
+    let mut writer = BufWriter::new(file);
+    write_binary_to(&mut writer, config, clusters, hierarchy, blobs)?;
+    let file = writer.into_inner().map_err(|e| e.to_string())?;
+    file.sync_all().map_err(|e| e.to_string())?;
+    fs::rename(&temp_path, Path::new(file_path)).map_err(|e| e.to_string())
+}
+
+/// [`write_binary()`] to any writer, e.g. the buffer of a collection
+pub(crate) fn write_binary_to<W: Write>(
+    mut writer: W,
+    config: &Config,
+    clusters: &[ClusterCenter],
+    hierarchy: Option<&Hierarchy>,
+    blobs: &[Option<Vec<u8>>],
+) -> Result<(), String> {
+    let mut offset = 0u64;
+    let locations = blobs
+        .iter()
+        .map(|blob| {
+            blob.as_ref().map(|bytes| {
+                offset += padding(offset);
+                let location = (offset, bytes.len() as u64);
+                offset += bytes.len() as u64;
+                location
+            })
+        })
+        .collect();
+
+    let header = BinaryHeader {
+        config: config.clone(),
+        clusters: clusters
+            .iter()
+            .map(|cluster| ClusterCenter {
+                assignment: Members::default(),
+                ..cluster.clone()
+            })
+            .collect(),
+        members: clusters.iter().map(|cluster| cluster.assignment.len() as u64).collect(),
+        hierarchy: hierarchy.cloned(),
+        blobs: locations,
+    };
+    let mut header_json = serde_json::to_vec(&header).map_err(|e| e.to_string())?;
+    let header_end = (PREAMBLE_LEN + header_json.len()) as u64;
+    header_json.resize(header_json.len() + padding(header_end) as usize, b' ');
+
+    writer.write_all(MAGIC).map_err(|e| e.to_string())?;
+    writer
+        .write_all(&FORMAT_VERSION.to_le_bytes())
+        .map_err(|e| e.to_string())?;
+    writer
+        .write_all(&(header_json.len() as u64).to_le_bytes())
+        .map_err(|e| e.to_string())?;
+    writer.write_all(&header_json).map_err(|e| e.to_string())?;
+    for cluster in clusters {
+        for &point in &cluster.assignment {
+            writer
+                .write_all(&(point as u64).to_le_bytes())
+                .map_err(|e| e.to_string())?;
+        }
+    }
+    let mut written = 0u64;
+    for bytes in blobs.iter().flatten() {
+        let zeros = [0u8; BLOB_ALIGN as usize];
+        writer
+            .write_all(&zeros[..padding(written) as usize])
+            .map_err(|e| e.to_string())?;
+        written += padding(written);
+        writer.write_all(bytes).map_err(|e| e.to_string())?;
+        written += bytes.len() as u64;
+    }
+    writer.flush().map_err(|e| e.to_string())
+}
+
+/// Parses a binary index file, returning its header, with the members of the clusters
+/// copied from the member section, and the blob section.
+///
+/// `bytes` is usually a memory mapped file: the blob section borrows from it.
+pub(crate) fn parse_binary(bytes: &[u8]) -> Result<(BinaryHeader, &[u8]), String> {
+    let (mut header, member_section, blob_section) = parse_header(bytes)?;
+    if let Some(cluster) = truncated_blob(&header, blob_section) {
+        return Err(format!("truncated blob section at cluster {}", cluster));
+    }
+    for (cluster, range) in header.clusters.iter_mut().zip(member_ranges(&header.members)) {
+        cluster.assignment = Members::decode(&member_section[range])?;
+    }
+
+    Ok((header, blob_section))
+}
+
+/// Byte range of the members of each cluster in the member section, for the member counts
+/// of a header checked by [`parse_header()`]
+pub(crate) fn member_ranges(members: &[u64]) -> impl Iterator<Item = Range<usize>> + '_ {
+    let mut end = 0;
+    members.iter().map(move |&count| {
+        let start = end;
+        end += count as usize * 8;
+        start..end
+    })
+}
+
+/// Position of the first cluster whose blob ends past the end of `blob_section`, if any
+pub(crate) fn truncated_blob(header: &BinaryHeader, blob_section: &[u8]) -> Option<usize> {
+    header.blobs.iter().position(|location| {
+        location.is_some_and(|(offset, len)| offset.saturating_add(len) > blob_section.len() as u64)
+    })
+}
+
+/// [`parse_binary()`] without checking that the blobs fit in the blob section, returning the
+/// member section instead of filling the members of the clusters
+pub(crate) fn parse_header(bytes: &[u8]) -> Result<(BinaryHeader, &[u8], &[u8]), String> {
+    if bytes.len() < PREAMBLE_LEN || &bytes[..MAGIC.len()] != MAGIC {
+        return Err("not a CLANN binary index file".to_string());
+    }
+
+    let version = u32::from_le_bytes(bytes[8..12].try_into().unwrap());
+    if version != FORMAT_VERSION {
+        return Err(format!(
+            "unsupported binary format version {} (expected {})",
+            version, FORMAT_VERSION
+        ));
+    }
+
+    let header_len = u64::from_le_bytes(bytes[12..20].try_into().unwrap()) as usize;
+    let header_end = PREAMBLE_LEN
+        .checked_add(header_len)
+        .filter(|&end| end <= bytes.len())
+        .ok_or_else(|| "truncated header".to_string())?;
+
+    let header: BinaryHeader =
+        serde_json::from_slice(&bytes[PREAMBLE_LEN..header_end]).map_err(|e| e.to_string())?;
+    if header.members.len() != header.clusters.len() {
+        return Err(format!(
+            "{} clusters but {} member counts",
+            header.clusters.len(),
+            header.members.len()
+        ));
+    }
+    let members_end = header
+        .members
+        .iter()
+        .try_fold(header_end, |end, &count| {
+            usize::try_from(count).ok()?.checked_mul(8).and_then(|len| end.checked_add(len))
+        })
+        .filter(|&end| end <= bytes.len())
+        .ok_or_else(|| "truncated member section".to_string())?;
+    let member_section = &bytes[header_end..members_end];
+    let blob_section = &bytes[members_end..];
+
+    Ok((header, member_section, blob_section))
+}
+
+/// Named index of a collection file, located in its section
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct CollectionEntry {
+    pub(crate) name: String,
+    /// (offset, length) of the binary index, relative to the start of the section
+    pub(crate) location: (u64, u64),
+}
+
+/// Several binary indexes in one file, e.g. one per embedding model or tenant.
+///
+/// File layout (integers are little-endian):
+/// ```text
+/// | magic (8) | version (u32) | manifest_len (u64) | manifest (JSON) | section |
+/// ```
+/// The section holds the binary index of each collection, in the layout of
+/// [`write_binary()`], located through `collections`. As in a binary index file, the manifest
+/// and the indexes are padded so that every index starts at a multiple of 8 bytes.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct Manifest {
+    pub(crate) collections: Vec<CollectionEntry>,
+}
+
+/// Parses a collection file, returning its manifest and the section of the indexes.
+pub(crate) fn parse_collections(bytes: &[u8]) -> Result<(Manifest, &[u8]), String> {
+    if bytes.len() < PREAMBLE_LEN || &bytes[..COLLECTION_MAGIC.len()] != COLLECTION_MAGIC {
+        return Err("not a CLANN collection file".to_string());
+    }
+
+    let version = u32::from_le_bytes(bytes[8..12].try_into().unwrap());
+    if version != COLLECTION_VERSION {
+        return Err(format!(
+            "unsupported collection format version {} (expected {})",
+            version, COLLECTION_VERSION
+        ));
+    }
+
+    let manifest_len = u64::from_le_bytes(bytes[12..20].try_into().unwrap()) as usize;
+    let manifest_end = PREAMBLE_LEN
+        .checked_add(manifest_len)
+        .filter(|&end| end <= bytes.len())
+        .ok_or_else(|| "truncated manifest".to_string())?;
+
+    let manifest: Manifest =
+        serde_json::from_slice(&bytes[PREAMBLE_LEN..manifest_end]).map_err(|e| e.to_string())?;
+    let section = &bytes[manifest_end..];
+
+    for entry in &manifest.collections {
+        collection_bytes(section, entry.location)?;
+    }
+
+    Ok((manifest, section))
+}
+
+/// Bytes of the index at `(offset, len)` in the section of a collection file
+fn collection_bytes(section: &[u8], (offset, len): (u64, u64)) -> Result<&[u8], String> {
+    offset
+        .checked_add(len)
+        .and_then(|end| section.get(usize::try_from(offset).ok()?..usize::try_from(end).ok()?))
+        .ok_or_else(|| "truncated collection section".to_string())
+}
+
+/// Binary index of the collection `name` in the collection file `bytes`.
+pub(crate) fn find_collection<'a>(bytes: &'a [u8], name: &str) -> Result<&'a [u8], String> {
+    let (manifest, section) = parse_collections(bytes)?;
+    let location = manifest
+        .collections
+        .iter()
+        .find(|c| c.name == name)
+        .map(|c| c.location)
+        .ok_or_else(|| format!("no collection named {}", name))?;
+    collection_bytes(section, location)
+}
+
+/// Names of the collections of the file at `file_path`, in the order they were first saved.
+pub(crate) fn collection_names(file_path: &str) -> Result<Vec<String>, String> {
+    let bytes = fs::read(file_path).map_err(|e| format!("file {}: {}", file_path, e))?;
+    let (manifest, _) = parse_collections(&bytes)?;
+    Ok(manifest.collections.into_iter().map(|c| c.name).collect())
+}
+
+/// Writes the binary index `index` as the collection `name` of the file at `file_path`,
+/// replacing the collection with the same name and keeping the others. The file is created
+/// if it doesn't exist.
+///
+/// The file is written to a temporary name and renamed, so the other collections are never
+/// lost to a failed write.
+pub(crate) fn write_collection(file_path: &str, name: &str, index: &[u8]) -> Result<(), String> {
+    let existing = match fs::read(file_path) {
+        Ok(bytes) => Some(bytes),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e.to_string()),
+    };
+    let mut indexes: Vec<(String, &[u8])> = match &existing {
+        Some(bytes) => {
+            let (manifest, section) = parse_collections(bytes)?;
+            manifest
+                .collections
+                .into_iter()
+                .filter(|c| c.name != name)
+                .map(|c| Ok((c.name, collection_bytes(section, c.location)?)))
+                .collect::<Result<_, String>>()?
+        }
+        None => Vec::new(),
+    };
+    indexes.push((name.to_string(), index));
+
+    let mut offset = 0u64;
+    let manifest = Manifest {
+        collections: indexes
+            .iter()
+            .map(|(name, bytes)| {
+                offset += padding(offset);
+                let location = (offset, bytes.len() as u64);
+                offset += bytes.len() as u64;
+                CollectionEntry {
+                    name: name.clone(),
+                    location,
+                }
+            })
+            .collect(),
+    };
+    let mut manifest_json = serde_json::to_vec(&manifest).map_err(|e| e.to_string())?;
+    let manifest_end = (PREAMBLE_LEN + manifest_json.len()) as u64;
+    manifest_json.resize(manifest_json.len() + padding(manifest_end) as usize, b' ');
+
+    let temp_path = format!("{}.tmp", file_path);
+    let file = File::create(&temp_path).map_err(|e| e.to_string())?;
+    let mut writer = BufWriter::new(file);
+    writer.write_all(COLLECTION_MAGIC).map_err(|e| e.to_string())?;
+    writer
+        .write_all(&COLLECTION_VERSION.to_le_bytes())
+        .map_err(|e| e.to_string())?;
+    writer
+        .write_all(&(manifest_json.len() as u64).to_le_bytes())
+        .map_err(|e| e.to_string())?;
+    writer.write_all(&manifest_json).map_err(|e| e.to_string())?;
+    let mut written = 0u64;
+    for (_, bytes) in &indexes {
+        let zeros = [0u8; BLOB_ALIGN as usize];
+        writer
+            .write_all(&zeros[..padding(written) as usize])
+            .map_err(|e| e.to_string())?;
+        written += padding(written);
+        writer.write_all(bytes).map_err(|e| e.to_string())?;
+        written += bytes.len() as u64;
+    }
+    let file = writer.into_inner().map_err(|e| e.to_string())?;
+    file.sync_all().map_err(|e| e.to_string())?;
+    fs::rename(&temp_path, Path::new(file_path)).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::test_dir;
+
+    fn cluster(idx: usize, brute_force: bool) -> ClusterCenter {
+        ClusterCenter {
+            idx,
+            center_idx: idx * 10,
+            radius: 0.5,
+            assignment: vec![idx * 10, idx * 10 + 1].into(),
+            brute_force,
+            memory_used: 0,
+            num_tables: None,
+            build_time: Default::default(),
+            search_stats: Default::default(),
+            member_distances: None,
+            outlier: false,
+            over_memory_ceiling: false,
+            outlier_threshold: None,
+        }
+    }
+
+    #[test]
+    fn test_binary_roundtrip() {
+        let dir = test_dir("binary_roundtrip");
+        let path = dir.join("index.bin");
+        let path = path.to_str().unwrap();
+        let clusters = vec![cluster(0, false), cluster(1, true), cluster(2, false)];
+        let blobs = vec![Some(vec![1u8, 2, 3]), None, Some(vec![4u8, 5])];
+
+        write_binary(path, &Config::default(), &clusters, None, &blobs).unwrap();
+        let bytes = std::fs::read(path).unwrap();
+        let (header, blob_section) = parse_binary(&bytes).unwrap();
+
+        assert_eq!(header.clusters.len(), 3);
+        assert_eq!(header.clusters[1].assignment[..], [10, 11]);
+        // the members are stored after the header, as u64s aligned in the file
+        let (_, member_section, _) = parse_header(&bytes).unwrap();
+        assert_eq!(member_section.len(), 3 * 2 * 8);
+        assert_eq!((member_section.as_ptr() as usize - bytes.as_ptr() as usize) % 8, 0);
+        assert_eq!(member_section[16..24], 10u64.to_le_bytes());
+        // every blob starts at a multiple of 8 bytes from the start of the file
+        assert_eq!(header.blobs, vec![Some((0, 3)), None, Some((8, 2))]);
+        assert_eq!(blob_section, &[1, 2, 3, 0, 0, 0, 0, 0, 4, 5]);
+        assert_eq!((bytes.len() - blob_section.len()) % 8, 0);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_binary_rejects_corrupted_files() {
+        assert!(parse_binary(b"not an index").is_err());
+
+        let dir = test_dir("binary_truncated");
+        let path = dir.join("index.bin");
+        let path = path.to_str().unwrap();
+        write_binary(path, &Config::default(), &[cluster(0, false)], None, &[Some(vec![0u8; 16])])
+            .unwrap();
+        let bytes = std::fs::read(path).unwrap();
+        assert!(parse_binary(&bytes[..bytes.len() - 1]).is_err());
+        let (header, _, blob_section) = parse_header(&bytes[..bytes.len() - 1]).unwrap();
+        assert_eq!(truncated_blob(&header, blob_section), Some(0));
+        // cut in the members of the cluster
+        assert!(parse_header(&bytes[..bytes.len() - 17]).is_err());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_collections() {
+        let dir = test_dir("binary_collections");
+        let path = dir.join("collections.clann");
+        let path = path.to_str().unwrap();
+
+        write_collection(path, "text", &[1, 2, 3]).unwrap();
+        write_collection(path, "images", &[4, 5]).unwrap();
+        let bytes = std::fs::read(path).unwrap();
+        assert_eq!(find_collection(&bytes, "text").unwrap(), &[1, 2, 3]);
+        assert_eq!(find_collection(&bytes, "images").unwrap(), &[4, 5]);
+        assert!(find_collection(&bytes, "audio").is_err());
+
+        // replaced in place of the old one, the others are kept
+        write_collection(path, "text", &[6]).unwrap();
+        let bytes = std::fs::read(path).unwrap();
+        let (manifest, _) = parse_collections(&bytes).unwrap();
+        let names: Vec<&str> = manifest.collections.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(names, vec!["images", "text"]);
+        assert_eq!(find_collection(&bytes, "text").unwrap(), &[6]);
+        assert_eq!(find_collection(&bytes, "images").unwrap(), &[4, 5]);
+        for name in ["images", "text"] {
+            let index = find_collection(&bytes, name).unwrap();
+            assert_eq!((index.as_ptr() as usize - bytes.as_ptr() as usize) % 8, 0);
+        }
+
+        assert!(parse_collections(&bytes[..bytes.len() - 1]).is_err());
+
+        // a location overflowing the offsets is out of the section
+        let manifest = Manifest {
+            collections: vec![CollectionEntry {
+                name: "text".to_string(),
+                location: (u64::MAX, 2),
+            }],
+        };
+        let manifest_json = serde_json::to_vec(&manifest).unwrap();
+        let mut corrupted = COLLECTION_MAGIC.to_vec();
+        corrupted.extend_from_slice(&COLLECTION_VERSION.to_le_bytes());
+        corrupted.extend_from_slice(&(manifest_json.len() as u64).to_le_bytes());
+        corrupted.extend_from_slice(&manifest_json);
+        corrupted.extend_from_slice(&[0; 16]);
+        assert!(parse_collections(&corrupted).is_err());
+        assert!(find_collection(&corrupted, "text").is_err());
+        std::fs::write(path, &corrupted).unwrap();
+        assert!(write_collection(path, "images", &[1]).is_err());
+
+        std::fs::write(path, b"not a collection file").unwrap();
+        assert!(write_collection(path, "text", &[1]).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,156 @@
+use rand::Rng;
+use rusqlite::{params, Connection, OptionalExtension};
+
+use super::errors::{ClusteredIndexError, Result};
+
+/// 64-bit FNV-1a hasher.
+///
+/// Fingerprints are stored on disk, so the hash must not change between Rust releases,
+/// which `DefaultHasher` does not guarantee.
+pub(crate) struct Fnv64(u64);
+
+impl Fnv64 {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0000_0100_0000_01b3;
+
+    pub(crate) fn new() -> Self {
+        Self(Self::OFFSET_BASIS)
+    }
+
+    pub(crate) fn write(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.0 ^= b as u64;
+            self.0 = self.0.wrapping_mul(Self::PRIME);
+        }
+    }
+
+    pub(crate) fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Query bytes and the results of the query
+pub(crate) type CacheEntry = (Vec<u8>, Vec<(f32, usize)>);
+
+/// On-disk cache of search results, stored in a SQLite database.
+///
+/// Entries are keyed by a fingerprint of the content of the index and of the search
+/// parameters, and by the exact bytes of the query, so results of an index are never returned
+/// for another one, a rebuilt index (with different clusters or LSH tables) starts from an
+/// empty cache, and changing k or delta between batches doesn't return the old results.
+pub(crate) struct QueryCache {
+    conn: Connection,
+    content: u64, // fingerprint of the dataset, clusters and cluster indices, random once they changed
+}
+
+impl QueryCache {
+    pub(crate) fn open(path: &str, content: u64) -> Result<Self> {
+        let conn =
+            Connection::open(path).map_err(|e| ClusteredIndexError::ResultDBError(e.to_string()))?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS query_cache (
+                fingerprint TEXT NOT NULL,
+                query BLOB NOT NULL,
+                results TEXT NOT NULL,
+                PRIMARY KEY (fingerprint, query)
+            )",
+            [],
+        )
+        .map_err(|e| ClusteredIndexError::ResultDBError(e.to_string()))?;
+
+        Ok(Self { conn, content })
+    }
+
+    /// Switches to new entries after the index was modified, without fingerprinting its whole
+    /// content again. The new fingerprint is random, so the entries are not shared with
+    /// another process, even one that modified the same index the same way.
+    pub(crate) fn invalidate(&mut self) {
+        let mut hasher = Fnv64::new();
+        hasher.write(&self.content.to_le_bytes());
+        hasher.write(&rand::thread_rng().gen::<u64>().to_le_bytes());
+        self.content = hasher.finish();
+    }
+
+    /// Fingerprint of the entries of the index searched with `parameters`, the serialized
+    /// search configuration
+    fn fingerprint(&self, parameters: &[u8]) -> String {
+        let mut hasher = Fnv64::new();
+        hasher.write(&self.content.to_le_bytes());
+        hasher.write(parameters);
+        format!("{:016x}", hasher.finish())
+    }
+
+    pub(crate) fn get(&self, parameters: &[u8], query: &[u8]) -> Result<Option<Vec<(f32, usize)>>> {
+        let results: Option<String> = self
+            .conn
+            .query_row(
+                "SELECT results FROM query_cache WHERE fingerprint = ?1 AND query = ?2",
+                params![self.fingerprint(parameters), query],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(|e| ClusteredIndexError::ResultDBError(e.to_string()))?;
+
+        results
+            .map(|r| {
+                serde_json::from_str(&r).map_err(|e| ClusteredIndexError::ResultDBError(e.to_string()))
+            })
+            .transpose()
+    }
+
+    /// Stores all `entries`, results of searches with `parameters`, in a single transaction.
+    pub(crate) fn put_many(&mut self, parameters: &[u8], entries: &[CacheEntry]) -> Result<()> {
+        let fingerprint = self.fingerprint(parameters);
+        let tx = self
+            .conn
+            .transaction()
+            .map_err(|e| ClusteredIndexError::ResultDBError(e.to_string()))?;
+        {
+            let mut stmt = tx
+                .prepare(
+                    "INSERT OR REPLACE INTO query_cache (fingerprint, query, results) VALUES (?1, ?2, ?3)",
+                )
+                .map_err(|e| ClusteredIndexError::ResultDBError(e.to_string()))?;
+            for (query, results) in entries {
+                let results = serde_json::to_string(results)
+                    .map_err(|e| ClusteredIndexError::ResultDBError(e.to_string()))?;
+                stmt.execute(params![fingerprint, query, results])
+                    .map_err(|e| ClusteredIndexError::ResultDBError(e.to_string()))?;
+            }
+        }
+        tx.commit()
+            .map_err(|e| ClusteredIndexError::ResultDBError(e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Fnv64, QueryCache};
+
+    #[test]
+    fn test_fnv_reference_value() {
+        let mut hasher = Fnv64::new();
+        hasher.write(b"a");
+        assert_eq!(hasher.finish(), 0xaf63_dc4c_8601_ec8c);
+    }
+
+    #[test]
+    fn test_cache_roundtrip() {
+        let mut cache = QueryCache::open(":memory:", 1).unwrap();
+        let results = vec![(0.1, 4), (0.2, 7)];
+
+        assert_eq!(cache.get(b"k=2", &[1, 2, 3]).unwrap(), None);
+        cache.put_many(b"k=2", &[(vec![1, 2, 3], results.clone())]).unwrap();
+        assert_eq!(cache.get(b"k=2", &[1, 2, 3]).unwrap(), Some(results.clone()));
+        assert_eq!(cache.get(b"k=2", &[1, 2]).unwrap(), None);
+
+        // same index, other search parameters
+        assert_eq!(cache.get(b"k=3", &[1, 2, 3]).unwrap(), None);
+
+        // same database, modified index
+        cache.invalidate();
+        assert_eq!(cache.get(b"k=2", &[1, 2, 3]).unwrap(), None);
+        cache.put_many(b"k=2", &[(vec![1, 2, 3], results.clone())]).unwrap();
+        assert_eq!(cache.get(b"k=2", &[1, 2, 3]).unwrap(), Some(results));
+    }
+}
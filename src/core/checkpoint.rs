@@ -0,0 +1,144 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::core::{ClusteredIndexError, Result};
+
+use super::index::{ClusterCenter, ClusteringSummary, Hierarchy};
+use super::memory::BuildReport;
+
+const HEADER_FILE: &str = "checkpoint.json";
+
+/// What a checkpoint was made from, a build resumes only from a checkpoint with the same key.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct CheckpointKey {
+    /// See [`Config::build_parameters`](crate::core::Config)
+    pub(crate) config: Option<Value>,
+    pub(crate) num_points: usize,
+    pub(crate) dimensions: usize,
+    pub(crate) data_hash: u64,
+}
+
+/// Clustering of an interrupted build, written once before the cluster indices are created.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct CheckpointHeader {
+    pub(crate) key: CheckpointKey,
+    pub(crate) clusters: Vec<ClusterCenter>,
+    pub(crate) report: BuildReport,
+    #[serde(default)]
+    pub(crate) clustering: ClusteringSummary,
+    #[serde(default)]
+    pub(crate) hierarchy: Option<Hierarchy>,
+}
+
+/// Work directory of a resumable build: the clustering, and one file per completed cluster index.
+///
+/// Files are written to a temporary name and renamed, so a build killed while writing never
+/// leaves a truncated file behind.
+pub(crate) struct Checkpoint {
+    dir: PathBuf,
+}
+
+impl Checkpoint {
+    /// Opens the work directory, creating it if needed.
+    pub(crate) fn open(dir: &str) -> Result<Self> {
+        fs::create_dir_all(dir).map_err(|e| {
+            ClusteredIndexError::SerializeError(format!("cannot create checkpoint directory {}: {}", dir, e))
+        })?;
+        Ok(Self { dir: PathBuf::from(dir) })
+    }
+
+    /// Reads the clustering of a previous build, `None` if there is none.
+    ///
+    /// # Errors
+    /// - `ClusteredIndexError::ConfigError` if the checkpoint was made with another
+    ///   configuration or dataset
+    /// - `ClusteredIndexError::SerializeError` if the header cannot be read
+    pub(crate) fn load_header(&self, key: &CheckpointKey) -> Result<Option<CheckpointHeader>> {
+        let path = self.dir.join(HEADER_FILE);
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let header: CheckpointHeader = fs::read(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|bytes| serde_json::from_slice(&bytes).map_err(|e| e.to_string()))
+            .map_err(|e| {
+                ClusteredIndexError::SerializeError(format!("cannot read {}: {}", path.display(), e))
+            })?;
+        if header.key != *key {
+            return Err(ClusteredIndexError::ConfigError(format!(
+                "the checkpoint in {} was made with another configuration or dataset, delete it to start over",
+                self.dir.display()
+            )));
+        }
+        Ok(Some(header))
+    }
+
+    pub(crate) fn save_header(&self, header: &CheckpointHeader) -> Result<()> {
+        let bytes = serde_json::to_vec(header)
+            .map_err(|e| ClusteredIndexError::SerializeError(e.to_string()))?;
+        self.write(&self.dir.join(HEADER_FILE), &bytes)
+    }
+
+    /// Reads the index of a completed cluster, with its memory and build time, `None` if the
+    /// cluster was not completed.
+    pub(crate) fn load_cluster(&self, idx: usize) -> Result<Option<(Vec<u8>, usize, Duration)>> {
+        let path = self.cluster_path(idx);
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let bytes = fs::read(&path).map_err(|e| {
+            ClusteredIndexError::SerializeError(format!("cannot read {}: {}", path.display(), e))
+        })?;
+        if bytes.len() < 16 {
+            return Err(ClusteredIndexError::SerializeError(format!(
+                "{} is truncated",
+                path.display()
+            )));
+        }
+        let memory_used = u64::from_le_bytes(bytes[0..8].try_into().unwrap()) as usize;
+        let build_time = Duration::from_nanos(u64::from_le_bytes(bytes[8..16].try_into().unwrap()));
+        Ok(Some((bytes[16..].to_vec(), memory_used, build_time)))
+    }
+
+    pub(crate) fn save_cluster(&self, idx: usize, index: &[u8], memory_used: usize, build_time: Duration) -> Result<()> {
+        let mut bytes = Vec::with_capacity(16 + index.len());
+        bytes.extend_from_slice(&(memory_used as u64).to_le_bytes());
+        bytes.extend_from_slice(&(build_time.as_nanos() as u64).to_le_bytes());
+        bytes.extend_from_slice(index);
+        self.write(&self.cluster_path(idx), &bytes)
+    }
+
+    /// Deletes the files of the checkpoint, once the build is complete. The directory is kept.
+    pub(crate) fn remove(&self) -> Result<()> {
+        let entries = fs::read_dir(&self.dir)
+            .map_err(|e| ClusteredIndexError::SerializeError(e.to_string()))?;
+        for entry in entries.flatten() {
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            if name == HEADER_FILE || (name.starts_with("cluster_") && name.ends_with(".bin")) {
+                fs::remove_file(entry.path())
+                    .map_err(|e| ClusteredIndexError::SerializeError(e.to_string()))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn cluster_path(&self, idx: usize) -> PathBuf {
+        self.dir.join(format!("cluster_{}.bin", idx))
+    }
+
+    fn write(&self, path: &Path, bytes: &[u8]) -> Result<()> {
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, bytes)
+            .and_then(|_| fs::rename(&tmp, path))
+            .map_err(|e| {
+                ClusteredIndexError::SerializeError(format!("cannot write {}: {}", path.display(), e))
+            })
+    }
+}
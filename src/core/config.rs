@@ -1,11 +1,111 @@
+use std::{fs, path::Path};
+
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::metricdata::Precision;
+use crate::transform::RandomProjection;
+
+use super::errors::{ClusteredIndexError, Result};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum MetricsOutput{
     DB,
+    /// JSON file at the given path, overwritten by each save
+    Json(String),
+    /// CSV files in the given directory, one per table of the SQLite schema, appended by each save
+    Csv(String),
     None
 }
 
+impl MetricsOutput {
+    /// Whether run metrics are collected during build and search
+    pub fn is_enabled(&self) -> bool {
+        !matches!(self, MetricsOutput::None)
+    }
+}
+
+/// Strategy used to pick the representative point of each cluster
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum CenterSelection {
+    /// Keep the centers chosen by greedy minimum-maximum clustering
+    #[default]
+    Greedy,
+    /// After assignment, replace each center with the member minimizing the maximum distance to the rest of the cluster
+    Medoid,
+}
+
+/// How the points inside a cluster are searched
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum IndexMode {
+    /// Build an LSH index for every cluster large enough, scan the small ones
+    #[default]
+    Lsh,
+    /// Build no LSH index and scan every probed cluster exhaustively (IVF-flat)
+    Flat,
+}
+
+/// Radius of the clusters compared with the distance of the current k-th neighbor to prune
+/// them during the search, see [`Config::pruning_radius`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum PruningRadius {
+    /// Largest distance from the center to a member: a pruned cluster can't hold a point
+    /// closer than the k-th neighbor
+    #[default]
+    Max,
+    /// 99th percentile of the distances from the center to the members: at most 1% of the
+    /// members of a pruned cluster can be closer than the k-th neighbor
+    P99,
+    /// 90th percentile of the distances from the center to the members: at most 10% of the
+    /// members of a pruned cluster can be closer than the k-th neighbor
+    P90,
+}
+
+/// How the build chooses the number of clusters, see [`Config::cluster_count`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum ClusterCount {
+    /// `sqrt(n) * num_clusters_factor` clusters for a dataset of `n` points
+    #[default]
+    Factor,
+    /// As many clusters as needed for an average of `points` points per cluster
+    AverageSize { points: usize },
+    /// The elbow of the radius of greedy clustering as centers are added, at most `max_clusters`:
+    /// past it, more clusters barely shrink the radius. Finding it costs a pass over the dataset
+    /// per candidate center, on top of the clustering
+    RadiusElbow { max_clusters: usize },
+}
+
+/// What the build does with the clusters too small to be worth probing, see [`Config::tiny_clusters`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum TinyClusters {
+    /// Keep every cluster of the clustering
+    #[default]
+    Keep,
+    /// Merge the clusters with fewer than `min_points` points into the cluster with the nearest
+    /// center, smallest first, until every cluster has at least `min_points` points or one is left
+    MergeNearest { min_points: usize },
+}
+
+/// Product quantization of the dataset, see [`Config::pq`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PqParams {
+    /// Number of subspaces, must divide the dimensions
+    pub m: usize,
+    /// Bits of the code of a subvector, from 1 to 8: every subspace has `2^nbits` centroids
+    pub nbits: usize,
+}
+
+/// Diversification of the results by maximal marginal relevance, see [`Config::mmr`]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct MmrParams {
+    /// Weight of the relevance against the diversity, in [0, 1]: 1 returns the closest
+    /// candidates, lower values prefer candidates far from the results already selected
+    pub lambda: f32,
+    /// Nearest neighbors collected before the k results are selected among them, at least `k`
+    pub candidates: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum MetricsGranularity {
     Run,     // Only overall run metrics
     Query,   // Run + per-query metrics
@@ -21,6 +121,10 @@ pub struct Config {
     /// Factor that needs to be multiplied to sqrt(n)
     pub num_clusters_factor: f32,
 
+    /// How the number of clusters is chosen, from `num_clusters_factor` by default
+    #[serde(default)]
+    pub cluster_count: ClusterCount,
+
     /// Number of nearest neighbors to search
     pub k: usize,
 
@@ -32,6 +136,134 @@ pub struct Config {
 
     // Where to save metrics
     pub metrics_output: MetricsOutput,
+
+    /// Free-form labels saved with each metrics run, e.g. the experiment name
+    #[serde(default)]
+    pub run_tags: Vec<String>,
+
+    /// How cluster centers are chosen after clustering
+    #[serde(default)]
+    pub center_selection: CenterSelection,
+
+    /// Whether clusters get an LSH index or are always scanned exhaustively
+    #[serde(default)]
+    pub index_mode: IndexMode,
+
+    /// Recompute the distances of the final top-k in f64 and re-rank them,
+    /// breaking ties by point index so that orderings are reproducible
+    #[serde(default)]
+    pub rerank_f64: bool,
+
+    /// Projection applied to the dataset before indexing, queries are projected with it before searching.
+    /// Stored as its dimensions and seed, the matrix is drawn again when the configuration is loaded
+    #[serde(default)]
+    pub projection: Option<RandomProjection>,
+
+    /// Check every point when the index is created and every inserted one: a NaN or infinite
+    /// component, or a zero norm under the angular distance, is a `DataError` listing the
+    /// offending rows instead of silently skewing the clustering. Reads the whole dataset
+    #[serde(default)]
+    pub validate_data: bool,
+
+    /// Collapse the identical points during the build: only the first point with given
+    /// components is assigned to a cluster and indexed, and its duplicates are reported right
+    /// after it in the results. Saves the LSH table space of datasets full of duplicates.
+    /// Not used with [`mmr`](Self::mmr), which passes over the duplicates anyway
+    #[serde(default)]
+    pub dedup: bool,
+
+    /// Scale every query to unit norm before searching, before the projection if there is one.
+    /// The angular distance doesn't depend on the norms, so this is for the other distances over
+    /// unit-norm points, e.g. Euclidean embeddings normalized upstream queried with raw ones.
+    /// Queries with a zero norm are rejected
+    #[serde(default)]
+    pub normalize_queries: bool,
+
+    /// Ceiling on the estimated memory of the cluster indices, in bytes. When the build would
+    /// exceed it, fewer tables are used and then the largest clusters are scanned exhaustively
+    #[serde(default)]
+    pub max_memory_bytes: Option<usize>,
+
+    /// Sample the resident set size of the process during the build, and report its peak while
+    /// clustering and while creating the cluster indices in the [`BuildReport`](crate::core::BuildReport)
+    /// and the build metrics. Only on Linux
+    #[serde(default)]
+    pub track_rss: bool,
+
+    /// Number of threads CLANN may use, `None` for one per core. Set it when the host
+    /// application runs its own pools, so that both don't oversubscribe the cores. PUFFINN
+    /// builds its indexes with this many OpenMP threads, whatever `OMP_NUM_THREADS` is, and
+    /// [`IndexHandle::new`](crate::core::IndexHandle::new) starts this many blocking threads.
+    /// Recorded in the runs of the metrics, for comparisons across machines
+    #[serde(default)]
+    pub num_threads: Option<usize>,
+
+    /// Precision the dataset is stored in, set from the dataset when the index is created.
+    /// Loading an index with a dataset of another precision logs a warning, as the cluster
+    /// radii were computed on the stored values
+    #[serde(default)]
+    pub storage: Precision,
+
+    /// Weight of every dimension in the distance, set from the dataset when the index is created,
+    /// `None` if they all weigh the same. Loading an index with a dataset of other weights fails,
+    /// as the clusters were built on the weighted distances
+    #[serde(default)]
+    pub weights: Option<Vec<f32>>,
+
+    /// Keep an i8 copy of every point, trained during the build, and compute the distances of
+    /// the candidates from it, a quarter of the bytes read per candidate. The distances of the
+    /// final top-k are recomputed on the dataset
+    #[serde(default)]
+    pub scalar_quantization: bool,
+
+    /// Product quantizer trained during the build, giving a lower bound of the distance of every
+    /// candidate: the candidates that cannot enter the top-k are pruned before their exact distance
+    #[serde(default)]
+    pub pq: Option<PqParams>,
+
+    /// Most clusters a query probes, closest centers first, regardless of their lower bound:
+    /// trades recall for latency as the number of probes of an IVF index. `None` probes every
+    /// cluster that may hold a neighbor
+    #[serde(default)]
+    pub max_clusters_probed: Option<usize>,
+
+    /// Radius of the clusters used to prune them. A percentile radius ignores the few members
+    /// far from the center, which would otherwise keep the cluster from being pruned, at the
+    /// price of missing them
+    #[serde(default)]
+    pub pruning_radius: PruningRadius,
+
+    /// Quantile of the distances from the points to their center above which a point is an
+    /// outlier, in (0, 1). Outliers are moved from their cluster, whose radius they would keep
+    /// from being pruned, to a brute force cluster scanned by every query. `None` keeps them
+    #[serde(default)]
+    pub outlier_quantile: Option<f32>,
+
+    /// Number of coarse cells of a two-level index: the dataset is clustered in cells, then every
+    /// cell in a share of the clusters proportional to its size, and a query only computes the
+    /// distances to the centers of the clusters of the cells that may hold its neighbors. Suits
+    /// datasets needing many clusters. `None` clusters the dataset in one level. Only used by the
+    /// greedy builds, a build from a partition or a sample has one level
+    #[serde(default)]
+    pub coarse_clusters: Option<usize>,
+
+    /// Consolidation of the clusters after the clustering: greedy k-center often leaves
+    /// near-empty clusters, each one a brute force scan with a fixed cost per probe
+    #[serde(default)]
+    pub tiny_clusters: TinyClusters,
+
+    /// Diversify the results: the searches collect the nearest candidates and select the k
+    /// results among them by maximal marginal relevance, so that near-duplicates of a result
+    /// already selected are passed over. `None` returns the k nearest neighbors
+    #[serde(default)]
+    pub mmr: Option<MmrParams>,
+
+    /// Scan every probed cluster instead of searching its LSH index, prune the clusters with
+    /// their largest radius and ignore `max_clusters_probed`: the searches return the exact
+    /// nearest neighbors through the same API, e.g. as the reference of differential tests of
+    /// the approximate search. The indexes are still built, no rebuild is needed to switch
+    #[serde(default)]
+    pub exact: bool,
 }
 
 impl Default for Config {
@@ -39,10 +271,33 @@ impl Default for Config {
         Self { 
             num_tables: 10,   
             num_clusters_factor: 1.0,
+            cluster_count: ClusterCount::default(),
             k: 10, 
             delta: 0.9,
             dataset_name: "".to_string(),
-            metrics_output: MetricsOutput::None
+            metrics_output: MetricsOutput::None,
+            run_tags: Vec::new(),
+            center_selection: CenterSelection::default(),
+            index_mode: IndexMode::default(),
+            rerank_f64: false,
+            projection: None,
+            validate_data: false,
+            dedup: false,
+            normalize_queries: false,
+            max_memory_bytes: None,
+            track_rss: false,
+            num_threads: None,
+            storage: Precision::F32,
+            weights: None,
+            scalar_quantization: false,
+            pq: None,
+            max_clusters_probed: None,
+            pruning_radius: PruningRadius::default(),
+            outlier_quantile: None,
+            tiny_clusters: TinyClusters::default(),
+            coarse_clusters: None,
+            mmr: None,
+            exact: false,
         }
     }
 }
@@ -62,14 +317,216 @@ impl Config {
             k,
             delta,
             dataset_name: dataset_name.to_string(),
-            metrics_output
+            metrics_output,
+            ..Default::default()
         }
     }
+
+    /// Loads a configuration from a TOML, YAML or JSON file, chosen by its extension.
+    ///
+    /// Missing keys take their default value, so a file only needs the parameters it changes:
+    ///
+    /// ```toml
+    /// num_tables = 84
+    /// num_clusters_factor = 0.4
+    /// dataset_name = "glove-25-angular"
+    /// metrics_output = "DB"
+    /// ```
+    ///
+    /// # Errors
+    /// Returns `ClusteredIndexError::ConfigError` if:
+    /// - The file can't be read, or its extension is not `.toml`, `.yaml`, `.yml` or `.json`
+    /// - The file is not valid TOML, YAML or JSON
+    /// - A key is unknown or has a value of the wrong type, the error names the key
+    /// - The configuration fails [`Config::validate`]
+    pub fn from_file(path: &str) -> Result<Self> {
+        Self::from_value(read_file(path)?).map_err(|e| ClusteredIndexError::ConfigError(format!("{}: {}", path, e)))
+    }
+
+    /// Loads a list of configurations from a TOML, YAML or JSON file, as used by the benchmarks.
+    ///
+    /// The file holds either a list of configurations, or a `configs` list (in TOML, an array
+    /// of `[[configs]]` tables), or a single configuration. Each configuration is read as in
+    /// [`Config::from_file`].
+    ///
+    /// # Errors
+    /// As [`Config::from_file`], the error names the position of the offending configuration
+    pub fn list_from_file(path: &str) -> Result<Vec<Self>> {
+        let items = match read_file(path)? {
+            Value::Array(items) => items,
+            Value::Object(mut map) if map.contains_key("configs") => match map.remove("configs") {
+                Some(Value::Array(items)) => items,
+                _ => {
+                    return Err(ClusteredIndexError::ConfigError(format!(
+                        "{}: `configs` must be a list",
+                        path
+                    )))
+                }
+            },
+            value => vec![value],
+        };
+
+        items
+            .into_iter()
+            .enumerate()
+            .map(|(i, value)| {
+                Self::from_value(value)
+                    .map_err(|e| ClusteredIndexError::ConfigError(format!("{}: config {}: {}", path, i, e)))
+            })
+            .collect()
+    }
+
+    /// Checks that the parameters are in range.
+    ///
+    /// # Errors
+    /// Returns `ClusteredIndexError::ConfigError` naming the first parameter out of range
+    pub fn validate(&self) -> Result<()> {
+        let error = |key: &str, expected: &str| {
+            Err(ClusteredIndexError::ConfigError(format!("`{}` must be {}", key, expected)))
+        };
+
+        if self.num_tables == 0 {
+            return error("num_tables", "positive");
+        }
+        if !(self.num_clusters_factor.is_finite() && self.num_clusters_factor > 0.0) {
+            return error("num_clusters_factor", "positive");
+        }
+        match self.cluster_count {
+            ClusterCount::AverageSize { points: 0 } => return error("cluster_count.points", "positive"),
+            ClusterCount::RadiusElbow { max_clusters: 0 } => {
+                return error("cluster_count.max_clusters", "positive")
+            }
+            _ => {}
+        }
+        if self.k == 0 {
+            return error("k", "positive");
+        }
+        if !(self.delta > 0.0 && self.delta <= 1.0) {
+            return error("delta", "in (0, 1]");
+        }
+        if self.max_clusters_probed == Some(0) {
+            return error("max_clusters_probed", "positive");
+        }
+        if let Some(quantile) = self.outlier_quantile {
+            if !(quantile > 0.0 && quantile < 1.0) {
+                return error("outlier_quantile", "in (0, 1)");
+            }
+        }
+        if self.coarse_clusters == Some(0) {
+            return error("coarse_clusters", "positive");
+        }
+        if self.tiny_clusters == (TinyClusters::MergeNearest { min_points: 0 }) {
+            return error("tiny_clusters.min_points", "positive");
+        }
+        if let Some(mmr) = self.mmr {
+            if !(0.0..=1.0).contains(&mmr.lambda) {
+                return error("mmr.lambda", "in [0, 1]");
+            }
+            if mmr.candidates < self.k {
+                return error("mmr.candidates", "at least k");
+            }
+        }
+        if self.max_memory_bytes == Some(0) {
+            return error("max_memory_bytes", "positive");
+        }
+        if self.num_threads == Some(0) {
+            return error("num_threads", "positive");
+        }
+        if let Some(pq) = self.pq {
+            if pq.m == 0 {
+                return error("pq.m", "positive");
+            }
+            if !(1..=8).contains(&pq.nbits) {
+                return error("pq.nbits", "in [1, 8]");
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Number of threads CLANN may use, [`num_threads`](Self::num_threads) or one per core
+    pub fn threads(&self) -> usize {
+        self.num_threads
+            .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()))
+    }
+
+    /// The parameters that shape a built index, without the search parameters and the metrics:
+    /// two configurations with the same value build the same index
+    pub(crate) fn build_parameters(&self) -> Option<Value> {
+        let mut config = self.clone();
+        config.k = 0;
+        config.delta = 0.0;
+        config.max_clusters_probed = None;
+        config.pruning_radius = PruningRadius::default();
+        config.mmr = None;
+        config.exact = false;
+        config.metrics_output = MetricsOutput::None;
+        config.run_tags.clear();
+        config.track_rss = false;
+        config.num_threads = None;
+        serde_json::to_value(config).ok()
+    }
+
+    /// Fills the keys missing from `value` with their default, checking the keys one at a time
+    /// so that errors name the offending one
+    fn from_value(value: Value) -> std::result::Result<Self, String> {
+        let Value::Object(map) = value else {
+            return Err("expected a table of parameters".to_string());
+        };
+
+        let mut merged = match serde_json::to_value(Config::default()) {
+            Ok(Value::Object(defaults)) => defaults,
+            _ => Map::new(),
+        };
+        for (key, value) in map {
+            if !merged.contains_key(&key) {
+                let mut known: Vec<&String> = merged.keys().collect();
+                known.sort();
+                return Err(format!(
+                    "unknown key `{}`, expected one of {}",
+                    key,
+                    known.iter().map(|k| format!("`{}`", k)).collect::<Vec<_>>().join(", ")
+                ));
+            }
+            merged.insert(key.clone(), value);
+            serde_json::from_value::<Config>(Value::Object(merged.clone()))
+                .map_err(|e| format!("invalid value for `{}`: {}", key, e))?;
+        }
+
+        let config: Config = serde_json::from_value(Value::Object(merged)).map_err(|e| e.to_string())?;
+        config.validate().map_err(|e| match e {
+            ClusteredIndexError::ConfigError(message) => message,
+            e => e.to_string(),
+        })?;
+        Ok(config)
+    }
+}
+
+/// Parses a configuration file into a generic value, by extension
+fn read_file(path: &str) -> Result<Value> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| ClusteredIndexError::ConfigError(format!("{}: {}", path, e)))?;
+    let extension = Path::new(path)
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+
+    let parsed = match extension.as_str() {
+        "toml" => toml::from_str::<Value>(&contents).map_err(|e| e.to_string()),
+        "yaml" | "yml" => serde_yaml::from_str::<Value>(&contents).map_err(|e| e.to_string()),
+        "json" => serde_json::from_str::<Value>(&contents).map_err(|e| e.to_string()),
+        _ => Err("unsupported extension, expected .toml, .yaml, .yml or .json".to_string()),
+    };
+    parsed.map_err(|e| ClusteredIndexError::ConfigError(format!("{}: {}", path, e)))
 }
 
 #[cfg(test)]
 mod tests {
+    use std::path::Path;
+
     use super::*;
+    use crate::utils::test_dir;
 
     #[test]
     fn test_default_config() {
@@ -153,6 +610,74 @@ mod tests {
         assert!(matches!(cloned.metrics_output, MetricsOutput::None));
     }
     
+    fn write_config(dir: &Path, name: &str, contents: &str) -> String {
+        let path = dir.join(name);
+        std::fs::write(&path, contents).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_from_file() {
+        let dir = test_dir("config_from_file");
+        let toml = write_config(
+            &dir,
+            "config.toml",
+            "num_tables = 84\nnum_clusters_factor = 0.4\ndataset_name = \"glove\"\nmetrics_output = { Json = \"out.json\" }\n",
+        );
+        let config = Config::from_file(&toml).unwrap();
+        assert_eq!(config.num_tables, 84);
+        assert_eq!(config.num_clusters_factor, 0.4);
+        assert_eq!(config.dataset_name, "glove");
+        assert!(matches!(config.metrics_output, MetricsOutput::Json(ref p) if p == "out.json"));
+        // missing keys are defaults
+        assert_eq!(config.k, 10);
+        assert_eq!(config.delta, 0.9);
+
+        let yaml = write_config(
+            &dir,
+            "config.yaml",
+            "k: 5\nindex_mode: Flat\nrun_tags:\n  - sweep\n",
+        );
+        let config = Config::from_file(&yaml).unwrap();
+        assert_eq!(config.k, 5);
+        assert_eq!(config.index_mode, IndexMode::Flat);
+        assert_eq!(config.run_tags, vec!["sweep".to_string()]);
+
+        let json = write_config(&dir, "configs.json", "[{\"k\": 3}, {\"delta\": 0.5}]");
+        let configs = Config::list_from_file(&json).unwrap();
+        assert_eq!(configs.len(), 2);
+        assert_eq!((configs[0].k, configs[1].delta), (3, 0.5));
+
+        let toml_list = write_config(
+            &dir,
+            "configs.toml",
+            "[[configs]]\nnum_tables = 5\n\n[[configs]]\nnum_tables = 6\n",
+        );
+        let configs = Config::list_from_file(&toml_list).unwrap();
+        assert_eq!((configs[0].num_tables, configs[1].num_tables), (5, 6));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_from_file_errors_name_the_key() {
+        let dir = test_dir("config_errors");
+        let cases = [
+            ("unknown.toml", "num_table = 3\n", "unknown key `num_table`"),
+            ("type.yaml", "k: ten\n", "invalid value for `k`"),
+            ("range.json", "{\"delta\": 1.5}", "`delta` must be in (0, 1]"),
+            ("list.json", "[{}, {\"k\": 0}]", "config 1: `k` must be positive"),
+        ];
+        for (name, contents, expected) in cases {
+            let path = write_config(&dir, name, contents);
+            let error = Config::list_from_file(&path).unwrap_err().to_string();
+            assert!(error.contains(expected), "{}", error);
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+
+        assert!(Config::from_file("config.ini").is_err());
+    }
+
     #[test]
     fn test_different_metric_outputs() {
         // Test with different MetricsOutput variants
@@ -0,0 +1,150 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+
+use crate::metricdata::{MetricData, Scalar};
+
+/// Identical points of a dataset, see [`Config::dedup`](crate::core::Config::dedup).
+///
+/// Points are compared by their components rounded to f32, hashed then checked exactly. The
+/// first point with given components is canonical and the later ones are its aliases: only
+/// the canonical point is assigned to a cluster, and its aliases are reported right after it
+/// in the results. The aliases are a function of the dataset, so they are found again when
+/// an index is loaded rather than saved with it.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Duplicates {
+    /// Canonical points by the hash of their components
+    canonical: HashMap<u64, Vec<usize>>,
+    /// Aliases of the canonical points having some, in increasing order
+    aliases: HashMap<usize, Vec<usize>>,
+    /// Canonical point of every alias
+    canonical_of: HashMap<usize, usize>,
+}
+
+/// Components of `point` rounded to f32, as bits, with -0 and 0 equal
+fn quantized<D: Scalar>(point: &[D]) -> Vec<u32> {
+    point.iter().map(|&x| (x.to_f32() + 0.0).to_bits()).collect()
+}
+
+impl Duplicates {
+    /// Finds the identical points of `data`
+    pub(crate) fn find<T: MetricData>(data: &T) -> Self {
+        let mut duplicates = Self::default();
+        for i in 0..data.num_points() {
+            duplicates.add(data, i);
+        }
+        duplicates
+    }
+
+    /// Adds point `i` of `data`, after the points before it.
+    ///
+    /// # Returns
+    /// The canonical point `i` is an alias of, `None` if it is the first with its components
+    pub(crate) fn add<T: MetricData>(&mut self, data: &T, i: usize) -> Option<usize> {
+        let point = quantized(&data.get_point(i));
+        let mut hasher = DefaultHasher::new();
+        point.hash(&mut hasher);
+        let candidates = self.canonical.entry(hasher.finish()).or_default();
+
+        match candidates.iter().copied().find(|&c| quantized(&data.get_point(c)) == point) {
+            Some(c) => {
+                self.aliases.entry(c).or_default().push(i);
+                self.canonical_of.insert(i, c);
+                Some(c)
+            }
+            None => {
+                candidates.push(i);
+                None
+            }
+        }
+    }
+
+    /// Number of aliases, the points left out of the clusters
+    pub(crate) fn num_aliases(&self) -> usize {
+        self.canonical_of.len()
+    }
+
+    /// Bytes of the ids of the points in the maps, without the overhead of the maps themselves
+    pub(crate) fn memory_used(&self) -> usize {
+        let ids = self.canonical.values().map(|points| points.len() + 1).sum::<usize>()
+            + self.aliases.values().map(|points| points.len() + 1).sum::<usize>()
+            + 2 * self.canonical_of.len();
+        ids * std::mem::size_of::<usize>()
+    }
+
+    /// Whether `point` is the alias of an earlier point
+    pub(crate) fn is_alias(&self, point: usize) -> bool {
+        self.canonical_of.contains_key(&point)
+    }
+
+    /// Canonical point of `point`, the point itself if it is not an alias
+    pub(crate) fn canonical(&self, point: usize) -> usize {
+        self.canonical_of.get(&point).copied().unwrap_or(point)
+    }
+
+    /// Aliases of `point`, in increasing order
+    pub(crate) fn aliases(&self, point: usize) -> &[usize] {
+        self.aliases.get(&point).map_or(&[], Vec::as_slice)
+    }
+
+    /// Points of the sorted `exclude` to leave out of the candidates: a canonical point is
+    /// still searched while one of its aliases is not excluded, as it stands for them
+    pub(crate) fn excluded_candidates(&self, exclude: &[usize]) -> Vec<usize> {
+        exclude
+            .iter()
+            .copied()
+            .filter(|&p| self.aliases(p).iter().all(|a| exclude.binary_search(a).is_ok()))
+            .collect()
+    }
+
+    /// `results` with the aliases of every point at the same distance, without the points of
+    /// the sorted `exclude`, sorted by distance then id and truncated to `k`
+    pub(crate) fn expand(&self, results: Vec<(f32, usize)>, exclude: &[usize], k: usize) -> Vec<(f32, usize)> {
+        let mut expanded: Vec<(f32, usize)> = results
+            .into_iter()
+            .flat_map(|(distance, p)| {
+                std::iter::once(p)
+                    .chain(self.aliases(p).iter().copied())
+                    .map(move |p| (distance, p))
+            })
+            .filter(|(_, p)| exclude.binary_search(p).is_err())
+            .collect();
+        // the aliases of a point may come after other points at the same distance
+        expanded.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+        expanded.truncate(k);
+        expanded
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::arr2;
+
+    use super::Duplicates;
+    use crate::metricdata::EuclideanData;
+
+    #[test]
+    fn test_duplicates() {
+        let data = EuclideanData::new(arr2(&[[1.0, 0.0], [0.0, 1.0], [1.0, 0.0], [-0.0, 1.0], [1.0, 0.0]]));
+        let duplicates = Duplicates::find(&data);
+        assert_eq!(duplicates.num_aliases(), 3);
+        assert_eq!(duplicates.aliases(0), &[2, 4]);
+        assert_eq!(duplicates.aliases(1), &[3]);
+        assert!(duplicates.is_alias(4) && !duplicates.is_alias(1));
+        assert_eq!(duplicates.canonical(3), 1);
+
+        let results = vec![(0.0, 0), (1.4, 1)];
+        assert_eq!(
+            duplicates.expand(results.clone(), &[], 10),
+            vec![(0.0, 0), (0.0, 2), (0.0, 4), (1.4, 1), (1.4, 3)]
+        );
+        assert_eq!(duplicates.expand(results, &[0, 4], 2), vec![(0.0, 2), (1.4, 1)]);
+        assert_eq!(
+            duplicates.expand(vec![(0.5, 0), (0.5, 1)], &[], 4),
+            vec![(0.5, 0), (0.5, 1), (0.5, 2), (0.5, 3)]
+        );
+
+        // point 0 still stands for point 2
+        assert_eq!(duplicates.excluded_candidates(&[0, 1, 3, 4]), vec![1, 3, 4]);
+    }
+}
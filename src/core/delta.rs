@@ -0,0 +1,168 @@
+/// What a [`DeltaPolicy`] knows of a cluster about to be searched with its index.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ProbeContext {
+    /// Distance from the query to the center of the cluster
+    pub center_distance: f32,
+
+    /// Lower bound on the distance from the query to any point of the cluster
+    pub lower_bound: f32,
+
+    /// Distance of the current k-th neighbor of the query, `None` while there are fewer than k
+    pub kth_distance: Option<f32>,
+
+    /// Number of clusters of the index searched with their index rather than scanned, at
+    /// least the number of clusters a query may ask the policy about
+    pub indexed_clusters: usize,
+}
+
+/// Chooses the recall target of every cluster searched by a query, in place of
+/// [`Config::delta`](crate::core::Config), see
+/// [`ClusteredIndex::set_delta_policy`](crate::core::ClusteredIndex::set_delta_policy).
+///
+/// Brute force clusters are always scanned exactly and don't ask the policy. It is shared by
+/// the concurrent searches of an [`IndexHandle`](crate::core::IndexHandle).
+pub trait DeltaPolicy: Send + Sync {
+    /// Recall target of the index of the cluster described by `context`, given the configured
+    /// `delta`. Clamped to `[0, 1]`.
+    fn cluster_delta(&self, delta: f32, context: &ProbeContext) -> f32;
+
+    /// Whether the recall target only holds if the searches probe every cluster that may hold
+    /// a neighbor, without [`Config::max_clusters_probed`](crate::core::Config) and with
+    /// [`PruningRadius::Max`](crate::core::PruningRadius).
+    /// [`ClusteredIndex::set_delta_policy`](crate::core::ClusteredIndex::set_delta_policy) warns
+    /// if the configuration probes fewer clusters.
+    fn needs_every_cluster(&self) -> bool {
+        false
+    }
+}
+
+/// Splits the failure probability `1 - delta` evenly across the clusters searched with an index,
+/// so that the recall target holds for the whole query rather than for each cluster.
+///
+/// Every cluster is searched with `delta' = 1 - (1 - delta) / m`, where `m` is the number of
+/// clusters with an index. The k nearest neighbors of the query lie in the clusters that are
+/// probed: the others are pruned only when their lower bound exceeds the k-th distance found,
+/// which is at least the true k-th distance. Brute force clusters are scanned exactly, and by
+/// the union bound, the probability that any of the at most `m` indexed clusters probed misses
+/// one of its true neighbors is at most `m * (1 - delta') = 1 - delta`. All the k nearest
+/// neighbors are thus found with probability at least `delta`, given the per-index guarantee
+/// of the LSH backend.
+///
+/// `m` counts every indexed cluster, not only those the query probes, so the bound is
+/// conservative: with many clusters the per-cluster target gets close to 1 and the searches
+/// cost more.
+///
+/// The guarantee only holds if the searches probe every cluster that may hold a neighbor:
+/// [`Config::max_clusters_probed`](crate::core::Config) must be `None`, since the limit stops
+/// before the remaining clusters are pruned, and [`Config::pruning_radius`](crate::core::Config)
+/// must be [`PruningRadius::Max`](crate::core::PruningRadius), since a percentile radius prunes
+/// clusters whose farthest members may be neighbors.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RigorousDelta;
+
+impl DeltaPolicy for RigorousDelta {
+    fn cluster_delta(&self, delta: f32, context: &ProbeContext) -> f32 {
+        1.0 - (1.0 - delta) / context.indexed_clusters.max(1) as f32
+    }
+
+    fn needs_every_cluster(&self) -> bool {
+        true
+    }
+}
+
+/// Spends the recall budget where the neighbors are likely to be.
+///
+/// A cluster whose lower bound is far below the current k-th distance is likely to hold true
+/// neighbors, and its probability `1 - delta` of missing one is divided by `miss_scale`. A
+/// marginal cluster, whose lower bound is just below the k-th distance, may hold at most a few
+/// neighbors at the edge of the top-k, and its miss probability is multiplied by `miss_scale`.
+/// Since most neighbors come from the first kind, the overall recall stays close to the target
+/// while the marginal clusters, usually the most numerous, cost less. Clusters probed before the
+/// top-k is full keep the configured delta.
+///
+/// How close the recall stays to the target depends on the dataset, check it with
+/// [`crate::eval`] before relying on it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AdaptiveDelta {
+    /// Clusters whose lower bound is below this fraction of the k-th distance are likely to hold neighbors
+    pub likely: f32,
+
+    /// Clusters whose lower bound is above this fraction of the k-th distance are marginal
+    pub marginal: f32,
+
+    /// Factor dividing the miss probability of likely clusters and multiplying the one of marginal clusters
+    pub miss_scale: f32,
+
+    /// Lowest recall target of a marginal cluster
+    pub min_delta: f32,
+}
+
+impl Default for AdaptiveDelta {
+    fn default() -> Self {
+        Self {
+            likely: 0.5,
+            marginal: 0.8,
+            miss_scale: 2.0,
+            min_delta: 0.5,
+        }
+    }
+}
+
+impl DeltaPolicy for AdaptiveDelta {
+    fn cluster_delta(&self, delta: f32, context: &ProbeContext) -> f32 {
+        let Some(kth) = context.kth_distance.filter(|&kth| kth > 0.0) else {
+            return delta;
+        };
+
+        let relative = context.lower_bound.max(0.0) / kth;
+        let miss = 1.0 - delta;
+        if relative < self.likely {
+            1.0 - miss / self.miss_scale
+        } else if relative > self.marginal {
+            (1.0 - miss * self.miss_scale).max(self.min_delta.min(delta))
+        } else {
+            delta
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AdaptiveDelta, DeltaPolicy, ProbeContext, RigorousDelta};
+
+    #[test]
+    fn test_adaptive_delta() {
+        let policy = AdaptiveDelta::default();
+        let context = |lower_bound, kth_distance| ProbeContext {
+            center_distance: lower_bound + 0.1,
+            lower_bound,
+            kth_distance,
+            indexed_clusters: 4,
+        };
+
+        assert_eq!(policy.cluster_delta(0.9, &context(0.1, None)), 0.9);
+        // likely, neutral and marginal clusters
+        assert!((policy.cluster_delta(0.9, &context(-0.2, Some(1.0))) - 0.95).abs() < 1e-6);
+        assert_eq!(policy.cluster_delta(0.9, &context(0.6, Some(1.0))), 0.9);
+        assert!((policy.cluster_delta(0.9, &context(0.9, Some(1.0))) - 0.8).abs() < 1e-6);
+        // never below the floor, unless the configured delta already is
+        assert_eq!(policy.cluster_delta(0.6, &context(0.9, Some(1.0))), 0.5);
+        assert_eq!(policy.cluster_delta(0.3, &context(0.9, Some(1.0))), 0.3);
+    }
+
+    #[test]
+    fn test_rigorous_delta() {
+        let context = |indexed_clusters| ProbeContext {
+            center_distance: 0.5,
+            lower_bound: 0.2,
+            kth_distance: Some(0.4),
+            indexed_clusters,
+        };
+        // the failure probabilities of the clusters add up to 1 - delta
+        assert!((RigorousDelta.cluster_delta(0.9, &context(4)) - 0.975).abs() < 1e-6);
+        assert!((RigorousDelta.cluster_delta(0.9, &context(1)) - 0.9).abs() < 1e-6);
+        assert!((RigorousDelta.cluster_delta(0.9, &context(0)) - 0.9).abs() < 1e-6);
+        assert!(RigorousDelta.needs_every_cluster());
+        assert!(!AdaptiveDelta::default().needs_every_cluster());
+    }
+}
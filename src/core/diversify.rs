@@ -0,0 +1,77 @@
+use crate::metricdata::MetricData;
+
+/// Selects `k` of the `candidates` of a query by maximal marginal relevance.
+///
+/// The closest candidate is selected first, then every step selects the candidate maximizing
+/// `lambda * relevance + (1 - lambda) * diversity`, where the relevance is minus its distance
+/// from the query and the diversity its distance from the closest candidate already selected.
+/// The distances between candidates are computed from every selected one to those left, with
+/// [`MetricData::distances_points`].
+///
+/// # Returns
+/// The (distance from the query, index) pairs of the selected candidates in the order they
+/// were selected, and the distance computations spent
+pub(crate) fn mmr_select<T: MetricData>(
+    data: &T,
+    candidates: &[(f32, usize)],
+    k: usize,
+    lambda: f32,
+) -> (Vec<(f32, usize)>, usize) {
+    let mut left: Vec<(f32, usize)> = candidates.to_vec();
+    let mut diversity = vec![f32::INFINITY; left.len()];
+    let mut selected = Vec::with_capacity(k.min(left.len()));
+    let mut distances = vec![0.0; left.len()];
+    let mut distance_computations = 0;
+
+    while selected.len() < k && !left.is_empty() {
+        let best = if selected.is_empty() {
+            // no diversity yet, the closest candidate
+            (0..left.len()).min_by(|&a, &b| left[a].0.total_cmp(&left[b].0)).unwrap_or(0)
+        } else {
+            let score = |i: usize| lambda * -left[i].0 + (1.0 - lambda) * diversity[i];
+            (0..left.len()).max_by(|&a, &b| score(a).total_cmp(&score(b)).then(b.cmp(&a))).unwrap_or(0)
+        };
+        let chosen = left.swap_remove(best);
+        diversity.swap_remove(best);
+        selected.push(chosen);
+        if selected.len() == k || left.is_empty() {
+            break;
+        }
+
+        let ids: Vec<usize> = left.iter().map(|&(_, p)| p).collect();
+        let point = data.get_point(chosen.1).into_owned();
+        data.distances_points(&ids, &point, &mut distances[..ids.len()]);
+        distance_computations += ids.len();
+        for (diversity, &distance) in diversity.iter_mut().zip(&distances) {
+            *diversity = diversity.min(distance);
+        }
+    }
+
+    (selected, distance_computations)
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::arr2;
+
+    use super::mmr_select;
+    use crate::metricdata::EuclideanData;
+
+    #[test]
+    fn test_mmr_select() {
+        // two near-duplicates close to the query at the origin, and a farther distinct point
+        let data = EuclideanData::new(arr2(&[[1.0, 0.0], [1.0, 0.01], [0.0, 1.5], [5.0, 5.0]]));
+        let candidates = vec![(1.0, 0), (1.00005, 1), (1.5, 2), (7.07, 3)];
+
+        let (selected, computed) = mmr_select(&data, &candidates, 2, 1.0);
+        assert_eq!(selected, vec![(1.0, 0), (1.00005, 1)]);
+        assert_eq!(computed, 3);
+
+        // the near-duplicate is passed over
+        let (selected, _) = mmr_select(&data, &candidates, 2, 0.5);
+        assert_eq!(selected, vec![(1.0, 0), (1.5, 2)]);
+
+        let (selected, _) = mmr_select(&data, &candidates, 10, 0.5);
+        assert_eq!(selected.len(), 4);
+    }
+}
@@ -10,6 +10,9 @@ pub enum ClusteredIndexError {
     #[error("Data Error: {0}")]
     DataError(String),
 
+    #[error("Dimension Mismatch: expected {expected} dimensions, got {got}")]
+    DimensionMismatch { expected: usize, got: usize },
+
     #[error("Result DB Error: {0}")]
     ResultDBError(String),
 
@@ -34,6 +37,18 @@ pub enum ClusteredIndexError {
     #[error("Serialize Error: {0}")]
     SerializeError(String),
 
+    #[error("Deserialize Error: cluster {cluster}: {message}")]
+    DeserializeError { cluster: usize, message: String },
+
     #[error("Metrics Error: {0}")]
     MetricsError(String),
+
+    #[error("Poisoned Lock: {0}")]
+    PoisonedLock(String),
+
+    #[error("Cancelled")]
+    Cancelled,
+
+    #[error("Rerank Error: {0}")]
+    RerankError(String),
 }
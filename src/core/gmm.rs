@@ -22,12 +22,26 @@ pub(crate) fn greedy_minimum_maximum<D: MetricData>(
     data: &D,
     k: usize,
 ) -> (Array1<usize>, Array1<usize>, Array1<f32>) {
+    greedy_minimum_maximum_with(data, k, |_| true).expect("clustering is never stopped")
+}
+
+/// Same as [`greedy_minimum_maximum`], calling `proceed` with the number of centers chosen
+/// so far after each of them. Returns `None` as soon as `proceed` returns false.
+pub(crate) fn greedy_minimum_maximum_with<D, F>(
+    data: &D,
+    k: usize,
+    mut proceed: F,
+) -> Option<(Array1<usize>, Array1<usize>, Array1<f32>)>
+where
+    D: MetricData,
+    F: FnMut(usize) -> bool,
+{
     let n = data.num_points();
     if n <= k {
         // Each point is its own center
         let centers = Array1::<usize>::from_iter(0..n);
         let assignment = Array1::<usize>::from_iter(0..n);
-        return (centers, assignment, Array1::<f32>::zeros(n));
+        return Some((centers, assignment, Array1::<f32>::zeros(n)));
     }
 
     let first_center = 0usize;
@@ -38,6 +52,9 @@ pub(crate) fn greedy_minimum_maximum<D: MetricData>(
     let mut assignment = Array1::<usize>::zeros(n);
 
     data.all_distances(first_center, &mut distances);
+    if !proceed(1) {
+        return None;
+    }
 
     for idx in 1..k {
         // FIXME: in a multithreaded context this call deadlocks
@@ -51,6 +68,9 @@ pub(crate) fn greedy_minimum_maximum<D: MetricData>(
                 distances[i] = new_distances[i];
             }
         }
+        if !proceed(idx + 1) {
+            return None;
+        }
     }
 
     let mut radii: Array1<f32> = Array1::zeros(k);
@@ -59,5 +79,137 @@ pub(crate) fn greedy_minimum_maximum<D: MetricData>(
         radii[assignment[i]] = radii[assignment[i]].max(distances[i]);
     }
 
-    (centers, assignment, radii)
-}
\ No newline at end of file
+    Some((centers, assignment, radii))
+}
+
+/// Radius of greedy minimum-maximum clustering with 1 to `max_k` centers: the i-th entry is the
+/// largest distance from a point to its nearest center once the first i + 1 centers are chosen.
+/// The centers chosen first don't depend on `k`, so these are the radii of
+/// [`greedy_minimum_maximum`] for every `k` up to `max_k`, at the cost of a single run.
+///
+/// Calls `proceed` with the number of centers chosen so far after each of them, and returns
+/// `None` as soon as it returns false.
+pub(crate) fn greedy_radii<D, F>(data: &D, max_k: usize, mut proceed: F) -> Option<Vec<f32>>
+where
+    D: MetricData,
+    F: FnMut(usize) -> bool,
+{
+    let n = data.num_points();
+    let max_k = max_k.min(n);
+    let mut radii = Vec::with_capacity(max_k);
+    if max_k == 0 {
+        return Some(radii);
+    }
+
+    let mut distances = vec![f32::INFINITY; n];
+    let mut new_distances = vec![f32::INFINITY; n];
+    data.all_distances(0, &mut distances);
+    loop {
+        let farthest = argmax(&distances);
+        radii.push(distances[farthest]);
+        if !proceed(radii.len()) {
+            return None;
+        }
+        if radii.len() == max_k {
+            return Some(radii);
+        }
+
+        data.all_distances(farthest, &mut new_distances);
+        for (distance, &new_distance) in distances.iter_mut().zip(&new_distances) {
+            *distance = distance.min(new_distance);
+        }
+    }
+}
+
+/// Number of centers at the elbow of the `radii` of [`greedy_radii`], past which more centers
+/// barely shrink the radius: the point of the curve farthest below the line joining its ends,
+/// with both axes scaled to [0, 1].
+pub(crate) fn radius_elbow(radii: &[f32]) -> usize {
+    let n = radii.len();
+    if n < 3 {
+        return n.max(1);
+    }
+
+    let last = radii[n - 1];
+    let span = (radii[0] - last).max(f32::EPSILON);
+    (0..n)
+        .map(|i| {
+            let x = i as f32 / (n - 1) as f32;
+            let y = (radii[i] - last) / span;
+            (i + 1, (1.0 - x) - y)
+        })
+        .max_by(|a, b| a.1.total_cmp(&b.1))
+        .map_or(1, |(k, _)| k)
+}
+
+/// Maximum number of members evaluated as medoid candidates for a single cluster.
+const MEDOID_CANDIDATES: usize = 256;
+
+/// Finds the member of a cluster minimizing the maximum distance to all other members.
+///
+/// To keep the cost at O(MEDOID_CANDIDATES * m) for a cluster of m points, only an
+/// evenly strided sample of members is evaluated, plus the current center so that
+/// the returned radius is never larger than the one given by greedy clustering.
+///
+/// Returns the chosen center (index into the dataset) and the resulting radius.
+pub(crate) fn min_max_medoid<D: MetricData>(
+    data: &D,
+    members: &[usize],
+    current_center: usize,
+) -> (usize, f32) {
+    let eccentricity = |candidate: usize| {
+        members
+            .iter()
+            .map(|&p| data.distance(candidate, p))
+            .fold(0.0f32, f32::max)
+    };
+
+    let stride = members.len().div_ceil(MEDOID_CANDIDATES).max(1);
+    let mut best = (current_center, eccentricity(current_center));
+    for &candidate in members.iter().step_by(stride) {
+        let radius = eccentricity(candidate);
+        if radius < best.1 {
+            best = (candidate, radius);
+        }
+    }
+
+    best
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::arr2;
+
+    use super::*;
+    use crate::metricdata::EuclideanData;
+
+    #[test]
+    fn test_medoid_shrinks_radius() {
+        // points on a line, greedy would pick the extreme point 0
+        let data = EuclideanData::new(arr2(&[[0.0], [1.0], [2.0], [3.0], [4.0]]));
+        let members = [0, 1, 2, 3, 4];
+
+        let (center, radius) = min_max_medoid(&data, &members, 0);
+
+        assert_eq!(center, 2);
+        assert!((radius - 2.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_radius_elbow() {
+        // 5 tight blobs of 20 points, far from each other
+        let blobs = [[0.0, 0.0], [100.0, 0.0], [0.0, 100.0], [100.0, 100.0], [50.0, 50.0]];
+        let points = Array2::from_shape_fn((100, 2), |(i, j)| blobs[i % 5][j] + (i / 5) as f32 * 0.05);
+        let data = EuclideanData::new(points);
+
+        let radii = greedy_radii(&data, 30, |_| true).unwrap();
+        assert_eq!(radii.len(), 30);
+        assert!(radii.windows(2).all(|w| w[0] >= w[1]));
+        // the radius of every prefix is the one of the clustering with as many centers
+        let (_, _, radius) = greedy_minimum_maximum(&data, 7);
+        assert_eq!(radius.iter().copied().fold(0.0, f32::max), radii[6]);
+
+        assert_eq!(radius_elbow(&radii), 5);
+        assert!(greedy_radii(&data, 30, |centers| centers < 3).is_none());
+    }
+}
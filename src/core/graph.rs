@@ -0,0 +1,86 @@
+/// k nearest neighbor graph of a dataset, see [`knn_graph()`](crate::knn_graph).
+///
+/// The neighbors of every point are stored one after the other, in compressed sparse row
+/// layout: the neighbors of point `i` are at `offsets[i]..offsets[i + 1]`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct KnnGraph {
+    k: usize,
+    offsets: Vec<usize>,
+    neighbors: Vec<(f32, usize)>,
+}
+
+impl KnnGraph {
+    /// Graph of the neighbors of every point, in point order
+    pub(crate) fn from_lists(k: usize, lists: Vec<Vec<(f32, usize)>>) -> Self {
+        let mut offsets = Vec::with_capacity(lists.len() + 1);
+        offsets.push(0);
+        let mut neighbors = Vec::with_capacity(lists.len() * k);
+        for list in lists {
+            neighbors.extend(list);
+            offsets.push(neighbors.len());
+        }
+
+        Self { k, offsets, neighbors }
+    }
+
+    /// Number of neighbors searched for every point, some may have fewer
+    pub fn k(&self) -> usize {
+        self.k
+    }
+
+    /// Number of points of the graph
+    pub fn num_points(&self) -> usize {
+        self.offsets.len().saturating_sub(1)
+    }
+
+    /// Number of edges of the graph
+    pub fn num_edges(&self) -> usize {
+        self.neighbors.len()
+    }
+
+    /// (distance, index) pairs of the neighbors of `point`, from the closest, the point itself excluded
+    ///
+    /// # Panics
+    /// If `point` is not a point of the graph
+    pub fn neighbors(&self, point: usize) -> &[(f32, usize)] {
+        &self.neighbors[self.offsets[point]..self.offsets[point + 1]]
+    }
+
+    /// Directed edges (point, neighbor, distance), in point order
+    pub fn edges(&self) -> impl Iterator<Item = (usize, usize, f32)> + '_ {
+        (0..self.num_points()).flat_map(move |point| {
+            self.neighbors(point)
+                .iter()
+                .map(move |&(distance, neighbor)| (point, neighbor, distance))
+        })
+    }
+
+    /// Offsets of the neighbors of every point, `num_points() + 1` of them
+    pub fn offsets(&self) -> &[usize] {
+        &self.offsets
+    }
+
+    /// (distance, index) pairs of the neighbors of all the points, see [`offsets()`](Self::offsets)
+    pub fn all_neighbors(&self) -> &[(f32, usize)] {
+        &self.neighbors
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::KnnGraph;
+
+    #[test]
+    fn test_graph_layout() {
+        let graph = KnnGraph::from_lists(2, vec![vec![(0.1, 1), (0.3, 2)], vec![], vec![(0.2, 0)]]);
+        assert_eq!(graph.num_points(), 3);
+        assert_eq!(graph.num_edges(), 3);
+        assert_eq!(graph.offsets(), &[0, 2, 2, 3]);
+        assert!(graph.neighbors(1).is_empty());
+        assert_eq!(graph.neighbors(2), &[(0.2, 0)]);
+        assert_eq!(
+            graph.edges().collect::<Vec<_>>(),
+            vec![(0, 1, 0.1), (0, 2, 0.3), (2, 0, 0.2)]
+        );
+    }
+}
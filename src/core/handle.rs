@@ -0,0 +1,704 @@
+//! Shared access to an index from several threads.
+//!
+//! An index is split into a single [`IndexWriter`] and any number of [`IndexReader`]s with
+//! [`IndexWriter::new`] and [`IndexWriter::reader`]. Both handles share the index behind a
+//! mutex, and every operation holds it from start to end: a search never observes an
+//! insertion halfway, with points added to the dataset but the indices of their clusters
+//! not rebuilt yet. Searches see the index either before or after each mutation.
+//!
+//! Searches update the per-cluster statistics and the distance counters of the backends,
+//! so they are serialized as well, with each other and with mutations. Readers on other
+//! threads wait for the running operation rather than reading a torn index.
+//!
+//! ```no_run
+//! use clann::core::{Config, IndexWriter};
+//! use clann::metricdata::AngularData;
+//! use clann::utils::load_hdf5_dataset;
+//!
+//! let dataset = load_hdf5_dataset("./datasets/glove-25-angular.hdf5").unwrap();
+//! let mut index = clann::init_with_config(AngularData::new(dataset.dataset_array), Config::default()).unwrap();
+//! clann::build(&mut index).unwrap();
+//!
+//! let writer = IndexWriter::new(index);
+//! let reader = writer.reader();
+//! let queries = dataset.dataset_queries;
+//! let searcher = std::thread::spawn(move || {
+//!     for query in queries.rows() {
+//!         reader.search(query.as_slice().unwrap()).unwrap();
+//!     }
+//! });
+//! writer.insert(&[0.5; 25]).unwrap();
+//! searcher.join().unwrap();
+//! ```
+//!
+//! An [`IndexHandle`] instead lets searches run concurrently, under a read lock, while
+//! mutations take the write lock; the searches are not recorded in the statistics. The
+//! clusters of inserted points are rebuilt under the read lock, and only swapped in under the
+//! write lock. From async code, it runs the operations on a dedicated thread pool and returns
+//! their futures, so that a search never blocks the threads of the runtime:
+//!
+//! ```no_run
+//! # use clann::core::{Config, IndexHandle};
+//! # use clann::metricdata::AngularData;
+//! # async fn serve(index: clann::core::ClusteredIndex<AngularData<ndarray::OwnedRepr<f32>>>) {
+//! let handle = IndexHandle::new(index);
+//! let neighbors = handle.search_async(vec![0.5; 25]).await.unwrap();
+//! # }
+//! ```
+
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+
+use ndarray::{Array2, ArrayBase, Data, Ix2};
+
+use crate::metricdata::{Insertable, MetricData, Subset};
+use crate::puffinn_binds::{IndexableSimilarity, PuffinnIndex};
+
+use super::backend::ClusterBackend;
+use super::index::ClusteredIndex;
+use super::params::SearchParams;
+use super::plan::SearchPlan;
+use super::pool::{BlockingPool, Pending};
+use super::{ClusteredIndexError, Config, Result};
+
+type Shared<T, B> = Arc<Mutex<ClusteredIndex<T, B>>>;
+
+/// Neighbors of every query of a batch
+type BatchResults = Vec<Vec<(f32, usize)>>;
+
+fn poisoned<E>(_: E) -> ClusteredIndexError {
+    // a panic in the middle of an operation may have left the index torn
+    ClusteredIndexError::PoisonedLock("an operation on the index panicked".to_string())
+}
+
+fn lock<T, B>(index: &Shared<T, B>) -> Result<MutexGuard<'_, ClusteredIndex<T, B>>>
+where
+    T: MetricData + IndexableSimilarity<T> + Subset,
+    <T as Subset>::Out: IndexableSimilarity<<T as Subset>::Out>,
+    B: ClusterBackend<T>,
+{
+    index.lock().map_err(poisoned)
+}
+
+/// Handle that mutates a shared index, there is at most one per index.
+pub struct IndexWriter<T, B = PuffinnIndex>
+where
+    T: MetricData + IndexableSimilarity<T> + Subset,
+    <T as Subset>::Out: IndexableSimilarity<<T as Subset>::Out>,
+    B: ClusterBackend<T>,
+{
+    index: Shared<T, B>,
+}
+
+/// Handle that searches a shared index, cloned freely and sent to other threads.
+pub struct IndexReader<T, B = PuffinnIndex>
+where
+    T: MetricData + IndexableSimilarity<T> + Subset,
+    <T as Subset>::Out: IndexableSimilarity<<T as Subset>::Out>,
+    B: ClusterBackend<T>,
+{
+    index: Shared<T, B>,
+}
+
+impl<T, B> Clone for IndexReader<T, B>
+where
+    T: MetricData + IndexableSimilarity<T> + Subset,
+    <T as Subset>::Out: IndexableSimilarity<<T as Subset>::Out>,
+    B: ClusterBackend<T>,
+{
+    fn clone(&self) -> Self {
+        Self {
+            index: Arc::clone(&self.index),
+        }
+    }
+}
+
+impl<T, B> IndexWriter<T, B>
+where
+    T: MetricData + IndexableSimilarity<T> + Subset,
+    <T as Subset>::Out: IndexableSimilarity<<T as Subset>::Out>,
+    B: ClusterBackend<T>,
+{
+    /// Takes ownership of `index`, use [`reader()`](Self::reader) to search it.
+    pub fn new(index: ClusteredIndex<T, B>) -> Self {
+        Self {
+            index: Arc::new(Mutex::new(index)),
+        }
+    }
+
+    /// Returns a new reader of the index.
+    pub fn reader(&self) -> IndexReader<T, B> {
+        IndexReader {
+            index: Arc::clone(&self.index),
+        }
+    }
+
+    /// Builds the index, see [`crate::build`]. Searches wait until the build is complete.
+    pub fn build(&self) -> Result<()> {
+        lock(&self.index)?.build()
+    }
+
+    /// Replaces the configuration, see [`ClusteredIndex::reconfigure`].
+    pub fn reconfigure(&self, config: Config) -> Result<()> {
+        lock(&self.index)?.reconfigure(config)
+    }
+
+    /// Inserts a point, see [`crate::insert`].
+    pub fn insert(&self, point: &[T::DataType]) -> Result<usize>
+    where
+        T: Insertable,
+    {
+        lock(&self.index)?.insert(point)
+    }
+
+    /// Inserts the rows of `points`, see [`crate::insert_batch`].
+    ///
+    /// The whole batch is published at once: searches see none or all of its points.
+    pub fn insert_batch<S>(&self, points: &ArrayBase<S, Ix2>) -> Result<Vec<usize>>
+    where
+        T: Insertable,
+        S: Data<Elem = T::DataType>,
+    {
+        lock(&self.index)?.insert_batch(points)
+    }
+
+    /// Gives the index back once every reader is dropped, otherwise returns the writer.
+    pub fn into_inner(self) -> std::result::Result<ClusteredIndex<T, B>, Self> {
+        match Arc::try_unwrap(self.index) {
+            Ok(index) => Ok(index.into_inner().unwrap_or_else(|e| e.into_inner())),
+            Err(index) => Err(Self { index }),
+        }
+    }
+}
+
+impl<T, B> IndexReader<T, B>
+where
+    T: MetricData + IndexableSimilarity<T> + Subset,
+    <T as Subset>::Out: IndexableSimilarity<<T as Subset>::Out>,
+    B: ClusterBackend<T>,
+{
+    /// Searches the nearest neighbors of `query`, see [`crate::search`].
+    pub fn search(&self, query: &[T::DataType]) -> Result<Vec<(f32, usize)>>
+    {
+        lock(&self.index)?.search(query)
+    }
+
+    /// Searches every row of `queries`, see [`crate::search_batch`].
+    ///
+    /// The batch runs on a single snapshot, mutations wait until it is complete.
+    pub fn search_batch<S>(&self, queries: &ArrayBase<S, Ix2>) -> Result<Vec<Vec<(f32, usize)>>>
+    where
+        S: Data<Elem = T::DataType>,
+    {
+        lock(&self.index)?.search_batch(queries)
+    }
+
+    /// Returns the cluster probe plan of `query`, see [`crate::plan`].
+    pub fn plan(&self, query: &[T::DataType]) -> Result<SearchPlan>
+    {
+        lock(&self.index)?.plan(query)
+    }
+
+    /// Returns the current configuration of the index.
+    pub fn config(&self) -> Result<Config> {
+        Ok(lock(&self.index)?.config().clone())
+    }
+}
+
+/// Handle to an index searched by many threads at once while a writer updates it, cloned
+/// freely and sent to other threads or tasks.
+///
+/// Searches share a read lock and run concurrently, through
+/// [`ClusteredIndex::search_shared`], so they are not recorded in the metrics or the
+/// statistics of the clusters. Mutations take the write lock: they wait for the running
+/// searches, and the searches started meanwhile wait for them, so every search sees the
+/// index before or after a mutation, never halfway through it. Mutations are serialized with
+/// each other, and [`insert_batch()`](Self::insert_batch) only takes the write lock to append
+/// the points and to swap in their clusters, rebuilt meanwhile under the read lock.
+///
+/// Every operation also has an async version, run on a dedicated thread pool and returning
+/// its future, so that it never blocks the threads of the runtime.
+pub struct IndexHandle<T, B = PuffinnIndex>
+where
+    T: MetricData + IndexableSimilarity<T> + Subset,
+    <T as Subset>::Out: IndexableSimilarity<<T as Subset>::Out>,
+    B: ClusterBackend<T>,
+{
+    index: Arc<RwLock<ClusteredIndex<T, B>>>,
+    writer: Arc<Mutex<()>>, // held by the mutations from start to end
+    pool: BlockingPool,
+}
+
+impl<T, B> Clone for IndexHandle<T, B>
+where
+    T: MetricData + IndexableSimilarity<T> + Subset,
+    <T as Subset>::Out: IndexableSimilarity<<T as Subset>::Out>,
+    B: ClusterBackend<T>,
+{
+    fn clone(&self) -> Self {
+        Self {
+            index: Arc::clone(&self.index),
+            writer: Arc::clone(&self.writer),
+            pool: self.pool.clone(),
+        }
+    }
+}
+
+impl<T, B> IndexHandle<T, B>
+where
+    T: MetricData + IndexableSimilarity<T> + Subset + Send + Sync + 'static,
+    <T as Subset>::Out: IndexableSimilarity<<T as Subset>::Out>,
+    B: ClusterBackend<T> + Send + Sync + 'static,
+{
+    /// Takes ownership of `index`, with a blocking pool of [`Config::threads()`] threads for the
+    /// async operations.
+    pub fn new(index: ClusteredIndex<T, B>) -> Self {
+        let pool = BlockingPool::new(index.config().threads());
+        Self::with_pool(index, pool)
+    }
+
+    /// Takes ownership of `index`, running the async operations on `pool`, which may be shared
+    /// with other indices and the rest of the application.
+    pub fn with_pool(index: ClusteredIndex<T, B>, pool: BlockingPool) -> Self {
+        Self {
+            index: Arc::new(RwLock::new(index)),
+            writer: Arc::new(Mutex::new(())),
+            pool,
+        }
+    }
+
+    /// Returns the pool running the async operations.
+    pub fn pool(&self) -> &BlockingPool {
+        &self.pool
+    }
+
+    /// Searches the nearest neighbors of `query` concurrently with the other searches, see
+    /// [`crate::search`].
+    pub fn search(&self, query: &[T::DataType]) -> Result<Vec<(f32, usize)>> {
+        self.search_with(query, &SearchParams::default())
+    }
+
+    /// Searches the nearest neighbors of `query` with the options of `params`, see
+    /// [`crate::search_with`].
+    pub fn search_with(&self, query: &[T::DataType], params: &SearchParams) -> Result<Vec<(f32, usize)>> {
+        self.index.read().map_err(poisoned)?.search_shared(query, params)
+    }
+
+    /// Searches every row of `queries`, all of them on the same version of the index.
+    pub fn search_batch<S>(&self, queries: &ArrayBase<S, Ix2>) -> Result<BatchResults>
+    where
+        S: Data<Elem = T::DataType>,
+    {
+        self.search_batch_with(queries, &SearchParams::default())
+    }
+
+    /// Searches every row of `queries` with the options of `params`, all of them on the same
+    /// version of the index.
+    pub fn search_batch_with<S>(&self, queries: &ArrayBase<S, Ix2>, params: &SearchParams) -> Result<BatchResults>
+    where
+        S: Data<Elem = T::DataType>,
+    {
+        let index = self.index.read().map_err(poisoned)?;
+        queries
+            .rows()
+            .into_iter()
+            .map(|query| index.search_shared(&query.to_vec(), params))
+            .collect()
+    }
+
+    /// Returns the current configuration of the index.
+    pub fn config(&self) -> Result<Config> {
+        Ok(self.index.read().map_err(poisoned)?.config().clone())
+    }
+
+    /// Returns the number of points of the dataset, including those inserted.
+    pub fn num_points(&self) -> Result<usize> {
+        Ok(self.index.read().map_err(poisoned)?.data().num_points())
+    }
+
+    /// Returns the dimensions of the points of the dataset.
+    pub fn dimensions(&self) -> Result<usize> {
+        Ok(self.index.read().map_err(poisoned)?.data().dimensions())
+    }
+
+    /// Returns the number of clusters of the index.
+    pub fn num_clusters(&self) -> Result<usize> {
+        Ok(self.index.read().map_err(poisoned)?.num_clusters())
+    }
+
+    /// Runs `f` with exclusive access to the index, to apply any mutation. Searches wait until
+    /// it returns.
+    pub fn update<R>(&self, f: impl FnOnce(&mut ClusteredIndex<T, B>) -> Result<R>) -> Result<R> {
+        let _writer = self.writer.lock().map_err(poisoned)?;
+        f(&mut *self.index.write().map_err(poisoned)?)
+    }
+
+    /// Inserts the rows of `points` at once, see [`crate::insert_batch`].
+    ///
+    /// The points are appended to the dataset under the write lock, in no cluster yet. Their
+    /// clusters are then rebuilt under the read lock, while the searches go on without them,
+    /// and swapped in under the write lock.
+    pub fn insert_batch<S>(&self, points: &ArrayBase<S, Ix2>) -> Result<Vec<usize>>
+    where
+        T: Insertable,
+        S: Data<Elem = T::DataType>,
+    {
+        // no other mutation between the steps, the prepared clusters stay current
+        let _writer = self.writer.lock().map_err(poisoned)?;
+        let ids = self.index.write().map_err(poisoned)?.append_points(points)?;
+        let prepared = self.index.read().map_err(poisoned)?.prepare_index_points(&ids);
+        let prepared = match prepared {
+            Ok(prepared) => prepared,
+            Err(e) => {
+                if let Some(&first_id) = ids.first() {
+                    self.index.read().map_err(poisoned)?.abort_logged_batch(first_id, ids.len())?;
+                }
+                return Err(e);
+            }
+        };
+
+        let mut index = self.index.write().map_err(poisoned)?;
+        index.apply_index_points(prepared);
+        index.refresh_query_cache();
+        Ok(ids)
+    }
+
+    /// [`update()`](Self::update) on the blocking pool.
+    pub fn with<R, F>(&self, f: F) -> Pending<Result<R>>
+    where
+        F: FnOnce(&mut ClusteredIndex<T, B>) -> Result<R> + Send + 'static,
+        R: Send + 'static,
+    {
+        let handle = self.clone();
+        self.pool.spawn(move || handle.update(f))
+    }
+
+    /// [`search()`](Self::search) on the blocking pool.
+    pub fn search_async(&self, query: Vec<T::DataType>) -> Pending<Result<Vec<(f32, usize)>>> {
+        let handle = self.clone();
+        self.pool.spawn(move || handle.search(&query))
+    }
+
+    /// [`search_with()`](Self::search_with) on the blocking pool, leaving out the points of
+    /// `exclude`, returning `k` neighbors if given and none farther than `max_distance` if
+    /// given, see [`SearchParams`].
+    pub fn search_with_async(
+        &self,
+        query: Vec<T::DataType>,
+        exclude: Vec<usize>,
+        k: Option<usize>,
+        max_distance: Option<f32>,
+    ) -> Pending<Result<Vec<(f32, usize)>>> {
+        let handle = self.clone();
+        self.pool.spawn(move || {
+            let params = SearchParams {
+                exclude: &exclude,
+                k,
+                max_distance,
+            };
+            handle.search_with(&query, &params)
+        })
+    }
+
+    /// [`search_batch()`](Self::search_batch) on the blocking pool.
+    pub fn search_batch_async(&self, queries: Array2<T::DataType>) -> Pending<Result<BatchResults>> {
+        let handle = self.clone();
+        self.pool.spawn(move || handle.search_batch(&queries))
+    }
+
+    /// [`insert_batch()`](Self::insert_batch) on the blocking pool.
+    pub fn insert_batch_async(&self, points: Array2<T::DataType>) -> Pending<Result<Vec<usize>>>
+    where
+        T: Insertable,
+    {
+        let handle = self.clone();
+        self.pool.spawn(move || handle.insert_batch(&points))
+    }
+
+    /// Gives the index back once every other handle is dropped, otherwise returns the handle.
+    pub fn into_inner(self) -> std::result::Result<ClusteredIndex<T, B>, Self> {
+        match Arc::try_unwrap(self.index) {
+            Ok(index) => Ok(index.into_inner().unwrap_or_else(|e| e.into_inner())),
+            Err(index) => Err(Self {
+                index,
+                writer: self.writer,
+                pool: self.pool,
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use std::future::Future;
+    use std::pin::pin;
+    use std::sync::{mpsc, Arc, Mutex};
+    use std::task::{Context, Poll, Wake};
+
+    use super::{BlockingPool, IndexHandle, IndexWriter};
+    use crate::core::index::ClusteredIndex;
+    use crate::core::{ClusterBackend, Config, IndexMode};
+    use crate::metricdata::{AngularData, MetricData};
+    use crate::utils::datagen::unit_vectors;
+
+    // the next build of a GatedBackend waits for a message on it
+    static GATE: Mutex<Option<mpsc::Receiver<()>>> = Mutex::new(None);
+
+    /// Backend returning every member of its cluster
+    struct GatedBackend(u32);
+
+    impl<M: MetricData> ClusterBackend<M> for GatedBackend {
+        fn build(_data: &M, indices: &[usize], _num_tables: usize) -> std::result::Result<(Self, usize), String> {
+            let gate = GATE.lock().unwrap().take();
+            if let Some(gate) = gate {
+                gate.recv().unwrap();
+            }
+            Ok((GatedBackend(indices.len() as u32), 4))
+        }
+
+        fn search(&self, _query: &[M::DataType], _k: usize, _max_dist: f32, _recall: f32) -> std::result::Result<(Vec<u32>, usize), String> {
+            Ok(((0..self.0).collect(), self.0 as usize))
+        }
+
+        fn to_bytes(&self) -> std::result::Result<Vec<u8>, String> {
+            Ok(self.0.to_le_bytes().to_vec())
+        }
+
+        fn from_bytes(bytes: &[u8]) -> std::result::Result<Self, String> {
+            let bytes = bytes.try_into().map_err(|_| "expected 4 bytes".to_string())?;
+            Ok(GatedBackend(u32::from_le_bytes(bytes)))
+        }
+    }
+
+    #[test]
+    fn test_searches_see_whole_batches() {
+        let config = Config {
+            index_mode: IndexMode::Flat,
+            k: 1,
+            ..Default::default()
+        };
+        let data = AngularData::new(unit_vectors(200, 8, 1));
+        let mut index: ClusteredIndex<_> = ClusteredIndex::new(config, data).unwrap();
+        index.build().unwrap();
+
+        let writer = IndexWriter::new(index);
+        let batches: Vec<_> = (0..5).map(|i| unit_vectors(20, 8, 200 + i)).collect();
+
+        let searchers: Vec<_> = batches
+            .iter()
+            .map(|batch| {
+                let reader = writer.reader();
+                let batch = batch.clone();
+                thread::spawn(move || {
+                    // a batch is either not inserted yet, or all of its points are found exactly
+                    let results = reader.search_batch(&batch).unwrap();
+                    let exact = results.iter().filter(|r| r[0].0.abs() < 1e-5).count();
+                    assert!(exact == 0 || exact == batch.nrows());
+                })
+            })
+            .collect();
+
+        for batch in &batches {
+            writer.insert_batch(batch).unwrap();
+        }
+        for searcher in searchers {
+            searcher.join().unwrap();
+        }
+
+        let index = writer.into_inner().ok().unwrap();
+        assert_eq!(crate::metricdata::MetricData::num_points(index.data()), 300);
+    }
+
+    struct Unpark(thread::Thread);
+
+    impl Wake for Unpark {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    /// Polls `future` on the current thread, parking it until woken
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let waker = Arc::new(Unpark(thread::current())).into();
+        let mut cx = Context::from_waker(&waker);
+        let mut future = pin!(future);
+        loop {
+            match future.as_mut().poll(&mut cx) {
+                Poll::Ready(output) => return output,
+                Poll::Pending => thread::park(),
+            }
+        }
+    }
+
+    #[test]
+    fn test_search_async() {
+        let config = Config {
+            index_mode: IndexMode::Flat,
+            k: 1,
+            ..Default::default()
+        };
+        let points = unit_vectors(200, 8, 3);
+        let data = AngularData::new(points.clone());
+        let mut index: ClusteredIndex<_> = ClusteredIndex::new(config, data).unwrap();
+        index.build().unwrap();
+        let handle = IndexHandle::new(index);
+
+        // searches from several threads, each awaiting its own futures
+        let searchers: Vec<_> = (0..4)
+            .map(|t| {
+                let handle = handle.clone();
+                let points = points.clone();
+                thread::spawn(move || {
+                    for i in (t..200).step_by(4) {
+                        let found = block_on(handle.search_async(points.row(i).to_vec())).unwrap();
+                        assert_eq!(found[0].1, i);
+                    }
+                })
+            })
+            .collect();
+        for searcher in searchers {
+            searcher.join().unwrap();
+        }
+        let found = block_on(handle.search_with_async(points.row(5).to_vec(), vec![5], Some(3), None)).unwrap();
+        assert_eq!(found.len(), 3);
+        assert!(found.iter().all(|&(_, id)| id != 5));
+
+        let batch = unit_vectors(10, 8, 4);
+        let ids = block_on(handle.insert_batch_async(batch.clone())).unwrap();
+        let found = block_on(handle.search_batch_async(batch)).unwrap();
+        assert_eq!(found.iter().map(|r| r[0].1).collect::<Vec<_>>(), ids);
+
+        // errors and panics reach the task awaiting them
+        assert!(block_on(handle.search_async(vec![0.5; 3])).is_err());
+        let panicking = handle.clone();
+        assert!(thread::spawn(move || block_on(panicking.with(|_| -> crate::core::Result<()> { panic!("boom") })))
+            .join()
+            .is_err());
+        assert!(block_on(handle.search_async(points.row(0).to_vec())).is_err());
+    }
+
+    #[test]
+    fn test_blocking_pool() {
+        let config = Config {
+            index_mode: IndexMode::Flat,
+            num_threads: Some(2),
+            ..Default::default()
+        };
+        let data = AngularData::new(unit_vectors(100, 8, 5));
+        let mut index: ClusteredIndex<_> = ClusteredIndex::new(config, data).unwrap();
+        index.build().unwrap();
+
+        // the pool follows the thread budget of the index
+        let handle = IndexHandle::new(index);
+        assert_eq!(handle.pool().threads(), 2);
+        let name = block_on(handle.with(|_| Ok(thread::current().name().map(str::to_string)))).unwrap();
+        assert!(name.unwrap().starts_with("clann-blocking-"));
+
+        // or is shared with the application
+        let pool = BlockingPool::new(3);
+        let index = handle.into_inner().unwrap_or_else(|_| panic!("the handle is not shared"));
+        let handle = IndexHandle::with_pool(index, pool.clone());
+        assert_eq!(handle.pool().threads(), 3);
+        let found = block_on(handle.search_async(vec![0.5; 8])).unwrap();
+        assert_eq!(found.len(), Config::default().k);
+        assert_eq!(block_on(pool.spawn(|| 42)), 42);
+
+        // an operation counts as pending until it ran, even once its future is dropped
+        let (release, gate) = mpsc::channel::<()>();
+        drop(pool.spawn(move || gate.recv().unwrap()));
+        assert_eq!(pool.pending(), 1);
+        release.send(()).unwrap();
+        while pool.pending() > 0 {
+            thread::yield_now();
+        }
+    }
+
+    #[test]
+    fn test_searches_during_rebuild() {
+        let config = Config {
+            k: 1,
+            num_clusters_factor: 0.1,
+            ..Default::default()
+        };
+        let points = unit_vectors(1000, 8, 6);
+        let mut index: ClusteredIndex<_, GatedBackend> = ClusteredIndex::new(config, AngularData::new(points.clone())).unwrap();
+        index.build().unwrap();
+        let handle = IndexHandle::new(index);
+
+        let (release, gate) = mpsc::channel();
+        *GATE.lock().unwrap() = Some(gate);
+        let batch = unit_vectors(1, 8, 7);
+        let inserter = {
+            let handle = handle.clone();
+            let batch = batch.clone();
+            thread::spawn(move || handle.insert_batch(&batch).unwrap())
+        };
+        while GATE.lock().unwrap().is_some() {
+            thread::yield_now();
+        }
+
+        // the cluster of the point is being rebuilt, the searches go on without the point
+        for i in (0..1000).step_by(50) {
+            assert_eq!(handle.search(points.row(i).as_slice().unwrap()).unwrap()[0].1, i);
+        }
+        assert!(handle.search(batch.row(0).as_slice().unwrap()).unwrap()[0].1 < 1000);
+
+        release.send(()).unwrap();
+        let ids = inserter.join().unwrap();
+        assert_eq!(handle.search(batch.row(0).as_slice().unwrap()).unwrap()[0].1, ids[0]);
+    }
+
+    #[test]
+    fn test_concurrent_searches() {
+        let config = Config {
+            index_mode: IndexMode::Flat,
+            k: 1,
+            ..Default::default()
+        };
+        let points = unit_vectors(300, 8, 8);
+        let data = AngularData::new(points.clone());
+        let mut index: ClusteredIndex<_> = ClusteredIndex::new(config, data).unwrap();
+        index.build().unwrap();
+        let handle = IndexHandle::new(index);
+        let batches: Vec<_> = (0..5).map(|i| unit_vectors(20, 8, 900 + i)).collect();
+
+        let searchers: Vec<_> = batches
+            .iter()
+            .map(|batch| {
+                let handle = handle.clone();
+                let batch = batch.clone();
+                thread::spawn(move || {
+                    for _ in 0..10 {
+                        // a batch is either not inserted yet, or all of its points are found exactly
+                        let results = handle.search_batch(&batch).unwrap();
+                        let exact = results.iter().filter(|r| r[0].0.abs() < 1e-5).count();
+                        assert!(exact == 0 || exact == batch.nrows());
+                    }
+                })
+            })
+            .collect();
+        let writer = {
+            let handle = handle.clone();
+            let batches = batches.clone();
+            thread::spawn(move || {
+                for batch in &batches {
+                    handle.insert_batch(batch).unwrap();
+                }
+            })
+        };
+        writer.join().unwrap();
+        for searcher in searchers {
+            searcher.join().unwrap();
+        }
+
+        // the shared searches find the same neighbors
+        let queries = unit_vectors(20, 8, 10);
+        let shared = handle.search_batch(&queries).unwrap();
+        let mut index = handle.into_inner().ok().unwrap();
+        assert_eq!(crate::metricdata::MetricData::num_points(index.data()), 400);
+        for (query, shared) in queries.rows().into_iter().zip(shared) {
+            assert_eq!(index.search(&query.to_vec()).unwrap(), shared);
+        }
+    }
+}
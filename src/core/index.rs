@@ -1,55 +1,332 @@
+use std::borrow::Cow;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
 use std::fs;
+use std::hash::{Hash, Hasher};
 use std::path::Path;
+use std::sync::Mutex;
 use std::time::{Duration, Instant};
 
 use hdf5::types::{VarLenAscii, VarLenUnicode};
-use hdf5::File;
-use log::{debug, error, info, trace};
-use ndarray::{Array, Ix2};
-use ordered_float::OrderedFloat;
+use hdf5::{File, H5Type};
+use log::{debug, error, info, trace, warn};
+use memmap2::Mmap;
+use ndarray::{s, Array2, ArrayBase, ArrayView2, Data, Ix2};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
 use rusqlite::Connection;
 use serde::{Deserialize, Serialize};
 
 use crate::core::config::MetricsOutput;
-use crate::core::heap::Element;
 use crate::core::{ClusteredIndexError, Config, Result};
-use crate::metricdata::{MetricData, Subset};
-use crate::puffinn_binds::get_distance_computations;
-use crate::puffinn_binds::puffinn::clear_distance_computations;
+use crate::metricdata::{checked_norm, Insertable, MetricData, Scalar, Subset};
 use crate::puffinn_binds::IndexableSimilarity;
 use crate::puffinn_binds::PuffinnIndex;
-use crate::utils::{db_exists, RunMetrics};
+use crate::topk::{Ties, TopK};
+use crate::utils::{BuildMetrics, DistanceComputations, MetricsCallbacks, QueryMetrics, RecallInput, RunMetrics};
 
-use super::config::MetricsGranularity;
-use super::gmm::greedy_minimum_maximum;
-use super::heap::TopKClosestHeap;
+use super::backend::ClusterBackend;
+use super::binary::{
+    find_collection, member_ranges, parse_binary, parse_header, truncated_blob, write_binary, write_binary_to,
+    write_collection,
+};
+use super::checkpoint::{Checkpoint, CheckpointHeader, CheckpointKey};
+use super::cache::{Fnv64, QueryCache};
+use super::config::{CenterSelection, ClusterCount, IndexMode, MetricsGranularity, PruningRadius, TinyClusters};
+use super::dedup::Duplicates;
+use super::delta::{DeltaPolicy, ProbeContext};
+use super::diversify::mmr_select;
+use super::gmm::{greedy_minimum_maximum, greedy_minimum_maximum_with, greedy_radii, min_max_medoid, radius_elbow};
+use super::graph::KnnGraph;
+use super::mapped::{MappedBytes, Members};
+use super::memory::{fit_memory, BuildEstimate, BuildReport, ClusterEstimate, Degradation, MemoryUsage};
+use super::misses::{Miss, MissReason, QueryMisses};
+use super::plan::{NearestCluster, PlanStep, QueryHardness, SearchPlan};
+use super::params::{Aggregation, SearchParams};
+use super::partition::{Partition, PartitionArtifact, RepartitionPolicy};
+use super::progress::{linear_eta, BuildObserver, BuildPhase, BuildProgress, CancellationToken};
+use super::pq::ProductQuantizer;
+use super::probe::{CenterDistances, ProbeOrder};
+use super::quantize::ScalarQuantizer;
+use super::rerank::Reranker;
+use super::rss::{PeakRss, RssSampler};
+use super::stats::{member_distances, Distribution, IndexStats};
+use super::wal::WriteAheadLog;
+
+/// Most points of a cluster searched together by [`ClusteredIndex::knn_graph`]
+const KNN_GRAPH_BATCH: usize = 1024;
+
+/// The first `k` of `neighbors` other than `point`
+fn without_point(mut neighbors: Vec<(f32, usize)>, point: usize, k: usize) -> Vec<(f32, usize)> {
+    neighbors.retain(|&(_, neighbor)| neighbor != point);
+    neighbors.truncate(k);
+    neighbors
+}
+
+/// Id padding the rows of [`ClusteredIndex::search_batch_matrix`] with fewer than k results
+pub const NO_NEIGHBOR: u32 = u32::MAX;
+
+/// Ids and distances of `results` as `results.len() x k` matrices, padded with [`NO_NEIGHBOR`]
+fn result_matrices(results: &[Vec<(f32, usize)>], k: usize) -> (Array2<u32>, Array2<f32>) {
+    let mut ids = Array2::from_elem((results.len(), k), NO_NEIGHBOR);
+    let mut distances = Array2::from_elem((results.len(), k), f32::INFINITY);
+    for (i, result) in results.iter().enumerate() {
+        for (j, &(distance, id)) in result.iter().take(k).enumerate() {
+            ids[[i, j]] = id as u32;
+            distances[[i, j]] = distance;
+        }
+    }
+    (ids, distances)
+}
+
+/// Steps per unit of norm of the grid rounding the queries of [`ClusteredIndex::search_batch`]
+/// to find near-duplicates
+const NEAR_DUPLICATE_STEPS: f64 = 32.0;
+
+/// Hash of `query` rounded to a grid of [`NEAR_DUPLICATE_STEPS`] steps per unit of its norm:
+/// queries a fraction of a step apart share it, unless they straddle a line of the grid
+fn near_duplicate_key<E: Scalar>(query: &[E]) -> u64 {
+    let norm = query.iter().map(|&x| Into::<f64>::into(x).powi(2)).sum::<f64>().sqrt();
+    let step = if norm > 0.0 { norm / NEAR_DUPLICATE_STEPS } else { 1.0 };
+    let mut hasher = DefaultHasher::new();
+    for &x in query {
+        ((Into::<f64>::into(x) / step).round() as i64).hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+/// First query searched of a group of near-duplicates of a batch, whose bounds seed the probe
+/// order of the others
+struct BatchAnchor<E> {
+    query: Vec<E>,
+    bounds: Vec<f32>, // lower bounds in the metric on its distances to the centers
+}
+
+/// Most invalid rows listed in the error of [`Config::validate_data`]
+const MAX_REPORTED_ROWS: usize = 10;
+
+/// Most points clustered by [`ClusteredIndex::estimate_build`]
+const ESTIMATE_SAMPLE_POINTS: usize = 10_000;
+
+/// Most points of the pilot cluster indexed by [`ClusteredIndex::estimate_build`]
+const ESTIMATE_PILOT_POINTS: usize = 2_000;
+
+const ESTIMATE_SEED: u64 = 0x4553;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub(crate) struct ClusterCenter {
     pub(crate) idx: usize, // index of the cluster, corresponds to the index of the vec of puffinn indexes
     pub(crate) center_idx: usize, // index of the center point in the original dataset
     pub(crate) radius: f32, // radius of the cluster
-    pub(crate) assignment: Vec<usize>, // vector of indices to the original dataset for points assigned to this cluster
+    pub(crate) assignment: Members, // indices to the original dataset of the points assigned to this cluster
     pub(crate) brute_force: bool, // flag indicating if brute force is applied instead of puffinn (<500 points)
     pub(crate) memory_used: usize, // memory used by the puffinn index
+    #[serde(default)]
+    pub(crate) num_tables: Option<usize>, // tables of the index, if fewer than configured to respect the memory ceiling
+    #[serde(default)]
+    pub(crate) build_time: Duration, // time spent building the index of the cluster, zero for brute force
+    #[serde(skip)]
+    pub(crate) search_stats: ClusterSearchStats, // statistics of the searches since the index was built or loaded
+    #[serde(default)]
+    pub(crate) member_distances: Option<Distribution>, // distances from the center to the members, None for indexes saved without them
+    #[serde(default)]
+    pub(crate) outlier: bool, // pool of the outliers, scanned by every query instead of probed in order
+    #[serde(default)]
+    pub(crate) over_memory_ceiling: bool, // left without an index by the memory ceiling, kept scanned by inserts
+    #[serde(default)]
+    pub(crate) outlier_threshold: Option<f32>, // of the pool of the outliers, distance to their center past which inserted points join it
+}
+
+impl ClusterCenter {
+    /// Radius compared with the distance of the k-th neighbor to prune the cluster, the
+    /// largest one if the member distances are unknown
+    pub(crate) fn pruning_radius(&self, mode: PruningRadius) -> f32 {
+        match (mode, &self.member_distances) {
+            (PruningRadius::P99, Some(distances)) => distances.p99 as f32,
+            (PruningRadius::P90, Some(distances)) => distances.p90 as f32,
+            _ => self.radius,
+        }
+    }
+}
+
+/// Number of clusters of the last clustering, reported in the build metrics
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub(crate) struct ClusteringSummary {
+    pub(crate) selected_clusters: usize, // clusters asked for, see Config::cluster_count
+    pub(crate) merged_clusters: usize,   // tiny clusters merged into their nearest cluster
+}
+
+/// Clusters that got some points added to the dataset, with their rebuilt index, see
+/// [`ClusteredIndex::prepare_index_points`]
+pub(crate) struct PreparedPoints<B> {
+    points: usize,
+    clusters: Vec<(usize, ClusterCenter, Option<B>)>, // position of the cluster, updated cluster, new index if rebuilt
+}
+
+/// Coarse level of a two-level index, see [`Config::coarse_clusters`]: every cell groups the
+/// clusters whose center is the closest to its own, so that a query computes the distances to
+/// the centers of the clusters of a cell only if the cell is close enough.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct Hierarchy {
+    pub(crate) cells: Vec<CoarseCell>,
+    centers: Vec<usize>, // center of every cluster when the cells were computed, in cluster order
+}
+
+/// Cell of a [`Hierarchy`]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct CoarseCell {
+    pub(crate) center_idx: usize,
+    /// Largest distance from the center to the center of a cluster of the cell, in the metric
+    /// of [`MetricData::distance_to_metric`]
+    pub(crate) radius: f32,
+    pub(crate) clusters: Vec<usize>, // positions of the clusters of the cell
+}
+
+impl Hierarchy {
+    /// Groups `clusters` in the cells of `coarse_centers`, each cluster in the cell with the
+    /// nearest center. The outlier clusters, scanned by every query, are in no cell.
+    pub(crate) fn compute<T: MetricData>(data: &T, clusters: &[ClusterCenter], coarse_centers: &[usize]) -> Self {
+        let mut cells: Vec<CoarseCell> = coarse_centers
+            .iter()
+            .map(|&center_idx| CoarseCell {
+                center_idx,
+                radius: 0.0,
+                clusters: Vec::new(),
+            })
+            .collect();
+        for (position, cluster) in clusters.iter().enumerate().filter(|(_, c)| !c.outlier) {
+            let Some((cell, distance)) = cells
+                .iter()
+                .enumerate()
+                .map(|(cell, coarse)| (cell, data.distance(coarse.center_idx, cluster.center_idx)))
+                .min_by(|a, b| a.1.total_cmp(&b.1))
+            else {
+                break;
+            };
+            let cell = &mut cells[cell];
+            cell.radius = cell.radius.max(data.distance_to_metric(distance));
+            cell.clusters.push(position);
+        }
+        cells.retain(|cell| !cell.clusters.is_empty());
+
+        Self {
+            cells,
+            centers: clusters.iter().map(|c| c.center_idx).collect(),
+        }
+    }
+
+    /// Whether the cells are the ones of the centers of `clusters`, see [`CenterDistances::matches`]
+    pub(crate) fn matches(&self, clusters: &[ClusterCenter]) -> bool {
+        !clusters.is_empty()
+            && self.centers.len() == clusters.len()
+            && self.centers.iter().zip(clusters).all(|(&c, cluster)| c == cluster.center_idx)
+    }
+
+    /// Centers of the cells
+    pub(crate) fn coarse_centers(&self) -> Vec<usize> {
+        self.cells.iter().map(|cell| cell.center_idx).collect()
+    }
+
+    /// Bytes of the cells and of the centers they were computed for
+    pub(crate) fn memory_used(&self) -> usize {
+        let ids = self.centers.len() + self.cells.iter().map(|cell| cell.clusters.len() + 1).sum::<usize>();
+        ids * std::mem::size_of::<usize>() + self.cells.len() * std::mem::size_of::<f32>()
+    }
+}
+
+/// Read-only view of a cluster of a built index, see [`ClusteredIndex::clusters`]
+#[derive(Debug, Clone, Copy)]
+pub struct Cluster<'a> {
+    /// Position of the cluster in the index, the id returned by [`ClusteredIndex::cluster_of`]
+    pub id: usize,
+
+    /// Dataset id of the center point
+    pub center: usize,
+
+    /// Largest distance from the center to a member
+    pub radius: f32,
+
+    /// Distribution of the distances from the center to the members, `None` for an index
+    /// saved without them
+    pub member_distances: Option<&'a Distribution>,
+
+    /// Dataset ids of the points assigned to the cluster
+    pub members: &'a [usize],
+
+    /// Whether the cluster is scanned exhaustively instead of searched with an index
+    pub brute_force: bool,
+
+    /// Whether the cluster pools the outliers of the other clusters, see
+    /// [`Config::outlier_quantile`]. Its center is one of them and it is scanned by every query
+    pub outlier: bool,
+}
+
+/// Search statistics of a cluster, accumulated over the queries run on the index
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ClusterSearchStats {
+    pub(crate) probes: usize,     // number of queries that probed the cluster
+    pub(crate) candidates: usize, // total candidates added to the top-k by the cluster
+}
+
+impl ClusterSearchStats {
+    /// Mean number of candidates added to the top-k by a probe, if the cluster was ever probed
+    pub(crate) fn mean_candidates(&self) -> Option<f32> {
+        (self.probes > 0).then(|| self.candidates as f32 / self.probes as f32)
+    }
+}
+
+/// Probe of a cluster by a query, recorded for the search statistics
+#[derive(Debug)]
+struct Probe {
+    cluster: usize,               // position of the cluster
+    points_added: usize,                          // candidates added to the top-k
+    distance_computations: DistanceComputations, // distances computed by the cluster
+    elapsed: Duration,
+}
+
+/// What a search did, applied to the statistics and metrics of the index once it is complete
+#[derive(Debug, Default)]
+struct QueryTrace {
+    probes: Vec<Probe>,
+    distance_computations: DistanceComputations, // outside of the clusters
+    origins: Option<HashMap<usize, usize>>, // cluster of each point added to the top-k, if tracked
 }
 
-pub struct ClusteredIndex<T>
+/// Clustered index over `data`, with a `B` index (PUFFINN by default) for each non brute-force cluster
+pub struct ClusteredIndex<T, B = PuffinnIndex>
 where
     T: MetricData + IndexableSimilarity<T> + Subset,
     <T as Subset>::Out: IndexableSimilarity<<T as Subset>::Out>,
+    B: ClusterBackend<T>,
 {
     data: T,
     clusters: Vec<ClusterCenter>,
+    center_distances: CenterDistances, // distances between the centers, to skip computing the ones of far clusters
+    hierarchy: Option<Hierarchy>,      // coarse cells of a two-level index
     config: Config,
-    puffinn_indices: Vec<Option<PuffinnIndex>>,
+    puffinn_indices: Vec<Option<B>>,
     pub(crate) metrics: Option<RunMetrics>,
+    query_cache: Option<Mutex<QueryCache>>, // only used through `&mut self`, see `query_cache()`
+    build_report: Option<BuildReport>,
+    clustering: ClusteringSummary,
+    last_distance_computations: usize, // distance computations of the last search, with or without metrics
+    callbacks: MetricsCallbacks,
+    build_observer: Option<Box<dyn BuildObserver>>,
+    cancellation: Option<CancellationToken>,
+    reranker: Option<Box<dyn Reranker<T::DataType>>>,
+    delta_policy: Option<Box<dyn DeltaPolicy>>,
+    quantizer: Option<ScalarQuantizer>,
+    product_quantizer: Option<ProductQuantizer>,
+    duplicates: Option<Duplicates>, // aliases left out of the clusters, with `Config::dedup`
+    wal: Option<WriteAheadLog>,     // log of the insertions since the last serialization, see `open_wal`
+    rebuilt_clusters: Vec<usize>,   // clusters whose serialized index couldn't be read when loading
 }
 
-impl<T> ClusteredIndex<T>
+impl<T, B> ClusteredIndex<T, B>
 where
     T: MetricData + IndexableSimilarity<T> + Subset,
     <T as Subset>::Out: IndexableSimilarity<<T as Subset>::Out>,
+    B: ClusterBackend<T>,
 {
     /// Creates a new Clustered Index from scratch.
     ///
@@ -67,105 +344,340 @@ where
     /// The index needs to be built using [`build()`] before it can be used for searching.
     ///
     /// # Errors
-    /// Returns `ClusteredIndexError::DataError` if the input dataset is empty
+    /// - `ClusteredIndexError::ConfigError` if a parameter of `config` is out of range (see
+    ///   [`Config::validate`])
+    /// - `ClusteredIndexError::DataError` if the input dataset is empty, or if
+    ///   [`Config::validate_data`] is set and some of its rows are invalid
     pub(crate) fn new(config: Config, data: T) -> Result<Self> {
-        if data.num_points() == 0 {
-            return Err(ClusteredIndexError::DataError("empty dataset".to_string()));
+        let k = Self::checked_num_clusters(&config, &data)?;
+        if config.validate_data {
+            Self::check_points(&data)?;
         }
+        let config = Config {
+            storage: data.precision(),
+            weights: data.weights().map(<[f32]>::to_vec),
+            ..config
+        };
 
         info!("Initializing Index with config {:?}", config);
 
-        let k = ((config.num_clusters_factor as f64 * (data.num_points() as f64).sqrt()).floor()
-            as usize)
-            .max(1);
-        let metrics = matches!(config.metrics_output, MetricsOutput::DB)
+        let metrics = config.metrics_output.is_enabled()
             .then(|| RunMetrics::new(config.clone(), data.num_points()));
 
         Ok(ClusteredIndex {
             data,
             clusters: Vec::with_capacity(k),
+            center_distances: CenterDistances::default(),
+            hierarchy: None,
             config,
             puffinn_indices: Vec::with_capacity(k),
             metrics,
+            query_cache: None,
+            build_report: None,
+            clustering: ClusteringSummary::default(),
+            last_distance_computations: 0,
+            callbacks: MetricsCallbacks::default(),
+            build_observer: None,
+            cancellation: None,
+            reranker: None,
+            delta_policy: None,
+            quantizer: None,
+            product_quantizer: None,
+            duplicates: None,
+            wal: None,
+            rebuilt_clusters: Vec::new(),
         })
     }
 
-    /// Creates a new Clustered Index by loading a previously serialized index from a file.
-    ///
-    /// # Parameters
-    /// - `data`: The dataset implementing required traits, must match the original dataset used to build the index
-    /// - `file_path`: Path to the HDF5 file containing the serialized index
-    ///
-    /// # Returns
-    /// A `ClusteredIndex` instance loaded from the file, ready to be used for searching
+    /// Checks that every point of `data` has finite components, and a nonzero norm under the
+    /// angular distance, see [`Config::validate_data`].
     ///
     /// # Errors
-    /// Returns `ClusteredIndexError::ConfigError` if:
-    /// - The file doesn't exist
-    /// - The file format is invalid
-    /// - The serialized data is corrupted or incompatible
-    pub(crate) fn new_from_file(data: T, file_path: &str) -> Result<Self> {
-        if !Path::new(file_path).exists() {
+    /// `ClusteredIndexError::DataError` listing the first invalid rows and the number of them
+    fn check_points(data: &T) -> Result<()> {
+        info!("Validating the {} points of the dataset...", data.num_points());
+        let invalid: Vec<String> = (0..data.num_points())
+            .filter_map(|i| {
+                checked_norm(&data.get_point(i), data.scale_invariant())
+                    .err()
+                    .map(|e| format!("row {} {}", i, e))
+            })
+            .collect();
+        if invalid.is_empty() {
+            return Ok(());
+        }
+
+        let mut message = format!("{} invalid rows: ", invalid.len());
+        message.push_str(&invalid[..invalid.len().min(MAX_REPORTED_ROWS)].join(", "));
+        if invalid.len() > MAX_REPORTED_ROWS {
+            message.push_str(", ...");
+        }
+        Err(ClusteredIndexError::DataError(message))
+    }
+
+    /// Checks that `config` can be used on `data` and returns the number of clusters it gives,
+    /// or the most it may give if it is chosen during the build (see [`Self::select_num_clusters`]).
+    fn checked_num_clusters(config: &Config, data: &T) -> Result<usize> {
+        config.validate()?;
+        if data.num_points() == 0 {
+            return Err(ClusteredIndexError::DataError("empty dataset".to_string()));
+        }
+
+        if let Some(projection) = &config.projection {
+            if data.dimensions() != projection.output_dim() {
+                return Err(ClusteredIndexError::ConfigError(format!(
+                    "dataset has {} dimensions but the projection outputs {}, project it with RandomProjection::transform",
+                    data.dimensions(),
+                    projection.output_dim()
+                )));
+            }
+        }
+
+        if !data.lsh_supported() && (config.scalar_quantization || config.pq.is_some()) {
             return Err(ClusteredIndexError::ConfigError(format!(
-                "file {} not found",
-                file_path
+                "the quantizers approximate inner products, they can't be used with the {} distance",
+                data.similarity_type()
             )));
         }
 
-        let file =
-            File::open(file_path).map_err(|e| ClusteredIndexError::ConfigError(e.to_string()))?;
-        let root = file
-            .group("/")
-            .map_err(|e| ClusteredIndexError::ConfigError(e.to_string()))?;
+        let num_points = data.num_points();
+        let k = match config.cluster_count {
+            ClusterCount::Factor => {
+                (config.num_clusters_factor as f64 * (num_points as f64).sqrt()).floor() as usize
+            }
+            ClusterCount::AverageSize { points } => num_points.div_ceil(points.max(1)),
+            ClusterCount::RadiusElbow { max_clusters } => max_clusters.min(num_points),
+        };
 
-        // read config
-        let config_dataset = root
-            .dataset("config")
-            .map_err(|e| ClusteredIndexError::ConfigError(e.to_string()))?;
-        let config_ascii = config_dataset
-            .read_scalar::<VarLenAscii>()
-            .map_err(|e| ClusteredIndexError::ConfigError(e.to_string()))?;
-        let config: Config = serde_json::from_str(config_ascii.as_str())
-            .map_err(|e| ClusteredIndexError::ConfigError(e.to_string()))?;
-        let metrics = matches!(config.metrics_output, MetricsOutput::DB)
-            .then(|| RunMetrics::new(config.clone(), data.num_points()));
+        Ok(k.max(1))
+    }
 
-        // read cluster centers
-        let cluster_dataset = root
-            .dataset("clusters")
-            .map_err(|e| ClusteredIndexError::ConfigError(e.to_string()))?;
-        let cluster_ascii = cluster_dataset
-            .read_scalar::<VarLenAscii>()
-            .map_err(|e| ClusteredIndexError::ConfigError(e.to_string()))?;
-        let clusters: Vec<ClusterCenter> = serde_json::from_str(cluster_ascii.as_str())
-            .map_err(|e| ClusteredIndexError::ConfigError(e.to_string()))?;
+    /// Replaces the configuration, discarding the clusters and their indices.
+    ///
+    /// The index must be built again with [`build()`] before searching. The query cache,
+    /// if enabled, is disabled since the fingerprint of the index is about to change.
+    ///
+    /// # Errors
+    /// Returns `ClusteredIndexError::ConfigError` if a parameter of `config` is out of range or
+    /// its projection doesn't match the dataset
+    pub fn reconfigure(&mut self, config: Config) -> Result<()> {
+        let k = Self::checked_num_clusters(&config, &self.data)?;
+        if config.validate_data && !self.config.validate_data {
+            Self::check_points(&self.data)?;
+        }
+        let config = Config {
+            storage: self.data.precision(),
+            weights: self.data.weights().map(<[f32]>::to_vec),
+            ..config
+        };
 
-        // read puffinn indices
-        let mut puffinn_indices = Vec::new();
-        for c in &clusters {
-            if !c.brute_force {
-                let index =
-                    PuffinnIndex::new_from_file(file_path, &format!("index_{}", c.idx)).unwrap();
-                puffinn_indices.push(Some(index));
-            } else {
-                puffinn_indices.push(None);
-            }
+        info!("Reconfiguring Index with config {:?}", config);
+
+        self.metrics = (config.metrics_output.is_enabled() || !self.callbacks.on_query.is_empty())
+            .then(|| RunMetrics::new(config.clone(), self.data.num_points()));
+        self.clusters = Vec::with_capacity(k);
+        self.puffinn_indices = Vec::with_capacity(k);
+        self.hierarchy = None;
+        self.duplicates = None;
+        self.config = config;
+        self.build_report = None;
+        if self.query_cache.take().is_some() {
+            info!("Query cache disabled, enable it again once the index is built");
         }
+        self.check_delta_policy();
 
-        Ok(Self {
-            data,
-            clusters,
-            config,
-            puffinn_indices,
-            metrics,
+        Ok(())
+    }
+
+    /// Returns the dataset the index is built on.
+    pub fn data(&self) -> &T {
+        &self.data
+    }
+
+    /// Drops the index and returns its dataset.
+    pub(crate) fn into_data(self) -> T {
+        self.data
+    }
+
+    /// Returns the number of clusters, zero if the index is not built.
+    pub fn num_clusters(&self) -> usize {
+        self.clusters.len()
+    }
+
+    /// Returns the clusters of the index with their center, radius and members, in order of id.
+    ///
+    /// Empty if the index is not built.
+    pub fn clusters(&self) -> impl ExactSizeIterator<Item = Cluster<'_>> + '_ {
+        self.clusters.iter().map(|cluster| Cluster {
+            id: cluster.idx,
+            center: cluster.center_idx,
+            radius: cluster.radius,
+            member_distances: cluster.member_distances.as_ref(),
+            members: &cluster.assignment,
+            brute_force: cluster.brute_force,
+            outlier: cluster.outlier,
         })
     }
 
+    /// Returns the id of the cluster `point_id` is assigned to, `None` if the point is not in
+    /// the index. A point collapsed by [`Config::dedup`] is in the cluster of its first copy.
+    ///
+    /// Scans the assignments, O(n): to map every point, iterate [`clusters()`](Self::clusters) once instead.
+    pub fn cluster_of(&self, point_id: usize) -> Option<usize> {
+        // duplicates are in the cluster of their canonical point
+        let point_id = self.duplicates.as_ref().map_or(point_id, |d| d.canonical(point_id));
+        self.clusters
+            .iter()
+            .find(|cluster| cluster.assignment.contains(&point_id))
+            .map(|cluster| cluster.idx)
+    }
+
+    /// Returns the size, radius and memory distributions of the clusters.
+    ///
+    /// Use [`crate::stats_from_file`] to inspect a serialized index without its dataset.
+    pub fn stats(&self) -> IndexStats {
+        let memory = self.memory_usage();
+        IndexStats {
+            quantized_memory: memory.quantized,
+            memory,
+            ..IndexStats::from_clusters(&self.clusters)
+        }
+    }
+
+    /// Returns the memory held by the index in bytes, by component.
+    ///
+    /// The cluster indices count the memory reported by the backend when they were built, the
+    /// other components the bytes of what they store, without the overhead of the allocator.
+    pub fn memory_usage(&self) -> MemoryUsage {
+        MemoryUsage {
+            dataset: self.data.memory_used(),
+            centers: self.center_distances.memory_used() + self.hierarchy.as_ref().map_or(0, Hierarchy::memory_used),
+            quantized: self.quantizer.as_ref().map_or(0, |q| q.memory_used())
+                + self.product_quantizer.as_ref().map_or(0, |q| q.memory_used()),
+            duplicates: self.duplicates.as_ref().map_or(0, Duplicates::memory_used),
+            ..MemoryUsage::from_clusters(&self.clusters)
+        }
+    }
+
+    /// Registers a callback called with the metrics of every query, as soon as it completes.
+    ///
+    /// Per-query metrics are collected from then on even if `metrics_output` is `None`, in
+    /// which case each query is dropped once passed to the callbacks. Queries answered by
+    /// the query cache or as duplicates in a batch are reported with empty metrics.
+    pub fn on_query<F>(&mut self, callback: F)
+    where
+        F: FnMut(&QueryMetrics) + Send + Sync + 'static,
+    {
+        if self.metrics.is_none() {
+            self.metrics = Some(RunMetrics::new(self.config.clone(), self.data.num_points()));
+        }
+        self.callbacks.on_query.push(Box::new(callback));
+    }
+
+    /// Registers a callback called with the summary of every build, once it completes.
+    pub fn on_build<F>(&mut self, callback: F)
+    where
+        F: FnMut(&BuildMetrics) + Send + Sync + 'static,
+    {
+        self.callbacks.on_build.push(Box::new(callback));
+    }
+
+    /// Removes the callbacks registered with [`on_query()`](Self::on_query) and [`on_build()`](Self::on_build).
+    pub fn clear_metrics_callbacks(&mut self) {
+        self.callbacks = MetricsCallbacks::default();
+        if !self.config.metrics_output.is_enabled() {
+            self.metrics = None;
+        }
+    }
+
+    /// Registers the observer receiving the progress of the next builds, replacing the previous one.
+    ///
+    /// # Example
+    /// ```no_run
+    /// use clann::{build, init, metricdata::AngularData};
+    ///
+    /// let mut index = init(AngularData::new(/* your dataset */)).unwrap();
+    /// index.set_build_observer(|phase, cluster, fraction, eta| {
+    ///     println!("{:?} {}: {:.0}%, {:?} left", phase, cluster, fraction * 100.0, eta);
+    /// });
+    /// build(&mut index).unwrap();
+    /// ```
+    pub fn set_build_observer<O>(&mut self, observer: O)
+    where
+        O: BuildObserver + 'static,
+    {
+        self.build_observer = Some(Box::new(observer));
+    }
+
+    /// Lets the next builds be aborted with `token`, checked after each center chosen by the
+    /// clustering and before each cluster indexed.
+    ///
+    /// A cancelled build returns `ClusteredIndexError::Cancelled` and leaves the index unbuilt.
+    pub fn set_cancellation_token(&mut self, token: CancellationToken) {
+        self.cancellation = Some(token);
+    }
+
+    /// Computes the exact distances of the candidates of the next searches with `reranker`,
+    /// replacing the previous one, e.g. a [`CudaReranker`](crate::gpu::CudaReranker) with the
+    /// `cuda` feature.
+    pub fn set_reranker<R>(&mut self, reranker: R)
+    where
+        R: Reranker<T::DataType> + 'static,
+    {
+        self.reranker = Some(Box::new(reranker));
+    }
+
+    /// Computes the distances of the candidates with the dataset again.
+    pub fn clear_reranker(&mut self) {
+        self.reranker = None;
+    }
+
+    /// Chooses the recall target of every cluster searched with `policy` instead of using the
+    /// configured delta for all of them, e.g. an [`AdaptiveDelta`](crate::core::AdaptiveDelta).
+    ///
+    /// Logs a warning if the policy needs every cluster that may hold a neighbor to be probed
+    /// and the configuration doesn't (see [`DeltaPolicy::needs_every_cluster`]).
+    pub fn set_delta_policy<P>(&mut self, policy: P)
+    where
+        P: DeltaPolicy + 'static,
+    {
+        self.delta_policy = Some(Box::new(policy));
+        self.check_delta_policy();
+    }
+
+    /// Searches every cluster with the configured delta again.
+    pub fn clear_delta_policy(&mut self) {
+        self.delta_policy = None;
+    }
+
+    /// Returns the id of the last run saved with [`crate::save_metrics`], if any.
+    ///
+    /// Every save is a new run with a random UUID, so repetitions of the same configuration
+    /// are kept side by side and can be averaged.
+    pub fn last_run_id(&self) -> Option<&str> {
+        self.metrics.as_ref().and_then(|m| m.last_run_id())
+    }
+
+    /// Passes the current query to the callbacks, and forgets it if no output needs it.
+    fn finish_query_metrics(&mut self) {
+        let Some(metrics) = &mut self.metrics else {
+            return;
+        };
+        if let Some(query) = metrics.current_query() {
+            self.callbacks.query(query);
+        }
+        if !self.config.metrics_output.is_enabled() {
+            metrics.drop_current_query();
+        }
+    }
+
     /// Builds the index by performing clustering and creating PUFFINN indices.
     ///
     /// The build process consists of two main steps:
-    /// 1. Clustering: Uses greedy minimum-maximum clustering to partition the dataset
-    /// 2. Index Creation: Creates a PUFFINN index for each cluster (except small ones which use brute force)
+    /// 1. Clustering: Uses greedy minimum-maximum clustering to partition the dataset,
+    ///    optionally replacing each center with its medoid (see [`CenterSelection`])
+    /// 2. Index Creation: Creates a PUFFINN index for each cluster (except small ones which use brute force,
+    ///    and all of them with [`IndexMode::Flat`])
     ///
     /// # Performance
     /// - Time complexity: O(n * sqrt(n)) for clustering + O(n * L) for PUFFINN index creation
@@ -175,536 +687,4115 @@ where
     /// # Errors
     /// Returns `ClusteredIndexError::PuffinnCreationError` if PUFFINN index creation fails for any cluster
     pub(crate) fn build(&mut self) -> Result<()> {
-        let total_clusters = self.clusters.capacity();
-        info!("Starting build process with {} clusters", total_clusters);
+        info!("Starting build process");
+        let start = Instant::now();
+        let rss = self.start_rss_sampler();
+        self.cluster()?;
+        self.build_indexes(start, rss, None, None)
+    }
+
+    /// Builds the index like [`build()`], checkpointing the progress in `dir` so that an
+    /// interrupted build can be resumed by calling this again with the same directory.
+    ///
+    /// The clustering is written to `dir` once computed, then the index of each cluster as soon
+    /// as it is created. If `dir` holds the checkpoint of a build of the same configuration on
+    /// the same dataset, its clustering and completed clusters are read instead of computed.
+    /// The checkpoint files are deleted once the build completes.
+    ///
+    /// # Errors
+    /// - `ClusteredIndexError::ConfigError` if `dir` holds a checkpoint made with another
+    ///   configuration or dataset
+    /// - `ClusteredIndexError::SerializeError` if the checkpoint cannot be read or written
+    /// - Any error returned by [`build()`]
+    pub(crate) fn build_resume(&mut self, dir: &str) -> Result<()>
+    {
+        let start = Instant::now();
+        let rss = self.start_rss_sampler();
+        let checkpoint = Checkpoint::open(dir)?;
+        let key = self.checkpoint_key();
+
+        let report = match checkpoint.load_header(&key)? {
+            Some(header) => {
+                info!(
+                    "Resuming build of {} clusters from the checkpoint in {}",
+                    header.clusters.len(),
+                    dir
+                );
+                self.clusters = header.clusters;
+                self.clustering = header.clustering;
+                self.hierarchy = header.hierarchy;
+                header.report
+            }
+            None => {
+                info!("Starting build process, checkpointed in {}", dir);
+                self.cluster()?;
+                self.collapse_duplicates();
+                let report = self.fit_memory_ceiling();
+                checkpoint.save_header(&CheckpointHeader {
+                    key,
+                    clusters: self.clusters.clone(),
+                    report: report.clone(),
+                    clustering: self.clustering,
+                    hierarchy: self.hierarchy.clone(),
+                })?;
+                report
+            }
+        };
+
+        self.build_indexes(start, rss, Some(&checkpoint), Some(report))?;
+        checkpoint.remove()
+    }
+
+    /// Identifies the configuration and dataset of a checkpointed build.
+    fn checkpoint_key(&self) -> CheckpointKey
+    {
+        let mut hasher = Fnv64::new();
+        for i in 0..self.data.num_points() {
+            hasher.write(&query_to_bytes(&self.data.get_point(i)));
+        }
+        CheckpointKey {
+            config: self.config.build_parameters(),
+            num_points: self.data.num_points(),
+            dimensions: self.data.dimensions(),
+            data_hash: hasher.finish(),
+        }
+    }
 
+    /// Clusters the dataset, the first step of a build, leaving the index unbuilt if cancelled.
+    fn cluster(&mut self) -> Result<()> {
         // 1) PERFORM CLUSTERING
         info!("Performing greedy clustering...");
         let start_clustering = std::time::Instant::now();
-        let (centers, assignment, radius) =
-            greedy_minimum_maximum(&self.data, self.clusters.capacity());
+        let mut observer = self.build_observer.take();
+        let cancellation = self.cancellation.clone();
+        let k = match self.select_num_clusters(None, &mut |_| {
+            !cancellation.as_ref().is_some_and(|token| token.is_cancelled())
+        }) {
+            Ok(k) => k,
+            Err(e) => {
+                self.build_observer = observer;
+                self.clear_clusters();
+                return Err(e);
+            }
+        };
+        info!("Clustering into {} clusters", k);
+        let mut coarse_centers = None;
+        let clusters = match self.config.coarse_clusters {
+            Some(num_cells) => self.hierarchical_clusters(num_cells, k, &mut observer).map(|(clusters, centers)| {
+                coarse_centers = Some(centers);
+                clusters
+            }),
+            None => self.greedy_clusters(None, k, &mut |centers| {
+                if let Some(observer) = &mut observer {
+                    let fraction = centers as f32 / k as f32;
+                    observer.on_progress(BuildPhase::Clustering, centers - 1, fraction, linear_eta(start_clustering, fraction));
+                }
+                !cancellation.as_ref().is_some_and(|token| token.is_cancelled())
+            }),
+        };
+        self.build_observer = observer;
+        self.clusters = match clusters {
+            Ok(clusters) => clusters,
+            Err(e) => {
+                self.clear_clusters();
+                return Err(e);
+            }
+        };
+        self.hierarchy = coarse_centers.map(|centers| Hierarchy::compute(&self.data, &self.clusters, &centers));
         info!("Clustering completed in {:.2?}", start_clustering.elapsed());
+        self.merge_tiny_clusters();
+        self.pool_outliers();
 
-        let mut assignments: Vec<Vec<usize>> = vec![Vec::new(); centers.len()];
+        Ok(())
+    }
 
-        for (data_idx, &center_pos) in assignment.iter().enumerate() {
-            assignments[center_pos].push(data_idx);
+    /// Clusters the dataset in `num_cells` coarse cells, then the points of every cell in a share
+    /// of the `k` clusters proportional to its size, see [`Config::coarse_clusters`].
+    ///
+    /// Every point is compared with the centers of its own cell only, so clustering costs
+    /// `O(n * (num_cells + k / num_cells))` distances instead of `O(n * k)`.
+    ///
+    /// # Returns
+    /// The clusters and the centers of the cells
+    fn hierarchical_clusters(
+        &self,
+        num_cells: usize,
+        k: usize,
+        observer: &mut Option<Box<dyn BuildObserver>>,
+    ) -> Result<(Vec<ClusterCenter>, Vec<usize>)> {
+        let start = Instant::now();
+        let cancellation = self.cancellation.clone();
+        let mut proceed = |_| !cancellation.as_ref().is_some_and(|token| token.is_cancelled());
+        let cells = self.greedy_clusters(None, num_cells, &mut proceed)?;
+        let num_points = self.data.num_points() as f64;
+
+        let mut clusters = Vec::with_capacity(k);
+        for (cell, coarse) in cells.iter().enumerate() {
+            if !coarse.assignment.is_empty() {
+                let share = (k as f64 * coarse.assignment.len() as f64 / num_points).round() as usize;
+                for mut cluster in self.greedy_clusters(Some(&coarse.assignment[..]), share.max(1), &mut proceed)? {
+                    cluster.idx = clusters.len();
+                    clusters.push(cluster);
+                }
+            }
+            if let Some(observer) = observer {
+                let fraction = (cell + 1) as f32 / cells.len() as f32;
+                observer.on_progress(BuildPhase::Clustering, cell, fraction, linear_eta(start, fraction));
+            }
         }
+        debug!("{} clusters in {} coarse cells", clusters.len(), cells.len());
 
-        self.clusters = centers
-            .iter()
-            .zip(radius.iter())
-            .zip(assignments)
-            .enumerate()
-            .map(|(idx, ((&center_idx, &radius), assignment_indexes))| {
-                let cluster = ClusterCenter {
-                    idx,
-                    center_idx,
-                    radius,
-                    brute_force: assignment_indexes.len() < 100
-                        || assignment_indexes.len() < self.config.k,
-                    assignment: assignment_indexes,
-                    memory_used: 0,
-                };
+        Ok((clusters, cells.iter().map(|cell| cell.center_idx).collect()))
+    }
 
-                trace!(
-                    "Cluster {}: center_idx={}, points={}, radius={}",
-                    idx,
-                    cluster.center_idx,
-                    cluster.assignment.len(),
-                    cluster.radius,
+    /// Number of clusters to ask greedy clustering for on `members`, all the points if `None`, as
+    /// chosen by [`Config::cluster_count`]. The radius elbow is found on `members`, the other
+    /// modes give the number of clusters of the whole dataset.
+    ///
+    /// `proceed` is called as in [`greedy_clusters`](Self::greedy_clusters) while looking for the elbow.
+    fn select_num_clusters(
+        &mut self,
+        members: Option<&[usize]>,
+        proceed: &mut dyn FnMut(usize) -> bool,
+    ) -> Result<usize> {
+        let k = Self::checked_num_clusters(&self.config, &self.data)?;
+        let k = match self.config.cluster_count {
+            ClusterCount::RadiusElbow { max_clusters } => {
+                let radii = match members {
+                    Some(members) => greedy_radii(&self.data.subset(members), max_clusters, proceed),
+                    None => greedy_radii(&self.data, max_clusters, proceed),
+                }
+                .ok_or(ClusteredIndexError::Cancelled)?;
+                let k = radius_elbow(&radii);
+                debug!(
+                    "Radius elbow at {} clusters, radius {} (max {} clusters, radius {})",
+                    k,
+                    radii[k - 1],
+                    radii.len(),
+                    radii[radii.len() - 1]
                 );
+                k
+            }
+            ClusterCount::Factor | ClusterCount::AverageSize { .. } => k,
+        };
+        let k = members.map_or(k, |members| k.min(members.len()));
 
-                cluster
-            })
-            .collect();
+        self.clustering = ClusteringSummary {
+            selected_clusters: k,
+            merged_clusters: 0,
+        };
+        Ok(k)
+    }
 
-        // 2) CREATE PUFFINN INDEXES
-        info!("Creating Puffinn indexes...");
-        self.puffinn_indices = Vec::with_capacity(self.clusters.len());
-        for (cluster_idx, cluster) in self.clusters.iter_mut().enumerate() {
-            // Progress logging
-            if cluster_idx % 10 == 0 {
-                info!(
-                    "Processing cluster {}/{} ({}%)",
-                    cluster_idx + 1,
-                    total_clusters,
-                    ((cluster_idx + 1) as f32 / total_clusters as f32 * 100.0).round()
-                );
-            }
+    /// Merges the clusters smaller than the minimum size of [`Config::tiny_clusters`] into the
+    /// cluster with the nearest center, smallest first, growing its radius to cover them.
+    fn merge_tiny_clusters(&mut self) {
+        self.clustering.merged_clusters = 0;
+        let TinyClusters::MergeNearest { min_points } = self.config.tiny_clusters else {
+            return;
+        };
 
-            if cluster.assignment.is_empty() {
-                debug!("Skipping empty cluster {}", cluster_idx);
+        let mut order: Vec<usize> = (0..self.clusters.len()).collect();
+        order.sort_by_key(|&position| self.clusters[position].assignment.len());
+        let mut merged = vec![false; self.clusters.len()];
+        for position in order {
+            // the clusters are visited by increasing size, a merge target may have grown since
+            if self.clusters[position].assignment.len() >= min_points
+                || self.clustering.merged_clusters + 1 == self.clusters.len()
+            {
                 continue;
             }
 
-            if cluster.brute_force {
-                info!(
-                    "Skipping cluster {} with {} points: doing brute force",
-                    cluster.idx,
-                    cluster.assignment.len()
-                );
-                self.puffinn_indices.push(None);
+            let center_idx = self.clusters[position].center_idx;
+            let Some((target, _)) = (0..self.clusters.len())
+                .filter(|&other| other != position && !merged[other])
+                .map(|other| (other, self.data.distance(center_idx, self.clusters[other].center_idx)))
+                .min_by(|a, b| a.1.total_cmp(&b.1))
+            else {
                 continue;
-            }
+            };
 
-            debug!(
-                "Cluster {}: L {}, points: {}",
-                cluster_idx,
-                self.config.num_tables,
-                cluster.assignment.len()
-            );
+            let members = std::mem::take(&mut self.clusters[position].assignment);
+            let center = self.data.get_point(self.clusters[target].center_idx).into_owned();
+            let mut distances = vec![0.0; members.len()];
+            self.data.distances_points(&members, &center, &mut distances);
+            let cluster = &mut self.clusters[target];
+            cluster.radius = distances.into_iter().fold(cluster.radius, f32::max);
+            let assignment = cluster.assignment.to_mut();
+            assignment.extend(members.iter().copied());
+            assignment.sort_unstable();
+            merged[position] = true;
+            self.clustering.merged_clusters += 1;
+        }
+        if self.clustering.merged_clusters == 0 {
+            return;
+        }
 
-            // Create Puffinn index
-            match PuffinnIndex::new(
-                &self.data.subset(&cluster.assignment),
-                self.config.num_tables,
-            ) {
-                Ok((puffinn_index, memory_used)) => {
-                    self.puffinn_indices.push(Some(puffinn_index));
-                    cluster.memory_used = memory_used;
-                }
-                Err(e) => {
-                    error!(
-                        "Failed to create Puffinn index for cluster {}: {:?}",
-                        cluster_idx, e
-                    );
-                    return Err(ClusteredIndexError::PuffinnCreationError(e));
+        let mut merged = merged.into_iter();
+        self.clusters.retain(|_| !merged.next().unwrap_or(false));
+        for position in 0..self.clusters.len() {
+            let brute_force = !self.needs_index(self.clusters[position].assignment.len());
+            let cluster = &mut self.clusters[position];
+            cluster.idx = position;
+            cluster.brute_force = brute_force;
+        }
+        info!(
+            "Merged {} clusters with fewer than {} points, {} left",
+            self.clustering.merged_clusters,
+            min_points,
+            self.clusters.len()
+        );
+    }
+
+    /// Moves the points farther from their center than the [`Config::outlier_quantile`] of the
+    /// distances from all the points to their center to a new brute force cluster, scanned by
+    /// every query, shrinking the radii of the clusters they leave.
+    ///
+    /// The center of the new cluster is its smallest id. Centers are never outliers, so no
+    /// cluster is left empty.
+    fn pool_outliers(&mut self) {
+        let Some(quantile) = self.config.outlier_quantile else {
+            return;
+        };
+
+        let distances: Vec<Vec<f32>> = self
+            .clusters
+            .iter()
+            .map(|cluster| {
+                let center = self.data.get_point(cluster.center_idx).into_owned();
+                let mut distances = vec![0.0; cluster.assignment.len()];
+                self.data.distances_points(&cluster.assignment, &center, &mut distances);
+                distances
+            })
+            .collect();
+        let mut sorted: Vec<f32> = distances.iter().flatten().copied().collect();
+        if sorted.is_empty() {
+            return;
+        }
+        sorted.sort_by(f32::total_cmp);
+        // nearest-rank quantile
+        let rank = (quantile as f64 * sorted.len() as f64).ceil() as usize;
+        let threshold = sorted[rank.clamp(1, sorted.len()) - 1];
+
+        let mut outliers = Vec::new();
+        for (cluster, distances) in self.clusters.iter_mut().zip(&distances) {
+            let mut kept = Vec::with_capacity(cluster.assignment.len());
+            let mut radius = 0.0f32;
+            for (&point, &distance) in cluster.assignment.iter().zip(distances) {
+                if distance > threshold && point != cluster.center_idx {
+                    outliers.push(point);
+                } else {
+                    kept.push(point);
+                    radius = radius.max(distance);
                 }
             }
+            cluster.assignment = kept.into();
+            cluster.radius = radius;
+        }
+        if outliers.is_empty() {
+            return;
+        }
+        for position in 0..self.clusters.len() {
+            self.clusters[position].brute_force = !self.needs_index(self.clusters[position].assignment.len());
         }
 
-        let indexing_duration = start_clustering.elapsed();
-
+        outliers.sort_unstable();
+        let center_idx = outliers[0];
+        let center = self.data.get_point(center_idx).into_owned();
+        let mut distances = vec![0.0; outliers.len()];
+        self.data.distances_points(&outliers, &center, &mut distances);
         info!(
-            "Build process completed. Total clusters: {}, Indexing time: {:.2?}",
-            total_clusters, indexing_duration
+            "Pooled {} outliers farther than {} from their center",
+            outliers.len(),
+            threshold
         );
+        self.clusters.push(ClusterCenter {
+            idx: self.clusters.len(),
+            center_idx,
+            radius: distances.into_iter().fold(0.0, f32::max),
+            assignment: outliers.into(),
+            brute_force: true,
+            memory_used: 0,
+            num_tables: None,
+            build_time: Duration::ZERO,
+            search_stats: ClusterSearchStats::default(),
+            member_distances: None,
+            outlier: true,
+            over_memory_ceiling: false,
+            outlier_threshold: Some(threshold),
+        });
+    }
 
-        if let Some(metrics) = &mut self.metrics {
-            metrics.log_index_building_time(indexing_duration);
+    /// Clusters pooling the outliers, scanned first by every query with a center distance of 0,
+    /// unused by brute force clusters. Merged indexes keep one per part
+    fn outlier_probes(&self) -> Vec<(usize, f32)> {
+        self.clusters
+            .iter()
+            .enumerate()
+            .filter(|(_, cluster)| cluster.outlier)
+            .map(|(position, _)| (position, 0.0))
+            .collect()
+    }
+
+    /// Clusters the points `members` of the dataset (all of them if `None`) into `k` clusters
+    /// with greedy minimum-maximum clustering, replacing the centers with medoids if configured.
+    ///
+    /// Clusters are numbered from 0 and have no index yet. `proceed` is called with the number
+    /// of centers chosen so far after each of them, the clustering stops with
+    /// `ClusteredIndexError::Cancelled` as soon as it returns false.
+    fn greedy_clusters(
+        &self,
+        members: Option<&[usize]>,
+        k: usize,
+        proceed: &mut dyn FnMut(usize) -> bool,
+    ) -> Result<Vec<ClusterCenter>> {
+        let (centers, assignment, radius) = match members {
+            Some(members) => greedy_minimum_maximum_with(&self.data.subset(members), k, proceed),
+            None => greedy_minimum_maximum_with(&self.data, k, proceed),
         }
+        .ok_or(ClusteredIndexError::Cancelled)?;
+        // ids in the clustered points to ids in the dataset
+        let to_dataset = |i: usize| members.map_or(i, |members| members[i]);
 
-        Ok(())
+        let mut assignments: Vec<Vec<usize>> = vec![Vec::new(); centers.len()];
+
+        for (data_idx, &center_pos) in assignment.iter().enumerate() {
+            assignments[center_pos].push(to_dataset(data_idx));
+        }
+
+        let mut clusters: Vec<ClusterCenter> = centers
+            .iter()
+            .zip(radius.iter())
+            .zip(assignments)
+            .enumerate()
+            .map(|(idx, ((&center_idx, &radius), assignment_indexes))| {
+                let cluster = ClusterCenter {
+                    idx,
+                    center_idx: to_dataset(center_idx),
+                    radius,
+                    brute_force: !self.needs_index(assignment_indexes.len()),
+                    assignment: assignment_indexes.into(),
+                    memory_used: 0,
+                    num_tables: None,
+                    build_time: Duration::ZERO,
+                    search_stats: ClusterSearchStats::default(),
+                    member_distances: None,
+                    outlier: false,
+                    over_memory_ceiling: false,
+                    outlier_threshold: None,
+                };
+
+                trace!(
+                    "Cluster {}: center_idx={}, points={}, radius={}",
+                    idx,
+                    cluster.center_idx,
+                    cluster.assignment.len(),
+                    cluster.radius,
+                );
+
+                cluster
+            })
+            .collect();
+
+        if self.config.center_selection == CenterSelection::Medoid {
+            debug!("Replacing greedy centers with medoids...");
+            for cluster in clusters.iter_mut() {
+                if cluster.assignment.is_empty() {
+                    continue;
+                }
+                let (center_idx, radius) =
+                    min_max_medoid(&self.data, &cluster.assignment, cluster.center_idx);
+                trace!(
+                    "Cluster {}: radius {} -> {} with medoid {}",
+                    cluster.idx,
+                    cluster.radius,
+                    radius,
+                    center_idx
+                );
+                cluster.center_idx = center_idx;
+                cluster.radius = radius;
+            }
+        }
+
+        Ok(clusters)
     }
 
-    /// Searches for the k nearest neighbors of a query point.
+    /// Builds the index on a partition computed elsewhere, instead of clustering the dataset.
     ///
-    /// The search process:
-    /// 1. Sorts clusters by distance from query to their centers
-    /// 2. Processes clusters in order until termination condition is met
-    /// 3. For each cluster either:
-    ///    - Uses PUFFINN index to find candidates (large clusters)
-    ///    - Uses brute force search (small clusters)
+    /// The clusters are exactly those of `partition`, with the given centers or, if it has
+    /// none, the member of each cluster minimizing its radius. The PUFFINN indices are then
+    /// created as in [`build()`], so the same partition can be reused across configurations.
     ///
-    /// # Parameters
-    /// - `query`: Query point with same dimensionality as dataset points
+    /// # Errors
+    /// - `ClusteredIndexError::DataError` if the partition doesn't assign every point of the
+    ///   dataset, has an empty cluster, or a center outside its cluster
+    /// - `ClusteredIndexError::PuffinnCreationError` if PUFFINN index creation fails for any cluster
+    pub(crate) fn build_with_partition(&mut self, partition: &Partition) -> Result<()> {
+        info!(
+            "Starting build process with a partition of {} clusters",
+            partition.num_clusters()
+        );
+        let start = Instant::now();
+        let rss = self.start_rss_sampler();
+        self.set_partition(partition)?;
+        self.build_indexes(start, rss, None, None)
+    }
+
+    /// Replaces the clusters with the ones of `partition`, without indices.
+    fn set_partition(&mut self, partition: &Partition) -> Result<()> {
+        self.hierarchy = None;
+        self.clustering = ClusteringSummary {
+            selected_clusters: partition.num_clusters(),
+            merged_clusters: 0,
+        };
+        self.clusters = partition
+            .clusters(&self.data)?
+            .into_iter()
+            .enumerate()
+            .map(|(idx, (center_idx, radius, assignment))| ClusterCenter {
+                idx,
+                center_idx,
+                radius,
+                brute_force: !self.needs_index(assignment.len()),
+                assignment: assignment.into(),
+                memory_used: 0,
+                num_tables: None,
+                build_time: Duration::ZERO,
+                search_stats: ClusterSearchStats::default(),
+                member_distances: None,
+                outlier: false,
+                over_memory_ceiling: false,
+                outlier_threshold: None,
+            })
+            .collect();
+
+        Ok(())
+    }
+
+    /// Builds the index clustering only the points `sample`, then assigning every point of the
+    /// dataset to its nearest center, reading the dataset once in order of id.
     ///
-    /// # Returns
-    /// Vector of (distance, index) pairs for the k nearest neighbors found,
-    /// sorted by distance in ascending order
+    /// The dataset is read once more to create the PUFFINN indices of the clusters, as in
+    /// [`build_with_partition`](Self::build_with_partition), after pooling the outliers as in
+    /// [`build()`](Self::build).
     ///
     /// # Errors
-    /// - `ClusteredIndexError::IndexNotFound` if a required PUFFINN index is missing
-    /// - `ClusteredIndexError::PuffinnSearchError` if PUFFINN search fails
-    /// - `ClusteredIndexError::IndexOutOfBounds` if candidate mapping fails
-    pub(crate) fn search(&mut self, query: &[T::DataType]) -> Result<Vec<(f32, usize)>> {
-        if let Some(metrics) = &mut self.metrics {
-            metrics.new_query();
-            clear_distance_computations();
+    /// - `ClusteredIndexError::DataError` if `sample` is empty
+    /// - `ClusteredIndexError::Cancelled` if the cancellation token is cancelled during the clustering
+    /// - `ClusteredIndexError::PuffinnCreationError` if PUFFINN index creation fails for any cluster
+    pub(crate) fn build_sampled(&mut self, sample: &[usize]) -> Result<()> {
+        if sample.is_empty() {
+            return Err(ClusteredIndexError::DataError("empty sample".to_string()));
         }
+        info!("Clustering a sample of {} points", sample.len());
+        let rss = self.start_rss_sampler();
 
-        debug!(
-            "Starting search procedure with parameters k={} and delta={:.2}",
-            self.config.k, self.config.delta
-        );
-        let query_time = Instant::now();
+        let cancellation = self.cancellation.clone();
+        let mut proceed = |_| !cancellation.as_ref().is_some_and(|token| token.is_cancelled());
+        let k = self.select_num_clusters(Some(sample), &mut proceed)?;
+        let clustering = self.clustering;
+        let centers: Vec<usize> = self
+            .greedy_clusters(Some(sample), k, &mut proceed)?
+            .iter()
+            .map(|cluster| cluster.center_idx)
+            .collect();
+
+        // the centers are kept in memory, the other points are read in order
+        let center_points: Vec<Vec<T::DataType>> =
+            centers.iter().map(|&c| self.data.get_point(c).into_owned()).collect();
+        let cluster_of_center: HashMap<usize, usize> =
+            centers.iter().enumerate().map(|(cluster, &center)| (center, cluster)).collect();
+        let assignments = (0..self.data.num_points())
+            .map(|i| {
+                // a center is in its own cluster even if another center is at distance 0
+                if let Some(&cluster) = cluster_of_center.get(&i) {
+                    return cluster;
+                }
+                center_points
+                    .iter()
+                    .map(|center| self.data.distance_point(i, center))
+                    .enumerate()
+                    .min_by(|a, b| a.1.total_cmp(&b.1))
+                    .map_or(0, |(cluster, _)| cluster)
+            })
+            .collect();
+
+        let start = Instant::now();
+        self.set_partition(&Partition {
+            assignments,
+            centers: Some(centers),
+        })?;
+        self.clustering = clustering;
+        self.merge_tiny_clusters();
+        self.pool_outliers();
+        self.build_indexes(start, rss, None, None)
+    }
 
-        let delta_prime = self.config.delta;
+    /// Returns the cluster of every point and the center of every cluster.
+    ///
+    /// # Errors
+    /// Returns `ClusteredIndexError::ConfigError` if the index is not built
+    pub fn export_partition(&self) -> Result<Partition> {
+        if self.clusters.is_empty() {
+            return Err(ClusteredIndexError::ConfigError(
+                "the index must be built before exporting its partition".to_string(),
+            ));
+        }
 
-        let sorted_cluster = self.sort_cluster_indices_by_distance(query);
+        let mut assignments = vec![0; self.data.num_points()];
+        for cluster in &self.clusters {
+            for &point in &cluster.assignment {
+                assignments[point] = cluster.idx;
+            }
+        }
+        if let Some(duplicates) = &self.duplicates {
+            for point in 0..assignments.len() {
+                assignments[point] = assignments[duplicates.canonical(point)];
+            }
+        }
+        Ok(Partition {
+            assignments,
+            centers: Some(self.clusters.iter().map(|c| c.center_idx).collect()),
+        })
+    }
 
-        let mut priority_queue = TopKClosestHeap::new(self.config.k);
+    /// Returns the configuration and the clusters of the index, everything
+    /// [`rebuild_from_artifact`](Self::rebuild_from_artifact) needs but the dataset.
+    ///
+    /// # Errors
+    /// Returns `ClusteredIndexError::ConfigError` if the index is not built
+    pub fn export_artifact(&self) -> Result<PartitionArtifact> {
+        Ok(PartitionArtifact {
+            config: self.config.clone(),
+            partition: self.export_partition()?,
+            radii: self.clusters.iter().map(|c| c.radius).collect(),
+            outliers: (0..self.clusters.len()).filter(|&c| self.clusters[c].outlier).collect(),
+        })
+    }
 
-        let mut max_dist = f32::INFINITY;
+    /// Creates the PUFFINN indices of the clusters of `artifact`, exported from an index over
+    /// the same dataset, without clustering it.
+    ///
+    /// # Errors
+    /// - `ClusteredIndexError::DataError` if the partition doesn't fit the dataset, or if the
+    ///   radius of a cluster differs from the exported one, i.e. the dataset is another one
+    /// - `ClusteredIndexError::PuffinnCreationError` if PUFFINN index creation fails for any cluster
+    pub(crate) fn rebuild_from_artifact(&mut self, artifact: &PartitionArtifact) -> Result<()> {
+        info!(
+            "Rebuilding the indices of {} exported clusters",
+            artifact.partition.num_clusters()
+        );
+        let start = Instant::now();
+        let rss = self.start_rss_sampler();
+        self.set_partition(&artifact.partition)?;
 
-        for cluster_idx in sorted_cluster {
-            debug!("cluster index: {}", cluster_idx);
-            let mut distance_computations = 0;
-            let cluster_start = Instant::now();
+        if artifact.radii.len() != self.clusters.len() {
+            return Err(ClusteredIndexError::DataError(format!(
+                "the artifact has {} radii for {} clusters",
+                artifact.radii.len(),
+                self.clusters.len()
+            )));
+        }
+        for (cluster, &radius) in self.clusters.iter().zip(&artifact.radii) {
+            if (cluster.radius - radius).abs() > 1e-4 * radius.max(1.0) {
+                return Err(ClusteredIndexError::DataError(format!(
+                    "cluster {} has radius {} on this dataset, {} when exported",
+                    cluster.idx, cluster.radius, radius
+                )));
+            }
+        }
+        for &position in &artifact.outliers {
+            let cluster = self.clusters.get_mut(position).ok_or_else(|| {
+                ClusteredIndexError::DataError(format!("outlier cluster {} doesn't exist", position))
+            })?;
+            cluster.outlier = true;
+            cluster.brute_force = true;
+        }
 
-            let cluster = &self.clusters[cluster_idx];
+        self.build_indexes(start, rss, None, None)
+    }
 
-            // exit condition: if there are no more possible nearest neighbor stop
-            // to see if there are no more possible nearest neighbor we check the top of the priority queue,
-            // if the distance to the worst point in PQ is less than the distance of the nearest possible point in the cluster
-            // then we can stop
-            if let Some(top) = priority_queue.get_top() {
-                debug!("top: {:?}", top);
+    /// Finds the identical points of the dataset if [`Config::dedup`] is set, and leaves the
+    /// duplicates out of the clusters, only the first copy of a point is indexed. The clusters
+    /// already without duplicates are left as they are.
+    fn collapse_duplicates(&mut self) {
+        if !self.config.dedup {
+            self.duplicates = None;
+            return;
+        }
 
-                max_dist = top.1;
+        let duplicates = Duplicates::find(&self.data);
+        for position in 0..self.clusters.len() {
+            let cluster = &mut self.clusters[position];
+            let size = cluster.assignment.len();
+            cluster.center_idx = duplicates.canonical(cluster.center_idx);
+            cluster.assignment.to_mut().retain(|&p| !duplicates.is_alias(p));
+            if cluster.assignment.len() < size && !cluster.outlier {
+                self.clusters[position].brute_force = !self.needs_index(self.clusters[position].assignment.len());
+            }
+        }
+        info!("Collapsed {} duplicate points", duplicates.num_aliases());
+        self.duplicates = Some(duplicates);
+    }
 
-                // skips the first iteration so i dont have to worry about last_points being zero
-                // log the distance computation of the exit condition
-                distance_computations += 1;
+    /// Starts sampling the resident set size of the process if [`Config::track_rss`] is set
+    fn start_rss_sampler(&self) -> Option<RssSampler> {
+        self.config.track_rss.then(RssSampler::start).flatten()
+    }
 
-                let cluster_min_distance =
-                    self.data.distance_point(cluster.center_idx, query) - cluster.radius;
-                if cluster_min_distance > top.1 {
-                    if let Some(metrics) = &mut self.metrics {
-                        metrics.add_distance_computation_cluster(distance_computations);
-                        metrics.log_cluster_time(cluster_start.elapsed());
-                    }
+    /// Creates the PUFFINN indices of the clusters, the second step of a build started at `start`.
+    ///
+    /// The memory ceiling is applied unless a `report` says it already was. With a `checkpoint`,
+    /// the clusters it holds are read from it, and the others are written to it once created.
+    /// With an `rss` sampler, everything before the quantizers is the clustering phase.
+    fn build_indexes(
+        &mut self,
+        start: Instant,
+        rss: Option<RssSampler>,
+        checkpoint: Option<&Checkpoint>,
+        report: Option<BuildReport>,
+    ) -> Result<()> {
+        self.collapse_duplicates();
+        let total_clusters = self.clusters.len();
+        let mut report = match report {
+            Some(report) => report,
+            None => self.fit_memory_ceiling(),
+        };
+        let clustering_rss = rss.as_ref().map(RssSampler::take_peak);
 
-                    return Ok(priority_queue.to_list());
-                }
+        self.quantizer = self.config.scalar_quantization.then(|| {
+            info!("Training the scalar quantizer...");
+            ScalarQuantizer::train(&self.data)
+        });
+        self.product_quantizer = match self.config.pq {
+            Some(params) => {
+                info!("Training the product quantizer...");
+                let quantizer = ProductQuantizer::train(&self.data, params)
+                    .map_err(ClusteredIndexError::ConfigError)?;
+                Some(quantizer)
             }
+            None => None,
+        };
 
-            let mut points_added = 0;
-            if cluster.brute_force {
-                // do brute force
+        // 2) CREATE PUFFINN INDEXES
+        info!("Creating Puffinn indexes...");
+        self.puffinn_indices = Vec::with_capacity(self.clusters.len());
+        // brute force clusters cost nothing to build, so they are left out of the estimate
+        let indexed_points = self
+            .clusters
+            .iter()
+            .filter(|c| !c.brute_force)
+            .map(|c| c.assignment.len())
+            .sum();
+        let mut progress = BuildProgress::new(self.clusters.len(), indexed_points);
+        let mut cancelled = false;
+        B::set_num_threads(self.config.threads());
+        for (cluster_idx, cluster) in self.clusters.iter_mut().enumerate() {
+            // Progress logging
+            if cluster_idx % 10 == 0 {
+                info!(
+                    "Processing cluster {}/{} ({}%), ETA {}",
+                    cluster_idx + 1,
+                    total_clusters,
+                    (progress.fraction() * 100.0).round(),
+                    progress
+                        .eta()
+                        .map_or("unknown".to_string(), |eta| format!("{:.0?}", eta))
+                );
+            }
 
-                let candidates = self.brute_force_search(cluster, query)?;
+            if self.cancellation.as_ref().is_some_and(|token| token.is_cancelled()) {
+                info!("Build cancelled at cluster {}/{}", cluster_idx + 1, total_clusters);
+                cancelled = true;
+                break;
+            }
 
-                for (distance, p) in &candidates {
-                    if priority_queue.add(Element {
-                        distance: OrderedFloat(*distance),
-                        point_index: *p,
-                    }) {
-                        points_added += 1;
-                    }
-                }
+            if !cluster.assignment.is_empty() {
+                cluster.member_distances = Some(member_distances(&self.data, cluster));
+            }
 
-                distance_computations += candidates.len();
+            if cluster.assignment.is_empty() {
+                // e.g. a center whose copies were all collapsed, keeps its slot in the indices
+                debug!("Skipping empty cluster {}", cluster_idx);
+                cluster.brute_force = true;
+                self.puffinn_indices.push(None);
+            } else if cluster.brute_force {
+                info!(
+                    "Skipping cluster {} with {} points: doing brute force",
+                    cluster.idx,
+                    cluster.assignment.len()
+                );
+                self.puffinn_indices.push(None);
+            } else if let Some((bytes, memory_used, build_time)) = match checkpoint {
+                Some(checkpoint) => checkpoint.load_cluster(cluster.idx)?,
+                None => None,
+            } {
+                debug!("Cluster {}: read from the checkpoint", cluster_idx);
+                let puffinn_index = B::from_bytes(&bytes).map_err(ClusteredIndexError::SerializeError)?;
+                self.puffinn_indices.push(Some(puffinn_index));
+                cluster.memory_used = memory_used;
+                cluster.build_time = build_time;
+                progress.cluster_done(cluster.assignment.len());
             } else {
-                // do puffinn query algorithm
-
-                let candidates = match &self.puffinn_indices[cluster.idx] {
-                    Some(index) => index
-                        .search::<T>(query, self.config.k, max_dist, delta_prime)
-                        .map_err(ClusteredIndexError::PuffinnSearchError)?,
-                    None => {
-                        return Err(ClusteredIndexError::IndexNotFound());
-                    }
-                };
-
-                // map puffinn result to the original dataset
-                let mapped_candidates = match self.map_candidates(&candidates, cluster) {
-                    Ok(c) => c,
-                    Err(e) => {
-                        eprintln!("Error on cluster {}", cluster_idx);
-                        return Err(e);
-                    }
-                };
+                let num_tables = cluster.num_tables.unwrap_or(self.config.num_tables);
+                debug!(
+                    "Cluster {}: L {}, points: {}",
+                    cluster_idx,
+                    num_tables,
+                    cluster.assignment.len()
+                );
 
-                let mut min_dist_cluster = f32::INFINITY;
-                let mut max_dist_cluster = f32::NEG_INFINITY;
-                for p in mapped_candidates {
-                    let distance = self.data.distance_point(p, query);
-                    if distance < min_dist_cluster {
-                        min_dist_cluster = distance;
+                // Create Puffinn index
+                let cluster_start = Instant::now();
+                match B::build(&self.data, &cluster.assignment, num_tables) {
+                    Ok((puffinn_index, memory_used)) => {
+                        if let Some(checkpoint) = checkpoint {
+                            let bytes = puffinn_index
+                                .to_bytes()
+                                .map_err(ClusteredIndexError::SerializeError)?;
+                            checkpoint.save_cluster(cluster.idx, &bytes, memory_used, cluster_start.elapsed())?;
+                        }
+                        self.puffinn_indices.push(Some(puffinn_index));
+                        cluster.memory_used = memory_used;
+                        cluster.build_time = cluster_start.elapsed();
+                        progress.cluster_done(cluster.assignment.len());
                     }
-                    if distance > max_dist_cluster {
-                        max_dist_cluster = distance;
-                    }
-                    if priority_queue.add(Element {
-                        distance: OrderedFloat(distance),
-                        point_index: p,
-                    }) {
-                        points_added += 1;
+                    Err(e) => {
+                        error!(
+                            "Failed to create Puffinn index for cluster {}: {:?}",
+                            cluster_idx, e
+                        );
+                        return Err(ClusteredIndexError::PuffinnCreationError(e));
                     }
                 }
-                debug!(
-                    "points_added = {}, min_dist = {}, max_dist = {}",
-                    points_added, min_dist_cluster, max_dist_cluster
-                );
+            }
 
-                distance_computations += get_distance_computations() as usize;
+            if let Some(observer) = &mut self.build_observer {
+                observer.on_progress(BuildPhase::Indexing, cluster_idx, progress.fraction(), progress.eta());
             }
+        }
 
-            debug!("Added {} points in cluster {})", points_added, cluster.idx);
+        if cancelled {
+            self.clear_clusters();
+            return Err(ClusteredIndexError::Cancelled);
+        }
 
-            if let Some(metrics) = &mut self.metrics {
-                metrics.log_n_candidates(points_added);
-                metrics.log_cluster_time(cluster_start.elapsed());
-                metrics.add_distance_computation_cluster(distance_computations);
-            }
+        let indexing_duration = start.elapsed();
+
+        info!(
+            "Build process completed. Total clusters: {}, Indexing time: {:.2?}",
+            total_clusters, indexing_duration
+        );
+
+        if let Some(metrics) = &mut self.metrics {
+            metrics.log_index_building_time(indexing_duration);
+            metrics.log_clustering(self.clustering.selected_clusters, self.clustering.merged_clusters);
         }
 
+        self.index_centers();
+
+        report.memory_used = self.clusters.iter().map(|c| c.memory_used).sum();
+        report.peak_rss = rss.zip(clustering_rss).map(|(rss, clustering)| PeakRss {
+            start: rss.start_rss(),
+            clustering,
+            indexing: rss.take_peak(),
+        });
+        if let Some(peak_rss) = &report.peak_rss {
+            info!(
+                "Peak resident set size: {} bytes clustering, {} bytes indexing",
+                peak_rss.clustering, peak_rss.indexing
+            );
+        }
         if let Some(metrics) = &mut self.metrics {
-            metrics.log_query_time(query_time.elapsed());
+            metrics.log_peak_rss(report.peak_rss);
         }
+        self.callbacks.build(&BuildMetrics {
+            dataset_len: self.data.num_points(),
+            num_clusters: self.clusters.len(),
+            greedy_num_clusters: self.clusters.iter().filter(|c| c.brute_force).count(),
+            memory_used_bytes: report.memory_used,
+            build_time: indexing_duration,
+            selected_clusters: self.clustering.selected_clusters,
+            merged_clusters: self.clustering.merged_clusters,
+            peak_rss: report.peak_rss,
+        });
+        self.build_report = Some(report);
 
-        Ok(priority_queue.to_list())
+        Ok(())
     }
 
-    /// Saves metrics from a search run to a SQLite database.
-    ///
-    /// # Parameters
-    /// - `db_path`: Path to SQLite database file
-    /// - `granularity`: Level of detail for metrics (Run/Query/Cluster)
-    /// - `ground_truth_distances`: True k-NN distances for computing recall
-    /// - `run_distances`: Distances returned by the search algorithm
-    /// - `total_search_time`: Total time spent on all queries
-    ///
-    /// # Errors
-    /// - `ClusteredIndexError::MetricsError` if metrics are not enabled or database doesn't exist
-    /// - `ClusteredIndexError::ResultDBError` for database connection/operation errors
-    pub(crate) fn save_metrics(
-        &mut self,
-        db_path: String,
-        granularity: MetricsGranularity,
-        ground_truth_distances: &Array<f32, Ix2>,
-        run_distances: &[Vec<f32>],
-        total_search_time: &Duration,
-    ) -> Result<()> {
-        if !db_exists(&db_path) {
-            return Err(ClusteredIndexError::MetricsError(format!(
-                "No existing database in path {}",
-                db_path
-            )));
+    /// Computes the distances between the centers of the clusters and the cells of the
+    /// hierarchy, if any, once the clusters changed.
+    fn index_centers(&mut self) {
+        self.center_distances = CenterDistances::compute(&self.data, &self.clusters);
+        if let Some(hierarchy) = self.hierarchy.as_ref().filter(|h| !h.matches(&self.clusters)) {
+            self.hierarchy = Some(Hierarchy::compute(&self.data, &self.clusters, &hierarchy.coarse_centers()));
         }
+    }
 
-        // Connect to the database
-        let conn_res = Connection::open(db_path)
-            .map_err(|e| ClusteredIndexError::ResultDBError(e.to_string()));
+    /// Leaves the index unbuilt, keeping the capacity of the clusters for the next build.
+    fn clear_clusters(&mut self) {
+        self.clusters.clear();
+        self.hierarchy = None;
+        self.puffinn_indices.clear();
+    }
 
-        match conn_res {
-            Ok(mut conn) => {
-                if let Some(metrics) = &mut self.metrics {
-                    return metrics.save_metrics(
-                        &mut conn,
-                        granularity,
-                        &self.clusters,
-                        ground_truth_distances,
-                        run_distances,
-                        total_search_time,
-                    );
-                } else {
-                    return Err(ClusteredIndexError::MetricsError(
-                        "run metrics are not enabled".to_string(),
-                    ));
-                }
+    /// Keeps the estimated memory of the cluster indices under [`Config::max_memory_bytes`].
+    ///
+    /// Lowers the number of tables of every indexed cluster and, if that is not enough,
+    /// marks the largest clusters as brute force (see [`fit_memory`]).
+    fn fit_memory_ceiling(&mut self) -> BuildReport {
+        let indexed: Vec<usize> = (0..self.clusters.len())
+            .filter(|&position| !self.clusters[position].brute_force)
+            .collect();
+        let sizes: Vec<usize> = indexed
+            .iter()
+            .map(|&position| self.clusters[position].assignment.len())
+            .collect();
+        let max_memory = self.config.max_memory_bytes.unwrap_or(usize::MAX);
+        let fit = fit_memory(
+            &sizes,
+            self.data.dimensions(),
+            self.config.num_tables,
+            max_memory,
+        );
+
+        let mut degradations = Vec::new();
+        if fit.num_tables < self.config.num_tables {
+            degradations.push(Degradation::ReducedTables {
+                from: self.config.num_tables,
+                to: fit.num_tables,
+            });
+            for &position in &indexed {
+                self.clusters[position].num_tables = Some(fit.num_tables);
             }
-            Err(e) => return Err(e),
+        }
+        for &position in &fit.brute_force {
+            let cluster = &mut self.clusters[indexed[position]];
+            cluster.brute_force = true;
+            cluster.over_memory_ceiling = true;
+            cluster.num_tables = None;
+            degradations.push(Degradation::BruteForce {
+                cluster: cluster.idx,
+                num_points: cluster.assignment.len(),
+            });
+        }
+
+        for degradation in &degradations {
+            warn!(
+                "Index would exceed {} bytes: {:?}",
+                max_memory, degradation
+            );
+        }
+
+        BuildReport {
+            max_memory_bytes: self.config.max_memory_bytes,
+            estimated_memory: fit.estimated_memory,
+            memory_used: 0,
+            degradations,
+            peak_rss: None,
         }
     }
 
-    /// Serializes the index to an HDF5 file.
-    ///
-    /// Saves:
-    /// - Configuration parameters
-    /// - Cluster information (centers, assignments, radii)
-    /// - PUFFINN indices for each cluster
+    /// Returns the memory report of the last build, `None` if the index was not built in this process.
+    pub fn build_report(&self) -> Option<&BuildReport> {
+        self.build_report.as_ref()
+    }
+
+    /// Returns the ids of the clusters whose serialized index was missing or corrupt when the
+    /// index was loaded, and which were rebuilt from the dataset instead.
+    pub fn rebuilt_clusters(&self) -> &[usize] {
+        &self.rebuilt_clusters
+    }
+
+    /// Predicts the memory and time of building an index of `data` with `config`, without building it.
     ///
-    /// # Parameters
-    /// - `directory`: Directory where the index file will be saved
+    /// A random sample of at most [`ESTIMATE_SAMPLE_POINTS`] points is clustered into as many
+    /// clusters as the whole dataset, every sample point standing for `n / sample` points. The
+    /// index of a pilot cluster, the sample points nearest to the center of the largest cluster,
+    /// is then built to measure the memory and time per point, which are scaled to the size of
+    /// every cluster getting an index. For a given number of clusters greedy clustering is linear
+    /// in the number of points, so its time on the sample is scaled by `n / sample` as well.
     ///
-    /// # File naming
-    /// The file is named: `index_{dataset_name}_k{clusters_factor}_L{num_tables}.h5`
+    /// Merging tiny clusters, pooling outliers, collapsing duplicates and the memory ceiling are
+    /// left out, and clustering with [`Config::coarse_clusters`] is faster than predicted.
     ///
     /// # Errors
-    /// Returns `ClusteredIndexError::SerializeError` if:
-    /// - Directory doesn't exist
-    /// - File creation fails
-    /// - Serialization of any component fails
-    pub(crate) fn serialize(&self, directory: &str) -> Result<()> {
-        if fs::metadata(directory).is_err() {
-            return Err(ClusteredIndexError::SerializeError(format!(
-                "directory {} doesn't exist",
-                directory
-            )));
+    /// - `ClusteredIndexError::DataError` or `ClusteredIndexError::ConfigError` if `config`
+    ///   cannot be used on `data`, as in [`new()`](Self::new)
+    /// - `ClusteredIndexError::PuffinnCreationError` if the index of the pilot cluster cannot be built
+    pub(crate) fn estimate_build(data: &T, config: &Config) -> Result<BuildEstimate> {
+        let k = Self::checked_num_clusters(config, data)?;
+        let num_points = data.num_points();
+        let mut rng = StdRng::seed_from_u64(ESTIMATE_SEED);
+        let mut sample =
+            rand::seq::index::sample(&mut rng, num_points, num_points.min(ESTIMATE_SAMPLE_POINTS)).into_vec();
+        sample.sort_unstable();
+        let scale = num_points as f64 / sample.len() as f64;
+        info!("Estimating the build by clustering a sample of {} points", sample.len());
+
+        let start = Instant::now();
+        let points = data.subset(&sample);
+        let k = match config.cluster_count {
+            ClusterCount::RadiusElbow { max_clusters } => {
+                radius_elbow(&greedy_radii(&points, max_clusters, |_| true).ok_or(ClusteredIndexError::Cancelled)?)
+            }
+            ClusterCount::Factor | ClusterCount::AverageSize { .. } => k.min(sample.len()),
+        };
+        let (centers, assignment, _) = greedy_minimum_maximum(&points, k);
+        let clustering_time = start.elapsed().mul_f64(scale);
+
+        let mut sizes = vec![0; centers.len()];
+        for &cluster in &assignment {
+            sizes[cluster] += 1;
         }
+        let sizes: Vec<usize> = sizes.into_iter().map(|size| (size as f64 * scale).round() as usize).collect();
 
-        let file_path = format!(
-            "{}/index_{}_k{:.2}_L{}.h5",
-            directory,
-            self.config.dataset_name,
-            self.config.num_clusters_factor,
-            self.config.num_tables
+        let mut estimate = BuildEstimate {
+            sample_points: sample.len(),
+            clustering_time,
+            ..Default::default()
+        };
+        if sizes.iter().any(|&size| Self::gets_index(config, data, size)) {
+            let largest = (0..sizes.len()).max_by_key(|&c| sizes[c]).unwrap_or(0);
+            let center = data.get_point(sample[centers[largest]]).into_owned();
+            let mut distances = vec![0.0; sample.len()];
+            data.distances_points(&sample, &center, &mut distances);
+            let mut nearest: Vec<usize> = (0..sample.len()).collect();
+            nearest.sort_by(|&a, &b| distances[a].total_cmp(&distances[b]));
+            let mut pilot: Vec<usize> = nearest
+                .into_iter()
+                .take(ESTIMATE_PILOT_POINTS)
+                .map(|i| sample[i])
+                .collect();
+            pilot.sort_unstable();
+
+            B::set_num_threads(config.threads());
+            let start = Instant::now();
+            let (_, memory_used) =
+                B::build(data, &pilot, config.num_tables).map_err(ClusteredIndexError::PuffinnCreationError)?;
+            let build_time = start.elapsed();
+            debug!("Pilot index of {} points: {} bytes in {:.2?}", pilot.len(), memory_used, build_time);
+            estimate.pilot_points = pilot.len();
+            estimate.memory_per_point = memory_used as f64 / pilot.len() as f64;
+            estimate.time_per_point = build_time.div_f64(pilot.len() as f64);
+        }
+
+        estimate.clusters = sizes
+            .into_iter()
+            .map(|num_points| {
+                let brute_force = !Self::gets_index(config, data, num_points);
+                let (memory_bytes, build_time) = if brute_force {
+                    (0, Duration::ZERO)
+                } else {
+                    (
+                        (estimate.memory_per_point * num_points as f64).round() as usize,
+                        estimate.time_per_point.mul_f64(num_points as f64),
+                    )
+                };
+                ClusterEstimate {
+                    num_points,
+                    brute_force,
+                    memory_bytes,
+                    build_time,
+                }
+            })
+            .collect();
+        estimate.clusters.sort_by_key(|c| std::cmp::Reverse(c.num_points));
+        estimate.memory_bytes = estimate.clusters.iter().map(|c| c.memory_bytes).sum();
+        estimate.build_time = estimate.clusters.iter().map(|c| c.build_time).sum::<Duration>() + clustering_time;
+        info!(
+            "Estimated build: {} clusters, {} bytes, {:.0?}",
+            estimate.clusters.len(),
+            estimate.memory_bytes,
+            estimate.build_time
         );
-        let file = File::create(file_path.clone())
-            .map_err(|e| ClusteredIndexError::SerializeError(e.to_string()))?;
 
-        // write Config
-        let config_json = serde_json::to_string(&self.config).unwrap();
-        let config_ascii = VarLenAscii::from_ascii(&config_json).unwrap();
-        file.new_dataset::<VarLenAscii>()
-            .create("config")
-            .unwrap()
-            .write_scalar(&config_ascii)
-            .map_err(|e| ClusteredIndexError::SerializeError(e.to_string()))?;
+        Ok(estimate)
+    }
 
-        // write all ClusterCenter
-        let clusters_json = serde_json::to_string(&self.clusters).unwrap();
-        let clusters_ascii = VarLenAscii::from_ascii(&clusters_json).unwrap();
-        file.new_dataset::<VarLenUnicode>()
-            .create("clusters")
-            .unwrap()
-            .write_scalar(&clusters_ascii)
-            .map_err(|e| ClusteredIndexError::SerializeError(e.to_string()))?;
+    /// Whether a cluster with `num_points` points gets an index, smaller clusters are scanned exhaustively.
+    fn needs_index(&self, num_points: usize) -> bool {
+        Self::gets_index(&self.config, &self.data, num_points)
+    }
 
-        // write all puffinn indexes
-        for (index_id, puffinn_index) in self.puffinn_indices.iter().enumerate() {
-            if let Some(index) = puffinn_index {
-                index
-                    .save_to_file(&file_path, index_id)
-                    .map_err(ClusteredIndexError::SerializeError)?;
-            }
-        }
+    /// [`needs_index`](Self::needs_index) of an index of `data` with `config`
+    fn gets_index(config: &Config, data: &T, num_points: usize) -> bool {
+        config.index_mode != IndexMode::Flat
+            && data.lsh_supported()
+            && num_points >= 100
+            && num_points >= config.k
+    }
 
-        Ok(())
+    /// Inserts a single point into a built index, see [`insert_batch()`].
+    ///
+    /// # Returns
+    /// Index of the point in the dataset
+    pub(crate) fn insert(&mut self, point: &[T::DataType]) -> Result<usize>
+    where
+        T: Insertable,
+    {
+        let points = ArrayView2::from_shape((1, point.len()), point)
+            .map_err(|e| ClusteredIndexError::DataError(e.to_string()))?;
+        Ok(self.insert_batch(&points)?[0])
     }
 
-    /// Returns the total number of distance computations for the current query.
+    /// Inserts every row of `points` into a built index.
+    ///
+    /// Each point is appended to the dataset and assigned to the cluster with the closest
+    /// center, whose radius grows if needed. With [`Config::outlier_quantile`], a point farther
+    /// from that center than the outliers pooled by the build joins their pool instead. Cluster
+    /// indices are rebuilt at the end of the batch, once per affected cluster, rather than once
+    /// per point. A brute force cluster growing past the brute force threshold gets an index,
+    /// unless the [`Config::max_memory_bytes`] ceiling left it without one at build time.
+    ///
+    /// Centers are not recomputed, so after many inserts the clusters can become much wider
+    /// than after a fresh [`build()`], which makes pruning less effective.
+    ///
+    /// # Parameters
+    /// - `points`: Matrix with one point per row, in the original space if the configuration has a projection
     ///
     /// # Returns
-    /// Total number of distance computations if metrics are enabled
+    /// Indices of the inserted points in the dataset, in the same order as the rows
     ///
     /// # Errors
-    /// Returns `ClusteredIndexError::MetricsError` if metrics are not enabled
-    pub fn get_distance_computations(&self) -> Result<usize> {
-        if let Some(metrics) = &self.metrics {
-            return Ok(metrics.current_query().unwrap().distance_computations);
-        }
-
-        Err(ClusteredIndexError::MetricsError(
-            "run metrics are not enabled".to_string(),
-        ))
+    /// - `ClusteredIndexError::ConfigError` if the index is not built
+    /// - `ClusteredIndexError::DataError` if a point has the wrong dimensionality, or is invalid
+    ///   with [`Config::validate_data`]
+    /// - `ClusteredIndexError::PuffinnCreationError` if a cluster index cannot be rebuilt. The
+    ///   clusters are computed and their indices rebuilt aside, so no cluster is changed, but the
+    ///   points stay appended to the dataset, in no cluster, as the dataset can't remove them.
+    ///   The write-ahead log records that the batch failed
+    pub(crate) fn insert_batch<S>(&mut self, points: &ArrayBase<S, Ix2>) -> Result<Vec<usize>>
+    where
+        S: Data<Elem = T::DataType>,
+        T: Insertable,
+    {
+        let ids = self.append_points(points)?;
+        self.index_points(&ids)?;
+        Ok(ids)
     }
 
-    /// Sorts clusters by their distance from the query point.
+    /// Appends every row of `points` to the dataset, and to the write-ahead log if one is open,
+    /// the first step of [`insert_batch()`](Self::insert_batch). The points are in no cluster
+    /// yet, so searches don't return them until [`index_points()`](Self::index_points).
     ///
-    /// # Implementation
-    /// 1. Computes distance from query to each cluster center
-    /// 2. Sorts clusters by these distances in ascending order
-    /// 3. Returns indices of clusters in sorted order
+    /// # Returns
+    /// Indices of the appended points in the dataset, in the same order as the rows
     ///
-    /// This ordering is crucial for early termination and efficiency:
-    /// - Closer clusters are more likely to contain nearest neighbors
-    /// - Allows terminating search when minimum distance to next cluster exceeds current kth distance
+    /// # Errors
+    /// - `ClusteredIndexError::ConfigError` if the index is not built
+    /// - `ClusteredIndexError::DataError` if a point has the wrong dimensionality, or is invalid
+    ///   with [`Config::validate_data`]
+    pub(crate) fn append_points<S>(&mut self, points: &ArrayBase<S, Ix2>) -> Result<Vec<usize>>
+    where
+        S: Data<Elem = T::DataType>,
+        T: Insertable,
+    {
+        if self.clusters.is_empty() {
+            return Err(ClusteredIndexError::ConfigError(
+                "the index must be built before inserting points".to_string(),
+            ));
+        }
+
+        // before inserting any of them
+        let dimensions = self
+            .config
+            .projection
+            .as_ref()
+            .map_or(self.data.dimensions(), |projection| projection.input_dim());
+        if points.ncols() != dimensions {
+            return Err(ClusteredIndexError::DataError(format!(
+                "point has {} dimensions, dataset has {}",
+                points.ncols(),
+                dimensions
+            )));
+        }
+        if self.config.validate_data {
+            for (i, row) in points.rows().into_iter().enumerate() {
+                checked_norm(&row.to_vec(), self.data.scale_invariant())
+                    .map_err(|e| ClusteredIndexError::DataError(format!("point {} {}", i, e)))?;
+            }
+        }
+
+        let first_id = self.data.num_points();
+        if let Some(wal) = &self.wal {
+            wal.append(first_id, points)?;
+        }
+        if let Err(e) = self.push_points(points) {
+            // the points pushed before the error stay in the dataset
+            self.abort_logged_batch(first_id, self.data.num_points() - first_id)?;
+            return Err(e);
+        }
+
+        Ok((first_id..self.data.num_points()).collect())
+    }
+
+    /// Pushes every row of `points` to the dataset and to the quantizers, see
+    /// [`append_points()`](Self::append_points)
+    fn push_points<S>(&mut self, points: &ArrayBase<S, Ix2>) -> Result<()>
+    where
+        S: Data<Elem = T::DataType>,
+        T: Insertable,
+    {
+        for row in points.rows() {
+            let mut point: Vec<T::DataType> = row.iter().copied().collect();
+            if let Some(projection) = &self.config.projection {
+                point = projection.transform_point(&point)?;
+            }
+            if point.len() != self.data.dimensions() {
+                return Err(ClusteredIndexError::DataError(format!(
+                    "point has {} dimensions, dataset has {}",
+                    point.len(),
+                    self.data.dimensions()
+                )));
+            }
+
+            let id = self
+                .data
+                .insert(&point)
+                .map_err(ClusteredIndexError::DataError)?;
+            let indexed = self.data.indexed_point(id).into_owned();
+            if let Some(quantizer) = &mut self.quantizer {
+                quantizer.push(&indexed);
+            }
+            if let Some(quantizer) = &mut self.product_quantizer {
+                quantizer.push(&indexed);
+            }
+            if let Some(duplicates) = &mut self.duplicates {
+                duplicates.add(&self.data, id);
+            }
+        }
+        Ok(())
+    }
+
+    /// Records in the write-ahead log, if one is open, that the batch logged from `first_id`
+    /// failed with its first `rows` points in the dataset, so that replaying the log leaves
+    /// them in no cluster as well
+    pub(crate) fn abort_logged_batch(&self, first_id: usize, rows: usize) -> Result<()> {
+        match &self.wal {
+            Some(wal) => wal.abort(first_id, rows),
+            None => Ok(()),
+        }
+    }
+
+    /// Assigns the points `ids` of the dataset, in no cluster yet, to their closest cluster,
+    /// and rebuilds the index of every cluster that got one.
     ///
-    /// # Parameters
-    /// - `query`: Query point to compute distances against
+    /// # Errors
+    /// `ClusteredIndexError::PuffinnCreationError` if a cluster index cannot be rebuilt, the
+    /// clusters are then left as they were
+    pub(crate) fn index_points(&mut self, ids: &[usize]) -> Result<()> {
+        let prepared = match self.prepare_index_points(ids) {
+            Ok(prepared) => prepared,
+            Err(e) => {
+                if let Some(&first_id) = ids.first() {
+                    self.abort_logged_batch(first_id, ids.len())?;
+                }
+                return Err(e);
+            }
+        };
+        self.apply_index_points(prepared);
+        self.refresh_query_cache();
+        Ok(())
+    }
+
+    /// Computes the clusters of the points `ids` of the dataset, in no cluster yet, and
+    /// rebuilds the index of every cluster that gets one, without modifying the index, so that
+    /// searches can go on meanwhile. The result is installed by
+    /// [`apply_index_points()`](Self::apply_index_points).
     ///
-    /// # Returns
-    /// Vector of cluster indices sorted by distance from query to cluster centers
-    fn sort_cluster_indices_by_distance(&mut self, query: &[T::DataType]) -> Vec<usize> {
-        let mut cluster_distances: Vec<(usize, f32)> = self
+    /// # Errors
+    /// `ClusteredIndexError::PuffinnCreationError` if a cluster index cannot be rebuilt
+    pub(crate) fn prepare_index_points(&self, ids: &[usize]) -> Result<PreparedPoints<B>> {
+        let mut affected: Vec<Option<ClusterCenter>> = vec![None; self.clusters.len()];
+        let pool = self
             .clusters
             .iter()
-            .map(|cluster| {
-                let dist = self.data.distance_point(cluster.center_idx, query);
-                (cluster.idx, dist)
-            })
-            .collect();
+            .enumerate()
+            .find_map(|(position, cluster)| cluster.outlier_threshold.map(|threshold| (position, threshold)));
 
-        // TODO: we can remove some distance computations from the main loop
-        // since we compute each distance from the center to the query we dont actually
-        // need to redo it in the exit condition
-        if let Some(metrics) = &mut self.metrics {
-            metrics.add_distance_computation_global(cluster_distances.len());
+        for &id in ids {
+            // a copy of an indexed point is only reported with it
+            if self.duplicates.as_ref().is_some_and(|duplicates| duplicates.is_alias(id)) {
+                continue;
+            }
+
+            let (mut position, mut distance) = self
+                .clusters
+                .iter()
+                .enumerate()
+                .filter(|(_, cluster)| !cluster.outlier)
+                .map(|(position, cluster)| (position, self.data.distance(cluster.center_idx, id)))
+                .min_by(|a, b| a.1.total_cmp(&b.1))
+                .expect("the index has at least one cluster");
+            // as far from its center as the outliers of the build, the point is pooled with them
+            if let Some((pool, threshold)) = pool.filter(|&(_, threshold)| distance > threshold) {
+                debug!("Point {} is {} from its center, past {}, pooled with the outliers", id, distance, threshold);
+                position = pool;
+                distance = self.data.distance(self.clusters[pool].center_idx, id);
+            }
+
+            let cluster = affected[position].get_or_insert_with(|| ClusterCenter {
+                search_stats: ClusterSearchStats::default(),
+                ..self.clusters[position].clone()
+            });
+            cluster.assignment.to_mut().push(id);
+            cluster.radius = cluster.radius.max(distance);
         }
 
-        cluster_distances.sort_by(|&(_, dist_a), &(_, dist_b)| {
-            dist_a
-                .partial_cmp(&dist_b)
-                .unwrap_or(std::cmp::Ordering::Equal)
-        });
+        let mut clusters = Vec::new();
+        B::set_num_threads(self.config.threads());
+        for (position, cluster) in affected.into_iter().enumerate() {
+            let Some(mut cluster) = cluster else {
+                continue;
+            };
+            cluster.member_distances = Some(member_distances(&self.data, &cluster));
+            if cluster.brute_force {
+                // an index the memory ceiling left out stays out until the next build
+                if cluster.over_memory_ceiling || cluster.outlier || !self.needs_index(cluster.assignment.len()) {
+                    clusters.push((position, cluster, None));
+                    continue;
+                }
+                cluster.brute_force = false;
+            }
+
+            let num_tables = cluster.num_tables.unwrap_or(self.config.num_tables);
+            let cluster_start = Instant::now();
+            let (index, memory_used) = B::build(&self.data, &cluster.assignment, num_tables)
+                    .map_err(ClusteredIndexError::PuffinnCreationError)?;
+            cluster.memory_used = memory_used;
+            cluster.build_time = cluster_start.elapsed();
+            clusters.push((position, cluster, Some(index)));
+        }
+
+        Ok(PreparedPoints {
+            points: ids.len(),
+            clusters,
+        })
+    }
+
+    /// Installs the clusters computed by [`prepare_index_points()`](Self::prepare_index_points),
+    /// at once. The clusters must not have changed since they were prepared.
+    pub(crate) fn apply_index_points(&mut self, prepared: PreparedPoints<B>) {
+        let mut rebuilt = 0;
+        for (position, mut cluster, index) in prepared.clusters {
+            cluster.search_stats = std::mem::take(&mut self.clusters[position].search_stats);
+            if let Some(index) = index {
+                self.puffinn_indices[cluster.idx] = Some(index);
+                rebuilt += 1;
+            }
+            self.clusters[position] = cluster;
+        }
 
-        cluster_distances.into_iter().map(|(i, _)| i).collect()
+        info!(
+            "Inserted {} points, rebuilt {} cluster indices",
+            prepared.points,
+            rebuilt
+        );
     }
 
-    /// Maps local indices from PUFFINN search results to global dataset indices.
-    ///
-    /// PUFFINN returns indices local to the subset of points in a cluster.
-    /// This function maps them back to indices in the original dataset using
-    /// the cluster's assignment vector.
+    /// Switches the query cache, if enabled, to new entries after the content of the index
+    /// changed, so that cached results of the old one are not returned. The content is not
+    /// fingerprinted again, see [`QueryCache::invalidate`].
+    pub(crate) fn refresh_query_cache(&mut self) {
+        if let Some(cache) = self.query_cache() {
+            cache.invalidate();
+        }
+    }
+
+    /// Number of points of the dataset covered by the clusters, one more than the largest
+    /// id they hold. The points after it were inserted in the dataset but not in the index.
+    fn indexed_points(&self) -> usize {
+        self.clusters
+            .iter()
+            .flat_map(|cluster| cluster.assignment.iter().copied().chain([cluster.center_idx]))
+            .max()
+            .map_or(0, |id| id + 1)
+    }
+
+    /// Opens the write-ahead log at `path`, replaying the insertions it holds, then appends
+    /// every later insertion to it until it is emptied by [`truncate_wal()`](Self::truncate_wal).
     ///
-    /// # Parameters
-    /// - `candidates`: Vector of local indices from PUFFINN search
-    /// - `cluster`: Cluster containing the mapping information
+    /// A batch of [`insert_batch()`](Self::insert_batch) is synced to the log before it is
+    /// applied, and a batch that fails is marked so that its points stay in no cluster on
+    /// replay, as they did. The index file only holds the ids of the inserted points, their components are
+    /// in the dataset, so the log keeps them until both the index and the dataset are saved.
+    /// After a crash, the last saved dataset is extended with the points of the log it lacks
+    /// (see [`extend_from_wal()`](super::wal::extend_from_wal)), the last index file is loaded
+    /// over it, and reopening the log adds to the clusters the points of the dataset saved after
+    /// the index file and inserts the others. The batches already in both are skipped.
     ///
     /// # Returns
-    /// Vector of global dataset indices corresponding to the local indices
+    /// The number of points replayed from the log
     ///
     /// # Errors
-    /// Returns `ClusteredIndexError::IndexOutOfBounds` if any local index
-    /// exceeds the cluster's size
-    fn map_candidates(&self, candidates: &[u32], cluster: &ClusterCenter) -> Result<Vec<usize>> {
-        candidates
-            .iter()
-            .map(|&local_idx| {
-                let local_idx = local_idx as usize;
-                if local_idx < cluster.assignment.len() {
-                    Ok(cluster.assignment[local_idx])
-                } else {
-                    Err(ClusteredIndexError::IndexOutOfBounds(
-                        local_idx,
-                        cluster.assignment.len(),
-                    ))
+    /// - `ClusteredIndexError::ConfigError` if the index is not built
+    /// - `ClusteredIndexError::SerializeError` if the log cannot be read or created
+    /// - `ClusteredIndexError::DataError` if the log doesn't follow the dataset, e.g. it was
+    ///   written by another index, or a batch cannot be inserted
+    pub(crate) fn open_wal<P: AsRef<Path>>(&mut self, path: P) -> Result<usize>
+    where
+        T: Insertable,
+    {
+        if self.clusters.is_empty() {
+            return Err(ClusteredIndexError::ConfigError(
+                "the index must be built before opening its write-ahead log".to_string(),
+            ));
+        }
+
+        let (wal, records) = WriteAheadLog::open(path.as_ref())?;
+        let mut indexed = self.indexed_points();
+        let mut replayed = 0;
+        for record in records {
+            let num_points = self.data.num_points();
+            let end = record.first_id + record.rows;
+            if record.first_id > num_points {
+                return Err(ClusteredIndexError::DataError(format!(
+                    "the write-ahead log {} continues from point {} but the dataset has {} points",
+                    wal.path().display(),
+                    record.first_id,
+                    num_points
+                )));
+            }
+
+            // a failed batch left its points in the dataset, in no cluster
+            if record.aborted {
+                if end > num_points {
+                    let points = record.points::<T::DataType>();
+                    replayed += self.append_points(&points.slice(s![num_points - record.first_id.., ..]))?.len();
                 }
-            })
-            .collect::<Result<Vec<usize>>>()
+                indexed = indexed.max(end);
+                continue;
+            }
+
+            // saved with the dataset but not with the index
+            let saved: Vec<usize> = (record.first_id.max(indexed)..end.min(num_points)).collect();
+            if !saved.is_empty() {
+                self.index_points(&saved)?;
+                replayed += saved.len();
+            }
+            if end > num_points {
+                let points = record.points::<T::DataType>();
+                replayed += self.insert_batch(&points.slice(s![num_points - record.first_id.., ..]))?.len();
+            }
+            indexed = indexed.max(end);
+        }
+        info!("Replayed {} points from the write-ahead log {}", replayed, wal.path().display());
+
+        self.wal = Some(wal);
+        Ok(replayed)
     }
 
-    /// Performs brute force search within a cluster.
+    /// Empties the write-ahead log opened with [`open_wal()`](Self::open_wal), once the index
+    /// and the dataset holding the points inserted since are both saved.
     ///
-    /// Used for small clusters where building an index would be inefficient.
-    /// Computes distances to all points in the cluster and returns the k nearest.
+    /// # Errors
+    /// - `ClusteredIndexError::ConfigError` if no log is open
+    /// - `ClusteredIndexError::SerializeError` if the log cannot be written
+    pub(crate) fn truncate_wal(&self) -> Result<()> {
+        let wal = self.wal.as_ref().ok_or_else(|| {
+            ClusteredIndexError::ConfigError("the index has no write-ahead log".to_string())
+        })?;
+        wal.clear()
+    }
+
+    /// Appends the clusters of `other` to the index, without rebuilding any cluster index.
     ///
-    /// # Parameters
-    /// - `cluster`: Cluster to search in
-    /// - `query`: Query point
+    /// The points of `other` are appended to the dataset, so a point `i` of `other` becomes
+    /// `offset + i`, where `offset` is the size of the dataset before merging. The cluster
+    /// indices only refer to points through the cluster members, so they are moved as they are.
+    /// The configuration of `self` is kept, the clusters of `other` keep their number of tables.
     ///
     /// # Returns
-    /// Vector of (distance, index) pairs for the k nearest neighbors in the cluster,
-    /// sorted by distance
+    /// The offset added to the ids of the points of `other`
     ///
-    /// # Performance
-    /// Time complexity: O(cluster_size * dim) where dim is point dimensionality
-    fn brute_force_search(
-        &self,
-        cluster: &ClusterCenter,
-        query: &[T::DataType],
-    ) -> Result<Vec<(f32, usize)>> {
-        let mut priority_queue = TopKClosestHeap::new(self.config.k);
-        let mut points_added = 0;
-        for p in &cluster.assignment {
-            let distance = self.data.distance_point(*p, query);
-            if priority_queue.add(Element {
-                distance: OrderedFloat(distance),
-                point_index: *p,
-            }) {
-                points_added += 1;
+    /// # Errors
+    /// - `ClusteredIndexError::ConfigError` if either index is not built, if they have
+    ///   different projections, if either collapses duplicates (see [`Config::dedup`]), or if
+    ///   `self` has a write-ahead log (see [`open_wal()`](Self::open_wal))
+    /// - `ClusteredIndexError::DataError` if the datasets have different dimensions
+    pub(crate) fn merge(&mut self, other: Self) -> Result<usize>
+    where
+        T: Insertable,
+    {
+        if self.clusters.is_empty() || other.clusters.is_empty() {
+            return Err(ClusteredIndexError::ConfigError(
+                "both indexes must be built before merging".to_string(),
+            ));
+        }
+        if self.data.dimensions() != other.data.dimensions() {
+            return Err(ClusteredIndexError::DataError(format!(
+                "cannot merge an index with {} dimensions into one with {}",
+                other.data.dimensions(),
+                self.data.dimensions()
+            )));
+        }
+        let projection = |config: &Config| serde_json::to_value(&config.projection).ok();
+        if projection(&self.config) != projection(&other.config) {
+            return Err(ClusteredIndexError::ConfigError(
+                "indexes with different projections cannot be merged".to_string(),
+            ));
+        }
+        if self.data.weights() != other.data.weights() {
+            return Err(ClusteredIndexError::ConfigError(
+                "indexes with different weights cannot be merged".to_string(),
+            ));
+        }
+        // the copies of each other's points would stay in the clusters of `other`
+        if self.config.dedup || other.config.dedup {
+            return Err(ClusteredIndexError::ConfigError(
+                "indexes with collapsed duplicates cannot be merged, build the merged dataset instead".to_string(),
+            ));
+        }
+        // the log replays insertions, which would not rebuild the clusters of `other`
+        if self.wal.is_some() {
+            return Err(ClusteredIndexError::ConfigError(
+                "an index with a write-ahead log cannot be merged into, merge before opening the log".to_string(),
+            ));
+        }
+
+        let offset = self.data.num_points();
+        for i in 0..other.data.num_points() {
+            let point = other.data.get_point(i);
+            let id = self
+                .data
+                .insert(&point)
+                .map_err(ClusteredIndexError::DataError)?;
+            let indexed = self.data.indexed_point(id).into_owned();
+            if let Some(quantizer) = &mut self.quantizer {
+                quantizer.push(&indexed);
+            }
+            if let Some(quantizer) = &mut self.product_quantizer {
+                quantizer.push(&indexed);
             }
         }
 
-        debug!("points added in brute force: {}", points_added);
-        Ok(priority_queue.to_list())
+        // the cells of both hierarchies group the clusters of both indexes
+        let mut coarse = self.hierarchy.as_ref().map_or_else(Vec::new, Hierarchy::coarse_centers);
+        if let Some(hierarchy) = &other.hierarchy {
+            coarse.extend(hierarchy.coarse_centers().into_iter().map(|c| c + offset));
+        }
+
+        let first = self.clusters.len();
+        for mut cluster in other.clusters {
+            cluster.idx += first;
+            cluster.center_idx += offset;
+            for point in cluster.assignment.iter_mut() {
+                *point += offset;
+            }
+            // rebuilds after an insertion must use the tables the cluster was built with
+            if !cluster.brute_force && cluster.num_tables.is_none() {
+                cluster.num_tables = Some(other.config.num_tables);
+            }
+            self.clusters.push(cluster);
+        }
+        self.puffinn_indices.extend(other.puffinn_indices);
+        if !coarse.is_empty() {
+            self.hierarchy = Some(Hierarchy::compute(&self.data, &self.clusters, &coarse));
+        }
+        self.index_centers();
+
+        // the content of the index changed, cached results of the old one must not be returned
+        self.refresh_query_cache();
+
+        info!(
+            "Merged {} clusters with {} points, the index has {} clusters",
+            self.clusters.len() - first,
+            self.data.num_points() - offset,
+            self.clusters.len()
+        );
+
+        Ok(offset)
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use crate::{core::Config, metricdata::AngularData};
-    use ndarray::arr2;
+    /// Clusters the points of a built index again, after insertions made its clusters stale.
+    ///
+    /// Only the indices of the clusters whose members changed are rebuilt, see
+    /// [`RepartitionPolicy`]. Rebuilt clusters use the configured number of tables, without
+    /// applying the memory ceiling again.
+    ///
+    /// # Returns
+    /// Number of clusters whose members changed
+    ///
+    /// # Errors
+    /// - `ClusteredIndexError::ConfigError` if the index is not built, or if `max_points` is zero
+    /// - `ClusteredIndexError::PuffinnCreationError` if a cluster index cannot be rebuilt
+    pub(crate) fn repartition(&mut self, policy: RepartitionPolicy) -> Result<usize>
+    {
+        if self.clusters.is_empty() {
+            return Err(ClusteredIndexError::ConfigError(
+                "the index must be built before repartitioning".to_string(),
+            ));
+        }
+        let start = Instant::now();
 
-    use super::{ClusterCenter, ClusteredIndex};
+        let changed = match policy {
+            RepartitionPolicy::Full => {
+                let k = self.select_num_clusters(None, &mut |_| true)?;
+                let mut previous: HashMap<Vec<usize>, (ClusterCenter, Option<B>)> =
+                    std::mem::take(&mut self.clusters)
+                        .into_iter()
+                        .zip(std::mem::take(&mut self.puffinn_indices))
+                        .map(|(cluster, index)| (cluster.assignment.to_vec(), (cluster, index)))
+                        .collect();
 
-    #[test]
-    fn test_sort_cluster() {
-        let points = arr2(&[
-            [0.1, 0.9, 0.4],
-            [0.7, 0.2, 0.6],
-            [0.5, 0.3, 0.9],
-            [0.8, 0.4, 0.1],
-            [0.2, 0.1, 0.8],
-            [0.9, 0.8, 0.3],
-            [0.3, 0.6, 0.5],
-            [0.4, 0.3, 0.7],
-            [0.1, 0.2, 0.9],
-            [0.6, 0.7, 0.8],
+                self.clusters = self.greedy_clusters(None, k, &mut |_| true)?;
+                self.merge_tiny_clusters();
+                self.pool_outliers();
+                self.collapse_duplicates();
+                let mut changed = Vec::new();
+                for cluster in self.clusters.iter_mut() {
+                    match previous.remove(&cluster.assignment[..]) {
+                        Some((old, index)) => {
+                            // same members, the index is still valid
+                            cluster.brute_force = old.brute_force;
+                            cluster.over_memory_ceiling = old.over_memory_ceiling;
+                            cluster.memory_used = old.memory_used;
+                            cluster.num_tables = old.num_tables;
+                            cluster.build_time = old.build_time;
+                            cluster.search_stats = old.search_stats;
+                            self.puffinn_indices.push(index);
+                        }
+                        None => {
+                            self.puffinn_indices.push(None);
+                            changed.push(cluster.idx);
+                        }
+                    }
+                }
+                changed
+            }
+            RepartitionPolicy::SplitOversized { max_points } => {
+                if max_points == 0 {
+                    return Err(ClusteredIndexError::ConfigError(
+                        "max_points must be positive".to_string(),
+                    ));
+                }
+                let oversized: Vec<usize> = (0..self.clusters.len())
+                    .filter(|&position| {
+                        !self.clusters[position].outlier && self.clusters[position].assignment.len() > max_points
+                    })
+                    .collect();
+
+                let mut changed = Vec::new();
+                for position in oversized {
+                    let members = &self.clusters[position].assignment;
+                    let parts = members.len().div_ceil(max_points);
+                    debug!("Splitting cluster {} with {} points in {}", position, members.len(), parts);
+                    let mut split = self
+                        .greedy_clusters(Some(&members[..]), parts, &mut |_| true)?
+                        .into_iter()
+                        .filter(|cluster| !cluster.assignment.is_empty());
+
+                    let first = split.next().expect("a non-empty cluster has at least one part");
+                    self.clusters[position] = ClusterCenter {
+                        idx: position,
+                        ..first
+                    };
+                    changed.push(position);
+                    for cluster in split {
+                        let idx = self.clusters.len();
+                        self.clusters.push(ClusterCenter { idx, ..cluster });
+                        self.puffinn_indices.push(None);
+                        changed.push(idx);
+                    }
+                }
+                changed
+            }
+        };
+
+        for &position in &changed {
+            self.rebuild_cluster(position)?;
+        }
+        self.index_centers();
+
+        // the content of the index changed, cached results of the old one must not be returned
+        self.refresh_query_cache();
+
+        info!(
+            "Repartitioned in {:.2?}: {} clusters, {} rebuilt",
+            start.elapsed(),
+            self.clusters.len(),
+            changed.len()
+        );
+
+        Ok(changed.len())
+    }
+
+    /// Builds the index of the cluster at `position` from its members, or drops it if the
+    /// cluster is now scanned exhaustively.
+    fn rebuild_cluster(&mut self, position: usize) -> Result<()> {
+        let needs_index = self.needs_index(self.clusters[position].assignment.len())
+            && !self.clusters[position].outlier
+            && !self.clusters[position].over_memory_ceiling;
+        let cluster = &mut self.clusters[position];
+        cluster.brute_force = !needs_index;
+        cluster.search_stats = ClusterSearchStats::default();
+        cluster.member_distances = Some(member_distances(&self.data, cluster));
+
+        if !needs_index {
+            self.puffinn_indices[cluster.idx] = None;
+            cluster.memory_used = 0;
+            cluster.num_tables = None;
+            cluster.build_time = Duration::ZERO;
+            return Ok(());
+        }
+
+        let num_tables = cluster.num_tables.unwrap_or(self.config.num_tables);
+        B::set_num_threads(self.config.threads());
+        let cluster_start = Instant::now();
+        let (index, memory_used) = B::build(&self.data, &cluster.assignment, num_tables)
+            .map_err(ClusteredIndexError::PuffinnCreationError)?;
+        self.puffinn_indices[cluster.idx] = Some(index);
+        cluster.memory_used = memory_used;
+        cluster.build_time = cluster_start.elapsed();
+
+        Ok(())
+    }
+
+    /// Rebuilds from the dataset the indexes of the clusters at the positions of `failures`,
+    /// whose serialized index couldn't be read for the paired reason, so that a partly corrupt
+    /// file doesn't force a full rebuild.
+    fn rebuild_unreadable(&mut self, failures: &[(usize, String)]) -> Result<()> {
+        for (position, reason) in failures {
+            let cluster = self.clusters[*position].idx;
+            self.rebuild_cluster(*position)
+                .map_err(|e| ClusteredIndexError::DeserializeError {
+                    cluster,
+                    message: format!("{}, and rebuilding it failed: {}", reason, e),
+                })?;
+            self.rebuilt_clusters.push(cluster);
+        }
+        if !self.rebuilt_clusters.is_empty() {
+            warn!(
+                "Rebuilt the indexes of {} clusters from the dataset: {:?}",
+                self.rebuilt_clusters.len(),
+                self.rebuilt_clusters
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Searches for the k nearest neighbors of a query point.
+    ///
+    /// The search process:
+    /// 1. Sorts clusters by distance from query to their centers
+    /// 2. Processes clusters in order until termination condition is met
+    /// 3. For each cluster either:
+    ///    - Uses PUFFINN index to find candidates (large clusters)
+    ///    - Uses brute force search (small clusters)
+    ///
+    /// # Parameters
+    /// - `query`: Query point with same dimensionality as dataset points, or as the original
+    ///   points if the configuration has a projection
+    ///
+    /// # Returns
+    /// Vector of (distance, index) pairs for the k nearest neighbors found, sorted by distance
+    /// in ascending order and equal distances by index, whether the clusters are scanned or
+    /// searched with PUFFINN
+    ///
+    /// # Errors
+    /// - `ClusteredIndexError::DataError` if the query has a NaN or infinite component, or a zero norm under the
+    ///   angular distance or with normalized queries
+    /// - `ClusteredIndexError::DimensionMismatch` if the query doesn't have the dimensions of the dataset,
+    ///   or the input dimensions of the projection
+    /// - `ClusteredIndexError::IndexNotFound` if a required PUFFINN index is missing
+    /// - `ClusteredIndexError::PuffinnSearchError` if PUFFINN search fails
+    /// - `ClusteredIndexError::IndexOutOfBounds` if candidate mapping fails
+    pub(crate) fn search(&mut self, query: &[T::DataType]) -> Result<Vec<(f32, usize)>>
+    {
+        self.search_with(query, &SearchParams::default())
+    }
+
+    /// Searches for the k nearest neighbors of a query point, with the per-query options of
+    /// `params`, see [`search()`](Self::search).
+    ///
+    /// The excluded points are left out of the candidates of every cluster, so they are
+    /// neither returned nor counted in the k neighbors. With a maximum distance, the search
+    /// returns the neighbors among the k nearest within it, and skips the clusters beyond it.
+    ///
+    /// # Errors
+    /// Same as [`search()`](Self::search)
+    pub(crate) fn search_with(&mut self, query: &[T::DataType], params: &SearchParams) -> Result<Vec<(f32, usize)>>
+    {
+        let query = self.prepare_query(query)?;
+        self.search_recorded(&query, params, None).map(|(results, _)| results)
+    }
+
+    /// [`search_with()`](Self::search_with) on a prepared query, probing the clusters in `order`
+    /// with the distances it computed upfront if given, and returning the order where the search
+    /// stopped.
+    fn search_recorded(
+        &mut self,
+        query: &[T::DataType],
+        params: &SearchParams,
+        order: Option<(ProbeOrder, usize)>,
+    ) -> Result<(Vec<(f32, usize)>, ProbeOrder)> {
+        self.last_distance_computations = 0;
+        if let Some(metrics) = &mut self.metrics {
+            metrics.new_query();
+        }
+        let query_time = Instant::now();
+
+        let mut trace = QueryTrace {
+            // cluster of each point added to the top-k, to report which clusters contributed to it
+            origins: self.metrics.is_some().then(HashMap::new),
+            ..Default::default()
+        };
+        let (results, order) = self.search_traced(query, params, order, Some(&mut trace))?;
+
+        for probe in &trace.probes {
+            self.last_distance_computations += probe.distance_computations.total();
+
+            let stats = &mut self.clusters[probe.cluster].search_stats;
+            stats.probes += 1;
+            stats.candidates += probe.points_added;
+
+            let probed = self.clusters[probe.cluster].idx;
+            if let Some(metrics) = &mut self.metrics {
+                metrics.log_probed_cluster(probed);
+                metrics.log_n_candidates(probe.points_added);
+                metrics.log_cluster_time(probe.elapsed);
+                metrics.add_distance_computation_cluster(probe.distance_computations);
+            }
+        }
+
+        self.last_distance_computations += trace.distance_computations.total();
+        if let Some(metrics) = &mut self.metrics {
+            metrics.add_distance_computation_global(trace.distance_computations);
+            metrics.log_query_time(query_time.elapsed());
+            if let Some(origins) = trace.origins {
+                let mut contributors: Vec<usize> =
+                    results.iter().filter_map(|(_, p)| origins.get(p).copied()).collect();
+                contributors.sort_unstable();
+                contributors.dedup();
+                metrics.log_topk_clusters(contributors);
+            }
+        }
+        self.finish_query_metrics();
+
+        Ok((results, order))
+    }
+
+    /// Searches for the k nearest neighbors of a query point through a shared reference, see
+    /// [`search_with()`](Self::search_with), so that several threads can search the index at
+    /// once, see [`IndexHandle`](crate::core::IndexHandle).
+    ///
+    /// The results are the same, but the search is not recorded: neither the per-query
+    /// metrics and callbacks, nor the statistics of the probed clusters, nor
+    /// [`last_distance_computations()`](Self::last_distance_computations) are updated.
+    ///
+    /// # Errors
+    /// Same as [`search()`](Self::search)
+    pub(crate) fn search_shared(&self, query: &[T::DataType], params: &SearchParams) -> Result<Vec<(f32, usize)>> {
+        let query = self.prepare_query(query)?;
+        self.search_traced(&query, params, None, None).map(|(results, _)| results)
+    }
+
+    /// Searches for the k nearest neighbors of a query point without recording anything,
+    /// as [`search_shared()`](Self::search_shared) with the default [`SearchParams`].
+    ///
+    /// Meant for serving a loaded index: there is no metrics bookkeeping, not even the timing
+    /// of the probed clusters, and no exclusive borrow of the index.
+    ///
+    /// # Errors
+    /// Same as [`search()`](Self::search)
+    pub(crate) fn search_readonly(&self, query: &[T::DataType]) -> Result<Vec<(f32, usize)>> {
+        self.search_shared(query, &SearchParams::default())
+    }
+
+    /// Searches every row of `queries` and tells, for every true neighbor missing from its
+    /// results, whether its cluster was pruned, not reached, or probed without its index
+    /// returning it, see [`MissReason`].
+    ///
+    /// The searches are not recorded, as with [`search_shared()`](Self::search_shared).
+    ///
+    /// # Parameters
+    /// - `queries`: One query per row
+    /// - `ground_truth`: Ids of the true neighbors of each query, closest first, at least k per row
+    ///
+    /// # Returns
+    /// The missed neighbors of each query, in the same order as the rows
+    ///
+    /// # Errors
+    /// - `ClusteredIndexError::ConfigError` if the ground truth has fewer than k neighbors per query
+    /// - `ClusteredIndexError::DataError` if the number of queries and of ground truth rows differ
+    /// - Any error returned by [`search()`](Self::search)
+    pub(crate) fn diagnose_misses<S>(
+        &self,
+        queries: &ArrayBase<S, Ix2>,
+        ground_truth: &Array2<usize>,
+    ) -> Result<Vec<QueryMisses>>
+    where
+        S: Data<Elem = T::DataType>,
+    {
+        let k = self.config.k;
+        if ground_truth.ncols() < k {
+            return Err(ClusteredIndexError::ConfigError(format!(
+                "k is {} but the ground truth has {} neighbors per query",
+                k,
+                ground_truth.ncols()
+            )));
+        }
+        if ground_truth.nrows() != queries.nrows() {
+            return Err(ClusteredIndexError::DataError(format!(
+                "{} queries but {} ground truth rows",
+                queries.nrows(),
+                ground_truth.nrows()
+            )));
+        }
+
+        // position of the cluster of every point, once for all the queries
+        let mut positions = vec![None; self.data.num_points()];
+        for (position, cluster) in self.clusters.iter().enumerate() {
+            for &p in &cluster.assignment {
+                positions[p] = Some(position);
+            }
+        }
+
+        queries
+            .rows()
+            .into_iter()
+            .zip(ground_truth.rows())
+            .map(|(query, truth)| {
+                let query = self.prepare_query(&query.to_vec())?.into_owned();
+                let mut trace = QueryTrace::default();
+                let (results, _) = self.search_traced(&query, &SearchParams::default(), None, Some(&mut trace))?;
+
+                let kth_distance = k.checked_sub(1).and_then(|i| results.get(i)).map_or(f32::INFINITY, |r| r.0);
+                let mut misses = Vec::new();
+                for &point in truth.iter().take(k) {
+                    if results.iter().any(|&(_, p)| p == point) {
+                        continue;
+                    }
+                    let canonical = self.duplicates.as_ref().map_or(point, |d| d.canonical(point));
+                    let Some(position) = positions.get(canonical).copied().flatten() else {
+                        continue;
+                    };
+                    let cluster = &self.clusters[position];
+
+                    let reason = if trace.probes.iter().any(|probe| probe.cluster == position) {
+                        MissReason::NotReturned
+                    } else if self.data.distance_to_metric(self.data.distance_point(cluster.center_idx, &query))
+                        - self.data.distance_to_metric(cluster.pruning_radius(self.pruning_radius()))
+                        > self.data.distance_to_metric(kth_distance)
+                    {
+                        MissReason::Pruned
+                    } else {
+                        MissReason::Unvisited
+                    };
+                    misses.push(Miss {
+                        point,
+                        cluster: cluster.idx,
+                        reason,
+                    });
+                }
+                Ok(QueryMisses { misses })
+            })
+            .collect()
+    }
+
+    /// Number of neighbors returned by a search with `params`, the k of the configuration if it
+    /// sets none.
+    ///
+    /// # Errors
+    /// Returns `ClusteredIndexError::ConfigError` if it is 0 or more than the MMR candidates, as
+    /// [`Config::validate`] does for the k of the configuration, or if the maximum distance of
+    /// `params` is negative or NaN
+    fn search_k(&self, params: &SearchParams) -> Result<usize> {
+        if let Some(max_distance) = params.max_distance.filter(|d| d.is_nan() || *d < 0.0) {
+            return Err(ClusteredIndexError::ConfigError(format!(
+                "max_distance must be a non-negative number, got {}",
+                max_distance
+            )));
+        }
+        let k = params.k.unwrap_or(self.config.k);
+        if k == 0 {
+            return Err(ClusteredIndexError::ConfigError("k must be at least 1".to_string()));
+        }
+        if let Some(mmr) = self.config.mmr.filter(|mmr| mmr.candidates < k) {
+            return Err(ClusteredIndexError::ConfigError(format!(
+                "k must be at most mmr.candidates ({}), got {}",
+                mmr.candidates, k
+            )));
+        }
+        Ok(k)
+    }
+
+    /// Searches the prepared `query`, recording in `trace` the clusters it probes and the
+    /// other distance computations for the statistics of the caller, nothing without a trace.
+    /// The clusters are probed in `order` with the distances it computed upfront if given, in
+    /// the order of [`probe_order()`](Self::probe_order) otherwise, returned where the search stopped.
+    fn search_traced(
+        &self,
+        query: &[T::DataType],
+        params: &SearchParams,
+        order: Option<(ProbeOrder, usize)>,
+        mut trace: Option<&mut QueryTrace>,
+    ) -> Result<(Vec<(f32, usize)>, ProbeOrder)> {
+        let k = self.search_k(params)?;
+        debug!(
+            "Starting search procedure with parameters k={} and delta={:.2}",
+            k, self.config.delta
+        );
+
+        let (mut order, center_distance_computations) = match order {
+            Some(order) => order,
+            None => self.probe_order_shared(query),
+        };
+        let mut exclude = params.exclude.to_vec();
+        exclude.sort_unstable();
+        exclude.dedup();
+        let skipped = match &self.duplicates {
+            Some(duplicates) => Cow::Owned(duplicates.excluded_candidates(&exclude)),
+            None => Cow::Borrowed(&exclude),
+        };
+
+        let mut priority_queue = TopK::new(self.candidates_per_query(k)).with_ties(Ties::SmallestId);
+
+        // exit condition: a cluster whose nearest possible point is farther than the worst point
+        // in the priority queue can't hold a nearer one, so ProbeOrder::next skips it and goes on
+        // with the next cluster, a farther one with a larger radius may still hold a neighbor.
+        // The search stops once every cluster is probed or skipped, or the probe limit is reached.
+        // With a maximum distance, no cluster farther than it can hold a result either
+        let mut outliers = self.outlier_probes();
+        while let Some((cluster_idx, center_distance)) = outliers.pop().or_else(|| {
+            let bound = match (priority_queue.kth_distance(), params.max_distance) {
+                (Some(kth), Some(max_distance)) => Some(kth.min(max_distance)),
+                (kth, max_distance) => kth.or(max_distance),
+            };
+            order.next(
+                &self.data,
+                &self.clusters,
+                &self.center_distances,
+                self.hierarchy.as_ref(),
+                query,
+                bound,
+            )
+        }) {
+            debug!("cluster index: {}", cluster_idx);
+            let cluster_start = trace.is_some().then(Instant::now);
+
+            let (points_added, distance_computations) = self.probe_cluster(
+                cluster_idx,
+                query,
+                center_distance,
+                &skipped,
+                &mut priority_queue,
+                trace.as_deref_mut().and_then(|trace| trace.origins.as_mut()),
+            )?;
+            debug!("Added {} points in cluster {})", points_added, cluster_idx);
+
+            if let (Some(trace), Some(cluster_start)) = (trace.as_deref_mut(), cluster_start) {
+                trace.probes.push(Probe {
+                    cluster: cluster_idx,
+                    points_added,
+                    distance_computations,
+                    elapsed: cluster_start.elapsed(),
+                });
+            }
+        }
+
+        let (mut results, rerank_distance_computations) = self.rerank(query, priority_queue.into_sorted_vec());
+        // on the reranked distances, which are exact
+        if let Some(max_distance) = params.max_distance {
+            results.retain(|&(distance, _)| distance <= max_distance);
+        }
+        let (results, mmr_distance_computations) = self.select_mmr(results, k);
+        if let Some(trace) = trace {
+            trace.distance_computations += DistanceComputations {
+                pruning: center_distance_computations + order.distance_computations(),
+                rerank: rerank_distance_computations + mmr_distance_computations,
+                ..Default::default()
+            };
+        }
+
+        Ok((self.report_duplicates(results, &exclude, k), order))
+    }
+
+    /// Searches for the k points nearest to a set of query vectors, by the `aggregation` of
+    /// their distances to every vector.
+    ///
+    /// The aggregation is pushed into the search rather than merging independent searches:
+    /// the clusters are probed by the aggregation of the lower bounds from every vector to
+    /// their points, each the difference of the center distance and the radius in the metric,
+    /// and the search stops at the first cluster whose bound exceeds the k-th aggregated
+    /// distance found. The candidates of an indexed cluster are those of its index for every
+    /// vector: a point closer than the k-th aggregated distance is closer than it to at least
+    /// one vector, for both aggregations. With [`Aggregation::Min`] such a point is among the
+    /// k nearest of that vector, with [`Aggregation::Mean`] it may not be, so every point of
+    /// the index within the k-th aggregated distance is a candidate, and clusters probed
+    /// before k points are found are scanned.
+    ///
+    /// Per-query metrics are not recorded and the delta policy is not asked, every index is
+    /// searched with the configured delta.
+    ///
+    /// # Errors
+    /// - `ClusteredIndexError::DataError` if there are no query vectors
+    /// - Any error returned by [`search()`](Self::search)
+    pub(crate) fn search_multi(
+        &mut self,
+        queries: &[&[T::DataType]],
+        aggregation: Aggregation,
+    ) -> Result<Vec<(f32, usize)>> {
+        if queries.is_empty() {
+            return Err(ClusteredIndexError::DataError("no query vectors".to_string()));
+        }
+        let queries: Vec<Vec<T::DataType>> = queries
+            .iter()
+            .map(|&query| self.prepare_query(query).map(Cow::into_owned))
+            .collect::<Result<_>>()?;
+
+        // clusters by the aggregated lower bound on the distance from the query vectors to
+        // their points, bounded per vector in the metric: the metric of an aggregated distance
+        // isn't the aggregation of the metrics, e.g. for the concave chord of angular data
+        let mut sorted: Vec<(usize, f32)> = self
+            .clusters
+            .iter()
+            .enumerate()
+            .filter(|(_, cluster)| !cluster.outlier)
+            .map(|(position, cluster)| {
+                let radius = cluster.pruning_radius(self.pruning_radius());
+                let bounds = queries
+                    .iter()
+                    .map(|query| self.lower_bound(self.data.distance_point(cluster.center_idx, query), radius));
+                (position, aggregation.combine(bounds) as f32)
+            })
+            .collect();
+        sorted.sort_by(|a, b| a.1.total_cmp(&b.1));
+        self.last_distance_computations = sorted.len() * queries.len();
+
+        // the keys are already the lower bounds on the distance of the points
+        let radii = vec![0.0; self.clusters.len()];
+        let max_probes = self.max_probes();
+        let mut order = ProbeOrder::sorted(sorted, radii, max_probes);
+
+        let mut priority_queue = TopK::new(self.config.k).with_ties(Ties::SmallestId);
+        let mut outliers = self.outlier_probes();
+        while let Some((cluster_idx, _)) = outliers.pop().or_else(|| {
+            order.next(
+                &self.data,
+                &self.clusters,
+                &self.center_distances,
+                None,
+                &queries[0],
+                priority_queue.kth_distance(),
+            )
+        }) {
+            let (points_added, distance_computations) =
+                self.probe_cluster_multi(cluster_idx, &queries, aggregation, &mut priority_queue)?;
+            self.last_distance_computations += distance_computations;
+
+            let stats = &mut self.clusters[cluster_idx].search_stats;
+            stats.probes += 1;
+            stats.candidates += points_added;
+        }
+
+        let mut results = priority_queue.into_sorted_vec();
+        if self.config.rerank_f64 || self.quantizer.is_some() {
+            // exact aggregated distances, as finalize_results does for a single query
+            for (distance, p) in results.iter_mut() {
+                *distance = if self.config.rerank_f64 {
+                    aggregation.combine(queries.iter().map(|query| self.data.distance_point_f64(*p, query))) as f32
+                } else {
+                    aggregation.combine(queries.iter().map(|query| self.data.distance_point(*p, query))) as f32
+                };
+            }
+            results.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+            self.last_distance_computations += results.len() * queries.len();
+        }
+
+        Ok(self.report_duplicates(results, &[], self.config.k))
+    }
+
+    /// Searches a single cluster for the points nearest to a set of query vectors, adding them
+    /// to the top-k with their aggregated distance, see [`search_multi()`](Self::search_multi).
+    ///
+    /// # Returns
+    /// The number of points added to the top-k and the distance computations spent
+    fn probe_cluster_multi(
+        &self,
+        cluster_idx: usize,
+        queries: &[Vec<T::DataType>],
+        aggregation: Aggregation,
+        priority_queue: &mut TopK,
+    ) -> Result<(usize, usize)> {
+        let cluster = &self.clusters[cluster_idx];
+        let mut distance_computations = 0;
+
+        let kth_distance = priority_queue.kth_distance();
+        let scan = match aggregation {
+            Aggregation::Min => false,
+            // every point of the cluster may be among the k nearest of the mean
+            Aggregation::Mean => kth_distance.is_none(),
+        };
+        let candidates = if cluster.brute_force || self.config.exact || scan {
+            cluster.assignment.to_vec()
+        } else {
+            let index = self.puffinn_indices[cluster.idx]
+                .as_ref()
+                .ok_or(ClusteredIndexError::IndexNotFound())?;
+            let max_dist = kth_distance.unwrap_or(f32::INFINITY);
+            let k = match aggregation {
+                Aggregation::Min => self.config.k.min(cluster.assignment.len()),
+                Aggregation::Mean => cluster.assignment.len(),
+            };
+            let mut candidates = Vec::new();
+            for query in queries {
+                let (found, computations) = index
+                    .search(&self.data.indexed_query(query), k, max_dist, self.config.delta)
+                    .map_err(ClusteredIndexError::PuffinnSearchError)?;
+                distance_computations += computations;
+                candidates.extend(self.map_candidates(&found, cluster)?);
+            }
+            candidates.sort_unstable();
+            candidates.dedup();
+            candidates
+        };
+
+        let distances: Vec<Vec<f32>> = queries
+            .iter()
+            .map(|query| {
+                let mut distances = vec![0.0; candidates.len()];
+                self.candidate_distances(&candidates, query, &mut distances)?;
+                Ok(distances)
+            })
+            .collect::<Result<_>>()?;
+        let aggregated =
+            (0..candidates.len()).map(|i| aggregation.combine(distances.iter().map(|distances| distances[i])) as f32);
+        distance_computations += candidates.len() * queries.len();
+
+        let mut points_added = 0;
+        for (p, distance) in candidates.into_iter().zip(aggregated) {
+            if priority_queue.push(distance, p) {
+                points_added += 1;
+            }
+        }
+
+        Ok((points_added, distance_computations))
+    }
+
+    /// Searches a single cluster, adding its candidates to the top-k of the query.
+    ///
+    /// Small clusters are scanned exhaustively, the others are searched with their index for
+    /// points closer than the current top of the top-k, with the recall target of the delta
+    /// policy if set. The points of `exclude`, sorted, are skipped. The cluster of every point
+    /// added to the top-k is recorded in `origins`, if given.
+    ///
+    /// # Returns
+    /// The number of points added to the top-k and the distance computations spent, inside the
+    /// index of the cluster and to its candidates
+    fn probe_cluster(
+        &self,
+        cluster_idx: usize,
+        query: &[T::DataType],
+        center_distance: f32,
+        exclude: &[usize],
+        priority_queue: &mut TopK,
+        mut origins: Option<&mut HashMap<usize, usize>>,
+    ) -> Result<(usize, DistanceComputations)> {
+        let cluster = &self.clusters[cluster_idx];
+        let mut points_added = 0;
+        let mut distance_computations = DistanceComputations::default();
+        let threshold = priority_queue.kth_distance();
+        let max_dist = priority_queue.kth_distance().unwrap_or(f32::INFINITY);
+
+        if cluster.brute_force || self.config.exact {
+            // do brute force
+
+            let (candidates, scanned) = self.brute_force_search(cluster, query, priority_queue.k(), threshold, exclude)?;
+
+            for (distance, p) in &candidates {
+                if priority_queue.push(*distance, *p) {
+                    points_added += 1;
+                    if let Some(origins) = origins.as_deref_mut() {
+                        origins.insert(*p, cluster.idx);
+                    }
+                }
+            }
+
+            distance_computations.rerank = scanned;
+        } else {
+            // do puffinn query algorithm
+
+            let index = self.puffinn_indices[cluster.idx]
+                .as_ref()
+                .ok_or(ClusteredIndexError::IndexNotFound())?;
+            let delta = match &self.delta_policy {
+                Some(policy) => {
+                    let context = ProbeContext {
+                        center_distance,
+                        lower_bound: self.lower_bound(center_distance, cluster.radius),
+                        kth_distance: threshold,
+                        indexed_clusters: self.clusters.iter().filter(|c| !c.brute_force).count(),
+                    };
+                    policy.cluster_delta(self.config.delta, &context).clamp(0.0, 1.0)
+                }
+                None => self.config.delta,
+            };
+            // the excluded points may take some of the k candidates, no more than the cluster holds
+            let k = (priority_queue.k() + exclude.len()).min(cluster.assignment.len());
+            let (candidates, lsh_computations) = index
+                .search(&self.data.indexed_query(query), k, max_dist, delta)
+                .map_err(ClusteredIndexError::PuffinnSearchError)?;
+
+            // map puffinn result to the original dataset
+            let mut mapped_candidates = match self.map_candidates(&candidates, cluster) {
+                Ok(c) => c,
+                Err(e) => {
+                    eprintln!("Error on cluster {}", cluster_idx);
+                    return Err(e);
+                }
+            };
+            if !exclude.is_empty() {
+                mapped_candidates.retain(|p| exclude.binary_search(p).is_err());
+            }
+
+            let mapped_candidates = self.prune(&mapped_candidates, query, threshold).into_owned();
+            let mut distances = vec![0.0; mapped_candidates.len()];
+            self.candidate_distances(&mapped_candidates, query, &mut distances)?;
+            distance_computations.rerank = mapped_candidates.len();
+
+            let mut min_dist_cluster = f32::INFINITY;
+            let mut max_dist_cluster = f32::NEG_INFINITY;
+            for (p, distance) in mapped_candidates.into_iter().zip(distances) {
+                if distance < min_dist_cluster {
+                    min_dist_cluster = distance;
+                }
+                if distance > max_dist_cluster {
+                    max_dist_cluster = distance;
+                }
+                if priority_queue.push(distance, p) {
+                    points_added += 1;
+                    if let Some(origins) = origins.as_deref_mut() {
+                        origins.insert(p, cluster.idx);
+                    }
+                }
+            }
+            debug!(
+                "points_added = {}, min_dist = {}, max_dist = {}",
+                points_added, min_dist_cluster, max_dist_cluster
+            );
+
+            distance_computations.lsh = lsh_computations;
+        }
+
+        Ok((points_added, distance_computations))
+    }
+
+    /// Re-ranks the results of a query in f64 if `rerank_f64` is enabled, or with the exact
+    /// distances if they were computed from quantized points, otherwise returns them unchanged.
+    ///
+    /// Distances are recomputed with [`MetricData::distance_point_f64`] (or
+    /// [`MetricData::distance_point`]) and sorted by (distance, point index), so neighbors closer
+    /// than f32 precision keep a stable order.
+    fn finalize_results(
+        &mut self,
+        query: &[T::DataType],
+        results: Vec<(f32, usize)>,
+    ) -> Vec<(f32, usize)> {
+        let (results, distance_computations) = self.rerank(query, results);
+        self.count_global_distance_computations(DistanceComputations {
+            rerank: distance_computations,
+            ..Default::default()
+        });
+        results
+    }
+
+    /// [`finalize_results()`](Self::finalize_results) through a shared reference, returning
+    /// the distance computations instead of counting them
+    fn rerank(&self, query: &[T::DataType], results: Vec<(f32, usize)>) -> (Vec<(f32, usize)>, usize) {
+        if !self.config.rerank_f64 && self.quantizer.is_none() {
+            return (results, 0);
+        }
+
+        let mut reranked: Vec<(f64, usize)> = results
+            .into_iter()
+            .map(|(_, p)| {
+                let distance = if self.config.rerank_f64 {
+                    self.data.distance_point_f64(p, query)
+                } else {
+                    self.data.distance_point(p, query) as f64
+                };
+                (distance, p)
+            })
+            .collect();
+        reranked.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+
+        let distance_computations = reranked.len();
+        let results = reranked
+            .into_iter()
+            .map(|(distance, p)| (distance as f32, p))
+            .collect();
+        (results, distance_computations)
+    }
+
+    /// Adds distance computations outside of the clusters to the last search and its metrics
+    fn count_global_distance_computations(&mut self, distance_computations: DistanceComputations) {
+        self.last_distance_computations += distance_computations.total();
+        if let Some(metrics) = &mut self.metrics {
+            metrics.add_distance_computation_global(distance_computations);
+        }
+    }
+
+    /// Nearest neighbors collected for every query returning `k`: k, or the candidates
+    /// diversified by [`Config::mmr`](crate::core::Config::mmr)
+    fn candidates_per_query(&self, k: usize) -> usize {
+        self.config.mmr.map_or(k, |mmr| mmr.candidates.max(k))
+    }
+
+    /// Selects the k results of a query among its `candidates` by maximal marginal relevance,
+    /// if [`Config::mmr`](crate::core::Config::mmr) is set, in the order they are selected.
+    /// Otherwise returns the candidates unchanged
+    fn diversify(&mut self, candidates: Vec<(f32, usize)>) -> Vec<(f32, usize)> {
+        let (selected, distance_computations) = self.select_mmr(candidates, self.config.k);
+        self.count_global_distance_computations(DistanceComputations {
+            rerank: distance_computations,
+            ..Default::default()
+        });
+        selected
+    }
+
+    /// [`diversify()`](Self::diversify) through a shared reference, selecting `k` results and
+    /// returning the distance computations instead of counting them
+    fn select_mmr(&self, candidates: Vec<(f32, usize)>, k: usize) -> (Vec<(f32, usize)>, usize) {
+        match self.config.mmr {
+            Some(mmr) => mmr_select(&self.data, &candidates, k, mmr.lambda),
+            None => (candidates, 0),
+        }
+    }
+
+    /// `results` with the points collapsed by [`Config::dedup`] right after their first copy,
+    /// except the points of the sorted `exclude`, truncated to `k`. Unchanged without duplicates
+    /// or with [`Config::mmr`], whose results are diverse on purpose
+    fn report_duplicates(&self, results: Vec<(f32, usize)>, exclude: &[usize], k: usize) -> Vec<(f32, usize)> {
+        match &self.duplicates {
+            Some(duplicates) if self.config.mmr.is_none() => duplicates.expand(results, exclude, k),
+            _ => results,
+        }
+    }
+
+    /// Computes the probe plan of a query without searching the clusters.
+    ///
+    /// Only the distances from the query to the centers are computed, and metrics are not
+    /// updated. See [`SearchPlan`] for how the skipped clusters are estimated.
+    ///
+    /// # Parameters
+    /// - `query`: Query point, as passed to [`search()`]
+    ///
+    /// # Errors
+    /// - `ClusteredIndexError::DataError` if the query has a NaN or infinite component, or a zero norm under the
+    ///   angular distance or with normalized queries
+    /// - `ClusteredIndexError::DimensionMismatch` if the query doesn't have the dimensions of the dataset,
+    ///   or the input dimensions of the projection
+    pub(crate) fn plan(&self, query: &[T::DataType]) -> Result<SearchPlan>
+    {
+        let query = self.prepare_query(query)?;
+        let query = &*query;
+        let radii = self.pruning_radii();
+
+        let mut steps: Vec<PlanStep> = self
+            .clusters
+            .iter()
+            .zip(&radii)
+            .map(|(cluster, &radius)| {
+                let center_distance = self.data.distance_point(cluster.center_idx, query);
+                let expected_candidates = cluster.search_stats.mean_candidates().or(
+                    cluster
+                        .brute_force
+                        .then(|| cluster.assignment.len().min(self.config.k) as f32),
+                );
+                PlanStep {
+                    cluster: cluster.idx,
+                    center_distance,
+                    lower_bound: self.lower_bound(center_distance, radius),
+                    num_points: cluster.assignment.len(),
+                    brute_force: cluster.brute_force,
+                    outlier: cluster.outlier,
+                    expected_pruned: false,
+                    expected_candidates,
+                }
+            })
+            .collect();
+
+        let mut center_distances: Vec<f32> = steps.iter().map(|step| step.center_distance).collect();
+        center_distances.sort_by(f32::total_cmp);
+        // the search bounds the clusters by the worst of the candidates it keeps
+        let k = self.candidates_per_query(self.config.k);
+        let estimated_kth_distance = (k > 0 && center_distances.len() >= k).then(|| center_distances[k - 1]);
+
+        // the outlier pools first, in the order they are popped from outlier_probes, then the
+        // order of ProbeOrder: by center distance, ties by cluster
+        steps.sort_by(|a, b| {
+            b.outlier.cmp(&a.outlier).then_with(|| {
+                if a.outlier {
+                    b.cluster.cmp(&a.cluster)
+                } else {
+                    a.center_distance
+                        .total_cmp(&b.center_distance)
+                        .then(a.cluster.cmp(&b.cluster))
+                }
+            })
+        });
+
+        // the bound tests of ProbeOrder::next, in the metric, on the cells and on the clusters
+        let bound = estimated_kth_distance.map(|kth| self.data.distance_to_metric(kth));
+        let mut pruned_cell = vec![false; self.clusters.len()];
+        let hierarchy = self.hierarchy.as_ref().filter(|h| h.matches(&self.clusters));
+        if let (Some(hierarchy), Some(bound)) = (hierarchy, bound) {
+            for cell in &hierarchy.cells {
+                let metric = self.data.distance_to_metric(self.data.distance_point(cell.center_idx, query));
+                let radius = cell.clusters.iter().map(|&c| radii[c]).fold(0.0, f32::max);
+                if (metric - cell.radius).max(0.0) - self.data.distance_to_metric(radius) > bound {
+                    for &cluster in &cell.clusters {
+                        pruned_cell[cluster] = true;
+                    }
+                }
+            }
+        }
+        let max_probes = self.max_probes();
+        let mut probes = 0;
+        for step in steps.iter_mut().filter(|step| !step.outlier) {
+            let metric = self.data.distance_to_metric(step.center_distance);
+            let radius = self.data.distance_to_metric(radii[step.cluster]);
+            step.expected_pruned = probes >= max_probes
+                || pruned_cell[step.cluster]
+                || bound.is_some_and(|bound| metric - radius > bound);
+            if !step.expected_pruned {
+                probes += 1;
+            }
+        }
+
+        Ok(SearchPlan {
+            steps,
+            estimated_kth_distance,
+        })
+    }
+
+    /// Estimates how hard `query` is from the distances to the centers and the radii of the
+    /// clusters, see [`QueryHardness`].
+    ///
+    /// # Errors
+    /// - `ClusteredIndexError::ConfigError` if the index is not built
+    /// - `ClusteredIndexError::DataError` if the query has a NaN or infinite component, or a zero norm under the
+    ///   angular distance or with normalized queries
+    /// - `ClusteredIndexError::DimensionMismatch` if the query doesn't have the dimensions of the dataset,
+    ///   or the input dimensions of the projection
+    pub(crate) fn estimate_hardness(&self, query: &[T::DataType]) -> Result<QueryHardness> {
+        if self.clusters.is_empty() {
+            return Err(ClusteredIndexError::ConfigError(
+                "the index must be built before estimating the hardness of a query".to_string(),
+            ));
+        }
+
+        // the outlier pools are scanned whatever the query
+        let plan = self.plan(query)?;
+        let mut steps: Vec<&PlanStep> = plan.steps.iter().filter(|step| !step.outlier).collect();
+        if steps.is_empty() {
+            steps = plan.steps.iter().collect();
+        }
+        let nearest = steps[0];
+        let radius = self.clusters[nearest.cluster].radius;
+        let relative_depth = if radius > 0.0 {
+            nearest.center_distance / radius
+        } else if nearest.center_distance > 0.0 {
+            f32::INFINITY
+        } else {
+            0.0
+        };
+        let margin = steps[1..]
+            .iter()
+            .map(|step| step.lower_bound)
+            .min_by(f32::total_cmp)
+            .map(|lower_bound| lower_bound - nearest.center_distance);
+
+        // the center is a dataset point, so the clusters that may hold a closer point are those
+        // the search may have to probe
+        let in_range: Vec<&PlanStep> = steps
+            .iter()
+            .copied()
+            .filter(|step| step.lower_bound <= nearest.center_distance)
+            .collect();
+        let points_in_range: usize = in_range.iter().map(|step| step.num_points).sum();
+
+        Ok(QueryHardness {
+            score: points_in_range as f32 / self.data.num_points() as f32,
+            relative_depth,
+            margin,
+            clusters_in_range: in_range.len(),
+        })
+    }
+
+    /// Returns the `m` clusters whose centers are closest to the query, closest first.
+    ///
+    /// Only the distances from the query to the centers are computed, and metrics are not
+    /// updated. Fewer than `m` clusters are returned if the index has fewer.
+    ///
+    /// # Errors
+    /// - `ClusteredIndexError::DataError` if the query has a NaN or infinite component, or a zero norm under the
+    ///   angular distance or with normalized queries
+    /// - `ClusteredIndexError::DimensionMismatch` if the query doesn't have the dimensions of the dataset,
+    ///   or the input dimensions of the projection
+    pub(crate) fn nearest_clusters(&self, query: &[T::DataType], m: usize) -> Result<Vec<NearestCluster>>
+    {
+        let query = self.prepare_query(query)?;
+        let query = &*query;
+
+        let mut nearest: Vec<NearestCluster> = self
+            .clusters
+            .iter()
+            .map(|cluster| NearestCluster {
+                cluster: cluster.idx,
+                center: cluster.center_idx,
+                distance: self.data.distance_point(cluster.center_idx, query),
+                radius: cluster.radius,
+            })
+            .collect();
+        let by_distance = |a: &NearestCluster, b: &NearestCluster| a.distance.total_cmp(&b.distance);
+        if m < nearest.len() {
+            if m > 0 {
+                nearest.select_nth_unstable_by(m - 1, by_distance);
+            }
+            nearest.truncate(m);
+        }
+        nearest.sort_by(by_distance);
+
+        Ok(nearest)
+    }
+
+    /// Searches the single cluster `cluster` for the `k` points nearest to the query, the
+    /// building block of the probing of [`search()`](Self::search), to schedule the clusters
+    /// differently, e.g. with [`nearest_clusters()`](Self::nearest_clusters).
+    ///
+    /// Brute force clusters are scanned exhaustively, the others are searched with their
+    /// PUFFINN index with the delta of the configuration, or of the delta policy if set. The
+    /// distances are the exact ones as in the results of a search, but duplicates collapsed by
+    /// [`Config::dedup`] are not expanded. Metrics and cluster statistics are not updated.
+    ///
+    /// # Returns
+    /// Up to `k` (distance, index) pairs of points of the cluster, closest first
+    ///
+    /// # Errors
+    /// - `ClusteredIndexError::IndexOutOfBounds` if there is no cluster `cluster`
+    /// - `ClusteredIndexError::DataError` if the query has a NaN or infinite component, or a zero norm under the
+    ///   angular distance or with normalized queries
+    /// - `ClusteredIndexError::DimensionMismatch` if the query doesn't have the dimensions of the dataset,
+    ///   or the input dimensions of the projection
+    /// - `ClusteredIndexError::IndexNotFound` if the PUFFINN index of the cluster is missing
+    /// - `ClusteredIndexError::PuffinnSearchError` if PUFFINN search fails
+    pub(crate) fn search_cluster(&self, cluster: usize, query: &[T::DataType], k: usize) -> Result<Vec<(f32, usize)>> {
+        let center_idx = self
+            .clusters
+            .get(cluster)
+            .ok_or(ClusteredIndexError::IndexOutOfBounds(cluster, self.clusters.len()))?
+            .center_idx;
+        let query = self.prepare_query(query)?;
+        let query = &*query;
+
+        let mut priority_queue = TopK::new(k).with_ties(Ties::SmallestId);
+        let center_distance = self.data.distance_point(center_idx, query);
+        self.probe_cluster(cluster, query, center_distance, &[], &mut priority_queue, None)?;
+
+        Ok(self.rerank(query, priority_queue.into_sorted_vec()).0)
+    }
+
+    /// Searches for the k nearest neighbors of every row of `queries`.
+    ///
+    /// Query streams are often skewed, with the same vector asked many times. Queries are
+    /// hashed on their exact bit pattern, and a query identical to one already answered in
+    /// the batch reuses its results instead of probing the clusters again.
+    ///
+    /// Near-duplicates are grouped by a hash of the queries rounded to a coarse grid, see
+    /// [`NEAR_DUPLICATE_STEPS`]. The first query searched in a group keeps its distances to the
+    /// centers it computed and its lower bounds on the others. The next ones start from these
+    /// bounds lowered by their distance to it, so that only the centers that may be probed next
+    /// are computed. The clusters are probed in the same order, so results are identical to
+    /// calling [`search()`] on each row. Only these center bounds are shared: the dot products
+    /// of a query with the centers are not reused by the others, and the norms of the centers
+    /// are the ones the dataset keeps for all its points. Datasets that can't measure the
+    /// distance between two queries (see [`MetricData::distance_between`]) only reuse exact
+    /// duplicates.
+    ///
+    /// If the query cache is enabled (see [`enable_query_cache()`]), queries already answered
+    /// by the same index with the same parameters in a previous batch are read from the cache,
+    /// and new results are written to it at the end of the batch. A reranker or a delta policy
+    /// can't be fingerprinted, so the cache is bypassed while one is set.
+    ///
+    /// # Parameters
+    /// - `queries`: Matrix with one query per row, same dimensionality as dataset points
+    ///
+    /// # Returns
+    /// One vector of (distance, index) pairs per query, in the same order as the rows
+    ///
+    /// # Errors
+    /// - `ClusteredIndexError::DimensionMismatch` if the queries don't have the dimensions of the
+    ///   dataset, or the input dimensions of the projection
+    /// - `ClusteredIndexError::ResultDBError` if the query cache cannot be read or written
+    /// - Any error returned by [`search()`]
+    pub(crate) fn search_batch<S>(
+        &mut self,
+        queries: &ArrayBase<S, Ix2>,
+    ) -> Result<Vec<Vec<(f32, usize)>>>
+    where
+        S: Data<Elem = T::DataType>,
+    {
+        self.search_batch_with(queries, &SearchParams::default())
+    }
+
+    /// Searches for the k nearest neighbors of every row of `queries` with the per-query
+    /// options of `params`, see [`search_batch()`] and [`search_with()`](Self::search_with).
+    ///
+    /// The query cache is keyed by the k of `params`, and bypassed if it excludes points or
+    /// bounds their distance.
+    ///
+    /// # Errors
+    /// Same as [`search_batch()`]
+    pub(crate) fn search_batch_with<S>(
+        &mut self,
+        queries: &ArrayBase<S, Ix2>,
+        params: &SearchParams,
+    ) -> Result<Vec<Vec<(f32, usize)>>>
+    where
+        S: Data<Elem = T::DataType>,
+    {
+        self.check_query_dimensions(queries.ncols())?;
+        // rows that are not contiguous, e.g. of a Fortran-order matrix, are copied
+        let rows: Vec<Cow<'_, [T::DataType]>> = queries
+            .rows()
+            .into_iter()
+            .map(|query| query.to_slice().map_or_else(|| query.to_vec().into(), Cow::Borrowed))
+            .collect();
+
+        // groups of near-duplicates, only those of several queries keep the bounds of the first
+        let prepared: Vec<Cow<'_, [T::DataType]>> =
+            rows.iter().map(|query| self.prepare_query(query)).collect::<Result<_>>()?;
+        let near_keys: Vec<u64> = prepared.iter().map(|query| near_duplicate_key(query)).collect();
+        let mut group_sizes: HashMap<u64, usize> = HashMap::new();
+        for &key in &near_keys {
+            *group_sizes.entry(key).or_default() += 1;
+        }
+        let mut anchors: HashMap<u64, BatchAnchor<T::DataType>> = HashMap::new();
+
+        let mut results: Vec<Vec<(f32, usize)>> = Vec::with_capacity(queries.nrows());
+        let mut seen: HashMap<u64, usize> = HashMap::new();
+        let mut duplicates = 0;
+        let mut cache_hits = 0;
+        let mut new_entries = Vec::new();
+        let parameters = match &self.query_cache {
+            Some(_)
+                if self.reranker.is_none()
+                    && self.delta_policy.is_none()
+                    && params.exclude.is_empty()
+                    && params.max_distance.is_none() =>
+            {
+                Some(self.search_parameters(params.k.unwrap_or(self.config.k))?)
+            }
+            _ => None,
+        };
+
+        for (i, query) in rows.iter().map(|query| &**query).enumerate() {
+            let mut hasher = DefaultHasher::new();
+            for &x in query {
+                Into::<f64>::into(x).to_bits().hash(&mut hasher);
+            }
+            let key = hasher.finish();
+
+            if let Some(&previous) = seen.get(&key) {
+                if &*rows[previous] == query {
+                    duplicates += 1;
+                    if let Some(metrics) = &mut self.metrics {
+                        metrics.new_query();
+                    }
+                    self.finish_query_metrics();
+                    results.push(results[previous].clone());
+                    continue;
+                }
+            }
+            seen.entry(key).or_insert(i);
+
+            if let (Some(cache), Some(parameters)) = (self.query_cache(), &parameters) {
+                let query_bytes = query_to_bytes(query);
+                if let Some(cached) = cache.get(parameters, &query_bytes)? {
+                    cache_hits += 1;
+                    if let Some(metrics) = &mut self.metrics {
+                        metrics.new_query();
+                    }
+                    self.finish_query_metrics();
+                    results.push(cached);
+                    continue;
+                }
+                let shared = group_sizes[&near_keys[i]] > 1;
+                let result = self.search_near_duplicate(&prepared[i], params, near_keys[i], shared, &mut anchors)?;
+                new_entries.push((query_bytes, result.clone()));
+                results.push(result);
+                continue;
+            }
+
+            let shared = group_sizes[&near_keys[i]] > 1;
+            results.push(self.search_near_duplicate(&prepared[i], params, near_keys[i], shared, &mut anchors)?);
+        }
+
+        if let (Some(cache), Some(parameters)) = (self.query_cache(), &parameters) {
+            cache.put_many(parameters, &new_entries)?;
+        }
+
+        debug!(
+            "Batch of {} queries, {} answered from duplicates, {} from the query cache, {} groups of near-duplicates",
+            queries.nrows(),
+            duplicates,
+            cache_hits,
+            anchors.len()
+        );
+
+        Ok(results)
+    }
+
+    /// Searches the prepared `query` of a batch from the bounds of `anchors[key]`, the first
+    /// query searched among its near-duplicates, if any. Otherwise, if other queries of the
+    /// batch are `shared` in its group, the bounds of `query` are kept as the anchor of the group.
+    fn search_near_duplicate(
+        &mut self,
+        query: &[T::DataType],
+        params: &SearchParams,
+        key: u64,
+        shared: bool,
+        anchors: &mut HashMap<u64, BatchAnchor<T::DataType>>,
+    ) -> Result<Vec<(f32, usize)>> {
+        let order = anchors.get(&key).and_then(|anchor| self.seeded_probe_order(query, anchor));
+        let (results, order) = self.search_recorded(query, params, order)?;
+        if shared {
+            anchors.entry(key).or_insert_with(|| BatchAnchor {
+                query: query.to_vec(),
+                bounds: order.metric_bounds(&self.data),
+            });
+        }
+        Ok(results)
+    }
+
+    /// Searches for the k nearest neighbors of every row of `queries` as with
+    /// [`search_batch()`], returning the ids and the distances as matrices in the layout of
+    /// ann-benchmarks.
+    ///
+    /// # Returns
+    /// Two `queries.nrows() x k` matrices, the ids and the distances of the neighbors of each
+    /// query in its row, closest first. Rows with fewer than k results are padded with
+    /// [`NO_NEIGHBOR`] ids and infinite distances.
+    ///
+    /// # Errors
+    /// Any error returned by [`search_batch()`]
+    pub(crate) fn search_batch_matrix<S>(
+        &mut self,
+        queries: &ArrayBase<S, Ix2>,
+    ) -> Result<(Array2<u32>, Array2<f32>)>
+    where
+        S: Data<Elem = T::DataType>,
+    {
+        let results = self.search_batch(queries)?;
+        Ok(result_matrices(&results, self.config.k))
+    }
+
+    /// Searches for the k nearest neighbors of every row of `queries`, probing each cluster
+    /// once for all the queries that need it.
+    ///
+    /// Queries advance through their clusters in rounds: in every round, each query not yet
+    /// terminated moves to its next closest cluster, and the queries landing on the same
+    /// cluster are searched one after the other, while its index and points are hot in cache.
+    /// Every query probes the same clusters in the same order as with [`search()`], so the
+    /// results are the same.
+    ///
+    /// Per-query metrics follow one query at a time, so with metrics enabled the queries are
+    /// searched one by one instead. Duplicates and the query cache are not looked up, see
+    /// [`search_batch()`]. The distance computations of the whole batch are reported by
+    /// [`last_distance_computations()`](Self::last_distance_computations).
+    ///
+    /// # Errors
+    /// - `ClusteredIndexError::DimensionMismatch` if the queries don't have the dimensions of the
+    ///   dataset, or the input dimensions of the projection
+    /// - Any error returned by [`search()`]
+    pub(crate) fn search_batch_grouped<S>(
+        &mut self,
+        queries: &ArrayBase<S, Ix2>,
+    ) -> Result<Vec<Vec<(f32, usize)>>>
+    where
+        S: Data<Elem = T::DataType>,
+    {
+        self.check_query_dimensions(queries.ncols())?;
+        if self.metrics.is_some() {
+            return queries
+                .rows()
+                .into_iter()
+                .map(|query| self.search(&query.to_vec()))
+                .collect();
+        }
+
+        let queries: Vec<Vec<T::DataType>> = queries
+            .rows()
+            .into_iter()
+            .map(|query| self.prepare_query(&query.to_vec()).map(Cow::into_owned))
+            .collect::<Result<_>>()?;
+
+        self.last_distance_computations = 0;
+        let heaps = self.search_grouped(&queries, self.candidates_per_query(self.config.k))?;
+        Ok(queries
+            .iter()
+            .zip(heaps)
+            .map(|(query, heap)| {
+                let results = self.finalize_results(query, heap.into_sorted_vec());
+                let results = self.diversify(results);
+                self.report_duplicates(results, &[], self.config.k)
+            })
+            .collect())
+    }
+
+    /// Top-k of the `k` nearest neighbors of every query, probing each cluster once for all the
+    /// queries that need it, see [`search_batch_grouped()`](Self::search_batch_grouped). The
+    /// queries are already projected, and the distance computations are added to
+    /// [`last_distance_computations()`](Self::last_distance_computations).
+    fn search_grouped(&mut self, queries: &[Vec<T::DataType>], k: usize) -> Result<Vec<TopK>> {
+        // clusters of every query from the closest, and the distance to the center of the next one
+        let mut orders: Vec<ProbeOrder> = queries.iter().map(|query| self.probe_order(query)).collect();
+        let mut center_distances = vec![0.0; queries.len()];
+        let mut outliers = vec![self.outlier_probes(); queries.len()];
+        let mut heaps: Vec<TopK> = queries
+            .iter()
+            .map(|_| TopK::new(k).with_ties(Ties::SmallestId))
+            .collect();
+
+        let mut groups: Vec<Vec<usize>> = vec![Vec::new(); self.clusters.len()];
+        let mut active: Vec<usize> = (0..queries.len()).collect();
+        let mut rounds = 0;
+        while !active.is_empty() {
+            // same exit condition as search()
+            active.retain(|&q| {
+                let Some((cluster_idx, center_distance)) = outliers[q].pop().or_else(|| {
+                    orders[q].next(
+                        &self.data,
+                        &self.clusters,
+                        &self.center_distances,
+                        self.hierarchy.as_ref(),
+                        &queries[q],
+                        heaps[q].kth_distance(),
+                    )
+                }) else {
+                    return false;
+                };
+                center_distances[q] = center_distance;
+                groups[cluster_idx].push(q);
+                true
+            });
+
+            for (cluster_idx, group) in groups.iter_mut().enumerate() {
+                for q in std::mem::take(group) {
+                    let (points_added, distance_computations) = self.probe_cluster(
+                        cluster_idx,
+                        &queries[q],
+                        center_distances[q],
+                        &[],
+                        &mut heaps[q],
+                        None,
+                    )?;
+                    self.last_distance_computations += distance_computations.total();
+
+                    let stats = &mut self.clusters[cluster_idx].search_stats;
+                    stats.probes += 1;
+                    stats.candidates += points_added;
+                }
+            }
+            rounds += 1;
+        }
+        self.last_distance_computations += orders.iter().map(|order| order.distance_computations()).sum::<usize>();
+        debug!("Batch of {} queries searched in {} rounds", queries.len(), rounds);
+
+        Ok(heaps)
+    }
+
+    /// Computes the `k` nearest neighbors of every point of the dataset.
+    ///
+    /// The points are searched cluster by cluster, in batches of at most [`KNN_GRAPH_BATCH`]
+    /// members of the same cluster: the queries of a batch start from the same cluster and
+    /// mostly probe the same neighbors, so every cluster index is searched by many queries in a
+    /// row, as with [`search_batch_grouped()`](Self::search_batch_grouped). Every point is searched
+    /// for `k + 1` neighbors and the point itself is left out, its duplicates are kept.
+    ///
+    /// The neighbors are as accurate as those of [`search()`] with the same parameters, and are
+    /// reranked the same way. Per-query metrics are not recorded; the distance computations of the
+    /// whole graph are reported by [`last_distance_computations()`](Self::last_distance_computations).
+    ///
+    /// # Errors
+    /// - `ClusteredIndexError::ConfigError` if `k` is 0
+    /// - `ClusteredIndexError::IndexNotFound` if the index is not built
+    /// - Any error returned by [`search()`]
+    pub(crate) fn knn_graph(&mut self, k: usize) -> Result<KnnGraph> {
+        if k == 0 {
+            return Err(ClusteredIndexError::ConfigError("k must be at least 1".to_string()));
+        }
+        if self.clusters.is_empty() {
+            return Err(ClusteredIndexError::IndexNotFound());
+        }
+
+        self.last_distance_computations = 0;
+        let mut lists = vec![Vec::new(); self.data.num_points()];
+        let members: Vec<Vec<usize>> = self.clusters.iter().map(|c| c.assignment.to_vec()).collect();
+        for batch in members.iter().flat_map(|members| members.chunks(KNN_GRAPH_BATCH)) {
+            let queries: Vec<Vec<T::DataType>> = batch
+                .iter()
+                .map(|&p| self.data.get_point(p).into_owned())
+                .collect();
+            let heaps = self.search_grouped(&queries, k + 1)?;
+            for ((&p, query), heap) in batch.iter().zip(&queries).zip(heaps) {
+                let neighbors = self.finalize_results(query, heap.into_sorted_vec());
+                let Some(duplicates) = &self.duplicates else {
+                    lists[p] = without_point(neighbors, p, k);
+                    continue;
+                };
+                // the copies of p have the same neighbors, p and the other copies among them
+                let neighbors = duplicates.expand(neighbors, &[], k + 1 + duplicates.aliases(p).len());
+                for &copy in duplicates.aliases(p) {
+                    lists[copy] = without_point(neighbors.clone(), copy, k);
+                }
+                lists[p] = without_point(neighbors, p, k);
+            }
+        }
+        debug!(
+            "k-NN graph of {} points computed with {} distance computations",
+            lists.len(),
+            self.last_distance_computations
+        );
+
+        Ok(KnnGraph::from_lists(k, lists))
+    }
+
+    /// Computes a fingerprint of the content of the index.
+    ///
+    /// The fingerprint covers the dataset, the clusters and the per-cluster indices. Together
+    /// with the [`search_parameters()`], two indices share it only if they return the same
+    /// results for the same queries.
+    ///
+    /// # Errors
+    /// Returns `ClusteredIndexError::SerializeError` if the clusters or a cluster index cannot be serialized
+    pub(crate) fn content_fingerprint(&self) -> Result<u64>
+    {
+        let mut hasher = Fnv64::new();
+
+        for i in 0..self.data.num_points() {
+            hasher.write(&query_to_bytes(&self.data.get_point(i)));
+        }
+
+        let clusters_json = serde_json::to_string(&self.clusters)
+            .map_err(|e| ClusteredIndexError::SerializeError(e.to_string()))?;
+        hasher.write(clusters_json.as_bytes());
+
+        for index in self.puffinn_indices.iter().flatten() {
+            let bytes = index
+                .to_bytes()
+                .map_err(ClusteredIndexError::SerializeError)?;
+            hasher.write(&bytes);
+        }
+
+        Ok(hasher.finish())
+    }
+
+    /// Serializes every configuration parameter except the metrics output and run tags, with
+    /// `k` neighbors per query, the part of the cache key that changes with `set_k()`,
+    /// `set_delta()` and the other setters.
+    fn search_parameters(&self, k: usize) -> Result<Vec<u8>>
+    {
+        let mut config = self.config.clone();
+        config.k = k;
+        config.metrics_output = MetricsOutput::None;
+        config.run_tags.clear();
+        serde_json::to_vec(&config).map_err(|e| ClusteredIndexError::SerializeError(e.to_string()))
+    }
+
+    /// Enables the on-disk cache of [`search_batch()`] results, stored in the SQLite database at `cache_path`.
+    ///
+    /// Cached results are keyed by the [`content_fingerprint()`] of the index and by the
+    /// [`search_parameters()`] of each batch, so the same database can be shared by several
+    /// indices, rebuilding the index invalidates its entries, and changing k or delta between
+    /// batches reads the entries of the new parameters. The content fingerprint reads the whole
+    /// index, so this should be called once after building or loading. Inserting points or
+    /// repartitioning the clusters invalidates the entries as well, without reading the index
+    /// again, and the entries written afterwards are not found once the index is reloaded.
+    ///
+    /// # Errors
+    /// - `ClusteredIndexError::ResultDBError` if the database cannot be opened
+    /// - Any error returned by [`content_fingerprint()`]
+    pub(crate) fn enable_query_cache(&mut self, cache_path: &str) -> Result<()>
+    {
+        let content = self.content_fingerprint()?;
+        info!("Using query cache {} for index {:016x}", cache_path, content);
+        self.query_cache = Some(Mutex::new(QueryCache::open(cache_path, content)?));
+        Ok(())
+    }
+
+    /// The query cache, if enabled. Its lock only makes the index `Sync`: the cache is
+    /// borrowed mutably, never locked.
+    fn query_cache(&mut self) -> Option<&mut QueryCache> {
+        self.query_cache
+            .as_mut()
+            .map(|cache| cache.get_mut().unwrap_or_else(|e| e.into_inner()))
+    }
+
+    /// Saves metrics from a search run to the target of `metrics_output`.
+    ///
+    /// # Parameters
+    /// - `db_path`: Path to SQLite database file, only used by `MetricsOutput::DB`. The
+    ///   database and its tables are created if they don't exist
+    /// - `granularity`: Level of detail for metrics (Run/Query/Cluster)
+    /// - `recall`: Ground truth and results of the run, by distance or by id. Without
+    ///   it the recall is left empty and the throughput counts the queries recorded by the metrics
+    /// - `total_search_time`: Total time spent on all queries
+    ///
+    /// # Errors
+    /// - `ClusteredIndexError::MetricsError` if metrics are not enabled, the CSV directory
+    ///   doesn't exist, or the JSON or CSV files cannot be written
+    /// - `ClusteredIndexError::ResultDBError` for database connection/operation errors
+    pub(crate) fn save_metrics(
+        &mut self,
+        db_path: String,
+        granularity: MetricsGranularity,
+        recall: Option<RecallInput>,
+        total_search_time: &Duration,
+    ) -> Result<()> {
+        let memory = self.memory_usage();
+        let Some(metrics) = &mut self.metrics else {
+            return Err(ClusteredIndexError::MetricsError(
+                "run metrics are not enabled".to_string(),
+            ));
+        };
+        metrics.log_memory_usage(memory);
+
+        match &self.config.metrics_output {
+            MetricsOutput::DB => {
+                // Connect to the database, created with the metrics tables if it doesn't exist
+                let mut conn = Connection::open(db_path)
+                    .map_err(|e| ClusteredIndexError::ResultDBError(e.to_string()))?;
+                metrics.save_metrics(
+                    &mut conn,
+                    granularity,
+                    &self.clusters,
+                    recall,
+                    total_search_time,
+                )
+            }
+            MetricsOutput::Json(file_path) => metrics.save_metrics_json(
+                file_path,
+                granularity,
+                &self.clusters,
+                recall,
+                total_search_time,
+            ),
+            MetricsOutput::Csv(dir) => {
+                if fs::metadata(dir).map_or(true, |m| !m.is_dir()) {
+                    return Err(ClusteredIndexError::MetricsError(format!(
+                        "directory {} doesn't exist",
+                        dir
+                    )));
+                }
+
+                metrics.save_metrics_csv(
+                    dir,
+                    granularity,
+                    &self.clusters,
+                    recall,
+                    total_search_time,
+                )
+            }
+            MetricsOutput::None => Err(ClusteredIndexError::MetricsError(
+                "run metrics are not enabled".to_string(),
+            )),
+        }
+    }
+
+    /// Serializes the index to a single binary file.
+    ///
+    /// Unlike [`serialize()`], the binary format doesn't depend on HDF5 and can be
+    /// loaded through a read-only memory map with [`new_from_mmap()`].
+    ///
+    /// # Parameters
+    /// - `directory`: Directory where the index file will be saved
+    ///
+    /// # File naming
+    /// The file is named: `index_{dataset_name}_k{clusters_factor}_L{num_tables}.bin`
+    ///
+    /// # Errors
+    /// Returns `ClusteredIndexError::SerializeError` if:
+    /// - Directory doesn't exist
+    /// - Serialization of any PUFFINN index fails
+    /// - Writing the file fails
+    pub(crate) fn serialize_binary(&self, directory: &str) -> Result<()> {
+        if fs::metadata(directory).is_err() {
+            return Err(ClusteredIndexError::SerializeError(format!(
+                "directory {} doesn't exist",
+                directory
+            )));
+        }
+
+        let file_path = self.binary_file_path(directory);
+        write_binary(&file_path, &self.config, &self.clusters, self.hierarchy.as_ref(), &self.blobs()?)
+            .map_err(ClusteredIndexError::SerializeError)
+    }
+
+    /// Serializes the index as the collection `name` of the file at `file_path`, a single
+    /// file holding several named indexes, e.g. one per embedding model or tenant.
+    ///
+    /// Each collection is saved in the format of [`serialize_binary()`]. A collection with
+    /// the same name is replaced, the others are kept; the file is created if it doesn't
+    /// exist. The file is rewritten under a temporary name and renamed, so a failed write
+    /// leaves the previous collections untouched.
+    ///
+    /// # Errors
+    /// Returns `ClusteredIndexError::SerializeError` if:
+    /// - The file exists but is not a collection file
+    /// - Serialization of any PUFFINN index fails
+    /// - Writing the file fails
+    pub(crate) fn serialize_collection(&self, file_path: &str, name: &str) -> Result<()> {
+        let mut bytes = Vec::new();
+        write_binary_to(&mut bytes, &self.config, &self.clusters, self.hierarchy.as_ref(), &self.blobs()?)
+            .map_err(ClusteredIndexError::SerializeError)?;
+        write_collection(file_path, name, &bytes).map_err(ClusteredIndexError::SerializeError)
+    }
+
+    /// Serialized PUFFINN index of each cluster, `None` for brute force clusters
+    fn blobs(&self) -> Result<Vec<Option<Vec<u8>>>> {
+        self.puffinn_indices
+            .iter()
+            .map(|index| index.as_ref().map(|i| i.to_bytes()).transpose())
+            .collect::<std::result::Result<Vec<_>, String>>()
+            .map_err(ClusteredIndexError::SerializeError)
+    }
+
+    /// Path of the file written by [`serialize_binary()`] in `directory`.
+    pub(crate) fn binary_file_path(&self, directory: &str) -> String {
+        format!(
+            "{}/index_{}_k{:.2}_L{}.bin",
+            directory,
+            self.config.dataset_name,
+            self.config.num_clusters_factor,
+            self.config.num_tables
+        )
+    }
+
+    /// Number of distance computations of the last search, centers included.
+    ///
+    /// Unlike [`get_distance_computations()`] it doesn't need run metrics to be enabled.
+    pub fn last_distance_computations(&self) -> usize {
+        self.last_distance_computations
+    }
+
+    /// Replaces the configuration with `config` and starts a new run of metrics, so that an
+    /// index loaded from a file searches and reports as if it had been built from `config`.
+    ///
+    /// # Errors
+    /// Returns `ClusteredIndexError::ConfigError` if a parameter of `config` is out of range or
+    /// `config` builds a different index
+    pub(crate) fn adopt_config(&mut self, config: Config) -> Result<()> {
+        config.validate()?;
+        let config = Config {
+            storage: self.data.precision(),
+            weights: self.data.weights().map(<[f32]>::to_vec),
+            ..config
+        };
+        if config.build_parameters() != self.config.build_parameters() {
+            return Err(ClusteredIndexError::ConfigError(
+                "the configuration builds a different index".to_string(),
+            ));
+        }
+
+        self.metrics = (config.metrics_output.is_enabled() || !self.callbacks.on_query.is_empty())
+            .then(|| RunMetrics::new(config.clone(), self.data.num_points()));
+        self.config = config;
+        self.check_delta_policy();
+        Ok(())
+    }
+
+    /// Sets the number of nearest neighbors returned by the next searches.
+    ///
+    /// # Errors
+    /// Returns `ClusteredIndexError::ConfigError` if `k` is 0 or more than the MMR candidates
+    /// of [`Config::mmr`]
+    pub fn set_k(&mut self, k: usize) -> Result<()> {
+        self.update_config(|config| config.k = k)
+    }
+
+    /// Sets the expected recall of the next searches, no rebuild is needed.
+    ///
+    /// # Errors
+    /// Returns `ClusteredIndexError::ConfigError` if `delta` is not in (0, 1]
+    pub fn set_delta(&mut self, delta: f32) -> Result<()> {
+        self.update_config(|config| config.delta = delta)
+    }
+
+    /// Sets the most clusters probed by the next searches, `None` to lift the limit, see
+    /// [`Config::max_clusters_probed`]. No rebuild is needed.
+    ///
+    /// # Errors
+    /// Returns `ClusteredIndexError::ConfigError` if `max_clusters_probed` is `Some(0)`
+    pub fn set_max_clusters_probed(&mut self, max_clusters_probed: Option<usize>) -> Result<()> {
+        self.update_config(|config| config.max_clusters_probed = max_clusters_probed)?;
+        self.check_delta_policy();
+        Ok(())
+    }
+
+    /// Applies `update` to a copy of the configuration and keeps it if it passes
+    /// [`Config::validate`], for the setters of the search parameters.
+    ///
+    /// # Errors
+    /// Returns `ClusteredIndexError::ConfigError` naming the parameter out of range, the
+    /// configuration is then left as it was
+    fn update_config(&mut self, update: impl FnOnce(&mut Config)) -> Result<()> {
+        let mut config = self.config.clone();
+        update(&mut config);
+        config.validate()?;
+        self.config = config;
+        Ok(())
+    }
+
+    /// Makes the next searches exact, scanning the probed clusters instead of searching their
+    /// index, see [`Config::exact`]. No rebuild is needed.
+    pub fn set_exact(&mut self, exact: bool) {
+        self.config.exact = exact;
+        self.check_delta_policy();
+    }
+
+    /// Warns if the delta policy relies on every cluster that may hold a neighbor being probed,
+    /// and the configuration limits the clusters probed or prunes them with a smaller radius.
+    fn check_delta_policy(&self) {
+        let Some(policy) = &self.delta_policy else {
+            return;
+        };
+        if policy.needs_every_cluster()
+            && (self.config.max_clusters_probed.is_some() || self.pruning_radius() != PruningRadius::Max)
+        {
+            warn!(
+                "The delta policy needs every cluster that may hold a neighbor to be probed, but max_clusters_probed is {:?} and pruning_radius {:?}: its recall target may not hold",
+                self.config.max_clusters_probed,
+                self.config.pruning_radius
+            );
+        }
+    }
+
+    /// Radius the clusters are pruned with, the largest one for an exact search
+    fn pruning_radius(&self) -> PruningRadius {
+        if self.config.exact {
+            PruningRadius::Max
+        } else {
+            self.config.pruning_radius
+        }
+    }
+
+    /// Lower bound on the distance from a query to the points within `radius` of a center at
+    /// `center_distance`, from the triangle inequality in the metric of
+    /// [`MetricData::distance_to_metric`] as the search prunes the clusters. Zero if the query
+    /// may lie within the radius
+    fn lower_bound(&self, center_distance: f32, radius: f32) -> f32 {
+        let metric = self.data.distance_to_metric(center_distance) - self.data.distance_to_metric(radius);
+        if metric > 0.0 {
+            self.data.metric_to_distance(metric)
+        } else {
+            0.0
+        }
+    }
+
+    /// Most clusters a query probes, all of those that may hold a neighbor for an exact search
+    fn max_probes(&self) -> usize {
+        match self.config.max_clusters_probed {
+            Some(max_probes) if !self.config.exact => max_probes,
+            _ => usize::MAX,
+        }
+    }
+
+    /// Returns the configuration of the index.
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Creates a new Clustered Index from a binary file written by [`serialize_binary()`].
+    ///
+    /// The file is memory mapped read-only. The members of the clusters are read in place
+    /// from the mapping, and copied into memory of the process only if their cluster changes,
+    /// so every process mapping the file shares them. The index of every cluster is restored
+    /// from its range of the mapping with [`ClusterBackend::from_mapped`]: only backends
+    /// reading their arrays in place, as the pure-Rust
+    /// [`CrossPolytopeIndex`](crate::lsh::CrossPolytopeIndex), share their tables too. The
+    /// default PUFFINN backend rebuilds its hash tables in memory of each process.
+    ///
+    /// # Parameters
+    /// - `data`: The dataset implementing required traits, must match the original dataset used to build the index
+    /// - `file_path`: Path to the binary index file
+    ///
+    /// # Errors
+    /// Returns `ClusteredIndexError::ConfigError` if:
+    /// - The file doesn't exist or can't be mapped
+    /// - The file is not a binary index or has an unsupported version
+    /// - The serialized data is corrupted or incompatible
+    ///
+    /// Returns `ClusteredIndexError::DeserializeError` if the PUFFINN index of a cluster is
+    /// truncated or can't be decoded
+    pub(crate) fn new_from_mmap(data: T, file_path: &str) -> Result<Self> {
+        let file = fs::File::open(file_path).map_err(|e| {
+            ClusteredIndexError::ConfigError(format!("file {}: {}", file_path, e))
+        })?;
+
+        // SAFETY: the map is read-only, the file must not be modified or truncated by another
+        // process while an index restored from it is alive
+        let mmap = unsafe { Mmap::map(&file) }
+            .map_err(|e| ClusteredIndexError::ConfigError(e.to_string()))?;
+
+        Self::from_binary(data, MappedBytes::new(mmap))
+    }
+
+    /// Creates a new Clustered Index from the collection `name` of a file written by
+    /// [`serialize_collection()`], memory mapped as in [`new_from_mmap()`].
+    ///
+    /// # Parameters
+    /// - `data`: The dataset implementing required traits, must match the original dataset used to build the index
+    /// - `file_path`: Path to the collection file
+    /// - `name`: Name of the collection
+    ///
+    /// # Errors
+    /// Returns `ClusteredIndexError::ConfigError` if:
+    /// - The file doesn't exist or can't be mapped
+    /// - The file is not a collection file or has no collection `name`
+    /// - The serialized data is corrupted or incompatible
+    ///
+    /// Returns `ClusteredIndexError::DeserializeError` if the PUFFINN index of a cluster is
+    /// truncated or can't be decoded
+    pub(crate) fn new_from_collection(data: T, file_path: &str, name: &str) -> Result<Self> {
+        let file = fs::File::open(file_path).map_err(|e| {
+            ClusteredIndexError::ConfigError(format!("file {}: {}", file_path, e))
+        })?;
+
+        // SAFETY: as in new_from_mmap
+        let mmap = MappedBytes::new(
+            unsafe { Mmap::map(&file) }.map_err(|e| ClusteredIndexError::ConfigError(e.to_string()))?,
+        );
+        let bytes = find_collection(&mmap, name).map_err(ClusteredIndexError::ConfigError)?;
+
+        Self::from_binary(data, mmap.subslice(bytes))
+    }
+
+    /// Decodes an index in the format of [`serialize_binary()`], restoring the index of every
+    /// cluster from its range of `bytes`
+    fn from_binary(data: T, bytes: MappedBytes) -> Result<Self> {
+        let (mut header, member_section, blob_section) =
+            parse_header(&bytes).map_err(ClusteredIndexError::ConfigError)?;
+        let member_section = bytes.subslice(member_section);
+        let blob_section = bytes.subslice(blob_section);
+
+        // the members are read in place, shared with every process mapping the file
+        for (cluster, range) in header.clusters.iter_mut().zip(member_ranges(&header.members)) {
+            cluster.assignment =
+                Members::mapped(member_section.slice(range)).map_err(ClusteredIndexError::ConfigError)?;
+        }
+
+        if header.blobs.len() != header.clusters.len() {
+            return Err(ClusteredIndexError::ConfigError(format!(
+                "{} clusters but {} PUFFINN entries",
+                header.clusters.len(),
+                header.blobs.len()
+            )));
+        }
+
+        if let Some(position) = truncated_blob(&header, &blob_section) {
+            return Err(ClusteredIndexError::DeserializeError {
+                cluster: header.clusters[position].idx,
+                message: "index truncated".to_string(),
+            });
+        }
+        let puffinn_indices = header
+            .blobs
+            .iter()
+            .zip(&header.clusters)
+            .map(|(location, cluster)| {
+                location
+                    .map(|(offset, len)| {
+                        let start = offset as usize;
+                        B::from_mapped(blob_section.slice(start..start + len as usize)).map_err(|message| {
+                            ClusteredIndexError::DeserializeError {
+                                cluster: cluster.idx,
+                                message,
+                            }
+                        })
+                    })
+                    .transpose()
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let config = header.config;
+        check_storage(&config, &data);
+        check_weights(&config, &data)?;
+        check_points(&header.clusters, &data)?;
+        // the codes are a function of the dataset, they are not saved with the index
+        let quantizer = config.scalar_quantization.then(|| ScalarQuantizer::train(&data));
+        let product_quantizer = config
+            .pq
+            .map(|params| ProductQuantizer::train(&data, params))
+            .transpose()
+            .map_err(ClusteredIndexError::ConfigError)?;
+        let metrics = config.metrics_output.is_enabled()
+            .then(|| RunMetrics::new(config.clone(), data.num_points()));
+        let duplicates = config.dedup.then(|| Duplicates::find(&data));
+        let center_distances = CenterDistances::compute(&data, &header.clusters);
+
+        Ok(Self {
+            data,
+            clusters: header.clusters,
+            center_distances,
+            hierarchy: header.hierarchy,
+            config,
+            puffinn_indices,
+            metrics,
+            query_cache: None,
+            build_report: None,
+            clustering: ClusteringSummary::default(),
+            last_distance_computations: 0,
+            callbacks: MetricsCallbacks::default(),
+            build_observer: None,
+            cancellation: None,
+            reranker: None,
+            delta_policy: None,
+            quantizer,
+            product_quantizer,
+            duplicates,
+            wal: None,
+            rebuilt_clusters: Vec::new(),
+        })
+    }
+
+    /// Returns the total number of distance computations for the current query.
+    ///
+    /// # Returns
+    /// Total number of distance computations if metrics are enabled
+    ///
+    /// # Errors
+    /// Returns `ClusteredIndexError::MetricsError` if metrics are not enabled
+    pub fn get_distance_computations(&self) -> Result<usize> {
+        // queries collected only for the callbacks are dropped once complete
+        if let Some(query) = self.metrics.as_ref().and_then(|m| m.current_query()) {
+            return Ok(query.distance_computations);
+        }
+
+        Err(ClusteredIndexError::MetricsError(
+            "run metrics are not enabled".to_string(),
+        ))
+    }
+
+    /// Order in which `query` probes the clusters, computing the distances to the centers as
+    /// needed if the hierarchy or the center distances are the ones of the current clusters,
+    /// all at once otherwise. Stops after [`Config::max_clusters_probed`] clusters, and prunes them with
+    /// the radius of [`Config::pruning_radius`]. The outlier clusters are left out, as they
+    /// are scanned first.
+    fn probe_order(&mut self, query: &[T::DataType]) -> ProbeOrder {
+        let (order, distance_computations) = self.probe_order_shared(query);
+        self.count_global_distance_computations(DistanceComputations {
+            pruning: distance_computations,
+            ..Default::default()
+        });
+        order
+    }
+
+    /// [`probe_order()`](Self::probe_order) through a shared reference, with the distances to
+    /// the centers computed up front instead of counting them
+    fn probe_order_shared(&self, query: &[T::DataType]) -> (ProbeOrder, usize) {
+        let max_probes = self.max_probes();
+        let radii = self.pruning_radii();
+        let hierarchy = self.hierarchy.as_ref().filter(|h| h.matches(&self.clusters));
+        let mut distance_computations = 0;
+        let mut order = if let Some(hierarchy) = hierarchy {
+            ProbeOrder::hierarchical(&self.data, hierarchy, query, radii, max_probes)
+        } else if self.center_distances.matches(&self.clusters) {
+            ProbeOrder::bounded(radii, max_probes)
+        } else {
+            distance_computations = self.clusters.len();
+            ProbeOrder::sorted(self.sort_cluster_indices_by_distance(query), radii, max_probes)
+        };
+        for (position, _) in self.outlier_probes() {
+            order.exclude(position);
+        }
+        (order, distance_computations)
+    }
+
+    /// Order in which `query` probes the clusters, starting from the center bounds of `anchor`,
+    /// a nearby query of the same batch, lowered by its distance to `query`. The clusters are
+    /// probed as with [`probe_order()`](Self::probe_order), only computing the distances to the
+    /// centers whose bound is the smallest left. `None` if the dataset can't measure the
+    /// distance between two queries.
+    fn seeded_probe_order(&self, query: &[T::DataType], anchor: &BatchAnchor<T::DataType>) -> Option<(ProbeOrder, usize)> {
+        let offset = self.data.distance_to_metric(self.data.distance_between(&anchor.query, query)?);
+        let bounds = anchor.bounds.iter().map(|&bound| (bound - offset).max(0.0)).collect();
+        let pivots = self.center_distances.matches(&self.clusters);
+        let mut order = ProbeOrder::seeded(bounds, self.pruning_radii(), self.max_probes(), pivots);
+        for (position, _) in self.outlier_probes() {
+            order.exclude(position);
+        }
+        Some((order, 1))
+    }
+
+    /// Radius pruning every cluster, see [`Config::pruning_radius`]
+    fn pruning_radii(&self) -> Vec<f32> {
+        self.clusters
+            .iter()
+            .map(|cluster| cluster.pruning_radius(self.pruning_radius()))
+            .collect()
+    }
+
+    /// Sorts clusters by their distance from the query point.
+    ///
+    /// # Implementation
+    /// 1. Computes distance from query to each cluster center
+    /// 2. Sorts clusters by these distances in ascending order
+    /// 3. Returns indices of clusters in sorted order, together with the distances
+    ///    so that the exit condition of the search doesn't need to recompute them
+    ///
+    /// This ordering is crucial for early termination and efficiency:
+    /// - Closer clusters are more likely to contain nearest neighbors
+    /// - Allows terminating search when minimum distance to next cluster exceeds current kth distance
+    ///
+    /// # Parameters
+    /// - `query`: Query point to compute distances against
+    ///
+    /// # Returns
+    /// Vector of (cluster index, center distance) pairs sorted by distance from query to cluster centers
+    fn sort_cluster_indices_by_distance(&self, query: &[T::DataType]) -> Vec<(usize, f32)> {
+        let mut cluster_distances: Vec<(usize, f32)> = self
+            .clusters
+            .iter()
+            .map(|cluster| {
+                let dist = self.data.distance_point(cluster.center_idx, query);
+                (cluster.idx, dist)
+            })
+            .collect();
+
+        cluster_distances.sort_by(|&(_, dist_a), &(_, dist_b)| {
+            dist_a
+                .partial_cmp(&dist_b)
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+
+        cluster_distances
+    }
+
+    /// Maps local indices from PUFFINN search results to global dataset indices.
+    ///
+    /// PUFFINN returns indices local to the subset of points in a cluster.
+    /// This function maps them back to indices in the original dataset using
+    /// the cluster's assignment vector.
+    ///
+    /// # Parameters
+    /// - `candidates`: Vector of local indices from PUFFINN search
+    /// - `cluster`: Cluster containing the mapping information
+    ///
+    /// # Returns
+    /// Vector of global dataset indices corresponding to the local indices
+    ///
+    /// # Errors
+    /// Returns `ClusteredIndexError::IndexOutOfBounds` if any local index
+    /// exceeds the cluster's size
+    fn map_candidates(&self, candidates: &[u32], cluster: &ClusterCenter) -> Result<Vec<usize>> {
+        candidates
+            .iter()
+            .map(|&local_idx| {
+                let local_idx = local_idx as usize;
+                if local_idx < cluster.assignment.len() {
+                    Ok(cluster.assignment[local_idx])
+                } else {
+                    Err(ClusteredIndexError::IndexOutOfBounds(
+                        local_idx,
+                        cluster.assignment.len(),
+                    ))
+                }
+            })
+            .collect::<Result<Vec<usize>>>()
+    }
+
+    /// Candidates of `ids` that can be closer to `query` than `threshold`, the k-th distance of
+    /// the top-k, by the lower bounds of the product quantizer. All of them without product
+    /// quantizer or before the top-k is full.
+    fn prune<'a>(&self, ids: &'a [usize], query: &[T::DataType], threshold: Option<f32>) -> Cow<'a, [usize]> {
+        let (Some(quantizer), Some(threshold)) = (&self.product_quantizer, threshold) else {
+            return Cow::Borrowed(ids);
+        };
+
+        let mut bounds = vec![0.0; ids.len()];
+        quantizer.lower_bounds(ids, &self.data.indexed_query(query), &mut bounds);
+        let kept: Vec<usize> = ids
+            .iter()
+            .zip(bounds)
+            .filter(|&(_, bound)| bound <= threshold || bound.is_nan())
+            .map(|(&p, _)| p)
+            .collect();
+        trace!("pruned {} of {} candidates", ids.len() - kept.len(), ids.len());
+        Cow::Owned(kept)
+    }
+
+    /// Checks that queries of `dimensions` components can be searched: they must have the
+    /// dimensions of the dataset, or the input dimensions of the projection if there is one.
+    ///
+    /// # Errors
+    /// `ClusteredIndexError::DimensionMismatch` otherwise
+    fn check_query_dimensions(&self, dimensions: usize) -> Result<()> {
+        let expected = self
+            .config
+            .projection
+            .as_ref()
+            .map_or_else(|| self.data.dimensions(), |projection| projection.input_dim());
+        if dimensions != expected {
+            return Err(ClusteredIndexError::DimensionMismatch { expected, got: dimensions });
+        }
+        Ok(())
+    }
+
+    /// `query` as searched: checked, scaled to unit norm if [`Config::normalize_queries`] is set
+    /// and projected if the index has a projection. Borrowed if it is searched as given.
+    ///
+    /// # Errors
+    /// - `ClusteredIndexError::DimensionMismatch` if the query doesn't have the dimensions of
+    ///   the dataset, or the input dimensions of the projection
+    /// - `ClusteredIndexError::DataError` if a component of the query is NaN or infinite, or if
+    ///   its norm is zero while the distance is angular or the queries are normalized
+    fn prepare_query<'q>(&self, query: &'q [T::DataType]) -> Result<Cow<'q, [T::DataType]>> {
+        self.check_query_dimensions(query.len())?;
+        let nonzero = self.config.normalize_queries || self.data.scale_invariant();
+        let norm = checked_norm(query, nonzero).map_err(|e| ClusteredIndexError::DataError(format!("query {}", e)))?;
+
+        let mut query = Cow::Borrowed(query);
+        if self.config.normalize_queries {
+            query = Cow::Owned(
+                query
+                    .iter()
+                    .map(|&x| T::DataType::from_f64(Into::<f64>::into(x) / norm))
+                    .collect(),
+            );
+        }
+        if let Some(projection) = &self.config.projection {
+            query = Cow::Owned(projection.transform_point(&query)?);
+        }
+        Ok(query)
+    }
+
+    /// Distances from `query` to the candidates `ids`, with the reranker if the batch is large
+    /// enough, otherwise from the quantized points if there are
+    fn candidate_distances(&self, ids: &[usize], query: &[T::DataType], out: &mut [f32]) -> Result<()> {
+        match (&self.reranker, &self.quantizer) {
+            (Some(reranker), _) if ids.len() >= reranker.min_batch() => reranker.distances(ids, query, out),
+            (_, Some(quantizer)) => {
+                quantizer.distances(ids, &self.data.indexed_query(query), out);
+                Ok(())
+            }
+            _ => {
+                self.data.distances_points(ids, query, out);
+                Ok(())
+            }
+        }
+    }
+
+    /// Performs brute force search within a cluster.
+    ///
+    /// Used for small clusters where building an index would be inefficient.
+    /// Computes distances to all points in the cluster and returns the k nearest.
+    ///
+    /// # Parameters
+    /// - `cluster`: Cluster to search in
+    /// - `query`: Query point
+    /// - `k`: Number of nearest neighbors to return
+    /// - `exclude`: Sorted points of the cluster to skip
+    ///
+    /// # Returns
+    /// Vector of (distance, index) pairs for the k nearest neighbors in the cluster,
+    /// sorted by distance, and the number of distances computed
+    ///
+    /// # Performance
+    /// Time complexity: O(cluster_size * dim) where dim is point dimensionality
+    fn brute_force_search(
+        &self,
+        cluster: &ClusterCenter,
+        query: &[T::DataType],
+        k: usize,
+        threshold: Option<f32>,
+        exclude: &[usize],
+    ) -> Result<(Vec<(f32, usize)>, usize)> {
+        let mut members = self.prune(&cluster.assignment, query, threshold);
+        if !exclude.is_empty() {
+            members = Cow::Owned(members.iter().copied().filter(|p| exclude.binary_search(p).is_err()).collect());
+        }
+        let mut distances = vec![0.0; members.len()];
+        self.candidate_distances(&members, query, &mut distances)?;
+
+        let mut priority_queue = TopK::new(k).with_ties(Ties::SmallestId);
+        let mut points_added = 0;
+        for (p, distance) in members.iter().zip(distances) {
+            if priority_queue.push(distance, *p) {
+                points_added += 1;
+            }
+        }
+
+        debug!("points added in brute force: {}", points_added);
+        Ok((priority_queue.into_sorted_vec(), members.len()))
+    }
+}
+
+impl<T> ClusteredIndex<T, PuffinnIndex>
+where
+    T: MetricData + IndexableSimilarity<T> + Subset,
+    <T as Subset>::Out: IndexableSimilarity<<T as Subset>::Out>,
+{
+    /// Creates a new Clustered Index by loading a previously serialized index from a file.
+    ///
+    /// # Parameters
+    /// - `data`: The dataset implementing required traits, must match the original dataset used to build the index
+    /// - `file_path`: Path to the HDF5 file containing the serialized index
+    ///
+    /// # Returns
+    /// A `ClusteredIndex` instance loaded from the file, ready to be used for searching. The
+    /// PUFFINN index of a cluster that is missing or corrupt is rebuilt from `data`, see
+    /// [`rebuilt_clusters()`](Self::rebuilt_clusters)
+    ///
+    /// # Errors
+    /// Returns `ClusteredIndexError::ConfigError` if:
+    /// - The file doesn't exist
+    /// - The file format is invalid
+    /// - The serialized data is corrupted or incompatible
+    ///
+    /// Returns `ClusteredIndexError::DeserializeError` if the index of a cluster can't be read
+    /// nor rebuilt from `data`
+    pub(crate) fn new_from_file(data: T, file_path: &str) -> Result<Self> {
+        let (config, clusters) = read_metadata(file_path)?;
+        check_storage(&config, &data);
+        check_weights(&config, &data)?;
+        check_points(&clusters, &data)?;
+        // the codes are a function of the dataset, they are not saved with the index
+        let quantizer = config.scalar_quantization.then(|| ScalarQuantizer::train(&data));
+        let product_quantizer = config
+            .pq
+            .map(|params| ProductQuantizer::train(&data, params))
+            .transpose()
+            .map_err(ClusteredIndexError::ConfigError)?;
+        let metrics = config.metrics_output.is_enabled()
+            .then(|| RunMetrics::new(config.clone(), data.num_points()));
+        let duplicates = config.dedup.then(|| Duplicates::find(&data));
+
+        // read puffinn indices, the unreadable ones are rebuilt once the index is assembled
+        let mut puffinn_indices = Vec::new();
+        let mut unreadable = Vec::new();
+        for (position, c) in clusters.iter().enumerate() {
+            if c.brute_force {
+                puffinn_indices.push(None);
+                continue;
+            }
+            match PuffinnIndex::new_from_file(file_path, &format!("index_{}", c.idx)) {
+                Ok(index) => puffinn_indices.push(Some(index)),
+                Err(e) => {
+                    warn!("Cannot read the index of cluster {}: {}", c.idx, e);
+                    puffinn_indices.push(None);
+                    unreadable.push((position, e));
+                }
+            }
+        }
+
+        let center_distances = CenterDistances::compute(&data, &clusters);
+        let hierarchy = read_hierarchy(file_path)?;
+
+        let mut index = Self {
+            data,
+            clusters,
+            center_distances,
+            hierarchy,
+            config,
+            puffinn_indices,
+            metrics,
+            query_cache: None,
+            build_report: None,
+            clustering: ClusteringSummary::default(),
+            last_distance_computations: 0,
+            callbacks: MetricsCallbacks::default(),
+            build_observer: None,
+            cancellation: None,
+            reranker: None,
+            delta_policy: None,
+            quantizer,
+            product_quantizer,
+            duplicates,
+            wal: None,
+            rebuilt_clusters: Vec::new(),
+        };
+        index.rebuild_unreadable(&unreadable)?;
+
+        Ok(index)
+    }
+
+    /// Serializes the index to an HDF5 file.
+    ///
+    /// Saves:
+    /// - Configuration parameters
+    /// - Cluster information (centers, assignments, radii)
+    /// - PUFFINN indices for each cluster
+    ///
+    /// # Parameters
+    /// - `directory`: Directory where the index file will be saved
+    ///
+    /// # File naming
+    /// The file is named: `index_{dataset_name}_k{clusters_factor}_L{num_tables}.h5`
+    ///
+    /// # Errors
+    /// Returns `ClusteredIndexError::SerializeError` if:
+    /// - Directory doesn't exist
+    /// - File creation fails
+    /// - Serialization of any component fails
+    pub(crate) fn serialize(&self, directory: &str) -> Result<()> {
+        if fs::metadata(directory).is_err() {
+            return Err(ClusteredIndexError::SerializeError(format!(
+                "directory {} doesn't exist",
+                directory
+            )));
+        }
+
+        let file_path = self.file_path(directory);
+        self.write_hdf5(&file_path)
+    }
+
+    /// Writes the HDF5 file of [`serialize()`](Self::serialize), closed when this returns
+    fn write_hdf5(&self, file_path: &str) -> Result<()> {
+        let file = File::create(file_path)
+            .map_err(|e| ClusteredIndexError::SerializeError(e.to_string()))?;
+
+        // write Config, without the run tags: they are free text describing a run, not the index
+        let mut config = self.config.clone();
+        config.run_tags.clear();
+        write_json_scalar::<VarLenAscii, _>(&file, "config", &config)?;
+
+        // write all ClusterCenter
+        write_json_scalar::<VarLenUnicode, _>(&file, "clusters", &self.clusters)?;
+
+        // write the cells of a two-level index
+        if let Some(hierarchy) = &self.hierarchy {
+            write_json_scalar::<VarLenAscii, _>(&file, "hierarchy", hierarchy)?;
+        }
+
+        // write all puffinn indexes
+        for (index_id, puffinn_index) in self.puffinn_indices.iter().enumerate() {
+            if let Some(index) = puffinn_index {
+                index
+                    .save_to_file(file_path, index_id)
+                    .map_err(ClusteredIndexError::SerializeError)?;
+            }
+        }
+
+        Ok(())
+    }
+
+
+    /// Path of the file written by [`serialize()`] in `directory`.
+    pub(crate) fn file_path(&self, directory: &str) -> String {
+        index_file_path(directory, &self.config)
+    }
+}
+
+/// Writes `value` as JSON to the scalar dataset `name` of `file`, stored as `D`.
+///
+/// # Errors
+/// Returns `ClusteredIndexError::SerializeError` if the value can't be converted to ASCII JSON
+/// or the dataset can't be written
+fn write_json_scalar<D: H5Type, V: Serialize + ?Sized>(file: &File, name: &str, value: &V) -> Result<()> {
+    let json = serde_json::to_string(value)
+        .map_err(|e| ClusteredIndexError::SerializeError(format!("{}: {}", name, e)))?;
+    let ascii = VarLenAscii::from_ascii(&json)
+        .map_err(|e| ClusteredIndexError::SerializeError(format!("{}: {}", name, e)))?;
+    file.new_dataset::<D>()
+        .create(name)
+        .map_err(|e| ClusteredIndexError::SerializeError(format!("{}: {}", name, e)))?
+        .write_scalar(&ascii)
+        .map_err(|e| ClusteredIndexError::SerializeError(format!("{}: {}", name, e)))
+}
+
+/// Path of the file [`serialize()`] writes in `directory` for an index built from `config`.
+pub(crate) fn index_file_path(directory: &str, config: &Config) -> String {
+    format!(
+        "{}/index_{}_k{:.2}_L{}.h5",
+        directory, config.dataset_name, config.num_clusters_factor, config.num_tables
+    )
+}
+
+/// Reads the configuration and the clusters of an index file, HDF5 or binary (`.bin`),
+/// without loading the cluster indices nor the dataset.
+///
+/// # Errors
+/// Returns `ClusteredIndexError::ConfigError` if the file doesn't exist or is not a valid index file
+pub(crate) fn read_metadata(file_path: &str) -> Result<(Config, Vec<ClusterCenter>)> {
+    if !Path::new(file_path).exists() {
+        return Err(ClusteredIndexError::ConfigError(format!(
+            "file {} not found",
+            file_path
+        )));
+    }
+
+    if file_path.ends_with(".bin") {
+        let file = fs::File::open(file_path)
+            .map_err(|e| ClusteredIndexError::ConfigError(format!("file {}: {}", file_path, e)))?;
+        // SAFETY: the map is read-only and dropped before returning
+        let mmap = unsafe { Mmap::map(&file) }
+            .map_err(|e| ClusteredIndexError::ConfigError(e.to_string()))?;
+        let (header, _) = parse_binary(&mmap).map_err(ClusteredIndexError::ConfigError)?;
+        return Ok((header.config, header.clusters));
+    }
+
+    let file =
+        File::open(file_path).map_err(|e| ClusteredIndexError::ConfigError(e.to_string()))?;
+    let root = file
+        .group("/")
+        .map_err(|e| ClusteredIndexError::ConfigError(e.to_string()))?;
+
+    // read config
+    let config_dataset = root
+        .dataset("config")
+        .map_err(|e| ClusteredIndexError::ConfigError(e.to_string()))?;
+    let config_ascii = config_dataset
+        .read_scalar::<VarLenAscii>()
+        .map_err(|e| ClusteredIndexError::ConfigError(e.to_string()))?;
+    let config: Config = serde_json::from_str(config_ascii.as_str())
+        .map_err(|e| ClusteredIndexError::ConfigError(e.to_string()))?;
+
+    // read cluster centers
+    let cluster_dataset = root
+        .dataset("clusters")
+        .map_err(|e| ClusteredIndexError::ConfigError(e.to_string()))?;
+    let cluster_ascii = cluster_dataset
+        .read_scalar::<VarLenAscii>()
+        .map_err(|e| ClusteredIndexError::ConfigError(e.to_string()))?;
+    let clusters: Vec<ClusterCenter> = serde_json::from_str(cluster_ascii.as_str())
+        .map_err(|e| ClusteredIndexError::ConfigError(e.to_string()))?;
+
+    Ok((config, clusters))
+}
+
+/// Reads the cells of a two-level index from an HDF5 index file, `None` for a one-level index.
+///
+/// # Errors
+/// Returns `ClusteredIndexError::ConfigError` if the file can't be read or the cells are corrupted
+fn read_hierarchy(file_path: &str) -> Result<Option<Hierarchy>> {
+    let file =
+        File::open(file_path).map_err(|e| ClusteredIndexError::ConfigError(e.to_string()))?;
+    let Ok(dataset) = file.dataset("hierarchy") else {
+        return Ok(None);
+    };
+    let hierarchy_ascii = dataset
+        .read_scalar::<VarLenAscii>()
+        .map_err(|e| ClusteredIndexError::ConfigError(e.to_string()))?;
+    serde_json::from_str(hierarchy_ascii.as_str())
+        .map(Some)
+        .map_err(|e| ClusteredIndexError::ConfigError(e.to_string()))
+}
+
+/// Warns when an index built on a dataset stored in one precision is loaded with another.
+fn check_storage<T: MetricData>(config: &Config, data: &T) {
+    if config.storage != data.precision() {
+        warn!(
+            "the index was built on {:?} data but the dataset is {:?}, the cluster radii may be off",
+            config.storage,
+            data.precision()
+        );
+    }
+}
+
+/// Checks that an index is loaded with a dataset of the weights it was built with.
+///
+/// # Errors
+/// Returns `ClusteredIndexError::ConfigError` if the weights differ
+fn check_weights<T: MetricData>(config: &Config, data: &T) -> Result<()> {
+    if config.weights.as_deref() != data.weights() {
+        return Err(ClusteredIndexError::ConfigError(format!(
+            "the index was built with weights {:?} but the dataset has {:?}",
+            config.weights,
+            data.weights()
+        )));
+    }
+    Ok(())
+}
+
+/// Checks that every point of the loaded `clusters` is in the dataset, e.g. an index saved
+/// after an insertion is not loaded with the dataset from before it.
+///
+/// # Errors
+/// Returns `ClusteredIndexError::ConfigError` if a cluster holds a point past the end of `data`
+fn check_points<T: MetricData>(clusters: &[ClusterCenter], data: &T) -> Result<()> {
+    for cluster in clusters {
+        let largest = cluster.assignment.iter().copied().chain([cluster.center_idx]).max();
+        if let Some(point) = largest.filter(|&point| point >= data.num_points()) {
+            return Err(ClusteredIndexError::ConfigError(format!(
+                "cluster {} holds point {} but the dataset has {} points",
+                cluster.idx,
+                point,
+                data.num_points()
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Bytes of the bit patterns of `point`, widened to f64, used as the key of a point in the query cache.
+fn query_to_bytes<D: Copy + Into<f64>>(point: &[D]) -> Vec<u8> {
+    point
+        .iter()
+        .flat_map(|&x| Into::<f64>::into(x).to_bits().to_le_bytes())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::{core::{ClusterBackend, ClusteredIndexError, Config, Aggregation, IndexMode, MissCounts, MmrParams, PqParams, SearchParams}, metricdata::{AngularData, MetricData}};
+    use crate::core::wal::extend_from_wal;
+    use crate::transform::RandomProjection;
+    use crate::utils::{brute_force_search, datagen::unit_vectors, test_dir};
+    use ndarray::{arr2, Array2};
+
+    use super::{near_duplicate_key, query_to_bytes, result_matrices, CenterDistances, Cluster, ClusterCenter, ClusteredIndex, Members, ESTIMATE_SAMPLE_POINTS, NO_NEIGHBOR};
+
+    /// Fraction of the `k` points of `data` closest to `query`, except those of `exclude`,
+    /// that are in `found`
+    fn recall<T>(data: &T, query: &[f32], k: usize, exclude: &[usize], found: &[(f32, usize)]) -> f32
+    where
+        T: MetricData<DataType = f32>,
+    {
+        let mut expected: Vec<(f32, usize)> = (0..data.num_points())
+            .filter(|p| !exclude.contains(p))
+            .map(|p| (data.distance_point(p, query), p))
+            .collect();
+        expected.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+        expected.truncate(k);
+        let hits = expected.iter().filter(|&&(_, p)| found.iter().any(|&(_, q)| q == p)).count();
+        hits as f32 / k as f32
+    }
+
+    /// Asserts that `found` holds the `k` points of `data` closest to `query`, except those of
+    /// `exclude`, at their distance. Clusters scanned exhaustively and pruned with the triangle
+    /// inequality miss no neighbor
+    fn assert_exact_neighbors<T>(data: &T, query: &[f32], k: usize, exclude: &[usize], found: &[(f32, usize)])
+    where
+        T: MetricData<DataType = f32>,
+    {
+        assert_eq!(found.len(), k);
+        assert_eq!(recall(data, query, k, exclude, found), 1.0, "{:?}", found);
+        for &(distance, p) in found {
+            assert!((distance - data.distance_point(p, query)).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn test_sort_cluster() {
+        let points = arr2(&[
+            [0.1, 0.9, 0.4],
+            [0.7, 0.2, 0.6],
+            [0.5, 0.3, 0.9],
+            [0.8, 0.4, 0.1],
+            [0.2, 0.1, 0.8],
+            [0.9, 0.8, 0.3],
+            [0.3, 0.6, 0.5],
+            [0.4, 0.3, 0.7],
+            [0.1, 0.2, 0.9],
+            [0.6, 0.7, 0.8],
             [0.2, 0.8, 0.1],
             [0.9, 0.2, 0.4],
             [0.3, 0.5, 0.6],
@@ -717,33 +4808,2918 @@ mod tests {
             [0.5, 0.1, 0.3],
         ]);
 
-        let data = AngularData::new(points);
+        let data = AngularData::new(points);
+
+        let cluster_indices: [usize; 3] = [6, 3, 17];
+        let mut clusters = Vec::new();
+        for (idx, center_idx) in cluster_indices.iter().enumerate() {
+            clusters.push(ClusterCenter {
+                idx,
+                center_idx: *center_idx,
+                radius: 0.0,
+                assignment: Members::default(),
+                brute_force: false,
+                memory_used: 0,
+                num_tables: None,
+                build_time: Default::default(),
+                search_stats: Default::default(),
+                member_distances: None,
+                outlier: false,
+                over_memory_ceiling: false,
+                outlier_threshold: None,
+            });
+        }
+
+        let config = Config::default();
+
+        let index: ClusteredIndex<_> = ClusteredIndex {
+            data,
+            clusters,
+            center_distances: CenterDistances::default(),
+            hierarchy: None,
+            config,
+            puffinn_indices: Vec::new(),
+            metrics: None,
+            query_cache: None,
+            build_report: None,
+            clustering: Default::default(),
+            last_distance_computations: 0,
+            callbacks: Default::default(),
+            build_observer: None,
+            cancellation: None,
+            reranker: None,
+            delta_policy: None,
+            quantizer: None,
+            product_quantizer: None,
+            duplicates: None,
+            wal: None,
+            rebuilt_clusters: Vec::new(),
+        };
+
+        let sorted_indices: Vec<usize> = index
+            .sort_cluster_indices_by_distance(&[0.1, 0.0, 0.7])
+            .into_iter()
+            .map(|(i, _)| i)
+            .collect();
+
+        assert_eq!(sorted_indices, vec![2, 0, 1]);
+    }
+
+    #[test]
+    fn test_rerank_f64_breaks_ties_by_index() {
+        let points = arr2(&[[1.0, 0.0], [0.0, 1.0], [1.0, 0.0], [0.5, 0.5]]);
+        let config = Config {
+            rerank_f64: true,
+            ..Default::default()
+        };
+
+        let mut index: ClusteredIndex<_> = ClusteredIndex {
+            data: AngularData::new(points),
+            clusters: Vec::new(),
+            center_distances: CenterDistances::default(),
+            hierarchy: None,
+            config,
+            puffinn_indices: Vec::new(),
+            metrics: None,
+            query_cache: None,
+            build_report: None,
+            clustering: Default::default(),
+            last_distance_computations: 0,
+            callbacks: Default::default(),
+            build_observer: None,
+            cancellation: None,
+            reranker: None,
+            delta_policy: None,
+            quantizer: None,
+            product_quantizer: None,
+            duplicates: None,
+            wal: None,
+            rebuilt_clusters: Vec::new(),
+        };
+
+        // points 0 and 2 are identical, the input order must not matter
+        let results = index.finalize_results(&[1.0, 0.0], vec![(0.0, 2), (0.0, 0), (0.5, 3)]);
+        let ids: Vec<usize> = results.iter().map(|&(_, p)| p).collect();
+        assert_eq!(ids, vec![0, 2, 3]);
+        assert!((results[2].0 - (1.0 - 0.5f32.sqrt())).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_search_batch_query_cache() {
+        let data = AngularData::new(unit_vectors(500, 8, 1));
+        let queries = unit_vectors(10, 8, 2);
+        let config = Config {
+            index_mode: IndexMode::Flat,
+            ..Default::default()
+        };
+
+        let mut index: ClusteredIndex<_> = ClusteredIndex::new(config, data).unwrap();
+        index.build().unwrap();
+        index.enable_query_cache(":memory:").unwrap();
+
+        let first = index.search_batch(&queries).unwrap();
+        let parameters = index.search_parameters(index.config.k).unwrap();
+        let cache = index.query_cache().unwrap();
+        for (query, results) in queries.rows().into_iter().zip(&first) {
+            let cached = cache.get(&parameters, &query_to_bytes(query.as_slice().unwrap())).unwrap();
+            assert_eq!(cached.as_ref(), Some(results));
+        }
+        assert_eq!(index.search_batch(&queries).unwrap(), first);
+
+        // the setters change the key, the entries of the previous k are not returned
+        let k = index.config.k;
+        index.set_k(k + 5).unwrap();
+        let more = index.search_batch(&queries).unwrap();
+        for (results, previous) in more.iter().zip(&first) {
+            assert_eq!(results.len(), k + 5);
+            assert_eq!(&results[..k], &previous[..]);
+        }
+        index.set_k(k).unwrap();
+        assert_eq!(index.search_batch(&queries).unwrap(), first);
+
+        // an insertion invalidates the entries, the queries find the points inserted
+        index.insert_batch(&queries).unwrap();
+        let query = query_to_bytes(queries.row(0).as_slice().unwrap());
+        assert_eq!(index.query_cache().unwrap().get(&parameters, &query).unwrap(), None);
+        let updated = index.search_batch(&queries).unwrap();
+        assert_eq!(updated[0][0].1, 500);
+
+        // the results of a delta policy are not cached
+        let other = unit_vectors(3, 8, 3);
+        index.set_delta_policy(crate::core::AdaptiveDelta::default());
+        index.search_batch(&other).unwrap();
+        let query = query_to_bytes(other.row(0).as_slice().unwrap());
+        assert_eq!(index.query_cache().unwrap().get(&parameters, &query).unwrap(), None);
+    }
+
+    #[test]
+    fn test_search_batch_near_duplicates() {
+        let data = AngularData::new(unit_vectors(2000, 8, 4));
+        let config = Config {
+            index_mode: IndexMode::Flat,
+            ..Default::default()
+        };
+        let mut index: ClusteredIndex<_> = ClusteredIndex::new(config, data).unwrap();
+        index.build().unwrap();
+
+        // an unrelated query, then two queries a rounding apart in the same cell of the grid
+        let mut queries = Array2::zeros((3, 8));
+        queries.row_mut(0).assign(&unit_vectors(1, 8, 5).row(0));
+        queries[[1, 0]] = 1.0;
+        queries.row_mut(2).fill(0.001);
+        queries[[2, 0]] = 1.0;
+        assert_eq!(
+            near_duplicate_key(queries.row(1).as_slice().unwrap()),
+            near_duplicate_key(queries.row(2).as_slice().unwrap())
+        );
+
+        let results = index.search_batch(&queries).unwrap();
+        // the near-duplicate started from the distances of the previous query to the centers
+        let seeded = index.last_distance_computations;
+
+        // same results as searching every query alone, the last one computing more distances
+        for (query, result) in queries.rows().into_iter().zip(&results) {
+            assert_eq!(&index.search(query.as_slice().unwrap()).unwrap(), result);
+        }
+        assert!(seeded < index.last_distance_computations);
+
+        // rows that are not contiguous are searched as well
+        let transposed = queries.t().to_owned();
+        assert_eq!(index.search_batch(&transposed.t()).unwrap(), results);
+        let fortran = queries.t().as_standard_layout().into_owned().reversed_axes();
+        assert!(fortran.row(0).as_slice().is_none());
+        assert_eq!(index.search_batch(&fortran).unwrap(), results);
+    }
+
+    #[test]
+    fn test_search_projects_queries() {
+        let raw = unit_vectors(300, 64, 6);
+        let projection = RandomProjection::new(64, 16, 3);
+        let data = AngularData::new(projection.transform(&raw).unwrap());
+        let config = Config {
+            index_mode: IndexMode::Flat,
+            projection: Some(projection),
+            ..Default::default()
+        };
+
+        assert!(ClusteredIndex::<_>::new(config.clone(), AngularData::new(raw.clone())).is_err());
+
+        let mut index: ClusteredIndex<_> = ClusteredIndex::new(config, data).unwrap();
+        index.build().unwrap();
+
+        let results = index.search(raw.row(42).as_slice().unwrap()).unwrap();
+        assert_eq!(results[0].1, 42);
+        assert!(index.search(&[0.0; 16]).is_err());
+        // queries are in the input space of the projection
+        assert_eq!(
+            index.search(&[1.0; 16]),
+            Err(ClusteredIndexError::DimensionMismatch { expected: 64, got: 16 })
+        );
+    }
+
+    #[test]
+    fn test_insert_batch() {
+        let data = AngularData::new(unit_vectors(500, 8, 7));
+        let new_points = unit_vectors(50, 8, 8);
+        let config = Config {
+            index_mode: IndexMode::Flat,
+            ..Default::default()
+        };
+
+        let mut index: ClusteredIndex<_> = ClusteredIndex::new(config, data).unwrap();
+        assert!(index.insert_batch(&new_points).is_err());
+        index.build().unwrap();
+
+        let ids = index.insert_batch(&new_points).unwrap();
+        assert_eq!(ids, (500..550).collect::<Vec<_>>());
+        assert_eq!(
+            index.clusters.iter().map(|c| c.assignment.len()).sum::<usize>(),
+            550
+        );
+
+        for (row, &id) in new_points.rows().into_iter().zip(&ids) {
+            let results = index.search(row.as_slice().unwrap()).unwrap();
+            assert_eq!(results[0].1, id);
+        }
+
+        assert!(index.insert(&[0.5; 3]).is_err());
+    }
+
+    #[test]
+    fn test_insert_batch_failed_build() {
+        let data = AngularData::new(unit_vectors(1000, 8, 9));
+        let new_points = unit_vectors(300, 8, 10);
+        // a few large clusters, with an index each
+        let config = Config {
+            num_clusters_factor: 0.1,
+            ..Default::default()
+        };
+        let mut index: ClusteredIndex<_, ListBackend> = ClusteredIndex::new(config, data).unwrap();
+        index.build().unwrap();
+        assert!(index.puffinn_indices.iter().any(Option::is_some));
+
+        let snapshot = |index: &ClusteredIndex<_, ListBackend>| {
+            let clusters: Vec<_> = index.clusters.iter().map(|c| (c.assignment.clone(), c.radius, c.brute_force)).collect();
+            let indices: Vec<_> = index.puffinn_indices.iter().map(|i| i.as_ref().map(|i| i.0.clone())).collect();
+            (clusters, indices)
+        };
+        let before = snapshot(&index);
+
+        LIST_FAILS.with(|fails| fails.set(true));
+        let result = index.insert_batch(&new_points);
+        LIST_FAILS.with(|fails| fails.set(false));
+        assert!(matches!(result, Err(ClusteredIndexError::PuffinnCreationError(_))));
+
+        // no cluster took the points, which stay in the dataset without being searched
+        assert_eq!(snapshot(&index), before);
+        assert_eq!(index.data.num_points(), 1300);
+        assert_eq!(index.cluster_of(1000), None);
+
+        let ids = index.insert_batch(&new_points).unwrap();
+        assert_eq!(ids, (1300..1600).collect::<Vec<_>>());
+        assert!(ids.iter().all(|&id| index.cluster_of(id).is_some()));
+        assert_eq!(index.cluster_of(1000), None);
+    }
+
+    #[test]
+    fn test_plan() {
+        let data = AngularData::new(unit_vectors(1000, 8, 11));
+        let query = unit_vectors(1, 8, 12);
+        let query = query.row(0).to_vec();
+        let config = Config {
+            index_mode: IndexMode::Flat,
+            ..Default::default()
+        };
+
+        let mut index: ClusteredIndex<_> = ClusteredIndex::new(config, data).unwrap();
+        index.build().unwrap();
+
+        let plan = index.plan(&query).unwrap();
+        assert_eq!(plan.steps.len(), index.clusters.len());
+        assert!(plan
+            .steps
+            .iter()
+            .filter(|step| !step.outlier)
+            .collect::<Vec<_>>()
+            .windows(2)
+            .all(|w| w[0].center_distance <= w[1].center_distance));
+        if let Some(kth) = plan.estimated_kth_distance {
+            assert!(plan.steps.iter().filter(|step| step.expected_pruned).all(|step| step.lower_bound >= kth));
+        }
+        // 1 - cos is not a metric, the bounds hold because they are derived from the angles
+        for step in &plan.steps {
+            let nearest = index.clusters[step.cluster]
+                .assignment
+                .iter()
+                .map(|&p| index.data.distance_point(p, &query))
+                .fold(f32::INFINITY, f32::min);
+            assert!(nearest >= step.lower_bound - 1e-5);
+        }
+
+        // probed clusters get their expected candidates from the search statistics
+        index.search(&query).unwrap();
+        // the clusters expected to be pruned are skipped by the exact search of the Flat mode
+        for step in plan.steps.iter().filter(|step| step.expected_pruned) {
+            assert_eq!(index.clusters[step.cluster].search_stats.probes, 0);
+        }
+        let first = &plan.steps[0];
+        let stats = &index.clusters[first.cluster].search_stats;
+        assert_eq!(stats.probes, 1);
+        assert_eq!(
+            index.plan(&query).unwrap().steps[0].expected_candidates,
+            Some(stats.candidates as f32)
+        );
+    }
+
+    #[test]
+    fn test_search_readonly() {
+        use crate::core::config::MetricsOutput;
+
+        let data = AngularData::new(unit_vectors(1000, 8, 13));
+        let queries = unit_vectors(5, 8, 14);
+        let config = Config {
+            index_mode: IndexMode::Flat,
+            metrics_output: MetricsOutput::DB,
+            ..Default::default()
+        };
+        let mut index: ClusteredIndex<_> = ClusteredIndex::new(config, data).unwrap();
+        index.build().unwrap();
+
+        let first = queries.row(0).to_vec();
+        let expected = index.search(&first).unwrap();
+        let last_distance_computations = index.last_distance_computations();
+        let probes: Vec<usize> = index.clusters.iter().map(|c| c.search_stats.probes).collect();
+        let distance_computations = index.get_distance_computations().unwrap();
+
+        let index = &index;
+        assert_eq!(index.search_readonly(&first).unwrap(), expected);
+        for query in queries.rows() {
+            index.search_readonly(query.as_slice().unwrap()).unwrap();
+        }
+        assert_eq!(index.last_distance_computations(), last_distance_computations);
+        assert!(index.clusters.iter().map(|c| c.search_stats.probes).eq(probes));
+        assert_eq!(index.get_distance_computations().unwrap(), distance_computations);
+    }
+
+    #[test]
+    fn test_max_clusters_probed() {
+        use std::sync::{Arc, Mutex};
+
+        let data = AngularData::new(unit_vectors(2000, 8, 15));
+        let queries = unit_vectors(20, 8, 16);
+        let config = Config {
+            index_mode: IndexMode::Flat,
+            max_clusters_probed: Some(0),
+            ..Default::default()
+        };
+        assert!(matches!(
+            ClusteredIndex::<_>::new(config.clone(), data.clone()),
+            Err(ClusteredIndexError::ConfigError(_))
+        ));
+
+        let config = Config { max_clusters_probed: None, ..config };
+        let mut index: ClusteredIndex<_> = ClusteredIndex::new(config, data).unwrap();
+        index.build().unwrap();
+        let probed = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&probed);
+        index.on_query(move |m| sink.lock().unwrap().push(m.clusters_probed()));
+
+        let unlimited = index.search_batch(&queries).unwrap();
+        assert!(probed.lock().unwrap().iter().any(|&p| p > 2));
+
+        probed.lock().unwrap().clear();
+        assert!(matches!(index.set_max_clusters_probed(Some(0)), Err(ClusteredIndexError::ConfigError(_))));
+        assert_eq!(index.config.max_clusters_probed, None);
+        index.set_max_clusters_probed(Some(2)).unwrap();
+        let limited = index.search_batch(&queries).unwrap();
+        assert!(probed.lock().unwrap().iter().all(|&p| p <= 2));
+        let query = queries.row(0).to_vec();
+        let plan = index.plan(&query).unwrap();
+        assert!(plan.steps.iter().filter(|step| !step.expected_pruned).count() <= 2);
+
+        // the grouped search stops at the same clusters
+        index.clear_metrics_callbacks();
+        assert_eq!(index.search_batch_grouped(&queries).unwrap(), limited);
+
+        index.set_max_clusters_probed(None).unwrap();
+        assert_eq!(index.search_batch(&queries).unwrap(), unlimited);
+    }
+
+    #[test]
+    fn test_center_distance_pruning() {
+        let data = AngularData::new(unit_vectors(4000, 3, 17));
+        let queries = unit_vectors(50, 3, 18);
+        let config = Config {
+            index_mode: IndexMode::Flat,
+            ..Default::default()
+        };
+
+        let mut index: ClusteredIndex<_> = ClusteredIndex::new(config, data).unwrap();
+        index.build().unwrap();
+        assert!(index.center_distances.matches(&index.clusters));
+
+        let search_all = |index: &mut ClusteredIndex<AngularData<ndarray::OwnedRepr<f32>>>| {
+            let mut computations = 0;
+            let mut results = Vec::new();
+            for query in queries.rows() {
+                results.push(index.search(query.as_slice().unwrap()).unwrap());
+                computations += index.last_distance_computations();
+            }
+            (results, computations)
+        };
+        let (pruned, pruned_computations) = search_all(&mut index);
+        let grouped = index.search_batch_grouped(&queries).unwrap();
+        assert_eq!(grouped, pruned);
+
+        index.center_distances = CenterDistances::default();
+        let (exhaustive, exhaustive_computations) = search_all(&mut index);
+        assert!(pruned_computations < exhaustive_computations);
+        // the same clusters are probed first, skipped clusters can only let the search go on
+        for (pruned, exhaustive) in pruned.iter().zip(&exhaustive) {
+            assert_eq!(pruned.len(), exhaustive.len());
+            assert!(pruned.last().unwrap().0 <= exhaustive.last().unwrap().0 + 1e-6);
+        }
+    }
+
+    #[test]
+    fn test_percentile_pruning_radius() {
+        use std::sync::{Arc, Mutex};
+
+        use crate::core::PruningRadius;
+
+        let data = AngularData::new(unit_vectors(3000, 8, 19));
+        let queries = unit_vectors(30, 8, 20);
+        let config = Config {
+            index_mode: IndexMode::Flat,
+            ..Default::default()
+        };
+
+        let mut index: ClusteredIndex<_> = ClusteredIndex::new(config, data).unwrap();
+        index.build().unwrap();
+        for cluster in index.clusters() {
+            let distances = cluster.member_distances.unwrap();
+            assert!(distances.p90 <= distances.p99 && distances.p99 <= distances.max);
+            assert!((distances.max as f32 - cluster.radius).abs() < 1e-4);
+        }
+
+        let probed = Arc::new(Mutex::new(0));
+        let sink = Arc::clone(&probed);
+        index.on_query(move |m| *sink.lock().unwrap() += m.clusters_probed());
+        let mut probes = Vec::new();
+        for radius in [PruningRadius::Max, PruningRadius::P99, PruningRadius::P90] {
+            *probed.lock().unwrap() = 0;
+            index.config.pruning_radius = radius;
+            for query in queries.rows() {
+                assert_eq!(index.search(query.as_slice().unwrap()).unwrap().len(), 10);
+            }
+            probes.push(*probed.lock().unwrap());
+        }
+        // smaller radii prune more clusters
+        assert!(probes[0] >= probes[1] && probes[1] >= probes[2]);
+        assert!(probes[2] < probes[0]);
+    }
+
+    #[test]
+    fn test_hierarchical_index() {
+        let data = AngularData::new(unit_vectors(4000, 8, 21));
+        let queries = unit_vectors(20, 8, 22);
+        let config = Config {
+            index_mode: IndexMode::Flat,
+            num_clusters_factor: 2.0,
+            coarse_clusters: Some(8),
+            dataset_name: "test_hierarchical".to_string(),
+            ..Default::default()
+        };
+
+        let mut index: ClusteredIndex<_> = ClusteredIndex::new(config, data.clone()).unwrap();
+        index.build().unwrap();
+        let hierarchy = index.hierarchy.clone().unwrap();
+        assert!(hierarchy.matches(&index.clusters));
+        assert!(hierarchy.cells.len() <= 8);
+        // every cluster is in exactly one cell, whose radius covers its center
+        let mut clusters: Vec<usize> = hierarchy.cells.iter().flat_map(|c| c.clusters.iter().copied()).collect();
+        clusters.sort_unstable();
+        assert_eq!(clusters, (0..index.num_clusters()).collect::<Vec<_>>());
+        for cell in &hierarchy.cells {
+            for &cluster in &cell.clusters {
+                let distance = data.distance(cell.center_idx, index.clusters[cluster].center_idx);
+                assert!(data.distance_to_metric(distance) <= cell.radius + 1e-5);
+            }
+        }
+
+        let mut found = Vec::new();
+        for query in queries.rows() {
+            let query = query.as_slice().unwrap();
+            let result = index.search(query).unwrap();
+            assert_exact_neighbors(&data, query, 10, &[], &result);
+            found.push(result);
+        }
+
+        // the cells are saved with the index
+        let dir = test_dir("hierarchical_index");
+        let directory = dir.to_str().unwrap();
+        index.serialize_binary(directory).unwrap();
+        let path = index.binary_file_path(directory);
+        let mut loaded: ClusteredIndex<_> = ClusteredIndex::new_from_mmap(data, &path).unwrap();
+        assert!(loaded.hierarchy.as_ref().is_some_and(|h| h.matches(&loaded.clusters)));
+        // the members are read in place from the mapped file
+        let in_place = cfg!(all(target_endian = "little", target_pointer_width = "64"));
+        assert!(loaded.clusters.iter().all(|c| c.assignment.is_mapped() == in_place));
+        for (query, expected) in queries.rows().into_iter().zip(&found) {
+            assert_eq!(&loaded.search(query.as_slice().unwrap()).unwrap(), expected);
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_cluster_count() {
+        use std::sync::{Arc, Mutex};
+
+        use crate::core::ClusterCount;
+
+        // 6 tight blobs around orthogonal directions
+        let noise = unit_vectors(600, 8, 23);
+        let mut points = ndarray::Array2::from_shape_fn((600, 8), |(i, j)| {
+            if j == i % 6 { 1.0 } else { 0.0 }
+        }) + noise * 0.05;
+        for mut row in points.rows_mut() {
+            let norm = row.dot(&row).sqrt();
+            row /= norm;
+        }
+        let data = AngularData::new(points);
+        let build = |cluster_count| {
+            let config = Config {
+                index_mode: IndexMode::Flat,
+                cluster_count,
+                ..Default::default()
+            };
+            let mut index: ClusteredIndex<_> = ClusteredIndex::new(config, data.clone()).unwrap();
+            let selected = Arc::new(Mutex::new(None));
+            let sink = Arc::clone(&selected);
+            index.on_build(move |m| *sink.lock().unwrap() = Some(m.selected_clusters));
+            index.build().unwrap();
+            let selected = selected.lock().unwrap().unwrap();
+            (index.num_clusters(), selected)
+        };
+
+        assert_eq!(build(ClusterCount::Factor), (24, 24));
+        assert_eq!(build(ClusterCount::AverageSize { points: 50 }), (12, 12));
+        assert_eq!(build(ClusterCount::RadiusElbow { max_clusters: 40 }), (6, 6));
+
+        let config = Config {
+            cluster_count: ClusterCount::RadiusElbow { max_clusters: 0 },
+            ..Default::default()
+        };
+        assert!(matches!(ClusteredIndex::<_>::new(config, data), Err(ClusteredIndexError::ConfigError(_))));
+    }
+
+    #[test]
+    fn test_merge_tiny_clusters() {
+        use std::sync::{Arc, Mutex};
+
+        use crate::core::TinyClusters;
+
+        let data = AngularData::new(unit_vectors(500, 8, 24));
+        let queries = unit_vectors(20, 8, 25);
+        let build = |tiny_clusters| {
+            let config = Config {
+                index_mode: IndexMode::Flat,
+                num_clusters_factor: 3.0,
+                tiny_clusters,
+                ..Default::default()
+            };
+            let mut index: ClusteredIndex<_> = ClusteredIndex::new(config, data.clone()).unwrap();
+            let merged = Arc::new(Mutex::new(None));
+            let sink = Arc::clone(&merged);
+            index.on_build(move |m| *sink.lock().unwrap() = Some(m.merged_clusters));
+            index.build().unwrap();
+            let merged = merged.lock().unwrap().unwrap();
+            (index, merged)
+        };
+
+        let (plain, merged) = build(TinyClusters::Keep);
+        assert_eq!(merged, 0);
+        assert!(plain.clusters().any(|c| c.members.len() < 10));
+
+        let (mut index, merged) = build(TinyClusters::MergeNearest { min_points: 10 });
+        assert!(merged > 0);
+        assert_eq!(index.num_clusters() + merged, plain.num_clusters());
+        let mut members = Vec::new();
+        for (idx, cluster) in index.clusters().enumerate() {
+            assert_eq!(cluster.id, idx);
+            assert!(cluster.members.len() >= 10);
+            // the radius covers the members merged in
+            for &member in cluster.members {
+                assert!(data.distance(cluster.center, member) <= cluster.radius + 1e-5);
+            }
+            members.extend_from_slice(cluster.members);
+        }
+        members.sort_unstable();
+        assert_eq!(members, (0..500).collect::<Vec<_>>());
+
+        for query in queries.rows() {
+            let query = query.as_slice().unwrap();
+            assert_exact_neighbors(&data, query, 10, &[], &index.search(query).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_outlier_pool() {
+        let data = AngularData::new(unit_vectors(3000, 8, 26));
+        let build = |outlier_quantile| {
+            let config = Config {
+                index_mode: IndexMode::Flat,
+                outlier_quantile,
+                ..Default::default()
+            };
+            let mut index: ClusteredIndex<_> = ClusteredIndex::new(config, data.clone()).unwrap();
+            index.build().unwrap();
+            index
+        };
+
+        let plain = build(None);
+        let mut pooled = build(Some(0.95));
+        assert!(plain.clusters().all(|c| !c.outlier));
+        assert_eq!(pooled.clusters().len(), plain.clusters().len() + 1);
+        let clusters: Vec<Cluster> = pooled.clusters().collect();
+        let (pool, main) = clusters.split_last().unwrap();
+        assert!(pool.outlier && pool.brute_force);
+        assert!(pool.members.len() >= 100 && pool.members.len() <= 160);
+        // every point is in exactly one cluster
+        let mut members: Vec<usize> = clusters.iter().flat_map(|c| c.members.iter().copied()).collect();
+        members.sort_unstable();
+        assert_eq!(members, (0..3000).collect::<Vec<_>>());
+        // the main clusters are tighter
+        let max_radius = main.iter().map(|c| c.radius).fold(0.0, f32::max);
+        assert!(max_radius < plain.clusters().map(|c| c.radius).fold(0.0, f32::max));
+        let outliers = pool.members.to_vec();
+        let (pool_id, main_id, center) = (pool.id, main[0].id, main[0].center);
+
+        // the outliers are found even if their original cluster would be pruned
+        for &id in outliers.iter().take(20) {
+            let query = data.get_point(id).into_owned();
+            let found = pooled.search(&query).unwrap();
+            assert_eq!(found[0].1 as usize, id);
+        }
+
+        // an inserted point as far from its center as the outliers joins their pool, a point
+        // next to a center joins its cluster
+        let rows: Vec<f32> = [outliers[0], center].iter().flat_map(|&id| data.get_point(id).into_owned()).collect();
+        let ids = pooled.insert_batch(&Array2::from_shape_vec((2, 8), rows).unwrap()).unwrap();
+        let clusters: Vec<Cluster> = pooled.clusters().collect();
+        assert!(clusters[pool_id].members.contains(&ids[0]));
+        assert!(clusters[pool_id].brute_force);
+        assert!(clusters[main_id].members.contains(&ids[1]));
+    }
+
+    #[test]
+    fn test_flat_mode_builds_no_index() {
+        let data = AngularData::new(unit_vectors(2000, 8, 27));
+        let queries = unit_vectors(20, 8, 28);
+        let config = Config {
+            index_mode: IndexMode::Flat,
+            ..Default::default()
+        };
+
+        let mut index: ClusteredIndex<_> = ClusteredIndex::new(config, data.clone()).unwrap();
+        index.build().unwrap();
+        assert!(index.clusters.iter().all(|c| c.brute_force));
+        assert!(index.puffinn_indices.iter().all(|i| i.is_none()));
+
+        for query in queries.rows() {
+            let query = query.as_slice().unwrap();
+            assert_exact_neighbors(&data, query, 10, &[], &index.search(query).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_search_excludes_points() {
+        let data = AngularData::new(unit_vectors(1000, 8, 29));
+        let config = Config {
+            index_mode: IndexMode::Flat,
+            ..Default::default()
+        };
+        let mut index: ClusteredIndex<_> = ClusteredIndex::new(config, data.clone()).unwrap();
+        index.build().unwrap();
+
+        let query = data.get_point(7).into_owned();
+        let found = index.search(&query).unwrap();
+        assert_eq!(found[0].1, 7);
+
+        let found = index.search_with(&query, &SearchParams::exclude_self_by_id(&7)).unwrap();
+        assert_eq!(found.len(), 10);
+        assert!(found.iter().all(|&(_, p)| p != 7));
+
+        // unsorted and repeated ids, k is not shrunk
+        let exclude = vec![found[3].1, 7, found[0].1, 7];
+        let excluded = index.search_with(&query, &SearchParams { exclude: &exclude, ..Default::default() }).unwrap();
+        assert_eq!(excluded.len(), 10);
+        assert!(excluded.iter().all(|(_, p)| !exclude.contains(p)));
+    }
+
+    #[test]
+    fn test_search_max_distance() {
+        let data = AngularData::new(unit_vectors(1000, 8, 1000));
+        let query = unit_vectors(1, 8, 1001).row(0).to_vec();
+        let config = Config {
+            index_mode: IndexMode::Flat,
+            ..Default::default()
+        };
+        let mut index: ClusteredIndex<_> = ClusteredIndex::new(config, data).unwrap();
+        index.build().unwrap();
+        let found = index.search(&query).unwrap();
+
+        // the neighbors within the distance, fewer than k
+        let max_distance = found[4].0;
+        let params = SearchParams {
+            max_distance: Some(max_distance),
+            ..Default::default()
+        };
+        let within = index.search_with(&query, &params).unwrap();
+        let expected: Vec<(f32, usize)> = found.iter().copied().filter(|&(d, _)| d <= max_distance).collect();
+        assert_eq!(within, expected);
+
+        for max_distance in [-0.5, f32::NAN] {
+            let params = SearchParams {
+                max_distance: Some(max_distance),
+                ..Default::default()
+            };
+            assert!(matches!(index.search_with(&query, &params), Err(ClusteredIndexError::ConfigError(_))));
+        }
+    }
+
+    #[test]
+    fn test_search_multi() {
+        let data = AngularData::new(unit_vectors(2000, 8, 30));
+        let queries = unit_vectors(3, 8, 31);
+        let queries: Vec<&[f32]> = queries.rows().into_iter().map(|q| q.to_slice().unwrap()).collect();
+        let config = Config {
+            index_mode: IndexMode::Flat,
+            ..Default::default()
+        };
+        let mut index: ClusteredIndex<_> = ClusteredIndex::new(config, data.clone()).unwrap();
+        index.build().unwrap();
+        assert!(index.search_multi(&[], Aggregation::Min).is_err());
+
+        for aggregation in [Aggregation::Min, Aggregation::Mean] {
+            let mut exact: Vec<(f32, usize)> = (0..data.num_points())
+                .map(|p| {
+                    let distances = queries.iter().map(|q| data.distance_point(p, q));
+                    (aggregation.combine(distances) as f32, p)
+                })
+                .collect();
+            exact.sort_by(|a, b| a.0.total_cmp(&b.0));
+            let expected: Vec<usize> = exact[..10].iter().map(|&(_, p)| p).collect();
+
+            let found = index.search_multi(&queries, aggregation).unwrap();
+            assert_eq!(found.len(), 10);
+            assert!(found.windows(2).all(|w| w[0].0 <= w[1].0));
+            for &(distance, p) in &found {
+                let true_distance = exact.iter().find(|&&(_, q)| q == p).unwrap().0;
+                assert!((distance - true_distance).abs() < 1e-5);
+            }
+            let recall = found.iter().filter(|(_, p)| expected.contains(p)).count();
+            assert_eq!(recall, 10, "{:?}", aggregation);
+        }
+    }
+
+    #[test]
+    fn test_mmr_diversifies_results() {
+        // every point with two near-duplicates
+        let points = unit_vectors(200, 8, 32);
+        let triples = ndarray::Array2::from_shape_fn((600, 8), |(i, j)| {
+            points[[i / 3, j]] + if j == i % 3 { 1e-3 } else { 0.0 }
+        });
+        let data = AngularData::new(triples);
+        let query = unit_vectors(1, 8, 33).row(0).to_vec();
+        let groups = |found: &[(f32, usize)]| {
+            let mut groups: Vec<usize> = found.iter().map(|&(_, p)| p / 3).collect();
+            groups.sort_unstable();
+            groups.dedup();
+            groups.len()
+        };
+
+        let config = Config {
+            index_mode: IndexMode::Flat,
+            ..Default::default()
+        };
+        let mut index: ClusteredIndex<_> = ClusteredIndex::new(config.clone(), data.clone()).unwrap();
+        index.build().unwrap();
+        let nearest = index.search(&query).unwrap();
+        assert!(groups(&nearest) < 6);
+
+        let config = Config {
+            mmr: Some(MmrParams { lambda: 0.2, candidates: 40 }),
+            ..config
+        };
+        let few_candidates = Config { mmr: Some(MmrParams { lambda: 0.2, candidates: 5 }), ..config.clone() };
+        assert!(matches!(
+            ClusteredIndex::<_>::new(few_candidates, data.clone()),
+            Err(ClusteredIndexError::ConfigError(_))
+        ));
+        let mut index: ClusteredIndex<_> = ClusteredIndex::new(config, data).unwrap();
+        index.build().unwrap();
+        let diverse = index.search(&query).unwrap();
+        assert_eq!(diverse.len(), 10);
+        // the closest candidate is selected first
+        assert!(diverse.iter().all(|&(distance, _)| distance >= diverse[0].0));
+
+        // k must be positive and at most the candidates, per call and in the setter
+        for k in [0, 41] {
+            let params = SearchParams { k: Some(k), ..Default::default() };
+            assert!(matches!(index.search_with(&query, &params), Err(ClusteredIndexError::ConfigError(_))));
+            assert!(matches!(index.set_k(k), Err(ClusteredIndexError::ConfigError(_))));
+        }
+        assert_eq!(index.config.k, 10);
+        assert_eq!(groups(&diverse), 10);
+    }
+
+    #[test]
+    fn test_knn_graph() {
+        let data = AngularData::new(unit_vectors(1000, 8, 34));
+        let config = Config {
+            index_mode: IndexMode::Flat,
+            ..Default::default()
+        };
+
+        let mut index: ClusteredIndex<_> = ClusteredIndex::new(config, data.clone()).unwrap();
+        assert!(index.knn_graph(5).is_err());
+        index.build().unwrap();
+        assert!(index.knn_graph(0).is_err());
+
+        let graph = index.knn_graph(5).unwrap();
+        assert_eq!(graph.num_points(), 1000);
+        assert_eq!(graph.k(), 5);
+        for point in 0..1000 {
+            let neighbors = graph.neighbors(point);
+            assert!(neighbors.windows(2).all(|w| w[0].0 <= w[1].0));
+            assert_exact_neighbors(&data, &data.get_point(point), 5, &[point], neighbors);
+        }
+        assert!(index.last_distance_computations() > 0);
+    }
+
+    #[test]
+    fn test_half_precision_index() {
+        use crate::metricdata::{f16, Precision};
+
+        let points = unit_vectors(1000, 8, 35);
+        let data = AngularData::<ndarray::OwnedRepr<f16>>::from_f32(&points);
+        let config = Config {
+            index_mode: IndexMode::Flat,
+            dataset_name: "test_half_precision".to_string(),
+            ..Default::default()
+        };
+
+        let mut index: ClusteredIndex<_> = ClusteredIndex::new(config, data.clone()).unwrap();
+        assert_eq!(index.config.storage, Precision::F16);
+        index.build().unwrap();
+
+        let query = points.row(3).to_vec();
+        let found = index.search(&query).unwrap();
+        assert_eq!(found[0].1, 3);
+        assert!(found[0].0.abs() < 1e-3);
+
+        // the precision is saved with the index
+        let dir = test_dir("half_precision_index");
+        let directory = dir.to_str().unwrap();
+        index.serialize_binary(directory).unwrap();
+        let path = index.binary_file_path(directory);
+        let mut loaded: ClusteredIndex<_> = ClusteredIndex::new_from_mmap(data, &path).unwrap();
+        assert_eq!(loaded.config.storage, Precision::F16);
+        assert_eq!(loaded.search(&query).unwrap(), found);
+
+        // nor overridden by a configuration given later
+        let config = Config {
+            storage: Precision::F32,
+            ..loaded.config.clone()
+        };
+        loaded.adopt_config(config.clone()).unwrap();
+        assert_eq!(loaded.config.storage, Precision::F16);
+        loaded.reconfigure(config).unwrap();
+        assert_eq!(loaded.config.storage, Precision::F16);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_weighted_index() {
+        use crate::metricdata::WeightedAngularData;
+
+        let points = unit_vectors(1000, 8, 36);
+        let weights = vec![8.0, 4.0, 2.0, 1.0, 1.0, 0.5, 0.25, 0.0];
+        let data = WeightedAngularData::new(points.clone(), weights.clone()).unwrap();
+        let config = Config {
+            index_mode: IndexMode::Flat,
+            scalar_quantization: true,
+            dataset_name: "test_weighted".to_string(),
+            ..Default::default()
+        };
+
+        let mut index: ClusteredIndex<_> = ClusteredIndex::new(config, data.clone()).unwrap();
+        assert_eq!(index.config.weights.as_ref(), Some(&weights));
+        index.build().unwrap();
+
+        let mut total = 0.0;
+        for i in 0..20 {
+            let query = points.row(i).to_vec();
+            let found = index.search(&query).unwrap();
+            assert_eq!(found[0].1, i);
+            for &(distance, p) in &found {
+                assert!((distance - data.distance_point(p, &query)).abs() < 1e-5);
+            }
+            total += recall(&data, &query, 10, &[], &found);
+        }
+        // the int8 codes rank the points of the scanned clusters, a neighbor within the
+        // quantization error of the k-th one may be swapped for the next point
+        assert!(total / 20.0 > 0.95);
+
+        // the weights are saved with the index, and it can't be loaded with other ones
+        let dir = test_dir("weighted_index");
+        let directory = dir.to_str().unwrap();
+        index.serialize_binary(directory).unwrap();
+        let path = index.binary_file_path(directory);
+        let query = points.row(7).to_vec();
+        let mut loaded: ClusteredIndex<_> = ClusteredIndex::new_from_mmap(data, &path).unwrap();
+        assert_eq!(loaded.search(&query).unwrap(), index.search(&query).unwrap());
+        let other = WeightedAngularData::new(points, vec![1.0; 8]).unwrap();
+        assert!(matches!(
+            ClusteredIndex::<_>::new_from_mmap(other, &path),
+            Err(ClusteredIndexError::ConfigError(_))
+        ));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_custom_metric_index() {
+        use crate::metricdata::CustomMetricData;
+
+        let manhattan = |a: &[f32], b: &[f32]| a.iter().zip(b).map(|(x, y)| (x - y).abs()).sum();
+        let points = unit_vectors(1000, 8, 37);
+        let data = CustomMetricData::new(&points, manhattan).unwrap();
+        let config = Config {
+            dataset_name: "test_custom".to_string(),
+            ..Default::default()
+        };
+
+        // the quantizers only approximate inner products
+        let quantized = Config {
+            scalar_quantization: true,
+            ..config.clone()
+        };
+        assert!(matches!(
+            ClusteredIndex::<_>::new(quantized, data.clone()),
+            Err(ClusteredIndexError::ConfigError(_))
+        ));
+
+        // every cluster is scanned, even with the LSH index mode
+        let mut index: ClusteredIndex<_> = ClusteredIndex::new(config, data.clone()).unwrap();
+        index.build().unwrap();
+        assert!(index.clusters.iter().all(|c| c.brute_force));
+
+        for i in 0..20 {
+            let query = points.row(i).to_vec();
+            let found = index.search(&query).unwrap();
+            assert_eq!(found[0], (0.0, i));
+            assert_exact_neighbors(&data, &query, 10, &[], &found);
+        }
+
+        let dir = test_dir("custom_metric_index");
+        let directory = dir.to_str().unwrap();
+        index.serialize_binary(directory).unwrap();
+        let path = index.binary_file_path(directory);
+        let query = points.row(3).to_vec();
+        let mut loaded: ClusteredIndex<_> = ClusteredIndex::new_from_mmap(data, &path).unwrap();
+        assert_eq!(loaded.search(&query).unwrap(), index.search(&query).unwrap());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_query_validation() {
+        let points = unit_vectors(500, 8, 38);
+        let config = Config {
+            index_mode: IndexMode::Flat,
+            ..Default::default()
+        };
+
+        // the angular distance to a zero vector is undefined
+        let mut angular: ClusteredIndex<_> =
+            ClusteredIndex::new(config.clone(), AngularData::new(points.clone())).unwrap();
+        angular.build().unwrap();
+        assert!(matches!(angular.search(&[0.0; 8]), Err(ClusteredIndexError::DataError(_))));
+        let mut nan = points.row(0).to_vec();
+        nan[3] = f32::NAN;
+        assert!(matches!(angular.search(&nan), Err(ClusteredIndexError::DataError(_))));
+
+        let mismatch = ClusteredIndexError::DimensionMismatch { expected: 8, got: 7 };
+        assert_eq!(angular.search(&[1.0; 7]).unwrap_err(), mismatch);
+        let short = unit_vectors(3, 7, 39);
+        assert_eq!(angular.search_batch(&short).unwrap_err(), mismatch);
+        assert_eq!(angular.search_batch_grouped(&short).unwrap_err(), mismatch);
+        let multi = angular.search_multi(&[&[1.0; 8], &[1.0; 7]], Aggregation::Min);
+        assert_eq!(multi.unwrap_err(), mismatch);
+
+        // scaled queries find the neighbors of the unit one among unit-norm points
+        let normalized = Config {
+            normalize_queries: true,
+            ..config
+        };
+        let l2 = |a: &[f32], b: &[f32]| a.iter().zip(b).map(|(x, y)| (x - y) * (x - y)).sum::<f32>().sqrt();
+        let data = crate::metricdata::CustomMetricData::new(&points, l2).unwrap();
+        let mut euclidean: ClusteredIndex<_> = ClusteredIndex::new(normalized, data).unwrap();
+        euclidean.build().unwrap();
+        let query = points.row(5).to_vec();
+        let scaled: Vec<f32> = query.iter().map(|x| x * 3.0).collect();
+        let expected = euclidean.search(&query).unwrap();
+        let found = euclidean.search(&scaled).unwrap();
+        assert_eq!(found.iter().map(|&(_, p)| p).collect::<Vec<_>>(), expected.iter().map(|&(_, p)| p).collect::<Vec<_>>());
+        assert!(found.iter().zip(&expected).all(|(a, b)| (a.0 - b.0).abs() < 1e-5));
+        assert!(matches!(euclidean.search(&[0.0; 8]), Err(ClusteredIndexError::DataError(_))));
+    }
+
+    #[test]
+    fn test_validate_data() {
+        let mut points = unit_vectors(500, 8, 40);
+        points.row_mut(3).fill(0.0);
+        points[[42, 1]] = f32::INFINITY;
+        let config = Config {
+            index_mode: IndexMode::Flat,
+            validate_data: true,
+            ..Default::default()
+        };
+
+        // off by default
+        assert!(ClusteredIndex::<_>::new(Config::default(), AngularData::new(points.clone())).is_ok());
+        assert_eq!(
+            ClusteredIndex::<_>::new(config.clone(), AngularData::new(points.clone())).err(),
+            Some(ClusteredIndexError::DataError(
+                "2 invalid rows: row 3 has zero norm, row 42 has a NaN or infinite component".to_string()
+            ))
+        );
+
+        points.row_mut(3).fill(1.0);
+        points[[42, 1]] = 0.5;
+        let mut index: ClusteredIndex<_> = ClusteredIndex::new(config, AngularData::new(points)).unwrap();
+        index.build().unwrap();
+        let mut new_points = unit_vectors(2, 8, 41);
+        new_points[[1, 0]] = f32::NAN;
+        assert!(matches!(index.insert_batch(&new_points), Err(ClusteredIndexError::DataError(_))));
+        // nothing was inserted
+        assert_eq!(index.data().num_points(), 500);
+    }
+
+
+    #[test]
+    fn test_dedup() {
+        use crate::metricdata::CustomMetricData;
+
+        let l2 = |a: &[f32], b: &[f32]| a.iter().zip(b).map(|(x, y)| (x - y) * (x - y)).sum::<f32>().sqrt();
+        let mut points = unit_vectors(500, 8, 42);
+        for copy in [100, 200, 300] {
+            let row = points.row(7).to_owned();
+            points.row_mut(copy).assign(&row);
+        }
+        let data = CustomMetricData::new(&points, l2).unwrap();
+        let config = Config {
+            dataset_name: "test_dedup".to_string(),
+            index_mode: IndexMode::Flat,
+            dedup: true,
+            ..Default::default()
+        };
+        let mut index: ClusteredIndex<_> = ClusteredIndex::new(config, data.clone()).unwrap();
+        index.build().unwrap();
+
+        // the copies are left out of the clusters and reported with the first one
+        let members = index.clusters.iter().map(|c| c.assignment.len()).sum::<usize>();
+        assert_eq!(members, 497);
+        assert_eq!(index.cluster_of(200), index.cluster_of(7));
+        let query = points.row(7).to_vec();
+        let found = index.search(&query).unwrap();
+        let copies: Vec<usize> = found.iter().take(4).map(|&(_, p)| p).collect();
+        assert_eq!(copies, vec![7, 100, 200, 300]);
+        assert!(found.iter().take(4).all(|&(d, _)| d < 1e-6));
+        assert_eq!(found.len(), 10);
+
+        // an excluded copy is skipped, the others are still found
+        let found = index.search_with(&query, &SearchParams { exclude: &[7, 200], ..Default::default() }).unwrap();
+        assert_eq!(found[0].1, 100);
+        assert_eq!(found[1].1, 300);
+
+        let dir = test_dir("dedup");
+        let directory = dir.to_str().unwrap();
+        index.serialize_binary(directory).unwrap();
+        let path = index.binary_file_path(directory);
+        let mut loaded: ClusteredIndex<_> = ClusteredIndex::new_from_mmap(data.clone(), &path).unwrap();
+        assert_eq!(loaded.search(&query).unwrap(), index.search(&query).unwrap());
+        std::fs::remove_dir_all(dir).unwrap();
+
+        // an inserted copy is an alias as well
+        let ids = index.insert_batch(&points.slice(ndarray::s![7..8, ..])).unwrap();
+        assert_eq!(ids, vec![500]);
+        assert_eq!(index.clusters.iter().map(|c| c.assignment.len()).sum::<usize>(), members);
+        assert!(index.search(&query).unwrap().iter().take(5).any(|&(_, p)| p == 500));
+
+        let other: ClusteredIndex<_> = ClusteredIndex::new(index.config.clone(), data).unwrap();
+        assert!(matches!(index.merge(other), Err(ClusteredIndexError::ConfigError(_))));
+    }
+
+    #[test]
+    fn test_dedup_empty_clusters() {
+        // 20 distinct points with 5 copies each, every point is its own center
+        let distinct = unit_vectors(20, 8, 43);
+        let points = Array2::from_shape_fn((100, 8), |(i, j)| distinct[[i % 20, j]]);
+        let data = AngularData::new(points.clone());
+        let config = Config {
+            dataset_name: "test_dedup_empty_clusters".to_string(),
+            cluster_count: crate::core::ClusterCount::AverageSize { points: 1 },
+            dedup: true,
+            ..Default::default()
+        };
+        let mut index: ClusteredIndex<_> = ClusteredIndex::new(config, data.clone()).unwrap();
+        index.build().unwrap();
+
+        // the clusters of the copies are left empty but keep their slot
+        assert_eq!(index.clusters.len(), 100);
+        assert_eq!(index.clusters.iter().filter(|c| c.assignment.is_empty()).count(), 80);
+        assert_eq!(index.puffinn_indices.len(), index.clusters.len());
+        let query = points.row(3).to_vec();
+        let found = index.search(&query).unwrap();
+        assert!(found.iter().take(5).all(|&(d, p)| d < 1e-6 && p % 20 == 3));
+
+        let dir = test_dir("dedup_empty_clusters");
+        let directory = dir.to_str().unwrap();
+        index.serialize_binary(directory).unwrap();
+        let path = index.binary_file_path(directory);
+        let mut loaded: ClusteredIndex<_> = ClusteredIndex::new_from_mmap(data, &path).unwrap();
+        assert_eq!(loaded.search(&query).unwrap(), found);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_serialize_without_run_tags() {
+        let data = AngularData::new(unit_vectors(300, 8, 44));
+        let config = Config {
+            dataset_name: "test_run_tags".to_string(),
+            index_mode: IndexMode::Flat,
+            run_tags: vec!["café".to_string()],
+            ..Default::default()
+        };
+        let mut index: ClusteredIndex<_> = ClusteredIndex::new(config, data.clone()).unwrap();
+        index.build().unwrap();
+
+        let dir = test_dir("run_tags");
+        let directory = dir.to_str().unwrap();
+        index.serialize(directory).unwrap();
+        let mut loaded: ClusteredIndex<_> = ClusteredIndex::new_from_file(data, &index.file_path(directory)).unwrap();
+        assert!(loaded.config().run_tags.is_empty());
+        let query = unit_vectors(1, 8, 45).row(0).to_vec();
+        assert_eq!(loaded.search(&query).unwrap(), index.search(&query).unwrap());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_wal() {
+        let points = unit_vectors(300, 8, 46);
+        let config = Config {
+            dataset_name: "test_wal".to_string(),
+            index_mode: IndexMode::Flat,
+            ..Default::default()
+        };
+        let dir = test_dir("wal");
+        let directory = dir.to_str().unwrap();
+        let log = dir.join("index.wal");
+
+        let mut index: ClusteredIndex<_> = ClusteredIndex::new(config.clone(), AngularData::new(points.clone())).unwrap();
+        assert!(matches!(index.open_wal(&log), Err(ClusteredIndexError::ConfigError(_))));
+        index.build().unwrap();
+        assert_eq!(index.open_wal(&log).unwrap(), 0);
+        index.serialize_binary(directory).unwrap();
+        let path = index.binary_file_path(directory);
+        let first_file = dir.join("first.bin");
+        std::fs::copy(&path, &first_file).unwrap();
+        let first_file = first_file.to_str().unwrap();
+        let snapshot = index.data().clone();
+
+        // inserted after the last serialization, then lost in a crash
+        let batch = unit_vectors(10, 8, 47);
+        assert_eq!(index.insert_batch(&batch).unwrap(), (300..310).collect::<Vec<_>>());
+        assert!(index.insert_batch(&unit_vectors(1, 5, 48)).is_err());
+        drop(index);
+
+        let mut loaded: ClusteredIndex<_> = ClusteredIndex::new_from_mmap(snapshot.clone(), &path).unwrap();
+        assert_eq!(loaded.open_wal(&log).unwrap(), 10);
+        for (i, query) in batch.rows().into_iter().enumerate() {
+            assert_eq!(loaded.search(&query.to_vec()).unwrap()[0].1, 300 + i);
+        }
+        let other: ClusteredIndex<_> = ClusteredIndex::new(config.clone(), AngularData::new(points.clone())).unwrap();
+        assert!(matches!(loaded.merge(other), Err(ClusteredIndexError::ConfigError(_))));
+
+        // the index is saved but not the dataset, the file holds points the dataset lacks
+        loaded.serialize_binary(directory).unwrap();
+        assert!(matches!(
+            ClusteredIndex::<_>::new_from_mmap(snapshot.clone(), &path),
+            Err(ClusteredIndexError::ConfigError(_))
+        ));
+        let mut extended = snapshot.clone();
+        assert_eq!(extend_from_wal(&mut extended, &config, &log).unwrap(), 10);
+        assert_eq!(extend_from_wal(&mut extended, &config, &log).unwrap(), 0);
+        assert_eq!(extended.get_point(305), loaded.data().get_point(305));
+        let mut reloaded: ClusteredIndex<_> = ClusteredIndex::new_from_mmap(extended, &path).unwrap();
+        assert_eq!(reloaded.open_wal(&log).unwrap(), 0);
+
+        // the dataset is saved but not the index, the points are in the dataset only
+        let mut stale: ClusteredIndex<_> = ClusteredIndex::new_from_mmap(loaded.data().clone(), first_file).unwrap();
+        assert_eq!(stale.open_wal(&log).unwrap(), 10);
+        assert_eq!(stale.data().num_points(), 310);
+        for (i, query) in batch.rows().into_iter().enumerate() {
+            assert_eq!(stale.search(&query.to_vec()).unwrap()[0].1, 300 + i);
+        }
+
+        // both saved, the log is emptied
+        reloaded.truncate_wal().unwrap();
+        let mut fresh: ClusteredIndex<_> = ClusteredIndex::new_from_mmap(loaded.data().clone(), &path).unwrap();
+        assert!(matches!(fresh.truncate_wal(), Err(ClusteredIndexError::ConfigError(_))));
+        assert_eq!(fresh.open_wal(&log).unwrap(), 0);
+
+        // a log that doesn't follow the dataset
+        reloaded.insert_batch(&unit_vectors(5, 8, 49)).unwrap();
+        let mut index: ClusteredIndex<_> = ClusteredIndex::new(config, snapshot).unwrap();
+        index.build().unwrap();
+        assert!(matches!(index.open_wal(&log), Err(ClusteredIndexError::DataError(_))));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_wal_failed_batch() {
+        let config = Config {
+            dataset_name: "test_wal_failed_batch".to_string(),
+            num_clusters_factor: 0.1,
+            ..Default::default()
+        };
+        let dir = test_dir("wal_failed_batch");
+        let directory = dir.to_str().unwrap();
+        let log = dir.join("index.wal");
+
+        let data = AngularData::new(unit_vectors(1000, 8, 50));
+        let mut index: ClusteredIndex<_, ListBackend> = ClusteredIndex::new(config.clone(), data).unwrap();
+        index.build().unwrap();
+        assert_eq!(index.open_wal(&log).unwrap(), 0);
+        index.serialize_binary(directory).unwrap();
+        let path = index.binary_file_path(directory);
+        let snapshot = index.data().clone();
+
+        // the failed batch stays in the dataset in no cluster, then a crash
+        LIST_FAILS.with(|fails| fails.set(true));
+        let result = index.insert_batch(&unit_vectors(20, 8, 51));
+        LIST_FAILS.with(|fails| fails.set(false));
+        assert!(matches!(result, Err(ClusteredIndexError::PuffinnCreationError(_))));
+        let ids = index.insert_batch(&unit_vectors(10, 8, 52)).unwrap();
+        assert_eq!(ids, (1020..1030).collect::<Vec<_>>());
+        drop(index);
+
+        // replaying the log doesn't index the failed batch either
+        let mut extended = snapshot.clone();
+        assert_eq!(extend_from_wal(&mut extended, &config, &log).unwrap(), 30);
+        let mut loaded: ClusteredIndex<_, ListBackend> = ClusteredIndex::new_from_mmap(extended, &path).unwrap();
+        assert_eq!(loaded.open_wal(&log).unwrap(), 10);
+        let mut stale: ClusteredIndex<_, ListBackend> = ClusteredIndex::new_from_mmap(snapshot, &path).unwrap();
+        assert_eq!(stale.open_wal(&log).unwrap(), 30);
+        for index in [&loaded, &stale] {
+            assert_eq!(index.data().num_points(), 1030);
+            assert!((1000..1020).all(|id| index.cluster_of(id).is_none()));
+            assert!((1020..1030).all(|id| index.cluster_of(id).is_some()));
+        }
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_collections() {
+        let dir = test_dir("index_collections");
+        let path = dir.join("collections.clann");
+        let path = path.to_str().unwrap();
+
+        let text = unit_vectors(300, 8, 53);
+        let images = unit_vectors(200, 16, 54);
+        for (name, points) in [("text", &text), ("images", &images)] {
+            let config = Config {
+                dataset_name: name.to_string(),
+                index_mode: IndexMode::Flat,
+                ..Default::default()
+            };
+            let mut index: ClusteredIndex<_> = ClusteredIndex::new(config, AngularData::new(points.clone())).unwrap();
+            index.build().unwrap();
+            index.serialize_collection(path, name).unwrap();
+        }
+
+        let mut loaded: ClusteredIndex<_> =
+            ClusteredIndex::new_from_collection(AngularData::new(images.clone()), path, "images").unwrap();
+        assert_eq!(loaded.config().dataset_name, "images");
+        assert_eq!(loaded.search(&images.row(7).to_vec()).unwrap()[0].1, 7);
+        let mut loaded: ClusteredIndex<_> =
+            ClusteredIndex::new_from_collection(AngularData::new(text.clone()), path, "text").unwrap();
+        assert_eq!(loaded.search(&text.row(3).to_vec()).unwrap()[0].1, 3);
+
+        let missing: crate::core::Result<ClusteredIndex<_>> =
+            ClusteredIndex::new_from_collection(AngularData::new(text), path, "audio");
+        assert!(matches!(missing, Err(ClusteredIndexError::ConfigError(_))));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+    #[test]
+    fn test_borrowed_dataset_index() {
+        let points = unit_vectors(1000, 8, 55);
+        let config = Config {
+            index_mode: IndexMode::Flat,
+            ..Default::default()
+        };
+
+        // two indexes over the same points, without copying them
+        let mut first: ClusteredIndex<_> =
+            ClusteredIndex::new(config.clone(), AngularData::from_view(points.view())).unwrap();
+        let mut second: ClusteredIndex<_> =
+            ClusteredIndex::new(config, AngularData::from_slice(points.as_slice().unwrap(), (1000, 8)).unwrap()).unwrap();
+        first.build().unwrap();
+        second.build().unwrap();
+
+        let query = points.row(5).to_vec();
+        let found = first.search(&query).unwrap();
+        assert_eq!(found[0].1, 5);
+        assert_eq!(second.search(&query).unwrap(), found);
+    }
+
+    #[test]
+    fn test_out_of_core_index() {
+        use crate::metricdata::{OutOfCoreData, RowSource};
+
+        struct Rows(ndarray::Array2<f32>);
+        impl RowSource for Rows {
+            fn num_rows(&self) -> usize {
+                self.0.nrows()
+            }
+            fn dimensions(&self) -> usize {
+                self.0.ncols()
+            }
+            fn read_rows(&self, start: usize, end: usize) -> std::result::Result<ndarray::Array2<f32>, String> {
+                Ok(self.0.slice(ndarray::s![start..end, ..]).to_owned())
+            }
+        }
+
+        let points = unit_vectors(1000, 8, 56);
+        let config = Config {
+            index_mode: IndexMode::Flat,
+            ..Default::default()
+        };
+        let mut in_memory: ClusteredIndex<_> = ClusteredIndex::new(config.clone(), AngularData::new(points.clone())).unwrap();
+        let data = OutOfCoreData::new(Rows(points.clone()), 300).unwrap();
+        let mut out_of_core: ClusteredIndex<_> = ClusteredIndex::new(config, data).unwrap();
+        in_memory.build().unwrap();
+        out_of_core.build().unwrap();
+
+        let query = points.row(9).to_vec();
+        let found = out_of_core.search(&query).unwrap();
+        assert_eq!(found[0].1, 9);
+        assert_eq!(found, in_memory.search(&query).unwrap());
+    }
+
+    #[test]
+    fn test_scalar_quantization() {
+        let data = AngularData::new(unit_vectors(2000, 16, 57));
+        let queries = unit_vectors(20, 16, 58);
+        let config = Config {
+            index_mode: IndexMode::Flat,
+            scalar_quantization: true,
+            ..Default::default()
+        };
+
+        let mut index: ClusteredIndex<_> = ClusteredIndex::new(config, data.clone()).unwrap();
+        index.build().unwrap();
+        assert_eq!(index.stats().quantized_memory, index.quantizer.as_ref().unwrap().memory_used());
+
+        let mut total = 0.0;
+        for query in queries.rows() {
+            let query = query.as_slice().unwrap();
+            let found = index.search(query).unwrap();
+            // the returned distances are the exact ones
+            for &(distance, p) in &found {
+                assert_eq!(distance, data.distance_point(p, query));
+            }
+            assert!(found.windows(2).all(|w| w[0].0 <= w[1].0));
+            total += recall(&data, query, 10, &[], &found);
+        }
+        // the int8 codes rank the points of the scanned clusters, a neighbor within the
+        // quantization error of the k-th one may be swapped for the next point
+        assert!(total / 20.0 > 0.95);
+
+        // inserted points are quantized too
+        let point = queries.row(0).to_owned().insert_axis(ndarray::Axis(0));
+        let before = index.stats().quantized_memory;
+        let ids = index.insert_batch(&point).unwrap();
+        // 16 codes and a norm
+        assert_eq!(index.stats().quantized_memory, before + 16 + 4);
+        assert_eq!(index.search(queries.row(0).as_slice().unwrap()).unwrap()[0].1, ids[0]);
+    }
+
+    #[test]
+    fn test_product_quantization_pruning() {
+        let data = AngularData::new(unit_vectors(2000, 16, 59));
+        let queries = unit_vectors(20, 16, 60);
+        let config = Config {
+            index_mode: IndexMode::Flat,
+            ..Default::default()
+        };
+        let mut exact: ClusteredIndex<_> = ClusteredIndex::new(config.clone(), data.clone()).unwrap();
+        exact.build().unwrap();
+
+        let config = Config {
+            pq: Some(PqParams { m: 8, nbits: 5 }),
+            ..config
+        };
+        let mut index: ClusteredIndex<_> = ClusteredIndex::new(config, data.clone()).unwrap();
+        index.build().unwrap();
+        assert!(index.stats().quantized_memory > 0);
+
+        // the lower bounds never prune a neighbor
+        for query in queries.rows() {
+            let query = query.as_slice().unwrap();
+            assert_eq!(index.search(query).unwrap(), exact.search(query).unwrap());
+        }
+
+        let query = queries.row(0).to_vec();
+        let ids: Vec<usize> = (0..2000).collect();
+        let kth = exact.search(&query).unwrap()[9].0;
+        assert_eq!(index.prune(&ids, &query, None).len(), 2000);
+        let kept = index.prune(&ids, &query, Some(kth));
+        assert!(kept.len() < 1000);
+        assert!(exact.search(&query).unwrap().iter().all(|(_, p)| kept.contains(p)));
+
+        let config = Config {
+            pq: Some(PqParams { m: 3, nbits: 6 }),
+            ..Default::default()
+        };
+        let mut index: ClusteredIndex<_> = ClusteredIndex::new(config, data).unwrap();
+        assert!(matches!(index.build(), Err(ClusteredIndexError::ConfigError(_))));
+    }
+
+    #[test]
+    fn test_clusters() {
+        let data = AngularData::new(unit_vectors(500, 8, 61));
+        let config = Config {
+            index_mode: IndexMode::Flat,
+            ..Default::default()
+        };
+        let mut index: ClusteredIndex<_> = ClusteredIndex::new(config, data).unwrap();
+        assert_eq!(index.clusters().len(), 0);
+        assert_eq!(index.cluster_of(0), None);
+        index.build().unwrap();
+
+        let mut seen = vec![false; 500];
+        for (position, cluster) in index.clusters().enumerate() {
+            assert_eq!(cluster.id, position);
+            assert!(cluster.members.contains(&cluster.center));
+            for &member in cluster.members {
+                assert!(!seen[member]);
+                seen[member] = true;
+                assert_eq!(index.cluster_of(member), Some(cluster.id));
+                assert!(index.data().distance(cluster.center, member) <= cluster.radius + 1e-5);
+            }
+        }
+        assert!(seen.iter().all(|&s| s));
+        assert_eq!(index.cluster_of(500), None);
+    }
+
+    #[test]
+    fn test_build_with_partition() {
+        use crate::core::Partition;
+
+        let data = AngularData::new(unit_vectors(500, 8, 62));
+        let config = Config {
+            index_mode: IndexMode::Flat,
+            ..Default::default()
+        };
+        let mut index: ClusteredIndex<_> = ClusteredIndex::new(config.clone(), data.clone()).unwrap();
+        assert!(index.export_partition().is_err());
+        index.build().unwrap();
+        let partition = index.export_partition().unwrap();
+        assert_eq!(partition.num_clusters(), index.num_clusters());
+
+        // same clusters under another configuration
+        let mut other: ClusteredIndex<_> = ClusteredIndex::new(
+            Config {
+                num_clusters_factor: 0.1,
+                ..config.clone()
+            },
+            data.clone(),
+        )
+        .unwrap();
+        other.build_with_partition(&partition).unwrap();
+        assert_eq!(other.export_partition().unwrap(), partition);
+        for (a, b) in index.clusters().zip(other.clusters()) {
+            assert_eq!(a.members, b.members);
+            assert!((a.radius - b.radius).abs() < 1e-5);
+        }
+        let query = unit_vectors(1, 8, 63);
+        let query = query.row(0).to_vec();
+        assert_eq!(index.search(&query).unwrap(), other.search(&query).unwrap());
+
+        // labels of an external clustering
+        let labels: Vec<usize> = (0..500).map(|p| p % 4).collect();
+        let mut external: ClusteredIndex<_> = ClusteredIndex::new(config, data).unwrap();
+        external
+            .build_with_partition(&Partition::from_assignments(labels.clone()))
+            .unwrap();
+        assert_eq!(external.num_clusters(), 4);
+        assert_eq!(external.export_partition().unwrap().assignments, labels);
+        assert!(external
+            .build_with_partition(&Partition::from_assignments(vec![0; 10]))
+            .is_err());
+    }
+
+    #[test]
+    fn test_rebuild_from_artifact() {
+        use crate::core::PartitionArtifact;
+
+        let data = AngularData::new(unit_vectors(500, 8, 64));
+        let config = Config {
+            index_mode: IndexMode::Flat,
+            outlier_quantile: Some(0.9),
+            ..Default::default()
+        };
+        let mut index: ClusteredIndex<_> = ClusteredIndex::new(config, data.clone()).unwrap();
+        assert!(index.export_artifact().is_err());
+        index.build().unwrap();
+
+        let dir = test_dir("partition_artifact");
+        let path = dir.join("artifact.json");
+        let path = path.to_str().unwrap();
+        let artifact = index.export_artifact().unwrap();
+        assert!(!artifact.outliers.is_empty());
+        artifact.save(path).unwrap();
+        let artifact = PartitionArtifact::load(path).unwrap();
+        std::fs::remove_dir_all(dir).unwrap();
+
+        let mut rebuilt: ClusteredIndex<_> = ClusteredIndex::new(artifact.config.clone(), data).unwrap();
+        rebuilt.rebuild_from_artifact(&artifact).unwrap();
+        assert_eq!(rebuilt.export_partition().unwrap(), index.export_partition().unwrap());
+        assert_eq!(rebuilt.outlier_probes(), index.outlier_probes());
+        let query = unit_vectors(1, 8, 65);
+        let query = query.row(0).to_vec();
+        assert_eq!(index.search(&query).unwrap(), rebuilt.search(&query).unwrap());
+
+        // the clusters don't fit another dataset of the same size
+        let other = AngularData::new(unit_vectors(500, 8, 66));
+        let mut wrong: ClusteredIndex<_> = ClusteredIndex::new(artifact.config.clone(), other).unwrap();
+        assert!(matches!(
+            wrong.rebuild_from_artifact(&artifact),
+            Err(ClusteredIndexError::DataError(_))
+        ));
+    }
+
+    #[test]
+    fn test_build_sampled() {
+        let points = unit_vectors(1000, 8, 67);
+        let config = Config {
+            index_mode: IndexMode::Flat,
+            ..Default::default()
+        };
+        let mut index: ClusteredIndex<_> = ClusteredIndex::new(config, AngularData::new(points.clone())).unwrap();
+        let sample: Vec<usize> = (0..1000).step_by(5).collect();
+        index.build_sampled(&sample).unwrap();
+
+        // the centers come from the sample, every point is in the cluster of its nearest center
+        let data = index.data();
+        for cluster in index.clusters() {
+            assert_eq!(cluster.center % 5, 0);
+            for &member in cluster.members {
+                let nearest = index
+                    .clusters()
+                    .map(|other| data.distance(member, other.center))
+                    .fold(f32::INFINITY, f32::min);
+                assert_eq!(data.distance(member, cluster.center), nearest);
+            }
+        }
+        assert_eq!(index.clusters().map(|c| c.members.len()).sum::<usize>(), 1000);
+        assert_eq!(index.search(&points.row(4).to_vec()).unwrap()[0].1, 4);
+        assert!(index.build_sampled(&[]).is_err());
+    }
+
+    #[test]
+    fn test_estimate_hardness() {
+        let data = AngularData::new(unit_vectors(2000, 8, 68));
+        let config = Config {
+            index_mode: IndexMode::Flat,
+            ..Default::default()
+        };
+        let mut index: ClusteredIndex<_> = ClusteredIndex::new(config, data).unwrap();
+        let query = unit_vectors(1, 8, 69).row(0).to_vec();
+        assert!(matches!(index.estimate_hardness(&query), Err(ClusteredIndexError::ConfigError(_))));
+        index.build().unwrap();
+
+        // a center is at depth 0 of its cluster, up to round-off
+        let center = index.clusters().next().unwrap().center;
+        let hardness = index.estimate_hardness(&index.data().get_point(center)).unwrap();
+        assert!(hardness.relative_depth < 1e-3);
+        assert!(hardness.margin.is_some());
+
+        // the hardest half of the queries computes more distances than the easiest half
+        let queries = unit_vectors(200, 8, 70);
+        let mut runs: Vec<(f32, usize)> = queries
+            .rows()
+            .into_iter()
+            .map(|query| {
+                let query = query.to_vec();
+                let hardness = index.estimate_hardness(&query).unwrap();
+                assert!((0.0..=1.0).contains(&hardness.score));
+                assert!(hardness.clusters_in_range >= 1);
+                index.search(&query).unwrap();
+                (hardness.score, index.last_distance_computations())
+            })
+            .collect();
+        runs.sort_by(|a, b| a.0.total_cmp(&b.0));
+        let work = |runs: &[(f32, usize)]| runs.iter().map(|r| r.1).sum::<usize>();
+        assert!(work(&runs[..100]) < work(&runs[100..]));
+    }
+
+    #[test]
+    fn test_nearest_clusters() {
+        let data = AngularData::new(unit_vectors(500, 8, 71));
+        let config = Config {
+            index_mode: IndexMode::Flat,
+            ..Default::default()
+        };
+        let mut index: ClusteredIndex<_> = ClusteredIndex::new(config, data).unwrap();
+        index.build().unwrap();
+        let query = unit_vectors(1, 8, 72);
+        let query = query.row(0).to_vec();
+
+        let all = index.nearest_clusters(&query, usize::MAX).unwrap();
+        assert_eq!(all.len(), index.num_clusters());
+        assert!(all.windows(2).all(|w| w[0].distance <= w[1].distance));
+        let plan = index.plan(&query).unwrap();
+        assert_eq!(all[0].cluster, plan.steps[0].cluster);
+
+        let nearest = index.nearest_clusters(&query, 3).unwrap();
+        assert_eq!(nearest, all[..3]);
+        let cluster = index.clusters().nth(nearest[0].cluster).unwrap();
+        assert_eq!((nearest[0].center, nearest[0].radius), (cluster.center, cluster.radius));
+        assert!(index.nearest_clusters(&query, 0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_merge() {
+        let config = Config {
+            index_mode: IndexMode::Flat,
+            ..Default::default()
+        };
+        let left = unit_vectors(300, 8, 73);
+        let right = unit_vectors(200, 8, 74);
+        let mut index: ClusteredIndex<_> = ClusteredIndex::new(config.clone(), AngularData::new(left.clone())).unwrap();
+        let mut other: ClusteredIndex<_> = ClusteredIndex::new(config.clone(), AngularData::new(right.clone())).unwrap();
+        index.build().unwrap();
+        other.build().unwrap();
+        let (left_clusters, right_clusters) = (index.num_clusters(), other.num_clusters());
+        let other_partition = other.export_partition().unwrap();
+
+        assert_eq!(index.merge(other).unwrap(), 300);
+        assert_eq!(index.data().num_points(), 500);
+        assert_eq!(index.num_clusters(), left_clusters + right_clusters);
+        for (point, &cluster) in other_partition.assignments.iter().enumerate() {
+            assert_eq!(index.cluster_of(300 + point), Some(left_clusters + cluster));
+        }
+
+        // the merged index finds the points of both sides
+        let query = right.row(42).to_vec();
+        let result = index.search(&query).unwrap();
+        assert_eq!(result[0].1, 342);
+        let query = left.row(7).to_vec();
+        assert_eq!(index.search(&query).unwrap()[0].1, 7);
+
+        let unbuilt: ClusteredIndex<_> = ClusteredIndex::new(config, AngularData::new(right)).unwrap();
+        assert!(index.merge(unbuilt).is_err());
+    }
+
+    #[test]
+    fn test_repartition() {
+        use crate::core::RepartitionPolicy;
+
+        let data = AngularData::new(unit_vectors(300, 8, 75));
+        let config = Config {
+            index_mode: IndexMode::Flat,
+            ..Default::default()
+        };
+        let mut index: ClusteredIndex<_> = ClusteredIndex::new(config, data).unwrap();
+        index.build().unwrap();
+        let before = index.num_clusters();
+
+        // drift: 200 points around the center of the first cluster
+        let center = index.data().get_point(index.clusters().next().unwrap().center).to_vec();
+        let mut points = unit_vectors(200, 8, 76).mapv(|v| v * 0.01);
+        for mut row in points.rows_mut() {
+            row += &ndarray::ArrayView1::from(&center);
+        }
+        index.insert_batch(&points).unwrap();
+        let largest = index.clusters().map(|c| c.members.len()).max().unwrap();
+        assert!(largest > 200);
+
+        let changed = index
+            .repartition(RepartitionPolicy::SplitOversized { max_points: 100 })
+            .unwrap();
+        assert!(changed >= 3);
+        assert!(index.num_clusters() > before);
+        let assert_partition = |index: &ClusteredIndex<_>| {
+            let mut seen = vec![false; 500];
+            for cluster in index.clusters() {
+                assert_eq!(cluster.id, index.clusters().position(|c| c.id == cluster.id).unwrap());
+                assert!(cluster.members.contains(&cluster.center));
+                for &member in cluster.members {
+                    assert!(!std::mem::replace(&mut seen[member], true));
+                }
+            }
+            assert!(seen.iter().all(|&s| s));
+        };
+        assert_partition(&index);
+        let query = points.row(3).to_vec();
+        assert_eq!(index.search(&query).unwrap()[0].1, 303);
+
+        let changed = index.repartition(RepartitionPolicy::Full).unwrap();
+        assert!(changed > 0);
+        assert_eq!(index.num_clusters(), (500f64.sqrt()).floor() as usize);
+        assert_partition(&index);
+        assert_eq!(index.search(&query).unwrap()[0].1, 303);
+        // nothing drifted since, every cluster keeps its index
+        assert_eq!(index.repartition(RepartitionPolicy::Full).unwrap(), 0);
+
+        assert!(index
+            .repartition(RepartitionPolicy::SplitOversized { max_points: 0 })
+            .is_err());
+    }
+
+    #[test]
+    fn test_build_observer_and_cancellation() {
+        use crate::core::{BuildPhase, CancellationToken, ClusteredIndexError};
+        use std::sync::{Arc, Mutex};
+
+        let data = AngularData::new(unit_vectors(500, 8, 77));
+        let config = Config {
+            index_mode: IndexMode::Flat,
+            ..Default::default()
+        };
+        let mut index: ClusteredIndex<_> = ClusteredIndex::new(config, data).unwrap();
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let recorded = events.clone();
+        index.set_build_observer(move |phase, cluster, fraction, eta| {
+            recorded.lock().unwrap().push((phase, cluster, fraction, eta));
+        });
+        index.build().unwrap();
+
+        let events = std::mem::take(&mut *events.lock().unwrap());
+        let clustering: Vec<_> = events.iter().filter(|e| e.0 == BuildPhase::Clustering).collect();
+        let indexing: Vec<_> = events.iter().filter(|e| e.0 == BuildPhase::Indexing).collect();
+        assert_eq!(clustering.len() + indexing.len(), events.len());
+        assert_eq!(clustering.len(), index.num_clusters());
+        assert_eq!(clustering.last().unwrap().2, 1.0);
+        assert_eq!(clustering.last().unwrap().3, Some(Duration::ZERO));
+        // the clustering phase comes first
+        assert!(events[..clustering.len()].iter().all(|e| e.0 == BuildPhase::Clustering));
+        assert_eq!(indexing.len(), index.num_clusters());
+        assert!(indexing.iter().enumerate().all(|(i, e)| e.1 == i));
+
+        // cancelled from the observer, during each phase
+        for cancel_phase in [BuildPhase::Clustering, BuildPhase::Indexing] {
+            let token = CancellationToken::new();
+            let canceller = token.clone();
+            index.set_cancellation_token(token);
+            index.set_build_observer(move |phase, cluster, _, _| {
+                if phase == cancel_phase && cluster == 2 {
+                    canceller.cancel();
+                }
+            });
+            assert_eq!(index.build(), Err(ClusteredIndexError::Cancelled));
+            assert_eq!(index.num_clusters(), 0);
+        }
+
+        index.set_cancellation_token(CancellationToken::new());
+        index.build().unwrap();
+        assert!(index.num_clusters() > 2);
+    }
+
+    #[test]
+    fn test_build_resume() {
+        use crate::core::{BuildPhase, CancellationToken, ClusteredIndexError};
+
+        let dir = test_dir("build_resume");
+        let dir = dir.to_str().unwrap();
+        let data = AngularData::new(unit_vectors(500, 8, 78));
+        let config = Config {
+            index_mode: IndexMode::Flat,
+            ..Default::default()
+        };
+        let mut expected: ClusteredIndex<_> = ClusteredIndex::new(config.clone(), data.clone()).unwrap();
+        expected.build().unwrap();
+
+        // interrupted while creating the cluster indices, after the clustering was checkpointed
+        let mut index: ClusteredIndex<_> = ClusteredIndex::new(config.clone(), data.clone()).unwrap();
+        let token = CancellationToken::new();
+        let canceller = token.clone();
+        index.set_cancellation_token(token);
+        index.set_build_observer(move |phase, cluster, _, _| {
+            if phase == BuildPhase::Indexing && cluster == 3 {
+                canceller.cancel();
+            }
+        });
+        assert_eq!(index.build_resume(dir), Err(ClusteredIndexError::Cancelled));
+        assert!(std::path::Path::new(dir).join("checkpoint.json").exists());
+
+        // another configuration cannot resume it
+        let mut other: ClusteredIndex<_> = ClusteredIndex::new(
+            Config {
+                num_clusters_factor: 0.5,
+                ..config
+            },
+            data,
+        )
+        .unwrap();
+        assert!(matches!(other.build_resume(dir), Err(ClusteredIndexError::ConfigError(_))));
+
+        index.set_cancellation_token(CancellationToken::new());
+        index.build_resume(dir).unwrap();
+        assert_eq!(index.export_partition().unwrap(), expected.export_partition().unwrap());
+        assert_eq!(std::fs::read_dir(dir).unwrap().count(), 0);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(feature = "rust-lsh")]
+    #[test]
+    fn test_build_resume_reads_cluster_indices() {
+        use crate::core::{BuildPhase, CancellationToken};
+        use crate::lsh::CrossPolytopeIndex;
+
+        let dir = test_dir("build_resume_lsh");
+        let data = AngularData::new(unit_vectors(2000, 8, 79));
+        let config = Config {
+            num_clusters_factor: 0.1,
+            num_tables: 4,
+            ..Default::default()
+        };
+        let mut index: ClusteredIndex<_, CrossPolytopeIndex> = ClusteredIndex::new(config, data).unwrap();
+
+        let token = CancellationToken::new();
+        let canceller = token.clone();
+        index.set_cancellation_token(token);
+        index.set_build_observer(move |phase, cluster, _, _| {
+            if phase == BuildPhase::Indexing && cluster == 1 {
+                canceller.cancel();
+            }
+        });
+        assert!(index.build_resume(dir.to_str().unwrap()).is_err());
+        assert!(dir.join("cluster_0.bin").exists() && dir.join("cluster_1.bin").exists());
+        assert!(!dir.join("cluster_2.bin").exists());
+
+        let resumed = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let recorded = resumed.clone();
+        index.set_cancellation_token(CancellationToken::new());
+        index.set_build_observer(move |phase, cluster, _, _| {
+            if phase == BuildPhase::Indexing {
+                recorded.lock().unwrap().push(cluster);
+            }
+        });
+        index.build_resume(dir.to_str().unwrap()).unwrap();
+        assert_eq!(resumed.lock().unwrap().len(), index.num_clusters());
+        assert!(index.clusters().all(|c| !c.brute_force));
+        let query = index.data().get_point(7).to_vec();
+        assert_eq!(index.search(&query).unwrap()[0].1, 7);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_reranker() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        use crate::core::{ClusteredIndexError, Reranker, Result};
+
+        /// Reranks on the CPU, counting the points it is given
+        struct Counting {
+            data: AngularData<ndarray::OwnedRepr<f32>>,
+            points: Arc<AtomicUsize>,
+            fail: bool,
+        }
+        impl Reranker<f32> for Counting {
+            fn distances(&self, ids: &[usize], query: &[f32], out: &mut [f32]) -> Result<()> {
+                if self.fail {
+                    return Err(ClusteredIndexError::RerankError("no device".to_string()));
+                }
+                self.points.fetch_add(ids.len(), Ordering::Relaxed);
+                self.data.distances_points(ids, query, out);
+                Ok(())
+            }
+
+            fn min_batch(&self) -> usize {
+                10
+            }
+        }
+
+        let points = unit_vectors(1000, 8, 80);
+        let query = unit_vectors(1, 8, 81).row(0).to_vec();
+        let config = Config {
+            index_mode: IndexMode::Flat,
+            k: 5,
+            ..Default::default()
+        };
+        let mut index: ClusteredIndex<_> = ClusteredIndex::new(config, AngularData::new(points.clone())).unwrap();
+        index.build().unwrap();
+        let expected = index.search(&query).unwrap();
 
-        let cluster_indices: [usize; 3] = [6, 3, 17];
-        let mut clusters = Vec::new();
-        for (idx, center_idx) in cluster_indices.iter().enumerate() {
-            clusters.push(ClusterCenter {
-                idx,
-                center_idx: *center_idx,
-                radius: 0.0,
-                assignment: vec![],
-                brute_force: false,
-                memory_used: 0,
-            });
+        let reranked = Arc::new(AtomicUsize::new(0));
+        index.set_reranker(Counting {
+            data: AngularData::new(points.clone()),
+            points: reranked.clone(),
+            fail: false,
+        });
+        assert_eq!(index.search(&query).unwrap(), expected);
+        // the probed clusters with at least min_batch points went to the reranker
+        let probed: usize = index
+            .clusters
+            .iter()
+            .filter(|c| c.search_stats.probes > 0 && c.assignment.len() >= 10)
+            .map(|c| c.assignment.len())
+            .sum();
+        assert!(probed > 0);
+        assert_eq!(reranked.load(Ordering::Relaxed), probed);
+
+        index.set_reranker(Counting {
+            data: AngularData::new(points),
+            points: reranked,
+            fail: true,
+        });
+        assert!(matches!(index.search(&query), Err(ClusteredIndexError::RerankError(_))));
+        index.clear_reranker();
+        assert_eq!(index.search(&query).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_search_batch_grouped() {
+        let data = AngularData::new(unit_vectors(1000, 8, 82));
+        let queries = unit_vectors(50, 8, 83);
+        let config = Config {
+            index_mode: IndexMode::Flat,
+            k: 5,
+            ..Default::default()
+        };
+        let mut index: ClusteredIndex<_> = ClusteredIndex::new(config, data).unwrap();
+        index.build().unwrap();
+
+        let mut probes_one_by_one = 0;
+        let mut expected = Vec::new();
+        for query in queries.rows() {
+            expected.push(index.search(&query.to_vec()).unwrap());
+            probes_one_by_one += index.last_distance_computations();
         }
+        let grouped = index.search_batch_grouped(&queries).unwrap();
+        assert_eq!(grouped, expected);
+        // same clusters probed, so the same distance computations
+        assert_eq!(index.last_distance_computations(), probes_one_by_one);
+        assert!(index.search_batch_grouped(&queries.slice(ndarray::s![..0, ..])).unwrap().is_empty());
+    }
 
-        let config = Config::default();
+    #[test]
+    fn test_search_batch_matrix() {
+        let data = AngularData::new(unit_vectors(1000, 8, 84));
+        let queries = unit_vectors(20, 8, 85);
+        let config = Config {
+            index_mode: IndexMode::Flat,
+            k: 5,
+            ..Default::default()
+        };
+        let mut index: ClusteredIndex<_> = ClusteredIndex::new(config, data).unwrap();
+        index.build().unwrap();
 
-        let mut index = ClusteredIndex {
-            data,
-            clusters,
-            config,
-            puffinn_indices: Vec::new(),
-            metrics: None,
+        let expected = index.search_batch(&queries).unwrap();
+        let (ids, distances) = index.search_batch_matrix(&queries).unwrap();
+        assert_eq!(ids.dim(), (20, 5));
+        assert_eq!(distances.dim(), (20, 5));
+        for (i, result) in expected.iter().enumerate() {
+            for (j, &(distance, id)) in result.iter().enumerate() {
+                assert_eq!((distances[[i, j]], ids[[i, j]] as usize), (distance, id));
+            }
+        }
+
+        // fewer results than k
+        let (ids, distances) = result_matrices(&[vec![(0.5, 3)], vec![]], 2);
+        assert_eq!(ids, ndarray::arr2(&[[3, NO_NEIGHBOR], [NO_NEIGHBOR, NO_NEIGHBOR]]));
+        assert_eq!(distances.row(0).to_vec(), vec![0.5, f32::INFINITY]);
+        assert!(distances.row(1).iter().all(|d| d.is_infinite()));
+    }
+
+    #[test]
+    fn test_diagnose_misses() {
+        let data = AngularData::new(unit_vectors(1000, 8, 86));
+        let queries = unit_vectors(20, 8, 87);
+        let mut ground_truth = Array2::zeros((20, 5));
+        for (i, query) in queries.rows().into_iter().enumerate() {
+            for (j, id) in brute_force_search(&data, query.as_slice().unwrap(), 5).into_iter().enumerate() {
+                ground_truth[[i, j]] = id as usize;
+            }
+        }
+        let config = Config {
+            index_mode: IndexMode::Flat,
+            k: 5,
+            max_clusters_probed: Some(1),
+            ..Default::default()
         };
+        let mut index: ClusteredIndex<_> = ClusteredIndex::new(config, data).unwrap();
+        index.build().unwrap();
 
-        let sorted_indices = index.sort_cluster_indices_by_distance(&[0.1, 0.0, 0.7]);
+        // the clusters are scanned exhaustively, the neighbors are missed in the clusters not probed
+        let misses = index.diagnose_misses(&queries, &ground_truth).unwrap();
+        assert_eq!(misses.len(), 20);
+        let limited = MissCounts::total(&misses);
+        assert!(limited.unvisited > 0);
+        assert_eq!(limited.not_returned, 0);
+        for (query, truth) in misses.iter().zip(ground_truth.rows()) {
+            for miss in &query.misses {
+                assert!(truth.iter().any(|&p| p == miss.point));
+                assert_eq!(index.cluster_of(miss.point), Some(miss.cluster));
+            }
+        }
 
-        assert_eq!(sorted_indices, vec![2, 0, 1]);
+        // the clusters left out by the limit are probed
+        index.set_max_clusters_probed(None).unwrap();
+        let misses = index.diagnose_misses(&queries, &ground_truth).unwrap();
+        let unlimited = MissCounts::total(&misses);
+        assert!(unlimited.unvisited < limited.unvisited);
+        assert_eq!(unlimited.not_returned, 0);
+
+        assert!(index.diagnose_misses(&queries, &ground_truth.slice(ndarray::s![.., ..3]).to_owned()).is_err());
+        assert!(index.diagnose_misses(&queries.slice(ndarray::s![..2, ..]), &ground_truth).is_err());
+    }
+
+    #[test]
+    fn test_search_cluster() {
+        let points = unit_vectors(1000, 8, 88);
+        let data = AngularData::new(points.clone());
+        let config = Config {
+            index_mode: IndexMode::Flat,
+            ..Default::default()
+        };
+        let mut index: ClusteredIndex<_> = ClusteredIndex::new(config, data.clone()).unwrap();
+        index.build().unwrap();
+
+        // probing every cluster by hand finds the exact neighbors
+        let query = unit_vectors(1, 8, 89).row(0).to_vec();
+        let mut found = Vec::new();
+        for cluster in index.nearest_clusters(&query, usize::MAX).unwrap() {
+            let results = index.search_cluster(cluster.cluster, &query, 10).unwrap();
+            assert!(results.windows(2).all(|w| w[0].0 <= w[1].0));
+            assert!(results.iter().all(|&(_, p)| index.cluster_of(p) == Some(cluster.cluster)));
+            found.extend(results);
+        }
+        found.sort_by(|a, b| a.0.total_cmp(&b.0));
+        let found: Vec<usize> = found.iter().take(10).map(|&(_, p)| p).collect();
+        let expected: Vec<usize> = brute_force_search(&data, &query, 10).into_iter().map(|p| p as usize).collect();
+        assert_eq!(found, expected);
+
+        assert!(index.search_cluster(0, &query, 0).unwrap().is_empty());
+        let clusters = index.num_clusters();
+        assert_eq!(
+            index.search_cluster(clusters, &query, 10),
+            Err(ClusteredIndexError::IndexOutOfBounds(clusters, clusters))
+        );
+    }
+
+    /// Backend remembering the points of its cluster, 8 bytes each, without searching them
+    struct ListBackend(Vec<usize>);
+
+    thread_local! {
+        // threads of the last ListBackend::set_num_threads of the test
+        static LIST_THREADS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+        // the builds of ListBackend on this thread fail while set
+        static LIST_FAILS: std::cell::Cell<bool> = const { std::cell::Cell::new(false) };
+    }
+
+    impl<M: MetricData> ClusterBackend<M> for ListBackend {
+        fn build(_data: &M, indices: &[usize], _num_tables: usize) -> std::result::Result<(Self, usize), String> {
+            if LIST_FAILS.with(|fails| fails.get()) {
+                return Err("build failed".to_string());
+            }
+            Ok((ListBackend(indices.to_vec()), 8 * indices.len()))
+        }
+
+        fn set_num_threads(num_threads: usize) {
+            LIST_THREADS.with(|threads| threads.set(num_threads));
+        }
+
+        fn search(&self, _query: &[M::DataType], _k: usize, _max_dist: f32, _recall: f32) -> std::result::Result<(Vec<u32>, usize), String> {
+            Ok((Vec::new(), 0))
+        }
+
+        fn to_bytes(&self) -> std::result::Result<Vec<u8>, String> {
+            Ok(self.0.iter().flat_map(|&p| (p as u64).to_le_bytes()).collect())
+        }
+
+        fn from_bytes(bytes: &[u8]) -> std::result::Result<Self, String> {
+            Ok(ListBackend(bytes.chunks_exact(8).map(|w| u64::from_le_bytes(w.try_into().unwrap()) as usize).collect()))
+        }
+    }
+
+    /// Backend searching the points of its cluster exactly by angular distance, so that the
+    /// candidates of an indexed cluster are deterministic
+    struct ExactBackend(Vec<Vec<f32>>);
+
+    impl<M: MetricData<DataType = f32>> ClusterBackend<M> for ExactBackend {
+        fn build(data: &M, indices: &[usize], _num_tables: usize) -> std::result::Result<(Self, usize), String> {
+            let points: Vec<Vec<f32>> = indices.iter().map(|&i| data.get_point(i).into_owned()).collect();
+            let memory = points.iter().map(|p| p.len() * 4).sum();
+            Ok((ExactBackend(points), memory))
+        }
+
+        fn search(&self, query: &[f32], k: usize, max_dist: f32, _recall: f32) -> std::result::Result<(Vec<u32>, usize), String> {
+            let norm = |p: &[f32]| p.iter().map(|x| x * x).sum::<f32>().sqrt();
+            let mut found: Vec<(f32, u32)> = self
+                .0
+                .iter()
+                .enumerate()
+                .map(|(i, p)| {
+                    let dot: f32 = p.iter().zip(query).map(|(a, b)| a * b).sum();
+                    (1.0 - dot / (norm(p) * norm(query)), i as u32)
+                })
+                .filter(|&(distance, _)| distance <= max_dist)
+                .collect();
+            found.sort_by(|a, b| a.0.total_cmp(&b.0));
+            found.truncate(k);
+            Ok((found.into_iter().map(|(_, i)| i).collect(), self.0.len()))
+        }
+
+        fn to_bytes(&self) -> std::result::Result<Vec<u8>, String> {
+            Err("not serializable".to_string())
+        }
+
+        fn from_bytes(_bytes: &[u8]) -> std::result::Result<Self, String> {
+            Err("not serializable".to_string())
+        }
+    }
+
+    #[test]
+    fn test_search_multi_indexed() {
+        let data = AngularData::new(unit_vectors(2000, 8, 90));
+        let queries = unit_vectors(3, 8, 91);
+        let queries: Vec<&[f32]> = queries.rows().into_iter().map(|q| q.to_slice().unwrap()).collect();
+        // a few large clusters, with an index each
+        let config = Config {
+            index_mode: IndexMode::Lsh,
+            num_clusters_factor: 0.1,
+            ..Default::default()
+        };
+        let mut index: ClusteredIndex<_, ExactBackend> = ClusteredIndex::new(config, data.clone()).unwrap();
+        index.build().unwrap();
+        assert!(index.puffinn_indices.iter().any(Option::is_some));
+
+        // the points nearest by the mean need not be among the k nearest of any vector
+        for aggregation in [Aggregation::Min, Aggregation::Mean] {
+            let mut exact: Vec<(f32, usize)> = (0..data.num_points())
+                .map(|p| (aggregation.combine(queries.iter().map(|q| data.distance_point(p, q))) as f32, p))
+                .collect();
+            exact.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+            let found = index.search_multi(&queries, aggregation).unwrap();
+            assert_eq!(found.len(), 10);
+            for (&(distance, _), &(expected, _)) in found.iter().zip(&exact) {
+                assert!((distance - expected).abs() < 1e-5, "{:?}", aggregation);
+            }
+        }
+    }
+
+    #[test]
+    fn test_num_threads() {
+        let data = AngularData::new(unit_vectors(1000, 8, 92));
+        let config = Config {
+            num_threads: Some(3),
+            ..Default::default()
+        };
+        let mut index: ClusteredIndex<_, ListBackend> = ClusteredIndex::new(config.clone(), data.clone()).unwrap();
+        index.build().unwrap();
+        assert_eq!(LIST_THREADS.with(|threads| threads.get()), 3);
+
+        // one per core by default
+        let mut index: ClusteredIndex<_, ListBackend> = ClusteredIndex::new(Config::default(), data).unwrap();
+        index.build().unwrap();
+        assert_eq!(LIST_THREADS.with(|threads| threads.get()), Config::default().threads());
+        assert!(Config::default().threads() >= 1);
+    }
+
+    #[test]
+    fn test_rebuild_unreadable_clusters() {
+        let data = AngularData::new(unit_vectors(1000, 8, 93));
+        let config = Config {
+            num_clusters_factor: 0.2,
+            ..Default::default()
+        };
+        let mut index: ClusteredIndex<_, ListBackend> = ClusteredIndex::new(config, data).unwrap();
+        index.build().unwrap();
+        assert!(index.rebuilt_clusters().is_empty());
+
+        // as if the index of the cluster couldn't be read
+        let position = index.clusters.iter().position(|c| !c.brute_force).unwrap();
+        let idx = index.clusters[position].idx;
+        let members = index.puffinn_indices[idx].take().unwrap().0;
+
+        index.rebuild_unreadable(&[(position, "missing".to_string())]).unwrap();
+        assert_eq!(index.rebuilt_clusters(), &[idx]);
+        assert_eq!(index.puffinn_indices[idx].as_ref().unwrap().0, members);
+        assert!(index.clusters.iter().all(|c| c.brute_force == index.puffinn_indices[c.idx].is_none()));
+    }
+
+    #[test]
+    fn test_exact_search() {
+        use crate::core::PruningRadius;
+        use crate::utils::brute_force_search;
+
+        let data = AngularData::new(unit_vectors(2000, 8, 94));
+        let queries = unit_vectors(20, 8, 95);
+        let config = Config {
+            num_clusters_factor: 0.5,
+            max_clusters_probed: Some(1),
+            pruning_radius: PruningRadius::P90,
+            ..Default::default()
+        };
+        // the cluster indexes return nothing, only the scans find neighbors
+        let mut index: ClusteredIndex<_, ListBackend> = ClusteredIndex::new(config, data.clone()).unwrap();
+        index.build().unwrap();
+        assert!(index.clusters.iter().any(|c| !c.brute_force));
+
+        index.set_exact(true);
+        let results = index.search_batch(&queries).unwrap();
+        for (query, found) in queries.rows().into_iter().zip(&results) {
+            let expected: Vec<usize> = brute_force_search(&data, query.as_slice().unwrap(), 10)
+                .into_iter()
+                .map(|p| p as usize)
+                .collect();
+            assert_eq!(found.iter().map(|&(_, p)| p).collect::<Vec<_>>(), expected);
+            assert_eq!(&index.search_readonly(query.as_slice().unwrap()).unwrap(), found);
+        }
+        assert_eq!(index.search_batch_grouped(&queries).unwrap(), results);
+
+        index.set_exact(false);
+        assert_ne!(index.search_batch(&queries).unwrap(), results);
+    }
+
+    #[test]
+    fn test_truncated_binary_file() {
+        let data = AngularData::new(unit_vectors(1000, 8, 96));
+        let config = Config {
+            num_clusters_factor: 0.2,
+            dataset_name: "test_truncated_binary_file".to_string(),
+            ..Default::default()
+        };
+        let mut index: ClusteredIndex<_, ListBackend> = ClusteredIndex::new(config, data.clone()).unwrap();
+        index.build().unwrap();
+        let last = index.clusters.iter().rfind(|c| !c.brute_force).unwrap().idx;
+
+        let dir = test_dir("truncated_binary_file");
+        let directory = dir.to_str().unwrap();
+        index.serialize_binary(directory).unwrap();
+        let path = index.binary_file_path(directory);
+        let bytes = std::fs::read(&path).unwrap();
+
+        // the blob of the last indexed cluster is cut short
+        std::fs::write(&path, &bytes[..bytes.len() - 1]).unwrap();
+        match ClusteredIndex::<_, ListBackend>::new_from_mmap(data.clone(), &path) {
+            Err(ClusteredIndexError::DeserializeError { cluster, .. }) => assert_eq!(cluster, last),
+            other => panic!("expected a DeserializeError, got {:?}", other.map(|_| ())),
+        }
+
+        // so is the header
+        std::fs::write(&path, &bytes[..30]).unwrap();
+        assert!(matches!(
+            ClusteredIndex::<_, ListBackend>::new_from_mmap(data, &path),
+            Err(ClusteredIndexError::ConfigError(_))
+        ));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_estimate_build() {
+        let data = AngularData::new(unit_vectors(3000, 8, 97));
+        let config = Config {
+            num_clusters_factor: 0.2,
+            ..Default::default()
+        };
+
+        // the sample is the whole dataset, the clusters are those of the build
+        let estimate = ClusteredIndex::<_, ListBackend>::estimate_build(&data, &config).unwrap();
+        let mut index: ClusteredIndex<_, ListBackend> = ClusteredIndex::new(config.clone(), data.clone()).unwrap();
+        index.build().unwrap();
+        let mut sizes: Vec<usize> = index.clusters().map(|c| c.members.len()).collect();
+        sizes.sort_unstable_by(|a, b| b.cmp(a));
+        assert_eq!(estimate.sample_points, 3000);
+        assert_eq!(estimate.clusters.iter().map(|c| c.num_points).collect::<Vec<_>>(), sizes);
+
+        assert_eq!(estimate.pilot_points, 2000);
+        assert_eq!(estimate.memory_per_point, 8.0);
+        assert_eq!(estimate.memory_bytes, index.build_report().unwrap().memory_used);
+        for cluster in &estimate.clusters {
+            assert_eq!(cluster.brute_force, cluster.num_points < 100);
+            assert_eq!(cluster.memory_bytes, if cluster.brute_force { 0 } else { 8 * cluster.num_points });
+        }
+        let indexing: Duration = estimate.clusters.iter().map(|c| c.build_time).sum();
+        assert_eq!(estimate.build_time, estimate.clustering_time + indexing);
+
+        // a larger dataset is sampled, its clusters stand for all the points
+        let data = AngularData::new(unit_vectors(25_000, 4, 98));
+        let estimate = ClusteredIndex::<_, ListBackend>::estimate_build(&data, &config).unwrap();
+        assert_eq!(estimate.sample_points, ESTIMATE_SAMPLE_POINTS);
+        assert_eq!(estimate.clusters.len(), (0.2 * 25_000f64.sqrt()) as usize);
+        let total: usize = estimate.clusters.iter().map(|c| c.num_points).sum();
+        assert!(total.abs_diff(25_000) <= estimate.clusters.len());
+
+        // nothing to build without indices
+        let flat = Config {
+            index_mode: IndexMode::Flat,
+            ..config
+        };
+        let estimate = ClusteredIndex::<_, ListBackend>::estimate_build(&data, &flat).unwrap();
+        assert_eq!(estimate.pilot_points, 0);
+        assert_eq!(estimate.memory_bytes, 0);
+        assert!(estimate.clusters.iter().all(|c| c.brute_force));
+    }
+
+    #[test]
+    fn test_result_ordering() {
+        // points on a grid, many at the same distance from the queries
+        let points = Array2::from_shape_fn((400, 2), |(i, j)| if j == 0 { (i % 20) as f32 } else { (i / 20) as f32 });
+        let l2 = |a: &[f32], b: &[f32]| a.iter().zip(b).map(|(x, y)| (x - y) * (x - y)).sum::<f32>().sqrt();
+        let queries = arr2(&[[10.0f32, 10.0], [0.0, 0.0], [5.0, 7.0]]);
+        let sorted = |results: &[(f32, usize)]| results.windows(2).all(|w| w[0].0 < w[1].0 || (w[0].0 == w[1].0 && w[0].1 < w[1].1));
+
+        for dedup in [false, true] {
+            let mut points = points.clone();
+            if dedup {
+                points.append(ndarray::Axis(0), points.clone().view()).unwrap();
+            }
+            let data = crate::metricdata::CustomMetricData::new(&points, l2).unwrap();
+            let config = Config {
+                index_mode: IndexMode::Flat,
+                k: 13,
+                dedup,
+                ..Default::default()
+            };
+            let mut index: ClusteredIndex<_> = ClusteredIndex::new(config, data).unwrap();
+            index.build().unwrap();
+
+            let grouped = index.search_batch_grouped(&queries).unwrap();
+            for (query, grouped) in queries.rows().into_iter().zip(grouped) {
+                let results = index.search(&query.to_vec()).unwrap();
+                assert_eq!(results.len(), 13);
+                assert!(sorted(&results), "{:?}", results);
+                assert_eq!(grouped, results);
+            }
+        }
+    }
+
+    #[test]
+    fn test_tied_neighbors() {
+        // the cluster of point 0 around x = -3, the other around x = 2 is probed first from
+        // x = 0, both with points at distance 1 from the query
+        let mut points = vec![[-3.0f32, 0.0]];
+        points.extend([[-1.0, 0.0]; 10]);
+        points.extend([[1.0, 0.0]; 10]);
+        points.push([2.0, 0.0]);
+        let points = Array2::from_shape_vec((22, 2), points.concat()).unwrap();
+        let config = Config {
+            index_mode: IndexMode::Flat,
+            num_clusters_factor: 0.5,
+            k: 5,
+            ..Default::default()
+        };
+        let l2 = |a: &[f32], b: &[f32]| a.iter().zip(b).map(|(x, y)| (x - y) * (x - y)).sum::<f32>().sqrt();
+        let data = crate::metricdata::CustomMetricData::new(&points, l2).unwrap();
+        let mut index: ClusteredIndex<_> = ClusteredIndex::new(config, data).unwrap();
+        index.build().unwrap();
+        assert_eq!(index.clusters.len(), 2);
+
+        // the points tied at the k-th distance with the smallest ids are kept, whatever the
+        // order the clusters are probed in
+        let expected: Vec<(f32, usize)> = (1..=5).map(|p| (1.0, p)).collect();
+        assert_eq!(index.search(&[0.0, 0.0]).unwrap(), expected);
+        assert_eq!(index.search_batch_grouped(&arr2(&[[0.0f32, 0.0]])).unwrap()[0], expected);
+    }
+
+    #[test]
+    fn test_stats() {
+        let data = AngularData::new(unit_vectors(500, 8, 99));
+        let config = Config {
+            index_mode: IndexMode::Flat,
+            ..Default::default()
+        };
+        let mut index: ClusteredIndex<_> = ClusteredIndex::new(config, data).unwrap();
+        index.build().unwrap();
+
+        let stats = index.stats();
+        assert_eq!(stats.num_clusters, index.num_clusters());
+        assert_eq!(stats.num_points, 500);
+        assert_eq!(stats.brute_force_clusters, stats.num_clusters);
+        assert_eq!(stats.size_histogram.iter().map(|b| b.count).sum::<usize>(), stats.num_clusters);
+        assert!(stats.sizes.min <= stats.sizes.p50 && stats.sizes.p50 <= stats.sizes.max);
+        assert!(stats.radii.max > 0.0);
+
+        // from the serialized metadata alone
+        let dir = test_dir("stats");
+        index.serialize_binary(dir.to_str().unwrap()).unwrap();
+        let path = index.binary_file_path(dir.to_str().unwrap());
+        let (config, file_stats) = crate::stats_from_file(&path).unwrap();
+        assert_eq!(config.index_mode, IndexMode::Flat);
+        assert_eq!(file_stats.num_points, 500);
+        assert_eq!(file_stats.sizes, stats.sizes);
+        assert_eq!(file_stats.memory.assignments, stats.memory.assignments);
+        assert_eq!(file_stats.memory.dataset, 0);
+        std::fs::remove_dir_all(dir).unwrap();
+
+        assert!(crate::stats_from_file("missing.h5").is_err());
+    }
+
+    #[test]
+    fn test_memory_usage() {
+        let mut points = unit_vectors(500, 8, 100);
+        for i in 0..10 {
+            let row = points.row(i).to_owned();
+            points.row_mut(490 + i).assign(&row);
+        }
+        let config = Config {
+            index_mode: IndexMode::Flat,
+            ..Default::default()
+        };
+        let mut index: ClusteredIndex<_> = ClusteredIndex::new(config.clone(), AngularData::new(points.clone())).unwrap();
+        index.build().unwrap();
+
+        let memory = index.memory_usage();
+        let clusters = index.num_clusters();
+        assert_eq!(memory.dataset, 500 * (8 + 1) * 4);
+        assert_eq!(memory.cluster_indexes, 0);
+        assert_eq!(memory.assignments, 500 * 8);
+        assert_eq!(memory.centers, clusters * 8 + clusters * clusters * 4);
+        assert_eq!(memory.quantized, 0);
+        assert_eq!(memory.duplicates, 0);
+        assert_eq!(memory.total(), memory.dataset + memory.assignments + memory.centers);
+        assert_eq!(index.stats().memory, memory);
+
+        // every component is counted
+        let config = Config {
+            dedup: true,
+            scalar_quantization: true,
+            ..config
+        };
+        let mut index: ClusteredIndex<_> = ClusteredIndex::new(config, AngularData::new(points)).unwrap();
+        index.build().unwrap();
+        let memory = index.memory_usage();
+        assert_eq!(memory.assignments, 490 * 8);
+        assert!(memory.duplicates > 0);
+        assert_eq!(memory.quantized, index.stats().quantized_memory);
+        assert!(memory.quantized > 0);
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_track_rss() {
+        let data = AngularData::new(unit_vectors(1000, 8, 101));
+        let config = Config {
+            index_mode: IndexMode::Flat,
+            ..Default::default()
+        };
+        let mut index: ClusteredIndex<_> = ClusteredIndex::new(config.clone(), data.clone()).unwrap();
+        index.build().unwrap();
+        assert_eq!(index.build_report().unwrap().peak_rss, None);
+
+        let config = Config {
+            track_rss: true,
+            ..config
+        };
+        let mut index: ClusteredIndex<_> = ClusteredIndex::new(config, data).unwrap();
+        let reported = std::sync::Arc::new(std::sync::Mutex::new(None));
+        let sink = reported.clone();
+        index.on_build(move |metrics| *sink.lock().unwrap() = metrics.peak_rss);
+        index.build().unwrap();
+
+        let peak_rss = index.build_report().unwrap().peak_rss.unwrap();
+        assert!(peak_rss.start > 0 && peak_rss.clustering > 0 && peak_rss.indexing > 0);
+        assert_eq!(peak_rss.peak(), peak_rss.clustering.max(peak_rss.indexing));
+        assert_eq!(*reported.lock().unwrap(), Some(peak_rss));
+    }
+
+    #[test]
+    fn test_save_metrics_json() {
+        use crate::core::config::{MetricsGranularity, MetricsOutput};
+        use crate::utils::RecallInput;
+        use ndarray::Array2;
+        use std::time::Duration;
+
+        let dir = test_dir("metrics_json");
+        let path = dir.join("metrics.json");
+        let data = AngularData::new(unit_vectors(500, 8, 102));
+        let queries = unit_vectors(5, 8, 103);
+        let config = Config {
+            index_mode: IndexMode::Flat,
+            k: 3,
+            metrics_output: MetricsOutput::Json(path.to_str().unwrap().to_string()),
+            ..Default::default()
+        };
+
+        let mut index: ClusteredIndex<_> = ClusteredIndex::new(config, data).unwrap();
+        index.build().unwrap();
+        let run: Vec<Vec<usize>> = queries
+            .rows()
+            .into_iter()
+            .map(|q| index.search(q.as_slice().unwrap()).unwrap().iter().map(|&(_, p)| p).collect())
+            .collect();
+        let ground_truth = Array2::from_shape_fn((5, 3), |(i, j)| run[i][j]);
+
+        let mut save = |granularity| {
+            let recall = RecallInput::Ids {
+                ground_truth: &ground_truth,
+                run: &run,
+            };
+            index
+                .save_metrics(String::new(), granularity, Some(recall), &Duration::from_millis(5))
+                .unwrap();
+            let json: serde_json::Value =
+                serde_json::from_reader(std::fs::File::open(&path).unwrap()).unwrap();
+            json
+        };
+
+        let json = save(MetricsGranularity::Run);
+        assert_eq!(json["search"]["recall_mean"], 1.0);
+        assert_eq!(json["build"]["dataset_len"], 500);
+        assert!(json.get("queries").is_none());
+
+        let json = save(MetricsGranularity::Query);
+        assert_eq!(json["queries"].as_array().unwrap().len(), 5);
+        assert!(json["queries"][0].get("clusters").is_none());
+
+        let json = save(MetricsGranularity::Cluster);
+        assert!(!json["queries"][0]["clusters"].as_array().unwrap().is_empty());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_save_metrics_csv() {
+        use crate::core::config::{MetricsGranularity, MetricsOutput};
+        use crate::utils::RecallInput;
+        use ndarray::Array2;
+        use std::time::Duration;
+
+        let dir = test_dir("metrics_csv");
+        let data = AngularData::new(unit_vectors(500, 8, 104));
+        let queries = unit_vectors(5, 8, 105);
+        let config = Config {
+            index_mode: IndexMode::Flat,
+            k: 3,
+            metrics_output: MetricsOutput::Csv(dir.to_str().unwrap().to_string()),
+            ..Default::default()
+        };
+
+        let mut index: ClusteredIndex<_> = ClusteredIndex::new(config, data).unwrap();
+        index.build().unwrap();
+        let run: Vec<Vec<usize>> = queries
+            .rows()
+            .into_iter()
+            .map(|q| index.search(q.as_slice().unwrap()).unwrap().iter().map(|&(_, p)| p).collect())
+            .collect();
+        let ground_truth = Array2::zeros((5, 3));
+
+        for granularity in [MetricsGranularity::Query, MetricsGranularity::Cluster] {
+            let recall = RecallInput::Ids {
+                ground_truth: &ground_truth,
+                run: &run,
+            };
+            index
+                .save_metrics(String::new(), granularity, Some(recall), &Duration::from_millis(5))
+                .unwrap();
+        }
+
+        let rows = |table: &str| {
+            let mut reader = csv::Reader::from_path(dir.join(format!("{}.csv", table))).unwrap();
+            let header = reader.headers().unwrap().clone();
+            (header, reader.records().count())
+        };
+
+        // one header, then the rows of both saves
+        let (header, num_rows) = rows("search_metrics");
+        assert_eq!(&header[0], "run_id");
+        assert_eq!(&header[7], "search_time_ms");
+        assert_eq!(num_rows, 2);
+        assert_eq!(rows("build_metrics_cluster").1, 2 * index.num_clusters());
+        assert_eq!(rows("search_metrics_query").1, 10);
+        assert_eq!(rows("runs").1, 2);
+        // only the second save has cluster granularity
+        assert!(rows("search_metrics_cluster").1 >= 5);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_metrics_callbacks() {
+        use std::sync::{Arc, Mutex};
+
+        let data = AngularData::new(unit_vectors(500, 8, 106));
+        let queries = unit_vectors(4, 8, 107);
+        let config = Config {
+            index_mode: IndexMode::Flat,
+            ..Default::default()
+        };
+
+        let mut index: ClusteredIndex<_> = ClusteredIndex::new(config, data).unwrap();
+        let builds = Arc::new(Mutex::new(Vec::new()));
+        let queries_seen = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&builds);
+        index.on_build(move |m| sink.lock().unwrap().push(m.num_clusters));
+        let sink = Arc::clone(&queries_seen);
+        index.on_query(move |m| sink.lock().unwrap().push(m.distance_computations));
+
+        index.build().unwrap();
+        assert_eq!(*builds.lock().unwrap(), vec![index.num_clusters()]);
+
+        for query in queries.rows() {
+            index.search(query.as_slice().unwrap()).unwrap();
+        }
+        let seen = queries_seen.lock().unwrap().clone();
+        assert_eq!(seen.len(), 4);
+        // at least the distance to the closest center, far ones are skipped without computing it
+        assert!(seen.iter().all(|&d| d > 0));
+        // without a metrics output the queries are not kept
+        assert!(index.metrics.as_ref().unwrap().queries.is_empty());
+
+        index.clear_metrics_callbacks();
+        assert!(index.metrics.is_none());
+        index.search(queries.row(0).as_slice().unwrap()).unwrap();
+        assert_eq!(queries_seen.lock().unwrap().len(), 4);
+    }
+
+    #[test]
+    fn test_distance_breakdown() {
+        use std::sync::{Arc, Mutex};
+
+        let data = AngularData::new(unit_vectors(500, 8, 108));
+        let queries = unit_vectors(4, 8, 109);
+        let config = Config {
+            index_mode: IndexMode::Flat,
+            rerank_f64: true,
+            ..Default::default()
+        };
+
+        let mut index: ClusteredIndex<_> = ClusteredIndex::new(config, data).unwrap();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&seen);
+        index.on_query(move |m| sink.lock().unwrap().push(m.clone()));
+        index.build().unwrap();
+
+        for query in queries.rows() {
+            index.search(query.as_slice().unwrap()).unwrap();
+            let metrics = seen.lock().unwrap().pop().unwrap();
+            let breakdown = metrics.distance_breakdown;
+            assert_eq!(breakdown.total(), metrics.distance_computations);
+            assert_eq!(breakdown.total(), index.last_distance_computations());
+            assert_eq!(breakdown.lsh, 0);
+            assert!(breakdown.pruning > 0);
+
+            // every point of a scanned cluster is counted, not only the ones it returns
+            let sizes: Vec<usize> = metrics
+                .cluster_ids
+                .iter()
+                .map(|&id| index.clusters().find(|c| c.id == id).unwrap().members.len())
+                .collect();
+            assert_eq!(metrics.cluster_distance_computations, sizes);
+            // and the k results re-ranked in f64
+            assert_eq!(breakdown.rerank, sizes.iter().sum::<usize>() + 10);
+        }
+    }
+
+    #[cfg(feature = "rust-lsh")]
+    #[test]
+    fn test_delta_policy() {
+        use std::sync::{Arc, Mutex};
+
+        use crate::core::{DeltaPolicy, ProbeContext};
+        use crate::lsh::CrossPolytopeIndex;
+        use crate::metricdata::MetricData;
+
+        /// Keeps the configured delta, recording the clusters it is asked about
+        struct Recording(Arc<Mutex<Vec<ProbeContext>>>);
+        impl DeltaPolicy for Recording {
+            fn cluster_delta(&self, delta: f32, context: &ProbeContext) -> f32 {
+                self.0.lock().unwrap().push(*context);
+                delta
+            }
+        }
+
+        let data = AngularData::new(unit_vectors(3000, 16, 110));
+        let query = data.get_point(42).to_vec();
+        let config = Config {
+            num_tables: 8,
+            num_clusters_factor: 0.1,
+            k: 5,
+            ..Default::default()
+        };
+        let mut index: ClusteredIndex<_, CrossPolytopeIndex> = ClusteredIndex::new(config, data).unwrap();
+        index.build().unwrap();
+        let expected = index.search(&query).unwrap();
+
+        let contexts = Arc::new(Mutex::new(Vec::new()));
+        index.set_delta_policy(Recording(contexts.clone()));
+        assert_eq!(index.search(&query).unwrap(), expected);
+        {
+            let contexts = contexts.lock().unwrap();
+            // the nearest cluster is probed first, before any neighbor is known
+            assert!(!contexts.is_empty());
+            assert_eq!(contexts[0].kth_distance, None);
+            assert!(contexts.windows(2).all(|w| w[0].center_distance <= w[1].center_distance));
+            assert!(contexts.iter().all(|c| c.lower_bound <= c.center_distance));
+        }
+
+        index.clear_delta_policy();
+        contexts.lock().unwrap().clear();
+        index.search(&query).unwrap();
+        assert!(contexts.lock().unwrap().is_empty());
+    }
+
+    #[cfg(feature = "rust-lsh")]
+    #[test]
+    fn test_mmap_rust_lsh() {
+        use crate::lsh::CrossPolytopeIndex;
+
+        let data = AngularData::new(unit_vectors(3000, 16, 111));
+        let config = Config {
+            num_tables: 8,
+            num_clusters_factor: 0.1,
+            k: 5,
+            dataset_name: "test_mmap_rust_lsh".to_string(),
+            ..Default::default()
+        };
+        let mut index: ClusteredIndex<_, CrossPolytopeIndex> =
+            ClusteredIndex::new(config, data.clone()).unwrap();
+        index.build().unwrap();
+
+        let dir = test_dir("mmap_rust_lsh");
+        let directory = dir.to_str().unwrap();
+        index.serialize_binary(directory).unwrap();
+        let path = index.binary_file_path(directory);
+        let mut loaded: ClusteredIndex<_, CrossPolytopeIndex> = ClusteredIndex::new_from_mmap(data.clone(), &path).unwrap();
+        for i in [3, 500, 2999] {
+            let query = data.get_point(i).to_vec();
+            assert_eq!(loaded.search(&query).unwrap(), index.search(&query).unwrap());
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(feature = "rust-lsh")]
+    #[test]
+    fn test_rigorous_delta_recall() {
+        use crate::core::RigorousDelta;
+        use crate::lsh::CrossPolytopeIndex;
+        use crate::metricdata::MetricData;
+
+        let points = unit_vectors(3000, 16, 112);
+        let data = AngularData::new(points.clone());
+        let queries = unit_vectors(50, 16, 113);
+        let k = 10;
+        let ground_truth: Vec<Vec<usize>> = queries
+            .rows()
+            .into_iter()
+            .map(|query| {
+                let mut distances: Vec<(f32, usize)> = (0..data.num_points())
+                    .map(|i| (data.distance_point(i, query.as_slice().unwrap()), i))
+                    .collect();
+                distances.sort_by(|a, b| a.0.total_cmp(&b.0));
+                distances[..k].iter().map(|d| d.1).collect()
+            })
+            .collect();
+
+        let config = Config {
+            num_tables: 8,
+            num_clusters_factor: 0.1,
+            k,
+            delta: 0.5,
+            ..Default::default()
+        };
+        let mut index: ClusteredIndex<_, CrossPolytopeIndex> = ClusteredIndex::new(config, data).unwrap();
+        index.build().unwrap();
+        // mean recall, and fraction of the queries with all their neighbors found
+        let recall = |index: &mut ClusteredIndex<_, CrossPolytopeIndex>| {
+            let found: Vec<usize> = queries
+                .rows()
+                .into_iter()
+                .zip(&ground_truth)
+                .map(|(query, truth)| {
+                    let results = index.search(query.as_slice().unwrap()).unwrap();
+                    results.iter().filter(|(_, p)| truth.contains(p)).count()
+                })
+                .collect();
+            let complete = found.iter().filter(|&&f| f == k).count();
+            (
+                found.iter().sum::<usize>() as f32 / (k * found.len()) as f32,
+                complete as f32 / found.len() as f32,
+            )
+        };
+
+        let (per_cluster, _) = recall(&mut index);
+        index.set_delta_policy(RigorousDelta);
+        let (rigorous, complete) = recall(&mut index);
+        // all the neighbors are found for at least a fraction delta of the queries
+        assert!(complete >= 0.5);
+        assert!(rigorous >= per_cluster);
+    }
+
+    #[cfg(feature = "rust-lsh")]
+    #[test]
+    fn test_search_with_rust_backend() {
+        use crate::lsh::CrossPolytopeIndex;
+        use crate::metricdata::MetricData;
+        use crate::utils::datagen::unit_vectors;
+
+        let data = AngularData::new(unit_vectors(2000, 16, 114));
+        let query = data.get_point(123).to_vec();
+        let config = Config {
+            num_tables: 8,
+            num_clusters_factor: 0.1,
+            k: 5,
+            ..Default::default()
+        };
+
+        let mut index: ClusteredIndex<_, CrossPolytopeIndex> =
+            ClusteredIndex::new(config, data).unwrap();
+        index.build().unwrap();
+        let results = index.search(&query).unwrap();
+
+        assert_eq!(results.len(), 5);
+        assert_eq!(results[0].1, 123);
+    }
+
+    #[cfg(feature = "rust-lsh")]
+    #[test]
+    fn test_build_respects_memory_ceiling() {
+        use crate::core::Degradation;
+        use crate::lsh::CrossPolytopeIndex;
+
+        let data = AngularData::new(unit_vectors(2000, 16, 115));
+        let config = Config {
+            num_tables: 8,
+            num_clusters_factor: 0.1,
+            k: 5,
+            max_memory_bytes: Some(1),
+            ..Default::default()
+        };
+
+        let mut index: ClusteredIndex<_, CrossPolytopeIndex> =
+            ClusteredIndex::new(config, data).unwrap();
+        index.build().unwrap();
+
+        // nothing fits, every cluster is scanned exhaustively
+        assert!(index.clusters.iter().all(|c| c.brute_force));
+        let report = index.build_report().unwrap();
+        assert_eq!(report.estimated_memory, 0);
+        assert!(report.degradations.contains(&Degradation::ReducedTables { from: 8, to: 2 }));
+        assert_eq!(
+            report
+                .degradations
+                .iter()
+                .filter(|d| matches!(d, Degradation::BruteForce { .. }))
+                .count(),
+            index.clusters.len()
+        );
+    }
+
+    #[cfg(feature = "rust-lsh")]
+    #[test]
+    fn test_insert_keeps_memory_ceiling() {
+        use crate::lsh::CrossPolytopeIndex;
+
+        let points = unit_vectors(2000, 16, 116);
+        let config = Config {
+            num_tables: 8,
+            num_clusters_factor: 0.1,
+            k: 5,
+            max_memory_bytes: Some(1),
+            ..Default::default()
+        };
+
+        let mut index: ClusteredIndex<_, CrossPolytopeIndex> =
+            ClusteredIndex::new(config, AngularData::new(points.clone())).unwrap();
+        index.build().unwrap();
+        let degraded = index.clusters.iter().filter(|c| c.over_memory_ceiling).count();
+        assert!(degraded > 0);
+
+        // copies of the points land in the clusters of their originals, which stay scanned
+        let ids = index.insert_batch(&points.slice(ndarray::s![..500, ..])).unwrap();
+        assert_eq!(ids, (2000..2500).collect::<Vec<_>>());
+        assert_eq!(index.clusters.iter().filter(|c| c.over_memory_ceiling).count(), degraded);
+        for (cluster, puffinn_index) in index.clusters.iter().zip(&index.puffinn_indices) {
+            if cluster.over_memory_ceiling {
+                assert!(cluster.brute_force);
+                assert!(puffinn_index.is_none());
+                assert_eq!(cluster.memory_used, 0);
+            }
+        }
+
+        let results = index.search(points.row(0).as_slice().unwrap()).unwrap();
+        let ids: Vec<usize> = results.iter().map(|&(_, id)| id).collect();
+        assert!(ids.contains(&0) && ids.contains(&2000));
     }
 }
@@ -0,0 +1,207 @@
+//! Byte ranges of a memory mapped index file, kept alive by the cluster indices and the
+//! cluster members reading them.
+
+use std::fmt;
+use std::ops::{Deref, DerefMut, Range};
+use std::sync::Arc;
+
+use memmap2::Mmap;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// Part of a read-only memory mapped file, cloned without copying the bytes.
+///
+/// The mapping is unmapped once the last range of it is dropped. While it lives, the pages of
+/// the file are shared with every other process mapping it, so a backend restored with
+/// [`ClusterBackend::from_mapped`](crate::core::ClusterBackend::from_mapped) that keeps its
+/// range reads its arrays from a single physical copy of the file.
+#[derive(Clone)]
+pub struct MappedBytes {
+    map: Arc<Mmap>,
+    range: Range<usize>,
+}
+
+impl MappedBytes {
+    /// The whole mapping
+    pub(crate) fn new(map: Mmap) -> Self {
+        let range = 0..map.len();
+        Self {
+            map: Arc::new(map),
+            range,
+        }
+    }
+
+    /// The bytes of `range`, relative to the start of these bytes.
+    ///
+    /// # Panics
+    /// If `range` is out of bounds
+    pub fn slice(&self, range: Range<usize>) -> Self {
+        assert!(
+            range.start <= range.end && range.end <= self.range.len(),
+            "range {:?} out of {} mapped bytes",
+            range,
+            self.range.len()
+        );
+        Self {
+            map: Arc::clone(&self.map),
+            range: self.range.start + range.start..self.range.start + range.end,
+        }
+    }
+
+    /// The bytes of the subslice `bytes` of these bytes, e.g. found by parsing them.
+    ///
+    /// # Panics
+    /// If `bytes` is not part of these bytes
+    pub(crate) fn subslice(&self, bytes: &[u8]) -> Self {
+        let start = (bytes.as_ptr() as usize)
+            .checked_sub(self.as_ptr() as usize)
+            .expect("the bytes are part of the mapping");
+        self.slice(start..start + bytes.len())
+    }
+}
+
+impl Deref for MappedBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.map[self.range.clone()]
+    }
+}
+
+impl fmt::Debug for MappedBytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MappedBytes").field("range", &self.range).finish()
+    }
+}
+
+/// Dataset ids of the points of a cluster, built in memory or read in place from the member
+/// section of a memory mapped index file, see [`crate::core::binary`].
+///
+/// Mapped ids are little-endian `u64`s, read as `usize` on 64-bit little-endian targets. They
+/// are copied into memory of the process the first time the cluster changes, e.g. when points
+/// are inserted into it.
+#[derive(Clone)]
+pub(crate) enum Members {
+    Owned(Vec<usize>),
+    /// Bytes aligned for `usize`, a whole number of ids, on a 64-bit little-endian target
+    Mapped(MappedBytes),
+}
+
+impl Members {
+    /// Copies the little-endian `u64` ids of `bytes`
+    pub(crate) fn decode(bytes: &[u8]) -> Result<Self, String> {
+        if !bytes.len().is_multiple_of(8) {
+            return Err(format!("{} bytes of members are not a whole number of ids", bytes.len()));
+        }
+        bytes
+            .chunks_exact(8)
+            .map(|id| usize::try_from(u64::from_le_bytes(id.try_into().expect("8 bytes"))).map_err(|e| e.to_string()))
+            .collect::<Result<Vec<_>, _>>()
+            .map(Members::Owned)
+    }
+
+    /// Reads the ids of `bytes` in place if they are aligned and the target is 64-bit
+    /// little-endian, otherwise copies them
+    pub(crate) fn mapped(bytes: MappedBytes) -> Result<Self, String> {
+        let aligned = (bytes.as_ptr() as usize).is_multiple_of(std::mem::align_of::<usize>());
+        if cfg!(all(target_endian = "little", target_pointer_width = "64"))
+            && aligned
+            && bytes.len().is_multiple_of(8)
+        {
+            Ok(Members::Mapped(bytes))
+        } else {
+            Self::decode(&bytes)
+        }
+    }
+
+    /// The ids as a vector owned by the process, copying them out of the mapping if needed
+    pub(crate) fn to_mut(&mut self) -> &mut Vec<usize> {
+        if let Members::Mapped(bytes) = self {
+            *self = Members::Owned(Self::as_ids(bytes).to_vec());
+        }
+        match self {
+            Members::Owned(ids) => ids,
+            Members::Mapped(_) => unreachable!("copied above"),
+        }
+    }
+
+    /// Whether the ids are read in place from a mapped file
+    #[cfg(test)]
+    pub(crate) fn is_mapped(&self) -> bool {
+        matches!(self, Members::Mapped(_))
+    }
+
+    fn as_ids(bytes: &MappedBytes) -> &[usize] {
+        // SAFETY: `Members::mapped` checked that the bytes are aligned for `usize` and hold a
+        // whole number of 8-byte ids, in the byte order of the target. Any bits are a `usize`,
+        // and the mapping is read-only and kept alive by `bytes`.
+        unsafe { std::slice::from_raw_parts(bytes.as_ptr().cast::<usize>(), bytes.len() / 8) }
+    }
+}
+
+impl Default for Members {
+    fn default() -> Self {
+        Members::Owned(Vec::new())
+    }
+}
+
+impl From<Vec<usize>> for Members {
+    fn from(ids: Vec<usize>) -> Self {
+        Members::Owned(ids)
+    }
+}
+
+impl FromIterator<usize> for Members {
+    fn from_iter<I: IntoIterator<Item = usize>>(ids: I) -> Self {
+        Members::Owned(ids.into_iter().collect())
+    }
+}
+
+impl Deref for Members {
+    type Target = [usize];
+
+    fn deref(&self) -> &[usize] {
+        match self {
+            Members::Owned(ids) => ids,
+            Members::Mapped(bytes) => Self::as_ids(bytes),
+        }
+    }
+}
+
+impl DerefMut for Members {
+    fn deref_mut(&mut self) -> &mut [usize] {
+        self.to_mut()
+    }
+}
+
+impl<'a> IntoIterator for &'a Members {
+    type Item = &'a usize;
+    type IntoIter = std::slice::Iter<'a, usize>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl PartialEq for Members {
+    fn eq(&self, other: &Self) -> bool {
+        **self == **other
+    }
+}
+
+impl fmt::Debug for Members {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+impl Serialize for Members {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        (**self).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Members {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Vec::deserialize(deserializer).map(Members::Owned)
+    }
+}
@@ -0,0 +1,238 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use super::index::ClusterCenter;
+use super::rss::PeakRss;
+
+/// Bytes of the sketches PUFFINN keeps for every point to filter candidates
+const SKETCH_BYTES_PER_POINT: usize = 256;
+
+/// Bytes of a table entry, a 32-bit hash and a 32-bit point index
+const TABLE_ENTRY_BYTES: usize = 8;
+
+/// Estimates the memory, in bytes, of an index with `num_tables` tables over `num_points` points.
+///
+/// Every point costs its own copy of the vector, its sketches and one entry per table. The
+/// estimate is an upper bound for the PUFFINN indices, which store angular vectors in 16 bits.
+pub(crate) fn estimate_index_memory(num_points: usize, dimensions: usize, num_tables: usize) -> usize {
+    num_points
+        * (dimensions * std::mem::size_of::<f32>()
+            + SKETCH_BYTES_PER_POINT
+            + num_tables * TABLE_ENTRY_BYTES)
+}
+
+/// Change made by the build to keep the cluster indices under [`Config::max_memory_bytes`](crate::core::Config).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Degradation {
+    /// Every cluster index was built with `to` tables instead of the configured `from`
+    ReducedTables { from: usize, to: usize },
+
+    /// The cluster is scanned exhaustively instead of getting an index
+    BruteForce { cluster: usize, num_points: usize },
+}
+
+/// Memory outcome of the last build of an index.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BuildReport {
+    /// Ceiling the build had to respect, if any
+    pub max_memory_bytes: Option<usize>,
+
+    /// Estimated memory of the cluster indices, after the degradations
+    pub estimated_memory: usize,
+
+    /// Memory of the cluster indices as reported by the backend
+    pub memory_used: usize,
+
+    /// Degradations applied to fit under the ceiling, empty if none was needed
+    pub degradations: Vec<Degradation>,
+
+    /// Resident set size of the process during the build, if [`Config::track_rss`](crate::core::Config) is set
+    #[serde(default)]
+    pub peak_rss: Option<PeakRss>,
+}
+
+/// Memory held by an index, in bytes, by component, see
+/// [`ClusteredIndex::memory_usage`](crate::core::ClusteredIndex::memory_usage).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MemoryUsage {
+    /// Points of the dataset held in memory, see [`MetricData::memory_used`](crate::metricdata::MetricData::memory_used)
+    pub dataset: usize,
+
+    /// Indices of the clusters as reported by the backend
+    pub cluster_indexes: usize,
+
+    /// Ids of the points of every cluster
+    pub assignments: usize,
+
+    /// Distances between the centers of the clusters, and the centers of the coarse cells
+    pub centers: usize,
+
+    /// Quantized copies of the dataset, see [`Config::scalar_quantization`](crate::core::Config)
+    /// and [`Config::pq`](crate::core::Config)
+    pub quantized: usize,
+
+    /// Identical points collapsed by [`Config::dedup`](crate::core::Config)
+    pub duplicates: usize,
+}
+
+impl MemoryUsage {
+    /// Memory of the indices and of the assignments of `clusters`, the other components are zero
+    pub(crate) fn from_clusters(clusters: &[ClusterCenter]) -> Self {
+        Self {
+            cluster_indexes: clusters.iter().map(|c| c.memory_used).sum(),
+            assignments: clusters.iter().map(|c| c.assignment.len()).sum::<usize>() * std::mem::size_of::<usize>(),
+            ..Self::default()
+        }
+    }
+
+    /// Bytes of all the components
+    pub fn total(&self) -> usize {
+        self.dataset + self.cluster_indexes + self.assignments + self.centers + self.quantized + self.duplicates
+    }
+}
+
+/// Predicted cost of building the index of a dataset, see [`estimate_build()`](crate::estimate_build).
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BuildEstimate {
+    /// Points clustered to predict the clusters of the dataset
+    pub sample_points: usize,
+
+    /// Points of the pilot cluster whose index was built to measure the costs per point,
+    /// 0 if no cluster would get an index
+    pub pilot_points: usize,
+
+    /// Memory of the pilot index per point, in bytes
+    pub memory_per_point: f64,
+
+    /// Build time of the pilot index per point
+    pub time_per_point: Duration,
+
+    /// Predicted time of clustering the whole dataset
+    pub clustering_time: Duration,
+
+    /// Predicted clusters, largest first
+    pub clusters: Vec<ClusterEstimate>,
+
+    /// Predicted memory of the cluster indices, in bytes
+    pub memory_bytes: usize,
+
+    /// Predicted time of the whole build, clustering included
+    pub build_time: Duration,
+}
+
+/// Predicted size and cost of a cluster, see [`BuildEstimate`]
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ClusterEstimate {
+    pub num_points: usize,
+
+    /// Whether the cluster would be scanned exhaustively, costing nothing to build
+    pub brute_force: bool,
+
+    pub memory_bytes: usize,
+
+    pub build_time: Duration,
+}
+
+/// Outcome of [`fit_memory`]
+#[derive(Debug, PartialEq)]
+pub(crate) struct MemoryFit {
+    /// Tables to build for every indexed cluster
+    pub(crate) num_tables: usize,
+
+    /// Positions (in the input slice) of the clusters that must be scanned exhaustively
+    pub(crate) brute_force: Vec<usize>,
+
+    pub(crate) estimated_memory: usize,
+}
+
+/// Chooses how to build the indices of clusters with `sizes` points within `max_memory` bytes.
+///
+/// The number of tables is lowered first, as PUFFINN keeps its recall guarantee with fewer
+/// tables at the cost of probing more candidates, but never below a quarter of `num_tables`.
+/// If that is not enough, the largest clusters are left without an index until the rest fits.
+pub(crate) fn fit_memory(
+    sizes: &[usize],
+    dimensions: usize,
+    num_tables: usize,
+    max_memory: usize,
+) -> MemoryFit {
+    let total = |tables: usize| -> usize {
+        sizes
+            .iter()
+            .map(|&n| estimate_index_memory(n, dimensions, tables))
+            .sum()
+    };
+
+    // the estimate is linear in the number of tables: total(L) = fixed + L * per_table
+    let fixed = total(0);
+    let per_table = total(1) - fixed;
+    let min_tables = (num_tables / 4).max(1);
+
+    let num_tables = if fixed + num_tables * per_table <= max_memory {
+        num_tables
+    } else if per_table > 0 && fixed + min_tables * per_table <= max_memory {
+        (max_memory - fixed) / per_table
+    } else {
+        min_tables
+    };
+
+    let mut estimated_memory = total(num_tables);
+    let mut brute_force = Vec::new();
+    if estimated_memory > max_memory {
+        let mut by_size: Vec<usize> = (0..sizes.len()).collect();
+        by_size.sort_by(|&a, &b| sizes[b].cmp(&sizes[a]));
+
+        for position in by_size {
+            if estimated_memory <= max_memory {
+                break;
+            }
+            estimated_memory -= estimate_index_memory(sizes[position], dimensions, num_tables);
+            brute_force.push(position);
+        }
+    }
+
+    MemoryFit {
+        num_tables,
+        brute_force,
+        estimated_memory,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{estimate_index_memory, fit_memory};
+
+    #[test]
+    fn test_fit_memory() {
+        let sizes = [1000, 400, 200];
+        let full = sizes
+            .iter()
+            .map(|&n| estimate_index_memory(n, 16, 40))
+            .sum::<usize>();
+
+        // enough memory, nothing changes
+        let fit = fit_memory(&sizes, 16, 40, full);
+        assert_eq!(fit.num_tables, 40);
+        assert!(fit.brute_force.is_empty());
+        assert_eq!(fit.estimated_memory, full);
+
+        // fewer tables are enough
+        let fit = fit_memory(&sizes, 16, 40, full - 1);
+        assert_eq!(fit.num_tables, 39);
+        assert!(fit.brute_force.is_empty());
+        assert!(fit.estimated_memory < full);
+
+        // at the minimum number of tables the largest cluster is dropped
+        let limit = estimate_index_memory(600, 16, 10);
+        let fit = fit_memory(&sizes, 16, 40, limit);
+        assert_eq!(fit.num_tables, 10);
+        assert_eq!(fit.brute_force, vec![0]);
+        assert_eq!(fit.estimated_memory, limit);
+
+        // no memory at all, every cluster is scanned
+        let fit = fit_memory(&sizes, 16, 40, 0);
+        assert_eq!(fit.brute_force.len(), 3);
+        assert_eq!(fit.estimated_memory, 0);
+    }
+}
@@ -0,0 +1,75 @@
+use serde::Serialize;
+
+/// Why a true neighbor is missing from the results of a query, see
+/// [`diagnose_misses()`](crate::diagnose_misses).
+///
+/// [`Pruned`](Self::Pruned) and [`Unvisited`](Self::Unvisited) are losses of the clustering,
+/// [`NotReturned`](Self::NotReturned) of the index of the cluster.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum MissReason {
+    /// Its cluster was not probed, its center being farther from the query than the k-th
+    /// neighbor found by more than the pruning radius of the cluster. The bound holds for
+    /// distances satisfying the triangle inequality with the full radius, not for the
+    /// angular distance or a smaller [`Config::pruning_radius`](crate::core::Config::pruning_radius)
+    Pruned,
+
+    /// Its cluster could hold a closer point than the k-th neighbor found but was not probed:
+    /// the search stopped at an earlier cluster, or after [`Config::max_clusters_probed`](crate::core::Config::max_clusters_probed)
+    Unvisited,
+
+    /// Its cluster was probed, but the neighbor was not among the candidates of its PUFFINN
+    /// index, or was pushed out of the top-k
+    NotReturned,
+}
+
+/// A true neighbor missing from the results of a query
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Miss {
+    /// Dataset id of the neighbor
+    pub point: usize,
+
+    /// Index of the cluster of the neighbor
+    pub cluster: usize,
+
+    pub reason: MissReason,
+}
+
+/// Number of missed true neighbors by [`MissReason`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct MissCounts {
+    pub pruned: usize,
+    pub unvisited: usize,
+    pub not_returned: usize,
+}
+
+impl MissCounts {
+    fn add(&mut self, reason: MissReason) {
+        match reason {
+            MissReason::Pruned => self.pruned += 1,
+            MissReason::Unvisited => self.unvisited += 1,
+            MissReason::NotReturned => self.not_returned += 1,
+        }
+    }
+
+    /// Counts of all the queries of `misses`
+    pub fn total<'a>(misses: impl IntoIterator<Item = &'a QueryMisses>) -> Self {
+        let mut counts = Self::default();
+        for miss in misses.into_iter().flat_map(|query| &query.misses) {
+            counts.add(miss.reason);
+        }
+        counts
+    }
+}
+
+/// True neighbors missing from the results of a query, in ground truth order
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct QueryMisses {
+    pub misses: Vec<Miss>,
+}
+
+impl QueryMisses {
+    /// Number of missed neighbors by reason
+    pub fn counts(&self) -> MissCounts {
+        MissCounts::total([self])
+    }
+}
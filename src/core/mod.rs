@@ -1,8 +1,48 @@
+pub(crate) mod backend;
+pub(crate) mod binary;
+pub(crate) mod cache;
+pub(crate) mod checkpoint;
 pub(crate) mod config;
+pub(crate) mod dedup;
+pub(crate) mod delta;
+pub(crate) mod diversify;
 pub(crate) mod index;
 pub(crate) mod errors;
 pub(crate) mod gmm;
-mod heap;
+pub(crate) mod graph;
+pub(crate) mod handle;
+pub(crate) mod mapped;
+pub(crate) mod memory;
+pub(crate) mod misses;
+pub(crate) mod params;
+pub(crate) mod partition;
+pub(crate) mod plan;
+pub(crate) mod pool;
+pub(crate) mod pq;
+pub(crate) mod probe;
+pub(crate) mod progress;
+pub(crate) mod quantize;
+pub(crate) mod rerank;
+pub(crate) mod rss;
+pub(crate) mod stats;
+pub(crate) mod stream;
+pub(crate) mod wal;
 
-pub use config::{Config, MetricsOutput, MetricsGranularity};
-pub use errors::{Result, ClusteredIndexError};
\ No newline at end of file
+pub use backend::ClusterBackend;
+pub use delta::{AdaptiveDelta, DeltaPolicy, ProbeContext, RigorousDelta};
+pub use config::{CenterSelection, ClusterCount, Config, IndexMode, MetricsOutput, MetricsGranularity, MmrParams, PqParams, PruningRadius, TinyClusters};
+pub use errors::{Result, ClusteredIndexError};
+pub use graph::KnnGraph;
+pub use handle::{IndexHandle, IndexReader, IndexWriter};
+pub use index::{Cluster, ClusteredIndex, NO_NEIGHBOR};
+pub use mapped::MappedBytes;
+pub use memory::{BuildEstimate, BuildReport, ClusterEstimate, Degradation, MemoryUsage};
+pub use misses::{Miss, MissCounts, MissReason, QueryMisses};
+pub use params::{Aggregation, SearchParams};
+pub use partition::{Partition, PartitionArtifact, RepartitionPolicy};
+pub use plan::{NearestCluster, PlanStep, QueryHardness, SearchPlan};
+pub use pool::{BlockingPool, Pending};
+pub use progress::{BuildObserver, BuildPhase, BuildProgress, CancellationToken};
+pub use rerank::Reranker;
+pub use rss::PeakRss;
+pub use stats::{Distribution, HistogramBin, IndexStats};
\ No newline at end of file
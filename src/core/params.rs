@@ -0,0 +1,73 @@
+/// Per-query options of [`search_with()`](crate::search_with), on top of the [`Config`](crate::core::Config)
+/// of the index.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SearchParams<'a> {
+    /// Points never returned, in any order. They are skipped when the candidates of a cluster
+    /// are mapped to the dataset, so the query still gets k neighbors when there are enough
+    /// others, for example when querying with a point of the dataset or one of its known duplicates
+    pub exclude: &'a [usize],
+
+    /// Number of neighbors returned, instead of [`Config::k`](crate::core::Config::k), so that
+    /// queries on a shared index can ask for different k without reconfiguring it
+    pub k: Option<usize>,
+
+    /// Neighbors farther than this are not returned, even if there are fewer than k. The
+    /// search also skips the clusters whose points are all farther
+    pub max_distance: Option<f32>,
+}
+
+impl<'a> SearchParams<'a> {
+    /// Parameters leaving out the point `id`, to search the neighbors of a point of the
+    /// dataset without getting the point itself
+    pub fn exclude_self_by_id(id: &'a usize) -> Self {
+        Self {
+            exclude: std::slice::from_ref(id),
+            ..Default::default()
+        }
+    }
+}
+
+/// How [`search_multi()`](crate::search_multi) combines the distances from a set of query vectors to a point.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Aggregation {
+    /// Distance to the closest query vector, a point is near the set if it is near any of them
+    #[default]
+    Min,
+
+    /// Mean of the distances to the query vectors
+    Mean,
+}
+
+impl Aggregation {
+    /// Combines the distances from every query vector to the same point
+    pub(crate) fn combine<D: Into<f64>>(self, distances: impl IntoIterator<Item = D>) -> f64 {
+        let mut count = 0;
+        let mut combined = match self {
+            Aggregation::Min => f64::INFINITY,
+            Aggregation::Mean => 0.0,
+        };
+        for distance in distances {
+            let distance = distance.into();
+            count += 1;
+            combined = match self {
+                Aggregation::Min => combined.min(distance),
+                Aggregation::Mean => combined + distance,
+            };
+        }
+        match self {
+            Aggregation::Mean if count > 0 => combined / count as f64,
+            _ => combined,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Aggregation;
+
+    #[test]
+    fn test_aggregation() {
+        assert_eq!(Aggregation::Min.combine([0.5f32, 0.25, 1.0]), 0.25);
+        assert_eq!(Aggregation::Mean.combine([0.5f32, 0.25, 0.75]), 0.5);
+    }
+}
@@ -0,0 +1,191 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+
+use serde::{Deserialize, Serialize};
+
+use crate::core::{ClusteredIndexError, Config, Result};
+use crate::metricdata::MetricData;
+
+use super::gmm::min_max_medoid;
+
+/// Assignment of every point of a dataset to a cluster.
+///
+/// Exported from a built index with [`ClusteredIndex::export_partition`](crate::core::ClusteredIndex::export_partition),
+/// or made from the labels of an external clustering, and used to build an index with
+/// [`crate::build_with_partition`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Partition {
+    /// Cluster of every point of the dataset, clusters are numbered from 0 and none is empty
+    pub assignments: Vec<usize>,
+
+    /// Center of every cluster, one of its members. `None` to use the member minimizing the
+    /// radius, see [`CenterSelection::Medoid`](crate::core::CenterSelection::Medoid)
+    pub centers: Option<Vec<usize>>,
+}
+
+impl Partition {
+    /// Partition with the labels of an external clustering, the centers are chosen at build time.
+    pub fn from_assignments(assignments: Vec<usize>) -> Self {
+        Self {
+            assignments,
+            centers: None,
+        }
+    }
+
+    pub fn num_clusters(&self) -> usize {
+        match &self.centers {
+            Some(centers) => centers.len(),
+            None => self.assignments.iter().max().map_or(0, |&max| max + 1),
+        }
+    }
+
+    /// Checks the partition against `data` and returns the center, radius and members of every cluster.
+    pub(crate) fn clusters<D: MetricData>(&self, data: &D) -> Result<Vec<(usize, f32, Vec<usize>)>> {
+        if self.assignments.len() != data.num_points() {
+            return Err(ClusteredIndexError::DataError(format!(
+                "the partition assigns {} points, the dataset has {}",
+                self.assignments.len(),
+                data.num_points()
+            )));
+        }
+
+        let num_clusters = self.num_clusters();
+        let mut members = vec![Vec::new(); num_clusters];
+        for (point, &cluster) in self.assignments.iter().enumerate() {
+            if cluster >= num_clusters {
+                return Err(ClusteredIndexError::DataError(format!(
+                    "point {} is assigned to cluster {}, the partition has {} centers",
+                    point, cluster, num_clusters
+                )));
+            }
+            members[cluster].push(point);
+        }
+        if let Some(empty) = members.iter().position(|m| m.is_empty()) {
+            return Err(ClusteredIndexError::DataError(format!(
+                "cluster {} has no points",
+                empty
+            )));
+        }
+
+        members
+            .into_iter()
+            .enumerate()
+            .map(|(cluster, members)| {
+                let (center, radius) = match &self.centers {
+                    Some(centers) => {
+                        let center = centers[cluster];
+                        if self.assignments.get(center) != Some(&cluster) {
+                            return Err(ClusteredIndexError::DataError(format!(
+                                "center {} of cluster {} is not one of its members",
+                                center, cluster
+                            )));
+                        }
+                        let radius = members
+                            .iter()
+                            .map(|&p| data.distance(center, p))
+                            .fold(0.0f32, f32::max);
+                        (center, radius)
+                    }
+                    None => min_max_medoid(data, &members, members[0]),
+                };
+                Ok((center, radius, members))
+            })
+            .collect()
+    }
+}
+
+/// A built index without its LSH tables: its configuration and its clusters.
+///
+/// Written with [`crate::serialize_partition`], it is a small fraction of the size of a
+/// serialized index, and [`crate::rebuild_from_partition`] creates the PUFFINN indices again
+/// from it, without clustering the dataset. Moving it between machines and rebuilding is
+/// usually faster than moving the LSH tables.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PartitionArtifact {
+    pub config: Config,
+
+    /// Clusters of the index, with their centers
+    pub partition: Partition,
+
+    /// Radius of every cluster, checked against the dataset the index is rebuilt on
+    pub radii: Vec<f32>,
+
+    /// Clusters pooling the outliers, see [`Config::outlier_quantile`]
+    #[serde(default)]
+    pub outliers: Vec<usize>,
+}
+
+impl PartitionArtifact {
+    /// Writes the artifact to `path` as JSON.
+    ///
+    /// # Errors
+    /// Returns `ClusteredIndexError::SerializeError` if the file can't be written
+    pub fn save(&self, path: &str) -> Result<()> {
+        let to_err = |e: String| ClusteredIndexError::SerializeError(format!("{}: {}", path, e));
+        let file = File::create(path).map_err(|e| to_err(e.to_string()))?;
+        serde_json::to_writer(BufWriter::new(file), self).map_err(|e| to_err(e.to_string()))
+    }
+
+    /// Reads an artifact written by [`save`](Self::save).
+    ///
+    /// # Errors
+    /// Returns `ClusteredIndexError::SerializeError` if the file can't be read or isn't an artifact
+    pub fn load(path: &str) -> Result<Self> {
+        let to_err = |e: String| ClusteredIndexError::SerializeError(format!("{}: {}", path, e));
+        let file = File::open(path).map_err(|e| to_err(e.to_string()))?;
+        serde_json::from_reader(BufReader::new(file)).map_err(|e| to_err(e.to_string()))
+    }
+}
+
+/// How [`crate::repartition`] clusters the points of a built index again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RepartitionPolicy {
+    /// Clusters the whole dataset again as a build would, with the number of clusters of the
+    /// current dataset size. Clusters whose members didn't change keep their index
+    Full,
+
+    /// Splits every cluster with more than `max_points` points into `size / max_points`
+    /// (rounded up) clusters with greedy clustering, leaving the other clusters untouched.
+    /// Greedy clustering doesn't balance sizes, so a part can still exceed `max_points`
+    SplitOversized { max_points: usize },
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::arr2;
+
+    use super::Partition;
+    use crate::metricdata::EuclideanData;
+
+    #[test]
+    fn test_partition_clusters() {
+        let data = EuclideanData::new(arr2(&[[0.0], [1.0], [2.0], [10.0], [12.0]]));
+
+        let clusters = Partition::from_assignments(vec![0, 0, 0, 1, 1]).clusters(&data).unwrap();
+        assert_eq!(clusters.len(), 2);
+        assert_eq!(clusters[0], (1, 1.0, vec![0, 1, 2]));
+        assert_eq!(clusters[1].2, vec![3, 4]);
+
+        let with_centers = Partition {
+            assignments: vec![0, 0, 0, 1, 1],
+            centers: Some(vec![0, 4]),
+        };
+        let clusters = with_centers.clusters(&data).unwrap();
+        assert_eq!((clusters[0].0, clusters[0].1), (0, 2.0));
+        assert_eq!((clusters[1].0, clusters[1].1), (4, 2.0));
+
+        // wrong length, empty cluster, label without center, center outside its cluster
+        assert!(Partition::from_assignments(vec![0, 0]).clusters(&data).is_err());
+        assert!(Partition::from_assignments(vec![0, 0, 0, 2, 2]).clusters(&data).is_err());
+        let bad = Partition {
+            assignments: vec![0, 0, 0, 1, 1],
+            centers: Some(vec![0]),
+        };
+        assert!(bad.clusters(&data).is_err());
+        let bad = Partition {
+            assignments: vec![0, 0, 0, 1, 1],
+            centers: Some(vec![3, 4]),
+        };
+        assert!(bad.clusters(&data).is_err());
+    }
+}
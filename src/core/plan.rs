@@ -0,0 +1,95 @@
+use serde::Serialize;
+
+/// Probe plan of a query, computed without searching the clusters.
+///
+/// Steps are in the order the search would probe the clusters: the outlier pools first, which
+/// every query scans, then the other clusters from the closest center. The search skips a
+/// cluster whose lower bound exceeds the distance of its current k-th neighbor and goes on
+/// with the next one, since a farther cluster with a larger radius may still hold a neighbor.
+/// That distance is only known while searching; the plan estimates it with the distance of the
+/// k-th closest center. Centers are dataset points, so an exact search never has a k-th
+/// neighbor farther than that: up to the recall of the LSH indices, the clusters
+/// [`expected_pruned`](PlanStep::expected_pruned) are skipped, and the others may be skipped
+/// as well once the search found closer neighbors.
+#[derive(Debug, Clone, Serialize)]
+pub struct SearchPlan {
+    pub steps: Vec<PlanStep>,
+
+    /// Distance of the k-th closest center, `None` if there are fewer than k clusters. With
+    /// [`Config::mmr`](crate::core::Config::mmr), k is the number of candidates it selects from
+    pub estimated_kth_distance: Option<f32>,
+}
+
+/// A cluster in a [`SearchPlan`].
+#[derive(Debug, Clone, Serialize)]
+pub struct PlanStep {
+    /// Index of the cluster
+    pub cluster: usize,
+
+    /// Distance from the query to the center of the cluster
+    pub center_distance: f32,
+
+    /// Lower bound on the distance from the query to any point of the cluster, zero if the
+    /// query may lie inside it. Derived in the metric the search prunes with, see
+    /// [`MetricData::distance_to_metric`](crate::metricdata::MetricData::distance_to_metric)
+    pub lower_bound: f32,
+
+    pub num_points: usize,
+
+    /// Whether the cluster is scanned exhaustively instead of searched with its index
+    pub brute_force: bool,
+
+    /// Whether the cluster is an outlier pool, scanned by every query before the others
+    pub outlier: bool,
+
+    /// Whether the search is expected to skip the cluster: its lower bound, or the one of its
+    /// coarse cell with [`Config::coarse_clusters`](crate::core::Config::coarse_clusters),
+    /// exceeds the estimated k-th distance, or
+    /// [`Config::max_clusters_probed`](crate::core::Config::max_clusters_probed) clusters are
+    /// probed before it. Never set for an outlier pool
+    pub expected_pruned: bool,
+
+    /// Mean number of candidates added to the top-k when previous queries probed the cluster,
+    /// at most k for brute force clusters that were never probed, `None` if unknown
+    pub expected_candidates: Option<f32>,
+}
+
+/// A cluster close to a query, see [`crate::nearest_clusters`].
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct NearestCluster {
+    /// Index of the cluster
+    pub cluster: usize,
+
+    /// Dataset id of the center of the cluster
+    pub center: usize,
+
+    /// Distance from the query to the center
+    pub distance: f32,
+
+    pub radius: f32,
+}
+
+/// How hard a query is expected to be for the index, see [`crate::estimate_hardness`].
+///
+/// Easy queries lie deep inside a cluster, far from the others, so that the search stops
+/// after a few clusters. Hard ones lie between clusters, or outside all of them, and the
+/// search probes many clusters before its k-th distance rules the others out.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct QueryHardness {
+    /// Fraction of the dataset in the clusters that may hold a point closer than the nearest
+    /// center, i.e. whose lower bound is within the distance to that center, in `[0, 1]`.
+    /// Grows with the expected distance computations
+    pub score: f32,
+
+    /// Distance to the nearest center over the radius of its cluster, above 1 outside of it
+    pub relative_depth: f32,
+
+    /// Lower bound of the closest other cluster minus the distance to the nearest center,
+    /// negative when another cluster may hold points closer than that center, `None` with
+    /// a single cluster
+    pub margin: Option<f32>,
+
+    /// Number of clusters whose lower bound is within the distance to the nearest center,
+    /// including its own
+    pub clusters_in_range: usize,
+}
@@ -0,0 +1,130 @@
+//! Thread pool running the blocking operations of [`IndexHandle`](super::IndexHandle) off the
+//! threads of an async runtime.
+//!
+//! Every handle created with [`IndexHandle::new`](super::IndexHandle::new) starts a pool of
+//! [`Config::threads()`](super::Config::threads) threads, shared by its clones, and several
+//! indices can share a [`BlockingPool`] of the application with
+//! [`IndexHandle::with_pool`](super::IndexHandle::with_pool). The pool does not depend on a
+//! runtime: the [`Pending`] futures it returns are woken from the pool threads, so they can
+//! be awaited on tokio, async-std or a plain `block_on`. Its threads exit once every handle
+//! to the pool is dropped.
+
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Result of an operation and the waker of the task awaiting it
+struct Slot<R> {
+    result: Option<thread::Result<R>>,
+    waker: Option<Waker>,
+}
+
+/// Future of an operation running on the blocking pool.
+///
+/// Dropping it does not cancel the operation, which still runs to completion. A panic of the
+/// operation is resumed in the task awaiting it.
+pub struct Pending<R> {
+    slot: Arc<Mutex<Slot<R>>>,
+}
+
+impl<R> Future for Pending<R> {
+    type Output = R;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<R> {
+        let mut slot = self.slot.lock().unwrap_or_else(|e| e.into_inner());
+        match slot.result.take() {
+            Some(Ok(result)) => Poll::Ready(result),
+            Some(Err(panic)) => panic::resume_unwind(panic),
+            None => {
+                slot.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// Threads running the blocking operations of one or more [`IndexHandle`](super::IndexHandle)s,
+/// cloned freely, see the [module documentation](self).
+#[derive(Clone)]
+pub struct BlockingPool {
+    sender: Arc<Mutex<Sender<Job>>>,
+    threads: usize,
+    pending: Arc<AtomicUsize>, // operations queued or running
+}
+
+impl BlockingPool {
+    /// Starts a pool of `threads` threads, at least one.
+    ///
+    /// # Panics
+    /// If the operating system cannot start a thread
+    pub fn new(threads: usize) -> Self {
+        let threads = threads.max(1);
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for i in 0..threads {
+            let receiver = Arc::clone(&receiver);
+            thread::Builder::new()
+                .name(format!("clann-blocking-{i}"))
+                .spawn(move || loop {
+                    // the lock is released before running the job
+                    let job = receiver.lock().unwrap_or_else(|e| e.into_inner()).recv();
+                    match job {
+                        Ok(job) => job(),
+                        Err(_) => break,
+                    }
+                })
+                .expect("failed to start the blocking pool");
+        }
+
+        Self {
+            sender: Arc::new(Mutex::new(sender)),
+            threads,
+            pending: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Returns the number of threads of the pool.
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+
+    /// Returns the number of operations queued or running on the pool, including those whose
+    /// future was dropped, e.g. to shed load before the queue grows without bound.
+    pub fn pending(&self) -> usize {
+        self.pending.load(Ordering::Acquire)
+    }
+
+    /// Runs `f` on the pool, returning the future of its result
+    pub(crate) fn spawn<R, F>(&self, f: F) -> Pending<R>
+    where
+        F: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let slot = Arc::new(Mutex::new(Slot { result: None, waker: None }));
+        let job_slot = Arc::clone(&slot);
+        let pending = Arc::clone(&self.pending);
+        let job: Job = Box::new(move || {
+            let result = panic::catch_unwind(AssertUnwindSafe(f));
+            pending.fetch_sub(1, Ordering::AcqRel);
+            let waker = {
+                let mut slot = job_slot.lock().unwrap_or_else(|e| e.into_inner());
+                slot.result = Some(result);
+                slot.waker.take()
+            };
+            if let Some(waker) = waker {
+                waker.wake();
+            }
+        });
+        self.pending.fetch_add(1, Ordering::AcqRel);
+        // the workers never exit while the sender is alive, so sending cannot fail
+        let _ = self.sender.lock().unwrap_or_else(|e| e.into_inner()).send(job);
+        Pending { slot }
+    }
+}
@@ -0,0 +1,233 @@
+use rand::rngs::StdRng;
+use rand::seq::index::sample;
+use rand::SeedableRng;
+
+use crate::core::config::PqParams;
+use crate::metricdata::{simd, MetricData, Scalar};
+
+/// Seed of the sampling and of the k-means initialization, so that the codebooks are a function
+/// of the dataset and can be trained again when an index is loaded
+const SEED: u64 = 0x5051;
+
+/// Points sampled to train the codebooks
+const TRAIN_SAMPLE: usize = 16_384;
+
+/// Iterations of k-means in every subspace
+const ITERATIONS: usize = 10;
+
+/// Round-off allowed on the lower bounds
+const EPSILON: f32 = 1e-5;
+
+/// Product quantizer of the dataset, see [`Config::pq`](crate::core::Config).
+///
+/// The dimensions are split in `m` subspaces of `d / m` dimensions, each with a codebook of
+/// `2^nbits` centroids trained with k-means, and every point is stored as the centroid of each
+/// of its subvectors. The angular distance of a query to a point is approximated from a table
+/// of the dot products of the query with every centroid, computed once per query.
+///
+/// With the exact norm of the point, the approximation is off by at most the relative error
+/// `|x - x'| / |x|` of the reconstruction `x'` of the point, kept for every point. Subtracting
+/// it gives a lower bound of the distance, so pruning the candidates whose bound exceeds the
+/// current k-th distance never loses a neighbor.
+#[derive(Debug, Clone)]
+pub(crate) struct ProductQuantizer {
+    m: usize,
+    sub_dims: usize,
+    centroids_per_subspace: usize,
+    /// Centroid `c` of subspace `s` starts at `(s * centroids_per_subspace + c) * sub_dims`
+    centroids: Vec<f32>,
+    /// `m` codes per point
+    codes: Vec<u8>,
+    norms: Vec<f32>,
+    /// Relative reconstruction error of every point
+    errors: Vec<f32>,
+}
+
+impl ProductQuantizer {
+    /// Trains the codebooks on a sample of `data` and encodes all its points.
+    ///
+    /// # Errors
+    /// If the dimensions of `data` are not a multiple of `m`, or `nbits` is not in `1..=8`
+    pub(crate) fn train<T: MetricData>(data: &T, params: PqParams) -> Result<Self, String> {
+        let dims = data.dimensions();
+        if params.m == 0 || !dims.is_multiple_of(params.m) {
+            return Err(format!(
+                "the {} dimensions cannot be split in {} subspaces",
+                dims, params.m
+            ));
+        }
+        if !(1..=8).contains(&params.nbits) {
+            return Err(format!("codes of {} bits don't fit a byte", params.nbits));
+        }
+
+        let mut rng = StdRng::seed_from_u64(SEED);
+        let n = data.num_points();
+        let sample: Vec<Vec<f32>> = sample(&mut rng, n, n.min(TRAIN_SAMPLE))
+            .into_iter()
+            .map(|i| to_f32(&data.indexed_point(i)))
+            .collect();
+
+        let mut quantizer = Self {
+            m: params.m,
+            sub_dims: dims / params.m,
+            centroids_per_subspace: 1 << params.nbits,
+            centroids: Vec::new(),
+            codes: Vec::with_capacity(n * params.m),
+            norms: Vec::with_capacity(n),
+            errors: Vec::with_capacity(n),
+        };
+        for s in 0..params.m {
+            let range = s * quantizer.sub_dims..(s + 1) * quantizer.sub_dims;
+            let subvectors: Vec<&[f32]> = sample.iter().map(|p| &p[range.clone()]).collect();
+            let centroids = kmeans(&subvectors, quantizer.centroids_per_subspace, &mut rng);
+            quantizer.centroids.extend(centroids);
+        }
+
+        for i in 0..n {
+            quantizer.push(&data.indexed_point(i));
+        }
+        Ok(quantizer)
+    }
+
+    fn centroid(&self, s: usize, c: usize) -> &[f32] {
+        let start = (s * self.centroids_per_subspace + c) * self.sub_dims;
+        &self.centroids[start..start + self.sub_dims]
+    }
+
+    /// Encodes `point` as the next point.
+    pub(crate) fn push<D: Scalar>(&mut self, point: &[D]) {
+        let point = to_f32(point);
+        assert_eq!(point.len(), self.m * self.sub_dims);
+
+        let mut residual = 0.0;
+        for (s, subvector) in point.chunks_exact(self.sub_dims).enumerate() {
+            let (code, distance) = (0..self.centroids_per_subspace)
+                .map(|c| (c, simd::squared_l2(subvector, self.centroid(s, c))))
+                .min_by(|a, b| a.1.total_cmp(&b.1))
+                .expect("codebooks have at least one centroid");
+            self.codes.push(code as u8);
+            residual += distance;
+        }
+
+        let norm = simd::dot(&point, &point).sqrt();
+        self.norms.push(norm);
+        // zero vectors have no angular distance, they are never pruned
+        self.errors.push(if norm > 0.0 { residual.sqrt() / norm } else { f32::INFINITY });
+    }
+
+    /// Bytes of the codebooks and of the codes, norms and errors of the points
+    pub(crate) fn memory_used(&self) -> usize {
+        self.codes.len()
+            + (self.centroids.len() + self.norms.len() + self.errors.len()) * std::mem::size_of::<f32>()
+    }
+
+    /// Lower bounds of the distances from `query` to the points `ids`, written to `out`.
+    pub(crate) fn lower_bounds<D: Scalar>(&self, ids: &[usize], query: &[D], out: &mut [f32]) {
+        assert_eq!(ids.len(), out.len());
+        let query = to_f32(query);
+        assert_eq!(query.len(), self.m * self.sub_dims);
+
+        let table: Vec<f32> = query
+            .chunks_exact(self.sub_dims)
+            .enumerate()
+            .flat_map(|(s, subvector)| {
+                (0..self.centroids_per_subspace).map(move |c| simd::dot(subvector, self.centroid(s, c)))
+            })
+            .collect();
+        let norm_query = simd::dot(&query, &query).sqrt();
+
+        for (o, &i) in out.iter_mut().zip(ids) {
+            let codes = &self.codes[i * self.m..(i + 1) * self.m];
+            let dot: f32 = codes
+                .iter()
+                .enumerate()
+                .map(|(s, &c)| table[s * self.centroids_per_subspace + c as usize])
+                .sum();
+            let approximate = 1.0 - dot / (self.norms[i] * norm_query);
+            *o = approximate - self.errors[i] - EPSILON;
+        }
+    }
+}
+
+fn to_f32<D: Scalar>(point: &[D]) -> Vec<f32> {
+    point.iter().map(|&x| x.to_f32()).collect()
+}
+
+/// `k` centroids of `points` with Lloyd's algorithm, concatenated, initialized with distinct
+/// points when there are enough
+fn kmeans(points: &[&[f32]], k: usize, rng: &mut StdRng) -> Vec<f32> {
+    let dims = points[0].len();
+    let init: Vec<usize> = if points.len() >= k {
+        sample(rng, points.len(), k).into_vec()
+    } else {
+        (0..k).map(|c| c % points.len()).collect()
+    };
+    let mut centroids: Vec<f32> = init.iter().flat_map(|&i| points[i].iter().copied()).collect();
+
+    let mut assignment = vec![0; points.len()];
+    for _ in 0..ITERATIONS {
+        for (a, point) in assignment.iter_mut().zip(points) {
+            *a = centroids
+                .chunks_exact(dims)
+                .enumerate()
+                .map(|(c, centroid)| (c, simd::squared_l2(point, centroid)))
+                .min_by(|a, b| a.1.total_cmp(&b.1))
+                .map_or(0, |(c, _)| c);
+        }
+
+        let mut sums = vec![0.0f32; k * dims];
+        let mut counts = vec![0usize; k];
+        for (&a, point) in assignment.iter().zip(points) {
+            counts[a] += 1;
+            for (sum, x) in sums[a * dims..(a + 1) * dims].iter_mut().zip(point.iter()) {
+                *sum += x;
+            }
+        }
+        // empty clusters keep their centroid
+        for (c, &count) in counts.iter().enumerate().filter(|(_, &count)| count > 0) {
+            for (centroid, sum) in centroids[c * dims..(c + 1) * dims]
+                .iter_mut()
+                .zip(&sums[c * dims..(c + 1) * dims])
+            {
+                *centroid = sum / count as f32;
+            }
+        }
+    }
+    centroids
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ProductQuantizer;
+    use crate::core::config::PqParams;
+    use crate::metricdata::{AngularData, MetricData};
+    use crate::utils::generate_random_unit_vectors;
+
+    #[test]
+    fn test_lower_bounds() {
+        let data = AngularData::new(generate_random_unit_vectors(2000, 16));
+        let pq = ProductQuantizer::train(&data, PqParams { m: 4, nbits: 6 }).unwrap();
+        assert_eq!(pq.codes.len(), 2000 * 4);
+
+        let ids: Vec<usize> = (0..2000).collect();
+        let mut gap = 0.0;
+        for query in generate_random_unit_vectors(10, 16).rows() {
+            let query = query.as_slice().unwrap();
+            let mut bounds = vec![0.0; 2000];
+            pq.lower_bounds(&ids, query, &mut bounds);
+            for (i, &bound) in bounds.iter().enumerate() {
+                let exact = data.distance_point(i, query);
+                assert!(bound <= exact);
+                gap += exact - bound;
+            }
+        }
+        // tight enough to prune
+        assert!(gap / 20_000.0 < 0.5);
+
+        // the same dataset trains the same codebooks
+        let again = ProductQuantizer::train(&data, PqParams { m: 4, nbits: 6 }).unwrap();
+        assert_eq!(again.codes, pq.codes);
+
+        assert!(ProductQuantizer::train(&data, PqParams { m: 5, nbits: 6 }).is_err());
+    }
+}
@@ -0,0 +1,477 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+use ordered_float::OrderedFloat;
+
+use crate::metricdata::MetricData;
+
+use super::index::{ClusterCenter, Hierarchy};
+
+/// Most clusters whose center distances are kept, 64 MiB of distances
+const MAX_CLUSTERS: usize = 4096;
+
+/// Centers whose distance from the query raises the lower bounds of every cluster, the ones
+/// computed first. Updating the bounds costs a pass over the clusters, so the later distances
+/// are only used to order the probes
+const MAX_PIVOTS: usize = 16;
+
+/// Distances between the centers of the clusters, in the metric of
+/// [`MetricData::distance_to_metric`], computed when the clusters are built.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct CenterDistances {
+    centers: Vec<usize>, // dataset id of the center of every cluster, in cluster order
+    distances: Vec<f32>, // distance between the centers of clusters i and j at i * n + j
+}
+
+impl CenterDistances {
+    /// Distances between the centers of `clusters`, none if there are more than [`MAX_CLUSTERS`]
+    pub(crate) fn compute<T: MetricData>(data: &T, clusters: &[ClusterCenter]) -> Self {
+        let n = clusters.len();
+        if n > MAX_CLUSTERS {
+            return Self::default();
+        }
+
+        let centers: Vec<usize> = clusters.iter().map(|c| c.center_idx).collect();
+        let points: Vec<Vec<T::DataType>> = centers
+            .iter()
+            .map(|&c| data.get_point(c).into_owned())
+            .collect();
+        let mut distances = vec![0.0; n * n];
+        for i in 0..n {
+            for j in i + 1..n {
+                let distance = data.distance_to_metric(data.distance_point(centers[i], &points[j]));
+                distances[i * n + j] = distance;
+                distances[j * n + i] = distance;
+            }
+        }
+
+        Self { centers, distances }
+    }
+
+    /// Whether the distances are the ones of the centers of `clusters`, which change when the
+    /// index is rebuilt, merged or repartitioned
+    pub(crate) fn matches(&self, clusters: &[ClusterCenter]) -> bool {
+        !clusters.is_empty()
+            && self.centers.len() == clusters.len()
+            && self.centers.iter().zip(clusters).all(|(&c, cluster)| c == cluster.center_idx)
+    }
+
+    /// Bytes of the centers and of their distances
+    pub(crate) fn memory_used(&self) -> usize {
+        self.centers.len() * std::mem::size_of::<usize>() + self.distances.len() * std::mem::size_of::<f32>()
+    }
+
+    /// Distances from the center of cluster `i` to the centers of all the clusters
+    fn row(&self, i: usize) -> &[f32] {
+        let n = self.centers.len();
+        &self.distances[i * n..(i + 1) * n]
+    }
+}
+
+/// What the key of an entry of a [`ProbeOrder`] is
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Key {
+    /// Distance from the query to the center of a cluster
+    Exact,
+    /// Lower bound on the distance from the query to the center of a cluster
+    Bound,
+    /// Lower bound on the distance from the query to the centers of the clusters of a coarse cell
+    Cell,
+}
+
+/// Order in which a query probes the clusters, from the closest center.
+///
+/// With the center distances, the distance from the query to a center is only computed when
+/// the cluster may be the next one to probe. Every cluster starts with a lower bound of 0 on
+/// that distance, raised by the triangle inequality from the distances to the first centers
+/// computed, and is resolved when its bound is the smallest left: the clusters are probed in
+/// the same order as if all the distances were computed upfront. A cluster whose bound minus
+/// its radius exceeds the stopping distance can't hold a point closer than the k-th neighbor,
+/// and is skipped without computing its distance.
+///
+/// With a [`Hierarchy`], the coarse cells are bounded the same way from the distance to their
+/// center, and the distances to the centers of the clusters of a cell are computed when the
+/// cell is resolved.
+pub(crate) struct ProbeOrder {
+    heap: BinaryHeap<Reverse<(OrderedFloat<f32>, usize, Key)>>, // key, cluster or cell, kind of key
+    lower_bounds: Vec<f32>,
+    distances: Vec<Option<f32>>, // distance from the query to every center, once computed
+    radii: Vec<f32>, // radius of every cluster used to prune it
+    pivots: usize,
+    probes_left: usize,
+    distance_computations: usize,
+}
+
+impl ProbeOrder {
+    /// Probes the clusters in the order of `sorted`, the distances from the query to all the
+    /// centers being already computed, pruning cluster `i` with radius `radii[i]`
+    pub(crate) fn sorted(sorted: Vec<(usize, f32)>, radii: Vec<f32>, max_probes: usize) -> Self {
+        let mut distances = vec![None; radii.len()];
+        for &(cluster, distance) in &sorted {
+            distances[cluster] = Some(distance);
+        }
+
+        Self {
+            heap: sorted
+                .into_iter()
+                .map(|(cluster, distance)| Reverse((OrderedFloat(distance), cluster, Key::Exact)))
+                .collect(),
+            lower_bounds: Vec::new(),
+            distances,
+            radii,
+            pivots: MAX_PIVOTS,
+            probes_left: max_probes,
+            distance_computations: 0,
+        }
+    }
+
+    /// Computes the distances from the query to the centers of the clusters when needed,
+    /// bounding the others with the center distances, and prunes cluster `i` with radius `radii[i]`
+    pub(crate) fn bounded(radii: Vec<f32>, max_probes: usize) -> Self {
+        let num_clusters = radii.len();
+        Self {
+            heap: (0..num_clusters)
+                .map(|cluster| Reverse((OrderedFloat(0.0), cluster, Key::Bound)))
+                .collect(),
+            lower_bounds: vec![0.0; num_clusters],
+            distances: vec![None; num_clusters],
+            radii,
+            pivots: 0,
+            probes_left: max_probes,
+            distance_computations: 0,
+        }
+    }
+
+    /// Probes the clusters as [`bounded()`](Self::bounded), starting from `lower_bounds`, lower
+    /// bounds in the metric on the distances from the query to every center, e.g. the
+    /// [`metric_bounds()`](Self::metric_bounds) of a nearby query minus its distance to this
+    /// one. The center distances only raise the bounds further with `pivots`, so they are not
+    /// needed without.
+    pub(crate) fn seeded(lower_bounds: Vec<f32>, radii: Vec<f32>, max_probes: usize, pivots: bool) -> Self {
+        Self {
+            heap: lower_bounds
+                .iter()
+                .enumerate()
+                .map(|(cluster, &bound)| Reverse((OrderedFloat(bound), cluster, Key::Bound)))
+                .collect(),
+            distances: vec![None; lower_bounds.len()],
+            lower_bounds,
+            radii,
+            pivots: if pivots { 0 } else { MAX_PIVOTS },
+            probes_left: max_probes,
+            distance_computations: 0,
+        }
+    }
+
+    /// Computes the distances from the query to the centers of the coarse cells of `hierarchy`,
+    /// and to the centers of the clusters of a cell when it may hold the next one to probe,
+    /// pruning cluster `i` with radius `radii[i]`
+    pub(crate) fn hierarchical<T: MetricData>(
+        data: &T,
+        hierarchy: &Hierarchy,
+        query: &[T::DataType],
+        radii: Vec<f32>,
+        max_probes: usize,
+    ) -> Self {
+        let heap = hierarchy
+            .cells
+            .iter()
+            .enumerate()
+            .map(|(cell, coarse)| {
+                let metric = data.distance_to_metric(data.distance_point(coarse.center_idx, query));
+                Reverse((OrderedFloat((metric - coarse.radius).max(0.0)), cell, Key::Cell))
+            })
+            .collect();
+
+        Self {
+            heap,
+            lower_bounds: Vec::new(),
+            distances: vec![None; radii.len()],
+            radii,
+            pivots: MAX_PIVOTS,
+            probes_left: max_probes,
+            distance_computations: hierarchy.cells.len(),
+        }
+    }
+
+    /// Next cluster to probe with the distance from the query to its center, or `None` once
+    /// the probe limit is reached or the next cluster is farther than `bound` from the query
+    /// by more than its radius. Clusters skipped because of `bound` are never returned, so it
+    /// must not grow between calls.
+    pub(crate) fn next<T: MetricData>(
+        &mut self,
+        data: &T,
+        clusters: &[ClusterCenter],
+        center_distances: &CenterDistances,
+        hierarchy: Option<&Hierarchy>,
+        query: &[T::DataType],
+        bound: Option<f32>,
+    ) -> Option<(usize, f32)> {
+        if self.probes_left == 0 {
+            return None;
+        }
+        // compared in the metric, where the triangle inequality holds
+        let bound = bound.map(|bound| data.distance_to_metric(bound));
+
+        while let Some(Reverse((key, cluster, kind))) = self.heap.pop() {
+            if kind == Key::Cell {
+                let Some(cell) = hierarchy.map(|hierarchy| &hierarchy.cells[cluster]) else {
+                    continue;
+                };
+                let radius = cell.clusters.iter().map(|&c| self.radii[c]).fold(0.0, f32::max);
+                if bound.is_some_and(|bound| key.0 - data.distance_to_metric(radius) > bound) {
+                    continue;
+                }
+                for &cluster in &cell.clusters {
+                    let distance = data.distance_point(clusters[cluster].center_idx, query);
+                    self.distances[cluster] = Some(distance);
+                    self.heap.push(Reverse((OrderedFloat(data.distance_to_metric(distance)), cluster, Key::Exact)));
+                }
+                self.distance_computations += cell.clusters.len();
+                continue;
+            }
+
+            let radius = data.distance_to_metric(self.radii[cluster]);
+            if kind == Key::Exact {
+                let Some(distance) = self.distances[cluster] else {
+                    continue;
+                };
+                if bound.is_some_and(|bound| data.distance_to_metric(distance) - radius > bound) {
+                    // pruned, a farther cluster with a larger radius may still hold a neighbor
+                    continue;
+                }
+                self.probes_left -= 1;
+                return Some((cluster, distance));
+            }
+
+            let lower_bound = self.lower_bounds[cluster];
+            if lower_bound > key.0 {
+                // the bound was raised since the cluster was pushed
+                self.heap.push(Reverse((OrderedFloat(lower_bound), cluster, Key::Bound)));
+                continue;
+            }
+            if bound.is_some_and(|bound| lower_bound - radius > bound) {
+                continue;
+            }
+
+            let distance = data.distance_point(clusters[cluster].center_idx, query);
+            let metric = data.distance_to_metric(distance);
+            self.distance_computations += 1;
+            self.distances[cluster] = Some(distance);
+            if self.pivots < MAX_PIVOTS {
+                self.pivots += 1;
+                for (lower_bound, &between) in self.lower_bounds.iter_mut().zip(center_distances.row(cluster)) {
+                    *lower_bound = lower_bound.max((metric - between).abs());
+                }
+            }
+            self.heap.push(Reverse((OrderedFloat(metric), cluster, Key::Exact)));
+        }
+
+        None
+    }
+
+    /// Leaves `cluster` out of the order, it is never returned by [`next()`](Self::next)
+    pub(crate) fn exclude(&mut self, cluster: usize) {
+        self.heap.retain(|&Reverse((_, c, kind))| c != cluster || kind == Key::Cell);
+    }
+
+    /// Distances from the query to a center computed by [`next()`](Self::next)
+    pub(crate) fn distance_computations(&self) -> usize {
+        self.distance_computations
+    }
+
+    /// Lower bounds in the metric on the distances from the query to every center, exact for
+    /// the centers whose distance was computed, to seed the order of a nearby query with
+    /// [`seeded()`](Self::seeded)
+    pub(crate) fn metric_bounds<T: MetricData>(&self, data: &T) -> Vec<f32> {
+        self.distances
+            .iter()
+            .enumerate()
+            .map(|(cluster, distance)| match distance {
+                Some(distance) => data.distance_to_metric(*distance),
+                None => self.lower_bounds.get(cluster).copied().unwrap_or(0.0),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::Array2;
+
+    use super::{CenterDistances, ProbeOrder};
+    use crate::core::index::{ClusterCenter, Hierarchy};
+    use crate::metricdata::{AngularData, EuclideanData, MetricData};
+    use crate::utils::datagen::unit_vectors;
+
+    fn clusters(centers: &[usize], radius: f32) -> Vec<ClusterCenter> {
+        centers
+            .iter()
+            .enumerate()
+            .map(|(idx, &center_idx)| ClusterCenter {
+                idx,
+                center_idx,
+                radius,
+                assignment: vec![center_idx].into(),
+                brute_force: true,
+                memory_used: 0,
+                num_tables: None,
+                build_time: Default::default(),
+                search_stats: Default::default(),
+                member_distances: None,
+                outlier: false,
+                over_memory_ceiling: false,
+                outlier_threshold: None,
+            })
+            .collect()
+    }
+
+    fn probes<T: MetricData>(
+        data: &T,
+        clusters: &[ClusterCenter],
+        center_distances: &CenterDistances,
+        query: &[T::DataType],
+        bound: Option<f32>,
+    ) -> (Vec<(usize, f32)>, usize) {
+        let radii = clusters.iter().map(|c| c.radius).collect();
+        let mut order = ProbeOrder::bounded(radii, usize::MAX);
+        let mut probed = Vec::new();
+        while let Some(probe) = order.next(data, clusters, center_distances, None, query, bound) {
+            probed.push(probe);
+        }
+        (probed, order.distance_computations())
+    }
+
+    #[test]
+    fn test_bounded_order() {
+        // centers on a line, 1 apart
+        let points = Array2::from_shape_fn((50, 2), |(i, j)| if j == 0 { i as f32 } else { 0.0 });
+        let data = EuclideanData::new(points);
+        let clusters = clusters(&(0..50).collect::<Vec<_>>(), 0.5);
+        let center_distances = CenterDistances::compute(&data, &clusters);
+        assert!(center_distances.matches(&clusters));
+
+        let query = [20.2, 0.0];
+        let (probed, computed) = probes(&data, &clusters, &center_distances, &query, None);
+        let expected: Vec<usize> = vec![20, 21, 19, 22, 18];
+        assert_eq!(probed.len(), 50);
+        assert_eq!(probed.iter().take(5).map(|&(c, _)| c).collect::<Vec<_>>(), expected);
+        assert!(probed.windows(2).all(|w| w[0].1 <= w[1].1));
+        assert_eq!(computed, 50);
+
+        // the clusters farther than the bound by more than their radius are not probed
+        let (probed, computed) = probes(&data, &clusters, &center_distances, &query, Some(2.5));
+        assert_eq!(probed.iter().map(|&(c, _)| c).collect::<Vec<_>>(), vec![20, 21, 19, 22, 18, 23]);
+        assert!(computed < 10);
+    }
+
+    #[test]
+    fn test_angular_bounds() {
+        let data = AngularData::new(unit_vectors(300, 3, 1));
+        let clusters = clusters(&(0..300).step_by(3).collect::<Vec<_>>(), 0.0);
+        let center_distances = CenterDistances::compute(&data, &clusters);
+        let query = unit_vectors(1, 3, 2).row(0).to_vec();
+
+        // same order as sorting the exact distances
+        let (probed, _) = probes(&data, &clusters, &center_distances, &query, None);
+        let mut sorted: Vec<(usize, f32)> = clusters
+            .iter()
+            .map(|c| (c.idx, data.distance_point(c.center_idx, &query)))
+            .collect();
+        sorted.sort_by(|a, b| a.1.total_cmp(&b.1));
+        let mut order = ProbeOrder::sorted(sorted.clone(), vec![0.0; clusters.len()], 3);
+        let limited: Vec<_> = std::iter::from_fn(|| order.next(&data, &clusters, &center_distances, None, &query, None)).collect();
+        assert_eq!(limited, sorted[..3]);
+        for (a, b) in probed.iter().zip(&sorted) {
+            assert!((a.1 - b.1).abs() < 1e-6);
+        }
+
+        // no cluster closer than the bound is skipped
+        let (probed, computed) = probes(&data, &clusters, &center_distances, &query, Some(0.1));
+        let within = sorted.iter().take_while(|&&(_, d)| d <= 0.1).count();
+        assert_eq!(probed.len(), within);
+        assert!(computed < clusters.len());
+    }
+
+    #[test]
+    fn test_hierarchical_order() {
+        // centers on a line, 1 apart, in cells of 5 consecutive centers
+        let points = Array2::from_shape_fn((100, 2), |(i, j)| if j == 0 { i as f32 } else { 0.0 });
+        let data = EuclideanData::new(points);
+        let clusters = clusters(&(0..100).collect::<Vec<_>>(), 0.5);
+        let hierarchy = Hierarchy::compute(&data, &clusters, &(2..100).step_by(5).collect::<Vec<_>>());
+        assert_eq!(hierarchy.cells.len(), 20);
+        assert!(hierarchy.cells.iter().all(|cell| cell.clusters.len() == 5 && cell.radius == 2.0));
+
+        let query = [40.2, 0.0];
+        let radii = vec![0.5; clusters.len()];
+        let mut order = ProbeOrder::hierarchical(&data, &hierarchy, &query, radii.clone(), usize::MAX);
+        let probed: Vec<(usize, f32)> = std::iter::from_fn(|| {
+            order.next(&data, &clusters, &CenterDistances::default(), Some(&hierarchy), &query, None)
+        })
+        .collect();
+        // same order as sorting all the distances
+        assert_eq!(probed.len(), 100);
+        assert_eq!(probed.iter().take(5).map(|&(c, _)| c).collect::<Vec<_>>(), vec![40, 41, 39, 42, 38]);
+        assert!(probed.windows(2).all(|w| w[0].1 <= w[1].1));
+        assert_eq!(order.distance_computations(), 120);
+
+        // the cells farther than the bound are not opened
+        let mut order = ProbeOrder::hierarchical(&data, &hierarchy, &query, radii, usize::MAX);
+        let probed: Vec<usize> = std::iter::from_fn(|| {
+            order.next(&data, &clusters, &CenterDistances::default(), Some(&hierarchy), &query, Some(2.5))
+        })
+        .map(|(c, _)| c)
+        .collect();
+        assert_eq!(probed, vec![40, 41, 39, 42, 38, 43]);
+        assert!(order.distance_computations() <= 20 + 15);
+    }
+
+    #[test]
+    fn test_seeded_order() {
+        // centers on a line, 1 apart
+        let points = Array2::from_shape_fn((50, 2), |(i, j)| if j == 0 { i as f32 } else { 0.0 });
+        let data = EuclideanData::new(points);
+        let clusters = clusters(&(0..50).collect::<Vec<_>>(), 0.5);
+        let center_distances = CenterDistances::compute(&data, &clusters);
+        let radii = vec![0.5; clusters.len()];
+
+        // the bounds of a query searched with the center distances never exceed its distances
+        let anchor = [20.2, 0.0];
+        let mut order = ProbeOrder::bounded(radii.clone(), usize::MAX);
+        while order.next(&data, &clusters, &center_distances, None, &anchor, Some(2.5)).is_some() {}
+        let bounds = order.metric_bounds(&data);
+        for (cluster, &bound) in bounds.iter().enumerate() {
+            assert!(bound <= data.distance_point(cluster, &anchor) + 1e-5);
+        }
+        assert_eq!(bounds[20], data.distance_point(20, &anchor));
+
+        // a nearby query starts from the exact distances of the anchor, lowered by its offset
+        let mut sorted: Vec<(usize, f32)> = (0..50).map(|c| (c, data.distance_point(c, &anchor))).collect();
+        sorted.sort_by(|a, b| a.1.total_cmp(&b.1));
+        let mut order = ProbeOrder::sorted(sorted, radii.clone(), usize::MAX);
+        while order.next(&data, &clusters, &center_distances, None, &anchor, None).is_some() {}
+        let query = [20.3, 0.0];
+        let offset = 0.1;
+        let seeded: Vec<f32> = order.metric_bounds(&data).iter().map(|b| (b - offset).max(0.0)).collect();
+
+        // same order as sorting all the distances
+        let mut order = ProbeOrder::seeded(seeded.clone(), radii.clone(), usize::MAX, false);
+        let probed: Vec<(usize, f32)> =
+            std::iter::from_fn(|| order.next(&data, &clusters, &CenterDistances::default(), None, &query, None))
+                .collect();
+        assert_eq!(probed.len(), 50);
+        assert_eq!(probed.iter().take(5).map(|&(c, _)| c).collect::<Vec<_>>(), vec![20, 21, 19, 22, 18]);
+        assert!(probed.windows(2).all(|w| w[0].1 <= w[1].1));
+
+        // only the centers whose seeded bound is within the stopping distance are computed
+        let mut order = ProbeOrder::seeded(seeded, radii, usize::MAX, false);
+        let probed: Vec<usize> = std::iter::from_fn(|| {
+            order.next(&data, &clusters, &CenterDistances::default(), None, &query, Some(2.5))
+        })
+        .map(|(c, _)| c)
+        .collect();
+        assert_eq!(probed, vec![20, 21, 19, 22, 18, 23]);
+        assert_eq!(order.distance_computations(), 6);
+    }
+}
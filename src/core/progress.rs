@@ -0,0 +1,170 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+/// Tracks the progress of the PUFFINN index creation phase of a build.
+///
+/// Building a PUFFINN index is roughly linear in the number of points inserted,
+/// so progress is weighted by cluster sizes rather than by the number of clusters:
+/// a cluster with 100k points counts 100 times more than one with 1k points.
+#[derive(Debug, Clone)]
+pub struct BuildProgress {
+    start: Instant,
+    total_clusters: usize,
+    completed_clusters: usize,
+    total_points: usize,
+    processed_points: usize,
+}
+
+impl BuildProgress {
+    pub(crate) fn new(total_clusters: usize, total_points: usize) -> Self {
+        Self {
+            start: Instant::now(),
+            total_clusters,
+            completed_clusters: 0,
+            total_points,
+            processed_points: 0,
+        }
+    }
+
+    /// Marks a cluster with `num_points` points as completed.
+    pub(crate) fn cluster_done(&mut self, num_points: usize) {
+        self.completed_clusters += 1;
+        self.processed_points += num_points;
+    }
+
+    pub fn total_clusters(&self) -> usize {
+        self.total_clusters
+    }
+
+    pub fn completed_clusters(&self) -> usize {
+        self.completed_clusters
+    }
+
+    /// Fraction of points already indexed, in [0, 1].
+    pub fn fraction(&self) -> f32 {
+        if self.total_points == 0 {
+            return 1.0;
+        }
+        self.processed_points as f32 / self.total_points as f32
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    /// Estimated time remaining, extrapolated from the time spent per point so far.
+    ///
+    /// Returns `None` until at least one non-empty cluster has been completed.
+    pub fn eta(&self) -> Option<Duration> {
+        if self.processed_points == 0 {
+            return None;
+        }
+        let remaining_points = self.total_points.saturating_sub(self.processed_points);
+        let per_point = self.elapsed().as_secs_f64() / self.processed_points as f64;
+        Some(Duration::from_secs_f64(per_point * remaining_points as f64))
+    }
+}
+
+/// Phase of a build reported to a [`BuildObserver`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum BuildPhase {
+    /// Greedy clustering, one step per center chosen
+    Clustering,
+
+    /// Creation of the cluster indices, one step per cluster
+    Indexing,
+}
+
+/// Time left in a phase started at `start` with `fraction` of it completed, extrapolated
+/// linearly. Returns `None` until some of the phase is completed.
+pub(crate) fn linear_eta(start: Instant, fraction: f32) -> Option<Duration> {
+    if fraction <= 0.0 {
+        return None;
+    }
+    let fraction = fraction.min(1.0) as f64;
+    Some(start.elapsed().mul_f64((1.0 - fraction) / fraction))
+}
+
+/// Receives the progress of a build, see [`ClusteredIndex::set_build_observer`](crate::core::ClusteredIndex::set_build_observer).
+///
+/// Implemented by any `FnMut(BuildPhase, usize, f32, Option<Duration>) + Send + Sync` closure.
+pub trait BuildObserver: Send + Sync {
+    /// Called after each center chosen by the clustering and each cluster indexed, with the
+    /// index of the center or cluster, the fraction of the phase completed, in [0, 1], and
+    /// the estimated time left in the phase, `None` until it can be estimated.
+    /// Indexing is weighted by cluster sizes, as in [`BuildProgress::fraction`] and
+    /// [`BuildProgress::eta`].
+    fn on_progress(&mut self, phase: BuildPhase, cluster: usize, fraction: f32, eta: Option<Duration>);
+}
+
+impl<F> BuildObserver for F
+where
+    F: FnMut(BuildPhase, usize, f32, Option<Duration>) + Send + Sync,
+{
+    fn on_progress(&mut self, phase: BuildPhase, cluster: usize, fraction: f32, eta: Option<Duration>) {
+        self(phase, cluster, fraction, eta)
+    }
+}
+
+/// Aborts a build from another thread, see [`ClusteredIndex::set_cancellation_token`](crate::core::ClusteredIndex::set_cancellation_token).
+///
+/// Clones share the same flag. A cancelled token stays cancelled, so a new one is needed
+/// for the next build.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fraction_weighted_by_points() {
+        let mut progress = BuildProgress::new(2, 100);
+        assert_eq!(progress.fraction(), 0.0);
+        assert!(progress.eta().is_none());
+
+        progress.cluster_done(90);
+        assert_eq!(progress.completed_clusters(), 1);
+        assert!((progress.fraction() - 0.9).abs() < 1e-6);
+        assert!(progress.eta().is_some());
+
+        progress.cluster_done(10);
+        assert_eq!(progress.fraction(), 1.0);
+        assert_eq!(progress.eta(), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn test_linear_eta() {
+        let start = Instant::now() - Duration::from_secs(10);
+        assert!(linear_eta(start, 0.0).is_none());
+        let eta = linear_eta(start, 0.25).unwrap();
+        assert!(eta >= Duration::from_secs(30) && eta < Duration::from_secs(31));
+        assert_eq!(linear_eta(start, 1.0), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn test_cancellation_token_is_shared() {
+        let token = CancellationToken::new();
+        let clone = token.clone();
+        assert!(!clone.is_cancelled());
+        token.cancel();
+        assert!(clone.is_cancelled());
+    }
+}
@@ -0,0 +1,129 @@
+use crate::metricdata::{MetricData, Scalar};
+
+/// Number of levels of an i8 code
+const LEVELS: f32 = 255.0;
+
+/// i8 copy of the dataset, with one affine quantizer per dimension, see [`Config::scalar_quantization`](crate::core::Config).
+///
+/// Component `j` of a point is stored as the level `c` in `-128..=127` closest to
+/// `min[j] + scale[j] * (c + 128)`, where `min` and `scale` span the range of the dimension in
+/// the training points. Distances are asymmetric: the query stays in f32 and is compared with
+/// the decoded codes, without decoding them one by one.
+///
+/// The distance is the angular distance of the clustered indexes, computed from the norms of
+/// the decoded points.
+#[derive(Debug, Clone)]
+pub(crate) struct ScalarQuantizer {
+    min: Vec<f32>,
+    scale: Vec<f32>,
+    /// Codes of the points, row after row
+    codes: Vec<i8>,
+    /// Norms of the decoded points
+    norms: Vec<f32>,
+}
+
+impl ScalarQuantizer {
+    /// Learns the range of every dimension of `data` and encodes all its points.
+    pub(crate) fn train<T: MetricData>(data: &T) -> Self {
+        let dims = data.dimensions();
+        let mut min = vec![f32::INFINITY; dims];
+        let mut max = vec![f32::NEG_INFINITY; dims];
+        for i in 0..data.num_points() {
+            for (j, &x) in data.indexed_point(i).iter().enumerate() {
+                let x = x.to_f32();
+                min[j] = min[j].min(x);
+                max[j] = max[j].max(x);
+            }
+        }
+        // constant dimensions are decoded exactly with any scale
+        let scale = min
+            .iter()
+            .zip(&max)
+            .map(|(&lo, &hi)| if hi > lo { (hi - lo) / LEVELS } else { 1.0 })
+            .collect();
+
+        let mut quantizer = Self {
+            min,
+            scale,
+            codes: Vec::with_capacity(data.num_points() * dims),
+            norms: Vec::with_capacity(data.num_points()),
+        };
+        for i in 0..data.num_points() {
+            quantizer.push(&data.indexed_point(i));
+        }
+        quantizer
+    }
+
+    /// Encodes `point` as the next point, components outside the trained range are clamped.
+    pub(crate) fn push<D: Scalar>(&mut self, point: &[D]) {
+        assert_eq!(point.len(), self.min.len());
+        let mut norm = 0.0;
+        for ((&x, &min), &scale) in point.iter().zip(&self.min).zip(&self.scale) {
+            let x = x.to_f32();
+            let level = ((x - min) / scale).round().clamp(0.0, LEVELS);
+            let decoded = min + scale * level;
+            norm += decoded * decoded;
+            self.codes.push((level - 128.0) as i8);
+        }
+        self.norms.push(norm.sqrt());
+    }
+
+    /// Bytes of the codes and of the norms
+    pub(crate) fn memory_used(&self) -> usize {
+        self.codes.len() + (self.norms.len() + 2 * self.min.len()) * std::mem::size_of::<f32>()
+    }
+
+    /// Approximate distances from `query` to the points `ids`, written to `out`.
+    pub(crate) fn distances<D: Scalar>(&self, ids: &[usize], query: &[D], out: &mut [f32]) {
+        assert_eq!(ids.len(), out.len());
+        let dims = self.min.len();
+        assert_eq!(query.len(), dims);
+        let query: Vec<f32> = query.iter().map(|&x| x.to_f32()).collect();
+
+        // <q, x> = sum(q * (min + 128 * scale)) + sum(q * scale * c)
+        let weights: Vec<f32> = query.iter().zip(&self.scale).map(|(q, s)| q * s).collect();
+        let offset: f32 = query
+            .iter()
+            .zip(&self.min)
+            .zip(&weights)
+            .map(|((q, min), w)| q * min + 128.0 * w)
+            .sum();
+        let norm_query = query.iter().map(|q| q * q).sum::<f32>().sqrt();
+
+        for (o, &i) in out.iter_mut().zip(ids) {
+            let codes = &self.codes[i * dims..(i + 1) * dims];
+            let dot: f32 = codes.iter().zip(&weights).map(|(&c, w)| c as f32 * w).sum();
+            *o = 1.0 - (offset + dot) / (self.norms[i] * norm_query);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ScalarQuantizer;
+    use crate::metricdata::{AngularData, MetricData};
+    use crate::utils::generate_random_unit_vectors;
+
+    #[test]
+    fn test_scalar_quantizer() {
+        let data = AngularData::new(generate_random_unit_vectors(300, 32));
+        let mut quantizer = ScalarQuantizer::train(&data);
+        assert_eq!(quantizer.norms.len(), 300);
+        assert!(quantizer.memory_used() < 300 * 32 * 4 / 3);
+
+        let query = generate_random_unit_vectors(1, 32).row(0).to_vec();
+        let ids: Vec<usize> = (0..300).collect();
+        let mut approximate = vec![0.0; 300];
+        quantizer.distances(&ids, &query, &mut approximate);
+        for (i, &d) in approximate.iter().enumerate() {
+            assert!((d - data.distance_point(i, &query)).abs() < 0.02);
+        }
+
+        // points outside the trained range are clamped, not wrapped around
+        let far: Vec<f32> = query.iter().map(|x| x * 10.0).collect();
+        quantizer.push(&far);
+        let mut out = [0.0];
+        quantizer.distances(&[300], &far, &mut out);
+        assert!(out[0] < 0.5);
+    }
+}
@@ -0,0 +1,23 @@
+use crate::core::Result;
+
+/// Computes the exact distances of the candidates of a search in place of the dataset, e.g. on
+/// a GPU, see [`ClusteredIndex::set_reranker`](crate::core::ClusteredIndex::set_reranker).
+///
+/// The candidates of a PUFFINN cluster, and all the points of a brute force cluster, are
+/// passed as one batch. Batches smaller than [`min_batch`](Self::min_batch) are computed by
+/// the dataset, where the transfer would cost more than it saves. It is shared by the
+/// concurrent searches of an [`IndexHandle`](crate::core::IndexHandle).
+pub trait Reranker<E>: Send + Sync {
+    /// Distances from `query` to the points `ids` of the dataset, written to `out`. They must
+    /// be the distances of [`MetricData::distance_point`](crate::metricdata::MetricData::distance_point),
+    /// up to round-off.
+    ///
+    /// # Errors
+    /// `ClusteredIndexError::RerankError` if the distances cannot be computed, the search fails
+    fn distances(&self, ids: &[usize], query: &[E], out: &mut [f32]) -> Result<()>;
+
+    /// Smallest batch given to the reranker
+    fn min_batch(&self) -> usize {
+        1
+    }
+}
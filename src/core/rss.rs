@@ -0,0 +1,121 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+/// Interval between two samples of the resident set size
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Resident set size of the process in bytes, `None` where `/proc/self/status` can't be read
+pub(crate) fn current_rss() -> Option<usize> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    let kilobytes: usize = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kilobytes * 1024)
+}
+
+/// Resident set size of the process during a build, by phase, see [`Config::track_rss`](crate::core::Config).
+///
+/// The peaks are sampled, a spike shorter than the sampling interval of 10ms may be missed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeakRss {
+    /// Resident set size when the build started
+    pub start: usize,
+
+    /// Highest resident set size while clustering the dataset and preparing the clusters
+    pub clustering: usize,
+
+    /// Highest resident set size while training the quantizers and creating the cluster indices
+    pub indexing: usize,
+}
+
+impl PeakRss {
+    /// Highest resident set size of the whole build
+    pub fn peak(&self) -> usize {
+        self.clustering.max(self.indexing)
+    }
+}
+
+/// Thread sampling the resident set size of the process until dropped
+pub(crate) struct RssSampler {
+    start: usize,
+    peak: Arc<AtomicUsize>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl RssSampler {
+    /// Starts sampling, `None` if the resident set size can't be read on this platform
+    pub(crate) fn start() -> Option<Self> {
+        let Some(start) = current_rss() else {
+            warn!("The resident set size can't be read on this platform, it won't be tracked");
+            return None;
+        };
+        let peak = Arc::new(AtomicUsize::new(start));
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let (peak, stop) = (peak.clone(), stop.clone());
+            std::thread::Builder::new()
+                .name("clann-rss".to_string())
+                .spawn(move || {
+                    while !stop.load(Ordering::Relaxed) {
+                        if let Some(rss) = current_rss() {
+                            peak.fetch_max(rss, Ordering::Relaxed);
+                        }
+                        std::thread::sleep(SAMPLE_INTERVAL);
+                    }
+                })
+                .ok()?
+        };
+
+        Some(Self {
+            start,
+            peak,
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    /// Resident set size when the sampling started
+    pub(crate) fn start_rss(&self) -> usize {
+        self.start
+    }
+
+    /// Highest resident set size since the start or the previous call, the next phase starting
+    /// from the current one
+    pub(crate) fn take_peak(&self) -> usize {
+        let current = current_rss().unwrap_or(0);
+        self.peak.swap(current, Ordering::Relaxed).max(current)
+    }
+}
+
+impl Drop for RssSampler {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{current_rss, RssSampler};
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_rss_sampler() {
+        let sampler = RssSampler::start().unwrap();
+        assert!(sampler.start_rss() > 0);
+
+        // a buffer alive for a few samples is in the peak of the phase, even once freed
+        let buffer = vec![1u8; 64 << 20];
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        assert!(current_rss().unwrap() >= buffer.len());
+        drop(buffer);
+        assert!(sampler.take_peak() >= 64 << 20);
+    }
+}
@@ -0,0 +1,164 @@
+use serde::{Deserialize, Serialize};
+
+use crate::metricdata::MetricData;
+use crate::puffinn_binds::{isa, Isa};
+
+use super::index::ClusterCenter;
+use super::memory::MemoryUsage;
+
+/// Summary of a set of values, zero everywhere if the set is empty.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Distribution {
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+    pub p50: f64,
+    pub p90: f64,
+    pub p99: f64,
+}
+
+impl Distribution {
+    pub(crate) fn new(mut values: Vec<f64>) -> Self {
+        if values.is_empty() {
+            return Self::default();
+        }
+        values.sort_by(f64::total_cmp);
+
+        // nearest-rank percentile
+        let percentile = |p: f64| {
+            let rank = ((p / 100.0) * values.len() as f64).ceil() as usize;
+            values[rank.clamp(1, values.len()) - 1]
+        };
+        Self {
+            min: values[0],
+            max: values[values.len() - 1],
+            mean: values.iter().sum::<f64>() / values.len() as f64,
+            p50: percentile(50.0),
+            p90: percentile(90.0),
+            p99: percentile(99.0),
+        }
+    }
+}
+
+/// Distances from the center of `cluster` to its members.
+pub(crate) fn member_distances<T: MetricData>(data: &T, cluster: &ClusterCenter) -> Distribution {
+    let center = data.get_point(cluster.center_idx).into_owned();
+    let mut distances = vec![0.0; cluster.assignment.len()];
+    data.distances_points(&cluster.assignment, &center, &mut distances);
+    Distribution::new(distances.into_iter().map(f64::from).collect())
+}
+
+/// Clusters with a number of points in `[min, max)`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct HistogramBin {
+    pub min: usize,
+    pub max: usize,
+    pub count: usize,
+}
+
+/// Shape of the clusters of an index, see [`ClusteredIndex::stats`](crate::core::ClusteredIndex::stats).
+#[derive(Debug, Clone, Serialize)]
+pub struct IndexStats {
+    pub num_clusters: usize,
+
+    /// Points assigned to the clusters, the dataset size for a built index
+    pub num_points: usize,
+
+    /// Clusters scanned exhaustively instead of searched with an index
+    pub brute_force_clusters: usize,
+
+    /// Points per cluster
+    pub sizes: Distribution,
+
+    /// Points per cluster in power-of-two bins, from the smallest to the largest cluster
+    pub size_histogram: Vec<HistogramBin>,
+
+    pub radii: Distribution,
+
+    /// Memory of the index of each cluster as reported by the backend, zero for brute force clusters
+    pub cluster_memory: Vec<usize>,
+
+    pub total_memory: usize,
+
+    /// Memory of the quantized copies of the dataset, see [`Config::scalar_quantization`](crate::core::Config)
+    /// and [`Config::pq`](crate::core::Config), zero without
+    pub quantized_memory: usize,
+
+    /// Memory of the whole index by component, see [`ClusteredIndex::memory_usage`](crate::core::ClusteredIndex::memory_usage).
+    /// Only the cluster indices and the assignments are known from an index file alone
+    pub memory: MemoryUsage,
+
+    /// Instruction set of the PUFFINN kernels of this process, see [`Isa`]
+    pub isa: Isa,
+}
+
+impl IndexStats {
+    pub(crate) fn from_clusters(clusters: &[ClusterCenter]) -> Self {
+        let sizes: Vec<usize> = clusters.iter().map(|c| c.assignment.len()).collect();
+        let cluster_memory: Vec<usize> = clusters.iter().map(|c| c.memory_used).collect();
+
+        Self {
+            num_clusters: clusters.len(),
+            num_points: sizes.iter().sum(),
+            brute_force_clusters: clusters.iter().filter(|c| c.brute_force).count(),
+            sizes: Distribution::new(sizes.iter().map(|&s| s as f64).collect()),
+            size_histogram: size_histogram(&sizes),
+            radii: Distribution::new(clusters.iter().map(|c| c.radius as f64).collect()),
+            total_memory: cluster_memory.iter().sum(),
+            cluster_memory,
+            quantized_memory: 0,
+            memory: MemoryUsage::from_clusters(clusters),
+            isa: isa(),
+        }
+    }
+}
+
+/// Bin 0 holds the empty clusters, bin `b > 0` the clusters with `[2^(b-1), 2^b)` points
+fn size_histogram(sizes: &[usize]) -> Vec<HistogramBin> {
+    let bin = |size: usize| (usize::BITS - size.leading_zeros()) as usize;
+    let (Some(first), Some(last)) = (
+        sizes.iter().map(|&s| bin(s)).min(),
+        sizes.iter().map(|&s| bin(s)).max(),
+    ) else {
+        return Vec::new();
+    };
+
+    let bounds = |b: usize| match b {
+        0 => (0, 1),
+        b => (1 << (b - 1), 1usize.checked_shl(b as u32).unwrap_or(usize::MAX)),
+    };
+    (first..=last)
+        .map(|b| {
+            let (min, max) = bounds(b);
+            HistogramBin {
+                min,
+                max,
+                count: sizes.iter().filter(|&&s| bin(s) == b).count(),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{size_histogram, Distribution, HistogramBin};
+
+    #[test]
+    fn test_size_histogram() {
+        let bins = size_histogram(&[3, 2, 9, 0]);
+        assert_eq!(bins.len(), 5);
+        assert_eq!(bins[0], HistogramBin { min: 0, max: 1, count: 1 });
+        assert_eq!(bins[2], HistogramBin { min: 2, max: 4, count: 2 });
+        assert_eq!(bins[3].count, 0);
+        assert_eq!(bins[4], HistogramBin { min: 8, max: 16, count: 1 });
+        assert!(size_histogram(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_distribution() {
+        let d = Distribution::new((1..=100).map(|v| v as f64).collect());
+        assert_eq!((d.min, d.max, d.p50, d.p99), (1.0, 100.0, 50.0, 99.0));
+        assert_eq!(d.mean, 50.5);
+        assert_eq!(Distribution::new(Vec::new()), Distribution::default());
+    }
+}
@@ -0,0 +1,126 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use log::info;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::core::{ClusteredIndex, ClusteredIndexError, Config, Result};
+use crate::metricdata::{MetricData, MmapRows, OutOfCoreData};
+
+/// Points sampled from the stream to choose the centers
+const SAMPLE_POINTS: usize = 50_000;
+
+/// Points of the spilled dataset cached in memory, besides the pages of the mapping
+const CACHE_POINTS: usize = 16_384;
+
+/// Seed of the reservoir sampling, so that the same stream gives the same index
+const SEED: u64 = 0x5354;
+
+/// Spill files created by this process
+static SPILLS: AtomicUsize = AtomicUsize::new(0);
+
+/// Builds an index over the points of `points` in two passes, without holding them in memory.
+///
+/// The first pass writes the points to a temporary file, removed with the index, and keeps a
+/// uniform sample of them, on which the centers are chosen. The second pass reads the file in
+/// order, assigns every point to its nearest center and creates the index of every cluster.
+pub(crate) fn build_from_iter<I>(points: I, config: Config) -> Result<ClusteredIndex<OutOfCoreData<MmapRows>>>
+where
+    I: IntoIterator<Item = Vec<f32>>,
+{
+    let path = std::env::temp_dir().join(format!(
+        "clann-stream-{}-{}.f32",
+        std::process::id(),
+        SPILLS.fetch_add(1, Ordering::Relaxed)
+    ));
+    let (dimensions, mut sample) = match spill(points, &path) {
+        Ok(spilled) => spilled,
+        Err(e) => {
+            let _ = std::fs::remove_file(&path);
+            return Err(e);
+        }
+    };
+
+    let rows = MmapRows::open_temporary(path, dimensions).map_err(ClusteredIndexError::DataError)?;
+    let data = OutOfCoreData::new(rows, CACHE_POINTS).map_err(ClusteredIndexError::DataError)?;
+    info!("Streamed {} points, clustering a sample of {}", data.num_points(), sample.len());
+    let mut index = ClusteredIndex::new(config, data)?;
+    sample.sort_unstable();
+    index.build_sampled(&sample)?;
+    Ok(index)
+}
+
+/// Writes `points` to `path` and returns their dimensions and a sample of at most
+/// `SAMPLE_POINTS` of their ids, with reservoir sampling.
+fn spill<I>(points: I, path: &PathBuf) -> Result<(usize, Vec<usize>)>
+where
+    I: IntoIterator<Item = Vec<f32>>,
+{
+    let io_error = |e: std::io::Error| ClusteredIndexError::DataError(format!("Error writing {}: {}", path.display(), e));
+    let mut writer = BufWriter::new(File::create(path).map_err(io_error)?);
+    let mut rng = StdRng::seed_from_u64(SEED);
+    let mut dimensions = None;
+    let mut sample = Vec::new();
+
+    for (i, point) in points.into_iter().enumerate() {
+        let dimensions = *dimensions.get_or_insert(point.len());
+        if point.len() != dimensions {
+            return Err(ClusteredIndexError::DataError(format!(
+                "point {} has {} dimensions, the first one has {}",
+                i,
+                point.len(),
+                dimensions
+            )));
+        }
+        for x in point {
+            writer.write_all(&x.to_ne_bytes()).map_err(io_error)?;
+        }
+
+        if sample.len() < SAMPLE_POINTS {
+            sample.push(i);
+        } else {
+            let j = rng.gen_range(0..=i);
+            if j < SAMPLE_POINTS {
+                sample[j] = i;
+            }
+        }
+    }
+    writer.flush().map_err(io_error)?;
+
+    match dimensions {
+        Some(dimensions) if dimensions > 0 => Ok((dimensions, sample)),
+        _ => Err(ClusteredIndexError::DataError("empty dataset".to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::build_from_iter;
+    use crate::core::{ClusteredIndexError, Config, IndexMode};
+    use crate::metricdata::MetricData;
+    use crate::utils::datagen::unit_vectors;
+
+    #[test]
+    fn test_build_from_iter() {
+        let points = unit_vectors(1500, 8, 1);
+        let config = Config {
+            index_mode: IndexMode::Flat,
+            ..Default::default()
+        };
+        let mut index = build_from_iter(points.outer_iter().map(|row| row.to_vec()), config.clone()).unwrap();
+        assert_eq!(index.data().num_points(), 1500);
+        assert_eq!(index.clusters().map(|c| c.members.len()).sum::<usize>(), 1500);
+
+        for i in [0, 700, 1499] {
+            let query = points.row(i).to_vec();
+            assert_eq!(index.search(&query).unwrap()[0].1, i);
+        }
+
+        let ragged = vec![vec![1.0, 0.0], vec![0.0, 1.0, 0.0]];
+        assert!(matches!(build_from_iter(ragged, config.clone()), Err(ClusteredIndexError::DataError(_))));
+        assert!(matches!(build_from_iter(Vec::new(), config), Err(ClusteredIndexError::DataError(_))));
+    }
+}
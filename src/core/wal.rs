@@ -0,0 +1,309 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use log::warn;
+use ndarray::{Array2, ArrayBase, Data, Ix2};
+
+use crate::core::{ClusteredIndexError, Config, Result};
+use crate::metricdata::{Insertable, Scalar};
+
+use super::cache::Fnv64;
+
+const MAGIC: &[u8; 8] = b"CLANNWAL";
+const VERSION: u32 = 1;
+const HEADER_LEN: u64 = 12;
+
+/// Batch of points inserted into an index, as given to
+/// [`insert_batch()`](crate::core::ClusteredIndex::insert_batch)
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct LoggedInsert {
+    /// Id given to the first point of the batch
+    pub(crate) first_id: usize,
+    pub(crate) rows: usize,
+    pub(crate) dims: usize,
+    values: Vec<f64>,
+    /// Whether the points of the batch stayed in the dataset in no cluster, as the batch
+    /// failed. Only the `rows` points appended before the failure are kept
+    pub(crate) aborted: bool,
+}
+
+/// Record of a write-ahead log
+enum Record {
+    Insert(LoggedInsert),
+    /// The batch from `first_id` failed with `rows` of its points in the dataset
+    Abort { first_id: usize, rows: usize },
+}
+
+impl LoggedInsert {
+    /// Points of the batch, one per row
+    pub(crate) fn points<D: Scalar>(&self) -> Array2<D> {
+        let values = self.values.iter().map(|&x| D::from_f64(x)).collect();
+        Array2::from_shape_vec((self.rows, self.dims), values).expect("the shape matches the values")
+    }
+
+    /// Keeps the first `rows` points of the batch, in no cluster
+    fn abort(&mut self, rows: usize) {
+        self.rows = self.rows.min(rows);
+        self.values.truncate(self.rows * self.dims);
+        self.aborted = true;
+    }
+}
+
+impl Record {
+    /// Reads the record of `payload`, `None` if it is malformed. An abort holds the first id
+    /// and the number of points of its batch, an insertion holds its dimensions and points too
+    fn decode(payload: &[u8]) -> Option<Self> {
+        let mut words = payload.chunks_exact(8).map(|w| u64::from_le_bytes(w.try_into().unwrap()));
+        let first_id = words.next()? as usize;
+        let rows = words.next()? as usize;
+        if payload.len() == 16 {
+            return Some(Record::Abort { first_id, rows });
+        }
+        let dims = words.next()? as usize;
+        let values: Vec<f64> = words.map(f64::from_bits).collect();
+        (payload.len().is_multiple_of(8) && values.len() == rows.checked_mul(dims)?).then_some(Record::Insert(
+            LoggedInsert {
+                first_id,
+                rows,
+                dims,
+                values,
+                aborted: false,
+            },
+        ))
+    }
+}
+
+/// Write-ahead log of the insertions into an index, see
+/// [`ClusteredIndex::open_wal`](crate::core::ClusteredIndex::open_wal).
+///
+/// Every batch is appended as a record with its length and checksum, and synced to disk
+/// before the batch is applied. The components are saved as f64, which holds every
+/// [`Scalar`] exactly. A batch that fails once logged is followed by an abort record, its
+/// points staying in the dataset in no cluster. A record cut short by a crash while appending
+/// fails its checksum, and is dropped with the rest of the file when the log is opened again:
+/// its batch was never applied. The log is only emptied explicitly, once the index and the
+/// dataset holding the inserted points are both saved.
+#[derive(Debug)]
+pub(crate) struct WriteAheadLog {
+    file: File,
+    path: PathBuf,
+}
+
+fn io_error(path: &Path, e: impl std::fmt::Display) -> ClusteredIndexError {
+    ClusteredIndexError::SerializeError(format!("write-ahead log {}: {}", path.display(), e))
+}
+
+impl WriteAheadLog {
+    /// Opens the log at `path`, creating it if needed.
+    ///
+    /// # Returns
+    /// The log, and the batches it holds in the order they were inserted, the failed ones
+    /// marked [`aborted`](LoggedInsert::aborted)
+    ///
+    /// # Errors
+    /// `ClusteredIndexError::SerializeError` if the file cannot be read or written, or is not
+    /// a write-ahead log
+    pub(crate) fn open(path: &Path) -> Result<(Self, Vec<LoggedInsert>)> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)
+            .map_err(|e| io_error(path, e))?;
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes).map_err(|e| io_error(path, e))?;
+        let log = Self {
+            file,
+            path: path.to_path_buf(),
+        };
+
+        if bytes.is_empty() {
+            let mut header = MAGIC.to_vec();
+            header.extend_from_slice(&VERSION.to_le_bytes());
+            log.write(&header)?;
+            return Ok((log, Vec::new()));
+        }
+        if bytes.len() < HEADER_LEN as usize || &bytes[..8] != MAGIC {
+            return Err(io_error(path, "not a write-ahead log"));
+        }
+        let version = u32::from_le_bytes(bytes[8..12].try_into().unwrap());
+        if version != VERSION {
+            return Err(io_error(path, format!("unsupported version {}", version)));
+        }
+
+        let mut records: Vec<LoggedInsert> = Vec::new();
+        let mut offset = HEADER_LEN as usize;
+        while let Some((record, len)) = Self::record_at(&bytes[offset..]) {
+            match record {
+                Record::Insert(insert) => records.push(insert),
+                Record::Abort { first_id, rows } => {
+                    if let Some(insert) = records.iter_mut().rev().find(|insert| insert.first_id == first_id) {
+                        insert.abort(rows);
+                    }
+                }
+            }
+            offset += len;
+        }
+        if offset < bytes.len() {
+            warn!(
+                "Dropping the last {} bytes of the write-ahead log {}, a record cut short",
+                bytes.len() - offset,
+                path.display()
+            );
+            log.file.set_len(offset as u64).map_err(|e| io_error(path, e))?;
+        }
+
+        Ok((log, records))
+    }
+
+    /// The record at the start of `bytes` and its length, `None` if it is incomplete
+    fn record_at(bytes: &[u8]) -> Option<(Record, usize)> {
+        let len = u64::from_le_bytes(bytes.get(..8)?.try_into().unwrap()) as usize;
+        let checksum = u64::from_le_bytes(bytes.get(8..16)?.try_into().unwrap());
+        let payload = bytes.get(16..16usize.checked_add(len)?)?;
+
+        let mut hasher = Fnv64::new();
+        hasher.write(payload);
+        if hasher.finish() != checksum {
+            return None;
+        }
+        Some((Record::decode(payload)?, 16 + len))
+    }
+
+    /// Appends the batch of `points` whose first point gets id `first_id`, and syncs it to disk.
+    pub(crate) fn append<D: Scalar, S: Data<Elem = D>>(&self, first_id: usize, points: &ArrayBase<S, Ix2>) -> Result<()> {
+        let mut payload = Vec::with_capacity(8 * (3 + points.len()));
+        for word in [first_id, points.nrows(), points.ncols()] {
+            payload.extend_from_slice(&(word as u64).to_le_bytes());
+        }
+        for &x in points {
+            payload.extend_from_slice(&Into::<f64>::into(x).to_bits().to_le_bytes());
+        }
+        self.write_record(&payload)
+    }
+
+    /// Appends that the batch logged with `first_id` failed with its first `rows` points in
+    /// the dataset, in no cluster, and syncs it to disk.
+    pub(crate) fn abort(&self, first_id: usize, rows: usize) -> Result<()> {
+        let mut payload = Vec::with_capacity(16);
+        for word in [first_id, rows] {
+            payload.extend_from_slice(&(word as u64).to_le_bytes());
+        }
+        self.write_record(&payload)
+    }
+
+    /// Drops every record, once their batches are saved with the index.
+    pub(crate) fn clear(&self) -> Result<()> {
+        self.file.set_len(HEADER_LEN).map_err(|e| io_error(&self.path, e))?;
+        self.file.sync_data().map_err(|e| io_error(&self.path, e))
+    }
+
+    /// Path of the log
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn write_record(&self, payload: &[u8]) -> Result<()> {
+        let mut hasher = Fnv64::new();
+        hasher.write(payload);
+
+        let mut record = Vec::with_capacity(16 + payload.len());
+        record.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+        record.extend_from_slice(&hasher.finish().to_le_bytes());
+        record.extend_from_slice(payload);
+        self.write(&record)
+    }
+
+    fn write(&self, bytes: &[u8]) -> Result<()> {
+        (&self.file).write_all(bytes).map_err(|e| io_error(&self.path, e))?;
+        self.file.sync_data().map_err(|e| io_error(&self.path, e))
+    }
+}
+
+/// Appends to `data` the points of the write-ahead log at `path` it doesn't hold yet,
+/// projected with the projection of `config`, e.g. to load an index file saved after
+/// insertions over a dataset saved before them.
+///
+/// # Returns
+/// The number of points appended
+///
+/// # Errors
+/// - `ClusteredIndexError::SerializeError` if the log cannot be read
+/// - `ClusteredIndexError::DataError` if the log doesn't follow `data`, or a point cannot be
+///   projected or inserted
+pub(crate) fn extend_from_wal<T: Insertable>(data: &mut T, config: &Config, path: &Path) -> Result<usize> {
+    let (log, records) = WriteAheadLog::open(path)?;
+    let mut appended = 0;
+    for record in records {
+        let num_points = data.num_points();
+        if record.first_id > num_points {
+            return Err(ClusteredIndexError::DataError(format!(
+                "the write-ahead log {} continues from point {} but the dataset has {} points",
+                log.path().display(),
+                record.first_id,
+                num_points
+            )));
+        }
+        let points = record.points::<T::DataType>();
+        for row in points.rows().into_iter().skip(num_points - record.first_id) {
+            let mut point = row.to_vec();
+            if let Some(projection) = &config.projection {
+                point = projection.transform_point(&point)?;
+            }
+            data.insert(&point).map_err(ClusteredIndexError::DataError)?;
+            appended += 1;
+        }
+    }
+    Ok(appended)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::OpenOptions;
+    use std::io::Write;
+
+    use ndarray::arr2;
+
+    use super::WriteAheadLog;
+    use crate::utils::test_dir;
+
+    #[test]
+    fn test_write_ahead_log() {
+        let dir = test_dir("write_ahead_log");
+        let path = dir.join("index.wal");
+
+        let (log, records) = WriteAheadLog::open(&path).unwrap();
+        assert!(records.is_empty());
+        log.append(10, &arr2(&[[1.5f32, -2.0], [0.1, 3.0]])).unwrap();
+        log.append(12, &arr2(&[[4.0f32, 5.0]])).unwrap();
+        drop(log);
+
+        // a crash in the middle of an append leaves a record cut short
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[40, 0, 0, 0, 0, 0, 0, 0, 1, 2, 3]).unwrap();
+        drop(file);
+
+        let (log, records) = WriteAheadLog::open(&path).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].first_id, 10);
+        assert_eq!(records[0].points::<f32>(), arr2(&[[1.5f32, -2.0], [0.1, 3.0]]));
+        assert_eq!(records[1].points::<f32>(), arr2(&[[4.0f32, 5.0]]));
+
+        // the torn record was dropped, new records follow the valid ones
+        log.append(13, &arr2(&[[6.0f32, 7.0], [8.0, 9.0]])).unwrap();
+        log.abort(13, 1).unwrap();
+        let (log, records) = WriteAheadLog::open(&path).unwrap();
+        assert_eq!(records.iter().map(|r| r.first_id).collect::<Vec<_>>(), vec![10, 12, 13]);
+        assert_eq!(records.iter().map(|r| r.aborted).collect::<Vec<_>>(), vec![false, false, true]);
+        assert_eq!(records[2].points::<f32>(), arr2(&[[6.0f32, 7.0]]));
+
+        log.clear().unwrap();
+        let (_, records) = WriteAheadLog::open(&path).unwrap();
+        assert!(records.is_empty());
+
+        std::fs::write(&path, b"not a log").unwrap();
+        assert!(WriteAheadLog::open(&path).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
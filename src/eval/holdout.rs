@@ -0,0 +1,215 @@
+use std::time::{Duration, Instant};
+
+use log::info;
+use ndarray::Array2;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use serde::Serialize;
+
+use super::{evaluate, exact_neighbors, EvalParams, EvalReport, GroundTruth};
+use crate::core::{ClusteredIndex, ClusteredIndexError, Config, Result};
+use crate::metricdata::{MetricData, Subset};
+use crate::puffinn_binds::IndexableSimilarity;
+
+/// Parameters of a hold-out evaluation.
+#[derive(Debug, Clone)]
+pub struct HoldoutParams {
+    /// Number of points held out as queries
+    pub num_queries: usize,
+
+    /// Seed of the split, fixed so that runs are comparable
+    pub seed: u64,
+
+    /// Parameters of the evaluation, `k` is also the number of true neighbors computed
+    pub eval: EvalParams,
+}
+
+impl Default for HoldoutParams {
+    fn default() -> Self {
+        Self {
+            num_queries: 1000,
+            seed: 42,
+            eval: EvalParams::default(),
+        }
+    }
+}
+
+/// Points of a dataset held out as queries and points the index is built on.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct HoldoutSplit {
+    /// Ids of the queries in the dataset
+    pub queries: Vec<usize>,
+
+    /// Ids of the indexed points in the dataset, the point `i` of the index is `train[i]`
+    pub train: Vec<usize>,
+}
+
+/// Outcome of [`evaluate_holdout`].
+#[derive(Debug, Clone, Serialize)]
+pub struct HoldoutReport {
+    pub split: HoldoutSplit,
+    pub build_time: Duration,
+
+    /// Evaluation on the held-out queries, neighbor ids being positions in [`HoldoutSplit::train`]
+    pub report: EvalReport,
+}
+
+/// Splits the points of a dataset into `num_queries` random queries and the rest.
+///
+/// The same seed gives the same split. Both lists are sorted, so that the indexed points
+/// keep the order of the dataset.
+///
+/// # Errors
+/// Returns `ClusteredIndexError::ConfigError` if `num_queries` is zero or not smaller than
+/// the number of points
+pub fn holdout_split(num_points: usize, num_queries: usize, seed: u64) -> Result<HoldoutSplit> {
+    if num_queries == 0 || num_queries >= num_points {
+        return Err(ClusteredIndexError::ConfigError(format!(
+            "num_queries must be between 1 and {}, got {}",
+            num_points.saturating_sub(1),
+            num_queries
+        )));
+    }
+
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut ids: Vec<usize> = (0..num_points).collect();
+    ids.shuffle(&mut rng);
+    let mut train = ids.split_off(num_queries);
+    ids.sort_unstable();
+    train.sort_unstable();
+
+    Ok(HoldoutSplit { queries: ids, train })
+}
+
+/// Holds out queries from a dataset without a test set, builds an index on the other points
+/// and evaluates it against the exact nearest neighbors of the queries.
+///
+/// The true neighbors are found by scanning every indexed point, which takes
+/// `num_queries * (num_points - num_queries)` distance computations.
+///
+/// # Parameters
+/// - `data`: Whole dataset, queries included
+/// - `config`: Configuration of the index, its `k` is replaced by the one of `params.eval`
+/// - `params`: Number of queries, seed of the split and evaluation parameters
+///
+/// # Returns
+/// A [`HoldoutReport`] with the split, the build time and the [`EvalReport`]
+///
+/// # Errors
+/// - `ClusteredIndexError::ConfigError` if the split is invalid, or if fewer than `k` points
+///   are left to index
+/// - Any error returned by the build or by [`evaluate`]
+///
+/// # Example
+/// ```no_run
+/// use clann::core::Config;
+/// use clann::eval::{evaluate_holdout, HoldoutParams};
+/// use clann::metricdata::AngularData;
+/// use clann::utils::load_hdf5_dataset;
+///
+/// let dataset = load_hdf5_dataset("./datasets/my-embeddings.hdf5").unwrap();
+/// let data = AngularData::new(dataset.dataset_array);
+/// let outcome = evaluate_holdout(&data, Config::default(), &HoldoutParams::default()).unwrap();
+/// println!("recall {:.3}", outcome.report.recall_mean);
+/// ```
+pub fn evaluate_holdout<T>(data: &T, config: Config, params: &HoldoutParams) -> Result<HoldoutReport>
+where
+    T: MetricData + Subset,
+    <T as Subset>::Out: MetricData<DataType = T::DataType> + IndexableSimilarity<<T as Subset>::Out> + Subset,
+    <<T as Subset>::Out as Subset>::Out: IndexableSimilarity<<<T as Subset>::Out as Subset>::Out>,
+{
+    let k = params.eval.k;
+    let split = holdout_split(data.num_points(), params.num_queries, params.seed)?;
+    if k == 0 || split.train.len() < k {
+        return Err(ClusteredIndexError::ConfigError(format!(
+            "k must be between 1 and the {} points left to index, got {}",
+            split.train.len(),
+            k
+        )));
+    }
+
+    let train = data.subset(&split.train);
+    let flat: Vec<T::DataType> = split
+        .queries
+        .iter()
+        .flat_map(|&q| data.get_point(q).into_owned())
+        .collect();
+    let queries = Array2::from_shape_vec((split.queries.len(), data.dimensions()), flat)
+        .map_err(|e| ClusteredIndexError::DataError(e.to_string()))?;
+
+    info!(
+        "Computing the {} nearest neighbors of {} held-out queries among {} points",
+        k,
+        split.queries.len(),
+        split.train.len()
+    );
+    let ground_truth = exact_neighbors(&train, &queries, k);
+
+    let mut index: ClusteredIndex<_> = ClusteredIndex::new(Config { k, ..config }, train)?;
+    let start = Instant::now();
+    index.build()?;
+    let build_time = start.elapsed();
+
+    let report = evaluate(&mut index, &queries, GroundTruth::Ids(&ground_truth), &params.eval)?;
+
+    Ok(HoldoutReport {
+        split,
+        build_time,
+        report,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{evaluate_holdout, holdout_split, HoldoutParams};
+    use crate::core::{Config, IndexMode};
+    use crate::eval::EvalParams;
+    use crate::metricdata::AngularData;
+    use crate::utils::datagen::unit_vectors;
+
+    #[test]
+    fn test_holdout_split() {
+        let split = holdout_split(100, 10, 7).unwrap();
+        assert_eq!(split, holdout_split(100, 10, 7).unwrap());
+        assert_ne!(split, holdout_split(100, 10, 8).unwrap());
+        assert_eq!(split.queries.len(), 10);
+        assert_eq!(split.train.len(), 90);
+
+        let mut all: Vec<usize> = split.queries.iter().chain(&split.train).copied().collect();
+        all.sort_unstable();
+        assert_eq!(all, (0..100).collect::<Vec<_>>());
+
+        assert!(holdout_split(100, 0, 7).is_err());
+        assert!(holdout_split(100, 100, 7).is_err());
+    }
+
+    #[test]
+    fn test_evaluate_holdout_flat_index() {
+        let data = AngularData::new(unit_vectors(500, 8, 1));
+        let config = Config {
+            index_mode: IndexMode::Flat,
+            ..Default::default()
+        };
+        let params = HoldoutParams {
+            num_queries: 20,
+            eval: EvalParams {
+                k: 5,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let outcome = evaluate_holdout(&data, config.clone(), &params).unwrap();
+        assert_eq!(outcome.split.queries.len(), 20);
+        assert_eq!(outcome.report.num_queries, 20);
+        assert_eq!(outcome.report.k, 5);
+        assert!(outcome.report.recall_mean > 0.8);
+
+        let params = HoldoutParams {
+            num_queries: 498,
+            ..params
+        };
+        assert!(evaluate_holdout(&data, config, &params).is_err());
+    }
+}
@@ -0,0 +1,548 @@
+//! Recall and throughput evaluation of an index, without the SQLite metrics.
+//!
+//! [`evaluate`] searches a set of queries one by one and returns an [`EvalReport`] that
+//! library users can inspect directly, e.g. to compare configurations programmatically.
+//! [`evaluate_holdout`] does the same on a dataset without a test set, holding out part of
+//! its points as queries. With [`EvalParams::ood`], queries far from the data are evaluated
+//! next to the given ones and reported apart.
+
+use std::time::{Duration, Instant};
+
+use ndarray::{Array, Array2, ArrayBase, Data, Ix2};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::Serialize;
+
+use crate::core::{ClusterBackend, ClusteredIndex, ClusteredIndexError, MissCounts, QueryMisses, Result};
+use crate::metricdata::{MetricData, Scalar, Subset};
+use crate::puffinn_binds::IndexableSimilarity;
+use crate::topk::TopK;
+use crate::utils::{gaussian, RecallInput, DEFAULT_RECALL_EPSILON};
+
+pub(crate) mod holdout;
+
+pub use holdout::{evaluate_holdout, holdout_split, HoldoutParams, HoldoutReport, HoldoutSplit};
+
+/// True nearest neighbors of the queries, one row per query, closest first.
+#[derive(Debug, Clone, Copy)]
+pub enum GroundTruth<'a> {
+    /// Ids of the true neighbors, recall counts the returned ids among them
+    Ids(&'a Array<usize, Ix2>),
+
+    /// Distances of the true neighbors, recall counts the returned neighbors within the
+    /// k-th true distance (plus a small epsilon)
+    Distances(&'a Array<f32, Ix2>),
+}
+
+impl GroundTruth<'_> {
+    fn dim(&self) -> (usize, usize) {
+        match self {
+            GroundTruth::Ids(a) => a.dim(),
+            GroundTruth::Distances(a) => a.dim(),
+        }
+    }
+}
+
+/// Parameters of an evaluation.
+#[derive(Debug, Clone)]
+pub struct EvalParams {
+    /// Number of nearest neighbors to search, recall is computed at k
+    pub k: usize,
+
+    /// Queries searched before the measured ones, to warm up caches. They are taken from
+    /// the start of the queries and measured again afterwards
+    pub warmup_queries: usize,
+
+    /// Whether to tell why each missed true neighbor was missed, see [`crate::diagnose_misses`].
+    /// The queries are searched again once measured, and the ground truth must be
+    /// [`GroundTruth::Ids`]
+    pub diagnose_misses: bool,
+
+    /// Out-of-distribution queries evaluated after the given ones, reported in
+    /// [`EvalReport::out_of_distribution`]
+    pub ood: Option<OodParams>,
+
+    /// Tolerance of the k-th true distance with [`GroundTruth::Distances`], see
+    /// [`get_recall_values`](crate::utils::get_recall_values)
+    pub recall_epsilon: f32,
+}
+
+impl Default for EvalParams {
+    fn default() -> Self {
+        Self {
+            k: 10,
+            warmup_queries: 0,
+            diagnose_misses: false,
+            ood: None,
+            recall_epsilon: DEFAULT_RECALL_EPSILON,
+        }
+    }
+}
+
+/// How the out-of-distribution queries of [`OodParams`] are made.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OodMode {
+    /// A random query of the set plus Gaussian noise, whose norm is `noise` times the one of
+    /// the query
+    Perturb { noise: f32 },
+
+    /// A random direction, `scale` times as long as the queries on average
+    Random { scale: f32 },
+}
+
+/// Queries far from the data, the ones the clusters can't prune well, see [`EvalParams::ood`].
+#[derive(Debug, Clone)]
+pub struct OodParams {
+    pub num_queries: usize,
+    pub mode: OodMode,
+
+    /// Seed of the queries, fixed so that runs are comparable
+    pub seed: u64,
+}
+
+impl Default for OodParams {
+    fn default() -> Self {
+        Self {
+            num_queries: 100,
+            mode: OodMode::Perturb { noise: 1.0 },
+            seed: 42,
+        }
+    }
+}
+
+/// Outcome of [`evaluate`].
+#[derive(Debug, Clone, Serialize)]
+pub struct EvalReport {
+    pub k: usize,
+    pub num_queries: usize,
+
+    pub recall_mean: f32,
+    pub recall_std: f32,
+
+    /// Recall of each query, in the same order as the queries
+    pub recalls: Vec<f32>,
+
+    pub queries_per_second: f32,
+    pub latency_mean: Duration,
+    pub latency_p50: Duration,
+    pub latency_p95: Duration,
+    pub latency_p99: Duration,
+    pub latency_max: Duration,
+
+    /// Mean number of distance computations per query, centers included
+    pub distance_computations_mean: f32,
+
+    /// Distance computations of each query
+    pub distance_computations: Vec<usize>,
+
+    /// Missed neighbors by reason over all the queries, if [`EvalParams::diagnose_misses`] is set
+    pub miss_counts: Option<MissCounts>,
+
+    /// Missed neighbors of each query, if [`EvalParams::diagnose_misses`] is set
+    pub misses: Option<Vec<QueryMisses>>,
+
+    /// Evaluation of the queries of [`EvalParams::ood`] against their exact neighbors, the
+    /// other fields being the one of the given queries
+    pub out_of_distribution: Option<Box<EvalReport>>,
+}
+
+/// Nearest-rank percentile of sorted `values`
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let rank = ((p / 100.0) * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+/// Searches every row of `queries` and measures recall, throughput, latency and distance computations.
+///
+/// Queries are searched one at a time with [`crate::search`], so the query cache and the
+/// duplicate detection of [`crate::search_batch`] don't affect the numbers. The `k` of the
+/// index is restored once the evaluation is complete.
+///
+/// # Parameters
+/// - `index`: Built index to evaluate
+/// - `queries`: One query per row
+/// - `ground_truth`: True nearest neighbors, one row per query with at least `k` columns
+/// - `params`: Number of neighbors and warm-up queries
+///
+/// # Returns
+/// An [`EvalReport`] with aggregated and per-query numbers
+///
+/// # Errors
+/// - `ClusteredIndexError::ConfigError` if `k` is zero or larger than the ground truth rows, or
+///   if misses are diagnosed without the ids of the ground truth
+/// - `ClusteredIndexError::DataError` if the number of queries and of ground truth rows differ,
+///   or if out-of-distribution queries are asked without queries to derive them from
+/// - Any error returned by [`crate::search`]
+///
+/// # Example
+/// ```no_run
+/// use clann::eval::{evaluate, EvalParams, GroundTruth};
+/// use clann::utils::load_hdf5_dataset;
+/// use clann::{build, init, metricdata::AngularData};
+///
+/// let dataset = load_hdf5_dataset("./datasets/glove-25-angular.hdf5").unwrap();
+/// let mut index = init(AngularData::new(dataset.dataset_array)).unwrap();
+/// build(&mut index).unwrap();
+///
+/// let neighbors = dataset.ground_truth_neighbors.unwrap();
+/// let report = evaluate(
+///     &mut index,
+///     &dataset.dataset_queries,
+///     GroundTruth::Ids(&neighbors),
+///     &EvalParams::default(),
+/// )
+/// .unwrap();
+/// println!("recall {:.3} at {:.0} QPS", report.recall_mean, report.queries_per_second);
+/// ```
+pub fn evaluate<T, B, S>(
+    index: &mut ClusteredIndex<T, B>,
+    queries: &ArrayBase<S, Ix2>,
+    ground_truth: GroundTruth,
+    params: &EvalParams,
+) -> Result<EvalReport>
+where
+    T: MetricData + IndexableSimilarity<T> + Subset,
+    <T as Subset>::Out: IndexableSimilarity<<T as Subset>::Out>,
+    B: ClusterBackend<T>,
+    S: Data<Elem = T::DataType>,
+{
+    let (truth_rows, truth_cols) = ground_truth.dim();
+    if params.k == 0 || params.k > truth_cols {
+        return Err(ClusteredIndexError::ConfigError(format!(
+            "k must be between 1 and the {} neighbors of the ground truth, got {}",
+            truth_cols, params.k
+        )));
+    }
+    if truth_rows != queries.nrows() {
+        return Err(ClusteredIndexError::DataError(format!(
+            "{} queries but {} ground truth rows",
+            queries.nrows(),
+            truth_rows
+        )));
+    }
+
+    let truth_ids = match ground_truth {
+        GroundTruth::Ids(ids) => Some(ids),
+        GroundTruth::Distances(_) => None,
+    };
+    if params.diagnose_misses && truth_ids.is_none() {
+        return Err(ClusteredIndexError::ConfigError(
+            "diagnosing misses needs the ids of the true neighbors".to_string(),
+        ));
+    }
+
+    let previous_k = index.config().k;
+    index.set_k(params.k)?;
+    let results = run_queries(index, queries, params.warmup_queries).and_then(|runs| {
+        let misses = match truth_ids.filter(|_| params.diagnose_misses) {
+            Some(ids) => Some(index.diagnose_misses(queries, ids)?),
+            None => None,
+        };
+        Ok((runs, misses))
+    });
+    index.set_k(previous_k)?;
+    let ((results, latencies, distance_computations), misses) = results?;
+
+    let (recall_mean, recall_std, found) = match ground_truth {
+        GroundTruth::Ids(ground_truth) => {
+            let run: Vec<Vec<usize>> = results
+                .iter()
+                .map(|r| r.iter().map(|&(_, p)| p).collect())
+                .collect();
+            RecallInput::Ids {
+                ground_truth,
+                run: &run,
+            }
+            .recall_values(params.k)
+        }
+        GroundTruth::Distances(ground_truth) => {
+            let run: Vec<Vec<f32>> = results
+                .iter()
+                .map(|r| r.iter().map(|&(d, _)| d).collect())
+                .collect();
+            RecallInput::Distances {
+                ground_truth,
+                run: &run,
+                epsilon: params.recall_epsilon,
+            }
+            .recall_values(params.k)
+        }
+    };
+
+    let out_of_distribution = match &params.ood {
+        Some(ood) => {
+            let ood_queries = ood_queries(queries, ood)?;
+            let ground_truth = exact_neighbors(index.data(), &ood_queries, params.k);
+            let params = EvalParams {
+                warmup_queries: 0,
+                ood: None,
+                ..params.clone()
+            };
+            let report = evaluate(index, &ood_queries, GroundTruth::Ids(&ground_truth), &params)?;
+            Some(Box::new(report))
+        }
+        None => None,
+    };
+
+    let num_queries = latencies.len();
+    let total: Duration = latencies.iter().sum();
+    let mut sorted = latencies;
+    sorted.sort();
+
+    Ok(EvalReport {
+        k: params.k,
+        num_queries,
+        recall_mean,
+        recall_std,
+        recalls: found.iter().map(|&f| f / params.k as f32).collect(),
+        queries_per_second: if total.is_zero() {
+            0.0
+        } else {
+            num_queries as f32 / total.as_secs_f32()
+        },
+        latency_mean: total.checked_div(num_queries.max(1) as u32).unwrap_or_default(),
+        latency_p50: percentile(&sorted, 50.0),
+        latency_p95: percentile(&sorted, 95.0),
+        latency_p99: percentile(&sorted, 99.0),
+        latency_max: sorted.last().copied().unwrap_or_default(),
+        distance_computations_mean: distance_computations.iter().sum::<usize>() as f32
+            / num_queries.max(1) as f32,
+        distance_computations,
+        miss_counts: misses.as_deref().map(MissCounts::total),
+        misses,
+        out_of_distribution,
+    })
+}
+
+/// Out-of-distribution queries made from `queries` as told by `params`
+fn ood_queries<S>(queries: &ArrayBase<S, Ix2>, params: &OodParams) -> Result<Array2<S::Elem>>
+where
+    S: Data,
+    S::Elem: Scalar,
+{
+    if queries.nrows() == 0 {
+        return Err(ClusteredIndexError::DataError(
+            "out-of-distribution queries are derived from the queries, none were given".to_string(),
+        ));
+    }
+
+    let norm = |row: &[f64]| row.iter().map(|x| x * x).sum::<f64>().sqrt();
+    let rows: Vec<Vec<f64>> = queries
+        .rows()
+        .into_iter()
+        .map(|row| row.iter().map(|&x| x.into()).collect())
+        .collect();
+    let mean_norm = rows.iter().map(|row| norm(row)).sum::<f64>() / rows.len() as f64;
+
+    let mut rng = StdRng::seed_from_u64(params.seed);
+    let dimensions = queries.ncols();
+    let mut ood = Vec::with_capacity(params.num_queries * dimensions);
+    for _ in 0..params.num_queries {
+        let direction: Vec<f64> = (0..dimensions).map(|_| gaussian(&mut rng) as f64).collect();
+        let unit = norm(&direction).max(f64::EPSILON);
+        match params.mode {
+            OodMode::Perturb { noise } => {
+                let query = &rows[rng.gen_range(0..rows.len())];
+                let length = noise as f64 * norm(query) / unit;
+                ood.extend(query.iter().zip(&direction).map(|(&q, &d)| S::Elem::from_f64(q + length * d)));
+            }
+            OodMode::Random { scale } => {
+                let length = scale as f64 * mean_norm / unit;
+                ood.extend(direction.iter().map(|&d| S::Elem::from_f64(length * d)));
+            }
+        }
+    }
+
+    Array2::from_shape_vec((params.num_queries, dimensions), ood)
+        .map_err(|e| ClusteredIndexError::DataError(e.to_string()))
+}
+
+/// Ids of the `k` nearest points of `data` to every row of `queries`, found by scanning all of them
+pub(crate) fn exact_neighbors<T>(data: &T, queries: &Array2<T::DataType>, k: usize) -> Array2<usize>
+where
+    T: MetricData,
+{
+    let mut neighbors = Array2::zeros((queries.nrows(), k));
+    for (mut row, query) in neighbors.rows_mut().into_iter().zip(queries.rows()) {
+        let query = query.as_slice().expect("rows of an owned array are contiguous");
+        let mut top = TopK::new(k);
+        for p in 0..data.num_points() {
+            top.push(data.distance_point(p, query), p);
+        }
+        for (r, (_, p)) in row.iter_mut().zip(top.into_sorted_vec()) {
+            *r = p;
+        }
+    }
+    neighbors
+}
+
+/// Results, latencies and distance computations of each query
+type QueryRuns = (Vec<Vec<(f32, usize)>>, Vec<Duration>, Vec<usize>);
+
+fn run_queries<T, B, S>(
+    index: &mut ClusteredIndex<T, B>,
+    queries: &ArrayBase<S, Ix2>,
+    warmup_queries: usize,
+) -> Result<QueryRuns>
+where
+    T: MetricData + IndexableSimilarity<T> + Subset,
+    <T as Subset>::Out: IndexableSimilarity<<T as Subset>::Out>,
+    B: ClusterBackend<T>,
+    S: Data<Elem = T::DataType>,
+{
+    for query in queries.rows().into_iter().take(warmup_queries) {
+        index.search(&query.to_vec())?;
+    }
+
+    let mut results = Vec::with_capacity(queries.nrows());
+    let mut latencies = Vec::with_capacity(queries.nrows());
+    let mut distance_computations = Vec::with_capacity(queries.nrows());
+    for query in queries.rows() {
+        // rows of a non-standard layout are not contiguous, copy before timing
+        let query = query.to_vec();
+        let start = Instant::now();
+        let result = index.search(&query)?;
+        latencies.push(start.elapsed());
+        distance_computations.push(index.last_distance_computations());
+        results.push(result);
+    }
+
+    Ok((results, latencies, distance_computations))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use ndarray::Array2;
+
+    use super::{evaluate, ood_queries, percentile, EvalParams, GroundTruth, OodMode, OodParams};
+    use crate::core::{ClusteredIndex, Config, IndexMode};
+    use crate::metricdata::AngularData;
+    use crate::utils::datagen::GaussianMixture;
+    use crate::utils::{brute_force_search, datagen::unit_vectors};
+
+    #[test]
+    fn test_evaluate_flat_index() {
+        let data = AngularData::new(unit_vectors(1000, 8, 1));
+        let queries = unit_vectors(20, 8, 2);
+        let config = Config {
+            index_mode: IndexMode::Flat,
+            k: 3,
+            ..Default::default()
+        };
+
+        let mut ground_truth = Array2::zeros((20, 10));
+        for (i, query) in queries.rows().into_iter().enumerate() {
+            let ids = brute_force_search(&data, query.as_slice().unwrap(), 10);
+            for (j, id) in ids.into_iter().enumerate() {
+                ground_truth[[i, j]] = id as usize;
+            }
+        }
+
+        let mut index: ClusteredIndex<_> = ClusteredIndex::new(config, data).unwrap();
+        index.build().unwrap();
+
+        let params = EvalParams {
+            k: 10,
+            warmup_queries: 2,
+            diagnose_misses: true,
+            ood: None,
+            ..Default::default()
+        };
+        let report = evaluate(&mut index, &queries, GroundTruth::Ids(&ground_truth), &params).unwrap();
+
+        assert_eq!(report.num_queries, 20);
+        assert_eq!(report.recalls.len(), 20);
+        assert!(report.recall_mean > 0.8);
+        assert!(report.latency_p50 <= report.latency_p99);
+        assert!(report.latency_p99 <= report.latency_max);
+        // at least the distance to the closest center, far ones are skipped without computing it
+        assert!(report.distance_computations.iter().all(|&d| d > 0));
+        assert_eq!(index.config().k, 3);
+
+        // every neighbor not found is explained
+        let misses = report.misses.unwrap();
+        assert_eq!(misses.len(), 20);
+        for (recall, query) in report.recalls.iter().zip(&misses) {
+            assert_eq!(query.misses.len(), 10 - (recall * 10.0).round() as usize);
+        }
+        let counts = report.miss_counts.unwrap();
+        assert_eq!(counts.pruned + counts.unvisited + counts.not_returned, misses.iter().map(|q| q.misses.len()).sum::<usize>());
+
+        let params = EvalParams {
+            k: 3,
+            diagnose_misses: true,
+            ..Default::default()
+        };
+        let distances = Array2::zeros((20, 10));
+        assert!(evaluate(&mut index, &queries, GroundTruth::Distances(&distances), &params).is_err());
+
+        let params = EvalParams {
+            k: 11,
+            ..Default::default()
+        };
+        assert!(evaluate(&mut index, &queries, GroundTruth::Ids(&ground_truth), &params).is_err());
+    }
+
+    #[test]
+    fn test_out_of_distribution_queries() {
+        let dataset = GaussianMixture {
+            num_points: 2000,
+            num_queries: 20,
+            dimensions: 8,
+            num_clusters: 10,
+            spread: 0.05,
+            ..Default::default()
+        }
+        .generate();
+        let config = Config {
+            index_mode: IndexMode::Flat,
+            num_clusters_factor: 0.5,
+            ..Default::default()
+        };
+        let mut index: ClusteredIndex<_> = ClusteredIndex::new(config, AngularData::new(dataset.train)).unwrap();
+        index.build().unwrap();
+
+        let ood = OodParams {
+            num_queries: 10,
+            mode: OodMode::Random { scale: 1.0 },
+            seed: 3,
+        };
+        assert_eq!(ood_queries(&dataset.queries, &ood).unwrap(), ood_queries(&dataset.queries, &ood).unwrap());
+        let unperturbed = OodParams {
+            mode: OodMode::Perturb { noise: 0.0 },
+            ..ood.clone()
+        };
+        for row in ood_queries(&dataset.queries, &unperturbed).unwrap().rows() {
+            assert!(dataset.queries.rows().into_iter().any(|q| q == row));
+        }
+
+        let params = EvalParams {
+            ood: Some(ood),
+            ..Default::default()
+        };
+        let report = evaluate(&mut index, &dataset.queries, GroundTruth::Ids(&dataset.neighbors), &params).unwrap();
+        assert_eq!(report.num_queries, 20);
+        let ood_report = report.out_of_distribution.unwrap();
+        assert_eq!(ood_report.num_queries, 10);
+        assert!(ood_report.out_of_distribution.is_none());
+        // random directions fall between the clusters and are pruned worse
+        assert!(ood_report.distance_computations_mean > report.distance_computations_mean);
+
+        let empty = Array2::<f32>::zeros((0, 8));
+        assert!(ood_queries(&empty, &OodParams::default()).is_err());
+    }
+
+    #[test]
+    fn test_percentile() {
+        let sorted: Vec<Duration> = (1..=100).map(Duration::from_millis).collect();
+        assert_eq!(percentile(&sorted, 50.0), Duration::from_millis(50));
+        assert_eq!(percentile(&sorted, 99.0), Duration::from_millis(99));
+        assert_eq!(percentile(&sorted, 0.0), Duration::from_millis(1));
+        assert_eq!(percentile(&[], 50.0), Duration::ZERO);
+    }
+}
@@ -0,0 +1,69 @@
+//! The functions of the CUDA driver API and of NVRTC used by the reranker.
+#![allow(non_camel_case_types)]
+
+use std::ffi::{c_char, c_int, c_uint, c_void};
+
+pub(super) type CUresult = c_int;
+pub(super) type CUdevice = c_int;
+pub(super) type CUdeviceptr = u64;
+pub(super) type CUcontext = *mut c_void;
+pub(super) type CUmodule = *mut c_void;
+pub(super) type CUfunction = *mut c_void;
+pub(super) type CUstream = *mut c_void;
+
+pub(super) const CUDA_SUCCESS: CUresult = 0;
+
+pub(super) type nvrtcResult = c_int;
+pub(super) type nvrtcProgram = *mut c_void;
+
+pub(super) const NVRTC_SUCCESS: nvrtcResult = 0;
+
+#[link(name = "cuda")]
+unsafe extern "C" {
+    pub(super) fn cuInit(flags: c_uint) -> CUresult;
+    pub(super) fn cuDeviceGet(device: *mut CUdevice, ordinal: c_int) -> CUresult;
+    pub(super) fn cuCtxCreate_v2(context: *mut CUcontext, flags: c_uint, device: CUdevice) -> CUresult;
+    pub(super) fn cuCtxDestroy_v2(context: CUcontext) -> CUresult;
+    pub(super) fn cuCtxSetCurrent(context: CUcontext) -> CUresult;
+    pub(super) fn cuModuleLoadData(module: *mut CUmodule, image: *const c_void) -> CUresult;
+    pub(super) fn cuModuleUnload(module: CUmodule) -> CUresult;
+    pub(super) fn cuModuleGetFunction(function: *mut CUfunction, module: CUmodule, name: *const c_char) -> CUresult;
+    pub(super) fn cuMemAlloc_v2(ptr: *mut CUdeviceptr, bytes: usize) -> CUresult;
+    pub(super) fn cuMemFree_v2(ptr: CUdeviceptr) -> CUresult;
+    pub(super) fn cuMemcpyHtoD_v2(dst: CUdeviceptr, src: *const c_void, bytes: usize) -> CUresult;
+    pub(super) fn cuMemcpyDtoH_v2(dst: *mut c_void, src: CUdeviceptr, bytes: usize) -> CUresult;
+    #[allow(clippy::too_many_arguments)]
+    pub(super) fn cuLaunchKernel(
+        function: CUfunction,
+        grid_x: c_uint,
+        grid_y: c_uint,
+        grid_z: c_uint,
+        block_x: c_uint,
+        block_y: c_uint,
+        block_z: c_uint,
+        shared_memory_bytes: c_uint,
+        stream: CUstream,
+        params: *mut *mut c_void,
+        extra: *mut *mut c_void,
+    ) -> CUresult;
+    pub(super) fn cuGetErrorString(result: CUresult, message: *mut *const c_char) -> CUresult;
+}
+
+#[link(name = "nvrtc")]
+unsafe extern "C" {
+    pub(super) fn nvrtcCreateProgram(
+        program: *mut nvrtcProgram,
+        source: *const c_char,
+        name: *const c_char,
+        num_headers: c_int,
+        headers: *const *const c_char,
+        include_names: *const *const c_char,
+    ) -> nvrtcResult;
+    pub(super) fn nvrtcCompileProgram(program: nvrtcProgram, num_options: c_int, options: *const *const c_char) -> nvrtcResult;
+    pub(super) fn nvrtcGetPTXSize(program: nvrtcProgram, size: *mut usize) -> nvrtcResult;
+    pub(super) fn nvrtcGetPTX(program: nvrtcProgram, ptx: *mut c_char) -> nvrtcResult;
+    pub(super) fn nvrtcGetProgramLogSize(program: nvrtcProgram, size: *mut usize) -> nvrtcResult;
+    pub(super) fn nvrtcGetProgramLog(program: nvrtcProgram, log: *mut c_char) -> nvrtcResult;
+    pub(super) fn nvrtcDestroyProgram(program: *mut nvrtcProgram) -> nvrtcResult;
+    pub(super) fn nvrtcGetErrorString(result: nvrtcResult) -> *const c_char;
+}
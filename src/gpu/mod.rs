@@ -0,0 +1,407 @@
+//! Reranking of the candidates of a search on an NVIDIA GPU, with the `cuda` feature.
+//!
+//! The dataset is copied to the GPU once, then every batch of candidates only transfers their
+//! ids and the query. The kernel is compiled at runtime with NVRTC, so only the CUDA driver
+//! and NVRTC libraries are needed, not the CUDA toolkit.
+//!
+//! # Example
+//! ```no_run
+//! use clann::{build, init, search, metricdata::AngularData};
+//! use clann::gpu::CudaReranker;
+//!
+//! let dataset = ndarray::Array2::<f32>::zeros((100000, 768));
+//! let reranker = CudaReranker::angular(dataset.view()).unwrap();
+//! let mut index = init(AngularData::new(dataset)).unwrap();
+//! build(&mut index).unwrap();
+//! index.set_reranker(reranker);
+//!
+//! let query = vec![0.0; 768];
+//! let neighbors = search(&mut index, &query).unwrap();
+//! ```
+
+mod ffi;
+
+use std::ffi::{c_void, CStr, CString};
+use std::ptr;
+use std::sync::Mutex;
+
+use ndarray::{ArrayBase, Data, Ix2};
+
+use crate::core::{ClusteredIndexError, Reranker, Result};
+use crate::metricdata::simd;
+
+use self::ffi::*;
+
+const KERNEL: &str = r#"
+extern "C" __global__ void rerank(const float* data, const float* norms,
+                                  const unsigned long long* ids, const float* query,
+                                  float query_norm, int dims, int n, int euclidean, float* out) {
+    // one warp per candidate, the lanes sum strided parts of the dimensions
+    int warp = (blockIdx.x * blockDim.x + threadIdx.x) / 32;
+    int lane = threadIdx.x % 32;
+    if (warp >= n) return;
+
+    const float* row = data + ids[warp] * (unsigned long long) dims;
+    float acc = 0.0f;
+    for (int d = lane; d < dims; d += 32) {
+        float x = row[d];
+        float q = query[d];
+        acc += euclidean ? (x - q) * (x - q) : x * q;
+    }
+    for (int offset = 16; offset > 0; offset /= 2) {
+        acc += __shfl_down_sync(0xffffffff, acc, offset);
+    }
+    if (lane == 0) {
+        out[warp] = euclidean ? sqrtf(acc) : 1.0f - acc / (norms[ids[warp]] * query_norm);
+    }
+}
+"#;
+
+const THREADS_PER_BLOCK: u32 = 256;
+
+/// Candidates below which the CPU is faster, the transfers and the launch take tens of microseconds
+const DEFAULT_MIN_BATCH: usize = 512;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Metric {
+    Angular,
+    Euclidean,
+}
+
+/// Device buffer
+struct DeviceBuffer {
+    ptr: CUdeviceptr,
+    bytes: usize,
+}
+
+impl DeviceBuffer {
+    fn new(bytes: usize) -> Result<Self> {
+        let mut ptr = 0;
+        // cuMemAlloc fails on zero bytes
+        check(unsafe { cuMemAlloc_v2(&mut ptr, bytes.max(1)) }, "cuMemAlloc")?;
+        Ok(Self { ptr, bytes })
+    }
+
+    fn upload<E: Copy>(&self, values: &[E]) -> Result<()> {
+        let bytes = std::mem::size_of_val(values);
+        assert!(bytes <= self.bytes);
+        check(
+            unsafe { cuMemcpyHtoD_v2(self.ptr, values.as_ptr() as *const c_void, bytes) },
+            "cuMemcpyHtoD",
+        )
+    }
+}
+
+impl Drop for DeviceBuffer {
+    fn drop(&mut self) {
+        if self.ptr != 0 {
+            unsafe {
+                cuMemFree_v2(self.ptr);
+            }
+        }
+    }
+}
+
+/// Buffers of a batch, grown to the largest batch
+struct Batch {
+    ids: DeviceBuffer,
+    query: DeviceBuffer,
+    out: DeviceBuffer,
+    capacity: usize,
+}
+
+/// [`Reranker`] computing the distances of the candidates on the GPU.
+///
+/// It holds a copy of the dataset made at creation: points inserted afterwards are not on the
+/// GPU, and a batch with one of them fails with `ClusteredIndexError::RerankError`. Create the
+/// reranker again after insertions.
+pub struct CudaReranker {
+    context: CUcontext,
+    module: CUmodule,
+    kernel: CUfunction,
+    metric: Metric,
+    num_points: usize,
+    dims: usize,
+    // kept alive for the kernel
+    data: DeviceBuffer,
+    norms: DeviceBuffer,
+    batch: Mutex<Batch>,
+    min_batch: usize,
+}
+
+// SAFETY: the context is made current on the calling thread before every use, and the
+// buffers of the batches are behind a mutex
+unsafe impl Send for CudaReranker {}
+unsafe impl Sync for CudaReranker {}
+
+impl CudaReranker {
+    /// Reranker for [`AngularData`](crate::metricdata::AngularData) on `data`, on the first GPU.
+    ///
+    /// # Errors
+    /// `ClusteredIndexError::RerankError` if there is no GPU, the kernel doesn't compile, or
+    /// the dataset doesn't fit in the memory of the GPU
+    pub fn angular<S: Data<Elem = f32>>(data: ArrayBase<S, Ix2>) -> Result<Self> {
+        Self::new(data, Metric::Angular)
+    }
+
+    /// Reranker for [`EuclideanData`](crate::metricdata::EuclideanData) on `data`, on the first GPU.
+    ///
+    /// # Errors
+    /// Same as [`angular`](Self::angular)
+    pub fn euclidean<S: Data<Elem = f32>>(data: ArrayBase<S, Ix2>) -> Result<Self> {
+        Self::new(data, Metric::Euclidean)
+    }
+
+    /// Smallest batch computed on the GPU, smaller ones are computed on the CPU.
+    pub fn with_min_batch(mut self, min_batch: usize) -> Self {
+        self.min_batch = min_batch;
+        self
+    }
+
+    fn new<S: Data<Elem = f32>>(data: ArrayBase<S, Ix2>, metric: Metric) -> Result<Self> {
+        let (num_points, dims) = data.dim();
+        let rows = data.as_standard_layout();
+        let rows = rows.as_slice().expect("standard layout");
+        let norms: Vec<f32> = rows
+            .chunks_exact(dims.max(1))
+            .map(|row| simd::dot(row, row).sqrt())
+            .collect();
+
+        let mut context = ptr::null_mut();
+        unsafe {
+            check(cuInit(0), "cuInit")?;
+            let mut device = 0;
+            check(cuDeviceGet(&mut device, 0), "cuDeviceGet")?;
+            check(cuCtxCreate_v2(&mut context, 0, device), "cuCtxCreate")?;
+        }
+
+        // the context is destroyed if anything below fails
+        let module = compile_kernel(context)?;
+        let mut kernel = ptr::null_mut();
+        let name = CString::new("rerank").unwrap();
+        check(unsafe { cuModuleGetFunction(&mut kernel, module, name.as_ptr()) }, "cuModuleGetFunction")
+            .inspect_err(|_| unsafe {
+                cuModuleUnload(module);
+                cuCtxDestroy_v2(context);
+            })?;
+
+        let buffers = (|| {
+            let device_data = DeviceBuffer::new(std::mem::size_of_val(rows))?;
+            device_data.upload(rows)?;
+            let device_norms = DeviceBuffer::new(std::mem::size_of_val(&norms[..]))?;
+            device_norms.upload(&norms)?;
+            let batch = Batch {
+                ids: DeviceBuffer::new(0)?,
+                query: DeviceBuffer::new(dims * std::mem::size_of::<f32>())?,
+                out: DeviceBuffer::new(0)?,
+                capacity: 0,
+            };
+            Ok((device_data, device_norms, batch))
+        })();
+        let (data, norms, batch) = buffers.inspect_err(|_| unsafe {
+            cuModuleUnload(module);
+            cuCtxDestroy_v2(context);
+        })?;
+
+        Ok(Self {
+            context,
+            module,
+            kernel,
+            metric,
+            num_points,
+            dims,
+            data,
+            norms,
+            batch: Mutex::new(batch),
+            min_batch: DEFAULT_MIN_BATCH,
+        })
+    }
+}
+
+impl Reranker<f32> for CudaReranker {
+    fn distances(&self, ids: &[usize], query: &[f32], out: &mut [f32]) -> Result<()> {
+        assert_eq!(ids.len(), out.len());
+        if query.len() != self.dims {
+            return Err(ClusteredIndexError::RerankError(format!(
+                "the query has {} dimensions, the dataset on the GPU {}",
+                query.len(),
+                self.dims
+            )));
+        }
+        if let Some(&id) = ids.iter().find(|&&id| id >= self.num_points) {
+            return Err(ClusteredIndexError::RerankError(format!(
+                "point {} is not on the GPU, which has the first {} points: create the reranker again after insertions",
+                id, self.num_points
+            )));
+        }
+        if ids.is_empty() {
+            return Ok(());
+        }
+
+        let mut batch = self
+            .batch
+            .lock()
+            .map_err(|e| ClusteredIndexError::PoisonedLock(e.to_string()))?;
+        check(unsafe { cuCtxSetCurrent(self.context) }, "cuCtxSetCurrent")?;
+
+        if batch.capacity < ids.len() {
+            let capacity = ids.len().next_power_of_two();
+            batch.ids = DeviceBuffer::new(capacity * std::mem::size_of::<u64>())?;
+            batch.out = DeviceBuffer::new(capacity * std::mem::size_of::<f32>())?;
+            batch.capacity = capacity;
+        }
+        let device_ids: Vec<u64> = ids.iter().map(|&id| id as u64).collect();
+        batch.ids.upload(&device_ids)?;
+        batch.query.upload(query)?;
+
+        let query_norm = simd::dot(query, query).sqrt();
+        let dims = self.dims as i32;
+        let n = ids.len() as i32;
+        let euclidean = (self.metric == Metric::Euclidean) as i32;
+        let mut params: [*mut c_void; 9] = [
+            &self.data.ptr as *const _ as *mut c_void,
+            &self.norms.ptr as *const _ as *mut c_void,
+            &batch.ids.ptr as *const _ as *mut c_void,
+            &batch.query.ptr as *const _ as *mut c_void,
+            &query_norm as *const _ as *mut c_void,
+            &dims as *const _ as *mut c_void,
+            &n as *const _ as *mut c_void,
+            &euclidean as *const _ as *mut c_void,
+            &batch.out.ptr as *const _ as *mut c_void,
+        ];
+        let warps_per_block = THREADS_PER_BLOCK as usize / 32;
+        let blocks = ids.len().div_ceil(warps_per_block) as u32;
+        unsafe {
+            check(
+                cuLaunchKernel(
+                    self.kernel,
+                    blocks,
+                    1,
+                    1,
+                    THREADS_PER_BLOCK,
+                    1,
+                    1,
+                    0,
+                    ptr::null_mut(),
+                    params.as_mut_ptr(),
+                    ptr::null_mut(),
+                ),
+                "cuLaunchKernel",
+            )?;
+            // synchronous copy, it waits for the kernel
+            check(
+                cuMemcpyDtoH_v2(
+                    out.as_mut_ptr() as *mut c_void,
+                    batch.out.ptr,
+                    std::mem::size_of_val(out),
+                ),
+                "cuMemcpyDtoH",
+            )
+        }
+    }
+
+    fn min_batch(&self) -> usize {
+        self.min_batch
+    }
+}
+
+impl Drop for CudaReranker {
+    fn drop(&mut self) {
+        unsafe {
+            cuCtxSetCurrent(self.context);
+        }
+        // the buffers are freed before the context, by hand as fields drop after `drop`
+        let empty = || DeviceBuffer { ptr: 0, bytes: 0 };
+        drop(std::mem::replace(&mut self.data, empty()));
+        drop(std::mem::replace(&mut self.norms, empty()));
+        if let Ok(batch) = self.batch.get_mut() {
+            drop(std::mem::replace(&mut batch.ids, empty()));
+            drop(std::mem::replace(&mut batch.query, empty()));
+            drop(std::mem::replace(&mut batch.out, empty()));
+        }
+        unsafe {
+            cuModuleUnload(self.module);
+            cuCtxDestroy_v2(self.context);
+        }
+    }
+}
+
+/// Compiles the kernel to PTX with NVRTC and loads it in `context`, which is destroyed on error
+fn compile_kernel(context: CUcontext) -> Result<CUmodule> {
+    let compiled = unsafe { compile_ptx() }.and_then(|ptx| {
+        let mut module = ptr::null_mut();
+        check(
+            unsafe { cuModuleLoadData(&mut module, ptx.as_ptr() as *const c_void) },
+            "cuModuleLoadData",
+        )?;
+        Ok(module)
+    });
+    if compiled.is_err() {
+        unsafe {
+            cuCtxDestroy_v2(context);
+        }
+    }
+    compiled
+}
+
+unsafe fn compile_ptx() -> Result<CString> {
+    let source = CString::new(KERNEL).unwrap();
+    let name = CString::new("rerank.cu").unwrap();
+    let mut program = ptr::null_mut();
+    nvrtc_check(
+        nvrtcCreateProgram(&mut program, source.as_ptr(), name.as_ptr(), 0, ptr::null(), ptr::null()),
+        "nvrtcCreateProgram",
+    )?;
+
+    let options = [CString::new("--use_fast_math").unwrap()];
+    let options: Vec<_> = options.iter().map(|o| o.as_ptr()).collect();
+    let compiled = nvrtc_check(
+        nvrtcCompileProgram(program, options.len() as i32, options.as_ptr()),
+        "nvrtcCompileProgram",
+    )
+    .map_err(|e| {
+        let mut size = 0;
+        nvrtcGetProgramLogSize(program, &mut size);
+        let mut log = vec![0u8; size.max(1)];
+        nvrtcGetProgramLog(program, log.as_mut_ptr() as *mut _);
+        let log = CStr::from_bytes_until_nul(&log).map(|l| l.to_string_lossy().into_owned()).unwrap_or_default();
+        ClusteredIndexError::RerankError(format!("{}: {}", e, log))
+    })
+    .and_then(|_| {
+        let mut size = 0;
+        nvrtc_check(nvrtcGetPTXSize(program, &mut size), "nvrtcGetPTXSize")?;
+        let mut ptx = vec![0u8; size];
+        nvrtc_check(nvrtcGetPTX(program, ptx.as_mut_ptr() as *mut _), "nvrtcGetPTX")?;
+        CString::from_vec_with_nul(ptx)
+            .map_err(|e| ClusteredIndexError::RerankError(format!("invalid PTX: {}", e)))
+    });
+    nvrtcDestroyProgram(&mut program);
+    compiled
+}
+
+fn check(result: CUresult, call: &str) -> Result<()> {
+    if result == CUDA_SUCCESS {
+        return Ok(());
+    }
+    let mut message = ptr::null();
+    let message = unsafe {
+        if cuGetErrorString(result, &mut message) == CUDA_SUCCESS && !message.is_null() {
+            CStr::from_ptr(message).to_string_lossy().into_owned()
+        } else {
+            format!("error {}", result)
+        }
+    };
+    Err(ClusteredIndexError::RerankError(format!("{} failed: {}", call, message)))
+}
+
+fn nvrtc_check(result: nvrtcResult, call: &str) -> Result<()> {
+    if result == NVRTC_SUCCESS {
+        return Ok(());
+    }
+    let message = unsafe { CStr::from_ptr(nvrtcGetErrorString(result)) };
+    Err(ClusteredIndexError::RerankError(format!(
+        "{} failed: {}",
+        call,
+        message.to_string_lossy()
+    )))
+}
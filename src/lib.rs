@@ -4,16 +4,36 @@
 //! This approach, even though requires more memory and index building time, effectively cuts the hit distribution for the LSH function, ensuring that points that are far apart cannot collide. In classic LSH scenarios, it has been observed long tails of hits, due to the probabilistic nature of the function. Even though far points have low probability of colliding it was still not null, and the problem accentuated with queries far away from the dataset, where it approximates to a brute-force approach.
 //!
 
-use core::{config::MetricsGranularity, index::ClusteredIndex, Config, Result};
+use core::{
+    config::MetricsGranularity, index::ClusteredIndex, BuildEstimate, ClusterBackend, Config, IndexStats, KnnGraph, NearestCluster,
+    Aggregation, Partition, PartitionArtifact, QueryHardness, QueryMisses, RepartitionPolicy, Result, SearchParams, SearchPlan,
+};
 use std::time::Duration;
 
-use metricdata::{MetricData, Subset};
-use ndarray::{Array, Ix2};
+use metricdata::{Insertable, MetricData, MmapRows, OutOfCoreData, Subset};
+use ndarray::{Array, Array2, ArrayBase, Data, Ix2};
 use puffinn_binds::IndexableSimilarity;
+use utils::RecallInput;
 
+pub mod annbench;
+pub mod bench;
 pub mod core;
+pub mod eval;
+#[cfg(feature = "cuda")]
+pub mod gpu;
+#[cfg(feature = "rust-lsh")]
+pub mod lsh;
 pub mod metricdata;
 pub mod puffinn_binds;
+#[cfg(feature = "python")]
+mod python;
+#[cfg(feature = "report")]
+pub mod report;
+#[cfg(feature = "serve")]
+pub mod serve;
+pub mod topk;
+pub mod transform;
+pub mod tune;
 pub mod utils;
 
 /// Initializes a CLANN index from a previously serialized file.
@@ -23,7 +43,9 @@ pub mod utils;
 /// - `file_path`: Path to the HDF5 file containing the serialized index
 ///
 /// # Returns
-/// A `ClusteredIndex` instance loaded from the file, ready to be used for searching
+/// A `ClusteredIndex` instance loaded from the file, ready to be used for searching. Clusters
+/// whose PUFFINN index is missing or corrupt are rebuilt from `data`, see
+/// [`ClusteredIndex::rebuilt_clusters`]
 ///
 /// # Errors
 /// Returns `ClusteredIndexError::ConfigError` if:
@@ -31,6 +53,9 @@ pub mod utils;
 /// - The file format is invalid
 /// - The serialized data is corrupted or incompatible
 ///
+/// Returns `ClusteredIndexError::DeserializeError` if the index of a cluster can't be read
+/// nor rebuilt
+///
 /// # Example
 /// ```no_run
 /// use clann::{init_from_file, metricdata::AngularData};
@@ -46,6 +71,132 @@ where
     ClusteredIndex::new_from_file(data, file_path)
 }
 
+/// Initializes a CLANN index from a binary file, through a read-only memory map.
+///
+/// The members of the clusters are read in place from the file, and shared with every
+/// process loading it. The PUFFINN hash tables of the clusters are not: each process decodes
+/// its own copy of them, the bulk of the index, so loading the same file in several processes
+/// doesn't share the memory of the tables. See [`init_from_mmap_with_backend()`] for a backend
+/// reading them in place.
+///
+/// # Parameters
+/// - `data`: Dataset to search over, must match the original dataset used to build the index
+/// - `file_path`: Path to the binary file written by [`serialize_binary()`]
+///
+/// # Returns
+/// A `ClusteredIndex` instance loaded from the file, ready to be used for searching
+///
+/// # Errors
+/// Returns `ClusteredIndexError::ConfigError` if:
+/// - The file doesn't exist or can't be mapped
+/// - The file is not a binary index or has an unsupported version
+/// - The serialized data is corrupted or incompatible
+///
+/// Returns `ClusteredIndexError::DeserializeError` if the PUFFINN index of a cluster is
+/// truncated or can't be decoded
+///
+/// # Example
+/// ```no_run
+/// use clann::{init_from_mmap, metricdata::AngularData};
+///
+/// let data = AngularData::new(/* your dataset */);
+/// let index = init_from_mmap(data, "path/to/index.bin").unwrap();
+/// ```
+pub fn init_from_mmap<T>(data: T, file_path: &str) -> Result<ClusteredIndex<T>>
+where
+    T: MetricData + IndexableSimilarity<T> + Subset,
+    <T as Subset>::Out: IndexableSimilarity<<T as Subset>::Out>,
+{
+    ClusteredIndex::new_from_mmap(data, file_path)
+}
+
+/// Initializes a CLANN index from a named collection of a collection file, through a
+/// read-only memory map.
+///
+/// # Parameters
+/// - `data`: Dataset to search over, must match the original dataset used to build the index
+/// - `file_path`: Path to the collection file written by [`serialize_collection()`]
+/// - `name`: Name of the collection to load
+///
+/// # Returns
+/// A `ClusteredIndex` instance loaded from the collection, ready to be used for searching
+///
+/// # Errors
+/// Returns `ClusteredIndexError::ConfigError` if:
+/// - The file doesn't exist or can't be mapped
+/// - The file is not a collection file or has no collection `name`
+/// - The serialized data is corrupted or incompatible
+///
+/// Returns `ClusteredIndexError::DeserializeError` if the PUFFINN index of a cluster is
+/// truncated or can't be decoded
+///
+/// # Example
+/// ```no_run
+/// use clann::{load_collection, metricdata::AngularData};
+///
+/// let data = AngularData::new(/* your dataset */);
+/// let index = load_collection(data, "path/to/indexes.clann", "text-embeddings").unwrap();
+/// ```
+pub fn load_collection<T>(data: T, file_path: &str, name: &str) -> Result<ClusteredIndex<T>>
+where
+    T: MetricData + IndexableSimilarity<T> + Subset,
+    <T as Subset>::Out: IndexableSimilarity<<T as Subset>::Out>,
+{
+    ClusteredIndex::new_from_collection(data, file_path, name)
+}
+
+/// Lists the names of the collections in a collection file.
+///
+/// # Parameters
+/// - `file_path`: Path to the collection file written by [`serialize_collection()`]
+///
+/// # Returns
+/// The collection names, in the order they were first saved
+///
+/// # Errors
+/// Returns `ClusteredIndexError::ConfigError` if the file doesn't exist or is not a
+/// collection file
+///
+/// # Example
+/// ```no_run
+/// use clann::list_collections;
+///
+/// for name in list_collections("path/to/indexes.clann").unwrap() {
+///     println!("{}", name);
+/// }
+/// ```
+pub fn list_collections(file_path: &str) -> Result<Vec<String>> {
+    core::binary::collection_names(file_path).map_err(core::ClusteredIndexError::ConfigError)
+}
+
+/// Reads the configuration and the cluster statistics of a serialized index, without its dataset.
+///
+/// Only the metadata of the file is read, the cluster indices are not loaded.
+///
+/// # Parameters
+/// - `file_path`: Path to an index file written by [`serialize()`] or [`serialize_binary()`]
+///
+/// # Returns
+/// The configuration of the index and the [`IndexStats`] of its clusters
+///
+/// # Errors
+/// Returns `ClusteredIndexError::ConfigError` if:
+/// - The file doesn't exist
+/// - The file is not an HDF5 or binary index file
+/// - The serialized metadata is corrupted
+///
+/// # Example
+/// ```no_run
+/// use clann::stats_from_file;
+///
+/// let (config, stats) = stats_from_file("path/to/index.h5").unwrap();
+/// println!("{} clusters, {} bytes", stats.num_clusters, stats.total_memory);
+/// ```
+pub fn stats_from_file(file_path: &str) -> Result<(Config, IndexStats)> {
+    let (config, clusters) = core::index::read_metadata(file_path)?;
+    Ok((config, IndexStats::from_clusters(&clusters)))
+}
+
 /// Initializes a new CLANN index with default configuration.
 ///
 /// Default configuration uses:
@@ -97,7 +248,8 @@ where
 /// Call [`build()`] to construct the index before searching.
 ///
 /// # Errors
-/// Returns `ClusteredIndexError::DataError` if the input dataset is empty
+/// Returns `ClusteredIndexError::DataError` if the input dataset is empty, or if
+/// [`core::Config::validate_data`] is set and some of its rows are invalid
 ///
 /// # Example
 /// ```no_run
@@ -123,6 +275,108 @@ where
     ClusteredIndex::new(config, data)
 }
 
+/// Initializes a new CLANN index using `B` instead of PUFFINN as per-cluster index.
+///
+/// # Parameters
+/// - `data`: Dataset to build the index for
+/// - `config`: Configuration object, see [`init_with_config()`]
+///
+/// # Returns
+/// An unbuilt `ClusteredIndex` instance with the specified configuration.
+/// Call [`build()`] to construct the index before searching.
+///
+/// # Errors
+/// Returns `ClusteredIndexError::DataError` if the input dataset is empty, or if
+/// [`core::Config::validate_data`] is set and some of its rows are invalid
+///
+/// # Example
+/// ```ignore
+/// use clann::{init_with_backend, build, core::Config, lsh::CrossPolytopeIndex, metricdata::AngularData};
+///
+/// let data = AngularData::new(/* your dataset */);
+/// let mut index = init_with_backend::<_, CrossPolytopeIndex>(data, Config::default()).unwrap();
+/// build(&mut index).unwrap();
+/// ```
+pub fn init_with_backend<T, B>(data: T, config: Config) -> Result<ClusteredIndex<T, B>>
+where
+    T: MetricData + IndexableSimilarity<T> + Subset,
+    <T as Subset>::Out: IndexableSimilarity<<T as Subset>::Out>,
+    B: ClusterBackend<T>,
+{
+    ClusteredIndex::new(config, data)
+}
+
+/// Initializes a CLANN index using `B` as per-cluster index from a binary file, through a
+/// read-only memory map.
+///
+/// With a backend reading its arrays in place, as [`lsh::CrossPolytopeIndex`], the hash tables
+/// and the points of the clusters are used directly from the mapped file, as the members of
+/// the clusters, so every process loading the same file on a host shares a single physical
+/// copy of them.
+///
+/// # Parameters
+/// - `data`: Dataset to search over, must match the original dataset used to build the index
+/// - `file_path`: Path to the binary file written by [`serialize_binary()`] for an index with backend `B`
+///
+/// # Returns
+/// A `ClusteredIndex` instance loaded from the file, ready to be used for searching
+///
+/// # Errors
+/// Same as [`init_from_mmap()`]
+///
+/// # Example
+/// ```ignore
+/// use clann::{init_from_mmap_with_backend, lsh::CrossPolytopeIndex, metricdata::AngularData};
+///
+/// let data = AngularData::new(/* your dataset */);
+/// let index = init_from_mmap_with_backend::<_, CrossPolytopeIndex>(data, "path/to/index.bin").unwrap();
+/// ```
+pub fn init_from_mmap_with_backend<T, B>(data: T, file_path: &str) -> Result<ClusteredIndex<T, B>>
+where
+    T: MetricData + IndexableSimilarity<T> + Subset,
+    <T as Subset>::Out: IndexableSimilarity<<T as Subset>::Out>,
+    B: ClusterBackend<T>,
+{
+    ClusteredIndex::new_from_mmap(data, file_path)
+}
+
+/// Predicts the memory and time of building a CLANN index of `data` with `config`, before
+/// committing to a build that may take hours.
+///
+/// A sample of the dataset is clustered to predict the size of every cluster, and the PUFFINN
+/// index of a pilot cluster is built to measure the memory and time it takes per point, which
+/// are scaled to every cluster getting an index.
+///
+/// # Parameters
+/// - `data`: Dataset the index would be built for
+/// - `config`: Configuration of the build, see [`init_with_config()`]
+///
+/// # Returns
+/// The predicted clusters with their memory and build time, and the totals. Merging tiny
+/// clusters, pooling outliers, deduplication and [`core::Config::max_memory_bytes`] are not
+/// taken into account
+///
+/// # Errors
+/// - `ClusteredIndexError::DataError` or `ClusteredIndexError::ConfigError` if `config` cannot
+///   be used on `data`, as in [`init_with_config()`]
+/// - `ClusteredIndexError::PuffinnCreationError` if the index of the pilot cluster cannot be built
+///
+/// # Example
+/// ```no_run
+/// use clann::{estimate_build, core::Config, metricdata::AngularData};
+///
+/// let data = AngularData::new(/* your dataset */);
+/// let estimate = estimate_build(&data, &Config::default()).unwrap();
+/// println!("{} bytes in {:?}", estimate.memory_bytes, estimate.build_time);
+/// ```
+pub fn estimate_build<T>(data: &T, config: &Config) -> Result<BuildEstimate>
+where
+    T: MetricData + IndexableSimilarity<T> + Subset,
+    <T as Subset>::Out: IndexableSimilarity<<T as Subset>::Out>,
+{
+    ClusteredIndex::<T>::estimate_build(data, config)
+}
+
 /// Builds a CLANN index by performing clustering and creating PUFFINN indices.
 ///
 /// The build process consists of two main steps:
@@ -139,126 +393,1332 @@ where
 ///
 /// # Errors
 /// Returns `ClusteredIndexError::PuffinnCreationError` if PUFFINN index creation fails for any cluster
-pub fn build<T>(index: &mut ClusteredIndex<T>) -> Result<()>
+pub fn build<T, B>(index: &mut ClusteredIndex<T, B>) -> Result<()>
 where
     T: MetricData + IndexableSimilarity<T> + Subset,
     <T as Subset>::Out: IndexableSimilarity<<T as Subset>::Out>,
+    B: ClusterBackend<T>,
 {
     index.build()
 }
 
-/// Searches for the k nearest neighbors of a query point.
+/// Builds a CLANN index like [`build()`], checkpointing its progress so that an interrupted
+/// build can be resumed.
 ///
-/// The search process:
-/// 1. Sorts clusters by distance from query to their centers
-/// 2. Processes clusters in order until termination condition is met
-/// 3. For each cluster either:
-///    - Uses PUFFINN index to find candidates (large clusters)
-///    - Uses brute force search (small clusters)
+/// The clustering is written to `dir` as soon as it is computed, then the index of every
+/// cluster as soon as it is created. If the build dies, calling `build_resume` again with
+/// the same directory, configuration and dataset reads them back and only builds the missing
+/// clusters. The checkpoint files are deleted once the build completes.
 ///
 /// # Parameters
-/// - `index`: Built index to search in
-/// - `query`: Query point with same dimensionality as dataset points
-///
-/// # Returns
-/// Vector of (distance, index) pairs for the k nearest neighbors found,
-/// sorted by distance in ascending order
+/// - `index`: Unbuilt index instance to build
+/// - `dir`: Work directory of the checkpoint, created if needed
 ///
 /// # Errors
-/// - `ClusteredIndexError::IndexNotFound` if a required PUFFINN index is missing
-/// - `ClusteredIndexError::PuffinnSearchError` if PUFFINN search fails
-/// - `ClusteredIndexError::IndexOutOfBounds` if candidate mapping fails
+/// - `ClusteredIndexError::ConfigError` if `dir` holds a checkpoint made with another
+///   configuration or dataset, delete it to start over
+/// - `ClusteredIndexError::SerializeError` if the checkpoint cannot be read or written
+/// - Any error returned by [`build()`]
 ///
 /// # Example
 /// ```no_run
-/// use clann::{init, build, search, metricdata::AngularData};
-/// 
+/// use clann::{init, build_resume, metricdata::AngularData};
+///
 /// let data = AngularData::new(/* your dataset */);
 /// let mut index = init(data).unwrap();
-/// build(&mut index).unwrap();
-/// 
-/// let query = vec![0.1, 0.2, 0.3];
-/// let neighbors = search(&mut index, &query).unwrap();
+/// // run again after a crash to continue from the last completed cluster
+/// build_resume(&mut index, "./__build_checkpoint__").unwrap();
 /// ```
-pub fn search<T>(index: &mut ClusteredIndex<T>, query: &[T::DataType]) -> Result<Vec<(f32, usize)>>
+pub fn build_resume<T, B>(index: &mut ClusteredIndex<T, B>, dir: &str) -> Result<()>
 where
     T: MetricData + IndexableSimilarity<T> + Subset,
     <T as Subset>::Out: IndexableSimilarity<<T as Subset>::Out>,
+    B: ClusterBackend<T>,
 {
-    index.search(query)
+    index.build_resume(dir)
 }
 
-/// Saves metrics from a search run to a SQLite database.
+/// Builds a CLANN index on a given partition of the dataset instead of clustering it.
 ///
-/// # Parameters
-/// - `index`: Index containing the metrics to save
-/// - `output_path`: Path to SQLite database file
-/// - `granularity`: Level of detail for metrics:
-///   - `Run`: Only overall metrics like recall and total time
-///   - `Query`: Run metrics + per-query metrics
-///   - `Cluster`: Query metrics + per-cluster metrics
-/// - `ground_truth_distances`: True k-NN distances for computing recall
-/// - `run_distances`: Distances returned by the search algorithm
-/// - `total_search_time`: Total time spent on all queries
+/// The partition can be exported from another index with [`ClusteredIndex::export_partition`],
+/// to compare configurations on exactly the same clusters, or made from the labels of an
+/// external clustering (e.g. spherical k-means) with [`core::Partition::from_assignments`].
 ///
-/// # Database Schema
-/// The metrics are saved in multiple tables:
-/// - `build_metrics`: Index building statistics
-/// - `search_metrics`: Overall search performance
-/// - `search_metrics_query`: Per-query metrics
-/// - `search_metrics_cluster`: Per-cluster metrics
+/// # Parameters
+/// - `index`: Index instance to build, any previous build is replaced
+/// - `partition`: Cluster of every point of the dataset, and optionally the centers
 ///
 /// # Errors
-/// - `ClusteredIndexError::MetricsError` if metrics are not enabled or database doesn't exist
-/// - `ClusteredIndexError::ResultDBError` for database connection/operation errors
-pub fn save_metrics<T>(
-    index: &mut ClusteredIndex<T>,
-    output_path: &str,
-    granularity: MetricsGranularity,
-    ground_truth_distances: &Array<f32, Ix2>,
-    run_distances: &[Vec<f32>],
-    total_search_time: &Duration,
-) -> Result<()>
+/// - `ClusteredIndexError::DataError` if the partition doesn't assign every point of the
+///   dataset, has an empty cluster, or a center outside its cluster
+/// - `ClusteredIndexError::PuffinnCreationError` if PUFFINN index creation fails for any cluster
+///
+/// # Example
+/// ```no_run
+/// use clann::{build, build_with_partition, core::Config, init_with_config, metricdata::AngularData};
+///
+/// let points = ndarray::Array2::<f32>::zeros((10000, 3));
+/// let mut index = init_with_config(AngularData::new(points.clone()), Config::default()).unwrap();
+/// build(&mut index).unwrap();
+/// let partition = index.export_partition().unwrap();
+///
+/// // same clusters, more tables
+/// let config = Config { num_tables: 50, ..Default::default() };
+/// let mut other = init_with_config(AngularData::new(points), config).unwrap();
+/// build_with_partition(&mut other, &partition).unwrap();
+/// ```
+pub fn build_with_partition<T, B>(index: &mut ClusteredIndex<T, B>, partition: &Partition) -> Result<()>
 where
     T: MetricData + IndexableSimilarity<T> + Subset,
     <T as Subset>::Out: IndexableSimilarity<<T as Subset>::Out>,
+    B: ClusterBackend<T>,
 {
-    index.save_metrics(
-        output_path.to_string(),
-        granularity,
-        ground_truth_distances,
-        run_distances,
-        total_search_time,
-    )
+    index.build_with_partition(partition)
 }
 
-/// Serializes a CLANN index to an HDF5 file.
+/// Builds a CLANN index over a stream of points, never holding the whole dataset in memory.
+///
+/// The build makes two passes:
+/// 1. The points are written to a temporary file, while a uniform sample of at most 50,000 of
+///    them is kept, and the centers are chosen on the sample with greedy minimum-maximum clustering
+/// 2. The file is read in order, every point is assigned to its nearest center, and the index of
+///    every cluster is created, streaming its points from the file
+///
+/// The returned index reads the points from the temporary file, which is removed when the index
+/// is dropped. Points are numbered in the order of the stream.
 ///
 /// # Parameters
-/// - `index`: Index to serialize
-/// - `directory_path`: Directory where the index file will be saved
+/// - `points`: Points of the dataset, e.g. the rows of a database cursor
+/// - `config`: Configuration object, see [`init_with_config()`]
 ///
-/// # File Structure
-/// The HDF5 file contains:
-/// - Configuration parameters
-/// - Cluster information (centers, assignments, radii)
-/// - PUFFINN indices for each cluster
+/// # Errors
+/// - `ClusteredIndexError::DataError` if the stream is empty, its points don't all have the
+///   same dimensions, or the temporary file cannot be written
+/// - `ClusteredIndexError::PuffinnCreationError` if PUFFINN index creation fails for any cluster
 ///
-/// # File Naming
-/// The file is named: `index_{dataset_name}_k{clusters_factor}_L{num_tables}.h5`
+/// # Example
+/// ```no_run
+/// use clann::{build_from_iter, core::Config, search};
+///
+/// let rows = (0..100_000).map(|i| vec![i as f32, 1.0, 0.5]);
+/// let mut index = build_from_iter(rows, Config::default()).unwrap();
+/// let neighbors = search(&mut index, &[2.0, 1.0, 0.5]).unwrap();
+/// ```
+pub fn build_from_iter<I>(points: I, config: Config) -> Result<ClusteredIndex<OutOfCoreData<MmapRows>>>
+where
+    I: IntoIterator<Item = Vec<f32>>,
+{
+    core::stream::build_from_iter(points, config)
+}
+
+/// Inserts a single point into a built CLANN index.
+///
+/// The cluster receiving the point has its index rebuilt immediately, so for more than a
+/// few points prefer [`insert_batch()`], which rebuilds each affected cluster only once.
+///
+/// # Parameters
+/// - `index`: Built index to insert into, its dataset must be owned (e.g. `AngularData<OwnedRepr<f32>>`)
+/// - `point`: Point to insert, in the original space if the index has a projection
+///
+/// # Returns
+/// Index of the inserted point in the dataset
 ///
 /// # Errors
-/// Returns `ClusteredIndexError::SerializeError` if:
-/// - Directory doesn't exist
-/// - File creation fails
-/// - Serialization of any component fails
-pub fn serialize<T>(
-    index: &ClusteredIndex<T>,
-    directory_path: &str,
-) -> Result<()>
+/// Same as [`insert_batch()`]
+pub fn insert<T, B>(index: &mut ClusteredIndex<T, B>, point: &[T::DataType]) -> Result<usize>
 where
-    T: MetricData + IndexableSimilarity<T> + Subset,
+    T: MetricData + IndexableSimilarity<T> + Subset + Insertable,
     <T as Subset>::Out: IndexableSimilarity<<T as Subset>::Out>,
+    B: ClusterBackend<T>,
 {
-    index.serialize(directory_path)
+    index.insert(point)
+}
+
+/// Inserts a batch of points into a built CLANN index.
+///
+/// Every point is assigned to the cluster with the closest center, then the index of each
+/// affected cluster is rebuilt once at the end of the batch. Centers are not recomputed,
+/// so after inserting a large fraction of the dataset a new [`build()`] gives better pruning.
+///
+/// # Parameters
+/// - `index`: Built index to insert into, its dataset must be owned (e.g. `AngularData<OwnedRepr<f32>>`)
+/// - `points`: Matrix with one point per row, in the original space if the index has a projection
+///
+/// # Returns
+/// Indices of the inserted points in the dataset, in the same order as the rows
+///
+/// # Errors
+/// - `ClusteredIndexError::ConfigError` if the index is not built
+/// - `ClusteredIndexError::DataError` if a point has the wrong dimensionality, or is invalid
+///   with [`core::Config::validate_data`]
+/// - `ClusteredIndexError::PuffinnCreationError` if a cluster index cannot be rebuilt. No
+///   cluster is changed then, but the points stay at the end of the dataset and in the
+///   write-ahead log, since [`metricdata::Insertable`] can't remove them: they are in no
+///   cluster and never returned by searches, until the next [`build()`]
+///
+/// # Example
+/// ```no_run
+/// use clann::{init, build, insert_batch, metricdata::AngularData};
+///
+/// let data = AngularData::new(/* your dataset */);
+/// let mut index = init(data).unwrap();
+/// build(&mut index).unwrap();
+///
+/// let new_points = ndarray::Array2::<f32>::zeros((1000, 3));
+/// let ids = insert_batch(&mut index, &new_points).unwrap();
+/// ```
+pub fn insert_batch<T, B, S>(
+    index: &mut ClusteredIndex<T, B>,
+    points: &ArrayBase<S, Ix2>,
+) -> Result<Vec<usize>>
+where
+    T: MetricData + IndexableSimilarity<T> + Subset + Insertable,
+    <T as Subset>::Out: IndexableSimilarity<<T as Subset>::Out>,
+    B: ClusterBackend<T>,
+    S: Data<Elem = T::DataType>,
+{
+    index.insert_batch(points)
+}
+
+/// Opens the write-ahead log of a built CLANN index, so that no accepted insertion is lost
+/// in a crash between two serializations.
+///
+/// The insertions in the log are replayed first, then every batch of [`insert()`] or
+/// [`insert_batch()`] is synced to the log before it is applied. The index file only holds
+/// the ids of the inserted points, so the log keeps their components until both the index
+/// and the dataset are saved, then [`truncate_wal()`] empties it. To recover after a crash,
+/// extend the last saved dataset with [`extend_from_wal()`], load the last index file over
+/// it, then open the log, which indexes the points missing from the clusters.
+///
+/// # Parameters
+/// - `index`: Built index, its dataset must be owned (e.g. `AngularData<OwnedRepr<f32>>`)
+/// - `path`: Path of the log, created if it doesn't exist
+///
+/// # Returns
+/// The number of points replayed from the log
+///
+/// # Errors
+/// - `ClusteredIndexError::ConfigError` if the index is not built
+/// - `ClusteredIndexError::SerializeError` if the log cannot be read or created
+/// - `ClusteredIndexError::DataError` if the log doesn't follow the dataset of the index
+///
+/// # Example
+/// ```no_run
+/// use clann::{init_from_mmap, insert_batch, open_wal, serialize_binary, truncate_wal, metricdata::AngularData};
+///
+/// let data = AngularData::new(/* dataset saved with the index */);
+/// let mut index = init_from_mmap(data, "path/to/index.bin").unwrap();
+/// let replayed = open_wal(&mut index, "path/to/index.wal").unwrap();
+///
+/// let new_points = ndarray::Array2::<f32>::zeros((1000, 3));
+/// insert_batch(&mut index, &new_points).unwrap();
+/// serialize_binary(&index, "path/to").unwrap();
+/// // save index.data() with the inserted points, then
+/// truncate_wal(&index).unwrap();
+/// ```
+pub fn open_wal<T, B, P>(index: &mut ClusteredIndex<T, B>, path: P) -> Result<usize>
+where
+    T: MetricData + IndexableSimilarity<T> + Subset + Insertable,
+    <T as Subset>::Out: IndexableSimilarity<<T as Subset>::Out>,
+    B: ClusterBackend<T>,
+    P: AsRef<std::path::Path>,
+{
+    index.open_wal(path)
+}
+
+/// Empties the write-ahead log of a CLANN index, once the index and its dataset, holding the
+/// points inserted since the log was opened, are both saved.
+///
+/// # Errors
+/// - `ClusteredIndexError::ConfigError` if the index has no log, see [`open_wal()`]
+/// - `ClusteredIndexError::SerializeError` if the log cannot be written
+pub fn truncate_wal<T, B>(index: &ClusteredIndex<T, B>) -> Result<()>
+where
+    T: MetricData + IndexableSimilarity<T> + Subset,
+    <T as Subset>::Out: IndexableSimilarity<<T as Subset>::Out>,
+    B: ClusterBackend<T>,
+{
+    index.truncate_wal()
+}
+
+/// Appends to a dataset the points of a write-ahead log it doesn't hold yet, so that an index
+/// file saved after insertions can be loaded over a dataset saved before them.
+///
+/// # Parameters
+/// - `data`: The last saved dataset of the index
+/// - `config`: Configuration of the index, the logged points are projected with its projection
+/// - `path`: Path of the log
+///
+/// # Returns
+/// The number of points appended
+///
+/// # Errors
+/// - `ClusteredIndexError::SerializeError` if the log cannot be read
+/// - `ClusteredIndexError::DataError` if the log doesn't follow the dataset
+///
+/// # Example
+/// ```no_run
+/// use clann::{extend_from_wal, init_from_mmap, open_wal, core::Config, metricdata::AngularData};
+///
+/// let mut data = AngularData::new(/* last saved dataset */);
+/// extend_from_wal(&mut data, &Config::default(), "path/to/index.wal").unwrap();
+/// let mut index = init_from_mmap(data, "path/to/index.bin").unwrap();
+/// open_wal(&mut index, "path/to/index.wal").unwrap();
+/// ```
+pub fn extend_from_wal<T, P>(data: &mut T, config: &Config, path: P) -> Result<usize>
+where
+    T: Insertable,
+    P: AsRef<std::path::Path>,
+{
+    core::wal::extend_from_wal(data, config, path.as_ref())
+}
+
+/// Merges a built CLANN index into another, without rebuilding any cluster index.
+///
+/// Shards of a dataset can be built separately, e.g. on different machines, and merged into
+/// a single index for serving. The points of `other` are appended to the dataset of `index`,
+/// so their ids are shifted by the returned offset, the size of the dataset of `index` before
+/// merging. The configuration of `index` is kept.
+///
+/// Each shard is clustered on its own points, so the merged index can have overlapping
+/// clusters, which makes pruning less effective than a single build on the whole dataset.
+///
+/// # Parameters
+/// - `index`: Built index to merge into, its dataset must be owned (e.g. `AngularData<OwnedRepr<f32>>`)
+/// - `other`: Built index to merge, consumed
+///
+/// # Returns
+/// The offset added to the ids of the points of `other`
+///
+/// # Errors
+/// - `ClusteredIndexError::ConfigError` if either index is not built, if they have
+///   different projections, or if either collapses duplicates (see [`core::Config::dedup`])
+/// - `ClusteredIndexError::DataError` if the datasets have different dimensions
+///
+/// # Example
+/// ```no_run
+/// use clann::{init, build, merge, metricdata::AngularData};
+///
+/// let mut index = init(AngularData::new(/* first shard */)).unwrap();
+/// build(&mut index).unwrap();
+/// let mut other = init(AngularData::new(/* second shard */)).unwrap();
+/// build(&mut other).unwrap();
+///
+/// let offset = merge(&mut index, other).unwrap();
+/// ```
+pub fn merge<T, B>(index: &mut ClusteredIndex<T, B>, other: ClusteredIndex<T, B>) -> Result<usize>
+where
+    T: MetricData + IndexableSimilarity<T> + Subset + Insertable,
+    <T as Subset>::Out: IndexableSimilarity<<T as Subset>::Out>,
+    B: ClusterBackend<T>,
+{
+    index.merge(other)
+}
+
+/// Clusters the points of a built CLANN index again, rebuilding only the changed clusters.
+///
+/// Insertions assign new points to the existing centers, so after many of them the centers
+/// become stale and the radii grow, which makes pruning less effective. With
+/// [`RepartitionPolicy::Full`] the whole dataset is clustered again, while
+/// [`RepartitionPolicy::SplitOversized`] only splits the clusters that grew too large.
+/// Either way, the index of a cluster is rebuilt only if its members changed.
+///
+/// # Parameters
+/// - `index`: Built index to repartition
+/// - `policy`: Which clusters to recompute
+///
+/// # Returns
+/// Number of clusters whose members changed, and whose index was rebuilt
+///
+/// # Errors
+/// - `ClusteredIndexError::ConfigError` if the index is not built, or if `max_points` is zero
+/// - `ClusteredIndexError::PuffinnCreationError` if a cluster index cannot be rebuilt
+///
+/// # Example
+/// ```no_run
+/// use clann::{init, build, insert_batch, repartition, core::RepartitionPolicy, metricdata::AngularData};
+///
+/// let data = AngularData::new(/* your dataset */);
+/// let mut index = init(data).unwrap();
+/// build(&mut index).unwrap();
+///
+/// let new_points = ndarray::Array2::<f32>::zeros((100000, 3));
+/// insert_batch(&mut index, &new_points).unwrap();
+/// let rebuilt = repartition(&mut index, RepartitionPolicy::SplitOversized { max_points: 5000 }).unwrap();
+/// ```
+pub fn repartition<T, B>(index: &mut ClusteredIndex<T, B>, policy: RepartitionPolicy) -> Result<usize>
+where
+    T: MetricData + IndexableSimilarity<T> + Subset,
+    <T as Subset>::Out: IndexableSimilarity<<T as Subset>::Out>,
+    B: ClusterBackend<T>,
+{
+    index.repartition(policy)
+}
+
+/// Searches for the k nearest neighbors of a query point.
+///
+/// The search process:
+/// 1. Sorts clusters by distance from query to their centers
+/// 2. Processes clusters in order until termination condition is met
+/// 3. For each cluster either:
+///    - Uses PUFFINN index to find candidates (large clusters)
+///    - Uses brute force search (small clusters)
+///
+/// # Parameters
+/// - `index`: Built index to search in
+/// - `query`: Query point with same dimensionality as dataset points, or as the original
+///   points if the index was configured with a [`transform::RandomProjection`]
+///
+/// # Returns
+/// Vector of (distance, index) pairs for the k nearest neighbors found,
+/// sorted by distance in ascending order, or in the order they are selected with [`core::Config::mmr`]
+///
+/// # Ordering
+/// Neighbors at the same distance are sorted by increasing index, so the results of a query
+/// are the same from run to run and can be compared as a whole, e.g. in snapshot tests. This
+/// holds for clusters scanned exhaustively and searched with PUFFINN alike, and for the
+/// copies of a point expanded by [`core::Config::dedup`]. Of the candidates tied at the
+/// distance of the k-th neighbor, those with the smallest indices are kept, whatever the
+/// order the clusters are probed in. With [`core::Config::rerank_f64`],
+/// the neighbors are sorted by their f64 distance first, which may differ for equal f32 distances.
+///
+/// # Errors
+/// - `ClusteredIndexError::DataError` if the query has a NaN or infinite component, or a zero norm under the
+///   angular distance or with normalized queries
+/// - `ClusteredIndexError::DimensionMismatch` if the query doesn't have the dimensions of the dataset,
+///   or the input dimensions of the projection
+/// - `ClusteredIndexError::IndexNotFound` if a required PUFFINN index is missing
+/// - `ClusteredIndexError::PuffinnSearchError` if PUFFINN search fails
+/// - `ClusteredIndexError::IndexOutOfBounds` if candidate mapping fails
+///
+/// # Example
+/// ```no_run
+/// use clann::{init, build, search, metricdata::AngularData};
+/// 
+/// let data = AngularData::new(/* your dataset */);
+/// let mut index = init(data).unwrap();
+/// build(&mut index).unwrap();
+/// 
+/// let query = vec![0.1, 0.2, 0.3];
+/// let neighbors = search(&mut index, &query).unwrap();
+/// ```
+pub fn search<T, B>(index: &mut ClusteredIndex<T, B>, query: &[T::DataType]) -> Result<Vec<(f32, usize)>>
+where
+    T: MetricData + IndexableSimilarity<T> + Subset,
+    <T as Subset>::Out: IndexableSimilarity<<T as Subset>::Out>,
+    B: ClusterBackend<T>,
+{
+    index.search(query)
+}
+
+/// Searches for the k nearest neighbors of a query point through a shared reference, without
+/// any metrics bookkeeping.
+///
+/// Same results as [`search()`], but nothing is recorded: no per-query metrics or callbacks,
+/// no cluster statistics and no [`ClusteredIndex::last_distance_computations`]. Meant for serving a
+/// loaded index, where the metrics cost time and `&mut` forces exclusive access.
+///
+/// # Parameters
+/// - `index`: Built index to search in
+/// - `query`: Query point, as passed to [`search()`]
+///
+/// # Returns
+/// Vector of (distance, index) pairs for the k nearest neighbors found,
+/// sorted as with [`search()`]
+///
+/// # Errors
+/// Any error returned by [`search()`]
+///
+/// # Example
+/// ```no_run
+/// use clann::{init_from_file, search_readonly, metricdata::AngularData};
+///
+/// let data = AngularData::new(/* your dataset */);
+/// let index = init_from_file(data, "path/to/index.h5").unwrap();
+///
+/// let query = vec![0.1, 0.2, 0.3];
+/// let neighbors = search_readonly(&index, &query).unwrap();
+/// ```
+pub fn search_readonly<T, B>(index: &ClusteredIndex<T, B>, query: &[T::DataType]) -> Result<Vec<(f32, usize)>>
+where
+    T: MetricData + IndexableSimilarity<T> + Subset,
+    <T as Subset>::Out: IndexableSimilarity<<T as Subset>::Out>,
+    B: ClusterBackend<T>,
+{
+    index.search_readonly(query)
+}
+
+/// Searches for the k nearest neighbors of a query point, with per-query options.
+///
+/// Same as [`search()`], except that the points of [`SearchParams::exclude`] are skipped
+/// when the candidates of every cluster are mapped to the dataset: they are never returned,
+/// and the query still gets k neighbors if there are enough other points. [`SearchParams::k`]
+/// returns another number of neighbors for this query only, leaving the configuration unchanged.
+///
+/// # Parameters
+/// - `index`: Built index to search in
+/// - `query`: Query point, as passed to [`search()`]
+/// - `params`: Options of this query
+///
+/// # Returns
+/// Vector of (distance, index) pairs for the k nearest neighbors found,
+/// sorted as with [`search()`]
+///
+/// # Errors
+/// Any error returned by [`search()`]
+///
+/// # Example
+/// ```no_run
+/// use clann::{init, build, search_with, core::SearchParams, metricdata::{AngularData, MetricData}};
+///
+/// let data = AngularData::new(/* your dataset */);
+/// let mut index = init(data).unwrap();
+/// build(&mut index).unwrap();
+///
+/// // neighbors of point 42 of the dataset, without the point itself
+/// let query = index.data().get_point(42).into_owned();
+/// let neighbors = search_with(&mut index, &query, &SearchParams::exclude_self_by_id(&42)).unwrap();
+/// ```
+pub fn search_with<T, B>(
+    index: &mut ClusteredIndex<T, B>,
+    query: &[T::DataType],
+    params: &SearchParams,
+) -> Result<Vec<(f32, usize)>>
+where
+    T: MetricData + IndexableSimilarity<T> + Subset,
+    <T as Subset>::Out: IndexableSimilarity<<T as Subset>::Out>,
+    B: ClusterBackend<T>,
+{
+    index.search_with(query, params)
+}
+
+/// Searches for the k points nearest to a set of query vectors, for example the embeddings
+/// of the parts of a multi-vector document.
+///
+/// The distance of a point to the set is the [`Aggregation`] of its distances to every
+/// vector: the closest one with [`Aggregation::Min`], or their mean with [`Aggregation::Mean`].
+/// Clusters are probed by the aggregated distance to their center and candidates are scored
+/// by their aggregated distance, so the search stops as soon as no cluster can improve the
+/// aggregated top-k, rather than searching every vector on its own and merging the results.
+///
+/// # Parameters
+/// - `index`: Built index to search in
+/// - `queries`: Query vectors, each as passed to [`search()`]
+/// - `aggregation`: How the distances to the query vectors are combined
+///
+/// # Returns
+/// Vector of (aggregated distance, index) pairs for the k nearest points found,
+/// sorted by distance in ascending order and equal distances by index
+///
+/// # Errors
+/// - `ClusteredIndexError::DataError` if `queries` is empty
+/// - Any error returned by [`search()`]
+///
+/// # Example
+/// ```no_run
+/// use clann::{init, build, search_multi, core::Aggregation, metricdata::AngularData};
+///
+/// let data = AngularData::new(/* your dataset */);
+/// let mut index = init(data).unwrap();
+/// build(&mut index).unwrap();
+///
+/// let title = vec![0.1, 0.2, 0.3];
+/// let body = vec![0.3, 0.2, 0.1];
+/// let neighbors = search_multi(&mut index, &[&title, &body], Aggregation::Mean).unwrap();
+/// ```
+pub fn search_multi<T, B>(
+    index: &mut ClusteredIndex<T, B>,
+    queries: &[&[T::DataType]],
+    aggregation: Aggregation,
+) -> Result<Vec<(f32, usize)>>
+where
+    T: MetricData + IndexableSimilarity<T> + Subset,
+    <T as Subset>::Out: IndexableSimilarity<<T as Subset>::Out>,
+    B: ClusterBackend<T>,
+{
+    index.search_multi(queries, aggregation)
+}
+
+/// Computes the probe plan of a query without executing the search.
+///
+/// The plan lists the clusters in the order the search would probe them, with the lower
+/// bound on the distance of their points, the expected number of candidates (from the
+/// previous searches on this index) and whether the search is expected to skip them.
+/// Useful to debug slow queries or to explain a search in a UI.
+///
+/// # Parameters
+/// - `index`: Built index
+/// - `query`: Query point, as passed to [`search()`]
+///
+/// # Errors
+/// - `ClusteredIndexError::DataError` if the query has a NaN or infinite component, or a zero norm under the
+///   angular distance or with normalized queries
+/// - `ClusteredIndexError::DimensionMismatch` if the query doesn't have the dimensions of the dataset,
+///   or the input dimensions of the projection
+///
+/// # Example
+/// ```no_run
+/// use clann::{init, build, plan, metricdata::AngularData};
+///
+/// let data = AngularData::new(/* your dataset */);
+/// let mut index = init(data).unwrap();
+/// build(&mut index).unwrap();
+///
+/// let query = vec![0.1, 0.2, 0.3];
+/// let plan = plan(&index, &query).unwrap();
+/// println!("{}", serde_json::to_string_pretty(&plan).unwrap());
+/// ```
+pub fn plan<T, B>(index: &ClusteredIndex<T, B>, query: &[T::DataType]) -> Result<SearchPlan>
+where
+    T: MetricData + IndexableSimilarity<T> + Subset,
+    <T as Subset>::Out: IndexableSimilarity<<T as Subset>::Out>,
+    B: ClusterBackend<T>,
+{
+    index.plan(query)
+}
+
+/// Estimates how hard a query is for an index, without searching it.
+///
+/// The score is the fraction of the dataset in the clusters the search may have to probe,
+/// from the distance of the query to the nearest centers relative to the radii of the
+/// clusters, so it grows with the distance computations of the search. Useful to route hard
+/// queries to an index with a higher recall target, or to more resources.
+///
+/// # Parameters
+/// - `index`: Built index
+/// - `query`: Query point, as passed to [`search()`]
+///
+/// # Errors
+/// - `ClusteredIndexError::ConfigError` if the index is not built
+/// - `ClusteredIndexError::DataError` if the query has a NaN or infinite component, or a zero norm under the
+///   angular distance or with normalized queries
+/// - `ClusteredIndexError::DimensionMismatch` if the query doesn't have the dimensions of the dataset,
+///   or the input dimensions of the projection
+///
+/// # Example
+/// ```no_run
+/// use clann::{init, build, estimate_hardness, search, metricdata::AngularData};
+///
+/// let data = AngularData::new(/* your dataset */);
+/// let mut index = init(data).unwrap();
+/// build(&mut index).unwrap();
+///
+/// let query = vec![0.1, 0.2, 0.3];
+/// if estimate_hardness(&index, &query).unwrap().score > 0.5 {
+///     index.set_delta(0.99).unwrap();
+/// }
+/// let neighbors = search(&mut index, &query).unwrap();
+/// ```
+pub fn estimate_hardness<T, B>(index: &ClusteredIndex<T, B>, query: &[T::DataType]) -> Result<QueryHardness>
+where
+    T: MetricData + IndexableSimilarity<T> + Subset,
+    <T as Subset>::Out: IndexableSimilarity<<T as Subset>::Out>,
+    B: ClusterBackend<T>,
+{
+    index.estimate_hardness(query)
+}
+
+/// Finds the `m` clusters whose centers are closest to a query, without searching them.
+///
+/// Useful to route queries in a sharded deployment, where each shard holds a subset of
+/// the clusters: only the shards holding the nearest clusters need to be asked.
+///
+/// # Parameters
+/// - `index`: Built index
+/// - `query`: Query point, as passed to [`search()`]
+/// - `m`: Number of clusters to return
+///
+/// # Returns
+/// Up to `m` clusters with the distance from the query to their center and their radius,
+/// closest first
+///
+/// # Errors
+/// - `ClusteredIndexError::DataError` if the query has a NaN or infinite component, or a zero norm under the
+///   angular distance or with normalized queries
+/// - `ClusteredIndexError::DimensionMismatch` if the query doesn't have the dimensions of the dataset,
+///   or the input dimensions of the projection
+///
+/// # Example
+/// ```no_run
+/// use clann::{init, build, nearest_clusters, metricdata::AngularData};
+///
+/// let data = AngularData::new(/* your dataset */);
+/// let mut index = init(data).unwrap();
+/// build(&mut index).unwrap();
+///
+/// let query = vec![0.1, 0.2, 0.3];
+/// for cluster in nearest_clusters(&index, &query, 5).unwrap() {
+///     println!("cluster {} at {} (radius {})", cluster.cluster, cluster.distance, cluster.radius);
+/// }
+/// ```
+pub fn nearest_clusters<T, B>(
+    index: &ClusteredIndex<T, B>,
+    query: &[T::DataType],
+    m: usize,
+) -> Result<Vec<NearestCluster>>
+where
+    T: MetricData + IndexableSimilarity<T> + Subset,
+    <T as Subset>::Out: IndexableSimilarity<<T as Subset>::Out>,
+    B: ClusterBackend<T>,
+{
+    index.nearest_clusters(query, m)
+}
+
+/// Searches a single cluster for the `k` points nearest to a query point, to implement other
+/// probing strategies than the one of [`search()`] on top of the cluster searches.
+///
+/// Brute force clusters are scanned exhaustively, the others are searched with their PUFFINN
+/// index. Metrics and cluster statistics are not updated.
+///
+/// # Parameters
+/// - `index`: Built index to search in
+/// - `cluster`: Index of the cluster, as returned by [`nearest_clusters()`]
+/// - `query`: Query point with same dimensionality as dataset points
+/// - `k`: Number of neighbors to return
+///
+/// # Returns
+/// Up to `k` (distance, index) pairs of points of the cluster, closest first
+///
+/// # Errors
+/// - `ClusteredIndexError::IndexOutOfBounds` if the index has no such cluster
+/// - `ClusteredIndexError::DataError` if the query has a NaN or infinite component, or a zero norm under the
+///   angular distance or with normalized queries
+/// - `ClusteredIndexError::DimensionMismatch` if the query doesn't have the dimensions of the dataset,
+///   or the input dimensions of the projection
+/// - `ClusteredIndexError::IndexNotFound` if the PUFFINN index of the cluster is missing
+/// - `ClusteredIndexError::PuffinnSearchError` if PUFFINN search fails
+///
+/// # Example
+/// ```no_run
+/// use clann::{init, build, nearest_clusters, search_cluster, metricdata::AngularData};
+///
+/// let data = AngularData::new(/* your dataset */);
+/// let mut index = init(data).unwrap();
+/// build(&mut index).unwrap();
+///
+/// // probe the 3 closest clusters only
+/// let query = vec![0.1, 0.2, 0.3];
+/// let mut neighbors = Vec::new();
+/// for cluster in nearest_clusters(&index, &query, 3).unwrap() {
+///     neighbors.extend(search_cluster(&index, cluster.cluster, &query, 10).unwrap());
+/// }
+/// neighbors.sort_by(|a, b| a.0.total_cmp(&b.0));
+/// neighbors.truncate(10);
+/// ```
+pub fn search_cluster<T, B>(
+    index: &ClusteredIndex<T, B>,
+    cluster: usize,
+    query: &[T::DataType],
+    k: usize,
+) -> Result<Vec<(f32, usize)>>
+where
+    T: MetricData + IndexableSimilarity<T> + Subset,
+    <T as Subset>::Out: IndexableSimilarity<<T as Subset>::Out>,
+    B: ClusterBackend<T>,
+{
+    index.search_cluster(cluster, query, k)
+}
+
+/// Searches for the k nearest neighbors of a batch of query points.
+///
+/// Equivalent to calling [`search()`] on every row of `queries`, except that queries
+/// which are exact duplicates of an earlier query in the batch reuse its results,
+/// near-duplicates probe the clusters from the center bounds of the first of them,
+/// and results are read from and written to the query cache if it is enabled
+/// (see [`enable_query_cache()`]).
+///
+/// # Parameters
+/// - `index`: Built index to search in
+/// - `queries`: Matrix with one query per row
+///
+/// # Returns
+/// One vector of (distance, index) pairs per query, in the same order as the rows
+///
+/// # Errors
+/// - `ClusteredIndexError::DimensionMismatch` if the queries don't have the dimensions of the
+///   dataset, or the input dimensions of the projection
+/// - `ClusteredIndexError::ResultDBError` if the query cache cannot be read or written
+/// - Any error returned by [`search()`]
+///
+/// # Example
+/// ```no_run
+/// use clann::{init, build, search_batch, metricdata::AngularData};
+///
+/// let data = AngularData::new(/* your dataset */);
+/// let mut index = init(data).unwrap();
+/// build(&mut index).unwrap();
+///
+/// let queries = ndarray::Array2::<f32>::zeros((100, 3));
+/// let neighbors = search_batch(&mut index, &queries).unwrap();
+/// ```
+pub fn search_batch<T, B, S>(
+    index: &mut ClusteredIndex<T, B>,
+    queries: &ArrayBase<S, Ix2>,
+) -> Result<Vec<Vec<(f32, usize)>>>
+where
+    T: MetricData + IndexableSimilarity<T> + Subset,
+    <T as Subset>::Out: IndexableSimilarity<<T as Subset>::Out>,
+    B: ClusterBackend<T>,
+    S: Data<Elem = T::DataType>,
+{
+    index.search_batch(queries)
+}
+
+/// Searches for the k nearest neighbors of a batch of query points, with the per-query
+/// options of `params` applied to every query.
+///
+/// Same as [`search_batch()`], with the options of [`search_with()`]. Batches with excluded
+/// points are not cached.
+///
+/// # Parameters
+/// - `index`: Built index to search in
+/// - `queries`: Matrix with one query per row
+/// - `params`: Options of every query of the batch
+///
+/// # Returns
+/// One vector of (distance, index) pairs per query, in the same order as the rows
+///
+/// # Errors
+/// Any error returned by [`search_batch()`]
+///
+/// # Example
+/// ```no_run
+/// use clann::{init, build, search_batch_with, core::SearchParams, metricdata::AngularData};
+///
+/// let data = AngularData::new(/* your dataset */);
+/// let mut index = init(data).unwrap();
+/// build(&mut index).unwrap();
+///
+/// let queries = ndarray::Array2::<f32>::zeros((100, 3));
+/// let params = SearchParams { k: Some(100), ..Default::default() };
+/// let neighbors = search_batch_with(&mut index, &queries, &params).unwrap();
+/// ```
+pub fn search_batch_with<T, B, S>(
+    index: &mut ClusteredIndex<T, B>,
+    queries: &ArrayBase<S, Ix2>,
+    params: &SearchParams,
+) -> Result<Vec<Vec<(f32, usize)>>>
+where
+    T: MetricData + IndexableSimilarity<T> + Subset,
+    <T as Subset>::Out: IndexableSimilarity<<T as Subset>::Out>,
+    B: ClusterBackend<T>,
+    S: Data<Elem = T::DataType>,
+{
+    index.search_batch_with(queries, params)
+}
+
+/// Tells why true neighbors are missing from the results of a batch of queries, to see
+/// whether the recall is lost by the clustering or by the LSH indices.
+///
+/// Every missed neighbor among the first k of the ground truth gets a
+/// [`MissReason`](core::MissReason): its cluster was pruned by its radius, was not reached
+/// before the search stopped, or was probed without PUFFINN returning the neighbor. The
+/// searches are not recorded in the metrics nor in the cluster statistics.
+///
+/// # Parameters
+/// - `index`: Built index to search in
+/// - `queries`: Matrix with one query per row
+/// - `ground_truth`: Ids of the true neighbors of each query, closest first, at least k per row
+///
+/// # Returns
+/// The missed neighbors of each query with their cluster and reason, in the same order as the rows
+///
+/// # Errors
+/// - `ClusteredIndexError::ConfigError` if the ground truth has fewer than k neighbors per query
+/// - `ClusteredIndexError::DataError` if the number of queries and of ground truth rows differ
+/// - Any error returned by [`search()`]
+///
+/// # Example
+/// ```no_run
+/// use clann::{init, build, diagnose_misses, core::MissCounts, metricdata::AngularData};
+/// use clann::utils::load_hdf5_dataset;
+///
+/// let dataset = load_hdf5_dataset("./datasets/glove-25-angular.hdf5").unwrap();
+/// let mut index = init(AngularData::new(dataset.dataset_array)).unwrap();
+/// build(&mut index).unwrap();
+///
+/// let neighbors = dataset.ground_truth_neighbors.unwrap();
+/// let misses = diagnose_misses(&index, &dataset.dataset_queries, &neighbors).unwrap();
+/// let counts = MissCounts::total(&misses);
+/// println!("clustering: {}, LSH: {}", counts.pruned + counts.unvisited, counts.not_returned);
+/// ```
+pub fn diagnose_misses<T, B, S>(
+    index: &ClusteredIndex<T, B>,
+    queries: &ArrayBase<S, Ix2>,
+    ground_truth: &Array2<usize>,
+) -> Result<Vec<QueryMisses>>
+where
+    T: MetricData + IndexableSimilarity<T> + Subset,
+    <T as Subset>::Out: IndexableSimilarity<<T as Subset>::Out>,
+    B: ClusterBackend<T>,
+    S: Data<Elem = T::DataType>,
+{
+    index.diagnose_misses(queries, ground_truth)
+}
+
+/// Searches for the k nearest neighbors of a batch of query points as with [`search_batch()`],
+/// returning the ids and the distances as matrices in the layout of ann-benchmarks.
+///
+/// # Parameters
+/// - `index`: Built index to search in
+/// - `queries`: Matrix with one query per row
+///
+/// # Returns
+/// Two matrices with one row per query and k columns, the ids and the distances of its
+/// neighbors, closest first. Queries with fewer than k results are padded with
+/// [`NO_NEIGHBOR`](core::NO_NEIGHBOR) ids and infinite distances.
+///
+/// # Errors
+/// Any error returned by [`search_batch()`]
+///
+/// # Example
+/// ```no_run
+/// use clann::{init, build, search_batch_matrix, metricdata::AngularData};
+///
+/// let data = AngularData::new(/* your dataset */);
+/// let mut index = init(data).unwrap();
+/// build(&mut index).unwrap();
+///
+/// let queries = ndarray::Array2::<f32>::zeros((100, 3));
+/// let (ids, distances) = search_batch_matrix(&mut index, &queries).unwrap();
+/// ```
+pub fn search_batch_matrix<T, B, S>(
+    index: &mut ClusteredIndex<T, B>,
+    queries: &ArrayBase<S, Ix2>,
+) -> Result<(Array2<u32>, Array2<f32>)>
+where
+    T: MetricData + IndexableSimilarity<T> + Subset,
+    <T as Subset>::Out: IndexableSimilarity<<T as Subset>::Out>,
+    B: ClusterBackend<T>,
+    S: Data<Elem = T::DataType>,
+{
+    index.search_batch_matrix(queries)
+}
+
+/// Searches for the k nearest neighbors of a batch of query points, probing each cluster once
+/// for all the queries that need it.
+///
+/// Queries advance through their closest clusters in rounds, and in each round the queries
+/// probing the same cluster are searched together, which keeps its index and points in cache.
+/// Every query probes the same clusters as with [`search()`], so the results are the same;
+/// the gain grows with the number of queries sharing clusters, see the `time_benches` bench.
+///
+/// Unlike [`search_batch()`], duplicates and the query cache are not looked up. With metrics
+/// enabled the queries are searched one by one, to record the metrics of each.
+///
+/// # Parameters
+/// - `index`: Built index to search in
+/// - `queries`: Matrix with one query per row
+///
+/// # Returns
+/// One vector of (distance, index) pairs per query, in the same order as the rows
+///
+/// # Errors
+/// - `ClusteredIndexError::DimensionMismatch` if the queries don't have the dimensions of the
+///   dataset, or the input dimensions of the projection
+/// - Any error returned by [`search()`]
+///
+/// # Example
+/// ```no_run
+/// use clann::{init, build, search_batch_grouped, metricdata::AngularData};
+///
+/// let data = AngularData::new(/* your dataset */);
+/// let mut index = init(data).unwrap();
+/// build(&mut index).unwrap();
+///
+/// let queries = ndarray::Array2::<f32>::zeros((10000, 3));
+/// let neighbors = search_batch_grouped(&mut index, &queries).unwrap();
+/// ```
+pub fn search_batch_grouped<T, B, S>(
+    index: &mut ClusteredIndex<T, B>,
+    queries: &ArrayBase<S, Ix2>,
+) -> Result<Vec<Vec<(f32, usize)>>>
+where
+    T: MetricData + IndexableSimilarity<T> + Subset,
+    <T as Subset>::Out: IndexableSimilarity<<T as Subset>::Out>,
+    B: ClusterBackend<T>,
+    S: Data<Elem = T::DataType>,
+{
+    index.search_batch_grouped(queries)
+}
+
+/// Computes the k nearest neighbor graph of the whole dataset, for example to cluster it as a graph.
+///
+/// Every point is searched as a query, cluster by cluster: the points of a cluster probe mostly
+/// the same clusters, so each cluster index is searched by many of them in a row, as with
+/// [`search_batch_grouped()`]. The neighbors are as accurate as with [`search()`] and the same
+/// parameters, except for the `k` given here, and a point is never its own neighbor.
+///
+/// # Parameters
+/// - `index`: Built index of the dataset
+/// - `k`: Number of neighbors of every point
+///
+/// # Returns
+/// The graph, with the (distance, index) pairs of the neighbors of every point from the closest
+///
+/// # Errors
+/// - `ClusteredIndexError::ConfigError` if `k` is 0
+/// - `ClusteredIndexError::IndexNotFound` if the index is not built
+/// - Any error returned by [`search()`]
+///
+/// # Example
+/// ```no_run
+/// use clann::{init, build, knn_graph, metricdata::AngularData};
+///
+/// let data = AngularData::new(/* your dataset */);
+/// let mut index = init(data).unwrap();
+/// build(&mut index).unwrap();
+///
+/// let graph = knn_graph(&mut index, 10).unwrap();
+/// for (point, neighbor, distance) in graph.edges() {
+///     println!("{point} -> {neighbor}: {distance}");
+/// }
+/// ```
+pub fn knn_graph<T, B>(index: &mut ClusteredIndex<T, B>, k: usize) -> Result<KnnGraph>
+where
+    T: MetricData + IndexableSimilarity<T> + Subset,
+    <T as Subset>::Out: IndexableSimilarity<<T as Subset>::Out>,
+    B: ClusterBackend<T>,
+{
+    index.knn_graph(k)
+}
+
+/// Enables a persistent cache of [`search_batch()`] results.
+///
+/// Results are stored in the SQLite database at `cache_path`, keyed by a fingerprint of the
+/// index content (dataset, clusters and per-cluster indices) and of the search parameters of
+/// each batch. Evaluation sweeps that repeat the same queries on the same index then skip the
+/// search entirely, while a rebuilt index, or one searched with another k or delta, gets a
+/// different fingerprint and never sees stale results. Batches searched with a reranker or a
+/// delta policy bypass the cache.
+///
+/// # Parameters
+/// - `index`: Built index whose results should be cached
+/// - `cache_path`: Path to the SQLite database, created if it does not exist
+///
+/// # Errors
+/// - `ClusteredIndexError::ResultDBError` if the database cannot be opened
+/// - `ClusteredIndexError::SerializeError` if the index cannot be fingerprinted
+///
+/// # Example
+/// ```no_run
+/// use clann::{init_from_file, enable_query_cache, search_batch, metricdata::AngularData};
+///
+/// let data = AngularData::new(/* your dataset */);
+/// let mut index = init_from_file(data, "path/to/index.h5").unwrap();
+/// enable_query_cache(&mut index, "./query_cache.sqlite3").unwrap();
+///
+/// let queries = ndarray::Array2::<f32>::zeros((100, 3));
+/// let neighbors = search_batch(&mut index, &queries).unwrap();
+/// ```
+pub fn enable_query_cache<T, B>(index: &mut ClusteredIndex<T, B>, cache_path: &str) -> Result<()>
+where
+    T: MetricData + IndexableSimilarity<T> + Subset,
+    <T as Subset>::Out: IndexableSimilarity<<T as Subset>::Out>,
+    B: ClusterBackend<T>,
+{
+    index.enable_query_cache(cache_path)
+}
+
+/// Saves metrics from a search run to the `metrics_output` target of the configuration.
+///
+/// # Parameters
+/// - `index`: Index containing the metrics to save
+/// - `output_path`: Path to SQLite database file, ignored by `MetricsOutput::Json` and
+///   `MetricsOutput::Csv` which write to their own path
+/// - `granularity`: Level of detail for metrics:
+///   - `Run`: Only overall metrics like recall and total time
+///   - `Query`: Run metrics + per-query metrics
+///   - `Cluster`: Query metrics + per-cluster metrics
+/// - `ground_truth_distances`: True k-NN distances for computing recall
+/// - `run_distances`: Distances returned by the search algorithm
+/// - `total_search_time`: Total time spent on all queries
+///
+/// # Database Schema
+/// The metrics are saved in multiple tables:
+/// - `runs`: One row per save, with a random UUID `run_id`, the `run_tags` of the configuration,
+///   the host and the time. Every other table references it, so repeated runs of a
+///   configuration are all kept (see [`ClusteredIndex::last_run_id`])
+/// - `build_metrics`: Index building statistics
+/// - `build_metrics_cluster`: Size, memory and construction time of each cluster
+/// - `search_metrics`: Overall search performance
+/// - `search_metrics_cluster_summary`: Visits, prunes and top-k contributions of each cluster
+///   over the whole run, saved at every granularity
+/// - `search_metrics_query`: Per-query metrics
+/// - `search_metrics_cluster`: Per-cluster metrics
+///
+/// The `tuning_trace` table holds the evaluations of [`tune::auto_tune`], as runs of their own.
+///
+/// The database is created on first use, with the schema of `result_schema.sql` embedded in
+/// the crate. Databases written by older versions are migrated, the applied version is kept in
+/// the `schema_version` table.
+///
+/// With `MetricsOutput::Json` the same metrics are written to a single JSON document,
+/// with the per-query metrics under `queries` and the per-cluster metrics nested in each query.
+/// With `MetricsOutput::Csv` each table is a `{table}.csv` file with the same columns, created
+/// with a header on the first save and appended to afterwards. Times are in the unit of the column name.
+///
+/// # Errors
+/// - `ClusteredIndexError::MetricsError` if metrics are not enabled, the CSV directory
+///   doesn't exist, or the JSON or CSV files cannot be written
+/// - `ClusteredIndexError::ResultDBError` for database connection/operation errors
+pub fn save_metrics<T, B>(
+    index: &mut ClusteredIndex<T, B>,
+    output_path: &str,
+    granularity: MetricsGranularity,
+    ground_truth_distances: &Array<f32, Ix2>,
+    run_distances: &[Vec<f32>],
+    total_search_time: &Duration,
+) -> Result<()>
+where
+    T: MetricData + IndexableSimilarity<T> + Subset,
+    <T as Subset>::Out: IndexableSimilarity<<T as Subset>::Out>,
+    B: ClusterBackend<T>,
+{
+    index.save_metrics(
+        output_path.to_string(),
+        granularity,
+        Some(RecallInput::Distances {
+            ground_truth: ground_truth_distances,
+            run: run_distances,
+            epsilon: utils::DEFAULT_RECALL_EPSILON,
+        }),
+        total_search_time,
+    )
+}
+
+/// Saves metrics from a search run, computing the recall from neighbor ids.
+///
+/// Same as [`save_metrics()`], but the recall of each query is the size of the intersection
+/// of the true and of the returned neighbors, rather than the number of returned neighbors
+/// within the k-th true distance. Ties and near-duplicates at the k-th distance are not
+/// counted as hits unless they are true neighbors.
+///
+/// # Parameters
+/// - `index`: Index containing the metrics to save
+/// - `output_path`: Path to SQLite database file, ignored by `MetricsOutput::Json` and `MetricsOutput::Csv`
+/// - `granularity`: Level of detail for metrics
+/// - `ground_truth_neighbors`: Ids of the true k-NN of each query, e.g. the `neighbors` dataset
+///   of ann-benchmarks files (see [`utils::Hdf5Dataset::ground_truth_neighbors`])
+/// - `run_neighbors`: Ids returned by the search algorithm
+/// - `total_search_time`: Total time spent on all queries
+///
+/// # Errors
+/// - `ClusteredIndexError::MetricsError` if metrics are not enabled, the CSV directory
+///   doesn't exist, or the JSON or CSV files cannot be written
+/// - `ClusteredIndexError::ResultDBError` for database connection/operation errors
+pub fn save_metrics_by_ids<T, B>(
+    index: &mut ClusteredIndex<T, B>,
+    output_path: &str,
+    granularity: MetricsGranularity,
+    ground_truth_neighbors: &Array<usize, Ix2>,
+    run_neighbors: &[Vec<usize>],
+    total_search_time: &Duration,
+) -> Result<()>
+where
+    T: MetricData + IndexableSimilarity<T> + Subset,
+    <T as Subset>::Out: IndexableSimilarity<<T as Subset>::Out>,
+    B: ClusterBackend<T>,
+{
+    index.save_metrics(
+        output_path.to_string(),
+        granularity,
+        Some(RecallInput::Ids {
+            ground_truth: ground_truth_neighbors,
+            run: run_neighbors,
+        }),
+        total_search_time,
+    )
+}
+
+/// Saves metrics from a search run that has no ground truth, e.g. in production.
+///
+/// Same as [`save_metrics()`], but the recall columns are left empty (`NULL` in SQLite, `null`
+/// in JSON, an empty field in CSV). Timings, distance computations and cluster statistics are
+/// saved as usual, and the throughput counts the queries recorded since the metrics were enabled.
+///
+/// # Parameters
+/// - `index`: Index containing the metrics to save
+/// - `output_path`: Path to SQLite database file, ignored by `MetricsOutput::Json` and `MetricsOutput::Csv`
+/// - `granularity`: Level of detail for metrics
+/// - `total_search_time`: Total time spent on all queries
+///
+/// # Errors
+/// - `ClusteredIndexError::MetricsError` if metrics are not enabled, the CSV directory
+///   doesn't exist, or the JSON or CSV files cannot be written
+/// - `ClusteredIndexError::ResultDBError` for database connection/operation errors
+pub fn save_metrics_without_recall<T, B>(
+    index: &mut ClusteredIndex<T, B>,
+    output_path: &str,
+    granularity: MetricsGranularity,
+    total_search_time: &Duration,
+) -> Result<()>
+where
+    T: MetricData + IndexableSimilarity<T> + Subset,
+    <T as Subset>::Out: IndexableSimilarity<<T as Subset>::Out>,
+    B: ClusterBackend<T>,
+{
+    index.save_metrics(output_path.to_string(), granularity, None, total_search_time)
+}
+
+/// Serializes a CLANN index to an HDF5 file.
+///
+/// # Parameters
+/// - `index`: Index to serialize
+/// - `directory_path`: Directory where the index file will be saved
+///
+/// # File Structure
+/// The HDF5 file contains:
+/// - Configuration parameters
+/// - Cluster information (centers, assignments, radii)
+/// - PUFFINN indices for each cluster
+///
+/// # File Naming
+/// The file is named: `index_{dataset_name}_k{clusters_factor}_L{num_tables}.h5`
+///
+/// # Errors
+/// Returns `ClusteredIndexError::SerializeError` if:
+/// - Directory doesn't exist
+/// - File creation fails
+/// - Serialization of any component fails
+pub fn serialize<T>(
+    index: &ClusteredIndex<T>,
+    directory_path: &str,
+) -> Result<()>
+where
+    T: MetricData + IndexableSimilarity<T> + Subset,
+    <T as Subset>::Out: IndexableSimilarity<<T as Subset>::Out>,
+{
+    index.serialize(directory_path)
+}
+
+/// Serializes a CLANN index to a single binary file, loadable with [`init_from_mmap()`].
+///
+/// # Parameters
+/// - `index`: Index to serialize
+/// - `directory_path`: Directory where the index file will be saved
+///
+/// # File Structure
+/// A fixed preamble (magic bytes, format version, header length), a JSON header with the
+/// configuration and cluster information, followed by the PUFFINN index of each cluster.
+///
+/// # File Naming
+/// The file is named: `index_{dataset_name}_k{clusters_factor}_L{num_tables}.bin`
+///
+/// # Errors
+/// Returns `ClusteredIndexError::SerializeError` if:
+/// - Directory doesn't exist
+/// - File creation fails
+/// - Serialization of any component fails
+pub fn serialize_binary<T, B>(index: &ClusteredIndex<T, B>, directory_path: &str) -> Result<()>
+where
+    T: MetricData + IndexableSimilarity<T> + Subset,
+    <T as Subset>::Out: IndexableSimilarity<<T as Subset>::Out>,
+    B: ClusterBackend<T>,
+{
+    index.serialize_binary(directory_path)
+}
+
+/// Serializes a CLANN index as a named collection of a single file holding several indexes,
+/// e.g. one per embedding model or tenant, loadable with [`load_collection()`].
+///
+/// # Parameters
+/// - `index`: Index to serialize
+/// - `file_path`: Path to the collection file, created if it doesn't exist
+/// - `name`: Name of the collection, replacing a collection with the same name
+///
+/// # File Structure
+/// A fixed preamble (magic bytes, format version, manifest length), a JSON manifest with
+/// the name and location of each collection, followed by each index in the format of
+/// [`serialize_binary()`]. The file is rewritten under a temporary name and renamed, so a
+/// failed write leaves the other collections untouched.
+///
+/// # Errors
+/// Returns `ClusteredIndexError::SerializeError` if:
+/// - The file exists but is not a collection file
+/// - File creation fails
+/// - Serialization of any component fails
+///
+/// # Example
+/// ```no_run
+/// use clann::{init, build, serialize_collection, metricdata::AngularData};
+///
+/// let data = AngularData::new(/* your dataset */);
+/// let mut index = init(data).unwrap();
+/// build(&mut index).unwrap();
+/// serialize_collection(&index, "path/to/indexes.clann", "text-embeddings").unwrap();
+/// ```
+pub fn serialize_collection<T, B>(index: &ClusteredIndex<T, B>, file_path: &str, name: &str) -> Result<()>
+where
+    T: MetricData + IndexableSimilarity<T> + Subset,
+    <T as Subset>::Out: IndexableSimilarity<<T as Subset>::Out>,
+    B: ClusterBackend<T>,
+{
+    index.serialize_collection(file_path, name)
+}
+
+/// Writes the configuration and the clusters of an index to a file, without its LSH tables.
+///
+/// The file holds the assignment of every point, the centers and the radii of the clusters,
+/// a small fraction of a file written by [`serialize()`]. Load it with
+/// [`PartitionArtifact::load`] and pass it to [`rebuild_from_partition()`] to get the index
+/// back on another machine, which only builds the PUFFINN indices again.
+///
+/// # Parameters
+/// - `index`: Built index to export
+/// - `file_path`: File to write, as JSON
+///
+/// # Errors
+/// - `ClusteredIndexError::ConfigError` if the index is not built
+/// - `ClusteredIndexError::SerializeError` if the file can't be written
+///
+/// # Example
+/// ```no_run
+/// use clann::{init, build, serialize_partition, metricdata::AngularData};
+///
+/// let data = AngularData::new(/* your dataset */);
+/// let mut index = init(data).unwrap();
+/// build(&mut index).unwrap();
+/// serialize_partition(&index, "path/to/partition.json").unwrap();
+/// ```
+pub fn serialize_partition<T, B>(index: &ClusteredIndex<T, B>, file_path: &str) -> Result<()>
+where
+    T: MetricData + IndexableSimilarity<T> + Subset,
+    <T as Subset>::Out: IndexableSimilarity<<T as Subset>::Out>,
+    B: ClusterBackend<T>,
+{
+    index.export_artifact()?.save(file_path)
+}
+
+/// Builds an index from an artifact written by [`serialize_partition()`], creating the PUFFINN
+/// indices of its clusters without clustering the dataset again.
+///
+/// # Parameters
+/// - `data`: Dataset the artifact was exported from, in the same order
+/// - `artifact`: Configuration and clusters of the index
+///
+/// # Returns
+/// A built `ClusteredIndex` with the configuration and the clusters of the exported one
+///
+/// # Errors
+/// - `ClusteredIndexError::DataError` if the artifact doesn't fit the dataset, e.g. the
+///   radius of a cluster differs from the exported one
+/// - `ClusteredIndexError::PuffinnCreationError` if PUFFINN index creation fails for any cluster
+///
+/// # Example
+/// ```no_run
+/// use clann::{core::PartitionArtifact, rebuild_from_partition, metricdata::AngularData};
+///
+/// let data = AngularData::new(/* your dataset */);
+/// let artifact = PartitionArtifact::load("path/to/partition.json").unwrap();
+/// let index = rebuild_from_partition(data, &artifact).unwrap();
+/// ```
+pub fn rebuild_from_partition<T>(data: T, artifact: &PartitionArtifact) -> Result<ClusteredIndex<T>>
+where
+    T: MetricData + IndexableSimilarity<T> + Subset,
+    <T as Subset>::Out: IndexableSimilarity<<T as Subset>::Out>,
+{
+    let mut index = ClusteredIndex::new(artifact.config.clone(), data)?;
+    index.rebuild_from_artifact(artifact)?;
+    Ok(index)
 }
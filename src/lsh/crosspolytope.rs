@@ -0,0 +1,516 @@
+use std::ops::Range;
+
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use serde::{Deserialize, Serialize};
+
+use crate::core::{ClusterBackend, MappedBytes};
+use crate::metricdata::MetricData;
+use crate::topk::TopK;
+use crate::utils::gaussian;
+
+/// Maximum number of rotated dimensions used by a single cross-polytope hash
+const MAX_ROTATED_DIMS: usize = 32;
+
+/// Expected number of points per bucket, used to choose the number of hashes per table
+const TARGET_BUCKET_SIZE: f64 = 16.0;
+
+/// Seed of the random rotations, fixed so that builds are reproducible
+const SEED: u64 = 0x5eed_c1a2_2024;
+
+/// Cross-polytope LSH index for angular distance.
+///
+/// Each hash function applies a random Gaussian projection and returns the closest vertex
+/// of the cross-polytope (index of the largest absolute coordinate and its sign). A table
+/// concatenates enough hash functions to have roughly [`TARGET_BUCKET_SIZE`] points per bucket.
+///
+/// Unlike PUFFINN the number of probed tables is not adaptive: a search inspects
+/// `ceil(recall * num_tables)` tables and reranks their candidates exactly.
+///
+/// The tables and the points are flat arrays, serialized as they are in memory, so an index
+/// restored with [`ClusterBackend::from_mapped`] reads them in place from the mapped file.
+/// The rotations are drawn again from [`SEED`] instead of being stored.
+pub struct CrossPolytopeIndex {
+    dimensions: usize,
+    rotated_dims: usize,
+    hashes_per_table: usize,
+    /// One row-major `rotated_dims x dimensions` matrix per hash function, one after the other
+    rotations: Vec<f32>,
+    /// The keys of table `t` are `keys[table_starts[t]..table_starts[t + 1]]`
+    table_starts: Vec<usize>,
+    /// Keys of the non-empty buckets, sorted within each table
+    keys: Array<u64>,
+    /// The points of bucket `b` are `ids[bucket_starts[b]..bucket_starts[b + 1]]`
+    bucket_starts: Array<u64>,
+    ids: Array<u32>,
+    /// Normalized points, row-major
+    points: Array<f32>,
+}
+
+/// Header of the serialized index, followed by the arrays, see [`CrossPolytopeIndex::to_bytes`]
+#[derive(Serialize, Deserialize)]
+struct Layout {
+    dimensions: usize,
+    rotated_dims: usize,
+    hashes_per_table: usize,
+    num_points: usize,
+    table_starts: Vec<usize>,
+}
+
+impl Layout {
+    /// Byte ranges of the keys, the bucket starts, the points and the ids after the header,
+    /// `None` if a corrupted header overflows them
+    fn sections(&self, start: usize) -> Option<[Range<usize>; 4]> {
+        let num_keys = self.table_starts.last().copied().unwrap_or(0);
+        let num_tables = self.table_starts.len().saturating_sub(1);
+        let lengths = [
+            num_keys.checked_mul(8)?,
+            num_keys.checked_add(1)?.checked_mul(8)?,
+            self.num_points.checked_mul(self.dimensions)?.checked_mul(4)?,
+            num_tables.checked_mul(self.num_points)?.checked_mul(4)?,
+        ];
+        let mut ranges = [0..0, 0..0, 0..0, 0..0];
+        let mut end = start;
+        for (range, len) in ranges.iter_mut().zip(lengths) {
+            *range = end..end.checked_add(len)?;
+            end = range.end;
+        }
+        Some(ranges)
+    }
+}
+
+/// Elements of the arrays of an index, stored little-endian, for which any bit pattern is a value
+trait Plain: Copy + 'static {
+    fn from_le(bytes: &[u8]) -> Self;
+    fn extend_le(self, out: &mut Vec<u8>);
+}
+
+macro_rules! plain {
+    ($($t:ty),*) => {$(
+        impl Plain for $t {
+            fn from_le(bytes: &[u8]) -> Self {
+                <$t>::from_le_bytes(bytes.try_into().expect("sized chunk"))
+            }
+
+            fn extend_le(self, out: &mut Vec<u8>) {
+                out.extend_from_slice(&self.to_le_bytes());
+            }
+        }
+    )*};
+}
+
+plain!(u32, u64, f32);
+
+/// Array of an index, built in memory or read in place from a memory mapped file
+enum Array<E> {
+    Owned(Vec<E>),
+    /// Bytes aligned for `E`, a whole number of elements, on a little-endian target
+    Mapped(MappedBytes),
+}
+
+impl<E: Plain> Array<E> {
+    /// Copies the elements of `bytes`, a whole number of them
+    fn decode(bytes: &[u8]) -> Self {
+        Array::Owned(bytes.chunks_exact(std::mem::size_of::<E>()).map(E::from_le).collect())
+    }
+
+    /// Reads the elements of `bytes` in place if they are aligned and the target is
+    /// little-endian, otherwise copies them
+    fn mapped(bytes: MappedBytes) -> Self {
+        let aligned = (bytes.as_ptr() as usize).is_multiple_of(std::mem::align_of::<E>());
+        if cfg!(target_endian = "little") && aligned && bytes.len().is_multiple_of(std::mem::size_of::<E>()) {
+            Array::Mapped(bytes)
+        } else {
+            Self::decode(&bytes)
+        }
+    }
+
+    fn as_slice(&self) -> &[E] {
+        match self {
+            Array::Owned(elements) => elements,
+            // SAFETY: `Array::mapped` checked that the bytes are aligned for `E` and hold a whole
+            // number of elements, in the byte order of the target. `E` is `Plain`, so any bits
+            // are a value, and the mapping is read-only and kept alive by `bytes`.
+            Array::Mapped(bytes) => unsafe {
+                std::slice::from_raw_parts(bytes.as_ptr().cast::<E>(), bytes.len() / std::mem::size_of::<E>())
+            },
+        }
+    }
+
+    fn extend_le(&self, out: &mut Vec<u8>) {
+        for &element in self.as_slice() {
+            element.extend_le(out);
+        }
+    }
+}
+
+/// Rotations of `count` hash functions, see [`CrossPolytopeIndex::rotations`]
+fn rotations(dimensions: usize, rotated_dims: usize, count: usize) -> Vec<f32> {
+    let mut rng = StdRng::seed_from_u64(SEED);
+    (0..count * rotated_dims * dimensions)
+        .map(|_| gaussian(&mut rng))
+        .collect()
+}
+
+impl CrossPolytopeIndex {
+    fn num_tables(&self) -> usize {
+        self.table_starts.len() - 1
+    }
+
+    fn num_points(&self) -> usize {
+        self.points.as_slice().len().checked_div(self.dimensions).unwrap_or(0)
+    }
+
+    fn point(&self, i: usize) -> &[f32] {
+        &self.points.as_slice()[i * self.dimensions..(i + 1) * self.dimensions]
+    }
+
+    fn hash(&self, table: usize, point: &[f32]) -> u64 {
+        let vertices = 2 * self.rotated_dims as u64;
+        let size = self.rotated_dims * self.dimensions;
+        let mut key = 0u64;
+        for h in 0..self.hashes_per_table {
+            let start = (table * self.hashes_per_table + h) * size;
+            let rotation = &self.rotations[start..start + size];
+            let mut best = (0, 0.0f32);
+            for (row, coefficients) in rotation.chunks_exact(self.dimensions).enumerate() {
+                let y: f32 = coefficients.iter().zip(point).map(|(a, b)| a * b).sum();
+                if y.abs() > best.1.abs() {
+                    best = (row, y);
+                }
+            }
+            let vertex = 2 * best.0 as u64 + (best.1 < 0.0) as u64;
+            key = key.wrapping_mul(vertices).wrapping_add(vertex);
+        }
+        key
+    }
+
+    /// Points of the bucket `key` of `table`
+    fn bucket(&self, table: usize, key: u64) -> &[u32] {
+        let first = self.table_starts[table];
+        let keys = &self.keys.as_slice()[first..self.table_starts[table + 1]];
+        match keys.binary_search(&key) {
+            Ok(position) => {
+                let starts = self.bucket_starts.as_slice();
+                let bucket = first + position;
+                &self.ids.as_slice()[starts[bucket] as usize..starts[bucket + 1] as usize]
+            }
+            Err(_) => &[],
+        }
+    }
+
+    fn memory_used(&self) -> usize {
+        (self.points.as_slice().len() + self.rotations.len() + self.ids.as_slice().len()) * 4
+            + (self.keys.as_slice().len() + self.bucket_starts.as_slice().len()) * 8
+    }
+
+    /// Whether the arrays are read in place from a mapped file
+    #[cfg(test)]
+    fn is_mapped(&self) -> bool {
+        matches!(self.points, Array::Mapped(_))
+    }
+
+    /// Restores an index from the bytes of [`to_bytes`](ClusterBackend::to_bytes), reading its
+    /// arrays in place from `mapped` if these are mapped bytes
+    fn restore(bytes: &[u8], mapped: Option<&MappedBytes>) -> Result<Self, String> {
+        let header_len = bytes
+            .get(..8)
+            .map(|len| u64::from_le_bytes(len.try_into().unwrap()) as usize)
+            .ok_or("truncated cross-polytope index")?;
+        let header_end = 8usize
+            .checked_add(header_len)
+            .filter(|&end| end <= bytes.len())
+            .ok_or("truncated cross-polytope header")?;
+        let layout: Layout = serde_json::from_slice(&bytes[8..header_end]).map_err(|e| e.to_string())?;
+
+        let [keys, bucket_starts, points, ids] = layout
+            .sections(header_end)
+            .filter(|sections| layout.table_starts.len() >= 2 && sections[3].end == bytes.len())
+            .ok_or("cross-polytope index doesn't match its header")?;
+        fn read<E: Plain>(bytes: &[u8], mapped: Option<&MappedBytes>, range: Range<usize>) -> Array<E> {
+            match mapped {
+                Some(mapped) => Array::mapped(mapped.slice(range)),
+                None => Array::decode(&bytes[range]),
+            }
+        }
+        let index = Self {
+            dimensions: layout.dimensions,
+            rotated_dims: layout.rotated_dims,
+            hashes_per_table: layout.hashes_per_table,
+            rotations: rotations(
+                layout.dimensions,
+                layout.rotated_dims,
+                (layout.table_starts.len() - 1) * layout.hashes_per_table,
+            ),
+            table_starts: layout.table_starts,
+            keys: read(bytes, mapped, keys),
+            bucket_starts: read(bytes, mapped, bucket_starts),
+            ids: read(bytes, mapped, ids),
+            points: read(bytes, mapped, points),
+        };
+        index.validate()?;
+        Ok(index)
+    }
+
+    /// Checks that the buckets stay within the arrays, so that searches never read past them
+    fn validate(&self) -> Result<(), String> {
+        let invalid = |what: &str| Err(format!("cross-polytope index with invalid {}", what));
+        let num_keys = self.keys.as_slice().len();
+        if self.table_starts.windows(2).any(|w| w[0] > w[1]) || self.table_starts.last() != Some(&num_keys) {
+            return invalid("tables");
+        }
+        let starts = self.bucket_starts.as_slice();
+        if starts.first() != Some(&0)
+            || starts.windows(2).any(|w| w[0] > w[1])
+            || starts.last().map(|&end| end as usize) != Some(self.ids.as_slice().len())
+        {
+            return invalid("buckets");
+        }
+        let num_points = self.num_points() as u32;
+        if self.ids.as_slice().iter().any(|&id| id >= num_points) {
+            return invalid("point ids");
+        }
+        Ok(())
+    }
+}
+
+fn normalize(point: &[f32]) -> Option<Vec<f32>> {
+    let norm = point.iter().map(|x| x * x).sum::<f32>().sqrt();
+    (norm > 0.0).then(|| point.iter().map(|x| x / norm).collect())
+}
+
+impl<M> ClusterBackend<M> for CrossPolytopeIndex
+where
+    M: MetricData<DataType = f32>,
+{
+    fn build(data: &M, indices: &[usize], num_tables: usize) -> Result<(Self, usize), String> {
+        if num_tables == 0 {
+            return Err("Cross-polytope index needs at least one table".to_string());
+        }
+
+        let dimensions = data.dimensions();
+        let rotated_dims = dimensions.clamp(1, MAX_ROTATED_DIMS);
+        let hashes_per_table = ((indices.len() as f64 / TARGET_BUCKET_SIZE).ln()
+            / (2.0 * rotated_dims as f64).ln())
+        .ceil()
+        .max(1.0) as usize;
+
+        let mut points = Vec::with_capacity(indices.len() * dimensions);
+        for &i in indices {
+            // zero vectors have no direction, they are kept but never collide meaningfully
+            let point = data.indexed_point(i);
+            points.extend(normalize(&point).unwrap_or_else(|| point.to_vec()));
+        }
+
+        let mut index = Self {
+            dimensions,
+            rotated_dims,
+            hashes_per_table,
+            rotations: rotations(dimensions, rotated_dims, num_tables * hashes_per_table),
+            table_starts: vec![0],
+            keys: Array::Owned(Vec::new()),
+            bucket_starts: Array::Owned(vec![0]),
+            ids: Array::Owned(Vec::new()),
+            points: Array::Owned(points),
+        };
+
+        let (mut keys, mut bucket_starts, mut ids) = (Vec::new(), vec![0u64], Vec::new());
+        for table in 0..num_tables {
+            let mut hashed: Vec<(u64, u32)> = (0..index.num_points())
+                .map(|i| (index.hash(table, index.point(i)), i as u32))
+                .collect();
+            hashed.sort_unstable();
+            for (position, &(key, id)) in hashed.iter().enumerate() {
+                if position > 0 && hashed[position - 1].0 != key {
+                    bucket_starts.push(ids.len() as u64);
+                }
+                if position == 0 || hashed[position - 1].0 != key {
+                    keys.push(key);
+                }
+                ids.push(id);
+            }
+            if !hashed.is_empty() {
+                bucket_starts.push(ids.len() as u64);
+            }
+            index.table_starts.push(keys.len());
+        }
+        index.keys = Array::Owned(keys);
+        index.bucket_starts = Array::Owned(bucket_starts);
+        index.ids = Array::Owned(ids);
+
+        let memory = index.memory_used();
+        Ok((index, memory))
+    }
+
+    fn search(&self, query: &[f32], k: usize, max_dist: f32, recall: f32) -> Result<(Vec<u32>, usize), String> {
+        if query.len() != self.dimensions {
+            return Err(format!(
+                "Query has {} dimensions, index has {}",
+                query.len(),
+                self.dimensions
+            ));
+        }
+        let Some(query) = normalize(query) else {
+            return Err("Cannot search with a zero query".to_string());
+        };
+
+        let probed_tables = ((recall.clamp(0.0, 1.0) * self.num_tables() as f32).ceil() as usize)
+            .clamp(1, self.num_tables());
+
+        let mut visited = vec![false; self.num_points()];
+        let mut heap = TopK::new(k);
+        let mut computations = 0;
+        for table in 0..probed_tables {
+            let key = self.hash(table, &query);
+            for &candidate in self.bucket(table, key) {
+                let candidate = candidate as usize;
+                if std::mem::replace(&mut visited[candidate], true) {
+                    continue;
+                }
+                let dot: f32 = self
+                    .point(candidate)
+                    .iter()
+                    .zip(&query)
+                    .map(|(a, b)| a * b)
+                    .sum();
+                let distance = 1.0 - dot;
+                computations += 1;
+                if distance <= max_dist {
+                    heap.push(distance, candidate);
+                }
+            }
+        }
+
+        let ids = heap.into_sorted_vec().into_iter().map(|(_, i)| i as u32).collect();
+        Ok((ids, computations))
+    }
+
+    /// Layout, integers little-endian, every array aligned for its elements if the bytes start
+    /// at a multiple of 8:
+    /// ```text
+    /// | header_len (u64) | header (JSON, padded to 8) | keys (u64) | bucket starts (u64) | points (f32) | ids (u32) |
+    /// ```
+    fn to_bytes(&self) -> Result<Vec<u8>, String> {
+        let mut header = serde_json::to_vec(&Layout {
+            dimensions: self.dimensions,
+            rotated_dims: self.rotated_dims,
+            hashes_per_table: self.hashes_per_table,
+            num_points: self.num_points(),
+            table_starts: self.table_starts.clone(),
+        })
+        .map_err(|e| e.to_string())?;
+        header.resize(header.len().next_multiple_of(8), b' ');
+
+        let mut bytes = Vec::with_capacity(8 + header.len() + self.memory_used());
+        bytes.extend_from_slice(&(header.len() as u64).to_le_bytes());
+        bytes.extend_from_slice(&header);
+        self.keys.extend_le(&mut bytes);
+        self.bucket_starts.extend_le(&mut bytes);
+        self.points.extend_le(&mut bytes);
+        self.ids.extend_le(&mut bytes);
+        Ok(bytes)
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        Self::restore(bytes, None)
+    }
+
+    /// Reads the tables and the points in place, if the bytes are aligned in the mapping
+    fn from_mapped(bytes: MappedBytes) -> Result<Self, String> {
+        Self::restore(&bytes, Some(&bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metricdata::AngularData;
+    use crate::utils::{datagen::unit_vectors, test_dir};
+
+    type Data = AngularData<ndarray::OwnedRepr<f32>>;
+
+    #[test]
+    fn test_finds_indexed_point() {
+        let data = AngularData::new(unit_vectors(500, 25, 1));
+        let indices: Vec<usize> = (0..500).step_by(2).collect();
+        let (index, memory) =
+            <CrossPolytopeIndex as ClusterBackend<Data>>::build(&data, &indices, 10).unwrap();
+        assert!(memory > 0);
+
+        // the point itself hashes to the same bucket in every table
+        let (results, computations) =
+            ClusterBackend::<Data>::search(&index, &data.get_point(40), 5, 2.0, 0.5).unwrap();
+        assert_eq!(results[0], 20);
+        assert!(computations > 0);
+    }
+
+    #[test]
+    fn test_bytes_roundtrip() {
+        let data = AngularData::new(unit_vectors(200, 10, 2));
+        let indices: Vec<usize> = (0..200).collect();
+        let (index, _) =
+            <CrossPolytopeIndex as ClusterBackend<Data>>::build(&data, &indices, 4).unwrap();
+
+        let bytes = ClusterBackend::<Data>::to_bytes(&index).unwrap();
+        let restored = <CrossPolytopeIndex as ClusterBackend<Data>>::from_bytes(&bytes).unwrap();
+
+        let query = &*data.get_point(7);
+        assert_eq!(
+            ClusterBackend::<Data>::search(&index, query, 10, 2.0, 1.0).unwrap(),
+            ClusterBackend::<Data>::search(&restored, query, 10, 2.0, 1.0).unwrap()
+        );
+    }
+
+    /// Writes `prefix` followed by the bytes of `index` to a file of the test `name` and maps it
+    fn mapped(name: &str, prefix: &[u8], index: &CrossPolytopeIndex) -> MappedBytes {
+        let path = test_dir(name).join("index.bin");
+        let mut bytes = prefix.to_vec();
+        bytes.extend(ClusterBackend::<Data>::to_bytes(index).unwrap());
+        std::fs::write(&path, bytes).unwrap();
+        let file = std::fs::File::open(&path).unwrap();
+        MappedBytes::new(unsafe { memmap2::Mmap::map(&file) }.unwrap())
+    }
+
+    #[test]
+    fn test_mapped_in_place() {
+        let data = AngularData::new(unit_vectors(200, 10, 3));
+        let indices: Vec<usize> = (0..200).collect();
+        let (index, _) =
+            <CrossPolytopeIndex as ClusterBackend<Data>>::build(&data, &indices, 4).unwrap();
+
+        let aligned = mapped("crosspolytope_mapped", &[], &index);
+        let restored = <CrossPolytopeIndex as ClusterBackend<Data>>::from_mapped(aligned).unwrap();
+        assert_eq!(restored.is_mapped(), cfg!(target_endian = "little"));
+
+        // a misaligned start falls back to a copy of the arrays
+        let file = mapped("crosspolytope_misaligned", &[0], &index);
+        let misaligned = file.slice(1..file.len());
+        let copied = <CrossPolytopeIndex as ClusterBackend<Data>>::from_mapped(misaligned).unwrap();
+        assert!(!copied.is_mapped());
+
+        for query in [7, 42, 199] {
+            let query = &*data.get_point(query);
+            let expected = ClusterBackend::<Data>::search(&index, query, 10, 2.0, 1.0).unwrap();
+            assert_eq!(ClusterBackend::<Data>::search(&restored, query, 10, 2.0, 1.0).unwrap(), expected);
+            assert_eq!(ClusterBackend::<Data>::search(&copied, query, 10, 2.0, 1.0).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_rejects_corrupted_bytes() {
+        let data = AngularData::new(unit_vectors(100, 10, 4));
+        let indices: Vec<usize> = (0..100).collect();
+        let (index, _) =
+            <CrossPolytopeIndex as ClusterBackend<Data>>::build(&data, &indices, 2).unwrap();
+        let bytes = ClusterBackend::<Data>::to_bytes(&index).unwrap();
+
+        let truncated = &bytes[..bytes.len() - 4];
+        assert!(<CrossPolytopeIndex as ClusterBackend<Data>>::from_bytes(truncated).is_err());
+
+        // the last id points past the indexed points
+        let mut corrupted = bytes.clone();
+        let end = corrupted.len();
+        corrupted[end - 4..].copy_from_slice(&1000u32.to_le_bytes());
+        assert!(<CrossPolytopeIndex as ClusterBackend<Data>>::from_bytes(&corrupted).is_err());
+    }
+}
@@ -0,0 +1,5 @@
+//! Pure-Rust LSH indices, usable as [`ClusterBackend`](crate::core::ClusterBackend) instead of PUFFINN.
+
+pub(crate) mod crosspolytope;
+
+pub use self::crosspolytope::CrossPolytopeIndex;
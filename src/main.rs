@@ -1,108 +1,496 @@
-use std::{env, fs, time::{Duration, Instant}};
+use std::{
+    error::Error,
+    fs,
+    io::{self, Write},
+    path::Path,
+    process::ExitCode,
+    time::{Duration, Instant},
+};
 
-use clann::{build, core::{Config, MetricsGranularity, MetricsOutput}, init_from_file, init_with_config, metricdata::AngularData, save_metrics, search, serialize, utils::load_hdf5_dataset};
+use clann::{
+    bench::{load_configs, run_configs, BenchOptions, RunStatus},
+    build,
+    core::{BuildPhase, ClusteredIndex, Config, Distribution, MetricsGranularity},
+    eval::{evaluate, EvalParams, GroundTruth, OodParams},
+    init_from_file, init_from_mmap, init_with_config,
+    metricdata::AngularData,
+    save_metrics, search, serialize, serialize_binary, stats_from_file,
+    utils::{load_hdf5_dataset, Hdf5Dataset, DEFAULT_RECALL_EPSILON},
+};
+use clap::{value_parser, Arg, ArgAction, ArgMatches, Command};
 use indicatif::{ProgressBar, ProgressStyle};
 use log::info;
+use ndarray::OwnedRepr;
 
-fn main() {
+type Index = ClusteredIndex<AngularData<OwnedRepr<f32>>>;
+
+fn cli() -> Command {
+    let dataset = Arg::new("dataset")
+        .value_name("DATASET")
+        .required(true)
+        .help("HDF5 dataset in the ann-benchmarks format (train, test, neighbors, distances)");
+    let index = Arg::new("index")
+        .long("index")
+        .short('i')
+        .value_name("FILE")
+        .required(true)
+        .help("Index file written by `clann build`, .h5 or .bin");
+    let k = Arg::new("k")
+        .short('k')
+        .value_name("K")
+        .value_parser(value_parser!(usize))
+        .help("Number of nearest neighbors, overrides the one of the index configuration");
+    let output = Arg::new("output").long("output").short('o').value_name("PATH");
+
+    Command::new("clann")
+        .about("Clustered LSH-based nearest neighbors search")
+        .subcommand_required(true)
+        .arg_required_else_help(true)
+        .subcommand(
+            Command::new("build")
+                .about("Builds an index on the train set of a dataset and serializes it")
+                .arg(dataset.clone())
+                .arg(
+                    Arg::new("config")
+                        .long("config")
+                        .short('c')
+                        .value_name("FILE")
+                        .help("TOML, YAML or JSON configuration of the index, missing keys are defaults"),
+                )
+                .arg(k.clone())
+                .arg(
+                    output
+                        .clone()
+                        .default_value(".")
+                        .help("Directory where the index file is written"),
+                )
+                .arg(
+                    Arg::new("binary")
+                        .long("binary")
+                        .action(ArgAction::SetTrue)
+                        .help("Write the memory-mappable binary format instead of HDF5"),
+                ),
+        )
+        .subcommand(
+            Command::new("search")
+                .about("Searches the test set of a dataset, writing the neighbors as CSV")
+                .arg(dataset.clone())
+                .arg(index.clone())
+                .arg(k.clone())
+                .arg(output.clone().help("CSV file of the results, standard output if omitted"))
+                .arg(
+                    Arg::new("metrics")
+                        .long("metrics")
+                        .value_name("PATH")
+                        .help("Save the metrics of the run with their recall, needs metrics enabled in the index configuration. PATH is the SQLite database of `MetricsOutput::DB`"),
+                ),
+        )
+        .subcommand(
+            Command::new("eval")
+                .about("Measures recall, throughput and latency on the test set of a dataset")
+                .arg(dataset.clone())
+                .arg(index.clone())
+                .arg(k)
+                .arg(output.help("JSON file of the report, standard output if omitted"))
+                .arg(
+                    Arg::new("diagnose-misses")
+                        .long("diagnose-misses")
+                        .action(ArgAction::SetTrue)
+                        .help("Tell for every missed true neighbor whether its cluster was pruned, not reached or probed"),
+                )
+                .arg(
+                    Arg::new("ood")
+                        .long("ood")
+                        .value_name("N")
+                        .value_parser(value_parser!(usize))
+                        .help("Also evaluate N out-of-distribution queries, test queries with as much Gaussian noise as their norm, reported apart"),
+                )
+                .arg(
+                    Arg::new("recall-epsilon")
+                        .long("recall-epsilon")
+                        .value_name("EPSILON")
+                        .value_parser(value_parser!(f32))
+                        .help("Tolerance of the k-th true distance, for datasets without neighbor ids [default: 0.001]"),
+                ),
+        )
+        .subcommand(
+            Command::new("bench")
+                .about("Runs a list of configurations and a PUFFINN baseline, saving the results to SQLite. Configurations already saved for this commit are skipped")
+                .arg(
+                    Arg::new("configs")
+                        .value_name("CONFIGS")
+                        .required(true)
+                        .help("TOML, YAML or JSON list of configurations, datasets are found by their name"),
+                )
+                .arg(
+                    Arg::new("db")
+                        .long("db")
+                        .value_name("PATH")
+                        .help("SQLite database of the results [default: ./results_v2.sqlite3]"),
+                )
+                .arg(
+                    Arg::new("index-dir")
+                        .long("index-dir")
+                        .value_name("DIR")
+                        .help("Directory of the cached indexes [default: ./__index_cache__]"),
+                )
+                .arg(
+                    Arg::new("datasets")
+                        .long("datasets")
+                        .value_name("DIR")
+                        .help("Directory of the HDF5 datasets, named <dataset_name>.hdf5 [default: ./datasets]"),
+                )
+                .arg(
+                    Arg::new("rerun")
+                        .long("rerun")
+                        .action(ArgAction::SetTrue)
+                        .help("Run the configurations already in the database again"),
+                )
+                .arg(
+                    Arg::new("no-puffinn")
+                        .long("no-puffinn")
+                        .action(ArgAction::SetTrue)
+                        .help("Skip the PUFFINN baseline"),
+                ),
+        )
+        .subcommand(
+            Command::new("info")
+                .about("Prints the configuration and the clusters of an index, without loading its dataset")
+                .arg(
+                    Arg::new("index")
+                        .value_name("INDEX")
+                        .required(true)
+                        .help("Index file written by `clann build`, .h5 or .bin"),
+                ),
+        )
+}
+
+fn main() -> ExitCode {
     env_logger::Builder::from_default_env()
         .format_timestamp_millis()
         .init();
 
-    let args: Vec<String> = env::args().collect();
-    info!("Starting search benchmark");
-    let total_start = Instant::now();
+    let result = match cli().get_matches().subcommand() {
+        Some(("build", args)) => build_command(args),
+        Some(("search", args)) => search_command(args),
+        Some(("eval", args)) => eval_command(args),
+        Some(("bench", args)) => bench_command(args),
+        Some(("info", args)) => info_command(args),
+        _ => unreachable!("a subcommand is required"),
+    };
 
-    const DB_PATH: &str = "./results_v2.sqlite3";
-    const INDEX_DIR: &str = "./__index_cache__";
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
 
-    let hdf5_dataset = load_hdf5_dataset("./datasets/glove-25-angular.hdf5").unwrap();
-    let data = AngularData::new(hdf5_dataset.dataset_array);
+fn build_command(args: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let dataset_path = args.get_one::<String>("dataset").unwrap();
+    let dataset = load_dataset(dataset_path)?;
 
-    let config = Config{
-        num_tables: 84,
-        num_clusters_factor: 0.4,
-        k: 10,
-        delta: 0.9,
-        dataset_name: "glove-25-angular".to_owned(),
-        metrics_output: MetricsOutput::DB,
+    let mut config = match args.get_one::<String>("config") {
+        Some(path) => Config::from_file(path)?,
+        None => Config::default(),
     };
+    if let Some(&k) = args.get_one::<usize>("k") {
+        config.k = k;
+    }
+    if config.dataset_name.is_empty() {
+        // the file name of the index is made from the dataset name
+        config.dataset_name = Path::new(dataset_path)
+            .file_stem()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_default();
+    }
 
-    let index_path = format!(
-        "{}/index_{}_k{:.2}_L{}.h5",
-        INDEX_DIR, config.dataset_name, config.num_clusters_factor, config.num_tables
-    );
+    let mut index = init_with_config(AngularData::new(dataset.dataset_array), config)?;
+    let bar = build_progress_bar();
+    let observed = bar.clone();
+    index.set_build_observer(move |phase, _, fraction, eta| update_progress_bar(&observed, phase, fraction, eta));
+    let start = Instant::now();
+    let built = build(&mut index);
+    bar.finish_and_clear();
+    built?;
+    info!("Index with {} clusters built in {:.2?}", index.num_clusters(), start.elapsed());
 
-    let mut index = if fs::metadata(&index_path).is_ok() {
-        info!("Loading index from file: {}", index_path);
-        init_from_file(data, &index_path).unwrap()
+    let output = args.get_one::<String>("output").unwrap();
+    if args.get_flag("binary") {
+        serialize_binary(&index, output)?;
     } else {
-        info!("No saved index found, initializing a new one");
-        let mut new_index = init_with_config(data, config).unwrap();
-        build(&mut new_index).map_err(|e| eprintln!("Error: {}", e)).unwrap();
-        serialize(&new_index, INDEX_DIR).unwrap();
-        new_index
+        serialize(&index, output)?;
+    }
+    info!("Index written to {}", output);
+
+    Ok(())
+}
+
+/// Resolution of the build progress bar, which is driven by the fraction of each phase
+const PROGRESS_STEPS: u64 = 1000;
+
+/// Progress bar of a build on stderr, hidden if stderr is not a terminal
+fn build_progress_bar() -> ProgressBar {
+    let bar = ProgressBar::new(PROGRESS_STEPS);
+    bar.set_style(
+        ProgressStyle::with_template("{prefix:>10} [{bar:40}] {percent:>3}% {msg}")
+            .expect("valid template")
+            .progress_chars("=> "),
+    );
+    bar
+}
+
+/// Shows the phase, fraction and estimated time left of a build, as reported to its observer
+fn update_progress_bar(bar: &ProgressBar, phase: BuildPhase, fraction: f32, eta: Option<Duration>) {
+    let phase = match phase {
+        BuildPhase::Clustering => "clustering",
+        BuildPhase::Indexing => "indexing",
     };
+    bar.set_prefix(phase);
+    bar.set_position((fraction.clamp(0.0, 1.0) * PROGRESS_STEPS as f32) as u64);
+    bar.set_message(match eta {
+        Some(eta) => format!("ETA {}", indicatif::HumanDuration(eta)),
+        None => "ETA unknown".to_string(),
+    });
+}
 
-    info!("Processing {} queries", hdf5_dataset.dataset_queries.nrows());
-    let mut distance_results = Vec::with_capacity(hdf5_dataset.dataset_queries.nrows());
-    
-    let progress_bar = ProgressBar::new(hdf5_dataset.dataset_queries.nrows() as u64);
-    progress_bar.set_style(ProgressStyle::default_bar()
-        .template("[{elapsed_precise}] {bar:40.cyan/blue} {pos:>7}/{len:7} {msg}")
-        .expect("Failed to set progress bar style")
-        .progress_chars("=>-"));
-
-    let mut total_queries = 0u32;
-    let mut min_search_time = Duration::from_secs(u64::MAX);
-    let mut max_search_time = Duration::from_secs(0);
-    let mut total_search_time = Duration::from_secs(0);
-
-    for (i, query) in hdf5_dataset.dataset_queries.rows().into_iter().enumerate() {
-        let query_start = Instant::now();
-        let result = search(&mut index, query.as_slice().unwrap()).unwrap();
-        let query_time = query_start.elapsed();
-
-        total_queries += 1;
-        total_search_time += query_time;
-        min_search_time = min_search_time.min(query_time);
-        max_search_time = max_search_time.max(query_time);
-
-        let distances: Vec<f32> = result.iter()
-            .map(|&(distance, _)| distance)
+fn search_command(args: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let (dataset, mut index) = load_index(args)?;
+
+    let start = Instant::now();
+    let mut results = Vec::with_capacity(dataset.dataset_queries.nrows());
+    for query in dataset.dataset_queries.rows() {
+        results.push(search(&mut index, &query.to_vec())?);
+    }
+    let elapsed = start.elapsed();
+    info!(
+        "{} queries searched in {:.2?}, {:.1} QPS",
+        results.len(),
+        elapsed,
+        results.len() as f64 / elapsed.as_secs_f64()
+    );
+
+    if let Some(path) = args.get_one::<String>("metrics") {
+        let distances: Vec<Vec<f32>> = results
+            .iter()
+            .map(|r| r.iter().map(|&(d, _)| d).collect())
             .collect();
-        distance_results.push(distances);
+        save_metrics(
+            &mut index,
+            path,
+            MetricsGranularity::Query,
+            &dataset.ground_truth_distances,
+            &distances,
+            &elapsed,
+        )?;
+        info!("Metrics saved to {}", path);
+    }
 
-        if (i + 1) % 1000 == 0 {
-            progress_bar.set_message(format!(
-                "Min: {:?} Max: {:?}", 
-                min_search_time, max_search_time
-            ));
+    let mut wtr = csv::Writer::from_writer(output_writer(args)?);
+    wtr.write_record(["query_idx", "rank", "point", "distance"])?;
+    for (query_idx, result) in results.iter().enumerate() {
+        for (rank, (distance, point)) in result.iter().enumerate() {
+            wtr.write_record([
+                query_idx.to_string(),
+                rank.to_string(),
+                point.to_string(),
+                distance.to_string(),
+            ])?;
         }
-        
-        progress_bar.inc(1);
+    }
+    wtr.flush()?;
+
+    Ok(())
+}
+
+fn eval_command(args: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let (dataset, mut index) = load_index(args)?;
+
+    let params = EvalParams {
+        k: index.config().k,
+        diagnose_misses: args.get_flag("diagnose-misses"),
+        ood: args.get_one::<usize>("ood").map(|&num_queries| OodParams {
+            num_queries,
+            ..Default::default()
+        }),
+        recall_epsilon: args.get_one::<f32>("recall-epsilon").copied().unwrap_or(DEFAULT_RECALL_EPSILON),
+        ..Default::default()
+    };
+    let ground_truth = match &dataset.ground_truth_neighbors {
+        Some(neighbors) => GroundTruth::Ids(neighbors),
+        None => GroundTruth::Distances(&dataset.ground_truth_distances),
+    };
+    let report = evaluate(&mut index, &dataset.dataset_queries, ground_truth, &params)?;
+    info!(
+        "recall {:.3} at {:.1} QPS",
+        report.recall_mean, report.queries_per_second
+    );
+    if let Some(ood) = &report.out_of_distribution {
+        info!(
+            "out-of-distribution recall {:.3} at {:.1} QPS",
+            ood.recall_mean, ood.queries_per_second
+        );
+    }
+    if let Some(counts) = &report.miss_counts {
+        info!(
+            "missed neighbors: {} pruned, {} unvisited, {} not returned by PUFFINN",
+            counts.pruned, counts.unvisited, counts.not_returned
+        );
     }
 
-    progress_bar.finish_with_message("Search complete");
+    let mut out = output_writer(args)?;
+    serde_json::to_writer_pretty(&mut out, &report)?;
+    writeln!(out)?;
 
-    let average_time = total_search_time / total_queries;
+    Ok(())
+}
 
-    info!("All queries processed in {:?}", total_search_time);
-    info!("Average query time: {:?}", average_time);
-    info!("Min query time: {:?}", min_search_time);
-    info!("Max query time: {:?}", max_search_time);
-    info!("Total results: {}", distance_results.len());
+fn bench_command(args: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let configs = load_configs(args.get_one::<String>("configs").unwrap())?;
 
-    if args.len() > 1 && &args[1] == "--save" {
-        info!("Saving metrics to {}", DB_PATH);
-        save_metrics(&mut index, 
-            DB_PATH,
-            MetricsGranularity::Cluster,
-            &hdf5_dataset.ground_truth_distances,
-            &distance_results,
-            &total_search_time
-        ).unwrap();
+    let mut options = BenchOptions {
+        rerun: args.get_flag("rerun"),
+        puffinn_baseline: !args.get_flag("no-puffinn"),
+        ..Default::default()
+    };
+    if let Some(path) = args.get_one::<String>("db") {
+        options.db_path = path.clone();
     }
+    if let Some(dir) = args.get_one::<String>("index-dir") {
+        options.index_dir = dir.clone();
+    }
+    if let Some(dir) = args.get_one::<String>("datasets") {
+        options.dataset_dir = dir.clone();
+    }
+
+    let outcomes = run_configs(&configs, &options)?;
+    let mut failed = 0;
+    for outcome in &outcomes {
+        let status = |s: &RunStatus| match s {
+            RunStatus::Ran => "ran".to_string(),
+            RunStatus::Skipped => "skipped".to_string(),
+            RunStatus::Failed(e) => format!("failed ({})", e),
+        };
+        let config = &outcome.config;
+        print!(
+            "{} k={} delta={} clusters={} L={}: CLANN {}",
+            config.dataset_name,
+            config.k,
+            config.delta,
+            config.num_clusters_factor,
+            config.num_tables,
+            status(&outcome.clann)
+        );
+        if let Some(puffinn) = &outcome.puffinn {
+            print!(", PUFFINN {}", status(puffinn));
+        }
+        println!();
+
+        let statuses = std::iter::once(&outcome.clann).chain(&outcome.puffinn);
+        failed += statuses.filter(|s| matches!(s, RunStatus::Failed(_))).count();
+    }
+    info!("Results saved to {}", options.db_path);
+
+    if failed > 0 {
+        return Err(format!("{} runs failed", failed).into());
+    }
+    Ok(())
+}
+
+fn info_command(args: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let path = args.get_one::<String>("index").unwrap();
+    let (config, stats) = stats_from_file(path)?;
+
+    println!("{}", serde_json::to_string_pretty(&config)?);
+    println!("points: {}", stats.num_points);
+    println!(
+        "clusters: {} ({} brute force)",
+        stats.num_clusters, stats.brute_force_clusters
+    );
+    let distribution = |name: &str, d: &Distribution| {
+        println!(
+            "{}: min {:.3}, mean {:.3}, p50 {:.3}, p90 {:.3}, p99 {:.3}, max {:.3}",
+            name, d.min, d.mean, d.p50, d.p90, d.p99, d.max
+        )
+    };
+    distribution("cluster sizes", &stats.sizes);
+    distribution("cluster radii", &stats.radii);
+    println!("size histogram:");
+    for bin in &stats.size_histogram {
+        println!("  [{}, {}): {}", bin.min, bin.max, bin.count);
+    }
+    println!(
+        "memory: {} bytes, {} bytes of assignments",
+        stats.total_memory, stats.memory.assignments
+    );
+    println!("PUFFINN kernels: {}", stats.isa);
 
-    info!("Benchmark completed in {:?}", total_start.elapsed());
-}
\ No newline at end of file
+    Ok(())
+}
+
+fn load_dataset(path: &str) -> Result<Hdf5Dataset, Box<dyn Error>> {
+    info!("Loading dataset {}", path);
+    Ok(load_hdf5_dataset(path)?)
+}
+
+/// Loads the dataset and the index of a command, applying its `k`
+fn load_index(args: &ArgMatches) -> Result<(Hdf5Dataset, Index), Box<dyn Error>> {
+    let mut dataset = load_dataset(args.get_one::<String>("dataset").unwrap())?;
+    let data = AngularData::new(std::mem::take(&mut dataset.dataset_array));
+
+    let path = args.get_one::<String>("index").unwrap();
+    info!("Loading index {}", path);
+    let mut index = if path.ends_with(".bin") {
+        init_from_mmap(data, path)?
+    } else {
+        init_from_file(data, path)?
+    };
+    if let Some(&k) = args.get_one::<usize>("k") {
+        index.set_k(k)?;
+    }
+
+    Ok((dataset, index))
+}
+
+fn output_writer(args: &ArgMatches) -> Result<Box<dyn Write>, Box<dyn Error>> {
+    Ok(match args.get_one::<String>("output") {
+        Some(path) => Box::new(fs::File::create(path)?),
+        None => Box::new(io::stdout().lock()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::cli;
+
+    #[test]
+    fn test_cli() {
+        cli().debug_assert();
+
+        let matches = cli()
+            .try_get_matches_from(["clann", "search", "data.hdf5", "--index", "index.h5", "-k", "5"])
+            .unwrap();
+        let (name, args) = matches.subcommand().unwrap();
+        assert_eq!(name, "search");
+        assert_eq!(args.get_one::<usize>("k"), Some(&5));
+
+        assert!(cli().try_get_matches_from(["clann", "eval", "data.hdf5"]).is_err());
+        let matches = cli()
+            .try_get_matches_from(["clann", "eval", "data.hdf5", "-i", "index.h5", "--ood", "50", "--recall-epsilon", "1e-5"])
+            .unwrap();
+        assert_eq!(matches.subcommand().unwrap().1.get_one::<usize>("ood"), Some(&50));
+        assert_eq!(matches.subcommand().unwrap().1.get_one::<f32>("recall-epsilon"), Some(&1e-5));
+        assert!(cli().try_get_matches_from(["clann", "info", "index.h5"]).is_ok());
+
+        let matches = cli()
+            .try_get_matches_from(["clann", "bench", "configs.json", "--db", "results.sqlite3", "--no-puffinn"])
+            .unwrap();
+        let (name, args) = matches.subcommand().unwrap();
+        assert_eq!(name, "bench");
+        assert_eq!(args.get_one::<String>("db").map(String::as_str), Some("results.sqlite3"));
+        assert!(args.get_flag("no-puffinn"));
+        assert!(!args.get_flag("rerun"));
+    }
+}
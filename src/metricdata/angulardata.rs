@@ -1,44 +1,219 @@
-use ndarray::{prelude::*, Data, OwnedRepr};
+use std::borrow::Cow;
 
-use crate::metricdata::{MetricData, Subset};
+use ndarray::{prelude::*, Data, ErrorKind, OwnedRepr, RawDataClone, ShapeError, ViewRepr};
 
+use crate::metricdata::{checked_norm, matvec, simd, Element, Insertable, MetricData, Precision, Subset};
+
+/// Dataset under the angular (cosine) distance.
+///
+/// The points can be stored as `f32`, [`f16`](crate::metricdata::f16) or
+/// [`bf16`](crate::metricdata::bf16), see [`from_f32`](AngularData::from_f32). Queries are always
+/// `f32`, and distances are accumulated in f32 whatever the storage.
 #[derive(Clone)]
-pub struct AngularData<S: Data<Elem=f32> + ndarray::RawDataClone> {
+pub struct AngularData<S: Data + RawDataClone>
+where
+    S::Elem: Element,
+{
     data: ArrayBase<S, Ix2>,
     norms: Array1<f32>,
+    /// Whether the points are scaled to unit norm, the inserted ones included
+    unit_norm: bool,
 }
 
-impl<S: Data<Elem = f32> + ndarray::RawDataClone> AngularData<S> {
+impl<S: Data + RawDataClone> AngularData<S>
+where
+    S::Elem: Element,
+{
     pub fn new(data: ArrayBase<S, Ix2>) -> Self {
-        let norms = data.rows().into_iter().map(|row| row.dot(&row).sqrt()).collect();
+        let mut buf = Vec::new();
+        let norms = (0..data.nrows())
+            .map(|i| {
+                let row = Self::row_f32(&data, i, &mut buf);
+                simd::dot(row, row).sqrt()
+            })
+            .collect();
 
         Self {
             data,
             norms,
+            unit_norm: false,
         }
     }
+
+    /// Row `i` of `data` in f32: borrowed when stored as contiguous f32, otherwise widened into `buf`
+    fn row_f32<'a>(data: &'a ArrayBase<S, Ix2>, i: usize, buf: &'a mut Vec<f32>) -> &'a [f32] {
+        let row = data.row(i);
+        if let Some(row) = row.to_slice().and_then(S::Elem::as_f32) {
+            return row;
+        }
+
+        buf.resize(row.len(), 0.0);
+        match row.to_slice() {
+            Some(row) => S::Elem::widen(row, buf),
+            None => {
+                for (b, &x) in buf.iter_mut().zip(row) {
+                    *b = x.to_f32();
+                }
+            }
+        }
+        buf
+    }
+
+    /// Dot product of row `i` with `point`, with the SIMD kernel
+    fn dot_row(&self, i: usize, point: &[f32], buf: &mut Vec<f32>) -> f32 {
+        simd::dot(Self::row_f32(&self.data, i, buf), point)
+    }
 }
 
-impl<S: Data<Elem = f32> + ndarray::RawDataClone> MetricData for AngularData<S> {
-    type DataType = S::Elem;
+impl<E: Element> AngularData<OwnedRepr<E>> {
+    /// Stores `data` with the precision of `E`, e.g. `AngularData::<OwnedRepr<f16>>::from_f32(&points)`
+    /// for half the memory of `f32` storage.
+    pub fn from_f32<V: Data<Elem = f32>>(data: &ArrayBase<V, Ix2>) -> Self {
+        Self::new(data.mapv(E::from_f32))
+    }
+
+    /// Stores the rows of `data` scaled to unit norm with the precision of `E`, and scales the
+    /// points inserted later as well. The angular distance doesn't depend on the norms, but the
+    /// stored points can then be compared with other unit-norm vectors by their dot product.
+    ///
+    /// # Errors
+    /// If a row has a NaN or infinite component, or a zero norm, its distance to the others
+    /// being undefined
+    pub fn normalized<V: Data<Elem = f32>>(data: &ArrayBase<V, Ix2>) -> Result<Self, String> {
+        let mut scaled = Vec::with_capacity(data.len());
+        for (i, row) in data.outer_iter().enumerate() {
+            let norm = match row.as_slice() {
+                Some(row) => checked_norm(row, true),
+                None => checked_norm(&row.to_vec(), true),
+            }
+            .map_err(|e| format!("row {} {}", i, e))? as f32;
+            scaled.extend(row.iter().map(|&x| E::from_f32(x / norm)));
+        }
+        let scaled = Array2::from_shape_vec(data.raw_dim(), scaled).map_err(|e| e.to_string())?;
+        Ok(Self {
+            unit_norm: true,
+            ..Self::new(scaled)
+        })
+    }
+
+    /// Whether the points are scaled to unit norm, see [`normalized`](Self::normalized)
+    pub fn is_normalized(&self) -> bool {
+        self.unit_norm
+    }
+}
+
+impl AngularData<OwnedRepr<f32>> {
+    /// Stores `rows` in one contiguous array, moving the points out of the rows one at a time.
+    ///
+    /// # Errors
+    /// If the rows don't all have the same length
+    pub fn from_rows(rows: Vec<Vec<f32>>) -> Result<Self, ShapeError> {
+        let ncols = rows.first().map_or(0, Vec::len);
+        let nrows = rows.len();
+        let mut flat = Vec::with_capacity(nrows * ncols);
+        for row in rows {
+            if row.len() != ncols {
+                return Err(ShapeError::from_kind(ErrorKind::IncompatibleShape));
+            }
+            flat.extend(row);
+        }
+        Ok(Self::new(Array2::from_shape_vec((nrows, ncols), flat)?))
+    }
+}
+
+impl<'a> AngularData<ViewRepr<&'a f32>> {
+    /// Borrows the points of `data` without copying them, e.g. to build several indexes over the
+    /// same dataset. Only the norms are allocated.
+    pub fn from_view(data: ArrayView2<'a, f32>) -> Self {
+        Self::new(data)
+    }
+
+    /// Borrows `data` as `shape.0` points of `shape.1` dimensions, stored row after row.
+    ///
+    /// # Errors
+    /// If the length of `data` is not `shape.0 * shape.1`
+    pub fn from_slice(data: &'a [f32], shape: (usize, usize)) -> Result<Self, ShapeError> {
+        Ok(Self::new(ArrayView2::from_shape(shape, data)?))
+    }
+}
+
+impl<S: Data + RawDataClone> MetricData for AngularData<S>
+where
+    S::Elem: Element,
+{
+    type DataType = f32;
 
     fn distance(&self, i: usize, j: usize) -> f32 {
-        1.0 - ( self.data.row(i).dot(&self.data.row(j)) / (self.norms[i] * self.norms[j]) )
+        let (mut buf_i, mut buf_j) = (Vec::new(), Vec::new());
+        let dot_product = simd::dot(
+            Self::row_f32(&self.data, i, &mut buf_i),
+            Self::row_f32(&self.data, j, &mut buf_j),
+        );
+        1.0 - ( dot_product / (self.norms[i] * self.norms[j]) )
     }
 
-    fn distance_point(&self, i: usize, point: &[Self::DataType]) -> f32 { 
-        let dot_product = self.data.row(i).dot(&ndarray::ArrayView1::from(point));
-        let norm_point = point.iter().map(|&x| x * x).sum::<f32>().sqrt();
-    
+    fn distance_point(&self, i: usize, point: &[Self::DataType]) -> f32 {
+        let dot_product = self.dot_row(i, point, &mut Vec::new());
+        let norm_point = simd::dot(point, point).sqrt();
+
         let cosine_similarity = dot_product / (self.norms[i] * norm_point);
         1.0 - cosine_similarity
     }
-      
+
+    fn distances_points(&self, ids: &[usize], point: &[Self::DataType], out: &mut [f32]) {
+        assert_eq!(ids.len(), out.len());
+        let norm_point = simd::dot(point, point).sqrt();
+        let mut buf = Vec::new();
+        for (o, &i) in out.iter_mut().zip(ids) {
+            *o = 1.0 - self.dot_row(i, point, &mut buf) / (self.norms[i] * norm_point);
+        }
+    }
+
+    fn distance_between(&self, a: &[Self::DataType], b: &[Self::DataType]) -> Option<f32> {
+        Some(1.0 - simd::dot(a, b) / (simd::dot(a, a) * simd::dot(b, b)).sqrt())
+    }
+
+    fn distance_point_f64(&self, i: usize, point: &[Self::DataType]) -> f64 {
+        let mut dot_product = 0.0f64;
+        let mut norm_row = 0.0f64;
+        let mut norm_point = 0.0f64;
+        for (&x, &y) in self.data.row(i).iter().zip(point) {
+            let x = x.to_f32();
+            dot_product += x as f64 * y as f64;
+            norm_row += x as f64 * x as f64;
+            norm_point += y as f64 * y as f64;
+        }
+
+        1.0 - dot_product / (norm_row.sqrt() * norm_point.sqrt())
+    }
+
+
+    /// The angle between the points, `1 - cos` is not a metric
+    fn distance_to_metric(&self, distance: f32) -> f32 {
+        (1.0 - distance).clamp(-1.0, 1.0).acos()
+    }
+
+    fn metric_to_distance(&self, metric: f32) -> f32 {
+        1.0 - metric.cos()
+    }
 
     fn all_distances(&self, j: usize, out: &mut [f32]){
         assert_eq!(out.len(), self.data.nrows());
-        for (i, oo) in out.iter_mut().enumerate() {
-            *oo = self.distance(i, j);
+        let point = self.get_point(j).into_owned();
+        match self.data.as_slice().and_then(S::Elem::as_f32) {
+            Some(flat) => {
+                let data = ArrayView2::from_shape(self.data.raw_dim(), flat).unwrap();
+                matvec::dot_rows(&data, &point, out);
+            }
+            None => {
+                let mut buf = Vec::new();
+                for (i, o) in out.iter_mut().enumerate() {
+                    *o = self.dot_row(i, &point, &mut buf);
+                }
+            }
+        }
+        for (oo, norm) in out.iter_mut().zip(&self.norms) {
+            *oo = 1.0 - *oo / (norm * self.norms[j]);
         }
     }
 
@@ -50,14 +225,142 @@ impl<S: Data<Elem = f32> + ndarray::RawDataClone> MetricData for AngularData<S>
         self.data.ncols()
     }
 
-    fn get_point(&self, i: usize) -> &[Self::DataType] {
-        self.data.row(i).to_slice().unwrap()
+    fn get_point(&self, i: usize) -> Cow<'_, [Self::DataType]> {
+        let row = self.data.row(i);
+        match row.to_slice().and_then(S::Elem::as_f32) {
+            Some(row) => Cow::Borrowed(row),
+            None => Cow::Owned(row.iter().map(|x| x.to_f32()).collect()),
+        }
+    }
+
+    fn precision(&self) -> Precision {
+        S::Elem::PRECISION
+    }
+
+    /// The points in their [`Precision`] and their norms
+    fn memory_used(&self) -> usize {
+        self.data.len() * self.precision().size() + self.norms.len() * std::mem::size_of::<f32>()
+    }
+
+    fn scale_invariant(&self) -> bool {
+        true
     }
 }
 
-impl<S: Data<Elem = f32> + ndarray::RawDataClone> Subset for AngularData<S> {
-    type Out = AngularData<OwnedRepr<f32>>;
+impl<S: Data + RawDataClone> Subset for AngularData<S>
+where
+    S::Elem: Element,
+{
+    type Out = AngularData<OwnedRepr<S::Elem>>;
     fn subset(&self, indices: &[usize]) -> Self::Out {
-        AngularData::new(self.data.select(Axis(0), indices))
+        AngularData {
+            unit_norm: self.unit_norm,
+            ..AngularData::new(self.data.select(Axis(0), indices))
+        }
+    }
+}
+
+impl<E: Element> Insertable for AngularData<OwnedRepr<E>> {
+    fn insert(&mut self, point: &[f32]) -> Result<usize, String> {
+        let scale = match self.unit_norm {
+            true => checked_norm(point, true).map_err(|e| format!("point {}", e))? as f32,
+            false => 1.0,
+        };
+        let stored: Array1<E> = point.iter().map(|&x| E::from_f32(x / scale)).collect();
+        self.data.push_row(stored.view()).map_err(|e| e.to_string())?;
+        // from the stored point, as in `new`
+        let norm = stored.iter().map(|x| x.to_f32() * x.to_f32()).sum::<f32>().sqrt();
+        self.norms
+            .append(Axis(0), ArrayView1::from(&[norm]))
+            .map_err(|e| e.to_string())?;
+        Ok(self.data.nrows() - 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::OwnedRepr;
+
+    use super::AngularData;
+    use crate::metricdata::{bf16, f16, Insertable, MetricData, Precision};
+    use crate::utils::datagen::unit_vectors;
+
+    #[test]
+    fn test_half_precision_storage() {
+        let points = unit_vectors(50, 24, 1);
+        let full = AngularData::new(points.clone());
+        let half = AngularData::<OwnedRepr<f16>>::from_f32(&points);
+        let brain = AngularData::<OwnedRepr<bf16>>::from_f32(&points);
+        assert_eq!(half.precision(), Precision::F16);
+        assert_eq!(brain.precision(), Precision::BF16);
+
+        let query = full.get_point(3).into_owned();
+        let mut expected = vec![0.0; 50];
+        full.all_distances(3, &mut expected);
+        for (data, tolerance) in [(&half as &dyn MetricData<DataType = f32>, 1e-3), (&brain, 1e-2)] {
+            let mut out = vec![0.0; 50];
+            data.all_distances(3, &mut out);
+            let ids: Vec<usize> = (0..50).collect();
+            let mut batch = vec![0.0; 50];
+            data.distances_points(&ids, &query, &mut batch);
+
+            for i in 0..50 {
+                assert!((out[i] - expected[i]).abs() < tolerance);
+                assert!((batch[i] - expected[i]).abs() < tolerance);
+                assert!((data.distance(i, 3) - expected[i]).abs() < tolerance);
+                assert!((data.distance_point_f64(i, &query) as f32 - expected[i]).abs() < tolerance);
+            }
+            assert!(data.get_point(7).iter().zip(full.get_point(7).iter()).all(|(a, b)| (a - b).abs() < tolerance));
+        }
+
+        let mut half = half;
+        let id = half.insert(&query).unwrap();
+        assert!(half.distance_point(id, &query).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_normalized_storage() {
+        let points = ndarray::arr2(&[[3.0, 4.0], [0.0, -2.0]]);
+        let mut data = AngularData::<OwnedRepr<f32>>::normalized(&points).unwrap();
+        assert!(data.is_normalized());
+        assert_eq!(data.get_point(0).as_ref(), &[0.6, 0.8]);
+        assert_eq!(data.get_point(1).as_ref(), &[0.0, -1.0]);
+        assert!((data.distance(0, 1) - AngularData::new(points).distance(0, 1)).abs() < 1e-6);
+
+        let id = data.insert(&[0.0, 5.0]).unwrap();
+        assert_eq!(data.get_point(id).as_ref(), &[0.0, 1.0]);
+        assert!(data.insert(&[0.0, 0.0]).is_err());
+
+        let zero = ndarray::arr2(&[[1.0, 0.0], [0.0, 0.0]]);
+        assert_eq!(
+            AngularData::<OwnedRepr<f32>>::normalized(&zero).err().unwrap(),
+            "row 1 has zero norm"
+        );
+        let nan = ndarray::arr2(&[[f32::NAN, 0.0]]);
+        assert!(AngularData::<OwnedRepr<f32>>::normalized(&nan).is_err());
+    }
+
+    #[test]
+    fn test_borrowed_constructors() {
+        let points = unit_vectors(20, 6, 2);
+        let owned = AngularData::new(points.clone());
+        let flat = points.as_slice().unwrap();
+
+        let view = AngularData::from_view(points.view());
+        let slice = AngularData::from_slice(flat, (20, 6)).unwrap();
+        let rows = AngularData::from_rows(points.outer_iter().map(|row| row.to_vec()).collect()).unwrap();
+        let mut expected = vec![0.0; 20];
+        owned.all_distances(4, &mut expected);
+        for data in [&view as &dyn MetricData<DataType = f32>, &slice, &rows] {
+            let mut out = vec![0.0; 20];
+            data.all_distances(4, &mut out);
+            assert_eq!(out, expected);
+        }
+        // the view points to the caller's buffer
+        assert_eq!(slice.get_point(3).as_ptr(), flat[18..].as_ptr());
+
+        assert!(AngularData::from_slice(flat, (21, 6)).is_err());
+        assert!(AngularData::from_rows(vec![vec![1.0, 0.0], vec![1.0]]).is_err());
+        assert_eq!(AngularData::from_rows(Vec::new()).unwrap().num_points(), 0);
     }
 }
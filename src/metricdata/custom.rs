@@ -0,0 +1,144 @@
+use std::borrow::Cow;
+use std::sync::Arc;
+
+use ndarray::{prelude::*, ShapeError};
+
+use crate::metricdata::{Insertable, MetricData, Subset};
+
+/// Dataset under a distance given as a closure, e.g. the earth mover's distance of histograms.
+///
+/// There is no LSH family for an arbitrary distance, so every cluster over the dataset is scanned
+/// exhaustively as with [`IndexMode::Flat`](crate::core::IndexMode::Flat); the clustering, the
+/// pruning of the clusters, the metrics and the serialization of the index work as with the
+/// other datasets. The clusters are pruned with the triangle inequality: with a distance that is
+/// not a metric, some neighbors may be missed. The closure is not saved with the index, which
+/// must be loaded with a dataset of the same distance.
+pub struct CustomMetricData<F>
+where
+    F: Fn(&[f32], &[f32]) -> f32,
+{
+    data: Array2<f32>,
+    distance: Arc<F>,
+}
+
+impl<F> CustomMetricData<F>
+where
+    F: Fn(&[f32], &[f32]) -> f32,
+{
+    /// Dataset of the rows of `data`, with `distance` between two points.
+    ///
+    /// # Errors
+    /// If `data` can't be stored as contiguous rows
+    pub fn new<S: ndarray::Data<Elem = f32>>(data: &ArrayBase<S, Ix2>, distance: F) -> Result<Self, ShapeError> {
+        let data = Array2::from_shape_vec(data.raw_dim(), data.iter().copied().collect())?;
+        Ok(Self {
+            data,
+            distance: Arc::new(distance),
+        })
+    }
+
+    fn row(&self, i: usize) -> &[f32] {
+        self.data.row(i).to_slice().expect("rows are stored contiguously")
+    }
+}
+
+impl<F> Clone for CustomMetricData<F>
+where
+    F: Fn(&[f32], &[f32]) -> f32,
+{
+    fn clone(&self) -> Self {
+        Self {
+            data: self.data.clone(),
+            distance: Arc::clone(&self.distance),
+        }
+    }
+}
+
+impl<F> MetricData for CustomMetricData<F>
+where
+    F: Fn(&[f32], &[f32]) -> f32,
+{
+    type DataType = f32;
+
+    fn distance(&self, i: usize, j: usize) -> f32 {
+        (self.distance)(self.row(i), self.row(j))
+    }
+
+    fn all_distances(&self, j: usize, out: &mut [f32]) {
+        assert_eq!(out.len(), self.data.nrows());
+        let point = self.row(j);
+        for (i, o) in out.iter_mut().enumerate() {
+            *o = (self.distance)(self.row(i), point);
+        }
+    }
+
+    fn num_points(&self) -> usize {
+        self.data.nrows()
+    }
+
+    fn dimensions(&self) -> usize {
+        self.data.ncols()
+    }
+
+    fn get_point(&self, i: usize) -> Cow<'_, [f32]> {
+        Cow::Borrowed(self.row(i))
+    }
+
+    fn distance_point(&self, i: usize, point: &[f32]) -> f32 {
+        (self.distance)(self.row(i), point)
+    }
+
+    fn distance_between(&self, a: &[f32], b: &[f32]) -> Option<f32> {
+        Some((self.distance)(a, b))
+    }
+}
+
+impl<F> Subset for CustomMetricData<F>
+where
+    F: Fn(&[f32], &[f32]) -> f32,
+{
+    type Out = Self;
+    fn subset(&self, indices: &[usize]) -> Self::Out {
+        Self {
+            data: self.data.select(Axis(0), indices),
+            distance: Arc::clone(&self.distance),
+        }
+    }
+}
+
+impl<F> Insertable for CustomMetricData<F>
+where
+    F: Fn(&[f32], &[f32]) -> f32,
+{
+    fn insert(&mut self, point: &[f32]) -> Result<usize, String> {
+        self.data.push_row(ArrayView1::from(point)).map_err(|e| e.to_string())?;
+        Ok(self.data.nrows() - 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::arr2;
+
+    use super::CustomMetricData;
+    use crate::metricdata::{Insertable, MetricData, Subset};
+
+    #[test]
+    fn test_custom_metric() {
+        let manhattan = |a: &[f32], b: &[f32]| a.iter().zip(b).map(|(x, y)| (x - y).abs()).sum();
+        // a non-contiguous view is copied
+        let points = arr2(&[[0.0, 0.0], [1.0, 2.0], [3.0, -1.0]]);
+        let mut data = CustomMetricData::new(&points.t().t(), manhattan).unwrap();
+        assert_eq!(data.distance(1, 2), 5.0);
+        assert_eq!(data.distance_point(0, &[1.0, 1.0]), 2.0);
+
+        let mut out = [0.0; 3];
+        data.all_distances(0, &mut out);
+        assert_eq!(out, [0.0, 3.0, 4.0]);
+
+        let subset = data.subset(&[2, 1]);
+        assert_eq!(subset.distance(0, 1), 5.0);
+        let id = data.insert(&[1.0, 1.0]).unwrap();
+        assert_eq!(data.distance(id, 1), 1.0);
+    }
+}
@@ -0,0 +1,97 @@
+//! Element types the datasets can be stored in. Half-precision storage halves the memory of the
+//! dataset, distances are still accumulated in f32: rows are widened to f32 before the kernels.
+
+use half::{bf16, f16, slice::HalfFloatSliceExt};
+use serde::{Deserialize, Serialize};
+
+/// Precision of the stored vectors of a dataset
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Precision {
+    #[default]
+    F32,
+    /// IEEE 754 half precision, 11 bits of mantissa
+    F16,
+    /// bfloat16, the exponent range of f32 with 8 bits of mantissa
+    BF16,
+}
+
+impl Precision {
+    /// Bytes of a stored component
+    pub fn size(self) -> usize {
+        match self {
+            Precision::F32 => 4,
+            Precision::F16 | Precision::BF16 => 2,
+        }
+    }
+}
+
+/// Type of the stored components of a dataset: `f32`, [`f16`] or [`bf16`].
+pub trait Element: Copy + Send + Sync + 'static {
+    const PRECISION: Precision;
+
+    fn to_f32(self) -> f32;
+    fn from_f32(x: f32) -> Self;
+
+    /// `src` widened to f32 in `dst`
+    fn widen(src: &[Self], dst: &mut [f32]) {
+        assert_eq!(src.len(), dst.len());
+        for (d, &s) in dst.iter_mut().zip(src) {
+            *d = s.to_f32();
+        }
+    }
+
+    /// `src` itself when it is already f32, so that it can be used without a copy
+    fn as_f32(_src: &[Self]) -> Option<&[f32]> {
+        None
+    }
+}
+
+impl Element for f32 {
+    const PRECISION: Precision = Precision::F32;
+
+    fn to_f32(self) -> f32 {
+        self
+    }
+
+    fn from_f32(x: f32) -> Self {
+        x
+    }
+
+    fn as_f32(src: &[Self]) -> Option<&[f32]> {
+        Some(src)
+    }
+}
+
+impl Element for f16 {
+    const PRECISION: Precision = Precision::F16;
+
+    fn to_f32(self) -> f32 {
+        f16::to_f32(self)
+    }
+
+    fn from_f32(x: f32) -> Self {
+        f16::from_f32(x)
+    }
+
+    // F16C on x86_64 when available
+    fn widen(src: &[Self], dst: &mut [f32]) {
+        src.convert_to_f32_slice(dst);
+    }
+}
+
+impl Element for bf16 {
+    const PRECISION: Precision = Precision::BF16;
+
+    fn to_f32(self) -> f32 {
+        bf16::to_f32(self)
+    }
+
+    fn from_f32(x: f32) -> Self {
+        bf16::from_f32(x)
+    }
+
+    fn widen(src: &[Self], dst: &mut [f32]) {
+        src.convert_to_f32_slice(dst);
+    }
+}
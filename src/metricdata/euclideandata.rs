@@ -1,15 +1,31 @@
+use std::borrow::Cow;
+
 use ndarray::{prelude::*, Data, OwnedRepr};
 
-use crate::metricdata::{MetricData, Subset};
+use crate::metricdata::{matvec, simd, Insertable, MetricData, Scalar, Subset};
 
-pub struct EuclideanData<S: Data<Elem = f32>> {
+/// Dataset under the Euclidean distance, over points of any [`Scalar`] type.
+///
+/// f32 points use the SIMD kernels, the others are compared in f64, so that f64 datasets
+/// don't lose precision.
+pub struct EuclideanData<S: Data>
+where
+    S::Elem: Scalar,
+{
     data: ArrayBase<S, Ix2>,
     squared_norms: Array1<f32>,
 }
 
-impl<S: Data<Elem = f32>> EuclideanData<S> {
+impl<S: Data> EuclideanData<S>
+where
+    S::Elem: Scalar,
+{
     pub fn new(data: ArrayBase<S, Ix2>) -> Self {
-        let norms = data.rows().into_iter().map(|row| row.dot(&row)).collect();
+        let norms = data
+            .rows()
+            .into_iter()
+            .map(|row| row.iter().map(|&x| Into::<f64>::into(x).powi(2)).sum::<f64>() as f32)
+            .collect();
 
         Self {
             data,
@@ -18,37 +34,66 @@ impl<S: Data<Elem = f32>> EuclideanData<S> {
     }
 }
 
-impl<S: Data<Elem = f32>> MetricData for EuclideanData<S> {
+/// Squared distance of `a` and `b`, summing the squared differences in f64
+fn squared_l2_f64<'a, E: Scalar>(a: impl IntoIterator<Item = &'a E>, b: &[E]) -> f64 {
+    a.into_iter()
+        .zip(b)
+        .map(|(&x, &y)| {
+            let diff = Into::<f64>::into(x) - Into::<f64>::into(y);
+            diff * diff
+        })
+        .sum()
+}
+
+impl<S: Data> MetricData for EuclideanData<S>
+where
+    S::Elem: Scalar,
+{
     type DataType = S::Elem;
 
     fn distance(&self, i: usize, j: usize) -> f32 {
-        let sq_eucl = self.squared_norms[i] + self.squared_norms[j]
-            - 2.0 * self.data.row(i).dot(&self.data.row(j));
-        if sq_eucl < 0.0 {
-            0.0
-        } else {
-            sq_eucl.sqrt()
+        let (row_i, row_j) = (self.data.row(i), self.data.row(j));
+        match row_j.to_slice() {
+            Some(row_j) => self.distance_point(i, row_j),
+            None => squared_l2_f64(row_i, &row_j.to_vec()).sqrt() as f32,
         }
     }
 
     fn distance_point(&self, i: usize, point: &[Self::DataType]) -> f32 {
         let row = self.data.row(i);
-        let sq_eucl = self.squared_norms[i] 
-            + point.iter().map(|&x| x * x).sum::<f32>() 
-            - 2.0 * row.dot(&ndarray::ArrayView1::from(point));
-        
-        if sq_eucl < 0.0 {
-            0.0
-        } else {
-            sq_eucl.sqrt()
+        // with a contiguous row the differences are summed directly, which is as fast with
+        // the SIMD kernel and doesn't cancel for close points
+        if let (Some(row), Some(point)) = (row.as_slice().and_then(S::Elem::as_f32), S::Elem::as_f32(point)) {
+            return simd::squared_l2(row, point).sqrt();
         }
+        squared_l2_f64(row, point).sqrt() as f32
+    }
+
+    fn distance_between(&self, a: &[Self::DataType], b: &[Self::DataType]) -> Option<f32> {
+        Some(squared_l2_f64(a, b).sqrt() as f32)
+    }
+
+    fn distance_point_f64(&self, i: usize, point: &[Self::DataType]) -> f64 {
+        // sum the squared differences directly, expanding with the norms cancels badly for close points
+        squared_l2_f64(self.data.row(i), point).sqrt()
     }
 
     fn all_distances(&self, j: usize, out: &mut [f32]) {
-        // OPTIMIZE: try using matrix vector product, for instance
         assert_eq!(out.len(), self.data.nrows());
-        for (i, oo) in out.iter_mut().enumerate() {
-            *oo = self.distance(i, j);
+        let Some(flat) = self.data.as_slice().and_then(S::Elem::as_f32) else {
+            let point = self.data.row(j).to_vec();
+            for (i, o) in out.iter_mut().enumerate() {
+                *o = squared_l2_f64(self.data.row(i), &point).sqrt() as f32;
+            }
+            return;
+        };
+
+        let data = ArrayView2::from_shape(self.data.raw_dim(), flat).unwrap();
+        let point = data.row(j).to_vec();
+        matvec::dot_rows(&data, &point, out);
+        for (oo, squared_norm) in out.iter_mut().zip(&self.squared_norms) {
+            let sq_eucl = squared_norm + self.squared_norms[j] - 2.0 * *oo;
+            *oo = if sq_eucl < 0.0 { 0.0 } else { sq_eucl.sqrt() };
         }
     }
 
@@ -60,15 +105,68 @@ impl<S: Data<Elem = f32>> MetricData for EuclideanData<S> {
         self.data.ncols()
     }
 
-    fn get_point(&self, i: usize) -> &[Self::DataType] {
-        self.data.row(i).to_slice().unwrap()
+    fn get_point(&self, i: usize) -> Cow<'_, [Self::DataType]> {
+        let row = self.data.row(i);
+        match row.to_slice() {
+            Some(row) => Cow::Borrowed(row),
+            None => Cow::Owned(row.to_vec()),
+        }
+    }
+
+    /// The points and their squared norms
+    fn memory_used(&self) -> usize {
+        self.data.len() * std::mem::size_of::<S::Elem>() + self.squared_norms.len() * std::mem::size_of::<f32>()
     }
-    
 }
 
-impl<S: Data<Elem = f32>> Subset for EuclideanData<S> {
-    type Out = EuclideanData<OwnedRepr<f32>>;
+impl<S: Data> Subset for EuclideanData<S>
+where
+    S::Elem: Scalar,
+{
+    type Out = EuclideanData<OwnedRepr<S::Elem>>;
     fn subset(&self, indices: &[usize]) -> Self::Out {
         EuclideanData::new(self.data.select(Axis(0), indices))
     }
 }
+
+impl<E: Scalar> Insertable for EuclideanData<OwnedRepr<E>> {
+    fn insert(&mut self, point: &[E]) -> Result<usize, String> {
+        let point = ArrayView1::from(point);
+        self.data.push_row(point).map_err(|e| e.to_string())?;
+        let squared_norm = point.iter().map(|&x| Into::<f64>::into(x).powi(2)).sum::<f64>() as f32;
+        self.squared_norms
+            .append(Axis(0), ArrayView1::from(&[squared_norm]))
+            .map_err(|e| e.to_string())?;
+        Ok(self.data.nrows() - 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::arr2;
+
+    use super::EuclideanData;
+    use crate::metricdata::{Insertable, MetricData};
+
+    #[test]
+    fn test_element_types() {
+        // 1 + 1e-12 is not representable in f32
+        let points = arr2(&[[1.0f64, 0.0], [1.0 + 1e-12, 0.0], [4.0, 4.0]]);
+        let data = EuclideanData::new(points.clone());
+        assert!((data.distance_point_f64(0, &[1.0 + 1e-12, 0.0]) - 1e-12).abs() < 1e-15);
+        assert_eq!(data.distance(0, 2), 5.0);
+
+        let single = EuclideanData::new(points.mapv(|x| x as f32));
+        let mut out = [0.0; 3];
+        let mut expected = [0.0; 3];
+        data.all_distances(2, &mut out);
+        single.all_distances(2, &mut expected);
+        assert_eq!(out, expected);
+
+        let mut sets = EuclideanData::new(arr2(&[[1u32, 0, 2], [1, 3, 2]]));
+        assert_eq!(sets.distance(0, 1), 3.0);
+        let id = sets.insert(&[5, 0, 2]).unwrap();
+        assert_eq!(sets.distance_point(id, &[1, 0, 2]), 4.0);
+        assert_eq!(&*sets.get_point(id), &[5, 0, 2]);
+    }
+}
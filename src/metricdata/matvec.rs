@@ -0,0 +1,80 @@
+//! Dot products of every row of a matrix with a vector, the inner loop of `all_distances`.
+//!
+//! Large matrices go through a BLAS GEMV with the `blas` feature. Otherwise, rows are read four
+//! at a time with the SIMD kernels, so that the vector is loaded once for every four rows.
+
+use ndarray::{linalg::general_mat_vec_mul, ArrayBase, ArrayView1, ArrayViewMut1, Data, Ix2};
+
+use super::simd;
+
+/// Below this many elements, the call overhead of BLAS costs more than it saves
+#[cfg_attr(not(feature = "blas"), allow(dead_code))]
+const GEMV_MIN_ELEMENTS: usize = 1 << 16;
+
+/// Writes the dot product of row `i` of `data` with `point` in `out[i]`.
+pub(crate) fn dot_rows<S: Data<Elem = f32>>(data: &ArrayBase<S, Ix2>, point: &[f32], out: &mut [f32]) {
+    assert_eq!(out.len(), data.nrows());
+    assert_eq!(point.len(), data.ncols());
+
+    #[cfg(feature = "blas")]
+    if data.len() >= GEMV_MIN_ELEMENTS {
+        return dot_rows_gemv(data, point, out);
+    }
+    dot_rows_blocked(data, point, out)
+}
+
+/// With ndarray's `blas` feature, `general_mat_vec_mul` calls `sgemv` for standard layouts
+fn dot_rows_gemv<S: Data<Elem = f32>>(data: &ArrayBase<S, Ix2>, point: &[f32], out: &mut [f32]) {
+    general_mat_vec_mul(1.0, data, &ArrayView1::from(point), 0.0, &mut ArrayViewMut1::from(out));
+}
+
+fn dot_rows_blocked<S: Data<Elem = f32>>(data: &ArrayBase<S, Ix2>, point: &[f32], out: &mut [f32]) {
+    let Some(flat) = data.as_slice() else {
+        // strided rows, e.g. a view of a column range
+        return dot_rows_gemv(data, point, out);
+    };
+    let dims = data.ncols();
+    if dims == 0 {
+        out.fill(0.0);
+        return;
+    }
+
+    let mut rows = flat.chunks_exact(dims);
+    let mut out_blocks = out.chunks_exact_mut(4);
+    for block in out_blocks.by_ref() {
+        let a = [(); 4].map(|_| rows.next().unwrap());
+        block.copy_from_slice(&simd::dot4(a, point));
+    }
+    for (o, row) in out_blocks.into_remainder().iter_mut().zip(rows) {
+        *o = simd::dot(row, point);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::s;
+
+    use super::{dot_rows, dot_rows_blocked, dot_rows_gemv};
+    use crate::utils::datagen::unit_vectors;
+
+    #[test]
+    fn test_dot_rows() {
+        // 4k + 3 rows, for the remainder of the blocks
+        let data = unit_vectors(103, 20, 1);
+        let point = data.row(7).to_vec();
+        let expected: Vec<f32> = data.rows().into_iter().map(|r| r.dot(&data.row(7))).collect();
+
+        for f in [dot_rows_blocked, dot_rows_gemv, dot_rows] {
+            let mut out = vec![0.0; 103];
+            f(&data, &point, &mut out);
+            assert!(out.iter().zip(&expected).all(|(a, b)| (a - b).abs() < 1e-5));
+            assert!((out[7] - 1.0).abs() < 1e-5);
+        }
+
+        // not in standard layout
+        let view = data.slice(s![.., ..10]);
+        let mut out = vec![0.0; 103];
+        dot_rows(&view, &point[..10], &mut out);
+        assert!((out[7] - view.row(7).dot(&view.row(7))).abs() < 1e-5);
+    }
+}
@@ -1,15 +1,108 @@
+use std::borrow::Cow;
+
 pub(crate) mod euclideandata;
 pub(crate) mod angulardata;
+pub(crate) mod element;
+pub(crate) mod scalar;
+pub(crate) mod subsetview;
+pub(crate) mod matvec;
+pub(crate) mod outofcore;
+pub(crate) mod simd;
+pub(crate) mod weighted;
+pub(crate) mod custom;
 
 pub trait MetricData {
-    type DataType;
+    /// Type of the components of the points and of the queries
+    type DataType: Scalar;
 
     fn distance(&self, i: usize, j: usize) -> f32;
     fn all_distances(&self, j: usize, out: &mut [f32]);
     fn num_points(&self) -> usize;
     fn dimensions(&self) -> usize;
-    fn get_point(&self, i: usize) -> &[Self::DataType];
+    /// Point `i`, borrowed unless the dataset stores it in another type than `DataType`
+    fn get_point(&self, i: usize) -> Cow<'_, [Self::DataType]>;
     fn distance_point(&self, i: usize, point: &[Self::DataType]) -> f32; 
+
+    /// Distances from `point` to the points `ids`, written to `out`.
+    ///
+    /// Same as [`distance_point`](Self::distance_point) on every id, datasets override it to
+    /// compute what only depends on `point` once per batch.
+    fn distances_points(&self, ids: &[usize], point: &[Self::DataType], out: &mut [f32]) {
+        assert_eq!(ids.len(), out.len());
+        for (o, &i) in out.iter_mut().zip(ids) {
+            *o = self.distance_point(i, point);
+        }
+    }
+
+    /// Distance between two points outside of the dataset, e.g. two queries, `None` for
+    /// datasets that only measure distances to their own points
+    fn distance_between(&self, _a: &[Self::DataType], _b: &[Self::DataType]) -> Option<f32> {
+        None
+    }
+
+    /// Same as [`distance_point`](Self::distance_point), accumulated in f64.
+    ///
+    /// Used to re-rank the final results, where f32 round-off can swap close neighbors.
+    fn distance_point_f64(&self, i: usize, point: &[Self::DataType]) -> f64 {
+        self.distance_point(i, point) as f64
+    }
+
+    /// Precision the points are stored in
+    fn precision(&self) -> Precision {
+        Precision::F32
+    }
+
+    /// Bytes of the dataset held in memory.
+    ///
+    /// The points as `DataType`, datasets override it to count the way they store their points
+    /// and what they compute from them.
+    fn memory_used(&self) -> usize {
+        self.num_points() * self.dimensions() * std::mem::size_of::<Self::DataType>()
+    }
+
+    /// Maps a distance to a metric increasing with it, so that the triangle inequality bounds
+    /// the distance between two points from their distances to a third one.
+    ///
+    /// The identity, for datasets whose distance already is a metric.
+    fn distance_to_metric(&self, distance: f32) -> f32 {
+        distance
+    }
+
+    /// Inverse of [`distance_to_metric`](Self::distance_to_metric)
+    fn metric_to_distance(&self, metric: f32) -> f32 {
+        metric
+    }
+
+    /// Point `i` as given to the LSH indexes and the quantizers, whose distances are the
+    /// unweighted ones: datasets with another distance map their points so that the unweighted
+    /// distance of the mapped points is theirs.
+    ///
+    /// The point itself, for datasets whose distance already is the unweighted one.
+    fn indexed_point(&self, i: usize) -> Cow<'_, [Self::DataType]> {
+        self.get_point(i)
+    }
+
+    /// `query` mapped as the points by [`indexed_point`](Self::indexed_point)
+    fn indexed_query<'a>(&self, query: &'a [Self::DataType]) -> Cow<'a, [Self::DataType]> {
+        Cow::Borrowed(query)
+    }
+
+    /// Weight of every dimension in the distance, `None` if they all weigh the same
+    fn weights(&self) -> Option<&[f32]> {
+        None
+    }
+
+    /// Whether the distance only depends on the directions of the points, as the angular
+    /// distance: a zero vector has no distance to the others and is rejected as a query
+    fn scale_invariant(&self) -> bool {
+        false
+    }
+}
+
+/// Datasets that can grow after an index is built on them.
+pub trait Insertable: MetricData {
+    /// Appends `point` to the dataset and returns its index.
+    fn insert(&mut self, point: &[Self::DataType]) -> Result<usize, String>;
 }
 
 pub trait Subset {
@@ -18,4 +111,12 @@ pub trait Subset {
 }
 
 pub use self::euclideandata::EuclideanData;
-pub use self::angulardata::AngularData;
\ No newline at end of file
+pub use self::angulardata::AngularData;
+pub use self::element::{Element, Precision};
+pub use self::scalar::Scalar;
+pub(crate) use self::scalar::checked_norm;
+pub use self::subsetview::SubsetView;
+pub use self::weighted::{WeightedAngularData, WeightedEuclideanData};
+pub use self::custom::CustomMetricData;
+pub use self::outofcore::{Hdf5Rows, MmapRows, OutOfCoreData, RowSource};
+pub use half::{bf16, f16};
\ No newline at end of file
@@ -0,0 +1,386 @@
+use std::borrow::Cow;
+use std::collections::{HashMap, VecDeque};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use memmap2::Mmap;
+
+use ndarray::{s, Array2};
+
+use crate::metricdata::{matvec, simd, AngularData, MetricData, Subset};
+
+/// Points read from disk in blocks of this many rows
+const BLOCK_ROWS: usize = 256;
+
+/// Storage of the rows of an [`OutOfCoreData`], read a range at a time.
+pub trait RowSource: Send + Sync {
+    fn num_rows(&self) -> usize;
+    fn dimensions(&self) -> usize;
+    /// Rows `start..end`, one point per row
+    fn read_rows(&self, start: usize, end: usize) -> Result<Array2<f32>, String>;
+}
+
+/// 2-dimensional f32 dataset of an HDF5 file, e.g. the `train` dataset of the ann-benchmarks files.
+pub struct Hdf5Rows {
+    dataset: hdf5::Dataset,
+    shape: (usize, usize),
+    // keeps the file open
+    _file: hdf5::File,
+}
+
+impl Hdf5Rows {
+    pub fn open(file_path: &str, dataset_name: &str) -> Result<Self, String> {
+        let file = hdf5::File::open(file_path)
+            .map_err(|e| format!("Error opening file '{}': {}", file_path, e))?;
+        let dataset = file
+            .dataset(dataset_name)
+            .map_err(|e| format!("Error opening dataset '{}': {}", dataset_name, e))?;
+        let shape = match dataset.shape()[..] {
+            [rows, dims] => (rows, dims),
+            ref shape => return Err(format!("Dataset '{}' has shape {:?}, expected 2 dimensions", dataset_name, shape)),
+        };
+        Ok(Self { dataset, shape, _file: file })
+    }
+}
+
+impl RowSource for Hdf5Rows {
+    fn num_rows(&self) -> usize {
+        self.shape.0
+    }
+
+    fn dimensions(&self) -> usize {
+        self.shape.1
+    }
+
+    fn read_rows(&self, start: usize, end: usize) -> Result<Array2<f32>, String> {
+        self.dataset
+            .read_slice_2d::<f32, _>(s![start..end, ..])
+            .map_err(|e| format!("Error reading rows {}..{}: {}", start, end, e))
+    }
+}
+
+/// File of f32 rows in native byte order, one after the other, mapped in memory.
+///
+/// The pages of the mapping are loaded and evicted by the OS, so the file doesn't have to fit in RAM.
+pub struct MmapRows {
+    map: Mmap,
+    shape: (usize, usize),
+    /// Removed when the rows are dropped
+    temporary: Option<PathBuf>,
+}
+
+impl MmapRows {
+    /// Maps `file_path` as rows of `dimensions` f32.
+    pub fn open(file_path: &str, dimensions: usize) -> Result<Self, String> {
+        let file = std::fs::File::open(file_path)
+            .map_err(|e| format!("Error opening file '{}': {}", file_path, e))?;
+        // SAFETY: the file must not be modified while it is mapped, as with any memory map
+        let map = unsafe { Mmap::map(&file) }
+            .map_err(|e| format!("Error mapping file '{}': {}", file_path, e))?;
+        let row_bytes = dimensions * std::mem::size_of::<f32>();
+        if row_bytes == 0 || map.len() % row_bytes != 0 {
+            return Err(format!(
+                "File '{}' of {} bytes doesn't hold rows of {} dimensions",
+                file_path,
+                map.len(),
+                dimensions
+            ));
+        }
+        Ok(Self {
+            shape: (map.len() / row_bytes, dimensions),
+            map,
+            temporary: None,
+        })
+    }
+
+    /// Same as [`open`](Self::open), removing the file once the rows are dropped
+    pub(crate) fn open_temporary(file_path: PathBuf, dimensions: usize) -> Result<Self, String> {
+        match Self::open(&file_path.to_string_lossy(), dimensions) {
+            Ok(mut rows) => {
+                rows.temporary = Some(file_path);
+                Ok(rows)
+            }
+            Err(e) => {
+                let _ = std::fs::remove_file(&file_path);
+                Err(e)
+            }
+        }
+    }
+}
+
+impl RowSource for MmapRows {
+    fn num_rows(&self) -> usize {
+        self.shape.0
+    }
+
+    fn dimensions(&self) -> usize {
+        self.shape.1
+    }
+
+    fn read_rows(&self, start: usize, end: usize) -> Result<Array2<f32>, String> {
+        let row_bytes = self.shape.1 * std::mem::size_of::<f32>();
+        let bytes = self
+            .map
+            .get(start * row_bytes..end * row_bytes)
+            .ok_or_else(|| format!("Rows {}..{} out of {}", start, end, self.shape.0))?;
+        let values = bytes
+            .chunks_exact(std::mem::size_of::<f32>())
+            .map(|b| f32::from_ne_bytes(b.try_into().unwrap()))
+            .collect();
+        Array2::from_shape_vec((end - start, self.shape.1), values).map_err(|e| e.to_string())
+    }
+}
+
+impl Drop for MmapRows {
+    fn drop(&mut self) {
+        if let Some(path) = &self.temporary {
+            // a mapped file can be removed on Unix, elsewhere it stays in the temporary directory
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Least recently used blocks, at most `capacity`
+struct BlockCache {
+    capacity: usize,
+    blocks: HashMap<usize, Arc<Array2<f32>>>,
+    /// Least recently used first
+    recency: VecDeque<usize>,
+}
+
+impl BlockCache {
+    fn get(&mut self, block: usize) -> Option<Arc<Array2<f32>>> {
+        let rows = self.blocks.get(&block)?.clone();
+        if let Some(position) = self.recency.iter().position(|&b| b == block) {
+            self.recency.remove(position);
+        }
+        self.recency.push_back(block);
+        Some(rows)
+    }
+
+    fn insert(&mut self, block: usize, rows: Arc<Array2<f32>>) {
+        if self.blocks.len() >= self.capacity {
+            if let Some(evicted) = self.recency.pop_front() {
+                self.blocks.remove(&evicted);
+            }
+        }
+        self.blocks.insert(block, rows);
+        self.recency.push_back(block);
+    }
+}
+
+/// Dataset under the angular distance read on demand from disk, for datasets that don't fit in RAM.
+///
+/// Only the norms of the points are kept in memory, the points are read in blocks of rows, with
+/// the most recently used blocks cached. The clusters list their points in increasing order, so
+/// each PUFFINN index reads the blocks of its cluster once during the build. The clustering
+/// itself reads the whole dataset once per center, so it is bound by the disk.
+///
+/// `MetricData` is infallible: read errors after [`new`](Self::new) panic.
+pub struct OutOfCoreData<R: RowSource> {
+    source: R,
+    norms: Vec<f32>,
+    cache: Mutex<BlockCache>,
+}
+
+impl<R: RowSource> OutOfCoreData<R> {
+    /// Reads `source` once to compute the norms, caching at most `cache_points` points afterwards.
+    pub fn new(source: R, cache_points: usize) -> Result<Self, String> {
+        let num_blocks = source.num_rows().div_ceil(BLOCK_ROWS);
+        let mut norms = Vec::with_capacity(source.num_rows());
+        for block in 0..num_blocks {
+            let rows = Self::read_block(&source, block)?;
+            norms.extend(rows.rows().into_iter().map(|row| {
+                let row = row.as_slice().expect("blocks are in standard layout");
+                simd::dot(row, row).sqrt()
+            }));
+        }
+
+        let cache = BlockCache {
+            capacity: (cache_points / BLOCK_ROWS).max(1),
+            blocks: HashMap::new(),
+            recency: VecDeque::new(),
+        };
+        Ok(Self {
+            source,
+            norms,
+            cache: Mutex::new(cache),
+        })
+    }
+
+    fn read_block(source: &R, block: usize) -> Result<Array2<f32>, String> {
+        let start = block * BLOCK_ROWS;
+        let end = (start + BLOCK_ROWS).min(source.num_rows());
+        let rows = source.read_rows(start, end)?;
+        if rows.dim() != (end - start, source.dimensions()) {
+            return Err(format!("Rows {}..{} have shape {:?}", start, end, rows.dim()));
+        }
+        Ok(rows.as_standard_layout().into_owned())
+    }
+
+    /// Block `block`, read from the source if it is not cached
+    fn block(&self, block: usize) -> Arc<Array2<f32>> {
+        if let Some(rows) = self.cache.lock().unwrap().get(block) {
+            return rows;
+        }
+        // read without holding the lock, two threads may read the same block
+        let rows = Arc::new(Self::read_block(&self.source, block).unwrap_or_else(|e| panic!("{}", e)));
+        self.cache.lock().unwrap().insert(block, rows.clone());
+        rows
+    }
+
+    fn dot_row(&self, i: usize, point: &[f32]) -> f32 {
+        let block = self.block(i / BLOCK_ROWS);
+        simd::dot(block.row(i % BLOCK_ROWS).as_slice().unwrap(), point)
+    }
+}
+
+impl<R: RowSource> MetricData for OutOfCoreData<R> {
+    type DataType = f32;
+
+    fn distance(&self, i: usize, j: usize) -> f32 {
+        let dot_product = self.dot_row(i, &self.get_point(j));
+        1.0 - dot_product / (self.norms[i] * self.norms[j])
+    }
+
+    fn all_distances(&self, j: usize, out: &mut [f32]) {
+        assert_eq!(out.len(), self.norms.len());
+        let point = self.get_point(j);
+        for (block, out) in out.chunks_mut(BLOCK_ROWS).enumerate() {
+            matvec::dot_rows(&*self.block(block), &point, out);
+        }
+        for (oo, norm) in out.iter_mut().zip(&self.norms) {
+            *oo = 1.0 - *oo / (norm * self.norms[j]);
+        }
+    }
+
+    fn num_points(&self) -> usize {
+        self.norms.len()
+    }
+
+    fn dimensions(&self) -> usize {
+        self.source.dimensions()
+    }
+
+    fn get_point(&self, i: usize) -> Cow<'_, [Self::DataType]> {
+        Cow::Owned(self.block(i / BLOCK_ROWS).row(i % BLOCK_ROWS).to_vec())
+    }
+
+    fn distance_point(&self, i: usize, point: &[Self::DataType]) -> f32 {
+        let norm_point = simd::dot(point, point).sqrt();
+        1.0 - self.dot_row(i, point) / (self.norms[i] * norm_point)
+    }
+
+    fn distance_point_f64(&self, i: usize, point: &[Self::DataType]) -> f64 {
+        let row = self.get_point(i);
+        let mut dot_product = 0.0f64;
+        let mut norm_row = 0.0f64;
+        let mut norm_point = 0.0f64;
+        for (&x, &y) in row.iter().zip(point) {
+            dot_product += x as f64 * y as f64;
+            norm_row += x as f64 * x as f64;
+            norm_point += y as f64 * y as f64;
+        }
+
+        1.0 - dot_product / (norm_row.sqrt() * norm_point.sqrt())
+    }
+
+    fn distance_to_metric(&self, distance: f32) -> f32 {
+        (1.0 - distance).clamp(-1.0, 1.0).acos()
+    }
+
+    fn metric_to_distance(&self, metric: f32) -> f32 {
+        1.0 - metric.cos()
+    }
+
+    fn scale_invariant(&self) -> bool {
+        true
+    }
+
+    /// The norms and the cached blocks, the other points are on disk
+    fn memory_used(&self) -> usize {
+        let cached_rows: usize = self.cache.lock().unwrap().blocks.values().map(|rows| rows.nrows()).sum();
+        (self.norms.len() + cached_rows * self.dimensions()) * std::mem::size_of::<f32>()
+    }
+}
+
+impl<R: RowSource> Subset for OutOfCoreData<R> {
+    type Out = AngularData<ndarray::OwnedRepr<f32>>;
+    fn subset(&self, indices: &[usize]) -> Self::Out {
+        let mut points = Array2::zeros((0, self.dimensions()));
+        for &i in indices {
+            points.push_row(self.block(i / BLOCK_ROWS).row(i % BLOCK_ROWS)).unwrap();
+        }
+        AngularData::new(points)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use ndarray::{s, Array2};
+
+    use super::{OutOfCoreData, RowSource, BLOCK_ROWS};
+    use crate::metricdata::{AngularData, MetricData, Subset};
+    use crate::utils::datagen::unit_vectors;
+
+    /// Rows in memory, counting the reads
+    struct CountingRows {
+        points: Array2<f32>,
+        reads: AtomicUsize,
+    }
+
+    impl RowSource for CountingRows {
+        fn num_rows(&self) -> usize {
+            self.points.nrows()
+        }
+
+        fn dimensions(&self) -> usize {
+            self.points.ncols()
+        }
+
+        fn read_rows(&self, start: usize, end: usize) -> Result<Array2<f32>, String> {
+            self.reads.fetch_add(1, Ordering::Relaxed);
+            Ok(self.points.slice(s![start..end, ..]).to_owned())
+        }
+    }
+
+    #[test]
+    fn test_out_of_core_data() {
+        let points = unit_vectors(3 * BLOCK_ROWS + 10, 12, 1);
+        let in_memory = AngularData::new(points.clone());
+        let source = CountingRows {
+            points,
+            reads: AtomicUsize::new(0),
+        };
+        let data = OutOfCoreData::new(source, 2 * BLOCK_ROWS).unwrap();
+        // the norms are computed in one pass
+        assert_eq!(data.source.reads.load(Ordering::Relaxed), 4);
+        assert_eq!(data.num_points(), 3 * BLOCK_ROWS + 10);
+
+        let query = in_memory.get_point(700).into_owned();
+        let mut out = vec![0.0; data.num_points()];
+        let mut expected = vec![0.0; data.num_points()];
+        data.all_distances(700, &mut out);
+        in_memory.all_distances(700, &mut expected);
+        for i in (0..data.num_points()).step_by(37) {
+            assert!((out[i] - expected[i]).abs() < 1e-5);
+            assert!((data.distance(i, 5) - in_memory.distance(i, 5)).abs() < 1e-5);
+            assert!((data.distance_point(i, &query) - in_memory.distance_point(i, &query)).abs() < 1e-5);
+        }
+
+        // the two most recent blocks stay cached
+        data.get_point(3 * BLOCK_ROWS);
+        data.get_point(2 * BLOCK_ROWS);
+        let reads = data.source.reads.load(Ordering::Relaxed);
+        data.get_point(3 * BLOCK_ROWS);
+        data.get_point(2 * BLOCK_ROWS);
+        assert_eq!(data.source.reads.load(Ordering::Relaxed), reads);
+        data.get_point(0);
+        assert_eq!(data.source.reads.load(Ordering::Relaxed), reads + 1);
+
+        let subset = data.subset(&[1, 300, 770]);
+        assert_eq!(subset.get_point(2), in_memory.get_point(770));
+    }
+}
@@ -0,0 +1,67 @@
+/// Norm of `point`, checking that its components are finite and, if `nonzero`, that they
+/// are not all zero.
+///
+/// # Errors
+/// What is wrong with the point, e.g. "has zero norm"
+pub(crate) fn checked_norm<D: Scalar>(point: &[D], nonzero: bool) -> Result<f64, &'static str> {
+    let mut squared = 0.0;
+    for &x in point {
+        let x: f64 = x.into();
+        if !x.is_finite() {
+            return Err("has a NaN or infinite component");
+        }
+        squared += x * x;
+    }
+    if nonzero && squared == 0.0 {
+        return Err("has zero norm");
+    }
+    Ok(squared.sqrt())
+}
+
+/// Type of the components of the points and of the queries: `f32`, `f64` or `u32`.
+///
+/// The distances of a dataset are computed in its own type, e.g. in f64 for `EuclideanData`
+/// over f64 points. Only the C API of PUFFINN, which indexes f32 vectors, converts the points,
+/// see [`to_f32`](Self::to_f32).
+pub trait Scalar: Copy + PartialEq + Send + Sync + 'static + Into<f64> {
+    /// Closest value to `x`, used by projections
+    fn from_f64(x: f64) -> Self;
+
+    /// Closest f32 to the value, the type of the PUFFINN indexes
+    fn to_f32(self) -> f32 {
+        Into::<f64>::into(self) as f32
+    }
+
+    /// `src` itself when it is already f32, so that the f32 kernels use it without a copy
+    fn as_f32(_src: &[Self]) -> Option<&[f32]> {
+        None
+    }
+}
+
+impl Scalar for f32 {
+    fn from_f64(x: f64) -> Self {
+        x as f32
+    }
+
+    fn to_f32(self) -> f32 {
+        self
+    }
+
+    fn as_f32(src: &[Self]) -> Option<&[f32]> {
+        Some(src)
+    }
+}
+
+impl Scalar for f64 {
+    fn from_f64(x: f64) -> Self {
+        x
+    }
+}
+
+/// Components of sets and counts
+impl Scalar for u32 {
+    // saturating, negative values to 0
+    fn from_f64(x: f64) -> Self {
+        x.round() as u32
+    }
+}
@@ -0,0 +1,272 @@
+//! Dot product and squared L2 kernels over `f32` slices, with AVX2/FMA on x86_64 and NEON on
+//! aarch64. The instruction set is detected once, at the first call, so binaries built for a
+//! generic target still use the vector units of the machine they run on.
+
+use std::sync::OnceLock;
+
+/// Instruction set used by the kernels
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Isa {
+    Scalar,
+    #[cfg(target_arch = "x86_64")]
+    Avx2,
+    #[cfg(target_arch = "aarch64")]
+    Neon,
+}
+
+pub(crate) fn isa() -> Isa {
+    static ISA: OnceLock<Isa> = OnceLock::new();
+    *ISA.get_or_init(|| {
+        #[cfg(target_arch = "x86_64")]
+        if is_x86_feature_detected!("avx2") && is_x86_feature_detected!("fma") {
+            return Isa::Avx2;
+        }
+        // NEON is part of the aarch64 baseline
+        #[cfg(target_arch = "aarch64")]
+        return Isa::Neon;
+        #[allow(unreachable_code)]
+        Isa::Scalar
+    })
+}
+
+pub(crate) fn dot(a: &[f32], b: &[f32]) -> f32 {
+    assert_eq!(a.len(), b.len());
+    match isa() {
+        // SAFETY: the features were detected at runtime, and the slices have the same length
+        #[cfg(target_arch = "x86_64")]
+        Isa::Avx2 => unsafe { x86::dot(a, b) },
+        #[cfg(target_arch = "aarch64")]
+        Isa::Neon => unsafe { neon::dot(a, b) },
+        Isa::Scalar => scalar::dot(a, b),
+    }
+}
+
+/// Dot products of four vectors with `b`, reading `b` once for the four
+pub(crate) fn dot4(a: [&[f32]; 4], b: &[f32]) -> [f32; 4] {
+    assert!(a.iter().all(|a| a.len() == b.len()));
+    match isa() {
+        // SAFETY: as in `dot`
+        #[cfg(target_arch = "x86_64")]
+        Isa::Avx2 => unsafe { x86::dot4(a, b) },
+        _ => a.map(|a| dot(a, b)),
+    }
+}
+
+pub(crate) fn squared_l2(a: &[f32], b: &[f32]) -> f32 {
+    assert_eq!(a.len(), b.len());
+    match isa() {
+        // SAFETY: as in `dot`
+        #[cfg(target_arch = "x86_64")]
+        Isa::Avx2 => unsafe { x86::squared_l2(a, b) },
+        #[cfg(target_arch = "aarch64")]
+        Isa::Neon => unsafe { neon::squared_l2(a, b) },
+        Isa::Scalar => scalar::squared_l2(a, b),
+    }
+}
+
+mod scalar {
+    // eight independent accumulators, so that the compiler can vectorize with the baseline ISA
+    pub(super) fn dot(a: &[f32], b: &[f32]) -> f32 {
+        let mut acc = [0.0f32; 8];
+        let (chunks_a, chunks_b) = (a.chunks_exact(8), b.chunks_exact(8));
+        let tail: f32 = chunks_a.remainder().iter().zip(chunks_b.remainder()).map(|(x, y)| x * y).sum();
+        for (ca, cb) in chunks_a.zip(chunks_b) {
+            for l in 0..8 {
+                acc[l] += ca[l] * cb[l];
+            }
+        }
+        acc.iter().sum::<f32>() + tail
+    }
+
+    pub(super) fn squared_l2(a: &[f32], b: &[f32]) -> f32 {
+        let mut acc = [0.0f32; 8];
+        let (chunks_a, chunks_b) = (a.chunks_exact(8), b.chunks_exact(8));
+        let tail: f32 = chunks_a
+            .remainder()
+            .iter()
+            .zip(chunks_b.remainder())
+            .map(|(x, y)| (x - y) * (x - y))
+            .sum();
+        for (ca, cb) in chunks_a.zip(chunks_b) {
+            for l in 0..8 {
+                let diff = ca[l] - cb[l];
+                acc[l] += diff * diff;
+            }
+        }
+        acc.iter().sum::<f32>() + tail
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+mod x86 {
+    use std::arch::x86_64::*;
+
+    #[target_feature(enable = "avx2,fma")]
+    unsafe fn hsum(v: __m256) -> f32 {
+        let sum = _mm_add_ps(_mm256_castps256_ps128(v), _mm256_extractf128_ps(v, 1));
+        let sum = _mm_add_ps(sum, _mm_movehl_ps(sum, sum));
+        let sum = _mm_add_ss(sum, _mm_shuffle_ps(sum, sum, 1));
+        _mm_cvtss_f32(sum)
+    }
+
+    /// # Safety
+    /// The CPU must support AVX2 and FMA, and `a` and `b` must have the same length
+    #[target_feature(enable = "avx2,fma")]
+    pub(super) unsafe fn dot(a: &[f32], b: &[f32]) -> f32 {
+        let n = a.len();
+        let (pa, pb) = (a.as_ptr(), b.as_ptr());
+        let (mut acc0, mut acc1) = (_mm256_setzero_ps(), _mm256_setzero_ps());
+        let mut i = 0;
+        while i + 16 <= n {
+            acc0 = _mm256_fmadd_ps(_mm256_loadu_ps(pa.add(i)), _mm256_loadu_ps(pb.add(i)), acc0);
+            acc1 = _mm256_fmadd_ps(_mm256_loadu_ps(pa.add(i + 8)), _mm256_loadu_ps(pb.add(i + 8)), acc1);
+            i += 16;
+        }
+        if i + 8 <= n {
+            acc0 = _mm256_fmadd_ps(_mm256_loadu_ps(pa.add(i)), _mm256_loadu_ps(pb.add(i)), acc0);
+            i += 8;
+        }
+        let mut sum = hsum(_mm256_add_ps(acc0, acc1));
+        while i < n {
+            sum += *pa.add(i) * *pb.add(i);
+            i += 1;
+        }
+        sum
+    }
+
+    /// # Safety
+    /// Same as [`dot`], for the four vectors
+    #[target_feature(enable = "avx2,fma")]
+    pub(super) unsafe fn dot4(a: [&[f32]; 4], b: &[f32]) -> [f32; 4] {
+        let n = b.len();
+        let pa = a.map(|a| a.as_ptr());
+        let pb = b.as_ptr();
+        let mut acc = [_mm256_setzero_ps(); 4];
+        let mut i = 0;
+        while i + 8 <= n {
+            let vb = _mm256_loadu_ps(pb.add(i));
+            for r in 0..4 {
+                acc[r] = _mm256_fmadd_ps(_mm256_loadu_ps(pa[r].add(i)), vb, acc[r]);
+            }
+            i += 8;
+        }
+        let mut sums = [hsum(acc[0]), hsum(acc[1]), hsum(acc[2]), hsum(acc[3])];
+        while i < n {
+            for r in 0..4 {
+                sums[r] += *pa[r].add(i) * *pb.add(i);
+            }
+            i += 1;
+        }
+        sums
+    }
+
+    /// # Safety
+    /// Same as [`dot`]
+    #[target_feature(enable = "avx2,fma")]
+    pub(super) unsafe fn squared_l2(a: &[f32], b: &[f32]) -> f32 {
+        let n = a.len();
+        let (pa, pb) = (a.as_ptr(), b.as_ptr());
+        let (mut acc0, mut acc1) = (_mm256_setzero_ps(), _mm256_setzero_ps());
+        let mut i = 0;
+        while i + 16 <= n {
+            let d0 = _mm256_sub_ps(_mm256_loadu_ps(pa.add(i)), _mm256_loadu_ps(pb.add(i)));
+            let d1 = _mm256_sub_ps(_mm256_loadu_ps(pa.add(i + 8)), _mm256_loadu_ps(pb.add(i + 8)));
+            acc0 = _mm256_fmadd_ps(d0, d0, acc0);
+            acc1 = _mm256_fmadd_ps(d1, d1, acc1);
+            i += 16;
+        }
+        if i + 8 <= n {
+            let d = _mm256_sub_ps(_mm256_loadu_ps(pa.add(i)), _mm256_loadu_ps(pb.add(i)));
+            acc0 = _mm256_fmadd_ps(d, d, acc0);
+            i += 8;
+        }
+        let mut sum = hsum(_mm256_add_ps(acc0, acc1));
+        while i < n {
+            let diff = *pa.add(i) - *pb.add(i);
+            sum += diff * diff;
+            i += 1;
+        }
+        sum
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+mod neon {
+    use std::arch::aarch64::*;
+
+    /// # Safety
+    /// `a` and `b` must have the same length
+    #[target_feature(enable = "neon")]
+    pub(super) unsafe fn dot(a: &[f32], b: &[f32]) -> f32 {
+        let n = a.len();
+        let (pa, pb) = (a.as_ptr(), b.as_ptr());
+        let (mut acc0, mut acc1) = (vdupq_n_f32(0.0), vdupq_n_f32(0.0));
+        let mut i = 0;
+        while i + 8 <= n {
+            acc0 = vfmaq_f32(acc0, vld1q_f32(pa.add(i)), vld1q_f32(pb.add(i)));
+            acc1 = vfmaq_f32(acc1, vld1q_f32(pa.add(i + 4)), vld1q_f32(pb.add(i + 4)));
+            i += 8;
+        }
+        let mut sum = vaddvq_f32(vaddq_f32(acc0, acc1));
+        while i < n {
+            sum += *pa.add(i) * *pb.add(i);
+            i += 1;
+        }
+        sum
+    }
+
+    /// # Safety
+    /// Same as [`dot`]
+    #[target_feature(enable = "neon")]
+    pub(super) unsafe fn squared_l2(a: &[f32], b: &[f32]) -> f32 {
+        let n = a.len();
+        let (pa, pb) = (a.as_ptr(), b.as_ptr());
+        let (mut acc0, mut acc1) = (vdupq_n_f32(0.0), vdupq_n_f32(0.0));
+        let mut i = 0;
+        while i + 8 <= n {
+            let d0 = vsubq_f32(vld1q_f32(pa.add(i)), vld1q_f32(pb.add(i)));
+            let d1 = vsubq_f32(vld1q_f32(pa.add(i + 4)), vld1q_f32(pb.add(i + 4)));
+            acc0 = vfmaq_f32(acc0, d0, d0);
+            acc1 = vfmaq_f32(acc1, d1, d1);
+            i += 8;
+        }
+        let mut sum = vaddvq_f32(vaddq_f32(acc0, acc1));
+        while i < n {
+            let diff = *pa.add(i) - *pb.add(i);
+            sum += diff * diff;
+            i += 1;
+        }
+        sum
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+
+    use super::{dot, dot4, scalar, squared_l2};
+
+    #[test]
+    fn test_kernels_match_scalar() {
+        let mut rng = rand::thread_rng();
+        // lengths around the vector widths, to cover the tails
+        for n in (0..40).chain([100, 128, 960]) {
+            let a: Vec<f32> = (0..n).map(|_| rng.gen_range(-1.0..1.0)).collect();
+            let b: Vec<f32> = (0..n).map(|_| rng.gen_range(-1.0..1.0)).collect();
+            let naive_dot: f64 = a.iter().zip(&b).map(|(&x, &y)| x as f64 * y as f64).sum();
+            let naive_l2: f64 = a.iter().zip(&b).map(|(&x, &y)| (x as f64 - y as f64).powi(2)).sum();
+
+            let close = |x: f32, y: f64| (x as f64 - y).abs() <= 1e-5 * (1.0 + y.abs());
+
+            assert!(close(dot(&a, &b), naive_dot), "dot, n = {}", n);
+            assert!(close(squared_l2(&a, &b), naive_l2), "l2, n = {}", n);
+            assert!(close(scalar::dot(&a, &b), naive_dot));
+            assert!(close(scalar::squared_l2(&a, &b), naive_l2));
+
+            let c: Vec<f32> = a.iter().map(|x| x * 2.0).collect();
+            let four = dot4([&a, &c, &b, &a], &b);
+            assert!(close(four[0], naive_dot) && close(four[3], naive_dot));
+            assert!(close(four[1], 2.0 * naive_dot));
+        }
+    }
+}
@@ -0,0 +1,122 @@
+use std::borrow::Cow;
+
+use crate::metricdata::{MetricData, Precision};
+
+/// Points `indices` of a dataset, without copying them.
+///
+/// Point `i` of the view is point `indices[i]` of `data`, so ids of the view are positions in
+/// `indices`, as with [`Subset::subset`](crate::metricdata::Subset::subset). Used to feed the
+/// points of a cluster to its index without materializing the cluster.
+pub struct SubsetView<'a, M: MetricData> {
+    data: &'a M,
+    indices: &'a [usize],
+}
+
+impl<'a, M: MetricData> SubsetView<'a, M> {
+    pub fn new(data: &'a M, indices: &'a [usize]) -> Self {
+        Self { data, indices }
+    }
+
+    /// Dataset the points are read from
+    pub(crate) fn data(&self) -> &'a M {
+        self.data
+    }
+}
+
+impl<M: MetricData> MetricData for SubsetView<'_, M> {
+    type DataType = M::DataType;
+
+    fn distance(&self, i: usize, j: usize) -> f32 {
+        self.data.distance(self.indices[i], self.indices[j])
+    }
+
+    fn all_distances(&self, j: usize, out: &mut [f32]) {
+        let point = self.data.get_point(self.indices[j]);
+        self.data.distances_points(self.indices, &point, out);
+    }
+
+    fn num_points(&self) -> usize {
+        self.indices.len()
+    }
+
+    fn dimensions(&self) -> usize {
+        self.data.dimensions()
+    }
+
+    fn get_point(&self, i: usize) -> Cow<'_, [Self::DataType]> {
+        self.data.get_point(self.indices[i])
+    }
+
+    fn distance_point(&self, i: usize, point: &[Self::DataType]) -> f32 {
+        self.data.distance_point(self.indices[i], point)
+    }
+
+    fn distances_points(&self, ids: &[usize], point: &[Self::DataType], out: &mut [f32]) {
+        let ids: Vec<usize> = ids.iter().map(|&i| self.indices[i]).collect();
+        self.data.distances_points(&ids, point, out);
+    }
+
+    fn distance_point_f64(&self, i: usize, point: &[Self::DataType]) -> f64 {
+        self.data.distance_point_f64(self.indices[i], point)
+    }
+
+    fn precision(&self) -> Precision {
+        self.data.precision()
+    }
+
+    fn distance_to_metric(&self, distance: f32) -> f32 {
+        self.data.distance_to_metric(distance)
+    }
+
+    fn metric_to_distance(&self, metric: f32) -> f32 {
+        self.data.metric_to_distance(metric)
+    }
+
+    fn indexed_point(&self, i: usize) -> Cow<'_, [Self::DataType]> {
+        self.data.indexed_point(self.indices[i])
+    }
+
+    fn indexed_query<'a>(&self, query: &'a [Self::DataType]) -> Cow<'a, [Self::DataType]> {
+        self.data.indexed_query(query)
+    }
+
+    fn weights(&self) -> Option<&[f32]> {
+        self.data.weights()
+    }
+
+    fn scale_invariant(&self) -> bool {
+        self.data.scale_invariant()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SubsetView;
+    use crate::metricdata::{AngularData, MetricData, Subset};
+    use crate::utils::datagen::unit_vectors;
+
+    #[test]
+    fn test_subset_view() {
+        let data = AngularData::new(unit_vectors(40, 8, 1));
+        let indices = [3, 17, 5, 39, 0];
+        let view = SubsetView::new(&data, &indices);
+        let copy = data.subset(&indices);
+        assert_eq!(view.num_points(), 5);
+        assert_eq!(view.dimensions(), 8);
+
+        let query = data.get_point(11).into_owned();
+        let (mut out, mut expected) = ([0.0; 5], [0.0; 5]);
+        view.all_distances(2, &mut out);
+        copy.all_distances(2, &mut expected);
+        for i in 0..5 {
+            assert!((out[i] - expected[i]).abs() < 1e-6);
+            assert_eq!(view.get_point(i), copy.get_point(i));
+            assert!((view.distance(i, 4) - copy.distance(i, 4)).abs() < 1e-6);
+            assert!((view.distance_point(i, &query) - copy.distance_point(i, &query)).abs() < 1e-6);
+        }
+
+        let mut batch = [0.0; 2];
+        view.distances_points(&[1, 3], &query, &mut batch);
+        assert_eq!(batch, [data.distance_point(17, &query), data.distance_point(39, &query)]);
+    }
+}
@@ -0,0 +1,364 @@
+use std::borrow::Cow;
+
+use ndarray::{prelude::*, Data, OwnedRepr, RawDataClone};
+
+use crate::metricdata::{matvec, simd, Insertable, MetricData, Subset};
+
+/// Checks that there is one finite, non-negative weight per dimension
+fn check_weights(weights: &[f32], dimensions: usize) -> Result<(), String> {
+    if weights.len() != dimensions {
+        return Err(format!(
+            "{} weights for {} dimensions",
+            weights.len(),
+            dimensions
+        ));
+    }
+    if let Some(w) = weights.iter().find(|w| !(w.is_finite() && **w >= 0.0)) {
+        return Err(format!("weights must be finite and non-negative, got {}", w));
+    }
+    Ok(())
+}
+
+/// `point` scaled by the square roots of the weights, whose unweighted distances are the weighted ones
+fn scale(point: &[f32], sqrt_weights: &[f32]) -> Vec<f32> {
+    point.iter().zip(sqrt_weights).map(|(x, w)| x * w).collect()
+}
+
+/// Row `i` of `data`, borrowed if contiguous
+fn row<S: Data<Elem = f32>>(data: &ArrayBase<S, Ix2>, i: usize) -> Cow<'_, [f32]> {
+    let row = data.row(i);
+    match row.to_slice() {
+        Some(row) => Cow::Borrowed(row),
+        None => Cow::Owned(row.to_vec()),
+    }
+}
+
+/// Dataset under the Euclidean distance with a weight per dimension,
+/// `sqrt(sum_d w_d * (x_d - y_d)^2)`.
+///
+/// The points are stored as given. The LSH indexes and the quantizers get them scaled by the
+/// square roots of the weights, see [`MetricData::indexed_point`], and the weights are saved
+/// with the index so that it can't be loaded with other ones.
+#[derive(Clone)]
+pub struct WeightedEuclideanData<S: Data<Elem = f32> + RawDataClone> {
+    data: ArrayBase<S, Ix2>,
+    weights: Vec<f32>,
+    sqrt_weights: Vec<f32>,
+    weighted_squared_norms: Vec<f32>,
+}
+
+impl<S: Data<Elem = f32> + RawDataClone> WeightedEuclideanData<S> {
+    /// Dataset of `data` weighing dimension `d` by `weights[d]`.
+    ///
+    /// # Errors
+    /// If there isn't one weight per column of `data`, or a weight is negative or not finite
+    pub fn new(data: ArrayBase<S, Ix2>, weights: Vec<f32>) -> Result<Self, String> {
+        check_weights(&weights, data.ncols())?;
+        Ok(Self::with_weights(data, weights))
+    }
+
+    fn with_weights(data: ArrayBase<S, Ix2>, weights: Vec<f32>) -> Self {
+        let weighted_squared_norms = data
+            .rows()
+            .into_iter()
+            .map(|row| row.iter().zip(&weights).map(|(x, w)| w * x * x).sum())
+            .collect();
+        Self {
+            sqrt_weights: weights.iter().map(|w| w.sqrt()).collect(),
+            data,
+            weights,
+            weighted_squared_norms,
+        }
+    }
+}
+
+impl<S: Data<Elem = f32> + RawDataClone> MetricData for WeightedEuclideanData<S> {
+    type DataType = f32;
+
+    fn distance(&self, i: usize, j: usize) -> f32 {
+        self.distance_point(i, &row(&self.data, j))
+    }
+
+    fn distance_point(&self, i: usize, point: &[f32]) -> f32 {
+        // sum the weighted squared differences directly, expanding with the norms cancels for close points
+        self.data
+            .row(i)
+            .iter()
+            .zip(point)
+            .zip(&self.weights)
+            .map(|((x, y), w)| w * (x - y) * (x - y))
+            .sum::<f32>()
+            .sqrt()
+    }
+
+    fn distance_point_f64(&self, i: usize, point: &[f32]) -> f64 {
+        self.data
+            .row(i)
+            .iter()
+            .zip(point)
+            .zip(&self.weights)
+            .map(|((&x, &y), &w)| w as f64 * (x as f64 - y as f64).powi(2))
+            .sum::<f64>()
+            .sqrt()
+    }
+
+    fn all_distances(&self, j: usize, out: &mut [f32]) {
+        assert_eq!(out.len(), self.data.nrows());
+        let weighted: Vec<f32> = row(&self.data, j).iter().zip(&self.weights).map(|(x, w)| x * w).collect();
+        matvec::dot_rows(&self.data, &weighted, out);
+        for (oo, squared_norm) in out.iter_mut().zip(&self.weighted_squared_norms) {
+            let sq_eucl = squared_norm + self.weighted_squared_norms[j] - 2.0 * *oo;
+            *oo = if sq_eucl < 0.0 { 0.0 } else { sq_eucl.sqrt() };
+        }
+    }
+
+    fn num_points(&self) -> usize {
+        self.data.nrows()
+    }
+
+    fn dimensions(&self) -> usize {
+        self.data.ncols()
+    }
+
+    fn get_point(&self, i: usize) -> Cow<'_, [f32]> {
+        row(&self.data, i)
+    }
+
+    fn indexed_point(&self, i: usize) -> Cow<'_, [f32]> {
+        Cow::Owned(scale(&row(&self.data, i), &self.sqrt_weights))
+    }
+
+    fn indexed_query<'a>(&self, query: &'a [f32]) -> Cow<'a, [f32]> {
+        Cow::Owned(scale(query, &self.sqrt_weights))
+    }
+
+    fn weights(&self) -> Option<&[f32]> {
+        Some(&self.weights)
+    }
+}
+
+impl<S: Data<Elem = f32> + RawDataClone> Subset for WeightedEuclideanData<S> {
+    type Out = WeightedEuclideanData<OwnedRepr<f32>>;
+    fn subset(&self, indices: &[usize]) -> Self::Out {
+        WeightedEuclideanData::with_weights(self.data.select(Axis(0), indices), self.weights.clone())
+    }
+}
+
+impl Insertable for WeightedEuclideanData<OwnedRepr<f32>> {
+    fn insert(&mut self, point: &[f32]) -> Result<usize, String> {
+        self.data.push_row(ArrayView1::from(point)).map_err(|e| e.to_string())?;
+        self.weighted_squared_norms
+            .push(point.iter().zip(&self.weights).map(|(x, w)| w * x * x).sum());
+        Ok(self.data.nrows() - 1)
+    }
+}
+
+/// Dataset under the angular (cosine) distance with a weight per dimension,
+/// `1 - sum_d w_d * x_d * y_d / (|x|_w * |y|_w)` where `|x|_w = sqrt(sum_d w_d * x_d^2)`.
+///
+/// This is the cosine distance of the points scaled by the square roots of the weights, which
+/// is what the PUFFINN indexes and the quantizers are given, see [`MetricData::indexed_point`].
+/// The points are stored as given, and the weights are saved with the index so that it can't
+/// be loaded with other ones.
+#[derive(Clone)]
+pub struct WeightedAngularData<S: Data<Elem = f32> + RawDataClone> {
+    data: ArrayBase<S, Ix2>,
+    weights: Vec<f32>,
+    sqrt_weights: Vec<f32>,
+    weighted_norms: Vec<f32>,
+}
+
+impl<S: Data<Elem = f32> + RawDataClone> WeightedAngularData<S> {
+    /// Dataset of `data` weighing dimension `d` by `weights[d]`.
+    ///
+    /// # Errors
+    /// If there isn't one weight per column of `data`, or a weight is negative or not finite
+    pub fn new(data: ArrayBase<S, Ix2>, weights: Vec<f32>) -> Result<Self, String> {
+        check_weights(&weights, data.ncols())?;
+        Ok(Self::with_weights(data, weights))
+    }
+
+    fn with_weights(data: ArrayBase<S, Ix2>, weights: Vec<f32>) -> Self {
+        let weighted_norms = data
+            .rows()
+            .into_iter()
+            .map(|row| row.iter().zip(&weights).map(|(x, w)| w * x * x).sum::<f32>().sqrt())
+            .collect();
+        Self {
+            sqrt_weights: weights.iter().map(|w| w.sqrt()).collect(),
+            data,
+            weights,
+            weighted_norms,
+        }
+    }
+
+    /// `point` multiplied by the weights and its weighted norm, shared by the distances to it
+    fn weighted_query(&self, point: &[f32]) -> (Vec<f32>, f32) {
+        let weighted: Vec<f32> = point.iter().zip(&self.weights).map(|(x, w)| x * w).collect();
+        let norm = simd::dot(point, &weighted).sqrt();
+        (weighted, norm)
+    }
+
+    fn distance_weighted(&self, i: usize, weighted: &[f32], norm_point: f32) -> f32 {
+        let dot_product = simd::dot(&row(&self.data, i), weighted);
+        1.0 - dot_product / (self.weighted_norms[i] * norm_point)
+    }
+}
+
+impl<S: Data<Elem = f32> + RawDataClone> MetricData for WeightedAngularData<S> {
+    type DataType = f32;
+
+    fn distance(&self, i: usize, j: usize) -> f32 {
+        self.distance_point(i, &row(&self.data, j))
+    }
+
+    fn distance_point(&self, i: usize, point: &[f32]) -> f32 {
+        let (weighted, norm_point) = self.weighted_query(point);
+        self.distance_weighted(i, &weighted, norm_point)
+    }
+
+    fn distances_points(&self, ids: &[usize], point: &[f32], out: &mut [f32]) {
+        assert_eq!(ids.len(), out.len());
+        let (weighted, norm_point) = self.weighted_query(point);
+        for (o, &i) in out.iter_mut().zip(ids) {
+            *o = self.distance_weighted(i, &weighted, norm_point);
+        }
+    }
+
+    fn distance_point_f64(&self, i: usize, point: &[f32]) -> f64 {
+        let mut dot_product = 0.0f64;
+        let mut norm_row = 0.0f64;
+        let mut norm_point = 0.0f64;
+        for ((&x, &y), &w) in self.data.row(i).iter().zip(point).zip(&self.weights) {
+            let (x, y, w) = (x as f64, y as f64, w as f64);
+            dot_product += w * x * y;
+            norm_row += w * x * x;
+            norm_point += w * y * y;
+        }
+
+        1.0 - dot_product / (norm_row.sqrt() * norm_point.sqrt())
+    }
+
+    /// The angle between the scaled points, as for [`AngularData`](crate::metricdata::AngularData)
+    fn distance_to_metric(&self, distance: f32) -> f32 {
+        (1.0 - distance).clamp(-1.0, 1.0).acos()
+    }
+
+    fn metric_to_distance(&self, metric: f32) -> f32 {
+        1.0 - metric.cos()
+    }
+
+    fn all_distances(&self, j: usize, out: &mut [f32]) {
+        assert_eq!(out.len(), self.data.nrows());
+        let (weighted, _) = self.weighted_query(&row(&self.data, j));
+        matvec::dot_rows(&self.data, &weighted, out);
+        for (oo, norm) in out.iter_mut().zip(&self.weighted_norms) {
+            *oo = 1.0 - *oo / (norm * self.weighted_norms[j]);
+        }
+    }
+
+    fn num_points(&self) -> usize {
+        self.data.nrows()
+    }
+
+    fn dimensions(&self) -> usize {
+        self.data.ncols()
+    }
+
+    fn get_point(&self, i: usize) -> Cow<'_, [f32]> {
+        row(&self.data, i)
+    }
+
+    fn indexed_point(&self, i: usize) -> Cow<'_, [f32]> {
+        Cow::Owned(scale(&row(&self.data, i), &self.sqrt_weights))
+    }
+
+    fn indexed_query<'a>(&self, query: &'a [f32]) -> Cow<'a, [f32]> {
+        Cow::Owned(scale(query, &self.sqrt_weights))
+    }
+
+    fn weights(&self) -> Option<&[f32]> {
+        Some(&self.weights)
+    }
+
+    fn scale_invariant(&self) -> bool {
+        true
+    }
+}
+
+impl<S: Data<Elem = f32> + RawDataClone> Subset for WeightedAngularData<S> {
+    type Out = WeightedAngularData<OwnedRepr<f32>>;
+    fn subset(&self, indices: &[usize]) -> Self::Out {
+        WeightedAngularData::with_weights(self.data.select(Axis(0), indices), self.weights.clone())
+    }
+}
+
+impl Insertable for WeightedAngularData<OwnedRepr<f32>> {
+    fn insert(&mut self, point: &[f32]) -> Result<usize, String> {
+        self.data.push_row(ArrayView1::from(point)).map_err(|e| e.to_string())?;
+        self.weighted_norms
+            .push(point.iter().zip(&self.weights).map(|(x, w)| w * x * x).sum::<f32>().sqrt());
+        Ok(self.data.nrows() - 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::Array2;
+
+    use super::{WeightedAngularData, WeightedEuclideanData};
+    use crate::metricdata::{AngularData, EuclideanData, Insertable, MetricData, Subset};
+    use crate::utils::datagen::unit_vectors;
+
+    /// Checks every distance of `weighted` against `plain`, the same points scaled by the
+    /// square roots of the weights
+    fn assert_matches(weighted: &dyn MetricData<DataType = f32>, plain: &dyn MetricData<DataType = f32>, query: &[f32]) {
+        let n = weighted.num_points();
+        let scaled_query = weighted.indexed_query(query);
+        let (mut out, mut expected) = (vec![0.0; n], vec![0.0; n]);
+        weighted.all_distances(3, &mut out);
+        plain.all_distances(3, &mut expected);
+        let ids: Vec<usize> = (0..n).collect();
+        let mut batch = vec![0.0; n];
+        weighted.distances_points(&ids, query, &mut batch);
+
+        for i in 0..n {
+            let expected_point = plain.distance_point(i, &scaled_query);
+            // the square root amplifies the round-off of the distance of point 3 to itself
+            let tolerance = if i == 3 { 1e-2 } else { 1e-4 };
+            assert!((out[i] - expected[i]).abs() < tolerance);
+            assert!((weighted.distance(i, 3) - expected[i]).abs() < tolerance);
+            assert!((weighted.distance_point(i, query) - expected_point).abs() < 1e-4);
+            assert!((batch[i] - expected_point).abs() < 1e-4);
+            assert!((weighted.distance_point_f64(i, query) as f32 - expected_point).abs() < 1e-4);
+            assert_eq!(&*weighted.indexed_point(i), &*plain.get_point(i));
+        }
+    }
+
+    #[test]
+    fn test_weighted_distances() {
+        let points = unit_vectors(40, 6, 1);
+        let weights: Vec<f32> = vec![4.0, 1.0, 0.25, 0.0, 2.0, 1.0];
+        let sqrt_weights = Array2::from_shape_fn((1, 6), |(_, j)| weights[j].sqrt());
+        let scaled = &points * &sqrt_weights;
+        let query = unit_vectors(1, 6, 2).row(0).to_vec();
+
+        let euclidean = WeightedEuclideanData::new(points.clone(), weights.clone()).unwrap();
+        assert_matches(&euclidean, &EuclideanData::new(scaled.clone()), &query);
+        let angular = WeightedAngularData::new(points.clone(), weights.clone()).unwrap();
+        assert_matches(&angular, &AngularData::new(scaled.clone()), &query);
+        assert_eq!(angular.weights(), Some(&weights[..]));
+        assert_eq!(&*angular.get_point(5), points.row(5).as_slice().unwrap());
+
+        // the subsets and the inserted points keep the weights
+        let subset = angular.subset(&[5, 7]);
+        assert!((subset.distance(0, 1) - angular.distance(5, 7)).abs() < 1e-6);
+        let mut euclidean = euclidean;
+        let id = euclidean.insert(&query).unwrap();
+        assert!(euclidean.distance_point(id, &query).abs() < 1e-6);
+        assert!((euclidean.distance(id, 2) - euclidean.distance_point(2, &query)).abs() < 1e-5);
+
+        assert!(WeightedAngularData::new(points.clone(), vec![1.0; 5]).is_err());
+        assert!(WeightedEuclideanData::new(points, vec![1.0, -1.0, 1.0, 1.0, 1.0, 1.0]).is_err());
+    }
+}
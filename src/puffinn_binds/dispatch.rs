@@ -0,0 +1,168 @@
+use std::fmt;
+use std::sync::OnceLock;
+
+use log::{info, warn};
+use serde::Serialize;
+
+use super::puffinn_sys::*;
+
+/// Instruction set of the PUFFINN kernels.
+///
+/// The C++ binder is compiled once per instruction set (see build.rs) and the best variant the
+/// CPU supports is chosen at the first PUFFINN call, so the same binary runs on any x86_64
+/// machine. Other architectures only have the `Base` variant.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Isa {
+    /// Baseline of the target architecture, SSE2 on x86_64
+    Base,
+    /// AVX2 with FMA and POPCNT
+    Avx2,
+    /// AVX-512 F, BW and VL
+    Avx512,
+}
+
+impl Isa {
+    fn name(self) -> &'static str {
+        match self {
+            Isa::Base => "base",
+            Isa::Avx2 => "avx2",
+            Isa::Avx512 => "avx512",
+        }
+    }
+
+    /// Best variant the CPU supports
+    fn detect() -> Self {
+        #[cfg(target_arch = "x86_64")]
+        {
+            let avx2 = is_x86_feature_detected!("avx2")
+                && is_x86_feature_detected!("fma")
+                && is_x86_feature_detected!("popcnt");
+            if avx2
+                && is_x86_feature_detected!("avx512f")
+                && is_x86_feature_detected!("avx512bw")
+                && is_x86_feature_detected!("avx512vl")
+            {
+                return Isa::Avx512;
+            }
+            if avx2 {
+                return Isa::Avx2;
+            }
+        }
+        Isa::Base
+    }
+}
+
+impl fmt::Display for Isa {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Instruction set of the PUFFINN kernels used by this process.
+///
+/// The `CLANN_ISA` environment variable (`base`, `avx2` or `avx512`) selects a lower variant
+/// than the detected one, e.g. to compare them; a variant the CPU doesn't support is ignored.
+pub fn isa() -> Isa {
+    static ISA: OnceLock<Isa> = OnceLock::new();
+    *ISA.get_or_init(|| {
+        let detected = Isa::detect();
+        let isa = match std::env::var("CLANN_ISA") {
+            Ok(name) => match [Isa::Base, Isa::Avx2, Isa::Avx512].into_iter().find(|i| i.name() == name) {
+                Some(requested) if requested <= detected => requested,
+                _ => {
+                    warn!("CLANN_ISA={} is not a variant this CPU supports, using {}", name, detected);
+                    detected
+                }
+            },
+            Err(_) => detected,
+        };
+        info!("PUFFINN kernels: {}", isa);
+        isa
+    })
+}
+
+/// Functions of a variant of the C API
+pub(crate) struct Api {
+    pub(crate) load_from_file: unsafe extern "C" fn(*const cty::c_char, *const cty::c_char) -> *mut CPUFFINN,
+    pub(crate) index_create: unsafe extern "C" fn(*const cty::c_char, cty::c_int) -> *mut CPUFFINN,
+    pub(crate) index_rebuild: unsafe extern "C" fn(*mut CPUFFINN, cty::c_uint) -> u64,
+    pub(crate) set_num_threads: unsafe extern "C" fn(cty::c_int),
+    pub(crate) index_insert_cosine: unsafe extern "C" fn(*mut CPUFFINN, *mut f32, cty::c_int),
+    pub(crate) search_cosine:
+        unsafe extern "C" fn(*mut CPUFFINN, *mut f32, cty::c_uint, f32, f32, cty::c_int, *mut cty::c_uint) -> *mut u32,
+    pub(crate) get_distance_computations: unsafe extern "C" fn() -> cty::c_uint,
+    pub(crate) clear_distance_computations: unsafe extern "C" fn(),
+    pub(crate) save_index: unsafe extern "C" fn(*mut CPUFFINN, *const cty::c_char, cty::c_int),
+    pub(crate) serialize_to_buffer: unsafe extern "C" fn(*mut CPUFFINN, *mut u64) -> *mut u8,
+    pub(crate) load_from_buffer: unsafe extern "C" fn(*const u8, u64) -> *mut CPUFFINN,
+}
+
+static BASE: Api = Api {
+    load_from_file: CPUFFINN_base_load_from_file,
+    index_create: CPUFFINN_base_index_create,
+    index_rebuild: CPUFFINN_base_index_rebuild,
+    set_num_threads: CPUFFINN_base_set_num_threads,
+    index_insert_cosine: CPUFFINN_base_index_insert_cosine,
+    search_cosine: CPUFFINN_base_search_cosine,
+    get_distance_computations: CPUFFINN_base_get_distance_computations,
+    clear_distance_computations: CPUFFINN_base_clear_distance_computations,
+    save_index: CPUFFINN_base_save_index,
+    serialize_to_buffer: CPUFFINN_base_serialize_to_buffer,
+    load_from_buffer: CPUFFINN_base_load_from_buffer,
+};
+
+#[cfg(target_arch = "x86_64")]
+static AVX2: Api = Api {
+    load_from_file: CPUFFINN_avx2_load_from_file,
+    index_create: CPUFFINN_avx2_index_create,
+    index_rebuild: CPUFFINN_avx2_index_rebuild,
+    set_num_threads: CPUFFINN_avx2_set_num_threads,
+    index_insert_cosine: CPUFFINN_avx2_index_insert_cosine,
+    search_cosine: CPUFFINN_avx2_search_cosine,
+    get_distance_computations: CPUFFINN_avx2_get_distance_computations,
+    clear_distance_computations: CPUFFINN_avx2_clear_distance_computations,
+    save_index: CPUFFINN_avx2_save_index,
+    serialize_to_buffer: CPUFFINN_avx2_serialize_to_buffer,
+    load_from_buffer: CPUFFINN_avx2_load_from_buffer,
+};
+
+#[cfg(target_arch = "x86_64")]
+static AVX512: Api = Api {
+    load_from_file: CPUFFINN_avx512_load_from_file,
+    index_create: CPUFFINN_avx512_index_create,
+    index_rebuild: CPUFFINN_avx512_index_rebuild,
+    set_num_threads: CPUFFINN_avx512_set_num_threads,
+    index_insert_cosine: CPUFFINN_avx512_index_insert_cosine,
+    search_cosine: CPUFFINN_avx512_search_cosine,
+    get_distance_computations: CPUFFINN_avx512_get_distance_computations,
+    clear_distance_computations: CPUFFINN_avx512_clear_distance_computations,
+    save_index: CPUFFINN_avx512_save_index,
+    serialize_to_buffer: CPUFFINN_avx512_serialize_to_buffer,
+    load_from_buffer: CPUFFINN_avx512_load_from_buffer,
+};
+
+/// C API of the variant of [`isa`]. Indexes must only be passed to the variant that created
+/// them, which holds as the variant never changes during the process.
+pub(crate) fn api() -> &'static Api {
+    match isa() {
+        #[cfg(target_arch = "x86_64")]
+        Isa::Avx2 => &AVX2,
+        #[cfg(target_arch = "x86_64")]
+        Isa::Avx512 => &AVX512,
+        _ => &BASE,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{isa, Isa};
+
+    #[test]
+    fn test_isa() {
+        assert!(isa() <= Isa::detect());
+        assert_eq!(isa(), isa());
+        assert_eq!(serde_json::to_string(&Isa::Avx512).unwrap(), "\"avx512\"");
+        assert_eq!(Isa::Avx2.to_string(), "avx2");
+    }
+}
@@ -1,7 +1,9 @@
+mod dispatch;
 mod puffinn_sys;
 pub(crate) mod puffinn_types;
 pub mod puffinn;
 
+pub use self::dispatch::{isa, Isa};
 pub use self::puffinn::PuffinnIndex;
 pub(crate) use self::puffinn_types::IndexableSimilarity;
-pub(crate) use self::puffinn::get_distance_computations;
\ No newline at end of file
+pub(crate) use self::puffinn::set_num_threads;
\ No newline at end of file
@@ -1,16 +1,30 @@
-use super::puffinn_sys::{
-    CPUFFINN_clear_distance_computations, CPUFFINN_get_distance_computations,
-    CPUFFINN_index_create, CPUFFINN_index_rebuild, CPUFFINN_load_from_file, CPUFFINN_save_index,
-    CPUFFINN,
-};
+use super::dispatch::api;
+use super::puffinn_sys::CPUFFINN;
 use super::puffinn_types::IndexableSimilarity;
-use crate::metricdata::MetricData;
+use crate::metricdata::{MetricData, Scalar};
+use std::borrow::Cow;
 use std::ffi::CString;
 
+/// `point` in f32, the type of the C API, borrowed when it already is
+fn to_f32<D: Scalar>(point: &[D]) -> Cow<'_, [f32]> {
+    match D::as_f32(point) {
+        Some(point) => Cow::Borrowed(point),
+        None => Cow::Owned(point.iter().map(|x| x.to_f32()).collect()),
+    }
+}
+
 pub struct PuffinnIndex {
     raw: *mut CPUFFINN,
 }
 
+// SAFETY: the PUFFINN index is owned exclusively through `raw` and holds no thread-local
+// state, so it can be moved to another thread. Searches go through `&self`: they keep the
+// hashes and sketches of the query in locals and only read the index, and the distance
+// counters they update are thread-local in the C++ library, so concurrent searches don't
+// race. The index is only mutated when it is built, before it is shared.
+unsafe impl Send for PuffinnIndex {}
+unsafe impl Sync for PuffinnIndex {}
+
 impl PuffinnIndex {
     pub fn new<M: MetricData + IndexableSimilarity<M>>(
         metric_data: &M,
@@ -25,7 +39,7 @@ impl PuffinnIndex {
         })?;
 
         let raw = unsafe {
-            CPUFFINN_index_create(
+            (api().index_create)(
                 dataset_type_cstr.as_ptr(),
                 metric_data.dimensions() as i32
             )
@@ -35,11 +49,12 @@ impl PuffinnIndex {
             return Err("Failed to create PUFFINN index".to_string());
         }
 
-        let index = Self { raw };
+        let index = Self::from_raw(raw);
 
         // Iterate over the data points and insert them.
         for i in 0..metric_data.num_points() {
-            let point = metric_data.get_point(i).to_owned();
+            let point = metric_data.indexed_point(i);
+            let point = to_f32(&point);
             unsafe {
                 M::insert_data(index.raw, point.as_ptr(), metric_data.dimensions() as i32);
             }
@@ -48,7 +63,7 @@ impl PuffinnIndex {
         // Rebuild the index after inserting the points.
         let memory;
         unsafe {
-            let r = CPUFFINN_index_rebuild(index.raw, num_maps as u32);
+            let r = (api().index_rebuild)(index.raw, num_maps as u32);
             if r == 0 {
                 return Err("Failed to create PUFFINN index, insufficient memory".to_string());
             }
@@ -69,9 +84,19 @@ impl PuffinnIndex {
         })?;
 
         let raw =
-            unsafe { CPUFFINN_load_from_file(file_path_cstr.as_ptr(), dataset_name_cstr.as_ptr()) };
+            unsafe { (api().load_from_file)(file_path_cstr.as_ptr(), dataset_name_cstr.as_ptr()) };
+        if raw.is_null() {
+            return Err(format!(
+                "Failed to load PUFFINN index '{}' from {}",
+                dataset_name, file_path
+            ));
+        }
 
-        Ok(Self { raw })
+        Ok(Self::from_raw(raw))
+    }
+
+    fn from_raw(raw: *mut CPUFFINN) -> Self {
+        Self { raw }
     }
 
     pub fn search<M: MetricData + IndexableSimilarity<M>>(
@@ -81,9 +106,25 @@ impl PuffinnIndex {
         max_dist: f32,
         recall: f32,
     ) -> Result<Vec<u32>, String> {
+        self.search_counted::<M>(query, k, max_dist, recall).map(|(results, _)| results)
+    }
+
+    /// [`search`](Self::search), also returning the distance computations of the search
+    /// inside PUFFINN
+    pub fn search_counted<M: MetricData + IndexableSimilarity<M>>(
+        &self,
+        query: &[M::DataType],
+        k: usize,
+        max_dist: f32,
+        recall: f32,
+    ) -> Result<(Vec<u32>, usize), String> {
         let max_sim = M::convert_to_sim(max_dist);
+        let query = to_f32(query);
 
         unsafe {
+            // the counters of the calling thread, the search runs on it
+            (api().clear_distance_computations)();
+            let mut num_results: u32 = 0;
             let results_ptr = M::search_data(
                 self.raw,
                 query.as_ptr(),
@@ -91,30 +132,19 @@ impl PuffinnIndex {
                 recall,
                 max_sim,
                 query.len() as i32,
+                &mut num_results,
             );
+            let distance_computations = (api().get_distance_computations)() as usize;
 
             if results_ptr.is_null() {
                 return Err("Search failed: returned null pointer.".to_string());
             }
 
-            let first_value = *results_ptr;
-
-            if first_value == 0xFFFFFFFF {
-                libc::free(results_ptr as *mut libc::c_void);
-                return Ok(Vec::new());
-            }
-
-            let mut results = Vec::new();
-            let mut offset = 0;
-
-            while offset < k {
-                let val = *(results_ptr.add(offset));
-                results.push(val);
-                offset += 1;
-            }
+            // PUFFINN returns fewer than k ids when the index holds fewer points
+            let results = std::slice::from_raw_parts(results_ptr, num_results as usize).to_vec();
 
             libc::free(results_ptr as *mut libc::c_void);
-            Ok(results)
+            Ok((results, distance_computations))
         }
     }
 
@@ -123,28 +153,62 @@ impl PuffinnIndex {
             .map_err(|_| format!("Failed to convert file name '{}' to CString", file_path))?;
 
         unsafe {
-            CPUFFINN_save_index(self.raw, file_path_cstring.as_ptr(), index_id as i32);
+            (api().save_index)(self.raw, file_path_cstring.as_ptr(), index_id as i32);
         }
 
         Ok(())
     }
-}
 
-pub fn get_distance_computations() -> u32 {
-    unsafe { CPUFFINN_get_distance_computations() }
+    /// Serializes the index into an owned byte buffer, in the same format used by [`save_to_file`](Self::save_to_file).
+    pub fn to_bytes(&self) -> Result<Vec<u8>, String> {
+        let mut buffer_len: u64 = 0;
+
+        unsafe {
+            let buffer_ptr = (api().serialize_to_buffer)(self.raw, &mut buffer_len);
+            if buffer_ptr.is_null() {
+                return Err("Serialization failed: returned null pointer.".to_string());
+            }
+
+            let bytes = std::slice::from_raw_parts(buffer_ptr, buffer_len as usize).to_vec();
+            libc::free(buffer_ptr as *mut libc::c_void);
+            Ok(bytes)
+        }
+    }
+
+    /// Restores an index from a byte buffer produced by [`to_bytes`](Self::to_bytes).
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        if bytes.is_empty() {
+            return Err("Cannot load PUFFINN index from an empty buffer".to_string());
+        }
+
+        let raw = unsafe { (api().load_from_buffer)(bytes.as_ptr(), bytes.len() as u64) };
+
+        if raw.is_null() {
+            return Err("Failed to load PUFFINN index from buffer".to_string());
+        }
+
+        Ok(Self::from_raw(raw))
+    }
 }
 
-pub(crate) fn clear_distance_computations() {
+/// Sets the OpenMP threads of the PUFFINN indexes built from the calling thread afterwards,
+/// instead of the ones `OMP_NUM_THREADS` gives
+pub(crate) fn set_num_threads(num_threads: usize) {
     unsafe {
-        CPUFFINN_clear_distance_computations();
+        (api().set_num_threads)(num_threads.min(i32::MAX as usize) as i32);
     }
 }
 
+/// Distance computations of the last PUFFINN search of the calling thread, whatever the index
+pub fn get_distance_computations() -> u32 {
+    unsafe { (api().get_distance_computations)() }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::metricdata::AngularData;
-    use crate::utils::{brute_force_search, generate_random_unit_vectors, load_hdf5_dataset};
+    use crate::utils::{brute_force_search, datagen::unit_vectors, generate_random_unit_vectors, load_hdf5_dataset};
 
     #[test]
     fn test_angular_create_index() {
@@ -175,6 +239,33 @@ mod tests {
         assert_eq!(results.unwrap().len(), k, "Search did not return k results");
     }
 
+    #[test]
+    fn test_bytes_roundtrip() {
+        let data = AngularData::new(unit_vectors(500, 25, 1));
+        let (index, _memory) = PuffinnIndex::new(&data, 20).expect("Failed to create PuffinnIndex");
+
+        let bytes = index.to_bytes().expect("Serialization failed");
+        assert!(!bytes.is_empty());
+
+        let restored = PuffinnIndex::from_bytes(&bytes).expect("Deserialization failed");
+
+        let query_raw = unit_vectors(1, 25, 2);
+        let binding = query_raw.row(0);
+        let query = binding.as_slice().unwrap();
+        let original_results = index
+            .search::<AngularData<ndarray::OwnedRepr<f32>>>(query, 10, 1.0, 0.9)
+            .unwrap();
+        let restored_results = restored
+            .search::<AngularData<ndarray::OwnedRepr<f32>>>(query, 10, 1.0, 0.9)
+            .unwrap();
+        assert_eq!(original_results.len(), restored_results.len());
+    }
+
+    #[test]
+    fn test_from_empty_bytes() {
+        assert!(PuffinnIndex::from_bytes(&[]).is_err());
+    }
+
     #[test]
     fn test_puffinn_angular_search() {
         let n = 1000;
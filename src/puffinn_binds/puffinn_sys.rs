@@ -6,47 +6,167 @@ pub struct CPUFFINN {
     _unused: [u8; 0],
 }
 unsafe extern "C" {
-    pub fn CPUFFINN_load_from_file(
+    pub fn CPUFFINN_base_load_from_file(
         file_name: *const cty::c_char,
         dataset_name: *const cty::c_char,
     ) -> *mut CPUFFINN;
 }
 unsafe extern "C" {
-    pub fn CPUFFINN_index_create(
+    pub fn CPUFFINN_base_index_create(
         dataset_type: *const cty::c_char,
         dataset_args: cty::c_int,
     ) -> *mut CPUFFINN;
 }
 unsafe extern "C" {
-    pub fn CPUFFINN_index_rebuild(index: *mut CPUFFINN, num_maps: cty::c_uint) -> u64;
+    pub fn CPUFFINN_base_index_rebuild(index: *mut CPUFFINN, num_maps: cty::c_uint) -> u64;
 }
 unsafe extern "C" {
-    pub fn CPUFFINN_index_insert_cosine(
+    pub fn CPUFFINN_base_set_num_threads(num_threads: cty::c_int);
+}
+unsafe extern "C" {
+    pub fn CPUFFINN_base_index_insert_cosine(
+        index: *mut CPUFFINN,
+        point: *mut f32,
+        dimension: cty::c_int,
+    );
+}
+unsafe extern "C" {
+    pub fn CPUFFINN_base_search_cosine(
+        index: *mut CPUFFINN,
+        query: *mut f32,
+        k: cty::c_uint,
+        recall: f32,
+        max_sim: f32,
+        dimension: cty::c_int,
+        result_len: *mut cty::c_uint,
+    ) -> *mut u32;
+}
+unsafe extern "C" {
+    pub fn CPUFFINN_base_get_distance_computations() -> cty::c_uint;
+}
+unsafe extern "C" {
+    pub fn CPUFFINN_base_clear_distance_computations();
+}
+unsafe extern "C" {
+    pub fn CPUFFINN_base_save_index(
+        index: *mut CPUFFINN,
+        file_name: *const cty::c_char,
+        index_number: cty::c_int,
+    );
+}
+unsafe extern "C" {
+    pub fn CPUFFINN_base_serialize_to_buffer(index: *mut CPUFFINN, buffer_len: *mut u64) -> *mut u8;
+}
+unsafe extern "C" {
+    pub fn CPUFFINN_base_load_from_buffer(buffer: *const u8, buffer_len: u64) -> *mut CPUFFINN;
+}
+unsafe extern "C" {
+    pub fn CPUFFINN_avx2_load_from_file(
+        file_name: *const cty::c_char,
+        dataset_name: *const cty::c_char,
+    ) -> *mut CPUFFINN;
+}
+unsafe extern "C" {
+    pub fn CPUFFINN_avx2_index_create(
+        dataset_type: *const cty::c_char,
+        dataset_args: cty::c_int,
+    ) -> *mut CPUFFINN;
+}
+unsafe extern "C" {
+    pub fn CPUFFINN_avx2_index_rebuild(index: *mut CPUFFINN, num_maps: cty::c_uint) -> u64;
+}
+unsafe extern "C" {
+    pub fn CPUFFINN_avx2_set_num_threads(num_threads: cty::c_int);
+}
+unsafe extern "C" {
+    pub fn CPUFFINN_avx2_index_insert_cosine(
+        index: *mut CPUFFINN,
+        point: *mut f32,
+        dimension: cty::c_int,
+    );
+}
+unsafe extern "C" {
+    pub fn CPUFFINN_avx2_search_cosine(
+        index: *mut CPUFFINN,
+        query: *mut f32,
+        k: cty::c_uint,
+        recall: f32,
+        max_sim: f32,
+        dimension: cty::c_int,
+        result_len: *mut cty::c_uint,
+    ) -> *mut u32;
+}
+unsafe extern "C" {
+    pub fn CPUFFINN_avx2_get_distance_computations() -> cty::c_uint;
+}
+unsafe extern "C" {
+    pub fn CPUFFINN_avx2_clear_distance_computations();
+}
+unsafe extern "C" {
+    pub fn CPUFFINN_avx2_save_index(
+        index: *mut CPUFFINN,
+        file_name: *const cty::c_char,
+        index_number: cty::c_int,
+    );
+}
+unsafe extern "C" {
+    pub fn CPUFFINN_avx2_serialize_to_buffer(index: *mut CPUFFINN, buffer_len: *mut u64) -> *mut u8;
+}
+unsafe extern "C" {
+    pub fn CPUFFINN_avx2_load_from_buffer(buffer: *const u8, buffer_len: u64) -> *mut CPUFFINN;
+}
+unsafe extern "C" {
+    pub fn CPUFFINN_avx512_load_from_file(
+        file_name: *const cty::c_char,
+        dataset_name: *const cty::c_char,
+    ) -> *mut CPUFFINN;
+}
+unsafe extern "C" {
+    pub fn CPUFFINN_avx512_index_create(
+        dataset_type: *const cty::c_char,
+        dataset_args: cty::c_int,
+    ) -> *mut CPUFFINN;
+}
+unsafe extern "C" {
+    pub fn CPUFFINN_avx512_index_rebuild(index: *mut CPUFFINN, num_maps: cty::c_uint) -> u64;
+}
+unsafe extern "C" {
+    pub fn CPUFFINN_avx512_set_num_threads(num_threads: cty::c_int);
+}
+unsafe extern "C" {
+    pub fn CPUFFINN_avx512_index_insert_cosine(
         index: *mut CPUFFINN,
         point: *mut f32,
         dimension: cty::c_int,
     );
 }
 unsafe extern "C" {
-    pub fn CPUFFINN_search_cosine(
+    pub fn CPUFFINN_avx512_search_cosine(
         index: *mut CPUFFINN,
         query: *mut f32,
         k: cty::c_uint,
         recall: f32,
         max_sim: f32,
         dimension: cty::c_int,
+        result_len: *mut cty::c_uint,
     ) -> *mut u32;
 }
 unsafe extern "C" {
-    pub fn CPUFFINN_get_distance_computations() -> cty::c_uint;
+    pub fn CPUFFINN_avx512_get_distance_computations() -> cty::c_uint;
 }
 unsafe extern "C" {
-    pub fn CPUFFINN_clear_distance_computations();
+    pub fn CPUFFINN_avx512_clear_distance_computations();
 }
 unsafe extern "C" {
-    pub fn CPUFFINN_save_index(
+    pub fn CPUFFINN_avx512_save_index(
         index: *mut CPUFFINN,
         file_name: *const cty::c_char,
         index_number: cty::c_int,
     );
 }
+unsafe extern "C" {
+    pub fn CPUFFINN_avx512_serialize_to_buffer(index: *mut CPUFFINN, buffer_len: *mut u64) -> *mut u8;
+}
+unsafe extern "C" {
+    pub fn CPUFFINN_avx512_load_from_buffer(buffer: *const u8, buffer_len: u64) -> *mut CPUFFINN;
+}
@@ -1,9 +1,13 @@
 use log::{error, warn};
 use ndarray::Data;
 
-use crate::metricdata::{AngularData, MetricData};
+use crate::metricdata::{
+    AngularData, CustomMetricData, Element, EuclideanData, MetricData, OutOfCoreData, RowSource, Scalar, SubsetView,
+    WeightedAngularData,
+};
 
-use super::puffinn_sys::{CPUFFINN_index_insert_cosine, CPUFFINN_search_cosine, CPUFFINN};
+use super::dispatch::api;
+use super::puffinn_sys::CPUFFINN;
 
 /// This trait extends [`MetricData`] enabling the insertion of the data into the PUFFINN index.
 pub trait IndexableSimilarity<M: MetricData> {
@@ -11,33 +15,44 @@ pub trait IndexableSimilarity<M: MetricData> {
     /// Returns the similarity type as understood by PUFFINN (e.g., "cosine", "angular").
     fn similarity_type(&self) -> &'static str;
 
-    /// Inserts a data point into the PUFFINN index.
+    /// Inserts a data point, converted to f32, into the PUFFINN index.
     /// 
     /// # Safety
     /// Uses a C++ library
     unsafe fn insert_data(
         raw: *mut CPUFFINN,
-        point: *const M::DataType,
+        point: *const f32,
         dimension: i32,
     );
 
-    /// Searches for the nearest neighbors using the PUFFINN index.
+    /// Searches for the nearest neighbors using the PUFFINN index, returning a malloc'd
+    /// buffer of at most `k` ids whose length is written to `result_len`.
     /// 
     /// # Safety
     /// Uses a C++ library
     unsafe fn search_data(
         raw: *mut CPUFFINN,
-        query: *const M::DataType,
+        query: *const f32,
         k: u32,
         recall: f32,
         max_sim: f32,
         dimension: i32,
+        result_len: *mut u32,
     ) -> *mut u32;
 
     fn convert_to_sim(max_dist: f32) -> f32;
+
+    /// Whether the points can be indexed with LSH. Datasets whose distance has no hash family
+    /// return false, and every cluster over them is scanned exhaustively
+    fn lsh_supported(&self) -> bool {
+        true
+    }
 }
 
-impl<S: Data<Elem = f32> + ndarray::RawDataClone, M: MetricData> IndexableSimilarity<M> for AngularData<S> {
+impl<S: Data + ndarray::RawDataClone, M: MetricData> IndexableSimilarity<M> for AngularData<S>
+where
+    S::Elem: Element,
+{
 
     fn similarity_type(&self) -> &'static str {
         "angular"
@@ -45,26 +60,27 @@ impl<S: Data<Elem = f32> + ndarray::RawDataClone, M: MetricData> IndexableSimila
 
     unsafe fn insert_data(
         raw: *mut CPUFFINN,
-        point: *const M::DataType,
+        point: *const f32,
         dimension: i32,
     ) {
-        CPUFFINN_index_insert_cosine(raw, point as *mut f32, dimension);
+        (api().index_insert_cosine)(raw, point as *mut f32, dimension);
     }
 
     unsafe fn search_data(
         raw: *mut CPUFFINN,
-        query: *const M::DataType,
+        query: *const f32,
         k: u32,
         recall: f32,
         max_sim: f32,
         dimension: i32,
+        result_len: *mut u32,
     ) -> *mut u32 {
         if query.is_null() || dimension <= 0 {
             warn!("Empty query or wrong dimensions");
             return std::ptr::null_mut();
         }
     
-        let result_ptr = CPUFFINN_search_cosine(raw, query as *mut f32, k, recall, max_sim, dimension);
+        let result_ptr = (api().search_cosine)(raw, query as *mut f32, k, recall, max_sim, dimension, result_len);
     
         if result_ptr.is_null() {
             error!("Search failed, received null pointer");
@@ -78,3 +94,163 @@ impl<S: Data<Elem = f32> + ndarray::RawDataClone, M: MetricData> IndexableSimila
         1.0 - distance / 2.0
     }
 }
+
+/// The points of a cluster are indexed as the points of the whole dataset
+impl<M, N: MetricData> IndexableSimilarity<N> for SubsetView<'_, M>
+where
+    M: MetricData + IndexableSimilarity<M>,
+{
+    fn similarity_type(&self) -> &'static str {
+        self.data().similarity_type()
+    }
+
+    fn lsh_supported(&self) -> bool {
+        self.data().lsh_supported()
+    }
+
+    unsafe fn insert_data(raw: *mut CPUFFINN, point: *const f32, dimension: i32) {
+        M::insert_data(raw, point, dimension)
+    }
+
+    unsafe fn search_data(
+        raw: *mut CPUFFINN,
+        query: *const f32,
+        k: u32,
+        recall: f32,
+        max_sim: f32,
+        dimension: i32,
+        result_len: *mut u32,
+    ) -> *mut u32 {
+        M::search_data(raw, query, k, recall, max_sim, dimension, result_len)
+    }
+
+    fn convert_to_sim(max_dist: f32) -> f32 {
+        M::convert_to_sim(max_dist)
+    }
+}
+
+/// Angular like [`AngularData`]
+impl<R: RowSource, N: MetricData> IndexableSimilarity<N> for OutOfCoreData<R> {
+    fn similarity_type(&self) -> &'static str {
+        "angular"
+    }
+
+    unsafe fn insert_data(raw: *mut CPUFFINN, point: *const f32, dimension: i32) {
+        <AngularData<ndarray::OwnedRepr<f32>> as IndexableSimilarity<N>>::insert_data(raw, point, dimension)
+    }
+
+    unsafe fn search_data(
+        raw: *mut CPUFFINN,
+        query: *const f32,
+        k: u32,
+        recall: f32,
+        max_sim: f32,
+        dimension: i32,
+        result_len: *mut u32,
+    ) -> *mut u32 {
+        <AngularData<ndarray::OwnedRepr<f32>> as IndexableSimilarity<N>>::search_data(raw, query, k, recall, max_sim, dimension, result_len)
+    }
+
+    fn convert_to_sim(max_dist: f32) -> f32 {
+        <AngularData<ndarray::OwnedRepr<f32>> as IndexableSimilarity<N>>::convert_to_sim(max_dist)
+    }
+}
+
+/// Angular like [`AngularData`], the points and queries being scaled by the square roots of the
+/// weights, see [`MetricData::indexed_point`]
+impl<S: Data<Elem = f32> + ndarray::RawDataClone, N: MetricData> IndexableSimilarity<N> for WeightedAngularData<S> {
+    fn similarity_type(&self) -> &'static str {
+        "angular"
+    }
+
+    unsafe fn insert_data(raw: *mut CPUFFINN, point: *const f32, dimension: i32) {
+        <AngularData<ndarray::OwnedRepr<f32>> as IndexableSimilarity<N>>::insert_data(raw, point, dimension)
+    }
+
+    unsafe fn search_data(
+        raw: *mut CPUFFINN,
+        query: *const f32,
+        k: u32,
+        recall: f32,
+        max_sim: f32,
+        dimension: i32,
+        result_len: *mut u32,
+    ) -> *mut u32 {
+        <AngularData<ndarray::OwnedRepr<f32>> as IndexableSimilarity<N>>::search_data(raw, query, k, recall, max_sim, dimension, result_len)
+    }
+
+    fn convert_to_sim(max_dist: f32) -> f32 {
+        <AngularData<ndarray::OwnedRepr<f32>> as IndexableSimilarity<N>>::convert_to_sim(max_dist)
+    }
+}
+
+/// No hash family, the clusters are always scanned exhaustively
+impl<F, N: MetricData> IndexableSimilarity<N> for CustomMetricData<F>
+where
+    F: Fn(&[f32], &[f32]) -> f32,
+{
+    fn similarity_type(&self) -> &'static str {
+        "custom"
+    }
+
+    unsafe fn insert_data(_raw: *mut CPUFFINN, _point: *const f32, _dimension: i32) {
+        error!("Custom metrics cannot be indexed by PUFFINN");
+    }
+
+    unsafe fn search_data(
+        _raw: *mut CPUFFINN,
+        _query: *const f32,
+        _k: u32,
+        _recall: f32,
+        _max_sim: f32,
+        _dimension: i32,
+        _result_len: *mut u32,
+    ) -> *mut u32 {
+        error!("Custom metrics cannot be searched with PUFFINN");
+        std::ptr::null_mut()
+    }
+
+    fn convert_to_sim(max_dist: f32) -> f32 {
+        max_dist
+    }
+
+    fn lsh_supported(&self) -> bool {
+        false
+    }
+}
+
+/// PUFFINN has no hash family for the Euclidean distance: the clusters are scanned exhaustively,
+/// still pruned by the distances of their centers
+impl<S: Data, N: MetricData> IndexableSimilarity<N> for EuclideanData<S>
+where
+    S::Elem: Scalar,
+{
+    fn similarity_type(&self) -> &'static str {
+        "euclidean"
+    }
+
+    unsafe fn insert_data(_raw: *mut CPUFFINN, _point: *const f32, _dimension: i32) {
+        error!("Euclidean data cannot be indexed by PUFFINN");
+    }
+
+    unsafe fn search_data(
+        _raw: *mut CPUFFINN,
+        _query: *const f32,
+        _k: u32,
+        _recall: f32,
+        _max_sim: f32,
+        _dimension: i32,
+        _result_len: *mut u32,
+    ) -> *mut u32 {
+        error!("Euclidean data cannot be searched with PUFFINN");
+        std::ptr::null_mut()
+    }
+
+    fn convert_to_sim(max_dist: f32) -> f32 {
+        max_dist
+    }
+
+    fn lsh_supported(&self) -> bool {
+        false
+    }
+}
@@ -0,0 +1,480 @@
+//! Python bindings, built with `maturin build --release` (see `pyproject.toml`).
+//!
+//! ```python
+//! import numpy as np
+//! import clann
+//!
+//! data = np.random.rand(10000, 100).astype(np.float32)
+//! index = clann.Index(data, {"num_clusters_factor": 1.0, "num_tables": 10, "delta": 0.9})
+//! index.build()
+//! distances, ids = index.search(data[0], k=10)
+//!
+//! # Euclidean distance, every cluster is scanned exhaustively
+//! index = clann.Index(data, metric="euclidean")
+//!
+//! # no copy of the points, `data` must not be written while the index lives
+//! index = clann.Index(data, copy=False)
+//! ```
+
+use ndarray::{Array2, ArrayView2, ViewRepr};
+use numpy::{IntoPyArray, PyArray1, PyArray2, PyArrayMethods, PyReadonlyArray1, PyReadonlyArray2, PyUntypedArrayMethods};
+use pyo3::exceptions::{PyRuntimeError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+
+use crate::core::index::ClusteredIndex;
+use crate::core::{ClusteredIndexError, Config, SearchParams};
+use crate::metricdata::{AngularData, EuclideanData};
+
+/// Points of the numpy array kept alive by the [`PyIndex`] searching them
+type PyPoints = ViewRepr<&'static f32>;
+
+/// Index over the points of a numpy array, with the distance chosen in Python
+enum MetricIndex {
+    Angular(ClusteredIndex<AngularData<PyPoints>>),
+    Euclidean(ClusteredIndex<EuclideanData<PyPoints>>),
+}
+
+/// Evaluates `$body` with `$index` bound to the index of `$metric_index`, whatever its distance
+macro_rules! with_index {
+    ($metric_index:expr, $index:ident => $body:expr) => {
+        match $metric_index {
+            MetricIndex::Angular($index) => $body,
+            MetricIndex::Euclidean($index) => $body,
+        }
+    };
+}
+
+/// Distance of an index, `"angular"` or `"euclidean"` in Python
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Metric {
+    Angular,
+    Euclidean,
+}
+
+impl Metric {
+    fn parse(metric: &str) -> PyResult<Self> {
+        match metric {
+            "angular" => Ok(Metric::Angular),
+            "euclidean" => Ok(Metric::Euclidean),
+            _ => Err(PyValueError::new_err(format!(
+                "unknown metric '{}', expected 'angular' or 'euclidean'",
+                metric
+            ))),
+        }
+    }
+}
+
+/// Distances and ids of the neighbors of a query
+type PySearchResult<'py> = (Bound<'py, PyArray1<f32>>, Bound<'py, PyArray1<i64>>);
+
+/// Distances and ids of the neighbors of a batch of queries, one row per query
+type PyBatchResult<'py> = (Bound<'py, PyArray2<f32>>, Bound<'py, PyArray2<i64>>);
+
+fn to_py_err(e: ClusteredIndexError) -> PyErr {
+    PyRuntimeError::new_err(e.to_string())
+}
+
+/// Builds a [`Config`] from a Python dict, missing keys take their default value.
+fn config_from_dict(py: Python<'_>, config: Option<&Bound<'_, PyDict>>) -> PyResult<Config> {
+    let mut value = serde_json::to_value(Config::default())
+        .map_err(|e| PyRuntimeError::new_err(e.to_string()))?;
+
+    if let Some(config) = config {
+        let json: String = py
+            .import("json")?
+            .call_method1("dumps", (config,))?
+            .extract()?;
+        let overrides: serde_json::Map<String, serde_json::Value> =
+            serde_json::from_str(&json).map_err(|e| PyValueError::new_err(e.to_string()))?;
+        for (key, v) in overrides {
+            if value.get(&key).is_none() {
+                return Err(PyValueError::new_err(format!("unknown config key '{}'", key)));
+            }
+            value[key] = v;
+        }
+    }
+
+    serde_json::from_value(value).map_err(|e| PyValueError::new_err(e.to_string()))
+}
+
+/// Array whose points an index searches: a C-contiguous copy of `data` that only the index
+/// references, or with `copy` false `data` itself, whose flags are left as they are. An array
+/// that is not C-contiguous cannot be searched in place and is refused without `copy`.
+fn indexed_array<'py>(py: Python<'py>, data: Bound<'py, PyArray2<f32>>, copy: bool) -> PyResult<Bound<'py, PyArray2<f32>>> {
+    if copy {
+        let points = data.readonly().as_array().as_standard_layout().into_owned();
+        Ok(PyArray2::from_owned_array(py, points))
+    } else if data.is_c_contiguous() {
+        Ok(data)
+    } else {
+        Err(PyValueError::new_err(
+            "copy=False needs a C-contiguous array, pass numpy.ascontiguousarray(data) or copy=True",
+        ))
+    }
+}
+
+/// Points of `array` borrowed for as long as the index holding `array` lives.
+///
+/// # Safety
+/// The view must not outlive `array`, and `array` must not be written while the view lives:
+/// [`PyIndex`] keeps a reference to the array, dropped after its index, and the array is
+/// either a copy only the index references, or lent with `copy=False` by a caller who doesn't
+/// write it.
+unsafe fn borrow_points(array: &Bound<'_, PyArray2<f32>>) -> ArrayView2<'static, f32> {
+    std::mem::transmute::<ArrayView2<'_, f32>, ArrayView2<'static, f32>>(array.as_array())
+}
+
+/// Search options of a call, without `k` the index searches with the k of its configuration
+fn search_params(k: Option<usize>, exclude: &[usize]) -> SearchParams<'_> {
+    SearchParams {
+        exclude,
+        k,
+        ..Default::default()
+    }
+}
+
+/// Pads the results of a batch to `k` columns, with infinite distance and id -1.
+fn pad_results(results: &[Vec<(f32, usize)>], k: usize) -> (Array2<f32>, Array2<i64>) {
+    let mut distances = Array2::from_elem((results.len(), k), f32::INFINITY);
+    let mut ids = Array2::from_elem((results.len(), k), -1i64);
+    for (i, result) in results.iter().enumerate() {
+        for (j, &(distance, id)) in result.iter().take(k).enumerate() {
+            distances[[i, j]] = distance;
+            ids[[i, j]] = id as i64;
+        }
+    }
+    (distances, ids)
+}
+
+/// Clustered LSH index over the rows of a float32 numpy array, with angular (the default) or
+/// Euclidean distance.
+///
+/// The points are copied once, so that writing the array afterwards doesn't change the index.
+/// With `copy=False` the index searches the buffer of the array instead, which it keeps alive:
+/// the array must be C-contiguous and must not be written while the index lives.
+#[pyclass(name = "Index", module = "clann", unsendable)]
+struct PyIndex {
+    // declared first so that it is dropped before the array it borrows
+    index: MetricIndex,
+    _array: Py<PyArray2<f32>>,
+}
+
+impl PyIndex {
+    /// Index created by `create` over the points of `data`, copied unless `copy` is false, with
+    /// the distance `metric`
+    fn with_points(
+        py: Python<'_>,
+        data: Bound<'_, PyArray2<f32>>,
+        metric: &str,
+        copy: bool,
+        create: impl FnOnce(Metric, ArrayView2<'static, f32>) -> PyResult<MetricIndex>,
+    ) -> PyResult<Self> {
+        let metric = Metric::parse(metric)?;
+        let array = indexed_array(py, data, copy)?;
+        // SAFETY: the array is a private copy or lent unwritten with copy=False, and is owned by
+        // the returned PyIndex with the index
+        let points = unsafe { borrow_points(&array) };
+        let index = create(metric, points)?;
+
+        Ok(Self {
+            index,
+            _array: array.unbind(),
+        })
+    }
+}
+
+#[pymethods]
+impl PyIndex {
+    #[new]
+    #[pyo3(signature = (data, config = None, metric = "angular", copy = true))]
+    fn new(
+        py: Python<'_>,
+        data: Bound<'_, PyArray2<f32>>,
+        config: Option<&Bound<'_, PyDict>>,
+        metric: &str,
+        copy: bool,
+    ) -> PyResult<Self> {
+        let config = config_from_dict(py, config)?;
+        Self::with_points(py, data, metric, copy, |metric, points| {
+            Ok(match metric {
+                Metric::Angular => {
+                    MetricIndex::Angular(ClusteredIndex::new(config, AngularData::from_view(points)).map_err(to_py_err)?)
+                }
+                Metric::Euclidean => {
+                    MetricIndex::Euclidean(ClusteredIndex::new(config, EuclideanData::new(points)).map_err(to_py_err)?)
+                }
+            })
+        })
+    }
+
+    /// Loads an index written by `save`, `data` must be the array it was built on and
+    /// `metric` the distance it was built with. `copy` is as in the constructor.
+    ///
+    /// The hash tables of the clusters are decoded into memory of the process, with or without
+    /// `copy`, so processes loading the same file only share the cluster members.
+    #[staticmethod]
+    #[pyo3(signature = (data, file_path, metric = "angular", copy = true))]
+    fn load(
+        py: Python<'_>,
+        data: Bound<'_, PyArray2<f32>>,
+        file_path: &str,
+        metric: &str,
+        copy: bool,
+    ) -> PyResult<Self> {
+        Self::with_points(py, data, metric, copy, |metric, points| {
+            Ok(match metric {
+                Metric::Angular => MetricIndex::Angular(
+                    ClusteredIndex::new_from_mmap(AngularData::from_view(points), file_path).map_err(to_py_err)?,
+                ),
+                Metric::Euclidean => MetricIndex::Euclidean(
+                    ClusteredIndex::new_from_mmap(EuclideanData::new(points), file_path).map_err(to_py_err)?,
+                ),
+            })
+        })
+    }
+
+    fn build(&mut self) -> PyResult<()> {
+        with_index!(&mut self.index, index => index.build().map_err(to_py_err))
+    }
+
+    /// Saves the index in `directory` and returns the path of the written file.
+    fn save(&self, directory: &str) -> PyResult<String> {
+        with_index!(&self.index, index => {
+            index.serialize_binary(directory).map_err(to_py_err)?;
+            Ok(index.binary_file_path(directory))
+        })
+    }
+
+    /// Returns the distances and ids of the `k` nearest neighbors of `query`, closest first,
+    /// leaving out the ids in `exclude`. Without `k`, the k of the configuration is used.
+    #[pyo3(signature = (query, k = None, exclude = None))]
+    fn search<'py>(
+        &mut self,
+        py: Python<'py>,
+        query: PyReadonlyArray1<'py, f32>,
+        k: Option<usize>,
+        exclude: Option<Vec<usize>>,
+    ) -> PyResult<PySearchResult<'py>> {
+        let query = query
+            .as_slice()
+            .map_err(|e| PyValueError::new_err(e.to_string()))?;
+        let params = search_params(k, exclude.as_deref().unwrap_or_default());
+        let result = with_index!(&mut self.index, index => index.search_with(query, &params)).map_err(to_py_err)?;
+
+        let (distances, ids): (Vec<f32>, Vec<i64>) =
+            result.into_iter().map(|(d, id)| (d, id as i64)).unzip();
+        Ok((distances.into_pyarray(py), ids.into_pyarray(py)))
+    }
+
+    /// Searches every row of `queries`, returning `(n, k)` arrays of distances and ids.
+    ///
+    /// Rows with fewer than `k` results are padded with infinite distances and id -1.
+    #[pyo3(signature = (queries, k = None))]
+    fn search_batch<'py>(
+        &mut self,
+        py: Python<'py>,
+        queries: PyReadonlyArray2<'py, f32>,
+        k: Option<usize>,
+    ) -> PyResult<PyBatchResult<'py>> {
+        let params = search_params(k, &[]);
+        let (results, default_k) = with_index!(&mut self.index, index => {
+            (index.search_batch_with(&queries.as_array(), &params), index.config().k)
+        });
+        let results = results.map_err(to_py_err)?;
+
+        let (distances, ids) = pad_results(&results, k.unwrap_or(default_k));
+        Ok((distances.into_pyarray(py), ids.into_pyarray(py)))
+    }
+}
+
+#[pymodule]
+fn clann(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyIndex>()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{config_from_dict, pad_results, MetricIndex, PyIndex};
+    use crate::core::IndexMode;
+    use crate::metricdata::MetricData;
+    use crate::utils::datagen::unit_vectors;
+    use numpy::{PyArray2, PyArrayMethods, PyUntypedArrayMethods};
+    use pyo3::prelude::*;
+    use pyo3::types::{PyDict, PySlice};
+
+    fn flat_index(py: Python<'_>, data: &Bound<'_, PyArray2<f32>>, metric: &str) -> PyIndex {
+        let config = PyDict::new(py);
+        config.set_item("index_mode", "Flat").unwrap();
+        let mut index = PyIndex::new(py, data.clone(), Some(&config), metric, true).unwrap();
+        index.build().unwrap();
+        index
+    }
+
+    #[test]
+    fn test_config_from_dict() {
+        Python::initialize();
+        Python::attach(|py| {
+            let config = PyDict::new(py);
+            config.set_item("index_mode", "Flat").unwrap();
+            config.set_item("k", 7).unwrap();
+            let config = config_from_dict(py, Some(&config)).unwrap();
+            assert_eq!(config.index_mode, IndexMode::Flat);
+            assert_eq!(config.k, 7);
+
+            let unknown = PyDict::new(py);
+            unknown.set_item("num_tabels", 4).unwrap();
+            assert!(config_from_dict(py, Some(&unknown)).is_err());
+        });
+    }
+
+    #[test]
+    fn test_pad_results() {
+        let (distances, ids) = pad_results(&[vec![(0.5, 3)], vec![(0.1, 1), (0.2, 2)]], 2);
+        assert_eq!(ids.row(0).to_vec(), vec![3, -1]);
+        assert_eq!(ids.row(1).to_vec(), vec![1, 2]);
+        assert_eq!(distances[[0, 1]], f32::INFINITY);
+    }
+
+    #[test]
+    fn test_data_is_copied() {
+        Python::initialize();
+        Python::attach(|py| {
+            let points = unit_vectors(500, 16, 1);
+            let data = PyArray2::from_array(py, &points);
+            let mut index = flat_index(py, &data, "angular");
+
+            // the array of the caller is left writable, writing it doesn't change the index
+            let MetricIndex::Angular(inner) = &index.index else {
+                panic!("expected an angular index");
+            };
+            assert_ne!(inner.data().get_point(0).as_ptr(), data.data() as *const f32);
+            assert!(data.getattr("flags").unwrap().getattr("writeable").unwrap().extract::<bool>().unwrap());
+            data.readwrite().as_array_mut().fill(0.0);
+            let query = numpy::PyArray1::from_slice(py, points.row(7).as_slice().unwrap());
+            let (_, ids) = index.search(py, query.readonly(), Some(1), None).unwrap();
+            assert_eq!(ids.readonly().as_slice().unwrap(), &[7]);
+        });
+    }
+
+    #[test]
+    fn test_data_is_borrowed() {
+        Python::initialize();
+        Python::attach(|py| {
+            let points = unit_vectors(500, 16, 2);
+            let data = PyArray2::from_array(py, &points);
+            let config = PyDict::new(py);
+            config.set_item("index_mode", "Flat").unwrap();
+            let mut index = PyIndex::new(py, data.clone(), Some(&config), "angular", false).unwrap();
+            index.build().unwrap();
+
+            // the index searches the buffer of the array, whose flags are left as they are
+            let MetricIndex::Angular(inner) = &index.index else {
+                panic!("expected an angular index");
+            };
+            assert_eq!(inner.data().get_point(0).as_ptr(), data.data() as *const f32);
+            assert!(data.getattr("flags").unwrap().getattr("writeable").unwrap().extract::<bool>().unwrap());
+
+            // the index keeps the array alive once Python drops it
+            drop(data);
+            let query = numpy::PyArray1::from_slice(py, points.row(0).as_slice().unwrap());
+            let (_, ids) = index.search(py, query.readonly(), Some(1), None).unwrap();
+            assert_eq!(ids.readonly().as_slice().unwrap(), &[0]);
+
+            // an array that is not C-contiguous can't be searched in place
+            let fortran = points.t().as_standard_layout().into_owned().reversed_axes();
+            let data = PyArray2::from_owned_array(py, fortran);
+            assert!(PyIndex::new(py, data, Some(&config), "angular", false).is_err());
+        });
+    }
+
+    #[test]
+    fn test_non_contiguous_data_is_copied() {
+        Python::initialize();
+        Python::attach(|py| {
+            let points = unit_vectors(500, 16, 3);
+            // a Fortran-ordered array, stored column after column
+            let fortran = points.t().as_standard_layout().into_owned().reversed_axes();
+            let data = PyArray2::from_owned_array(py, fortran);
+            assert!(!data.is_c_contiguous());
+            let mut index = flat_index(py, &data, "angular");
+
+            // the array of the caller stays writable, the index searches its own copy
+            assert!(data.try_readwrite().is_ok());
+            data.readwrite().as_array_mut().fill(0.0);
+            let query = numpy::PyArray1::from_slice(py, points.row(7).as_slice().unwrap());
+            let (_, ids) = index.search(py, query.readonly(), Some(1), None).unwrap();
+            assert_eq!(ids.readonly().as_slice().unwrap(), &[7]);
+        });
+    }
+
+    #[test]
+    fn test_view_is_copied() {
+        Python::initialize();
+        Python::attach(|py| {
+            let points = unit_vectors(500, 16, 4);
+            let base = PyArray2::from_array(py, &points);
+            // the first rows, sharing the buffer of `base`
+            let data: Bound<'_, PyArray2<f32>> =
+                base.get_item(PySlice::new(py, 0, 250, 1)).unwrap().extract().unwrap();
+            assert!(data.is_c_contiguous());
+            let mut index = flat_index(py, &data, "angular");
+
+            // writing the base array doesn't change the points of the index
+            assert!(base.try_readwrite().is_ok());
+            base.readwrite().as_array_mut().fill(0.0);
+            let query = numpy::PyArray1::from_slice(py, points.row(7).as_slice().unwrap());
+            let (_, ids) = index.search(py, query.readonly(), Some(1), None).unwrap();
+            assert_eq!(ids.readonly().as_slice().unwrap(), &[7]);
+        });
+    }
+
+    #[test]
+    fn test_metric() {
+        Python::initialize();
+        Python::attach(|py| {
+            // the nearest point of the origin is the shortest, whatever its direction
+            let points = ndarray::array![[3.0f32, 0.0], [0.0, 1.0], [2.0, 2.0]];
+            let data = PyArray2::from_owned_array(py, points);
+            let mut index = flat_index(py, &data, "euclidean");
+            assert!(matches!(index.index, MetricIndex::Euclidean(_)));
+
+            let query = numpy::PyArray1::from_slice(py, &[0.1f32, 0.0]);
+            let (distances, ids) = index.search(py, query.readonly(), Some(2), None).unwrap();
+            assert_eq!(ids.readonly().as_slice().unwrap(), &[1, 2]);
+            assert!((distances.readonly().as_slice().unwrap()[0] - 1.01f32.sqrt()).abs() < 1e-4);
+
+            let config = PyDict::new(py);
+            assert!(PyIndex::new(py, data, Some(&config), "hamming", true).is_err());
+        });
+    }
+
+    #[test]
+    fn test_k_per_call() {
+        Python::initialize();
+        Python::attach(|py| {
+            let points = unit_vectors(500, 16, 5);
+            let data = PyArray2::from_owned_array(py, points.clone());
+            let mut index = flat_index(py, &data, "angular");
+            let default_k = with_index!(&index.index, index => index.config().k);
+
+            let query = numpy::PyArray1::from_slice(py, points.row(0).as_slice().unwrap());
+            let (_, ids) = index.search(py, query.readonly(), Some(3), Some(vec![0])).unwrap();
+            assert_eq!(ids.len(), 3);
+            assert!(!ids.readonly().as_slice().unwrap().contains(&0));
+            // a call without k doesn't inherit the k of the previous one
+            let (_, ids) = index.search(py, query.readonly(), None, None).unwrap();
+            assert_eq!(ids.len(), default_k);
+            // a k of 0 is an error rather than an empty result
+            assert!(index.search(py, query.readonly(), Some(0), None).is_err());
+
+            let queries = PyArray2::from_owned_array(py, points.slice(ndarray::s![..4, ..]).to_owned());
+            let (distances, ids) = index.search_batch(py, queries.readonly(), Some(2)).unwrap();
+            assert_eq!(ids.shape(), &[4, 2]);
+            assert_eq!(distances.shape(), &[4, 2]);
+            let (_, ids) = index.search_batch(py, queries.readonly(), None).unwrap();
+            assert_eq!(ids.shape(), &[4, default_k]);
+        });
+    }
+}
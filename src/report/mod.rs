@@ -0,0 +1,673 @@
+//! Markdown and HTML reports of the metrics database.
+//!
+//! [`Report::from_db`] aggregates the runs saved with [`save_metrics`](crate::save_metrics)
+//! by configuration, averaging the repetitions of each one, and [`Report::write`] emits a
+//! recall-vs-QPS table and plot per dataset, followed by the per-cluster breakdown of every
+//! configuration: visits, prunes and top-k contributions of the search, and build time of
+//! the clusters. Plots are SVG, written next to the Markdown file or inlined in the HTML one.
+//!
+//! ```no_run
+//! use clann::report::{Report, ReportFormat};
+//!
+//! let report = Report::from_db("./results_v2.sqlite3").unwrap();
+//! let path = report.write("./report", ReportFormat::Html).unwrap();
+//! println!("Report written to {}", path.display());
+//! ```
+
+use std::collections::BTreeMap;
+use std::fmt::{Display, Write};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use plotters::prelude::*;
+use rusqlite::Connection;
+use serde::Serialize;
+
+use crate::core::{ClusteredIndexError, Result};
+use crate::utils::metrics::schema::sqlite_migrate;
+
+/// Parameters identifying a configuration, runs sharing them are averaged together.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RunConfig {
+    pub dataset: String,
+    pub num_clusters_factor: f64,
+    pub num_tables: usize,
+    pub k: usize,
+    pub delta: f64,
+}
+
+impl RunConfig {
+    /// Parameters of the index, the points of a recall-vs-QPS curve share them and vary `delta`
+    fn series_label(&self) -> String {
+        format!(
+            "factor {}, L {}, k {}",
+            self.num_clusters_factor, self.num_tables, self.k
+        )
+    }
+
+    fn title(&self) -> String {
+        format!(
+            "num_clusters_factor {}, num_tables {}, k {}, delta {}",
+            self.num_clusters_factor, self.num_tables, self.k, self.delta
+        )
+    }
+
+    /// Name of the per-cluster plot, unique among the configurations of a database
+    fn file_stem(&self) -> String {
+        format!(
+            "clusters_{}_f{}_L{}_k{}_d{}",
+            sanitize(&self.dataset),
+            self.num_clusters_factor,
+            self.num_tables,
+            self.k,
+            self.delta
+        )
+    }
+}
+
+/// Search results of a configuration, averaged over its runs.
+#[derive(Debug, Clone, Serialize)]
+pub struct ConfigSummary {
+    pub config: RunConfig,
+
+    /// Number of runs saved with the configuration
+    pub runs: usize,
+
+    /// `None` if no run was saved with ground truth
+    pub recall_mean: Option<f64>,
+    pub recall_std: Option<f64>,
+
+    pub queries_per_second: f64,
+    pub search_time_ms: f64,
+}
+
+/// Statistics of a cluster, averaged over the runs of a configuration.
+#[derive(Debug, Clone, Serialize)]
+pub struct ClusterSummary {
+    pub cluster_idx: usize,
+
+    /// `None` if the build metrics of the runs were not saved
+    pub num_points: Option<f64>,
+
+    /// `None` if the build metrics of the runs were not saved, or predate per-cluster build times
+    pub build_time_ms: Option<f64>,
+
+    /// Queries that searched the cluster
+    pub visits: f64,
+
+    /// Queries that skipped the cluster
+    pub prunes: f64,
+
+    /// Queries with at least one point of the cluster in their final top-k
+    pub topk_contributions: f64,
+}
+
+/// Per-cluster statistics of a configuration.
+#[derive(Debug, Clone, Serialize)]
+pub struct ClusterBreakdown {
+    pub config: RunConfig,
+
+    /// Ordered by cluster index
+    pub clusters: Vec<ClusterSummary>,
+}
+
+/// Output format of [`Report::write`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportFormat {
+    /// `report.md`, with the plots as SVG files in a `plots` directory next to it
+    Markdown,
+    /// `report.html`, a single file with the plots inlined
+    Html,
+}
+
+/// Summary of the runs in a metrics database.
+#[derive(Debug, Clone, Serialize)]
+pub struct Report {
+    /// Ordered by dataset, then by configuration
+    pub configs: Vec<ConfigSummary>,
+
+    /// Configurations without search summaries, saved before they were recorded, are missing
+    pub clusters: Vec<ClusterBreakdown>,
+}
+
+impl Report {
+    /// Reads a metrics database, applying its missing migrations first.
+    ///
+    /// # Errors
+    /// Returns `ClusteredIndexError::ResultDBError` if the database doesn't exist or can't be read
+    pub fn from_db(db_path: &str) -> Result<Self> {
+        if !Path::new(db_path).exists() {
+            return Err(ClusteredIndexError::ResultDBError(format!(
+                "Metrics database {} not found",
+                db_path
+            )));
+        }
+
+        let mut conn = Connection::open(db_path)
+            .map_err(|e| ClusteredIndexError::ResultDBError(e.to_string()))?;
+        sqlite_migrate(&mut conn).map_err(|e| ClusteredIndexError::ResultDBError(e.to_string()))?;
+
+        Self::from_connection(&conn).map_err(|e| ClusteredIndexError::ResultDBError(e.to_string()))
+    }
+
+    fn from_connection(conn: &Connection) -> rusqlite::Result<Self> {
+        let mut stmt = conn.prepare(
+            "SELECT dataset, num_clusters, num_tables, k, delta, COUNT(*),
+                AVG(recall_mean), AVG(recall_std), AVG(queries_per_second), AVG(search_time_ms)
+            FROM search_metrics
+            GROUP BY dataset, num_clusters, num_tables, k, delta
+            ORDER BY dataset, num_clusters, num_tables, k, delta",
+        )?;
+        let configs = stmt
+            .query_map([], |row| {
+                Ok(ConfigSummary {
+                    config: RunConfig {
+                        dataset: row.get(0)?,
+                        num_clusters_factor: row.get(1)?,
+                        num_tables: row.get(2)?,
+                        k: row.get(3)?,
+                        delta: row.get(4)?,
+                    },
+                    runs: row.get(5)?,
+                    recall_mean: row.get(6)?,
+                    recall_std: row.get(7)?,
+                    queries_per_second: row.get::<_, Option<f64>>(8)?.unwrap_or(0.0),
+                    search_time_ms: row.get::<_, Option<f64>>(9)?.unwrap_or(0.0),
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        // build metrics belong to the same run as the search summary
+        let mut stmt = conn.prepare(
+            "SELECT s.dataset, s.num_clusters, s.num_tables, s.k, s.delta, s.cluster_idx,
+                AVG(b.num_points), AVG(b.build_time_ms), AVG(s.visits), AVG(s.prunes), AVG(s.topk_contributions)
+            FROM search_metrics_cluster_summary s
+            LEFT JOIN build_metrics_cluster b ON b.run_id = s.run_id AND b.cluster_idx = s.cluster_idx
+            GROUP BY s.dataset, s.num_clusters, s.num_tables, s.k, s.delta, s.cluster_idx
+            ORDER BY s.dataset, s.num_clusters, s.num_tables, s.k, s.delta, s.cluster_idx",
+        )?;
+        let mut clusters: Vec<ClusterBreakdown> = Vec::new();
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                RunConfig {
+                    dataset: row.get(0)?,
+                    num_clusters_factor: row.get(1)?,
+                    num_tables: row.get(2)?,
+                    k: row.get(3)?,
+                    delta: row.get(4)?,
+                },
+                ClusterSummary {
+                    cluster_idx: row.get(5)?,
+                    num_points: row.get(6)?,
+                    build_time_ms: row.get(7)?,
+                    visits: row.get(8)?,
+                    prunes: row.get(9)?,
+                    topk_contributions: row.get(10)?,
+                },
+            ))
+        })?;
+        for row in rows {
+            let (config, cluster) = row?;
+            match clusters.last_mut() {
+                Some(breakdown) if breakdown.config == config => breakdown.clusters.push(cluster),
+                _ => clusters.push(ClusterBreakdown {
+                    config,
+                    clusters: vec![cluster],
+                }),
+            }
+        }
+
+        Ok(Self { configs, clusters })
+    }
+
+    /// Datasets of the report, in order
+    pub fn datasets(&self) -> Vec<&str> {
+        let mut datasets: Vec<&str> = self.configs.iter().map(|c| c.config.dataset.as_str()).collect();
+        datasets.dedup();
+        datasets
+    }
+
+    /// Recall-vs-QPS plot of a dataset, one curve per index configuration and `k`.
+    ///
+    /// # Returns
+    /// `None` if no run of the dataset was saved with ground truth
+    ///
+    /// # Errors
+    /// Returns `ClusteredIndexError::MetricsError` if the plot can't be drawn
+    pub fn recall_qps_svg(&self, dataset: &str) -> Result<Option<String>> {
+        let mut series: BTreeMap<String, Vec<(f64, f64)>> = BTreeMap::new();
+        for summary in self.configs.iter().filter(|c| c.config.dataset == dataset) {
+            if let Some(recall) = summary.recall_mean {
+                if summary.queries_per_second > 0.0 {
+                    series
+                        .entry(summary.config.series_label())
+                        .or_default()
+                        .push((recall, summary.queries_per_second));
+                }
+            }
+        }
+        if series.is_empty() {
+            return Ok(None);
+        }
+
+        let qps = series.values().flatten().map(|&(_, qps)| qps);
+        let min_qps = qps.clone().fold(f64::INFINITY, f64::min);
+        let max_qps = qps.fold(0.0, f64::max);
+
+        let mut svg = String::new();
+        {
+            let root = SVGBackend::with_string(&mut svg, (800, 500)).into_drawing_area();
+            root.fill(&WHITE).map_err(plot_error)?;
+
+            let mut chart = ChartBuilder::on(&root)
+                .caption(format!("{}: recall vs QPS", dataset), ("sans-serif", 20))
+                .margin(10)
+                .x_label_area_size(40)
+                .y_label_area_size(70)
+                .build_cartesian_2d(0f64..1f64, (min_qps * 0.8..max_qps * 1.25).log_scale())
+                .map_err(plot_error)?;
+            chart
+                .configure_mesh()
+                .x_desc("Recall")
+                .y_desc("Queries per second")
+                .draw()
+                .map_err(plot_error)?;
+
+            for (i, (label, mut points)) in series.into_iter().enumerate() {
+                points.sort_by(|a, b| a.0.total_cmp(&b.0));
+                let color = Palette99::pick(i).to_rgba();
+                chart
+                    .draw_series(LineSeries::new(points.iter().copied(), color.stroke_width(2)))
+                    .map_err(plot_error)?
+                    .label(label)
+                    .legend(move |(x, y)| PathElement::new(vec![(x, y), (x + 20, y)], color));
+                chart
+                    .draw_series(points.iter().map(|&p| Circle::new(p, 3, color.filled())))
+                    .map_err(plot_error)?;
+            }
+
+            chart
+                .configure_series_labels()
+                .position(SeriesLabelPosition::LowerLeft)
+                .background_style(WHITE.mix(0.8))
+                .border_style(BLACK)
+                .draw()
+                .map_err(plot_error)?;
+            root.present().map_err(plot_error)?;
+        }
+
+        Ok(Some(svg))
+    }
+
+    /// Emits the report as Markdown, the plots are referenced as `plots/<name>.svg`
+    /// and are written by [`Report::write`]
+    pub fn to_markdown(&self) -> String {
+        let mut md = String::from("# CLANN report\n");
+
+        for dataset in self.datasets() {
+            let _ = write!(md, "\n## {}\n\n", dataset);
+            if self.has_recall(dataset) {
+                let _ = write!(
+                    md,
+                    "![Recall vs QPS](plots/{}.svg)\n\n",
+                    recall_qps_stem(dataset)
+                );
+            }
+
+            md.push_str("| num_clusters_factor | num_tables | k | delta | runs | recall | recall std | QPS | search time (ms) |\n");
+            md.push_str("|---:|---:|---:|---:|---:|---:|---:|---:|---:|\n");
+            for summary in self.configs.iter().filter(|c| c.config.dataset == dataset) {
+                let c = &summary.config;
+                let _ = writeln!(
+                    md,
+                    "| {} | {} | {} | {} | {} | {} | {} | {:.1} | {:.1} |",
+                    c.num_clusters_factor,
+                    c.num_tables,
+                    c.k,
+                    c.delta,
+                    summary.runs,
+                    fmt_option(summary.recall_mean, 4),
+                    fmt_option(summary.recall_std, 4),
+                    summary.queries_per_second,
+                    summary.search_time_ms
+                );
+            }
+
+            for breakdown in self.clusters.iter().filter(|b| b.config.dataset == dataset) {
+                let _ = write!(
+                    md,
+                    "\n### Clusters, {}\n\n{}\n\n![Per-cluster breakdown](plots/{}.svg)\n",
+                    breakdown.config.title(),
+                    breakdown.describe(),
+                    breakdown.config.file_stem()
+                );
+            }
+        }
+
+        md
+    }
+
+    /// Emits the report as a standalone HTML page, with the plots inlined
+    ///
+    /// # Errors
+    /// Returns `ClusteredIndexError::MetricsError` if a plot can't be drawn
+    pub fn to_html(&self) -> Result<String> {
+        let mut html = String::from(
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>CLANN report</title>\n\
+            <style>body { font-family: sans-serif; margin: 2em; } table { border-collapse: collapse; } \
+            th, td { border: 1px solid #ccc; padding: 4px 8px; text-align: right; }</style>\n\
+            </head>\n<body>\n<h1>CLANN report</h1>\n",
+        );
+
+        for dataset in self.datasets() {
+            let _ = writeln!(html, "<h2>{}</h2>", escape_html(dataset));
+            if let Some(svg) = self.recall_qps_svg(dataset)? {
+                let _ = writeln!(html, "<div>{}</div>", svg);
+            }
+
+            html.push_str("<table>\n<tr><th>num_clusters_factor</th><th>num_tables</th><th>k</th><th>delta</th><th>runs</th><th>recall</th><th>recall std</th><th>QPS</th><th>search time (ms)</th></tr>\n");
+            for summary in self.configs.iter().filter(|c| c.config.dataset == dataset) {
+                let c = &summary.config;
+                let _ = writeln!(
+                    html,
+                    "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{:.1}</td><td>{:.1}</td></tr>",
+                    c.num_clusters_factor,
+                    c.num_tables,
+                    c.k,
+                    c.delta,
+                    summary.runs,
+                    fmt_option(summary.recall_mean, 4),
+                    fmt_option(summary.recall_std, 4),
+                    summary.queries_per_second,
+                    summary.search_time_ms
+                );
+            }
+            html.push_str("</table>\n");
+
+            for breakdown in self.clusters.iter().filter(|b| b.config.dataset == dataset) {
+                let _ = writeln!(
+                    html,
+                    "<h3>Clusters, {}</h3>\n<p>{}</p>\n<div>{}</div>",
+                    breakdown.config.title(),
+                    breakdown.describe(),
+                    breakdown.to_svg()?
+                );
+            }
+        }
+
+        html.push_str("</body>\n</html>\n");
+        Ok(html)
+    }
+
+    /// Writes the report in `dir`, creating it if needed.
+    ///
+    /// # Returns
+    /// Path of the written `report.md` or `report.html`
+    ///
+    /// # Errors
+    /// - `ClusteredIndexError::MetricsError` if a plot can't be drawn
+    /// - `ClusteredIndexError::ResultDBError` if the files can't be written
+    pub fn write(&self, dir: &str, format: ReportFormat) -> Result<PathBuf> {
+        let dir = Path::new(dir);
+        let io_error = |e: std::io::Error| ClusteredIndexError::ResultDBError(e.to_string());
+
+        let (path, contents) = match format {
+            ReportFormat::Markdown => {
+                let plots = dir.join("plots");
+                fs::create_dir_all(&plots).map_err(io_error)?;
+
+                for dataset in self.datasets() {
+                    if let Some(svg) = self.recall_qps_svg(dataset)? {
+                        fs::write(plots.join(format!("{}.svg", recall_qps_stem(dataset))), svg)
+                            .map_err(io_error)?;
+                    }
+                }
+                for breakdown in &self.clusters {
+                    fs::write(
+                        plots.join(format!("{}.svg", breakdown.config.file_stem())),
+                        breakdown.to_svg()?,
+                    )
+                    .map_err(io_error)?;
+                }
+
+                (dir.join("report.md"), self.to_markdown())
+            }
+            ReportFormat::Html => {
+                fs::create_dir_all(dir).map_err(io_error)?;
+                (dir.join("report.html"), self.to_html()?)
+            }
+        };
+
+        fs::write(&path, contents).map_err(io_error)?;
+        Ok(path)
+    }
+
+    fn has_recall(&self, dataset: &str) -> bool {
+        self.configs
+            .iter()
+            .any(|c| c.config.dataset == dataset && c.recall_mean.is_some() && c.queries_per_second > 0.0)
+    }
+}
+
+impl ClusterBreakdown {
+    /// Plot of the search statistics of every cluster, above their build time
+    ///
+    /// # Errors
+    /// Returns `ClusteredIndexError::MetricsError` if the plot can't be drawn
+    pub fn to_svg(&self) -> Result<String> {
+        let num_clusters = self.clusters.iter().map(|c| c.cluster_idx + 1).max().unwrap_or(1);
+        let max_visits = self
+            .clusters
+            .iter()
+            .map(|c| c.visits.max(c.prunes))
+            .fold(1.0, f64::max);
+        let build_times: Vec<(f64, f64)> = self
+            .clusters
+            .iter()
+            .filter_map(|c| c.build_time_ms.map(|t| (c.cluster_idx as f64, t)))
+            .collect();
+        let max_build_time = build_times.iter().map(|&(_, t)| t).fold(1.0, f64::max);
+        let x_range = 0f64..num_clusters as f64;
+
+        let mut svg = String::new();
+        {
+            let root = SVGBackend::with_string(&mut svg, (800, 600)).into_drawing_area();
+            root.fill(&WHITE).map_err(plot_error)?;
+            let (upper, lower) = root.split_vertically(380);
+
+            let mut chart = ChartBuilder::on(&upper)
+                .caption(self.config.title(), ("sans-serif", 18))
+                .margin(10)
+                .x_label_area_size(30)
+                .y_label_area_size(60)
+                .build_cartesian_2d(x_range.clone(), 0f64..max_visits * 1.05)
+                .map_err(plot_error)?;
+            chart
+                .configure_mesh()
+                .y_desc("Queries")
+                .draw()
+                .map_err(plot_error)?;
+
+            let points = |value: fn(&ClusterSummary) -> f64| -> Vec<(f64, f64)> {
+                self.clusters.iter().map(|c| (c.cluster_idx as f64, value(c))).collect()
+            };
+            let series = [
+                ("visits", points(|c| c.visits), BLUE),
+                ("prunes", points(|c| c.prunes), RED),
+                ("top-k contributions", points(|c| c.topk_contributions), GREEN),
+            ];
+            for (label, points, color) in series {
+                chart
+                    .draw_series(LineSeries::new(points, color))
+                    .map_err(plot_error)?
+                    .label(label)
+                    .legend(move |(x, y)| PathElement::new(vec![(x, y), (x + 20, y)], color));
+            }
+            chart
+                .configure_series_labels()
+                .background_style(WHITE.mix(0.8))
+                .border_style(BLACK)
+                .draw()
+                .map_err(plot_error)?;
+
+            let mut chart = ChartBuilder::on(&lower)
+                .margin(10)
+                .x_label_area_size(40)
+                .y_label_area_size(60)
+                .build_cartesian_2d(x_range, 0f64..max_build_time * 1.05)
+                .map_err(plot_error)?;
+            chart
+                .configure_mesh()
+                .x_desc("Cluster")
+                .y_desc("Build time (ms)")
+                .draw()
+                .map_err(plot_error)?;
+            chart
+                .draw_series(
+                    build_times
+                        .iter()
+                        .map(|&p| Circle::new(p, 2, BLACK.filled())),
+                )
+                .map_err(plot_error)?;
+
+            root.present().map_err(plot_error)?;
+        }
+
+        Ok(svg)
+    }
+
+    /// One sentence on how the search load is spread among the clusters
+    fn describe(&self) -> String {
+        let visits: f64 = self.clusters.iter().map(|c| c.visits).sum();
+        let topk: f64 = self.clusters.iter().map(|c| c.topk_contributions).sum();
+        let visited = self.clusters.iter().filter(|c| c.visits > 0.0).count();
+        format!(
+            "{} clusters, {} visited at least once; {:.1} visits and {:.1} top-k contributions per cluster on average.",
+            self.clusters.len(),
+            visited,
+            visits / self.clusters.len().max(1) as f64,
+            topk / self.clusters.len().max(1) as f64
+        )
+    }
+}
+
+fn recall_qps_stem(dataset: &str) -> String {
+    format!("recall_qps_{}", sanitize(dataset))
+}
+
+/// Keeps file names portable
+fn sanitize(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '.' { c } else { '_' })
+        .collect()
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn fmt_option(value: Option<f64>, precision: usize) -> String {
+    value.map_or("-".to_string(), |v| format!("{:.*}", precision, v))
+}
+
+fn plot_error<E: Display>(e: E) -> ClusteredIndexError {
+    ClusteredIndexError::MetricsError(e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::{params, Connection};
+
+    use super::{Report, ReportFormat};
+    use crate::utils::metrics::schema::sqlite_migrate;
+    use crate::utils::test_dir;
+
+    /// Saves a run of `glove` with two clusters, `recall` is `None` as for runs without ground truth
+    fn insert_run(conn: &Connection, run_id: &str, delta: f64, recall: Option<f64>, qps: f64) {
+        conn.execute("INSERT INTO runs (run_id) VALUES (?1)", [run_id]).unwrap();
+        conn.execute(
+            "INSERT INTO search_metrics (run_id, num_clusters, num_tables, k, delta, dataset, search_time_ms, queries_per_second, recall_mean, recall_std)
+            VALUES (?1, 0.5, 10, 10, ?2, 'glove', 100.0, ?3, ?4, 0.1)",
+            params![run_id, delta, qps, recall],
+        )
+        .unwrap();
+        for (cluster_idx, visits) in [(0, 4), (1, 2)] {
+            conn.execute(
+                "INSERT INTO search_metrics_cluster_summary (run_id, num_clusters, num_tables, k, delta, dataset, git_commit_hash, cluster_idx, visits, prunes, topk_contributions)
+                VALUES (?1, 0.5, 10, 10, ?2, 'glove', 'NO_COMMIT', ?3, ?4, ?5, 1)",
+                params![run_id, delta, cluster_idx, visits, 4 - visits],
+            )
+            .unwrap();
+            conn.execute(
+                "INSERT INTO build_metrics_cluster (run_id, num_clusters, num_tables, dataset, cluster_idx, num_points, build_time_ms)
+                VALUES (?1, 0.5, 10, 'glove', ?2, 50, 2.5)",
+                params![run_id, cluster_idx],
+            )
+            .unwrap();
+        }
+    }
+
+    #[test]
+    fn test_report_aggregates_runs() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        sqlite_migrate(&mut conn).unwrap();
+        insert_run(&conn, "a", 0.9, Some(0.8), 1000.0);
+        insert_run(&conn, "b", 0.9, Some(0.9), 2000.0);
+        insert_run(&conn, "c", 0.5, None, 4000.0);
+
+        let report = Report::from_connection(&conn).unwrap();
+        assert_eq!(report.datasets(), vec!["glove"]);
+        assert_eq!(report.configs.len(), 2);
+
+        let (low, high) = (&report.configs[0], &report.configs[1]);
+        assert_eq!(low.config.delta, 0.5);
+        assert_eq!(low.runs, 1);
+        assert_eq!(low.recall_mean, None);
+        assert_eq!(high.runs, 2);
+        assert!((high.recall_mean.unwrap() - 0.85).abs() < 1e-9);
+        assert!((high.queries_per_second - 1500.0).abs() < 1e-9);
+
+        assert_eq!(report.clusters.len(), 2);
+        let breakdown = &report.clusters[1];
+        assert_eq!(breakdown.config, high.config);
+        assert_eq!(breakdown.clusters.len(), 2);
+        assert_eq!(breakdown.clusters[0].visits, 4.0);
+        assert_eq!(breakdown.clusters[1].prunes, 2.0);
+        assert_eq!(breakdown.clusters[0].num_points, Some(50.0));
+        assert_eq!(breakdown.clusters[0].build_time_ms, Some(2.5));
+
+        let md = report.to_markdown();
+        assert!(md.contains("## glove"));
+        assert!(md.contains("| 0.5 | 10 | 10 | 0.9 | 2 | 0.8500 |"));
+        assert!(md.contains("| 0.5 | 10 | 10 | 0.5 | 1 | - |"));
+        assert!(md.contains("plots/recall_qps_glove.svg"));
+
+        assert!(report.recall_qps_svg("glove").unwrap().unwrap().contains("<svg"));
+        assert!(report.recall_qps_svg("sift").unwrap().is_none());
+        assert_eq!(report.to_html().unwrap().matches("<svg").count(), 3);
+    }
+
+    #[test]
+    fn test_write_report() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        sqlite_migrate(&mut conn).unwrap();
+        insert_run(&conn, "a", 0.9, Some(0.8), 1000.0);
+        let report = Report::from_connection(&conn).unwrap();
+
+        let dir = test_dir("report");
+
+        let path = report.write(dir.to_str().unwrap(), ReportFormat::Markdown).unwrap();
+        assert!(path.ends_with("report.md"));
+        assert!(dir.join("plots/recall_qps_glove.svg").exists());
+        assert!(dir.join("plots/clusters_glove_f0.5_L10_k10_d0.9.svg").exists());
+
+        let path = report.write(dir.to_str().unwrap(), ReportFormat::Html).unwrap();
+        assert!(std::fs::read_to_string(path).unwrap().starts_with("<!DOCTYPE html>"));
+
+        assert!(Report::from_db(dir.join("missing.sqlite3").to_str().unwrap()).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,398 @@
+//! gRPC server over a [`ClusteredIndex`], enabled with the `serve` feature.
+//!
+//! The service is defined in `proto/clann.proto`. Requests are handled concurrently by
+//! the tonic runtime, on the blocking thread pool so that they don't stall the async workers.
+//! The index is shared through an [`IndexHandle`]: searches run concurrently under its read
+//! lock, each with the k of its request, and are not recorded in the statistics of the
+//! clusters, while rebuilds take the write lock.
+//!
+//! ```no_run
+//! use clann::core::Config;
+//! use clann::metricdata::AngularData;
+//! use clann::utils::load_hdf5_dataset;
+//!
+//! #[tokio::main]
+//! async fn main() {
+//!     let dataset = load_hdf5_dataset("./datasets/glove-25-angular.hdf5").unwrap();
+//!     let data = AngularData::new(dataset.dataset_array);
+//!     let mut index = clann::init_with_config(data, Config::default()).unwrap();
+//!     clann::build(&mut index).unwrap();
+//!
+//!     clann::serve::serve(index, "127.0.0.1:50051".parse().unwrap()).await.unwrap();
+//! }
+//! ```
+
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+use log::info;
+use ndarray::ArrayView2;
+use tonic::{Request, Response, Status};
+
+use crate::core::index::ClusteredIndex;
+use crate::core::{ClusterBackend, ClusteredIndexError, Config, IndexHandle, SearchParams};
+use crate::metricdata::{MetricData, Subset};
+use crate::puffinn_binds::{IndexableSimilarity, PuffinnIndex};
+
+/// Types generated from `proto/clann.proto`
+pub mod proto {
+    tonic::include_proto!("clann");
+}
+
+use proto::search_service_server::{SearchService, SearchServiceServer};
+use proto::{
+    BatchSearchRequest, BatchSearchResponse, BuildIndexRequest, BuildIndexResponse, Neighbor,
+    SearchRequest, SearchResponse, StatsRequest, StatsResponse,
+};
+
+fn to_status(e: ClusteredIndexError) -> Status {
+    match e {
+        ClusteredIndexError::ConfigError(_)
+        | ClusteredIndexError::DataError(_)
+        | ClusteredIndexError::DimensionMismatch { .. } => Status::invalid_argument(e.to_string()),
+        ClusteredIndexError::Cancelled => Status::cancelled(e.to_string()),
+        _ => Status::internal(e.to_string()),
+    }
+}
+
+/// Search options of a request, a `k` of zero keeps the k of the index configuration.
+///
+/// A `k` above the `num_points` of the index is rejected, since the top-k is allocated upfront.
+fn search_params(k: u32, num_points: usize) -> Result<SearchParams<'static>, Status> {
+    let k = k as usize;
+    if k > num_points {
+        return Err(Status::invalid_argument(format!(
+            "k = {} is larger than the {} points of the index",
+            k, num_points
+        )));
+    }
+    Ok(SearchParams {
+        k: (k > 0).then_some(k),
+        ..Default::default()
+    })
+}
+
+fn to_response(result: Vec<(f32, usize)>) -> SearchResponse {
+    SearchResponse {
+        neighbors: result
+            .into_iter()
+            .map(|(distance, id)| Neighbor {
+                id: id as u64,
+                distance,
+            })
+            .collect(),
+    }
+}
+
+/// Implementation of the `SearchService` RPCs over a shared index.
+pub struct SearchServer<T, B = PuffinnIndex>
+where
+    T: MetricData + IndexableSimilarity<T> + Subset,
+    <T as Subset>::Out: IndexableSimilarity<<T as Subset>::Out>,
+    B: ClusterBackend<T>,
+{
+    index: IndexHandle<T, B>,
+    queries_served: Arc<AtomicU64>,
+}
+
+impl<T, B> SearchServer<T, B>
+where
+    T: MetricData<DataType = f32> + IndexableSimilarity<T> + Subset + Send + Sync + 'static,
+    <T as Subset>::Out: IndexableSimilarity<<T as Subset>::Out>,
+    B: ClusterBackend<T> + Send + Sync + 'static,
+{
+    pub fn new(index: ClusteredIndex<T, B>) -> Self {
+        Self::from_handle(IndexHandle::new(index))
+    }
+
+    /// Serves an index shared with the rest of the process, which may keep updating it
+    /// through its other handles.
+    pub fn from_handle(index: IndexHandle<T, B>) -> Self {
+        Self {
+            index,
+            queries_served: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Wraps the server into a tonic service, to be added to a `tonic::transport::Server`.
+    pub fn into_service(self) -> SearchServiceServer<Self> {
+        SearchServiceServer::new(self)
+    }
+
+    /// Runs `f` with the handle of the index on the blocking thread pool.
+    async fn with_index<R, F>(&self, f: F) -> Result<R, Status>
+    where
+        F: FnOnce(&IndexHandle<T, B>) -> Result<R, ClusteredIndexError> + Send + 'static,
+        R: Send + 'static,
+    {
+        let index = self.index.clone();
+        tokio::task::spawn_blocking(move || f(&index).map_err(to_status))
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?
+    }
+}
+
+#[tonic::async_trait]
+impl<T, B> SearchService for SearchServer<T, B>
+where
+    T: MetricData<DataType = f32> + IndexableSimilarity<T> + Subset + Send + Sync + 'static,
+    <T as Subset>::Out: IndexableSimilarity<<T as Subset>::Out>,
+    B: ClusterBackend<T> + Send + Sync + 'static,
+{
+    async fn build_index(
+        &self,
+        request: Request<BuildIndexRequest>,
+    ) -> Result<Response<BuildIndexResponse>, Status> {
+        let config_json = request.into_inner().config_json;
+        let config: Option<Config> = if config_json.is_empty() {
+            None
+        } else {
+            Some(
+                serde_json::from_str(&config_json)
+                    .map_err(|e| Status::invalid_argument(e.to_string()))?,
+            )
+        };
+
+        let response = self
+            .with_index(move |index| {
+                index.update(|index| {
+                    let start = Instant::now();
+                    let config = config.unwrap_or_else(|| index.config().clone());
+                    index.reconfigure(config)?;
+                    index.build()?;
+
+                    Ok(BuildIndexResponse {
+                        num_clusters: index.num_clusters() as u64,
+                        build_time_s: start.elapsed().as_secs_f64(),
+                    })
+                })
+            })
+            .await?;
+
+        info!(
+            "Index rebuilt with {} clusters in {:.2}s",
+            response.num_clusters, response.build_time_s
+        );
+        Ok(Response::new(response))
+    }
+
+    async fn search(
+        &self,
+        request: Request<SearchRequest>,
+    ) -> Result<Response<SearchResponse>, Status> {
+        let SearchRequest { query, k } = request.into_inner();
+        let params = search_params(k, self.index.num_points().map_err(to_status)?)?;
+
+        let result = self
+            .with_index(move |index| index.search_with(&query, &params))
+            .await?;
+
+        self.queries_served.fetch_add(1, Ordering::Relaxed);
+        Ok(Response::new(to_response(result)))
+    }
+
+    async fn batch_search(
+        &self,
+        request: Request<BatchSearchRequest>,
+    ) -> Result<Response<BatchSearchResponse>, Status> {
+        let BatchSearchRequest {
+            queries,
+            dimensions,
+            k,
+        } = request.into_inner();
+
+        let dimensions = dimensions as usize;
+        if dimensions == 0 || queries.len() % dimensions != 0 {
+            return Err(Status::invalid_argument(format!(
+                "{} values can't be split in queries of {} dimensions",
+                queries.len(),
+                dimensions
+            )));
+        }
+        let num_queries = queries.len() / dimensions;
+        let params = search_params(k, self.index.num_points().map_err(to_status)?)?;
+
+        let results = self
+            .with_index(move |index| {
+                let queries = ArrayView2::from_shape((num_queries, dimensions), &queries)
+                    .map_err(|e| ClusteredIndexError::DataError(e.to_string()))?;
+                index.search_batch_with(&queries, &params)
+            })
+            .await?;
+
+        self.queries_served
+            .fetch_add(num_queries as u64, Ordering::Relaxed);
+        Ok(Response::new(BatchSearchResponse {
+            results: results.into_iter().map(to_response).collect(),
+        }))
+    }
+
+    async fn stats(
+        &self,
+        _request: Request<StatsRequest>,
+    ) -> Result<Response<StatsResponse>, Status> {
+        let queries_served = self.queries_served.load(Ordering::Relaxed);
+
+        let response = self
+            .with_index(move |index| {
+                Ok(StatsResponse {
+                    num_points: index.num_points()? as u64,
+                    dimensions: index.dimensions()? as u64,
+                    num_clusters: index.num_clusters()? as u64,
+                    queries_served,
+                    config_json: serde_json::to_string(&index.config()?)
+                        .map_err(|e| ClusteredIndexError::SerializeError(e.to_string()))?,
+                })
+            })
+            .await?;
+
+        Ok(Response::new(response))
+    }
+}
+
+/// Serves `index` on `addr` until the process is stopped.
+///
+/// # Errors
+/// Returns the transport error if the server cannot bind `addr` or fails while running
+pub async fn serve<T, B>(
+    index: ClusteredIndex<T, B>,
+    addr: SocketAddr,
+) -> Result<(), tonic::transport::Error>
+where
+    T: MetricData<DataType = f32> + IndexableSimilarity<T> + Subset + Send + Sync + 'static,
+    <T as Subset>::Out: IndexableSimilarity<<T as Subset>::Out>,
+    B: ClusterBackend<T> + Send + Sync + 'static,
+{
+    info!("Serving index on {}", addr);
+    tonic::transport::Server::builder()
+        .add_service(SearchServer::new(index).into_service())
+        .serve(addr)
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::proto::search_service_server::SearchService;
+    use super::proto::{BatchSearchRequest, BuildIndexRequest, SearchRequest, StatsRequest};
+    use super::SearchServer;
+    use crate::core::index::ClusteredIndex;
+    use crate::core::{Config, IndexMode};
+    use crate::metricdata::AngularData;
+    use crate::utils::datagen::unit_vectors;
+    use tonic::{Code, Request};
+
+    fn server() -> SearchServer<AngularData<ndarray::OwnedRepr<f32>>> {
+        let config = Config {
+            index_mode: IndexMode::Flat,
+            ..Default::default()
+        };
+        let data = AngularData::new(unit_vectors(500, 16, 1));
+        let mut index = ClusteredIndex::new(config, data).unwrap();
+        index.build().unwrap();
+        SearchServer::new(index)
+    }
+
+    #[tokio::test]
+    async fn test_search_rpcs() {
+        let server = server();
+        let data = unit_vectors(500, 16, 2);
+
+        let query = data.row(0).to_vec();
+        let response = server
+            .search(Request::new(SearchRequest {
+                query: query.clone(),
+                k: 3,
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.neighbors.len(), 3);
+
+        let mut queries = query.clone();
+        queries.extend(data.row(1).iter());
+        let response = server
+            .batch_search(Request::new(BatchSearchRequest {
+                queries,
+                dimensions: 16,
+                k: 3,
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.results.len(), 2);
+
+        let stats = server
+            .stats(Request::new(StatsRequest {}))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(stats.num_points, 500);
+        assert_eq!(stats.dimensions, 16);
+        assert_eq!(stats.queries_served, 3);
+    }
+
+    #[tokio::test]
+    async fn test_k_per_request() {
+        let server = server();
+        let query = unit_vectors(1, 16, 3).row(0).to_vec();
+        let search = |k| {
+            server.search(Request::new(SearchRequest {
+                query: query.clone(),
+                k,
+            }))
+        };
+
+        assert_eq!(search(3).await.unwrap().into_inner().neighbors.len(), 3);
+        // a request without k gets the configured one, not the k of the previous request
+        let default_k = server.index.config().unwrap().k;
+        assert_eq!(search(0).await.unwrap().into_inner().neighbors.len(), default_k);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_requests() {
+        let server = server();
+
+        let status = server
+            .batch_search(Request::new(BatchSearchRequest {
+                queries: vec![0.0; 10],
+                dimensions: 16,
+                k: 3,
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+
+        // more neighbors than points
+        let status = server
+            .search(Request::new(SearchRequest {
+                query: vec![0.0; 16],
+                k: u32::MAX,
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+
+        let status = server
+            .build_index(Request::new(BuildIndexRequest {
+                config_json: "{".to_string(),
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn test_rebuild() {
+        let server = server();
+        let response = server
+            .build_index(Request::new(BuildIndexRequest {
+                config_json: r#"{"num_tables": 4, "num_clusters_factor": 0.5, "k": 5, "delta": 0.9, "dataset_name": "test", "metrics_output": "None", "index_mode": "Flat"}"#.to_string(),
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.num_clusters, 11);
+    }
+}
@@ -0,0 +1,294 @@
+//! Bounded collection of the k elements closest to a point, the one the search collects its
+//! neighbors in.
+//!
+//! [`TopK`] is exposed to post-process the results of CLANN: keeping the elements tied with
+//! the k-th one, cutting off the elements farther than a distance, and merging the top-k of
+//! several searches, e.g. of indexes over shards of a dataset.
+
+use std::collections::BinaryHeap;
+
+use ordered_float::OrderedFloat;
+
+/// What a full [`TopK`] does with an element at the distance of its k-th one.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Ties {
+    /// Keeps the elements pushed first, the new one is rejected
+    #[default]
+    KeepFirst,
+
+    /// Keeps the elements with the smallest ids, so that the result doesn't depend on the
+    /// order of the pushes
+    SmallestId,
+
+    /// Keeps every element at the distance of the k-th one, so there may be more than k
+    KeepAll,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct Entry<I> {
+    distance: OrderedFloat<f32>,
+    id: I,
+}
+
+/// The k elements closest to a point among the ones pushed, with their distance.
+///
+/// # Example
+/// ```
+/// use clann::topk::{Ties, TopK};
+///
+/// let mut top = TopK::new(2).with_ties(Ties::KeepAll).with_max_distance(1.0);
+/// for (distance, id) in [(0.5, 3), (0.2, 1), (0.5, 7), (1.5, 2)] {
+///     top.push(distance, id);
+/// }
+/// assert_eq!(top.into_sorted_vec(), vec![(0.2, 1), (0.5, 3), (0.5, 7)]);
+/// ```
+#[derive(Debug, Clone)]
+pub struct TopK<I = usize> {
+    heap: BinaryHeap<Entry<I>>, // the k closest, the farthest on top
+    k: usize,
+    ties: Ties,
+    max_distance: f32,
+    tied: Vec<Entry<I>>, // beyond the k closest, at the distance of the k-th, with Ties::KeepAll
+}
+
+impl<I: Ord + Copy> TopK<I> {
+    /// Empty top-k keeping the `k` closest elements, ties broken by [`Ties::KeepFirst`]
+    pub fn new(k: usize) -> Self {
+        Self {
+            heap: BinaryHeap::with_capacity(k),
+            k,
+            ties: Ties::default(),
+            max_distance: f32::INFINITY,
+            tied: Vec::new(),
+        }
+    }
+
+    /// Breaks the ties at the k-th distance with `ties`
+    pub fn with_ties(mut self, ties: Ties) -> Self {
+        self.ties = ties;
+        self
+    }
+
+    /// Rejects the elements farther than `max_distance`, even if there are fewer than k
+    pub fn with_max_distance(mut self, max_distance: f32) -> Self {
+        self.max_distance = max_distance;
+        self
+    }
+
+    /// Adds the element `id` at `distance`, if it is among the k closest.
+    ///
+    /// # Returns
+    /// Whether the element was kept
+    pub fn push(&mut self, distance: f32, id: I) -> bool {
+        if distance > self.max_distance {
+            return false;
+        }
+        let entry = Entry {
+            distance: OrderedFloat(distance),
+            id,
+        };
+        if self.heap.len() < self.k {
+            self.heap.push(entry);
+            return true;
+        }
+        let Some(&farthest) = self.heap.peek() else {
+            return false;
+        };
+
+        let closer = match self.ties {
+            Ties::SmallestId => entry < farthest,
+            Ties::KeepFirst | Ties::KeepAll => entry.distance < farthest.distance,
+        };
+        if closer {
+            self.heap.pop();
+            self.heap.push(entry);
+            if self.ties == Ties::KeepAll {
+                let kth = self.heap.peek().map(|e| e.distance);
+                self.tied.retain(|e| Some(e.distance) == kth);
+                if Some(farthest.distance) == kth {
+                    self.tied.push(farthest);
+                }
+            }
+            true
+        } else if self.ties == Ties::KeepAll && entry.distance == farthest.distance {
+            self.tied.push(entry);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Adds every element of `other`, as if they were pushed one by one
+    pub fn merge(&mut self, other: TopK<I>) {
+        for entry in other.heap.into_iter().chain(other.tied) {
+            self.push(entry.distance.0, entry.id);
+        }
+    }
+
+    /// Number of elements kept
+    pub fn k(&self) -> usize {
+        self.k
+    }
+
+    /// Number of elements, more than k only with [`Ties::KeepAll`]
+    pub fn len(&self) -> usize {
+        self.heap.len() + self.tied.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.heap.is_empty()
+    }
+
+    /// Farthest of the k closest elements, as (distance, id), even if there are fewer than k
+    pub fn farthest(&self) -> Option<(f32, I)> {
+        self.heap.peek().map(|e| (e.distance.0, e.id))
+    }
+
+    /// Distance of the k-th element, `None` until there are k
+    pub fn kth_distance(&self) -> Option<f32> {
+        if self.heap.len() < self.k {
+            return None;
+        }
+        self.heap.peek().map(|e| e.distance.0)
+    }
+
+    /// (distance, id) pairs of the elements, closest first, ties by id
+    pub fn to_sorted_vec(&self) -> Vec<(f32, I)> {
+        self.clone().into_sorted_vec()
+    }
+
+    /// (distance, id) pairs of the elements, closest first, ties by id
+    pub fn into_sorted_vec(self) -> Vec<(f32, I)> {
+        let mut entries = self.heap.into_vec();
+        entries.extend(self.tied);
+        entries.sort_unstable();
+        entries.into_iter().map(|e| (e.distance.0, e.id)).collect()
+    }
+}
+
+impl<I: Ord + Copy> Extend<(f32, I)> for TopK<I> {
+    fn extend<T: IntoIterator<Item = (f32, I)>>(&mut self, iter: T) {
+        for (distance, id) in iter {
+            self.push(distance, id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Ties, TopK};
+
+    #[test]
+    fn test_add_elements_less_than_capacity() {
+        let mut heap = TopK::new(3);
+
+        assert!(heap.push(2.5, 0));
+        assert!(heap.push(1.5, 1));
+
+        let elements = heap.to_sorted_vec();
+        assert_eq!(elements.len(), 2);
+        assert_eq!(elements, vec![(1.5, 1), (2.5, 0)]);
+    }
+
+    #[test]
+    fn test_add_elements_exceeding_capacity() {
+        let mut heap = TopK::new(3);
+
+        heap.push(3.0, 0);
+        heap.push(2.0, 1);
+        heap.push(1.0, 2);
+
+        // Adding an element with a smaller distance should replace the largest
+        heap.push(0.5, 3);
+
+        let elements = heap.to_sorted_vec();
+        assert_eq!(elements.len(), 3);
+        assert!(elements.contains(&(1.0, 2)));
+        assert!(elements.contains(&(2.0, 1)));
+        assert!(elements.contains(&(0.5, 3)));
+    }
+
+    #[test]
+    fn test_no_replace_if_distance_is_larger() {
+        let mut heap = TopK::new(3);
+
+        heap.push(3.0, 0);
+        heap.push(2.0, 1);
+        heap.push(1.0, 2);
+
+        // Adding an element with a larger distance does not replace the largest
+        assert!(!heap.push(4.0, 3));
+
+        let elements = heap.to_sorted_vec();
+        assert_eq!(elements.len(), 3);
+        assert!(!elements.contains(&(4.0, 3)));
+    }
+
+    #[test]
+    fn test_get_top_element() {
+        let mut heap = TopK::new(2);
+
+        heap.push(2.0, 1);
+        heap.push(1.0, 2);
+
+        assert_eq!(heap.farthest(), Some((2.0, 1)));
+
+        heap.push(0.5, 3);
+
+        assert_eq!(heap.farthest(), Some((1.0, 2)));
+    }
+
+    #[test]
+    fn test_empty_heap() {
+        let heap = TopK::<usize>::new(3);
+        assert_eq!(heap.to_sorted_vec().len(), 0);
+        assert_eq!(heap.farthest(), None);
+        assert_eq!(heap.kth_distance(), None);
+    }
+
+    #[test]
+    fn test_ties() {
+        let pushes = [(1.0, 5), (2.0, 4), (2.0, 9), (2.0, 1), (3.0, 0)];
+
+        let mut first = TopK::new(2);
+        first.extend(pushes);
+        assert_eq!(first.into_sorted_vec(), vec![(1.0, 5), (2.0, 4)]);
+
+        let mut smallest = TopK::new(2).with_ties(Ties::SmallestId);
+        smallest.extend(pushes);
+        assert_eq!(smallest.into_sorted_vec(), vec![(1.0, 5), (2.0, 1)]);
+
+        let mut all = TopK::new(2).with_ties(Ties::KeepAll);
+        all.extend(pushes);
+        assert_eq!(all.len(), 4);
+        assert_eq!(all.kth_distance(), Some(2.0));
+        assert_eq!(all.to_sorted_vec(), vec![(1.0, 5), (2.0, 1), (2.0, 4), (2.0, 9)]);
+
+        // a closer element drops the ties that are no longer at the k-th distance
+        all.push(0.5, 7);
+        assert_eq!(all.into_sorted_vec(), vec![(0.5, 7), (1.0, 5)]);
+
+        // the evicted element is still tied with the new k-th one
+        let mut all = TopK::new(2).with_ties(Ties::KeepAll);
+        all.extend([(2.0, 0), (2.0, 1), (2.0, 2), (1.0, 3)]);
+        assert_eq!(all.into_sorted_vec(), vec![(1.0, 3), (2.0, 0), (2.0, 1), (2.0, 2)]);
+    }
+
+    #[test]
+    fn test_max_distance_and_merge() {
+        let mut near = TopK::new(3).with_max_distance(1.0);
+        assert!(!near.push(1.5, 0));
+        assert!(near.push(1.0, 1));
+        assert!(near.push(0.2, 2));
+        assert_eq!(near.kth_distance(), None);
+
+        let mut other = TopK::new(3);
+        other.extend([(0.1, 3), (0.9, 4), (2.0, 5)]);
+        near.merge(other);
+        assert_eq!(near.into_sorted_vec(), vec![(0.1, 3), (0.2, 2), (0.9, 4)]);
+
+        let mut none = TopK::new(0);
+        assert!(!none.push(0.0, 0));
+        assert!(none.is_empty());
+    }
+}
@@ -0,0 +1,5 @@
+//! Transformations applied to the points before indexing, and to the queries before searching.
+
+pub(crate) mod randomprojection;
+
+pub use randomprojection::{ProjectionReport, RandomProjection};
@@ -0,0 +1,261 @@
+use std::fmt;
+use std::sync::Arc;
+
+use ndarray::{Array2, ArrayBase, ArrayView2, Axis, Data, Ix2};
+use rand::rngs::StdRng;
+use rand::seq::index::sample;
+use rand::SeedableRng;
+use serde::{Deserialize, Serialize};
+
+use crate::core::{ClusteredIndexError, Result};
+use crate::metricdata::Scalar;
+use crate::utils::gaussian;
+
+/// Number of point pairs used to measure the distance distortion in [`RandomProjection::report`]
+const REPORT_PAIRS: usize = 1000;
+
+/// Gaussian random projection to a lower dimensional space.
+///
+/// Points are multiplied by an `output_dim x input_dim` matrix with entries drawn from
+/// N(0, 1/output_dim), which preserves squared norms and distances in expectation
+/// (Johnson-Lindenstrauss). Reducing the dimensionality of very high dimensional
+/// embeddings makes the dataset, and the PUFFINN tables built on it, smaller.
+///
+/// Set it as [`Config::projection`](crate::core::Config) to have queries projected before
+/// searching. Only the dimensions and the seed are serialized with the index configuration,
+/// the matrix is drawn again from the seed when it is loaded, and shared by the clones.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(from = "ProjectionParams", into = "ProjectionParams")]
+pub struct RandomProjection {
+    input_dim: usize,
+    output_dim: usize,
+    seed: u64,
+    /// Row-major `output_dim x input_dim` matrix
+    matrix: Arc<[f32]>,
+}
+
+/// Serialized form of a [`RandomProjection`], which determines its matrix
+#[derive(Clone, Copy, Serialize, Deserialize)]
+struct ProjectionParams {
+    input_dim: usize,
+    output_dim: usize,
+    seed: u64,
+}
+
+impl From<ProjectionParams> for RandomProjection {
+    fn from(params: ProjectionParams) -> Self {
+        Self::new(params.input_dim, params.output_dim, params.seed)
+    }
+}
+
+impl From<RandomProjection> for ProjectionParams {
+    fn from(projection: RandomProjection) -> Self {
+        Self {
+            input_dim: projection.input_dim,
+            output_dim: projection.output_dim,
+            seed: projection.seed,
+        }
+    }
+}
+
+/// How well a projection preserves the geometry of a dataset.
+#[derive(Debug, Clone, Serialize)]
+pub struct ProjectionReport {
+    /// Total variance of the projected data over total variance of the original data
+    pub variance_ratio: f32,
+
+    /// Mean of |projected distance / original distance - 1| over sampled pairs
+    pub mean_distortion: f32,
+
+    /// Max of |projected distance / original distance - 1| over sampled pairs
+    pub max_distortion: f32,
+
+    /// Mean absolute difference of the cosine similarity over sampled pairs
+    pub mean_cosine_error: f32,
+}
+
+impl fmt::Debug for RandomProjection {
+    // the matrix would flood the logs, e.g. when printing the index configuration
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RandomProjection")
+            .field("input_dim", &self.input_dim)
+            .field("output_dim", &self.output_dim)
+            .field("seed", &self.seed)
+            .finish()
+    }
+}
+
+impl RandomProjection {
+    pub fn new(input_dim: usize, output_dim: usize, seed: u64) -> Self {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let scale = 1.0 / (output_dim as f32).sqrt();
+        let matrix = (0..output_dim * input_dim)
+            .map(|_| gaussian(&mut rng) * scale)
+            .collect();
+
+        Self {
+            input_dim,
+            output_dim,
+            seed,
+            matrix,
+        }
+    }
+
+    pub fn input_dim(&self) -> usize {
+        self.input_dim
+    }
+
+    pub fn output_dim(&self) -> usize {
+        self.output_dim
+    }
+
+    fn matrix(&self) -> ArrayView2<'_, f32> {
+        ArrayView2::from_shape((self.output_dim, self.input_dim), &self.matrix)
+            .expect("matrix has output_dim * input_dim entries")
+    }
+
+    /// Projects every row of `data`.
+    ///
+    /// # Errors
+    /// Returns `ClusteredIndexError::DataError` if rows don't have `input_dim` columns
+    pub fn transform<S: Data<Elem = f32>>(&self, data: &ArrayBase<S, Ix2>) -> Result<Array2<f32>> {
+        if data.ncols() != self.input_dim {
+            return Err(ClusteredIndexError::DataError(format!(
+                "data has {} dimensions, projection expects {}",
+                data.ncols(),
+                self.input_dim
+            )));
+        }
+        Ok(data.dot(&self.matrix().t()))
+    }
+
+    /// Projects a single point, converting it from and to the dataset element type.
+    ///
+    /// # Errors
+    /// Returns `ClusteredIndexError::DataError` if the point doesn't have `input_dim` coordinates
+    pub fn transform_point<D: Scalar>(&self, point: &[D]) -> Result<Vec<D>> {
+        if point.len() != self.input_dim {
+            return Err(ClusteredIndexError::DataError(format!(
+                "point has {} dimensions, projection expects {}",
+                point.len(),
+                self.input_dim
+            )));
+        }
+        Ok(self
+            .matrix
+            .chunks_exact(self.input_dim)
+            .map(|row| {
+                let value: f64 = row
+                    .iter()
+                    .zip(point)
+                    .map(|(&m, &x)| m as f64 * x.into())
+                    .sum();
+                D::from_f64(value)
+            })
+            .collect())
+    }
+
+    /// Measures how well the projection preserves the variance and the distances of `data`.
+    ///
+    /// Distances are compared on [`REPORT_PAIRS`] random pairs of points.
+    ///
+    /// # Errors
+    /// Returns `ClusteredIndexError::DataError` if rows don't have `input_dim` columns
+    pub fn report<S: Data<Elem = f32>>(&self, data: &ArrayBase<S, Ix2>) -> Result<ProjectionReport> {
+        let projected = self.transform(data)?;
+
+        let total_variance = |a: ArrayView2<f32>| -> f32 {
+            a.var_axis(Axis(0), 0.0).sum()
+        };
+        let original_variance = total_variance(data.view());
+        let variance_ratio = if original_variance > 0.0 {
+            total_variance(projected.view()) / original_variance
+        } else {
+            1.0
+        };
+
+        let mut rng = StdRng::seed_from_u64(self.seed);
+        let n = data.nrows();
+        let mut distortions = Vec::with_capacity(REPORT_PAIRS);
+        let mut cosine_errors = Vec::with_capacity(REPORT_PAIRS);
+        if n >= 2 {
+            for _ in 0..REPORT_PAIRS {
+                let pair = sample(&mut rng, n, 2);
+                let (i, j) = (pair.index(0), pair.index(1));
+                let (a, b) = (data.row(i), data.row(j));
+                let (pa, pb) = (projected.row(i), projected.row(j));
+
+                let distance = (&a - &b).mapv(|x| x * x).sum().sqrt();
+                if distance > 0.0 {
+                    let projected_distance = (&pa - &pb).mapv(|x| x * x).sum().sqrt();
+                    distortions.push((projected_distance / distance - 1.0).abs());
+                }
+
+                let cosine = |x: ndarray::ArrayView1<f32>, y: ndarray::ArrayView1<f32>| {
+                    x.dot(&y) / (x.dot(&x).sqrt() * y.dot(&y).sqrt())
+                };
+                let error = (cosine(a, b) - cosine(pa, pb)).abs();
+                if error.is_finite() {
+                    cosine_errors.push(error);
+                }
+            }
+        }
+
+        let mean = |v: &[f32]| if v.is_empty() { 0.0 } else { v.iter().sum::<f32>() / v.len() as f32 };
+
+        Ok(ProjectionReport {
+            variance_ratio,
+            mean_distortion: mean(&distortions),
+            max_distortion: distortions.iter().cloned().fold(0.0, f32::max),
+            mean_cosine_error: mean(&cosine_errors),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RandomProjection;
+    use crate::utils::datagen::unit_vectors;
+
+    #[test]
+    fn test_projection_preserves_geometry() {
+        let data = unit_vectors(500, 256, 1);
+        let projection = RandomProjection::new(256, 64, 7);
+
+        let projected = projection.transform(&data).unwrap();
+        assert_eq!(projected.dim(), (500, 64));
+
+        let point = projection.transform_point(data.row(3).as_slice().unwrap()).unwrap();
+        for (a, b) in point.iter().zip(projected.row(3)) {
+            assert!((a - b).abs() < 1e-4);
+        }
+
+        let report = projection.report(&data).unwrap();
+        assert!((report.variance_ratio - 1.0).abs() < 0.3);
+        assert!(report.mean_distortion < 0.2);
+    }
+
+    #[test]
+    fn test_serialized_without_matrix() {
+        let projection = RandomProjection::new(256, 64, 7);
+        let json = serde_json::to_string(&projection).unwrap();
+        assert_eq!(json, r#"{"input_dim":256,"output_dim":64,"seed":7}"#);
+
+        let loaded: RandomProjection = serde_json::from_str(&json).unwrap();
+        let point = unit_vectors(1, 256, 2);
+        let point = point.row(0).to_vec();
+        assert_eq!(
+            loaded.transform_point(&point).unwrap(),
+            projection.transform_point(&point).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_dimension_mismatch() {
+        let projection = RandomProjection::new(8, 4, 0);
+        assert!(projection.transform_point(&[1.0f32; 5]).is_err());
+        assert!(projection
+            .transform(&unit_vectors(3, 5, 3))
+            .is_err());
+    }
+}
@@ -0,0 +1,400 @@
+use std::cmp::Ordering;
+
+use log::info;
+use ndarray::{s, Array2, ArrayBase, Data, Ix2};
+use rusqlite::Connection;
+use serde::Serialize;
+
+use crate::core::{ClusteredIndex, ClusteredIndexError, Config, Result};
+use crate::eval::{evaluate, EvalParams, EvalReport, GroundTruth};
+use crate::metricdata::{MetricData, Subset};
+use crate::puffinn_binds::IndexableSimilarity;
+use crate::utils::metrics::save_tuning_trace;
+
+use super::grid::{check_sweep, load_or_build};
+
+/// Parameters of an auto-tuning.
+#[derive(Debug, Clone)]
+pub struct AutoTuneParams {
+    /// Mean recall the chosen configuration must reach
+    pub target_recall: f32,
+
+    pub num_tables: Vec<usize>,
+    pub num_clusters_factors: Vec<f32>,
+
+    /// Tried from the smallest, the first reaching the target is kept for each index
+    pub deltas: Vec<f32>,
+
+    /// Configuration of the indexes, its tuned parameters are overridden
+    pub base: Config,
+
+    /// Queries of the first round, doubled every round up to all the queries
+    pub min_queries: usize,
+
+    /// Directory where the built indexes are serialized, so that the indexes surviving a
+    /// round are loaded instead of built again. `None` to build them every round
+    pub cache_dir: Option<String>,
+
+    /// Metrics database where the trace is saved as a run, `None` to not save it
+    pub db_path: Option<String>,
+
+    /// Number of neighbors and warm-up queries of the evaluations
+    pub eval: EvalParams,
+}
+
+impl Default for AutoTuneParams {
+    fn default() -> Self {
+        Self {
+            target_recall: 0.9,
+            num_tables: vec![10, 20, 50, 100],
+            num_clusters_factors: vec![0.2, 0.5, 1.0, 2.0],
+            deltas: vec![0.5, 0.6, 0.7, 0.8, 0.9, 0.95],
+            base: Config::default(),
+            min_queries: 100,
+            cache_dir: None,
+            db_path: None,
+            eval: EvalParams::default(),
+        }
+    }
+}
+
+/// Evaluation of a configuration during an auto-tuning.
+#[derive(Debug, Clone, Serialize)]
+pub struct TuningStep {
+    /// Round of successive halving, starting from 0
+    pub round: usize,
+    pub num_tables: usize,
+    pub num_clusters_factor: f32,
+    pub delta: f32,
+
+    /// Queries of the round, the first ones of the sample
+    pub num_queries: usize,
+
+    pub recall_mean: f32,
+    pub distance_computations_mean: f32,
+    pub queries_per_second: f32,
+
+    /// Whether the index went on to the next round, or was chosen in the last one
+    pub promoted: bool,
+}
+
+/// Outcome of [`auto_tune`].
+#[derive(Debug, Clone)]
+pub struct AutoTuneResult {
+    /// Cheapest configuration found, `base` with the tuned parameters
+    pub config: Config,
+
+    /// Whether `config` reaches the target recall on all the queries. If no configuration
+    /// does, `config` is the one with the highest recall
+    pub reached: bool,
+
+    /// Evaluation of `config` on all the queries
+    pub report: EvalReport,
+
+    /// Every evaluation, in order
+    pub trace: Vec<TuningStep>,
+
+    /// Run of the trace in the metrics database, if saved
+    pub run_id: Option<String>,
+}
+
+/// Evaluation of an index with its cheapest delta
+struct Candidate {
+    config: Config,
+    report: EvalReport,
+    reached: bool,
+}
+
+/// Finds the cheapest configuration reaching a target recall, with successive halving.
+///
+/// Every `(num_tables, num_clusters_factor)` is an index, evaluated on the first
+/// `min_queries` queries with increasing deltas until the target is reached. The cheaper
+/// half of the indexes then goes on to the next round, evaluated on twice as many queries,
+/// until a single index is left, which is evaluated on all the queries. The cost of a
+/// configuration is its mean number of distance computations per query: unlike QPS, it
+/// doesn't depend on the load of the machine. Indexes reaching the target are ranked by
+/// cost, before the others ranked by recall.
+///
+/// # Parameters
+/// - `data`: Dataset to index
+/// - `queries`: Sample of queries, one per row
+/// - `ground_truth`: True nearest neighbors, one row per query with at least `k` columns
+/// - `params`: Target recall, parameters to search over and configuration shared by the indexes
+///
+/// # Returns
+/// An [`AutoTuneResult`] with the chosen configuration, its evaluation and the trace of the search
+///
+/// # Errors
+/// - `ClusteredIndexError::ConfigError` if a list of parameters is empty, or `min_queries` is zero
+/// - `ClusteredIndexError::ResultDBError` if the trace can't be saved
+/// - Any error returned by building, loading or [`evaluate`]-ing an index
+///
+/// # Example
+/// ```no_run
+/// use clann::eval::GroundTruth;
+/// use clann::metricdata::AngularData;
+/// use clann::tune::{auto_tune, AutoTuneParams};
+/// use clann::utils::load_hdf5_dataset;
+///
+/// let dataset = load_hdf5_dataset("./datasets/glove-25-angular.hdf5").unwrap();
+/// let mut params = AutoTuneParams {
+///     target_recall: 0.95,
+///     db_path: Some("./results_v2.sqlite3".to_string()),
+///     ..Default::default()
+/// };
+/// params.base.dataset_name = "glove-25-angular".to_string();
+///
+/// let result = auto_tune(
+///     AngularData::new(dataset.dataset_array),
+///     &dataset.dataset_queries,
+///     GroundTruth::Distances(&dataset.ground_truth_distances),
+///     &params,
+/// )
+/// .unwrap();
+/// println!("{:?}, recall {:.3}", result.config, result.report.recall_mean);
+/// ```
+pub fn auto_tune<T, S>(
+    data: T,
+    queries: &ArrayBase<S, Ix2>,
+    ground_truth: GroundTruth,
+    params: &AutoTuneParams,
+) -> Result<AutoTuneResult>
+where
+    T: MetricData + IndexableSimilarity<T> + Subset,
+    <T as Subset>::Out: IndexableSimilarity<<T as Subset>::Out>,
+    S: Data<Elem = T::DataType>,
+{
+    if params.num_tables.is_empty()
+        || params.num_clusters_factors.is_empty()
+        || params.deltas.is_empty()
+        || params.min_queries == 0
+    {
+        return Err(ClusteredIndexError::ConfigError(
+            "num_tables, num_clusters_factors and deltas must not be empty, and min_queries must be positive"
+                .to_string(),
+        ));
+    }
+
+    let mut deltas = params.deltas.clone();
+    deltas.sort_by(f32::total_cmp);
+
+    let mut candidates: Vec<Config> = params
+        .num_tables
+        .iter()
+        .flat_map(|&num_tables| {
+            params.num_clusters_factors.iter().map(move |&num_clusters_factor| Config {
+                num_tables,
+                num_clusters_factor,
+                ..params.base.clone()
+            })
+        })
+        .collect();
+    check_sweep(&candidates, &deltas)?;
+    let total_queries = queries.nrows();
+
+    let mut trace = Vec::new();
+    let mut index: ClusteredIndex<T> = ClusteredIndex::new(candidates[0].clone(), data)?;
+    let mut round = 0;
+    let best = loop {
+        let num_queries = if candidates.len() == 1 {
+            total_queries
+        } else {
+            params
+                .min_queries
+                .checked_shl(round as u32)
+                .unwrap_or(usize::MAX)
+                .min(total_queries)
+        };
+        let round_queries = queries.slice(s![..num_queries, ..]);
+        let round_truth = GroundTruthHead::new(ground_truth, num_queries);
+        info!(
+            "Auto-tuning round {}: {} indexes on {} queries",
+            round,
+            candidates.len(),
+            num_queries
+        );
+
+        let mut scored = Vec::with_capacity(candidates.len());
+        for config in candidates {
+            (index, _) = load_or_build(index, config, params.cache_dir.as_deref())?;
+
+            let mut candidate = None;
+            for &delta in &deltas {
+                index.set_delta(delta)?;
+                let report = evaluate(&mut index, &round_queries, round_truth.get(), &params.eval)?;
+                let reached = report.recall_mean >= params.target_recall;
+                trace.push(TuningStep {
+                    round,
+                    num_tables: index.config().num_tables,
+                    num_clusters_factor: index.config().num_clusters_factor,
+                    delta,
+                    num_queries,
+                    recall_mean: report.recall_mean,
+                    distance_computations_mean: report.distance_computations_mean,
+                    queries_per_second: report.queries_per_second,
+                    promoted: false,
+                });
+                candidate = Some((trace.len() - 1, Candidate {
+                    config: index.config().clone(),
+                    report,
+                    reached,
+                }));
+                if reached {
+                    break;
+                }
+            }
+            scored.extend(candidate);
+        }
+
+        scored.sort_by(|(_, a), (_, b)| compare(a, b));
+        let keep = if scored.len() == 1 { 1 } else { scored.len().div_ceil(2) };
+        for (step, _) in &scored[..keep] {
+            trace[*step].promoted = true;
+        }
+
+        if scored.len() == 1 {
+            break scored.pop().map(|(_, c)| c);
+        }
+        candidates = scored.into_iter().take(keep).map(|(_, c)| c.config).collect();
+        round += 1;
+    };
+    let best = best.ok_or_else(|| ClusteredIndexError::ConfigError("no configuration evaluated".to_string()))?;
+
+    info!(
+        "Chose num_tables {}, num_clusters_factor {:.2}, delta {:.2}: recall {:.3} with {:.0} distance computations",
+        best.config.num_tables,
+        best.config.num_clusters_factor,
+        best.config.delta,
+        best.report.recall_mean,
+        best.report.distance_computations_mean
+    );
+
+    let run_id = match &params.db_path {
+        Some(db_path) => {
+            let mut conn = Connection::open(db_path)
+                .map_err(|e| ClusteredIndexError::ResultDBError(e.to_string()))?;
+            Some(save_tuning_trace(&mut conn, &best.config, params.target_recall, &trace)?)
+        }
+        None => None,
+    };
+
+    Ok(AutoTuneResult {
+        config: best.config,
+        reached: best.reached,
+        report: best.report,
+        trace,
+        run_id,
+    })
+}
+
+/// Reached candidates first by increasing cost, then the others by decreasing recall
+fn compare(a: &Candidate, b: &Candidate) -> Ordering {
+    b.reached.cmp(&a.reached).then_with(|| {
+        if a.reached {
+            a.report
+                .distance_computations_mean
+                .total_cmp(&b.report.distance_computations_mean)
+        } else {
+            b.report.recall_mean.total_cmp(&a.report.recall_mean)
+        }
+    })
+}
+
+/// First rows of a ground truth, matching the queries of a round
+enum GroundTruthHead {
+    Ids(Array2<usize>),
+    Distances(Array2<f32>),
+}
+
+impl GroundTruthHead {
+    fn new(ground_truth: GroundTruth, rows: usize) -> Self {
+        match ground_truth {
+            GroundTruth::Ids(a) => Self::Ids(a.slice(s![..rows.min(a.nrows()), ..]).to_owned()),
+            GroundTruth::Distances(a) => {
+                Self::Distances(a.slice(s![..rows.min(a.nrows()), ..]).to_owned())
+            }
+        }
+    }
+
+    fn get(&self) -> GroundTruth<'_> {
+        match self {
+            Self::Ids(a) => GroundTruth::Ids(a),
+            Self::Distances(a) => GroundTruth::Distances(a),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::Array2;
+    use rusqlite::Connection;
+
+    use super::{auto_tune, AutoTuneParams};
+    use crate::core::{Config, IndexMode};
+    use crate::eval::{EvalParams, GroundTruth};
+    use crate::metricdata::AngularData;
+    use crate::utils::{brute_force_search, datagen::unit_vectors, test_dir};
+
+    #[test]
+    fn test_auto_tune_flat() {
+        let data = AngularData::new(unit_vectors(500, 8, 1));
+        let queries = unit_vectors(20, 8, 2);
+        let mut ground_truth = Array2::zeros((20, 5));
+        for (i, query) in queries.rows().into_iter().enumerate() {
+            for (j, id) in brute_force_search(&data, query.as_slice().unwrap(), 5).into_iter().enumerate() {
+                ground_truth[[i, j]] = id as usize;
+            }
+        }
+
+        let dir = test_dir("auto_tune");
+        let db = dir.join("auto_tune.sqlite3");
+        let params = AutoTuneParams {
+            target_recall: 0.8,
+            num_tables: vec![1],
+            num_clusters_factors: vec![0.5, 1.0, 2.0],
+            deltas: vec![0.9, 0.5],
+            base: Config {
+                index_mode: IndexMode::Flat,
+                dataset_name: "random".to_string(),
+                ..Default::default()
+            },
+            min_queries: 5,
+            db_path: Some(db.to_str().unwrap().to_string()),
+            eval: EvalParams {
+                k: 5,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let result = auto_tune(data, &queries, GroundTruth::Ids(&ground_truth), &params).unwrap();
+
+        assert_eq!(result.report.num_queries, 20);
+        assert_eq!(result.reached, result.report.recall_mean >= 0.8);
+        // 3 indexes on 5 queries, 2 on 10, 1 on all of them
+        let rounds: Vec<usize> = result.trace.iter().map(|s| s.round).collect();
+        assert_eq!(*rounds.last().unwrap(), 2);
+        assert!(result.trace.iter().filter(|s| s.round == 0).all(|s| s.num_queries == 5));
+        assert!(result.trace.iter().filter(|s| s.round == 1).all(|s| s.num_queries == 10));
+        assert_eq!(result.trace.iter().filter(|s| s.round == 0 && s.promoted).count(), 2);
+        // deltas are tried from the smallest
+        assert_eq!(result.trace[0].delta, 0.5);
+
+        let conn = Connection::open(&db).unwrap();
+        let steps: usize = conn
+            .query_row(
+                "SELECT COUNT(*) FROM tuning_trace WHERE run_id = ?1",
+                [result.run_id.as_ref().unwrap()],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(steps, result.trace.len());
+        std::fs::remove_dir_all(dir).unwrap();
+
+        let data = AngularData::new(unit_vectors(10, 8, 3));
+        let params = AutoTuneParams {
+            min_queries: 0,
+            ..params
+        };
+        assert!(auto_tune(data, &queries, GroundTruth::Ids(&ground_truth), &params).is_err());
+    }
+}
@@ -0,0 +1,280 @@
+use std::collections::HashSet;
+
+use log::info;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use serde::Serialize;
+
+use crate::core::gmm::greedy_minimum_maximum;
+use crate::core::{ClusteredIndexError, Result};
+use crate::metricdata::{MetricData, Subset};
+use crate::topk::TopK;
+
+/// Parameters of a cross-validation run.
+#[derive(Debug, Clone)]
+pub struct CvConfig {
+    /// Number of nearest neighbors to search
+    pub k: usize,
+
+    /// Number of held-out points used as queries
+    pub num_queries: usize,
+
+    /// Maximum number of points sampled from the dataset, queries included
+    pub sample_size: usize,
+
+    /// Seed of the sampling, fixed so that runs are comparable
+    pub seed: u64,
+}
+
+impl Default for CvConfig {
+    fn default() -> Self {
+        Self {
+            k: 10,
+            num_queries: 100,
+            sample_size: 100_000,
+            seed: 42,
+        }
+    }
+}
+
+/// Outcome of the clustering obtained with a single `num_clusters_factor`.
+#[derive(Debug, Clone, Serialize)]
+pub struct CvResult {
+    pub num_clusters_factor: f32,
+    pub num_clusters: usize,
+
+    /// Mean recall when the clusters not pruned by the search exit condition are scanned
+    pub recall: f32,
+
+    /// Mean number of clusters scanned, the others being pruned by the exit condition
+    pub clusters_probed: f32,
+
+    /// Mean number of distance computations, centers included
+    pub distance_computations: f32,
+
+    /// Mean recall after probing the closest `i + 1` clusters, for every `i`
+    pub recall_at_probes: Vec<f32>,
+}
+
+/// Evaluates several `num_clusters_factor` values on a held-out sample of the dataset.
+///
+/// Up to `sample_size` points are sampled: `num_queries` of them are held out as queries,
+/// the rest are clustered with greedy minimum-maximum clustering for every factor. PUFFINN
+/// indices are not built, each probed cluster is scanned exactly instead, so the results
+/// measure how well the clustering prunes the search independently of the LSH recall.
+///
+/// # Parameters
+/// - `data`: Dataset to sample from
+/// - `factors`: Values of `num_clusters_factor` to evaluate
+/// - `config`: Sampling and search parameters
+///
+/// # Returns
+/// One [`CvResult`] per factor, in the same order as `factors`
+///
+/// # Errors
+/// Returns `ClusteredIndexError::ConfigError` if `k` or the number of queries is zero,
+/// or if the sample leaves fewer than `k` points to cluster
+pub fn cv<T>(data: &T, factors: &[f32], config: &CvConfig) -> Result<Vec<CvResult>>
+where
+    T: MetricData + Subset,
+    <T as Subset>::Out: MetricData<DataType = T::DataType>,
+{
+    if config.k == 0 || config.num_queries == 0 {
+        return Err(ClusteredIndexError::ConfigError(
+            "k and num_queries must be greater than zero".to_string(),
+        ));
+    }
+
+    let mut rng = StdRng::seed_from_u64(config.seed);
+    let mut sample: Vec<usize> = (0..data.num_points()).collect();
+    sample.shuffle(&mut rng);
+    sample.truncate(config.sample_size);
+
+    if sample.len() < config.num_queries + config.k {
+        return Err(ClusteredIndexError::ConfigError(format!(
+            "sample of {} points is too small for {} queries and k={}",
+            sample.len(),
+            config.num_queries,
+            config.k
+        )));
+    }
+
+    let (queries, train_indices) = sample.split_at(config.num_queries);
+    let train = data.subset(train_indices);
+
+    info!(
+        "Cross-validating {} factors with {} queries over {} points",
+        factors.len(),
+        queries.len(),
+        train.num_points()
+    );
+
+    // ground truth, as indices into the training sample
+    let ground_truth: Vec<HashSet<usize>> = queries
+        .iter()
+        .map(|&q| {
+            let query = &*data.get_point(q);
+            let mut heap = TopK::new(config.k);
+            for p in 0..train.num_points() {
+                heap.push(train.distance_point(p, query), p);
+            }
+            heap.into_sorted_vec().into_iter().map(|(_, p)| p).collect()
+        })
+        .collect();
+
+    let results = factors
+        .iter()
+        .map(|&factor| {
+            let result = evaluate_factor(data, &train, queries, &ground_truth, factor, config.k);
+            info!(
+                "factor {:.2}: {} clusters, recall {:.3}, {:.1} clusters probed, {:.0} distance computations",
+                factor,
+                result.num_clusters,
+                result.recall,
+                result.clusters_probed,
+                result.distance_computations
+            );
+            result
+        })
+        .collect();
+
+    Ok(results)
+}
+
+fn evaluate_factor<T, U>(
+    data: &T,
+    train: &U,
+    queries: &[usize],
+    ground_truth: &[HashSet<usize>],
+    factor: f32,
+    k: usize,
+) -> CvResult
+where
+    T: MetricData,
+    U: MetricData<DataType = T::DataType>,
+{
+    // same number of clusters as ClusteredIndex::new would use on the sample
+    let num_clusters = ((factor as f64 * (train.num_points() as f64).sqrt()).floor() as usize).max(1);
+    let (centers, assignment, radii) = greedy_minimum_maximum(train, num_clusters);
+
+    let mut members: Vec<Vec<usize>> = vec![Vec::new(); centers.len()];
+    for (p, &c) in assignment.iter().enumerate() {
+        members[c].push(p);
+    }
+
+    let mut recall_sum = 0.0;
+    let mut probes_sum = 0;
+    let mut distance_computations_sum = 0;
+    let mut recall_at_probes = vec![0.0; centers.len()];
+
+    for (&q, truth) in queries.iter().zip(ground_truth) {
+        let query = &*data.get_point(q);
+
+        let mut sorted_clusters: Vec<(usize, f32)> = centers
+            .iter()
+            .enumerate()
+            .map(|(c, &center)| (c, train.distance_point(center, query)))
+            .collect();
+        sorted_clusters.sort_by(|a, b| a.1.total_cmp(&b.1));
+        distance_computations_sum += centers.len();
+
+        // recall as a function of the number of probed clusters, regardless of the exit condition
+        let mut found = 0;
+        for (rank, &(c, _)) in sorted_clusters.iter().enumerate() {
+            found += members[c].iter().filter(|p| truth.contains(p)).count();
+            recall_at_probes[rank] += found as f32 / truth.len() as f32;
+        }
+
+        // exact scan of the clusters, with the same exit condition as ClusteredIndex::search
+        let mut heap = TopK::new(k);
+        for &(c, center_distance) in &sorted_clusters {
+            // compared in the metric, where the triangle inequality holds. Clusters are sorted
+            // by center distance, a farther one with a larger radius can still hold a neighbor
+            if let Some(kth) = heap.kth_distance() {
+                let bound =
+                    train.distance_to_metric(center_distance) - train.distance_to_metric(radii[c]);
+                if bound > train.distance_to_metric(kth) {
+                    continue;
+                }
+            }
+            for &p in &members[c] {
+                heap.push(train.distance_point(p, query), p);
+            }
+            probes_sum += 1;
+            distance_computations_sum += members[c].len();
+        }
+
+        let hits = heap
+            .into_sorted_vec()
+            .iter()
+            .filter(|(_, p)| truth.contains(p))
+            .count();
+        recall_sum += hits as f32 / truth.len() as f32;
+    }
+
+    let num_queries = queries.len() as f32;
+    recall_at_probes.iter_mut().for_each(|r| *r /= num_queries);
+
+    CvResult {
+        num_clusters_factor: factor,
+        num_clusters: centers.len(),
+        recall: recall_sum / num_queries,
+        clusters_probed: probes_sum as f32 / num_queries,
+        distance_computations: distance_computations_sum as f32 / num_queries,
+        recall_at_probes,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{cv, CvConfig};
+    use crate::metricdata::{AngularData, EuclideanData};
+    use crate::utils::datagen::unit_vectors;
+
+    #[test]
+    fn test_cv_factors() {
+        let data = EuclideanData::new(unit_vectors(2000, 8, 1));
+        let config = CvConfig {
+            k: 5,
+            num_queries: 20,
+            ..Default::default()
+        };
+
+        let results = cv(&data, &[0.5, 1.0, 2.0], &config).unwrap();
+        assert_eq!(results.len(), 3);
+        assert!(results[0].num_clusters < results[2].num_clusters);
+
+        for result in &results {
+            // euclidean distance satisfies the triangle inequality, so the exit condition is exact
+            assert!((result.recall - 1.0).abs() < 1e-6);
+            assert!(result.clusters_probed >= 1.0);
+            assert!(result
+                .recall_at_probes
+                .windows(2)
+                .all(|w| w[0] <= w[1] + 1e-6));
+            assert!((result.recall_at_probes.last().unwrap() - 1.0).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn test_cv_angular() {
+        // 1 - cos is not a metric, the exit condition is exact only when compared in angles
+        let data = AngularData::new(unit_vectors(2000, 8, 2));
+        let config = CvConfig {
+            k: 5,
+            num_queries: 20,
+            ..Default::default()
+        };
+
+        for result in cv(&data, &[0.5, 2.0], &config).unwrap() {
+            assert!((result.recall - 1.0).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn test_cv_sample_too_small() {
+        let data = EuclideanData::new(unit_vectors(10, 4, 3));
+        assert!(cv(&data, &[1.0], &CvConfig::default()).is_err());
+    }
+}
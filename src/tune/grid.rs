@@ -0,0 +1,357 @@
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use log::info;
+use ndarray::{ArrayBase, Data, Ix2};
+use serde::Serialize;
+
+use crate::core::{ClusteredIndex, ClusteredIndexError, Config, Result};
+use crate::eval::{evaluate, EvalParams, EvalReport, GroundTruth};
+use crate::metricdata::{MetricData, Subset};
+use crate::puffinn_binds::IndexableSimilarity;
+
+/// Values of the parameters to combine, every combination is evaluated.
+#[derive(Debug, Clone)]
+pub struct ParamGrid {
+    pub num_tables: Vec<usize>,
+    pub num_clusters_factors: Vec<f32>,
+
+    /// Searched on the same index, changing delta needs no rebuild
+    pub deltas: Vec<f32>,
+
+    /// Configuration of the indexes, its gridded parameters are overridden
+    pub base: Config,
+
+    /// Directory where the built indexes are serialized, and loaded from by the next
+    /// grid searches. `None` to build every index
+    pub cache_dir: Option<String>,
+
+    /// Recall the ranking aims for, see [`grid_search`]
+    pub target_recall: f32,
+
+    /// Number of neighbors and warm-up queries of the evaluations
+    pub eval: EvalParams,
+}
+
+impl Default for ParamGrid {
+    fn default() -> Self {
+        Self {
+            num_tables: vec![10],
+            num_clusters_factors: vec![1.0],
+            deltas: vec![0.9],
+            base: Config::default(),
+            cache_dir: None,
+            target_recall: 0.9,
+            eval: EvalParams::default(),
+        }
+    }
+}
+
+/// Evaluation of a combination of the grid.
+#[derive(Debug, Clone, Serialize)]
+pub struct GridSearchResult {
+    pub num_tables: usize,
+    pub num_clusters_factor: f32,
+    pub delta: f32,
+    pub num_clusters: usize,
+
+    /// `None` if the index was loaded from the cache
+    pub build_time: Option<Duration>,
+
+    pub report: EvalReport,
+}
+
+/// Builds an index for every `(num_tables, num_clusters_factor)` of the grid, and evaluates
+/// it with every delta.
+///
+/// Indexes are built one at a time on the same dataset, so only one is in memory. With a
+/// `cache_dir`, an index serialized there by a previous grid search with the same dataset
+/// name and configuration is loaded instead of built, and every built index is serialized.
+///
+/// # Parameters
+/// - `data`: Dataset to index
+/// - `queries`: One query per row
+/// - `ground_truth`: True nearest neighbors, one row per query with at least `k` columns
+/// - `grid`: Parameters to combine, and the configuration shared by the indexes
+///
+/// # Returns
+/// One [`GridSearchResult`] per combination, ranked: the combinations reaching
+/// `target_recall` come first from the highest QPS, followed by the others from the
+/// highest recall
+///
+/// # Errors
+/// - `ClusteredIndexError::ConfigError` if a list of the grid is empty, or if the cache
+///   directory doesn't exist
+/// - Any error returned by building, loading, serializing or [`evaluate`]-ing an index. A
+///   cached index that can't be loaded is an error too, delete it to build it again
+///
+/// # Example
+/// ```no_run
+/// use clann::eval::GroundTruth;
+/// use clann::metricdata::AngularData;
+/// use clann::tune::{grid_search, ParamGrid};
+/// use clann::utils::load_hdf5_dataset;
+///
+/// let dataset = load_hdf5_dataset("./datasets/glove-25-angular.hdf5").unwrap();
+/// let mut grid = ParamGrid {
+///     num_tables: vec![20, 50, 100],
+///     num_clusters_factors: vec![0.2, 0.5, 1.0],
+///     deltas: vec![0.5, 0.7, 0.9],
+///     cache_dir: Some("./__index_cache__".to_string()),
+///     ..Default::default()
+/// };
+/// grid.base.dataset_name = "glove-25-angular".to_string();
+///
+/// let results = grid_search(
+///     AngularData::new(dataset.dataset_array),
+///     &dataset.dataset_queries,
+///     GroundTruth::Distances(&dataset.ground_truth_distances),
+///     &grid,
+/// )
+/// .unwrap();
+/// for r in &results {
+///     println!(
+///         "L {} factor {} delta {}: recall {:.3} at {:.0} QPS",
+///         r.num_tables, r.num_clusters_factor, r.delta, r.report.recall_mean, r.report.queries_per_second
+///     );
+/// }
+/// ```
+pub fn grid_search<T, S>(
+    data: T,
+    queries: &ArrayBase<S, Ix2>,
+    ground_truth: GroundTruth,
+    grid: &ParamGrid,
+) -> Result<Vec<GridSearchResult>>
+where
+    T: MetricData + IndexableSimilarity<T> + Subset,
+    <T as Subset>::Out: IndexableSimilarity<<T as Subset>::Out>,
+    S: Data<Elem = T::DataType>,
+{
+    if grid.num_tables.is_empty() || grid.num_clusters_factors.is_empty() || grid.deltas.is_empty() {
+        return Err(ClusteredIndexError::ConfigError(
+            "num_tables, num_clusters_factors and deltas must not be empty".to_string(),
+        ));
+    }
+    if let Some(dir) = &grid.cache_dir {
+        if !Path::new(dir).is_dir() {
+            return Err(ClusteredIndexError::ConfigError(format!(
+                "cache directory {} doesn't exist",
+                dir
+            )));
+        }
+    }
+
+    let combinations: Vec<Config> = grid
+        .num_tables
+        .iter()
+        .flat_map(|&num_tables| {
+            grid.num_clusters_factors.iter().map(move |&num_clusters_factor| Config {
+                num_tables,
+                num_clusters_factor,
+                ..grid.base.clone()
+            })
+        })
+        .collect();
+    check_sweep(&combinations, &grid.deltas)?;
+    info!(
+        "Grid search over {} indexes and {} deltas",
+        combinations.len(),
+        grid.deltas.len()
+    );
+
+    let mut results = Vec::with_capacity(combinations.len() * grid.deltas.len());
+    let mut index: ClusteredIndex<T> = ClusteredIndex::new(combinations[0].clone(), data)?;
+    for config in combinations {
+        let build_time;
+        (index, build_time) = load_or_build(index, config, grid.cache_dir.as_deref())?;
+
+        for &delta in &grid.deltas {
+            index.set_delta(delta)?;
+            let report = evaluate(&mut index, queries, ground_truth, &grid.eval)?;
+            info!(
+                "L {} factor {:.2} delta {:.2}: recall {:.3} at {:.0} QPS",
+                index.config().num_tables,
+                index.config().num_clusters_factor,
+                delta,
+                report.recall_mean,
+                report.queries_per_second
+            );
+
+            results.push(GridSearchResult {
+                num_tables: index.config().num_tables,
+                num_clusters_factor: index.config().num_clusters_factor,
+                delta,
+                num_clusters: index.num_clusters(),
+                build_time,
+                report,
+            });
+        }
+    }
+
+    rank(&mut results, grid.target_recall);
+    Ok(results)
+}
+
+/// Checks every configuration of a sweep with every delta, before anything is built.
+///
+/// # Errors
+/// Returns `ClusteredIndexError::ConfigError` naming the first parameter out of range
+pub(super) fn check_sweep(configs: &[Config], deltas: &[f32]) -> Result<()> {
+    for config in configs {
+        for &delta in deltas {
+            Config { delta, ..config.clone() }.validate()?;
+        }
+    }
+    Ok(())
+}
+
+/// Gives `index` the configuration `config`, loading it from `cache_dir` if it was cached
+/// there, building and caching it otherwise. Returns the build time of built indexes
+pub(super) fn load_or_build<T>(
+    mut index: ClusteredIndex<T>,
+    config: Config,
+    cache_dir: Option<&str>,
+) -> Result<(ClusteredIndex<T>, Option<Duration>)>
+where
+    T: MetricData + IndexableSimilarity<T> + Subset,
+    <T as Subset>::Out: IndexableSimilarity<<T as Subset>::Out>,
+{
+    index.reconfigure(config.clone())?;
+
+    if let Some(dir) = cache_dir {
+        let path = index.file_path(dir);
+        if Path::new(&path).exists() {
+            info!("Loading index from {}", path);
+            let loaded = ClusteredIndex::new_from_file(index.into_data(), &path)?;
+            if same_index(loaded.config(), &config) {
+                index = loaded;
+                // keep the metrics output and search parameters of the grid
+                index.set_k(config.k)?;
+                return Ok((index, None));
+            }
+            info!("Index in {} was built with another configuration, rebuilding it", path);
+            index = loaded;
+            index.reconfigure(config)?;
+        }
+    }
+
+    let start = Instant::now();
+    index.build()?;
+    let build_time = start.elapsed();
+    info!("Index built in {:.2?}", build_time);
+
+    if let Some(dir) = cache_dir {
+        index.serialize(dir)?;
+    }
+
+    Ok((index, Some(build_time)))
+}
+
+/// Whether two configurations build the same index, ignoring the search parameters and the metrics
+fn same_index(a: &Config, b: &Config) -> bool {
+    a.build_parameters() == b.build_parameters()
+}
+
+/// Combinations reaching `target_recall` first, by decreasing QPS, then the others by decreasing recall
+fn rank(results: &mut [GridSearchResult], target_recall: f32) {
+    results.sort_by(|a, b| {
+        let (a_reached, b_reached) = (
+            a.report.recall_mean >= target_recall,
+            b.report.recall_mean >= target_recall,
+        );
+        b_reached.cmp(&a_reached).then_with(|| {
+            if a_reached {
+                b.report.queries_per_second.total_cmp(&a.report.queries_per_second)
+            } else {
+                b.report.recall_mean.total_cmp(&a.report.recall_mean)
+            }
+        })
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::Array2;
+
+    use super::{grid_search, same_index, ParamGrid};
+    use crate::core::{ClusteredIndexError, Config, IndexMode};
+    use crate::eval::{EvalParams, GroundTruth};
+    use crate::metricdata::AngularData;
+    use crate::utils::{brute_force_search, datagen::unit_vectors};
+
+    #[test]
+    fn test_grid_search_flat() {
+        let data = AngularData::new(unit_vectors(500, 8, 1));
+        let queries = unit_vectors(10, 8, 2);
+        let mut ground_truth = Array2::zeros((10, 5));
+        for (i, query) in queries.rows().into_iter().enumerate() {
+            for (j, id) in brute_force_search(&data, query.as_slice().unwrap(), 5).into_iter().enumerate() {
+                ground_truth[[i, j]] = id as usize;
+            }
+        }
+
+        let grid = ParamGrid {
+            num_tables: vec![1],
+            num_clusters_factors: vec![0.5, 1.0],
+            deltas: vec![0.5, 0.9],
+            base: Config {
+                index_mode: IndexMode::Flat,
+                ..Default::default()
+            },
+            target_recall: 0.5,
+            eval: EvalParams {
+                k: 5,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let results = grid_search(data, &queries, GroundTruth::Ids(&ground_truth), &grid).unwrap();
+
+        assert_eq!(results.len(), 4);
+        assert!(results.iter().all(|r| r.build_time.is_some() && r.num_clusters > 0));
+        assert!(results.iter().any(|r| r.num_clusters_factor == 0.5 && r.delta == 0.9));
+        // ranked: the reached ones first, by decreasing QPS
+        let reached: Vec<_> = results.iter().take_while(|r| r.report.recall_mean >= 0.5).collect();
+        assert!(reached
+            .windows(2)
+            .all(|w| w[0].report.queries_per_second >= w[1].report.queries_per_second));
+        assert!(results[reached.len()..].iter().all(|r| r.report.recall_mean < 0.5));
+
+        // out of range values are rejected before building anything
+        for deltas in [vec![0.9, 1.5], vec![0.0], vec![f32::NAN]] {
+            let invalid = ParamGrid {
+                deltas,
+                ..grid.clone()
+            };
+            let data = AngularData::new(unit_vectors(10, 8, 3));
+            assert!(matches!(
+                grid_search(data, &queries, GroundTruth::Ids(&ground_truth), &invalid),
+                Err(ClusteredIndexError::ConfigError(_))
+            ));
+        }
+
+        let empty = ParamGrid {
+            deltas: Vec::new(),
+            ..grid
+        };
+        let data = AngularData::new(unit_vectors(10, 8, 4));
+        assert!(grid_search(data, &queries, GroundTruth::Ids(&ground_truth), &empty).is_err());
+    }
+
+    #[test]
+    fn test_same_index() {
+        let a = Config::default();
+        let b = Config {
+            k: 100,
+            delta: 0.5,
+            run_tags: vec!["sweep".to_string()],
+            ..Default::default()
+        };
+        assert!(same_index(&a, &b));
+        let c = Config {
+            num_tables: 3,
+            ..Default::default()
+        };
+        assert!(!same_index(&a, &c));
+    }
+}
@@ -0,0 +1,9 @@
+//! Tools to choose the index hyperparameters for a dataset.
+
+pub(crate) mod auto;
+pub(crate) mod cv;
+pub(crate) mod grid;
+
+pub use auto::{auto_tune, AutoTuneParams, AutoTuneResult, TuningStep};
+pub use cv::{cv, CvConfig, CvResult};
+pub use grid::{grid_search, GridSearchResult, ParamGrid};
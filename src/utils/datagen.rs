@@ -0,0 +1,335 @@
+//! Synthetic datasets with their exact ground truth, to test the search without downloading
+//! real embeddings.
+//!
+//! Every generator is seeded, so that a test sees the same points on every run, except
+//! [`generate_random_unit_vectors`], kept for the callers that don't need to.
+//!
+//! ```
+//! use clann::utils::datagen::{Distance, GaussianMixture};
+//!
+//! let dataset = GaussianMixture {
+//!     num_points: 1000,
+//!     num_queries: 10,
+//!     dimensions: 16,
+//!     num_clusters: 8,
+//!     far_queries: 2,
+//!     distance: Distance::Euclidean,
+//!     ..Default::default()
+//! }
+//! .generate();
+//! assert_eq!(dataset.train.dim(), (1000, 16));
+//! assert_eq!(dataset.neighbors.dim(), (10, 10));
+//! ```
+
+use ndarray::{Array2, ArrayView2, Axis};
+use rand::rngs::StdRng;
+use rand::{thread_rng, Rng, SeedableRng};
+
+use super::gaussian;
+use crate::metricdata::{AngularData, EuclideanData, MetricData};
+use crate::topk::TopK;
+
+/// Distance the ground truth of a synthetic dataset is computed with.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Distance {
+    /// As [`AngularData`], the points are normalized
+    #[default]
+    Angular,
+
+    /// As [`EuclideanData`]
+    Euclidean,
+}
+
+/// Points and queries with the exact nearest neighbors of the queries among the points.
+#[derive(Debug, Clone)]
+pub struct SyntheticDataset {
+    pub train: Array2<f32>,
+    pub queries: Array2<f32>,
+
+    /// Ids of the true nearest neighbors of each query, closest first
+    pub neighbors: Array2<usize>,
+
+    /// Distances of the true nearest neighbors of each query, closest first
+    pub distances: Array2<f32>,
+}
+
+impl SyntheticDataset {
+    /// Computes the `k` nearest neighbors of every query among `train` by scanning all of them.
+    ///
+    /// # Panics
+    /// If `k` is larger than the number of points
+    pub fn new(train: Array2<f32>, queries: Array2<f32>, k: usize, distance: Distance) -> Self {
+        assert!(k <= train.nrows(), "k = {} is larger than the {} points", k, train.nrows());
+        let (neighbors, distances) = match distance {
+            Distance::Angular => ground_truth(&AngularData::new(train.view()), queries.view(), k),
+            Distance::Euclidean => ground_truth(&EuclideanData::new(train.view()), queries.view(), k),
+        };
+
+        Self {
+            train,
+            queries,
+            neighbors,
+            distances,
+        }
+    }
+}
+
+fn ground_truth<T>(data: &T, queries: ArrayView2<f32>, k: usize) -> (Array2<usize>, Array2<f32>)
+where
+    T: MetricData<DataType = f32>,
+{
+    let mut neighbors = Array2::zeros((queries.nrows(), k));
+    let mut distances = Array2::zeros((queries.nrows(), k));
+    for (i, query) in queries.rows().into_iter().enumerate() {
+        let query = query.to_vec();
+        let mut top = TopK::new(k);
+        for p in 0..data.num_points() {
+            top.push(data.distance_point(p, &query), p);
+        }
+        for (j, (distance, p)) in top.into_sorted_vec().into_iter().enumerate() {
+            neighbors[[i, j]] = p;
+            distances[[i, j]] = distance;
+        }
+    }
+    (neighbors, distances)
+}
+
+/// Points drawn around `num_clusters` random centers, with heavy-tailed outliers and queries
+/// far from all of them.
+///
+/// The centers are standard normal vectors and the points are drawn around them with a
+/// standard deviation of `spread`, so the smaller the spread the better separated the
+/// clusters. The queries are drawn from the same mixture, except the last `far_queries`,
+/// which are random directions at `far_scale` times the norm of the farthest point: their
+/// neighbors are spread over many clusters, the hard case of the pruning.
+#[derive(Debug, Clone)]
+pub struct GaussianMixture {
+    pub num_points: usize,
+
+    /// Number of queries, the far ones included
+    pub num_queries: usize,
+    pub dimensions: usize,
+    pub num_clusters: usize,
+
+    /// Standard deviation of the points around their center
+    pub spread: f32,
+
+    /// Fraction of the points, in [0, 1], whose offset from their center follows a Cauchy
+    /// distribution instead of a normal one
+    pub outliers: f32,
+
+    /// Number of queries far from every cluster, at most `num_queries`
+    pub far_queries: usize,
+
+    /// Distance of the far queries from the origin, relative to the farthest point. With
+    /// [`Distance::Angular`] only their direction matters
+    pub far_scale: f32,
+
+    pub distance: Distance,
+
+    /// Number of true neighbors computed for each query
+    pub k: usize,
+
+    pub seed: u64,
+}
+
+impl Default for GaussianMixture {
+    fn default() -> Self {
+        Self {
+            num_points: 10_000,
+            num_queries: 100,
+            dimensions: 32,
+            num_clusters: 16,
+            spread: 0.1,
+            outliers: 0.0,
+            far_queries: 0,
+            far_scale: 10.0,
+            distance: Distance::Angular,
+            k: 10,
+            seed: 42,
+        }
+    }
+}
+
+impl GaussianMixture {
+    /// Draws the points and the queries and computes their ground truth.
+    ///
+    /// # Panics
+    /// If there are no clusters, more far queries than queries, or fewer points than `k`
+    pub fn generate(&self) -> SyntheticDataset {
+        assert!(self.num_clusters > 0, "a mixture needs at least one cluster");
+        assert!(
+            self.far_queries <= self.num_queries,
+            "{} far queries out of {} queries",
+            self.far_queries,
+            self.num_queries
+        );
+
+        let mut rng = StdRng::seed_from_u64(self.seed);
+        let centers = Array2::from_shape_simple_fn((self.num_clusters, self.dimensions), || gaussian(&mut rng));
+
+        let mut train = Array2::zeros((self.num_points, self.dimensions));
+        for mut row in train.rows_mut() {
+            let center = centers.row(rng.gen_range(0..self.num_clusters));
+            let heavy_tailed = rng.gen::<f32>() < self.outliers;
+            for (x, &c) in row.iter_mut().zip(center) {
+                let offset = if heavy_tailed {
+                    // ratio of two standard normals
+                    gaussian(&mut rng) / gaussian(&mut rng)
+                } else {
+                    gaussian(&mut rng)
+                };
+                *x = c + self.spread * offset;
+            }
+        }
+
+        let max_norm = train
+            .rows()
+            .into_iter()
+            .map(|row| row.dot(&row).sqrt())
+            .fold(0.0, f32::max);
+        let near_queries = self.num_queries - self.far_queries;
+        let mut queries = Array2::zeros((self.num_queries, self.dimensions));
+        for (i, mut row) in queries.rows_mut().into_iter().enumerate() {
+            if i < near_queries {
+                let center = centers.row(rng.gen_range(0..self.num_clusters));
+                for (x, &c) in row.iter_mut().zip(center) {
+                    *x = c + self.spread * gaussian(&mut rng);
+                }
+            } else {
+                row.iter_mut().for_each(|x| *x = gaussian(&mut rng));
+                let norm = row.dot(&row).sqrt().max(f32::EPSILON);
+                row *= self.far_scale * max_norm / norm;
+            }
+        }
+
+        if self.distance == Distance::Angular {
+            normalize(&mut train);
+            normalize(&mut queries);
+        }
+
+        SyntheticDataset::new(train, queries, self.k, self.distance)
+    }
+}
+
+fn normalize(data: &mut Array2<f32>) {
+    for mut row in data.axis_iter_mut(Axis(0)) {
+        let norm = row.dot(&row).sqrt();
+        if norm > 0.0 {
+            row /= norm;
+        }
+    }
+}
+
+/// `n` points drawn uniformly on the unit sphere, the same for the same seed.
+pub fn unit_vectors(n: usize, dimensions: usize, seed: u64) -> Array2<f32> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut data = Array2::from_shape_simple_fn((n, dimensions), || gaussian(&mut rng));
+    normalize(&mut data);
+    data
+}
+
+/// `n` unit vectors with uniform nonnegative components before normalization, drawn from the
+/// thread RNG: different on every call. Use [`unit_vectors`] for points a test can rely on.
+pub fn generate_random_unit_vectors(n: usize, dimensions: usize) -> Array2<f32> {
+    let mut rng = thread_rng();
+    let mut data = Array2::<f32>::zeros((n, dimensions));
+
+    for mut row in data.axis_iter_mut(Axis(0)) {
+        let vec: Vec<f32> = (0..dimensions).map(|_| rng.gen::<f32>()).collect();
+        let norm: f32 = vec.iter().map(|x| x.powi(2)).sum::<f32>().sqrt();
+        row.assign(&ndarray::arr1(
+            &vec.iter().map(|x| x / norm).collect::<Vec<f32>>(),
+        ));
+    }
+
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{unit_vectors, Distance, GaussianMixture};
+    use crate::core::{ClusteredIndex, Config, IndexMode};
+    use crate::eval::{evaluate, EvalParams, GroundTruth};
+    use crate::metricdata::AngularData;
+
+    fn mixture() -> GaussianMixture {
+        GaussianMixture {
+            num_points: 2000,
+            num_queries: 20,
+            dimensions: 8,
+            num_clusters: 10,
+            spread: 0.05,
+            outliers: 0.01,
+            far_queries: 5,
+            distance: Distance::Euclidean,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_generators_are_seeded() {
+        let a = mixture().generate();
+        let b = mixture().generate();
+        assert_eq!(a.train, b.train);
+        assert_eq!(a.queries, b.queries);
+        assert_eq!(a.neighbors, b.neighbors);
+        let c = GaussianMixture { seed: 7, ..mixture() }.generate();
+        assert_ne!(a.train, c.train);
+
+        assert_eq!(unit_vectors(10, 4, 1), unit_vectors(10, 4, 1));
+        for row in unit_vectors(10, 4, 1).rows() {
+            assert!((row.dot(&row) - 1.0).abs() < 1e-5);
+        }
+
+        let angular = GaussianMixture {
+            distance: Distance::Angular,
+            ..mixture()
+        }
+        .generate();
+        for row in angular.train.rows() {
+            assert!((row.dot(&row) - 1.0).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn test_ground_truth_and_far_queries() {
+        let dataset = mixture().generate();
+        assert_eq!(dataset.neighbors.dim(), (20, 10));
+        for row in dataset.distances.rows() {
+            assert!(row.windows(2).into_iter().all(|w| w[0] <= w[1]));
+        }
+
+        // the far queries are farther from their neighbors than the ones drawn from the mixture
+        let nearest = dataset.distances.column(0);
+        let near_max = nearest.iter().take(15).fold(0.0f32, |a, &b| a.max(b));
+        assert!(nearest.iter().skip(15).all(|&d| d > near_max));
+    }
+
+    #[test]
+    fn test_pruning_is_exact_on_separated_clusters() {
+        let dataset = GaussianMixture {
+            distance: Distance::Angular,
+            ..mixture()
+        }
+        .generate();
+        let config = Config {
+            index_mode: IndexMode::Flat,
+            num_clusters_factor: 0.5,
+            ..Default::default()
+        };
+        let mut index: ClusteredIndex<_> = ClusteredIndex::new(config, AngularData::new(dataset.train)).unwrap();
+        index.build().unwrap();
+
+        // clusters scanned exhaustively and pruned with their largest radius miss nothing, even
+        // for the far queries whose neighbors are spread over many clusters
+        let report = evaluate(
+            &mut index,
+            &dataset.queries,
+            GroundTruth::Ids(&dataset.neighbors),
+            &EvalParams::default(),
+        )
+        .unwrap();
+        assert_eq!(report.recall_mean, 1.0, "{:?}", report.recalls);
+    }
+}
@@ -1,20 +1,291 @@
-pub(crate) fn csv_build_metrics(
-    &self, 
-    file_path: &str,
-    num_clusters: usize,
-    num_greedy: usize,
-    memory_used_bytes: usize,
-    build_times_s: u64,
+use std::fs::OpenOptions;
+use std::path::Path;
+
+use crate::core::{config::MetricsGranularity, index::ClusterCenter};
+
+use super::run::RunInfo;
+use super::RunMetrics;
+
+const RUNS: &[&str] = &[
+    "run_id",
+    "num_clusters",
+    "num_tables",
+    "k",
+    "delta",
+    "dataset",
+    "git_commit_hash",
+    "tags",
+    "hostname",
+    "os",
+    "arch",
+    "num_cpus",
+    "created_at",
+    "num_threads",
+];
+
+const BUILD_METRICS: &[&str] = &[
+    "run_id",
+    "num_clusters",
+    "num_tables",
+    "dataset",
+    "git_commit_hash",
+    "dataset_len",
+    "total_num_clusters",
+    "greedy_num_clusters",
+    "memory_used_bytes",
+    "build_time_s",
+    "created_at",
+    "merged_clusters",
+    "selected_clusters",
+    "dataset_memory_bytes",
+    "assignments_memory_bytes",
+    "centers_memory_bytes",
+    "quantized_memory_bytes",
+    "duplicates_memory_bytes",
+    "total_memory_bytes",
+    "start_rss_bytes",
+    "clustering_peak_rss_bytes",
+    "indexing_peak_rss_bytes",
+    "peak_rss_bytes",
+];
+
+const BUILD_METRICS_CLUSTER: &[&str] = &[
+    "run_id",
+    "num_clusters",
+    "num_tables",
+    "dataset",
+    "git_commit_hash",
+    "cluster_idx",
+    "center_idx",
+    "greedy_flag",
+    "radius",
+    "num_points",
+    "memory_used_bytes",
+    "build_time_ms",
+];
+
+const SEARCH_METRICS: &[&str] = &[
+    "run_id",
+    "num_clusters",
+    "num_tables",
+    "k",
+    "delta",
+    "dataset",
+    "git_commit_hash",
+    "search_time_ms",
+    "queries_per_second",
+    "recall_mean",
+    "recall_std",
+    "created_at",
+];
+
+const SEARCH_METRICS_CLUSTER_SUMMARY: &[&str] = &[
+    "run_id",
+    "num_clusters",
+    "num_tables",
+    "k",
+    "delta",
+    "dataset",
+    "git_commit_hash",
+    "cluster_idx",
+    "visits",
+    "prunes",
+    "topk_contributions",
+];
+
+const SEARCH_METRICS_QUERY: &[&str] = &[
+    "run_id",
+    "num_clusters",
+    "num_tables",
+    "k",
+    "delta",
+    "dataset",
+    "git_commit_hash",
+    "query_idx",
+    "query_time_ms",
+    "distance_computations",
+    "clusters_probed",
+    "lsh_distance_computations",
+    "rerank_distance_computations",
+    "pruning_distance_computations",
+];
+
+const SEARCH_METRICS_CLUSTER: &[&str] = &[
+    "run_id",
+    "num_clusters",
+    "num_tables",
+    "k",
+    "delta",
+    "dataset",
+    "git_commit_hash",
+    "query_idx",
+    "cluster_idx",
+    "n_candidates",
+    "cluster_time_ms",
+    "cluster_distance_computations",
+];
+
+/// Opens `{table}.csv` in `dir` for appending, writing the header if the file is new
+fn open_table(
+    dir: &Path,
+    table: &str,
+    header: &[&str],
+) -> Result<csv::Writer<std::fs::File>, Box<dyn std::error::Error>> {
+    let path = dir.join(format!("{}.csv", table));
+    let is_new = !path.exists();
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+
+    let mut wtr = csv::Writer::from_writer(file);
+    if is_new {
+        wtr.write_record(header)?;
+    }
+
+    Ok(wtr)
+}
+
+/// Appends the metrics of a run to one CSV file per table of the SQLite schema
+pub(crate) fn csv_write_metrics(
+    metrics: &RunMetrics,
+    run: &RunInfo,
+    dir: &str,
+    granularity: &MetricsGranularity,
+    clusters: &[ClusterCenter],
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let mut wtr = csv::Writer::from_path(file_path)?;
+    let dir = Path::new(dir);
+    let config = &metrics.config;
+    let git_hash = option_env!("GIT_COMMIT_HASH").unwrap_or("NO_COMMIT");
+    let current_time = run.created_at.clone();
+
+    // columns identifying the run and the index, and the search parameters
+    let index_key = [
+        run.run_id.clone(),
+        config.num_clusters_factor.to_string(),
+        config.num_tables.to_string(),
+        config.dataset_name.clone(),
+        git_hash.to_string(),
+    ];
+    let search_key = [
+        run.run_id.clone(),
+        config.num_clusters_factor.to_string(),
+        config.num_tables.to_string(),
+        config.k.to_string(),
+        config.delta.to_string(),
+        config.dataset_name.clone(),
+        git_hash.to_string(),
+    ];
+
+    let mut wtr = open_table(dir, "runs", RUNS)?;
+    wtr.write_record(search_key.iter().cloned().chain([
+        run.tags_json(),
+        run.hostname.clone().unwrap_or_default(),
+        run.os.to_string(),
+        run.arch.to_string(),
+        run.num_cpus.to_string(),
+        current_time.clone(),
+        config.threads().to_string(),
+    ]))?;
+    wtr.flush()?;
+
+    let mut wtr = open_table(dir, "build_metrics", BUILD_METRICS)?;
+    wtr.write_record(index_key.iter().cloned().chain([
+        metrics.dataset_len.to_string(),
+        clusters.len().to_string(),
+        clusters.iter().filter(|c| c.brute_force).count().to_string(),
+        clusters.iter().map(|c| c.memory_used).sum::<usize>().to_string(),
+        metrics.indexing_duration.as_secs_f64().to_string(),
+        current_time.clone(),
+        metrics.merged_clusters.to_string(),
+        metrics.selected_clusters.to_string(),
+        metrics.memory.dataset.to_string(),
+        metrics.memory.assignments.to_string(),
+        metrics.memory.centers.to_string(),
+        metrics.memory.quantized.to_string(),
+        metrics.memory.duplicates.to_string(),
+        metrics.memory.total().to_string(),
+        metrics.peak_rss.map_or(String::new(), |rss| rss.start.to_string()),
+        metrics.peak_rss.map_or(String::new(), |rss| rss.clustering.to_string()),
+        metrics.peak_rss.map_or(String::new(), |rss| rss.indexing.to_string()),
+        metrics.peak_rss.map_or(String::new(), |rss| rss.peak().to_string()),
+    ]))?;
+    wtr.flush()?;
+
+    let mut wtr = open_table(dir, "build_metrics_cluster", BUILD_METRICS_CLUSTER)?;
+    for cluster in clusters {
+        wtr.write_record(index_key.iter().cloned().chain([
+            cluster.idx.to_string(),
+            cluster.center_idx.to_string(),
+            (cluster.brute_force as u8).to_string(),
+            cluster.radius.to_string(),
+            cluster.assignment.len().to_string(),
+            cluster.memory_used.to_string(),
+            (cluster.build_time.as_secs_f64() * 1000.0).to_string(),
+        ]))?;
+    }
+    wtr.flush()?;
+
+    let mut wtr = open_table(dir, "search_metrics", SEARCH_METRICS)?;
+    wtr.write_record(search_key.iter().cloned().chain([
+        (metrics.total_search_time_s.as_secs_f64() * 1000.0).to_string(),
+        metrics.queries_per_second.to_string(),
+        // left empty if the run has no ground truth
+        metrics.recall_mean.map_or(String::new(), |r| r.to_string()),
+        metrics.recall_std.map_or(String::new(), |r| r.to_string()),
+        current_time,
+    ]))?;
+    wtr.flush()?;
+
+    let mut wtr = open_table(dir, "search_metrics_cluster_summary", SEARCH_METRICS_CLUSTER_SUMMARY)?;
+    for cluster in metrics.cluster_summary(clusters) {
+        wtr.write_record(search_key.iter().cloned().chain([
+            cluster.cluster_idx.to_string(),
+            cluster.visits.to_string(),
+            cluster.prunes.to_string(),
+            cluster.topk_contributions.to_string(),
+        ]))?;
+    }
+    wtr.flush()?;
+
+    if matches!(granularity, MetricsGranularity::Run) {
+        return Ok(());
+    }
+
+    let mut wtr = open_table(dir, "search_metrics_query", SEARCH_METRICS_QUERY)?;
+    for (query_idx, query) in metrics.queries.iter().enumerate() {
+        wtr.write_record(search_key.iter().cloned().chain([
+            query_idx.to_string(),
+            (query.query_time.as_secs_f64() * 1000.0).to_string(),
+            query.distance_computations.to_string(),
+            query.clusters_probed().to_string(),
+            query.distance_breakdown.lsh.to_string(),
+            query.distance_breakdown.rerank.to_string(),
+            query.distance_breakdown.pruning.to_string(),
+        ]))?;
+    }
+    wtr.flush()?;
+
+    if !matches!(granularity, MetricsGranularity::Cluster) {
+        return Ok(());
+    }
 
-    wtr.write_record(&["num_clusters", &self.config.num_clusters_factor.to_string()])?;
-    wtr.write_record(&["num_tables", &self.config.num_tables.to_string()])?;
-    wtr.write_record(&["dataset", &self.config.dataset_name])?;
-    wtr.write_record(&["total_num_clusters", &num_clusters.to_string()])?;
-    wtr.write_record(&["greedy_num_clusters", &num_greedy.to_string()])?;
-    wtr.write_record(&["memory_used_bytes", &memory_used_bytes.to_string()])?;
-    wtr.write_record(&["build_time_s", &build_times_s.to_string()])?;
+    let mut wtr = open_table(dir, "search_metrics_cluster", SEARCH_METRICS_CLUSTER)?;
+    for (query_idx, query) in metrics.queries.iter().enumerate() {
+        for (cluster_idx, ((n_candidates, timing), distance_comp)) in query
+            .cluster_n_candidates
+            .iter()
+            .zip(&query.cluster_timings)
+            .zip(&query.cluster_distance_computations)
+            .enumerate()
+        {
+            wtr.write_record(search_key.iter().cloned().chain([
+                query_idx.to_string(),
+                cluster_idx.to_string(),
+                n_candidates.to_string(),
+                (timing.as_secs_f64() * 1000.0).to_string(),
+                distance_comp.to_string(),
+            ]))?;
+        }
+    }
+    wtr.flush()?;
 
     Ok(())
-}
\ No newline at end of file
+}
@@ -0,0 +1,222 @@
+use std::fs::File;
+use std::io::BufWriter;
+
+use serde::Serialize;
+
+use crate::core::{config::MetricsGranularity, index::ClusterCenter, MemoryUsage, PeakRss};
+
+use super::run::RunInfo;
+use super::{QueryMetrics, RunMetrics};
+
+/// Same content as the SQLite tables, nested: run, then queries, then the clusters of each query
+#[derive(Serialize)]
+struct JsonMetrics<'a> {
+    run_id: &'a str,
+    tags: &'a [String],
+    hostname: Option<&'a str>,
+    os: &'a str,
+    arch: &'a str,
+    num_cpus: usize,
+    num_threads: usize,
+    num_clusters: f32,
+    num_tables: usize,
+    k: usize,
+    delta: f32,
+    dataset: &'a str,
+    git_commit_hash: &'a str,
+    created_at: &'a str,
+    build: JsonBuildMetrics,
+    search: JsonSearchMetrics,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    queries: Option<Vec<JsonQueryMetrics>>,
+}
+
+#[derive(Serialize)]
+struct JsonBuildMetrics {
+    dataset_len: usize,
+    total_num_clusters: usize,
+    greedy_num_clusters: usize,
+    memory_used_bytes: usize,
+    build_time_s: f64,
+    selected_clusters: usize,
+    merged_clusters: usize,
+    /// Memory of the index by component, `memory_used_bytes` being the cluster indices
+    memory: MemoryUsage,
+    /// null unless the resident set size was tracked
+    peak_rss: Option<PeakRss>,
+    clusters: Vec<JsonBuildClusterMetrics>,
+}
+
+#[derive(Serialize)]
+struct JsonBuildClusterMetrics {
+    cluster_idx: usize,
+    center_idx: usize,
+    greedy_flag: bool,
+    radius: f32,
+    num_points: usize,
+    memory_used_bytes: usize,
+    build_time_ms: f64,
+}
+
+#[derive(Serialize)]
+struct JsonSearchMetrics {
+    search_time_s: f64,
+    queries_per_second: f32,
+    /// null if the run has no ground truth
+    recall_mean: Option<f32>,
+    recall_std: Option<f32>,
+    clusters: Vec<JsonClusterSummary>,
+}
+
+#[derive(Serialize)]
+struct JsonClusterSummary {
+    cluster_idx: usize,
+    visits: usize,
+    prunes: usize,
+    topk_contributions: usize,
+}
+
+#[derive(Serialize)]
+struct JsonQueryMetrics {
+    query_idx: usize,
+    query_time_ms: f64,
+    distance_computations: usize,
+    lsh_distance_computations: usize,
+    rerank_distance_computations: usize,
+    pruning_distance_computations: usize,
+    clusters_probed: usize,
+    topk_clusters: Vec<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    clusters: Option<Vec<JsonQueryClusterMetrics>>,
+}
+
+#[derive(Serialize)]
+struct JsonQueryClusterMetrics {
+    /// Position of the cluster in the probe order of the query
+    cluster_idx: usize,
+    cluster_id: usize,
+    n_candidates: usize,
+    cluster_time_ms: f64,
+    cluster_distance_computations: usize,
+}
+
+fn query_metrics(
+    query_idx: usize,
+    query: &QueryMetrics,
+    granularity: &MetricsGranularity,
+) -> JsonQueryMetrics {
+    let clusters = matches!(granularity, MetricsGranularity::Cluster).then(|| {
+        query
+            .cluster_n_candidates
+            .iter()
+            .zip(&query.cluster_timings)
+            .zip(&query.cluster_distance_computations)
+            .zip(&query.cluster_ids)
+            .enumerate()
+            .map(
+                |(cluster_idx, (((&n_candidates, timing), &distance_computations), &cluster_id))| {
+                    JsonQueryClusterMetrics {
+                        cluster_idx,
+                        cluster_id,
+                        n_candidates,
+                        cluster_time_ms: timing.as_secs_f64() * 1000.0,
+                        cluster_distance_computations: distance_computations,
+                    }
+                },
+            )
+            .collect()
+    });
+
+    JsonQueryMetrics {
+        query_idx,
+        query_time_ms: query.query_time.as_secs_f64() * 1000.0,
+        distance_computations: query.distance_computations,
+        lsh_distance_computations: query.distance_breakdown.lsh,
+        rerank_distance_computations: query.distance_breakdown.rerank,
+        pruning_distance_computations: query.distance_breakdown.pruning,
+        clusters_probed: query.clusters_probed(),
+        topk_clusters: query.topk_clusters.clone(),
+        clusters,
+    }
+}
+
+pub(crate) fn json_write_metrics(
+    metrics: &RunMetrics,
+    run: &RunInfo,
+    file_path: &str,
+    granularity: &MetricsGranularity,
+    clusters: &[ClusterCenter],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let build = JsonBuildMetrics {
+        dataset_len: metrics.dataset_len,
+        total_num_clusters: clusters.len(),
+        greedy_num_clusters: clusters.iter().filter(|c| c.brute_force).count(),
+        memory_used_bytes: clusters.iter().map(|c| c.memory_used).sum(),
+        build_time_s: metrics.indexing_duration.as_secs_f64(),
+        selected_clusters: metrics.selected_clusters,
+        merged_clusters: metrics.merged_clusters,
+        memory: metrics.memory,
+        peak_rss: metrics.peak_rss,
+        clusters: clusters
+            .iter()
+            .map(|cluster| JsonBuildClusterMetrics {
+                cluster_idx: cluster.idx,
+                center_idx: cluster.center_idx,
+                greedy_flag: cluster.brute_force,
+                radius: cluster.radius,
+                num_points: cluster.assignment.len(),
+                memory_used_bytes: cluster.memory_used,
+                build_time_ms: cluster.build_time.as_secs_f64() * 1000.0,
+            })
+            .collect(),
+    };
+
+    let queries = (!matches!(granularity, MetricsGranularity::Run)).then(|| {
+        metrics
+            .queries
+            .iter()
+            .enumerate()
+            .map(|(query_idx, query)| query_metrics(query_idx, query, granularity))
+            .collect()
+    });
+
+    let output = JsonMetrics {
+        run_id: &run.run_id,
+        tags: &run.tags,
+        hostname: run.hostname.as_deref(),
+        os: run.os,
+        arch: run.arch,
+        num_cpus: run.num_cpus,
+        num_threads: metrics.config.threads(),
+        num_clusters: metrics.config.num_clusters_factor,
+        num_tables: metrics.config.num_tables,
+        k: metrics.config.k,
+        delta: metrics.config.delta,
+        dataset: &metrics.config.dataset_name,
+        git_commit_hash: option_env!("GIT_COMMIT_HASH").unwrap_or("NO_COMMIT"),
+        created_at: &run.created_at,
+        build,
+        search: JsonSearchMetrics {
+            search_time_s: metrics.total_search_time_s.as_secs_f64(),
+            queries_per_second: metrics.queries_per_second,
+            recall_mean: metrics.recall_mean,
+            recall_std: metrics.recall_std,
+            clusters: metrics
+                .cluster_summary(clusters)
+                .into_iter()
+                .map(|c| JsonClusterSummary {
+                    cluster_idx: c.cluster_idx,
+                    visits: c.visits,
+                    prunes: c.prunes,
+                    topk_contributions: c.topk_contributions,
+                })
+                .collect(),
+        },
+        queries,
+    };
+
+    let writer = BufWriter::new(File::create(file_path)?);
+    serde_json::to_writer_pretty(writer, &output)?;
+
+    Ok(())
+}
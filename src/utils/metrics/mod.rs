@@ -1,22 +1,123 @@
-use ndarray::{Array, Ix2};
 use rusqlite::Connection;
+use run::RunInfo;
 use sqlite::{
     sqlite_build_metrics, sqlite_insert_clann_results, sqlite_insert_clann_results_query,
-    sqlite_insert_queries_only,
+    sqlite_insert_cluster_summary, sqlite_insert_queries_only, sqlite_insert_run,
+    sqlite_insert_tuning_trace,
 };
+use std::collections::HashMap;
+use std::ops::AddAssign;
 use std::time::Duration;
 
-use crate::core::{config::{MetricsGranularity, MetricsOutput}, index::ClusterCenter, ClusteredIndexError, Config};
+use crate::core::{config::{MetricsGranularity, MetricsOutput}, index::ClusterCenter, ClusteredIndexError, Config, MemoryUsage, PeakRss};
+use crate::tune::TuningStep;
 
-use super::get_recall_values;
+use super::RecallInput;
+mod csv;
+mod json;
+mod run;
+pub(crate) mod schema;
 mod sqlite;
 
-pub(crate) struct QueryMetrics {
-    pub(crate) distance_computations: usize, // Global distance computations
-    pub(crate) query_time: Duration,
-    pub(crate) cluster_n_candidates: Vec<usize>, // Number of candidates per cluster
-    pub(crate) cluster_timings: Vec<Duration>,   // Timing for each cluster
-    pub(crate) cluster_distance_computations: Vec<usize>, // Distance computations per cluster
+/// Distance computations of a query by what they were spent on. Every distance is counted
+/// once, whether the cluster is indexed or scanned exhaustively.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DistanceComputations {
+    /// Inside the cluster indexes, as counted by the backend, e.g. the candidates PUFFINN
+    /// filters before returning them
+    pub lsh: usize,
+    /// From the query to the candidates: those returned by the cluster indexes, every point
+    /// of the clusters scanned exhaustively, and the final re-ranking and diversification
+    pub rerank: usize,
+    /// From the query to the cluster centers and coarse cells, to order and prune the clusters
+    pub pruning: usize,
+}
+
+impl DistanceComputations {
+    pub fn total(&self) -> usize {
+        self.lsh + self.rerank + self.pruning
+    }
+}
+
+impl AddAssign for DistanceComputations {
+    fn add_assign(&mut self, other: Self) {
+        self.lsh += other.lsh;
+        self.rerank += other.rerank;
+        self.pruning += other.pruning;
+    }
+}
+
+/// Metrics of a single query, passed to the callbacks of [`ClusteredIndex::on_query`](crate::core::ClusteredIndex::on_query).
+///
+/// The per-cluster vectors follow the probe order of the query, so their i-th entries
+/// all refer to the i-th probed cluster.
+#[derive(Debug, Clone)]
+pub struct QueryMetrics {
+    /// Distance computations of the query, centers and re-ranking included
+    pub distance_computations: usize,
+    /// [`distance_computations`](Self::distance_computations) by what they were spent on
+    pub distance_breakdown: DistanceComputations,
+    pub query_time: Duration,
+    /// Candidates added to the top-k by each probed cluster
+    pub cluster_n_candidates: Vec<usize>,
+    pub cluster_timings: Vec<Duration>,
+    pub cluster_distance_computations: Vec<usize>,
+    /// Ids of the probed clusters, the clusters not in it were pruned
+    pub cluster_ids: Vec<usize>,
+    /// Ids of the clusters with at least one point in the final top-k, in increasing order
+    pub topk_clusters: Vec<usize>,
+}
+
+/// Search statistics of a cluster aggregated over the queries of a run
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ClusterRunSummary {
+    pub(crate) cluster_idx: usize,
+    pub(crate) visits: usize,             // queries that probed the cluster
+    pub(crate) prunes: usize,             // queries that skipped it thanks to the radius bound
+    pub(crate) topk_contributions: usize, // queries with at least one of its points in the final top-k
+}
+
+/// Summary of a build, passed to the callbacks of [`ClusteredIndex::on_build`](crate::core::ClusteredIndex::on_build).
+#[derive(Debug, Clone)]
+pub struct BuildMetrics {
+    pub dataset_len: usize,
+    pub num_clusters: usize,
+    /// Clusters scanned exhaustively instead of indexed
+    pub greedy_num_clusters: usize,
+    pub memory_used_bytes: usize,
+    pub build_time: Duration,
+    /// Number of clusters chosen for the clustering, see
+    /// [`Config::cluster_count`](crate::core::Config), before any merge or outlier pool
+    pub selected_clusters: usize,
+    /// Clusters merged into their nearest cluster for being too small, see
+    /// [`Config::tiny_clusters`](crate::core::Config)
+    pub merged_clusters: usize,
+    /// Resident set size of the process during the build, if
+    /// [`Config::track_rss`](crate::core::Config) is set
+    pub peak_rss: Option<PeakRss>,
+}
+
+type Callbacks<M> = Vec<Box<dyn FnMut(&M) + Send + Sync>>;
+
+/// Callbacks registered on an index, called as soon as the metrics of a query or build are complete
+#[derive(Default)]
+pub(crate) struct MetricsCallbacks {
+    pub(crate) on_query: Callbacks<QueryMetrics>,
+    pub(crate) on_build: Callbacks<BuildMetrics>,
+}
+
+impl MetricsCallbacks {
+    pub(crate) fn query(&mut self, metrics: &QueryMetrics) {
+        for callback in &mut self.on_query {
+            callback(metrics);
+        }
+    }
+
+    pub(crate) fn build(&mut self, metrics: &BuildMetrics) {
+        for callback in &mut self.on_build {
+            callback(metrics);
+        }
+    }
 }
 
 pub(crate) struct RunMetrics {
@@ -26,25 +127,42 @@ pub(crate) struct RunMetrics {
     dataset_len: usize,
     total_search_time_s: Duration,
     queries_per_second: f32,
-    recall_mean: f32,
-    recall_std: f32,
+    recall_mean: Option<f32>, // None if the run has no ground truth
+    recall_std: Option<f32>,
 
     // index metrics
     indexing_duration: Duration,
+    selected_clusters: usize,
+    merged_clusters: usize,
+    memory: MemoryUsage,
+    peak_rss: Option<PeakRss>,
+
+    // last saved run
+    last_run: Option<RunInfo>,
 }
 
 impl QueryMetrics {
     pub(crate) fn new() -> Self {
         Self {
             distance_computations: 0,
+            distance_breakdown: DistanceComputations::default(),
             query_time: Duration::default(),
             cluster_n_candidates: Vec::new(),
             cluster_timings: Vec::new(),
             cluster_distance_computations: Vec::new(),
+            cluster_ids: Vec::new(),
+            topk_clusters: Vec::new(),
         }
     }
 }
 
+impl QueryMetrics {
+    /// Number of clusters probed by the query, 0 if it was answered without searching
+    pub fn clusters_probed(&self) -> usize {
+        self.cluster_ids.len()
+    }
+}
+
 impl Default for QueryMetrics {
     fn default() -> Self {
         Self::new()
@@ -58,10 +176,15 @@ impl RunMetrics {
             config,
             total_search_time_s: Duration::ZERO,
             queries_per_second: 0.0,
-            recall_mean: 0.0,
-            recall_std: 0.0,
+            recall_mean: None,
+            recall_std: None,
             dataset_len,
             indexing_duration: Duration::ZERO,
+            selected_clusters: 0,
+            merged_clusters: 0,
+            memory: MemoryUsage::default(),
+            peak_rss: None,
+            last_run: None,
         }
     }
 
@@ -77,10 +200,83 @@ impl RunMetrics {
         self.queries.iter().last()
     }
 
+    /// Forgets the current query, once passed to the callbacks if no output keeps the queries
+    pub(crate) fn drop_current_query(&mut self) {
+        self.queries.pop();
+    }
+
+    /// Id of the last saved run
+    pub(crate) fn last_run_id(&self) -> Option<&str> {
+        self.last_run.as_ref().map(|run| run.run_id.as_str())
+    }
+
     pub(crate) fn log_index_building_time(&mut self, time: Duration) {
         self.indexing_duration = time;
     }
 
+    pub(crate) fn log_clustering(&mut self, selected_clusters: usize, merged_clusters: usize) {
+        self.selected_clusters = selected_clusters;
+        self.merged_clusters = merged_clusters;
+    }
+
+    pub(crate) fn log_memory_usage(&mut self, memory: MemoryUsage) {
+        self.memory = memory;
+    }
+
+    pub(crate) fn log_peak_rss(&mut self, peak_rss: Option<PeakRss>) {
+        self.peak_rss = peak_rss;
+    }
+
+    pub(crate) fn log_probed_cluster(&mut self, cluster_idx: usize) {
+        if let Some(query) = self.current_query_mut() {
+            query.cluster_ids.push(cluster_idx);
+        }
+    }
+
+    pub(crate) fn log_topk_clusters(&mut self, clusters: Vec<usize>) {
+        if let Some(query) = self.current_query_mut() {
+            query.topk_clusters = clusters;
+        }
+    }
+
+    /// Visits, prunes and top-k contributions of each cluster over the searched queries.
+    ///
+    /// Queries answered without probing any cluster (duplicates and cache hits) are left out.
+    pub(crate) fn cluster_summary(&self, clusters: &[ClusterCenter]) -> Vec<ClusterRunSummary> {
+        let mut summary: Vec<ClusterRunSummary> = clusters
+            .iter()
+            .map(|c| ClusterRunSummary {
+                cluster_idx: c.idx,
+                visits: 0,
+                prunes: 0,
+                topk_contributions: 0,
+            })
+            .collect();
+        let position: HashMap<usize, usize> =
+            clusters.iter().enumerate().map(|(i, c)| (c.idx, i)).collect();
+
+        let searched = self.queries.iter().filter(|q| !q.cluster_ids.is_empty());
+        let mut num_searched = 0;
+        for query in searched {
+            num_searched += 1;
+            for id in &query.cluster_ids {
+                if let Some(&i) = position.get(id) {
+                    summary[i].visits += 1;
+                }
+            }
+            for id in &query.topk_clusters {
+                if let Some(&i) = position.get(id) {
+                    summary[i].topk_contributions += 1;
+                }
+            }
+        }
+        for cluster in &mut summary {
+            cluster.prunes = num_searched - cluster.visits;
+        }
+
+        summary
+    }
+
     pub(crate) fn log_n_candidates(&mut self, n_candidates: usize) {
         if let Some(query) = self.current_query_mut() {
             query.cluster_n_candidates.push(n_candidates);
@@ -99,166 +295,162 @@ impl RunMetrics {
         }
     }
 
-    pub(crate) fn add_distance_computation_global(&mut self, n_comp: usize) {
+    pub(crate) fn add_distance_computation_global(&mut self, n_comp: DistanceComputations) {
         if let Some(query) = self.current_query_mut() {
-            query.distance_computations += n_comp;
+            query.distance_computations += n_comp.total();
+            query.distance_breakdown += n_comp;
         }
     }
 
-    pub(crate) fn add_distance_computation_cluster(&mut self, n_comp: usize) {
+    pub(crate) fn add_distance_computation_cluster(&mut self, n_comp: DistanceComputations) {
         if let Some(query) = self.current_query_mut() {
-            query.cluster_distance_computations.push(n_comp);
-            query.distance_computations += n_comp;
+            query.cluster_distance_computations.push(n_comp.total());
+            query.distance_computations += n_comp.total();
+            query.distance_breakdown += n_comp;
         }
     }
 
-    /// Save the results to the specified sqlite database, with the given granularity
+    /// Save the results to the specified sqlite database as a new run, with the given granularity.
+    /// The metrics tables are created or migrated first if needed
     pub(crate) fn save_metrics(
         &mut self,
         connection: &mut Connection,
         granularity: MetricsGranularity,
-        clusters: &Vec<ClusterCenter>,
-        dataset_distances: &Array<f32, Ix2>,
-        run_distances: &[Vec<f32>],
+        clusters: &[ClusterCenter],
+        recall: Option<RecallInput>,
         total_search_time: &Duration,
     ) -> Result<(), ClusteredIndexError> {
-        self.compute_run_statistics(
-            dataset_distances, 
-            run_distances, 
-            total_search_time
-        );
+        self.compute_run_statistics(recall, total_search_time);
+
+        schema::sqlite_migrate(connection).map_err(|e| ClusteredIndexError::ResultDBError(e.to_string()))?;
 
         // Start a transaction to ensure all inserts succeed or none do
         let tx = connection.transaction().map_err(|e| ClusteredIndexError::ResultDBError(e.to_string()))?;
+        let run = RunInfo::new(&self.config.run_tags);
+        self.save_run(&tx, &run, granularity, clusters)
+            .map_err(|e| ClusteredIndexError::ResultDBError(e.to_string()))?;
+        tx.commit().map_err(|e| ClusteredIndexError::ResultDBError(e.to_string()))?;
 
-        // Always insert build and run-level metrics
-        self.save_build_metrics(&tx, clusters)?;
-        self.save_search_metrics(&tx)?;
-
-        // Insert query and cluster metrics based on granularity
-        match granularity {
-            MetricsGranularity::Run => (), // Only run metrics, already inserted
-            MetricsGranularity::Query => {
-                self.save_search_metrics_query(&tx)?;
-            }
-            MetricsGranularity::Cluster => {
-                self.save_search_metrics_cluster(&tx)?;
-            }
-        }
-
-        tx.commit().map_err(|e| ClusteredIndexError::ResultDBError(e.to_string()))
+        self.last_run = Some(run);
+        Ok(())
     }
 
-    fn save_build_metrics(
-        &self,
-        conn: &Connection,
-        clusters: &Vec<ClusterCenter>,
+    /// Save the results to a JSON file at `file_path`, with the given granularity
+    pub(crate) fn save_metrics_json(
+        &mut self,
+        file_path: &str,
+        granularity: MetricsGranularity,
+        clusters: &[ClusterCenter],
+        recall: Option<RecallInput>,
+        total_search_time: &Duration,
     ) -> Result<(), ClusteredIndexError> {
-        let mut num_greedy = 0;
-        let mut memory_used_bytes = 0;
-        for cluster in clusters {
-            if cluster.brute_force {
-                num_greedy += 1;
-            }
+        self.compute_run_statistics(recall, total_search_time);
 
-            memory_used_bytes += cluster.memory_used;
-        }
-
-        match self.config.metrics_output {
-            MetricsOutput::DB => {
-                return sqlite_build_metrics(
-                    conn,
-                    self.config.num_clusters_factor,
-                    self.config.num_tables,
-                    self.config.dataset_name.clone(),
-                    self.dataset_len,
-                    clusters,
-                    num_greedy,
-                    memory_used_bytes,
-                    self.indexing_duration.as_secs(),
-                ).map_err(|e| ClusteredIndexError::ResultDBError(e.to_string()));
-            }
-            MetricsOutput::None => {} // do nothing
-        }
+        let run = RunInfo::new(&self.config.run_tags);
+        json::json_write_metrics(self, &run, file_path, &granularity, clusters)
+            .map_err(|e| ClusteredIndexError::MetricsError(e.to_string()))?;
 
+        self.last_run = Some(run);
         Ok(())
     }
 
-    fn save_search_metrics(&self, conn: &Connection) -> Result<(), ClusteredIndexError> {
-        match self.config.metrics_output {
-            MetricsOutput::DB => {
-                return sqlite_insert_clann_results(
-                    conn,
-                    self.config.num_clusters_factor,
-                    self.config.num_tables,
-                    self.config.k,
-                    self.config.delta,
-                    self.config.dataset_name.clone(),
-                    self.total_search_time_s,
-                    self.queries_per_second,
-                    self.recall_mean,
-                    self.recall_std,
-                ).map_err(|e| ClusteredIndexError::ResultDBError(e.to_string()))
-            }
-            MetricsOutput::None => {} // do nothing
-        }
+    /// Append the results to the CSV files in `dir`, with the given granularity
+    pub(crate) fn save_metrics_csv(
+        &mut self,
+        dir: &str,
+        granularity: MetricsGranularity,
+        clusters: &[ClusterCenter],
+        recall: Option<RecallInput>,
+        total_search_time: &Duration,
+    ) -> Result<(), ClusteredIndexError> {
+        self.compute_run_statistics(recall, total_search_time);
 
+        let run = RunInfo::new(&self.config.run_tags);
+        csv::csv_write_metrics(self, &run, dir, &granularity, clusters)
+            .map_err(|e| ClusteredIndexError::MetricsError(e.to_string()))?;
+
+        self.last_run = Some(run);
         Ok(())
     }
 
-    fn save_search_metrics_query(&self, conn: &Connection) -> Result<(), ClusteredIndexError> {
+    /// Inserts the run and its build and search metrics, reading the `metrics_output` so
+    /// that only a database output writes to `conn`
+    fn save_run(
+        &self,
+        conn: &Connection,
+        run: &RunInfo,
+        granularity: MetricsGranularity,
+        clusters: &[ClusterCenter],
+    ) -> Result<(), rusqlite::Error> {
         match self.config.metrics_output {
-            MetricsOutput::DB => {
-                return sqlite_insert_queries_only(
-                    conn,
-                    &self.queries,
-                    self.config.num_clusters_factor,
-                    self.config.num_tables,
-                    self.config.k,
-                    self.config.delta,
-                    self.config.dataset_name.clone(),
-                ).map_err(|e| ClusteredIndexError::ResultDBError(e.to_string()))
-            }
-            MetricsOutput::None => {} // do nothing
+            MetricsOutput::DB => {}
+            MetricsOutput::Json(_) | MetricsOutput::Csv(_) | MetricsOutput::None => return Ok(()), // not a database
         }
 
-        Ok(())
-    }
+        sqlite_insert_run(conn, run, &self.config)?;
+        sqlite_build_metrics(conn, run, self, clusters)?;
+        sqlite_insert_clann_results(
+            conn,
+            run,
+            &self.config,
+            self.total_search_time_s,
+            self.queries_per_second,
+            self.recall_mean,
+            self.recall_std,
+        )?;
+        sqlite_insert_cluster_summary(conn, run, &self.config, &self.cluster_summary(clusters))?;
 
-    fn save_search_metrics_cluster(&self, conn: &Connection) -> Result<(), ClusteredIndexError> {
-        match self.config.metrics_output {
-            MetricsOutput::DB => {
-                return sqlite_insert_clann_results_query(
-                    conn,
-                    &self.queries,
-                    self.config.num_clusters_factor,
-                    self.config.num_tables,
-                    self.config.k,
-                    self.config.delta,
-                    self.config.dataset_name.clone(),
-                ).map_err(|e| ClusteredIndexError::ResultDBError(e.to_string()))
+        // Insert query and cluster metrics based on granularity
+        match granularity {
+            MetricsGranularity::Run => Ok(()), // Only run metrics, already inserted
+            MetricsGranularity::Query => {
+                sqlite_insert_queries_only(conn, run, &self.config, &self.queries)
+            }
+            MetricsGranularity::Cluster => {
+                sqlite_insert_clann_results_query(conn, run, &self.config, &self.queries)
             }
-            MetricsOutput::None => {} // do nothing
         }
-
-        Ok(())
     }
 
-    fn compute_run_statistics(
-        &mut self,
-        dataset_distances: &Array<f32, Ix2>,
-        run_distances: &[Vec<f32>],
-        total_search_time: &Duration,
-    ) {
+    fn compute_run_statistics(&mut self, recall: Option<RecallInput>, total_search_time: &Duration) {
         // Recall
-        (self.recall_mean, self.recall_std, _) =
-            get_recall_values(dataset_distances, run_distances, self.config.k);
+        let num_queries = match &recall {
+            Some(recall) => {
+                let (mean, std, _) = recall.recall_values(self.config.k);
+                (self.recall_mean, self.recall_std) = (Some(mean), Some(std));
+                recall.num_queries()
+            }
+            None => {
+                (self.recall_mean, self.recall_std) = (None, None);
+                self.queries.len()
+            }
+        };
 
         // Search time
         self.total_search_time_s = *total_search_time;
 
         // QPS
-        self.queries_per_second = (run_distances.len() as f32)
+        self.queries_per_second = (num_queries as f32)
             / (self.total_search_time_s.as_nanos() as f32 / 1_000_000_000.0);
     }
 }
+
+/// Saves the steps of an auto-tuning as a new run, described by the chosen `config`.
+/// Returns the id of the run
+pub(crate) fn save_tuning_trace(
+    connection: &mut Connection,
+    config: &Config,
+    target_recall: f32,
+    trace: &[TuningStep],
+) -> Result<String, ClusteredIndexError> {
+    schema::sqlite_migrate(connection).map_err(|e| ClusteredIndexError::ResultDBError(e.to_string()))?;
+
+    let tx = connection.transaction().map_err(|e| ClusteredIndexError::ResultDBError(e.to_string()))?;
+    let run = RunInfo::new(&config.run_tags);
+    sqlite_insert_run(&tx, &run, config)
+        .and_then(|_| sqlite_insert_tuning_trace(&tx, &run, config, target_recall, trace))
+        .map_err(|e| ClusteredIndexError::ResultDBError(e.to_string()))?;
+    tx.commit().map_err(|e| ClusteredIndexError::ResultDBError(e.to_string()))?;
+
+    Ok(run.run_id)
+}
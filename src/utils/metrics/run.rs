@@ -0,0 +1,80 @@
+use rand::Rng;
+
+/// Identity of a saved run, and the machine it ran on
+#[derive(Debug, Clone)]
+pub(crate) struct RunInfo {
+    pub(crate) run_id: String,
+    pub(crate) tags: Vec<String>,
+    pub(crate) hostname: Option<String>,
+    pub(crate) os: &'static str,
+    pub(crate) arch: &'static str,
+    pub(crate) num_cpus: usize,
+    pub(crate) created_at: String,
+}
+
+impl RunInfo {
+    pub(crate) fn new(tags: &[String]) -> Self {
+        Self {
+            run_id: new_run_id(),
+            tags: tags.to_vec(),
+            hostname: hostname(),
+            os: std::env::consts::OS,
+            arch: std::env::consts::ARCH,
+            num_cpus: std::thread::available_parallelism().map_or(1, |n| n.get()),
+            created_at: chrono::Utc::now().to_rfc3339(),
+        }
+    }
+
+    pub(crate) fn tags_json(&self) -> String {
+        serde_json::to_string(&self.tags).unwrap_or_else(|_| "[]".to_string())
+    }
+}
+
+/// Random (version 4) UUID
+fn new_run_id() -> String {
+    let mut bytes: [u8; 16] = rand::thread_rng().gen();
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+
+    let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
+
+#[cfg(unix)]
+fn hostname() -> Option<String> {
+    let mut buf = [0u8; 256];
+    // SAFETY: the buffer is valid for its whole length, gethostname writes at most that many bytes
+    let ret = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    if ret != 0 {
+        return None;
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8(buf[..len].to_vec()).ok()
+}
+
+#[cfg(not(unix))]
+fn hostname() -> Option<String> {
+    std::env::var("COMPUTERNAME").ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::new_run_id;
+
+    #[test]
+    fn test_run_ids_are_uuids() {
+        let a = new_run_id();
+        let b = new_run_id();
+        assert_ne!(a, b);
+        assert_eq!(a.len(), 36);
+        assert_eq!(&a[14..15], "4");
+        assert!("89ab".contains(&a[19..20]));
+    }
+}
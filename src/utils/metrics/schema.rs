@@ -0,0 +1,312 @@
+use log::{info, warn};
+use rusqlite::{Connection, OptionalExtension};
+
+/// Migrations of the metrics database, applying the i-th brings it to version i + 1.
+///
+/// Only append to this list: a released migration must never change, since databases
+/// that already applied it would not see the difference.
+const MIGRATIONS: &[&str] = &[
+    // 1: initial schema
+    include_str!("../../../result_schema.sql"),
+    // 2: per-cluster build time and search summary
+    "ALTER TABLE build_metrics_cluster ADD COLUMN build_time_ms REAL;
+
+    CREATE TABLE search_metrics_cluster_summary (
+        num_clusters INTEGER NOT NULL,
+        num_tables INTEGER NOT NULL,
+        k INTEGER NOT NULL,
+        delta REAL NOT NULL,
+        dataset TEXT NOT NULL,
+        git_commit_hash CHAR(40) NOT NULL,
+        cluster_idx INTEGER NOT NULL,
+        visits INTEGER NOT NULL,
+        prunes INTEGER NOT NULL,
+        topk_contributions INTEGER NOT NULL,
+        PRIMARY KEY (num_clusters, num_tables, k, delta, dataset, git_commit_hash, cluster_idx),
+        FOREIGN KEY (num_clusters, num_tables, k, delta, dataset, git_commit_hash) REFERENCES search_metrics(num_clusters, num_tables, k, delta, dataset, git_commit_hash) ON DELETE CASCADE
+    );",
+    // 3: runs, referenced by every table so that repetitions of a configuration don't collide
+    RUNS_MIGRATION,
+    // 4: configurations evaluated by tune::auto_tune
+    "CREATE TABLE tuning_trace (
+        run_id TEXT NOT NULL REFERENCES runs(run_id) ON DELETE CASCADE,
+        step INTEGER NOT NULL,
+        round INTEGER NOT NULL,
+        num_clusters REAL NOT NULL,
+        num_tables INTEGER NOT NULL,
+        k INTEGER NOT NULL,
+        delta REAL NOT NULL,
+        dataset TEXT NOT NULL,
+        target_recall REAL NOT NULL,
+        num_queries INTEGER NOT NULL,
+        recall_mean REAL,
+        distance_computations_mean REAL,
+        queries_per_second REAL,
+        promoted INTEGER NOT NULL,
+        PRIMARY KEY (run_id, step)
+    );",
+    // 5: clusters probed by every query
+    "ALTER TABLE search_metrics_query ADD COLUMN clusters_probed INTEGER;",
+    // 6: tiny clusters merged by the build
+    "ALTER TABLE build_metrics ADD COLUMN merged_clusters INTEGER;",
+    // 7: number of clusters chosen by the build
+    "ALTER TABLE build_metrics ADD COLUMN selected_clusters INTEGER;",
+    // 8: memory of the index by component, memory_used_bytes being the cluster indices
+    "ALTER TABLE build_metrics ADD COLUMN dataset_memory_bytes INTEGER;
+    ALTER TABLE build_metrics ADD COLUMN assignments_memory_bytes INTEGER;
+    ALTER TABLE build_metrics ADD COLUMN centers_memory_bytes INTEGER;
+    ALTER TABLE build_metrics ADD COLUMN quantized_memory_bytes INTEGER;
+    ALTER TABLE build_metrics ADD COLUMN duplicates_memory_bytes INTEGER;
+    ALTER TABLE build_metrics ADD COLUMN total_memory_bytes INTEGER;",
+    // 9: resident set size of the process during the build, null unless tracked
+    "ALTER TABLE build_metrics ADD COLUMN start_rss_bytes INTEGER;
+    ALTER TABLE build_metrics ADD COLUMN clustering_peak_rss_bytes INTEGER;
+    ALTER TABLE build_metrics ADD COLUMN indexing_peak_rss_bytes INTEGER;
+    ALTER TABLE build_metrics ADD COLUMN peak_rss_bytes INTEGER;",
+    // 10: threads the run was allowed to use
+    "ALTER TABLE runs ADD COLUMN num_threads INTEGER;",
+    // 11: distance computations of every query by what they were spent on
+    "ALTER TABLE search_metrics_query ADD COLUMN lsh_distance_computations INTEGER;
+    ALTER TABLE search_metrics_query ADD COLUMN rerank_distance_computations INTEGER;
+    ALTER TABLE search_metrics_query ADD COLUMN pruning_distance_computations INTEGER;",
+];
+
+/// Adds the `runs` table and rebuilds every table with a `run_id` leading its primary key.
+/// Rows saved before belong to a single `legacy` run
+const RUNS_MIGRATION: &str = "
+CREATE TABLE runs (
+    run_id TEXT PRIMARY KEY,
+    num_clusters REAL,
+    num_tables INTEGER,
+    k INTEGER,
+    delta REAL,
+    dataset TEXT,
+    git_commit_hash CHAR(40) DEFAULT 'NO_COMMIT' NOT NULL,
+    tags TEXT DEFAULT '[]' NOT NULL, -- JSON array
+    hostname TEXT,
+    os TEXT,
+    arch TEXT,
+    num_cpus INTEGER,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+);
+
+INSERT INTO runs (run_id)
+    SELECT 'legacy' WHERE EXISTS (SELECT 1 FROM build_metrics) OR EXISTS (SELECT 1 FROM search_metrics);
+
+CREATE TABLE build_metrics_v3 (
+    run_id TEXT NOT NULL REFERENCES runs(run_id) ON DELETE CASCADE,
+    num_clusters INTEGER NOT NULL,
+    num_tables INTEGER NOT NULL,
+    dataset TEXT NOT NULL,
+    git_commit_hash CHAR(40) DEFAULT 'NO_COMMIT' NOT NULL,
+    dataset_len INTEGER,
+    total_num_clusters INTEGER NOT NULL DEFAULT 0,
+    greedy_num_clusters INTEGER NOT NULL DEFAULT 0,
+    memory_used_bytes INTEGER,
+    build_time_s INTEGER,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (run_id, num_clusters, num_tables, dataset, git_commit_hash),
+    CONSTRAINT positive_clusters CHECK (num_clusters > 0),
+    CONSTRAINT positive_L CHECK (num_tables > 0)
+);
+INSERT INTO build_metrics_v3 SELECT 'legacy', * FROM build_metrics;
+
+CREATE TABLE build_metrics_cluster_v3 (
+    run_id TEXT NOT NULL REFERENCES runs(run_id) ON DELETE CASCADE,
+    num_clusters INTEGER NOT NULL,
+    num_tables INTEGER NOT NULL,
+    dataset TEXT NOT NULL,
+    git_commit_hash CHAR(40) DEFAULT 'NO_COMMIT' NOT NULL,
+    cluster_idx INTEGER NOT NULL,
+    center_idx INTEGER,
+    greedy_flag INTEGER,
+    radius REAL,
+    num_points INTEGER,
+    memory_used_bytes INTEGER,
+    build_time_ms REAL,
+    PRIMARY KEY (run_id, num_clusters, num_tables, dataset, git_commit_hash, cluster_idx)
+);
+INSERT INTO build_metrics_cluster_v3 SELECT 'legacy', * FROM build_metrics_cluster;
+
+CREATE TABLE search_metrics_v3 (
+    run_id TEXT NOT NULL REFERENCES runs(run_id) ON DELETE CASCADE,
+    num_clusters INTEGER NOT NULL,
+    num_tables INTEGER NOT NULL,
+    k INTEGER NOT NULL,
+    delta REAL NOT NULL,
+    dataset TEXT NOT NULL,
+    git_commit_hash CHAR(40) DEFAULT 'NO_COMMIT' NOT NULL,
+    search_time_ms INTEGER,
+    queries_per_second REAL,
+    recall_mean REAL,
+    recall_std REAL,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (run_id, num_clusters, num_tables, k, delta, dataset, git_commit_hash),
+    CONSTRAINT valid_recall CHECK (recall_mean >= 0 AND recall_mean <= 1),
+    CONSTRAINT valid_recall_std CHECK (recall_std >= 0),
+    CONSTRAINT positive_clusters CHECK (num_clusters > 0),
+    CONSTRAINT positive_k CHECK (k > 0),
+    CONSTRAINT positive_L CHECK (num_tables > 0)
+);
+INSERT INTO search_metrics_v3 SELECT 'legacy', * FROM search_metrics;
+
+CREATE TABLE search_metrics_cluster_summary_v3 (
+    run_id TEXT NOT NULL REFERENCES runs(run_id) ON DELETE CASCADE,
+    num_clusters INTEGER NOT NULL,
+    num_tables INTEGER NOT NULL,
+    k INTEGER NOT NULL,
+    delta REAL NOT NULL,
+    dataset TEXT NOT NULL,
+    git_commit_hash CHAR(40) NOT NULL,
+    cluster_idx INTEGER NOT NULL,
+    visits INTEGER NOT NULL,
+    prunes INTEGER NOT NULL,
+    topk_contributions INTEGER NOT NULL,
+    PRIMARY KEY (run_id, num_clusters, num_tables, k, delta, dataset, git_commit_hash, cluster_idx)
+);
+INSERT INTO search_metrics_cluster_summary_v3 SELECT 'legacy', * FROM search_metrics_cluster_summary;
+
+CREATE TABLE search_metrics_query_v3 (
+    run_id TEXT NOT NULL REFERENCES runs(run_id) ON DELETE CASCADE,
+    num_clusters INTEGER NOT NULL,
+    num_tables INTEGER NOT NULL,
+    k INTEGER NOT NULL,
+    delta REAL NOT NULL,
+    dataset TEXT NOT NULL,
+    git_commit_hash CHAR(40) NOT NULL,
+    query_idx INTEGER NOT NULL,
+    query_time_ms INTEGER,
+    distance_computations INTEGER,
+    PRIMARY KEY (run_id, num_clusters, num_tables, k, delta, dataset, git_commit_hash, query_idx),
+    CONSTRAINT positive_time CHECK (query_time_ms >= 0),
+    CONSTRAINT positive_computations CHECK (distance_computations >= 0)
+);
+INSERT INTO search_metrics_query_v3 SELECT 'legacy', * FROM search_metrics_query;
+
+CREATE TABLE search_metrics_cluster_v3 (
+    run_id TEXT NOT NULL REFERENCES runs(run_id) ON DELETE CASCADE,
+    num_clusters INTEGER NOT NULL,
+    num_tables INTEGER NOT NULL,
+    k INTEGER NOT NULL,
+    delta REAL NOT NULL,
+    dataset TEXT NOT NULL,
+    git_commit_hash CHAR(40) NOT NULL,
+    query_idx INTEGER NOT NULL,
+    cluster_idx INTEGER NOT NULL,
+    n_candidates INTEGER,
+    cluster_time_ms INTEGER,
+    cluster_distance_computations INTEGER,
+    PRIMARY KEY (run_id, num_clusters, num_tables, k, delta, dataset, git_commit_hash, query_idx, cluster_idx),
+    CONSTRAINT positive_candidates CHECK (n_candidates >= 0),
+    CONSTRAINT positive_cluster_time CHECK (cluster_time_ms >= 0),
+    CONSTRAINT positive_cluster_computations CHECK (cluster_distance_computations >= 0)
+);
+INSERT INTO search_metrics_cluster_v3 SELECT 'legacy', * FROM search_metrics_cluster;
+
+DROP TABLE search_metrics_cluster;
+DROP TABLE search_metrics_query;
+DROP TABLE search_metrics_cluster_summary;
+DROP TABLE search_metrics;
+DROP TABLE build_metrics_cluster;
+DROP TABLE build_metrics;
+
+ALTER TABLE build_metrics_v3 RENAME TO build_metrics;
+ALTER TABLE build_metrics_cluster_v3 RENAME TO build_metrics_cluster;
+ALTER TABLE search_metrics_v3 RENAME TO search_metrics;
+ALTER TABLE search_metrics_cluster_summary_v3 RENAME TO search_metrics_cluster_summary;
+ALTER TABLE search_metrics_query_v3 RENAME TO search_metrics_query;
+ALTER TABLE search_metrics_cluster_v3 RENAME TO search_metrics_cluster;
+";
+
+/// Version of the schema written by this crate
+pub(crate) const SCHEMA_VERSION: usize = MIGRATIONS.len();
+
+/// Creates the metrics tables on a new database and applies the missing migrations to an existing one
+pub(crate) fn sqlite_migrate(conn: &mut Connection) -> Result<(), rusqlite::Error> {
+    let tx = conn.transaction()?;
+
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS schema_version (version INTEGER NOT NULL)",
+        [],
+    )?;
+    let version: Option<usize> = tx
+        .query_row("SELECT version FROM schema_version", [], |row| row.get(0))
+        .optional()?;
+
+    let version = match version {
+        Some(version) => version,
+        None => {
+            // databases created from result_schema.sql before it was versioned
+            let legacy: bool = tx.query_row(
+                "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'build_metrics')",
+                [],
+                |row| row.get(0),
+            )?;
+            let version = if legacy { 1 } else { 0 };
+            tx.execute("INSERT INTO schema_version (version) VALUES (?1)", [version])?;
+            version
+        }
+    };
+
+    if version > SCHEMA_VERSION {
+        warn!(
+            "Metrics database has schema version {}, newer than {}, some columns won't be filled",
+            version, SCHEMA_VERSION
+        );
+        return tx.commit();
+    }
+
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        info!("Migrating metrics database to schema version {}", i + 1);
+        tx.execute_batch(migration)?;
+    }
+    tx.execute("UPDATE schema_version SET version = ?1", [SCHEMA_VERSION])?;
+
+    tx.commit()
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::Connection;
+
+    use super::{sqlite_migrate, SCHEMA_VERSION};
+
+    fn version(conn: &Connection) -> usize {
+        conn.query_row("SELECT version FROM schema_version", [], |row| row.get(0))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_migrate_new_and_legacy_databases() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        sqlite_migrate(&mut conn).unwrap();
+        assert_eq!(version(&conn), SCHEMA_VERSION);
+        // applying again is a no-op
+        sqlite_migrate(&mut conn).unwrap();
+        assert_eq!(version(&conn), SCHEMA_VERSION);
+
+        // tables created by hand from result_schema.sql are kept
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(include_str!("../../../result_schema.sql"))
+            .unwrap();
+        conn.execute(
+            "INSERT INTO build_metrics (num_clusters, num_tables, dataset) VALUES (1, 1, 'test')",
+            [],
+        )
+        .unwrap();
+        sqlite_migrate(&mut conn).unwrap();
+        assert_eq!(version(&conn), SCHEMA_VERSION);
+        let rows: usize = conn
+            .query_row("SELECT COUNT(*) FROM build_metrics", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(rows, 1);
+        let run_id: String = conn
+            .query_row("SELECT run_id FROM build_metrics", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(run_id, "legacy");
+        let runs: usize = conn
+            .query_row("SELECT COUNT(*) FROM runs", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(runs, 1);
+    }
+}
@@ -1,27 +1,68 @@
 use std::time::Duration;
 
-use log::warn;
 use rusqlite::{params, Connection};
 
-use crate::core::index::ClusterCenter;
+use crate::core::{index::ClusterCenter, Config};
+use crate::tune::TuningStep;
 
-use super::QueryMetrics;
+use super::run::RunInfo;
+use super::{ClusterRunSummary, QueryMetrics, RunMetrics};
+
+pub(crate) fn sqlite_insert_run(
+    conn: &Connection,
+    run: &RunInfo,
+    config: &Config,
+) -> Result<(), rusqlite::Error> {
+    conn.execute(
+        "INSERT INTO runs (
+            run_id,
+            num_clusters,
+            num_tables,
+            k,
+            delta,
+            dataset,
+            git_commit_hash,
+            tags,
+            hostname,
+            os,
+            arch,
+            num_cpus,
+            created_at,
+            num_threads
+        ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14)",
+        params![
+            run.run_id,
+            config.num_clusters_factor,
+            config.num_tables,
+            config.k,
+            config.delta,
+            config.dataset_name,
+            option_env!("GIT_COMMIT_HASH").unwrap_or("NO_COMMIT"),
+            run.tags_json(),
+            run.hostname,
+            run.os,
+            run.arch,
+            run.num_cpus,
+            run.created_at,
+            config.threads(),
+        ],
+    )?;
+
+    Ok(())
+}
 
 pub(crate) fn sqlite_build_metrics(
     conn: &Connection,
-    num_clusters_factor: f32,
-    num_tables: usize,
-    dataset_name: String,
-    dataset_len: usize,
-    clusters: &Vec<ClusterCenter>,
-    num_greedy: usize,
-    memory_used_bytes: usize,
-    build_times_s: u64,
+    run: &RunInfo,
+    metrics: &RunMetrics,
+    clusters: &[ClusterCenter],
 ) -> Result<(), rusqlite::Error> {
-    let current_time = chrono::Utc::now().to_rfc3339();
+    let config = &metrics.config;
+    let git_hash = option_env!("GIT_COMMIT_HASH").unwrap_or("NO_COMMIT");
 
-    match conn.execute(
+    conn.execute(
         "INSERT INTO build_metrics (
+            run_id,
             num_clusters,
             num_tables,
             dataset,
@@ -31,38 +72,51 @@ pub(crate) fn sqlite_build_metrics(
             greedy_num_clusters,
             memory_used_bytes,
             build_time_s,
-            created_at
-        ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+            created_at,
+            merged_clusters,
+            selected_clusters,
+            dataset_memory_bytes,
+            assignments_memory_bytes,
+            centers_memory_bytes,
+            quantized_memory_bytes,
+            duplicates_memory_bytes,
+            total_memory_bytes,
+            start_rss_bytes,
+            clustering_peak_rss_bytes,
+            indexing_peak_rss_bytes,
+            peak_rss_bytes
+        ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19, ?20, ?21, ?22, ?23)",
         params![
-            num_clusters_factor,
-            num_tables,
-            dataset_name,
-            option_env!("GIT_COMMIT_HASH").unwrap_or("NO_COMMIT"),
-            dataset_len,
+            run.run_id,
+            config.num_clusters_factor,
+            config.num_tables,
+            config.dataset_name,
+            git_hash,
+            metrics.dataset_len,
             clusters.len(),
-            num_greedy,
-            memory_used_bytes,
-            build_times_s,
-            current_time
+            clusters.iter().filter(|c| c.brute_force).count(),
+            clusters.iter().map(|c| c.memory_used).sum::<usize>(),
+            metrics.indexing_duration.as_secs_f64(),
+            run.created_at,
+            metrics.merged_clusters,
+            metrics.selected_clusters,
+            metrics.memory.dataset,
+            metrics.memory.assignments,
+            metrics.memory.centers,
+            metrics.memory.quantized,
+            metrics.memory.duplicates,
+            metrics.memory.total(),
+            metrics.peak_rss.map(|rss| rss.start),
+            metrics.peak_rss.map(|rss| rss.clustering),
+            metrics.peak_rss.map(|rss| rss.indexing),
+            metrics.peak_rss.map(|rss| rss.peak())
         ],
-    ) {
-        Ok(_) => {},
-        Err(e) => {
-            if let rusqlite::Error::SqliteFailure(error, Some(message)) = &e {
-                if error.code == rusqlite::ErrorCode::ConstraintViolation
-                    && message.contains("UNIQUE constraint failed")
-                {
-                    warn!("Build metrics for this index already exist");
-                    return Ok(());
-                }
-            }
-            return Err(e);
-        }
-    };
+    )?;
 
     for cluster in clusters {
-        match conn.execute(
+        conn.execute(
             "INSERT INTO build_metrics_cluster (
+                run_id,
                 num_clusters,
                 num_tables,
                 dataset,
@@ -72,34 +126,24 @@ pub(crate) fn sqlite_build_metrics(
                 greedy_flag,
                 radius,
                 num_points,
-                memory_used_bytes
-            ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+                memory_used_bytes,
+                build_time_ms
+            ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
             params![
-                num_clusters_factor,
-                num_tables,
-                dataset_name,
-                option_env!("GIT_COMMIT_HASH").unwrap_or("NO_COMMIT"),
+                run.run_id,
+                config.num_clusters_factor,
+                config.num_tables,
+                config.dataset_name,
+                git_hash,
                 cluster.idx,
                 cluster.center_idx,
                 if cluster.brute_force { 1 } else { 0 },
                 cluster.radius,
                 cluster.assignment.len(),
                 cluster.memory_used,
+                cluster.build_time.as_secs_f64() * 1000.0,
             ],
-        ) {
-            Ok(_) => {},
-            Err(e) => {
-                if let rusqlite::Error::SqliteFailure(error, Some(message)) = &e {
-                    if error.code == rusqlite::ErrorCode::ConstraintViolation
-                        && message.contains("UNIQUE constraint failed")
-                    {
-                        warn!("Build metrics for this index already exist");
-                        return Ok(());
-                    }
-                }
-                return Err(e);
-            }
-        };
+        )?;
     }
 
     Ok(())
@@ -107,20 +151,16 @@ pub(crate) fn sqlite_build_metrics(
 
 pub(crate) fn sqlite_insert_clann_results(
     conn: &Connection,
-    num_clusters_factor: f32,
-    num_tables: usize,
-    k: usize,
-    delta: f32,
-    dataset_name: String,
+    run: &RunInfo,
+    config: &Config,
     total_search_time_s: Duration,
     queries_per_second: f32,
-    recall_mean: f32,
-    recall_std: f32
+    recall_mean: Option<f32>,
+    recall_std: Option<f32>
 ) -> Result<(), rusqlite::Error> {
-    let current_time = chrono::Utc::now().to_rfc3339();
-
-    match conn.execute(
+    conn.execute(
         "INSERT INTO search_metrics (
+            run_id,
             num_clusters,
             num_tables,
             k,
@@ -132,72 +172,109 @@ pub(crate) fn sqlite_insert_clann_results(
             recall_mean,
             recall_std,
             created_at
-        ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+        ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
         params![
-            num_clusters_factor,
-            num_tables,
-            k,
-            delta,
-            dataset_name,
+            run.run_id,
+            config.num_clusters_factor,
+            config.num_tables,
+            config.k,
+            config.delta,
+            config.dataset_name,
             option_env!("GIT_COMMIT_HASH").unwrap_or("NO_COMMIT"),
-            total_search_time_s.as_secs_f32(),
+            total_search_time_s.as_secs_f64() * 1000.0,
             queries_per_second,
             recall_mean,
             recall_std,
-            current_time
+            run.created_at
         ],
-    ) {
-        Ok(_) => Ok(()),
-        Err(e) => {
-            if let rusqlite::Error::SqliteFailure(error, Some(message)) = &e {
-                if error.code == rusqlite::ErrorCode::ConstraintViolation
-                    && message.contains("UNIQUE constraint failed")
-                {
-                    warn!("Metrics not saved, results with this configuration already exist");
-                    return Ok(());
-                }
-            }
-            Err(e)
-        }
-    }
+    )?;
+
+    Ok(())
 }
 
-pub(crate) fn sqlite_insert_queries_only(
+pub(crate) fn sqlite_insert_cluster_summary(
     conn: &Connection,
-    queries: &[QueryMetrics],
-    num_clusters_factor: f32,
-    num_tables: usize,
-    k: usize,
-    delta: f32,
-    dataset_name: String,
+    run: &RunInfo,
+    config: &Config,
+    summary: &[ClusterRunSummary],
 ) -> Result<(), rusqlite::Error> {
 
     let git_hash = option_env!("GIT_COMMIT_HASH").unwrap_or("NO_COMMIT");
 
-    // Insert only query-level metrics
-    for (query_idx, query) in queries.iter().enumerate() {
+    for cluster in summary {
         conn.execute(
-            "INSERT INTO search_metrics_query (
+            "INSERT INTO search_metrics_cluster_summary (
+                run_id,
                 num_clusters,
                 num_tables,
                 k,
                 delta,
                 dataset,
                 git_commit_hash,
-                query_idx,
-                query_time_ms,
-                distance_computations
-            ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                cluster_idx,
+                visits,
+                prunes,
+                topk_contributions
+            ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
             params![
-                num_clusters_factor,
+                run.run_id,
+                config.num_clusters_factor,
+                config.num_tables,
+                config.k,
+                config.delta,
+                config.dataset_name,
+                git_hash,
+                cluster.cluster_idx as i64,
+                cluster.visits as i64,
+                cluster.prunes as i64,
+                cluster.topk_contributions as i64,
+            ],
+        )?;
+    }
+
+    Ok(())
+}
+
+pub(crate) fn sqlite_insert_tuning_trace(
+    conn: &Connection,
+    run: &RunInfo,
+    config: &Config,
+    target_recall: f32,
+    trace: &[TuningStep],
+) -> Result<(), rusqlite::Error> {
+    for (step_idx, step) in trace.iter().enumerate() {
+        conn.execute(
+            "INSERT INTO tuning_trace (
+                run_id,
+                step,
+                round,
+                num_clusters,
                 num_tables,
                 k,
                 delta,
-                dataset_name,
-                git_hash,
-                query_idx as i64,
-                query.query_time.as_millis() as i64,
-                query.distance_computations as i64,
+                dataset,
+                target_recall,
+                num_queries,
+                recall_mean,
+                distance_computations_mean,
+                queries_per_second,
+                promoted
+            ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14)",
+            params![
+                run.run_id,
+                step_idx as i64,
+                step.round as i64,
+                step.num_clusters_factor,
+                step.num_tables as i64,
+                config.k as i64,
+                step.delta,
+                config.dataset_name,
+                target_recall,
+                step.num_queries as i64,
+                step.recall_mean,
+                step.distance_computations_mean,
+                step.queries_per_second,
+                step.promoted,
             ],
         )?;
     }
@@ -205,22 +282,20 @@ pub(crate) fn sqlite_insert_queries_only(
     Ok(())
 }
 
-pub(crate) fn sqlite_insert_clann_results_query(
+pub(crate) fn sqlite_insert_queries_only(
     conn: &Connection,
+    run: &RunInfo,
+    config: &Config,
     queries: &[QueryMetrics],
-    num_clusters_factor: f32,
-    num_tables: usize,
-    k: usize,
-    delta: f32,
-    dataset_name: String,
 ) -> Result<(), rusqlite::Error> {
 
     let git_hash = option_env!("GIT_COMMIT_HASH").unwrap_or("NO_COMMIT");
 
-    // Insert query-level metrics
+    // Insert only query-level metrics
     for (query_idx, query) in queries.iter().enumerate() {
         conn.execute(
             "INSERT INTO search_metrics_query (
+                run_id,
                 num_clusters,
                 num_tables,
                 k,
@@ -229,22 +304,48 @@ pub(crate) fn sqlite_insert_clann_results_query(
                 git_commit_hash,
                 query_idx,
                 query_time_ms,
-                distance_computations
-            ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                distance_computations,
+                clusters_probed,
+                lsh_distance_computations,
+                rerank_distance_computations,
+                pruning_distance_computations
+            ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14)",
             params![
-                num_clusters_factor,
-                num_tables,
-                k,
-                delta,
-                dataset_name,
+                run.run_id,
+                config.num_clusters_factor,
+                config.num_tables,
+                config.k,
+                config.delta,
+                config.dataset_name,
                 git_hash,
                 query_idx as i64,
-                query.query_time.as_millis() as i64,
+                query.query_time.as_secs_f64() * 1000.0,
                 query.distance_computations as i64,
+                query.clusters_probed() as i64,
+                query.distance_breakdown.lsh as i64,
+                query.distance_breakdown.rerank as i64,
+                query.distance_breakdown.pruning as i64,
             ],
         )?;
-        
-        // Insert cluster-level metrics for each query
+    }
+
+    Ok(())
+}
+
+pub(crate) fn sqlite_insert_clann_results_query(
+    conn: &Connection,
+    run: &RunInfo,
+    config: &Config,
+    queries: &[QueryMetrics],
+) -> Result<(), rusqlite::Error> {
+
+    let git_hash = option_env!("GIT_COMMIT_HASH").unwrap_or("NO_COMMIT");
+
+    // Insert query-level metrics
+    sqlite_insert_queries_only(conn, run, config, queries)?;
+
+    // Insert cluster-level metrics for each query
+    for (query_idx, query) in queries.iter().enumerate() {
         for (cluster_idx, ((n_candidates, timing), distance_comp)) in query
             .cluster_n_candidates
             .iter()
@@ -254,6 +355,7 @@ pub(crate) fn sqlite_insert_clann_results_query(
         {
             conn.execute(
                 "INSERT INTO search_metrics_cluster (
+                    run_id,
                     num_clusters,
                     num_tables,
                     k,
@@ -265,18 +367,19 @@ pub(crate) fn sqlite_insert_clann_results_query(
                     n_candidates,
                     cluster_time_ms,
                     cluster_distance_computations
-                ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+                ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
                 params![
-                    num_clusters_factor,
-                    num_tables,
-                    k,
-                    delta,
-                    dataset_name,
+                    run.run_id,
+                    config.num_clusters_factor,
+                    config.num_tables,
+                    config.k,
+                    config.delta,
+                    config.dataset_name,
                     git_hash,
                     query_idx as i64,
                     cluster_idx as i64,
                     *n_candidates as i64,
-                    timing.as_micros() as i64,
+                    timing.as_secs_f64() * 1000.0,
                     *distance_comp as i64,
                 ],
             )?;
@@ -284,4 +387,264 @@ pub(crate) fn sqlite_insert_clann_results_query(
     }
 
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use ndarray::Array2;
+    use rusqlite::Connection;
+
+    use crate::core::config::{MetricsGranularity, MetricsOutput};
+    use crate::core::index::ClusterCenter;
+    use crate::core::{Config, MemoryUsage, PeakRss};
+    use crate::utils::metrics::{DistanceComputations, QueryMetrics, RunMetrics};
+    use crate::utils::RecallInput;
+
+    fn run_metrics() -> (RunMetrics, Vec<ClusterCenter>) {
+        let config = Config {
+            num_clusters_factor: 0.5,
+            k: 2,
+            dataset_name: "test".to_string(),
+            metrics_output: MetricsOutput::DB,
+            num_threads: Some(3),
+            ..Default::default()
+        };
+        let mut metrics = RunMetrics::new(config, 100);
+        metrics.log_index_building_time(Duration::from_millis(1500));
+        metrics.log_clustering(12, 3);
+        metrics.log_memory_usage(MemoryUsage {
+            dataset: 4000,
+            cluster_indexes: 1024,
+            assignments: 800,
+            ..Default::default()
+        });
+        metrics.log_peak_rss(Some(PeakRss {
+            start: 100,
+            clustering: 300,
+            indexing: 200,
+        }));
+        for _ in 0..3 {
+            metrics.queries.push(QueryMetrics {
+                distance_computations: 30,
+                distance_breakdown: DistanceComputations {
+                    lsh: 12,
+                    rerank: 15,
+                    pruning: 3,
+                },
+                query_time: Duration::from_millis(4),
+                cluster_n_candidates: vec![2, 1],
+                cluster_timings: vec![Duration::from_micros(1500), Duration::from_micros(500)],
+                cluster_distance_computations: vec![20, 10],
+                cluster_ids: vec![0, 1],
+                topk_clusters: vec![1],
+            });
+        }
+
+        let clusters = (0..2)
+            .map(|idx| ClusterCenter {
+                idx,
+                center_idx: idx * 10,
+                radius: 0.5,
+                assignment: (0..50).collect(),
+                brute_force: idx == 0,
+                memory_used: 1024,
+                num_tables: None,
+                build_time: Duration::from_millis(3),
+                search_stats: Default::default(),
+                member_distances: None,
+                outlier: false,
+                over_memory_ceiling: false,
+                outlier_threshold: None,
+            })
+            .collect();
+
+        (metrics, clusters)
+    }
+
+    fn count(conn: &Connection, table: &str) -> usize {
+        conn.query_row(&format!("SELECT COUNT(*) FROM {}", table), [], |row| row.get(0))
+            .unwrap()
+    }
+
+    fn save(conn: &mut Connection, granularity: MetricsGranularity) {
+        let (mut metrics, clusters) = run_metrics();
+        let ground_truth = Array2::from_elem((3, 2), 1usize);
+        let run = vec![vec![1, 2]; 3];
+        let recall = RecallInput::Ids {
+            ground_truth: &ground_truth,
+            run: &run,
+        };
+        metrics
+            .save_metrics(conn, granularity, &clusters, Some(recall), &Duration::from_millis(12))
+            .unwrap();
+    }
+
+    #[test]
+    fn test_save_run_granularity() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        save(&mut conn, MetricsGranularity::Run);
+
+        assert_eq!(count(&conn, "build_metrics"), 1);
+        assert_eq!(count(&conn, "build_metrics_cluster"), 2);
+        assert_eq!(count(&conn, "search_metrics"), 1);
+        assert_eq!(count(&conn, "search_metrics_query"), 0);
+        assert_eq!(count(&conn, "search_metrics_cluster"), 0);
+
+        let (greedy, build_time, selected, merged): (usize, f64, usize, usize) = conn
+            .query_row(
+                "SELECT greedy_num_clusters, build_time_s, selected_clusters, merged_clusters FROM build_metrics",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+            )
+            .unwrap();
+        assert_eq!(greedy, 1);
+        assert_eq!(build_time, 1.5);
+        assert_eq!(selected, 12);
+        assert_eq!(merged, 3);
+
+        let (dataset, total): (usize, usize) = conn
+            .query_row("SELECT dataset_memory_bytes, total_memory_bytes FROM build_metrics", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .unwrap();
+        assert_eq!(dataset, 4000);
+        assert_eq!(total, 5824);
+
+        let peak_rss: usize = conn
+            .query_row("SELECT peak_rss_bytes FROM build_metrics", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(peak_rss, 300);
+
+        let num_threads: usize = conn
+            .query_row("SELECT num_threads FROM runs", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(num_threads, 3);
+
+        let (search_time, recall): (f64, f64) = conn
+            .query_row("SELECT search_time_ms, recall_mean FROM search_metrics", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .unwrap();
+        assert_eq!(search_time, 12.0);
+        assert_eq!(recall, 0.5);
+    }
+
+    #[test]
+    fn test_save_query_granularity() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        save(&mut conn, MetricsGranularity::Query);
+
+        assert_eq!(count(&conn, "search_metrics"), 1);
+        assert_eq!(count(&conn, "search_metrics_query"), 3);
+        assert_eq!(count(&conn, "search_metrics_cluster"), 0);
+
+        let (time, computations, probed): (f64, usize, usize) = conn
+            .query_row(
+                "SELECT query_time_ms, distance_computations, clusters_probed FROM search_metrics_query WHERE query_idx = 2",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .unwrap();
+        assert_eq!(time, 4.0);
+        assert_eq!(computations, 30);
+        assert_eq!(probed, 2);
+
+        let (lsh, rerank, pruning): (usize, usize, usize) = conn
+            .query_row(
+                "SELECT lsh_distance_computations, rerank_distance_computations, pruning_distance_computations FROM search_metrics_query WHERE query_idx = 2",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .unwrap();
+        assert_eq!((lsh, rerank, pruning), (12, 15, 3));
+    }
+
+    #[test]
+    fn test_save_cluster_granularity() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        save(&mut conn, MetricsGranularity::Cluster);
+
+        assert_eq!(count(&conn, "search_metrics"), 1);
+        assert_eq!(count(&conn, "search_metrics_query"), 3);
+        assert_eq!(count(&conn, "search_metrics_cluster"), 6);
+
+        let time: f64 = conn
+            .query_row(
+                "SELECT cluster_time_ms FROM search_metrics_cluster WHERE query_idx = 0 AND cluster_idx = 0",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(time, 1.5);
+    }
+
+    #[test]
+    fn test_save_without_ground_truth() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        let (mut metrics, clusters) = run_metrics();
+        metrics
+            .save_metrics(&mut conn, MetricsGranularity::Query, &clusters, None, &Duration::from_millis(12))
+            .unwrap();
+
+        assert_eq!(count(&conn, "search_metrics_query"), 3);
+        let (recall, qps): (Option<f64>, f64) = conn
+            .query_row("SELECT recall_mean, queries_per_second FROM search_metrics", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .unwrap();
+        assert_eq!(recall, None);
+        assert_eq!(qps, 250.0);
+    }
+
+    #[test]
+    fn test_cluster_summary() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        save(&mut conn, MetricsGranularity::Run);
+
+        assert_eq!(count(&conn, "search_metrics_cluster_summary"), 2);
+        let (visits, prunes, contributions): (usize, usize, usize) = conn
+            .query_row(
+                "SELECT visits, prunes, topk_contributions FROM search_metrics_cluster_summary WHERE cluster_idx = 1",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .unwrap();
+        assert_eq!((visits, prunes, contributions), (3, 0, 3));
+        let build_time: f64 = conn
+            .query_row("SELECT build_time_ms FROM build_metrics_cluster WHERE cluster_idx = 0", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(build_time, 3.0);
+
+        // the last query stops before the second cluster, a cache hit probes nothing
+        let (mut metrics, clusters) = run_metrics();
+        metrics.queries[2].cluster_ids = vec![0];
+        metrics.queries[2].topk_clusters = vec![0];
+        metrics.new_query();
+        let summary = metrics.cluster_summary(&clusters);
+        assert_eq!((summary[0].visits, summary[0].prunes, summary[0].topk_contributions), (3, 0, 1));
+        assert_eq!((summary[1].visits, summary[1].prunes, summary[1].topk_contributions), (2, 1, 2));
+    }
+
+    #[test]
+    fn test_save_same_run_twice() {
+        // repetitions of a configuration are separate runs
+        let mut conn = Connection::open_in_memory().unwrap();
+        save(&mut conn, MetricsGranularity::Cluster);
+        save(&mut conn, MetricsGranularity::Cluster);
+
+        assert_eq!(count(&conn, "runs"), 2);
+        assert_eq!(count(&conn, "build_metrics"), 2);
+        assert_eq!(count(&conn, "search_metrics"), 2);
+        assert_eq!(count(&conn, "search_metrics_query"), 6);
+        assert_eq!(count(&conn, "search_metrics_cluster"), 12);
+
+        let runs: usize = conn
+            .query_row("SELECT COUNT(DISTINCT run_id) FROM search_metrics_query", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(runs, 2);
+    }
+}
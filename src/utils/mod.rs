@@ -1,25 +1,28 @@
 use std::cmp::Ordering;
-use std::fs;
+use std::collections::HashSet;
 
 use hdf5::File;
 use log::debug;
 use ndarray::{Array, Ix1, Ix2};
-use ndarray::{Array2, Axis};
 
+pub mod datagen;
 pub(crate) mod metrics;
 
-use rand::thread_rng;
 use rand::Rng;
 
 use crate::metricdata::{MetricData, Subset};
 use crate::puffinn_binds::IndexableSimilarity;
 
-pub(crate) use metrics::RunMetrics;
+pub(crate) use metrics::{MetricsCallbacks, RunMetrics};
+pub use datagen::generate_random_unit_vectors;
+pub use metrics::{BuildMetrics, DistanceComputations, QueryMetrics};
 
 pub struct Hdf5Dataset {
     pub dataset_array: Array<f32, Ix2>,
     pub dataset_queries: Array<f32, Ix2>,
     pub ground_truth_distances: Array<f32, Ix2>,
+    /// Ids of the true nearest neighbors, if the file has a `neighbors` dataset
+    pub ground_truth_neighbors: Option<Array<usize, Ix2>>,
 }
 
 pub fn load_hdf5_dataset(filepath: &str) -> Result<Hdf5Dataset, String> {
@@ -47,32 +50,68 @@ pub fn load_hdf5_dataset(filepath: &str) -> Result<Hdf5Dataset, String> {
         .read::<f32, Ix2>()
         .map_err(|e| format!("Error reading dataset as f32 array: {}", e))?;
 
+    let ground_truth_neighbors = match file.dataset("neighbors") {
+        Ok(neighbors) => Some(
+            neighbors
+                .read::<i32, Ix2>()
+                .map_err(|e| format!("Error reading dataset as i32 array: {}", e))?
+                .mapv(|id| id as usize),
+        ),
+        Err(_) => None,
+    };
+
     debug!("Loaded dataset with shape: {:?}", dataset_array.dim());
 
     Ok(Hdf5Dataset {
         dataset_array,
         dataset_queries,
         ground_truth_distances,
+        ground_truth_neighbors,
     })
 }
 
+/// Tolerance added to the k-th true distance by [`get_recall_values`] when called through
+/// [`RecallInput::Distances`] built by the crate, e.g. by [`crate::save_metrics`]
+pub const DEFAULT_RECALL_EPSILON: f32 = 1e-3;
+
+/// The `count`-th smallest of `distances` plus `epsilon`, or the largest if there are fewer
 fn threshold(distances: &Array<f32, Ix1>, count: usize, epsilon: f32) -> f32 {
     // Assuming distances need to be sorted first since we're finding the k-th smallest
     let mut sorted_distances: Vec<f32> = distances.to_vec();
     sorted_distances.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
-    sorted_distances[count - 1] + epsilon
+    sorted_distances[count.min(sorted_distances.len()) - 1] + epsilon
 }
 
-pub(crate) fn get_recall_values(
+/// Recall from the distances of the true neighbors, as the number of the first `count`
+/// returned neighbors within `epsilon` of the `count`-th true distance.
+///
+/// `count` is the k the recall is computed at, not the number of returned neighbors: a query
+/// returning fewer than `count` neighbors has a lower recall, and the returned neighbors past
+/// the first `count` are ignored. If the ground truth has fewer than `count` distances per
+/// query, the threshold is its largest distance.
+///
+/// `epsilon` absorbs the rounding of distances computed differently from the ground truth, see
+/// [`DEFAULT_RECALL_EPSILON`]. It should be well below the gaps between neighbor distances,
+/// otherwise points just past the k-th true distance are counted as hits.
+///
+/// # Returns
+/// The mean and standard deviation of the recall over the queries, and the number of true
+/// neighbors found by each query
+///
+/// # Panics
+/// If `count` is zero, or if `dataset_distances` has fewer rows than `run_distances` or an empty row
+pub fn get_recall_values(
     dataset_distances: &Array<f32, Ix2>,
     run_distances: &[Vec<f32>],
     count: usize,
+    epsilon: f32,
 ) -> (f32, f32, Vec<f32>) {
+    assert!(count > 0, "recall at k = 0");
     let mut recalls = Vec::with_capacity(run_distances.len());
 
     for i in 0..run_distances.len() {
         // Get threshold from dataset (ground truth) distances
-        let t = threshold(&dataset_distances.row(i).to_owned(), count, 1e-3);
+        let t = threshold(&dataset_distances.row(i).to_owned(), count, epsilon);
 
         // Count matches in our search results
         let mut actual = 0;
@@ -94,23 +133,94 @@ pub(crate) fn get_recall_values(
     (mean_recall, std_recall, recalls)
 }
 
-pub(crate) fn db_exists(db_file_path: &str) -> bool {
-    fs::metadata(db_file_path).is_ok()
+/// Recall from the ids of the true neighbors, as the size of the intersection of the true
+/// and the returned `count` nearest neighbors.
+///
+/// Unlike [`get_recall_values`] it doesn't depend on a distance threshold, so ties and
+/// near-duplicates at the k-th distance are counted exactly.
+///
+/// # Returns
+/// The mean and standard deviation of the recall over the queries, and the number of true
+/// neighbors found by each query
+pub fn get_recall_values_by_ids(
+    dataset_neighbors: &Array<usize, Ix2>,
+    run_neighbors: &[Vec<usize>],
+    count: usize,
+) -> (f32, f32, Vec<f32>) {
+    let recalls: Vec<f32> = run_neighbors
+        .iter()
+        .enumerate()
+        .map(|(i, run)| {
+            let truth: HashSet<usize> = dataset_neighbors.row(i).iter().take(count).copied().collect();
+            let found: HashSet<usize> = run.iter().take(count).copied().collect();
+            found.intersection(&truth).count() as f32
+        })
+        .collect();
+
+    let mean_recall = recalls.iter().sum::<f32>() / (recalls.len() as f32 * count as f32);
+    let std_recall = {
+        let mean = recalls.iter().sum::<f32>() / recalls.len() as f32;
+        (recalls.iter().map(|&r| (r - mean).powi(2)).sum::<f32>() / recalls.len() as f32).sqrt()
+            / count as f32
+    };
+
+    (mean_recall, std_recall, recalls)
+}
+
+/// Ground truth and results of a run, from which its recall is computed
+pub enum RecallInput<'a> {
+    /// Distances of the true and of the returned neighbors, compared with a threshold
+    Distances {
+        ground_truth: &'a Array<f32, Ix2>,
+        run: &'a [Vec<f32>],
+        /// Tolerance of the threshold, see [`get_recall_values`]
+        epsilon: f32,
+    },
+
+    /// Ids of the true and of the returned neighbors, compared as sets
+    Ids {
+        ground_truth: &'a Array<usize, Ix2>,
+        run: &'a [Vec<usize>],
+    },
 }
 
-pub fn generate_random_unit_vectors(n: usize, dimensions: usize) -> Array2<f32> {
-    let mut rng = thread_rng();
-    let mut data = Array2::<f32>::zeros((n, dimensions));
+impl RecallInput<'_> {
+    pub(crate) fn num_queries(&self) -> usize {
+        match self {
+            RecallInput::Distances { run, .. } => run.len(),
+            RecallInput::Ids { run, .. } => run.len(),
+        }
+    }
 
-    for mut row in data.axis_iter_mut(Axis(0)) {
-        let vec: Vec<f32> = (0..dimensions).map(|_| rng.gen::<f32>()).collect();
-        let norm: f32 = vec.iter().map(|x| x.powi(2)).sum::<f32>().sqrt();
-        row.assign(&ndarray::arr1(
-            &vec.iter().map(|x| x / norm).collect::<Vec<f32>>(),
-        ));
+    /// Mean and standard deviation of the recall at `count`, and the number of true neighbors
+    /// found by each query
+    pub fn recall_values(&self, count: usize) -> (f32, f32, Vec<f32>) {
+        match self {
+            RecallInput::Distances {
+                ground_truth,
+                run,
+                epsilon,
+            } => get_recall_values(ground_truth, run, count, *epsilon),
+            RecallInput::Ids { ground_truth, run } => get_recall_values_by_ids(ground_truth, run, count),
+        }
     }
+}
 
-    data
+/// Standard normal sample through the Box-Muller transform
+pub(crate) fn gaussian<R: Rng>(rng: &mut R) -> f32 {
+    let u1: f32 = rng.gen_range(f32::EPSILON..1.0);
+    let u2: f32 = rng.gen();
+    (-2.0 * u1.ln()).sqrt() * (2.0 * std::f32::consts::PI * u2).cos()
+}
+
+/// Empty directory of the test `name` in the temporary directory, named after the process so
+/// that concurrent test runs don't overwrite each other's files
+#[cfg(test)]
+pub(crate) fn test_dir(name: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("clann_test_{}_{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
 }
 
 pub fn brute_force_search<T>(metric_data: &T, query: &[T::DataType], k: usize) -> Vec<u32>
@@ -129,3 +239,47 @@ where
 
     distances.into_iter().take(k).map(|(idx, _)| idx).collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use ndarray::arr2;
+
+    use super::{get_recall_values, get_recall_values_by_ids};
+
+    #[test]
+    fn test_recall_by_ids_counts_ties_exactly() {
+        // points 1 and 2 are at the same distance, only 1 is a true neighbor
+        let true_ids = arr2(&[[0, 1]]);
+        let true_distances = arr2(&[[0.1, 0.2, 0.2]]);
+
+        let (by_distance, _, _) = get_recall_values(&true_distances, &[vec![0.1, 0.2]], 2, 1e-3);
+        let (by_ids, _, per_query) = get_recall_values_by_ids(&true_ids, &[vec![0, 2]], 2);
+        assert_eq!(by_distance, 1.0);
+        assert_eq!(by_ids, 0.5);
+        assert_eq!(per_query, vec![1.0]);
+
+        // duplicated ids are counted once
+        let (by_ids, _, _) = get_recall_values_by_ids(&true_ids, &[vec![0, 0]], 2);
+        assert_eq!(by_ids, 0.5);
+    }
+
+    #[test]
+    fn test_recall_epsilon_and_count() {
+        let true_distances = arr2(&[[0.1, 0.2, 0.2005], [0.3, 0.4, 0.5]]);
+        let run = [vec![0.1, 0.2004], vec![0.3]];
+
+        // 0.2004 is within the default tolerance of the 2nd true distance, not of a tighter one
+        let (_, _, found) = get_recall_values(&true_distances, &run, 2, 1e-3);
+        assert_eq!(found, vec![2.0, 1.0]);
+        let (mean, _, found) = get_recall_values(&true_distances, &run, 2, 1e-5);
+        assert_eq!(found, vec![1.0, 1.0]);
+        assert_eq!(mean, 0.5);
+
+        // fewer returned neighbors than k count as misses, and k may exceed the ground truth
+        let (mean, _, found) = get_recall_values(&true_distances, &run, 3, 1e-5);
+        assert_eq!(found, vec![2.0, 1.0]);
+        assert_eq!(mean, 0.5);
+        let (_, _, found) = get_recall_values(&true_distances, &run, 5, 1e-5);
+        assert_eq!(found, vec![2.0, 1.0]);
+    }
+}